@@ -0,0 +1,562 @@
+use std::collections::BTreeSet;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use sdk::{
+    verifiers::Secp256k1Blob, Blob, BlobIndex, Calldata, ContractName, Identity, StructuredBlobData,
+};
+
+use crate::error::OrderbookError;
+
+/// Actions of the token contracts (smt-token), as encoded in their blobs
+#[derive(Debug, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum TokenAction {
+    Transfer {
+        sender: Identity,
+        recipient: Identity,
+        amount: u128,
+    },
+    TransferFrom {
+        owner: Identity,
+        spender: Identity,
+        recipient: Identity,
+        amount: u128,
+    },
+    Approve {
+        owner: Identity,
+        spender: Identity,
+        amount: u128,
+    },
+}
+
+/// Blob of the transfer of `amount` of `token` from `sender` to the orderbook contract
+/// `orderbook_cn`, backing a deposit. See [`CompanionBlobs::expect_deposit`].
+pub fn deposit_blob(
+    token: ContractName,
+    sender: Identity,
+    orderbook_cn: &ContractName,
+    amount: u128,
+) -> Blob {
+    Blob {
+        contract_name: token,
+        data: StructuredBlobData {
+            caller: None,
+            callees: None,
+            parameters: TokenAction::Transfer {
+                sender,
+                recipient: Identity(orderbook_cn.0.clone()),
+                amount,
+            },
+        }
+        .into(),
+    }
+}
+
+/// A token transfer found in the transaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenTransfer {
+    pub index: BlobIndex,
+    pub token: ContractName,
+    pub sender: Identity,
+    pub recipient: Identity,
+    pub amount: u128,
+    /// Blob of the contract the transfer is made on behalf of, when the sender is a contract
+    pub caller: Option<BlobIndex>,
+}
+
+/// Blob of the transfer of `amount` of `token` from the orderbook contract `orderbook_cn` to
+/// `recipient`, made on behalf of the orderbook blob at `orderbook_index`. See
+/// [`CompanionBlobs::expect_payout`].
+pub fn payout_blob(
+    token: ContractName,
+    orderbook_cn: &ContractName,
+    recipient: Identity,
+    amount: u128,
+    orderbook_index: BlobIndex,
+) -> Blob {
+    Blob {
+        contract_name: token,
+        data: StructuredBlobData {
+            caller: Some(orderbook_index),
+            callees: None,
+            parameters: TokenAction::Transfer {
+                sender: Identity(orderbook_cn.0.clone()),
+                recipient,
+                amount,
+            },
+        }
+        .into(),
+    }
+}
+
+/// The wallet blob authenticating the transaction's identity
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalletAuth {
+    pub index: BlobIndex,
+    pub wallet: ContractName,
+    pub identity: Identity,
+}
+
+/// Typed access to the blobs of a transaction other than the one being executed.
+///
+/// The contract only executes its own blob: the other blobs are verified by their own
+/// contracts, and the orderbook relies on them being settled in the same transaction.
+///
+/// A transfer backs a single deposit or withdrawal: the transfers backing the actions of the
+/// orderbook blob are claimed as they are found, and can't be claimed again.
+pub struct CompanionBlobs<'a> {
+    calldata: &'a Calldata,
+    claimed: BTreeSet<BlobIndex>,
+}
+
+impl<'a> CompanionBlobs<'a> {
+    pub fn new(calldata: &'a Calldata) -> Self {
+        CompanionBlobs {
+            calldata,
+            claimed: BTreeSet::new(),
+        }
+    }
+
+    /// Identity the transaction is sent as
+    pub fn identity(&self) -> &'a Identity {
+        &self.calldata.identity
+    }
+
+    fn blob(&self, index: BlobIndex) -> Result<&'a Blob, OrderbookError> {
+        if index == self.calldata.index {
+            return Err(invalid(index, "is the orderbook blob itself".to_string()));
+        }
+        self.calldata
+            .blobs
+            .get(&index)
+            .ok_or(OrderbookError::BlobNotFound { blob: index.0 })
+    }
+
+    /// Companion blobs with their index, in transaction order
+    fn others(&self) -> impl Iterator<Item = (BlobIndex, &'a Blob)> + 'a {
+        let own_index = self.calldata.index;
+        self.calldata
+            .blobs
+            .into_iter()
+            .filter(move |(index, _)| *index != own_index)
+            .map(|(index, blob)| (*index, blob))
+    }
+
+    /// Checks that the blob at `index` belongs to the wallet the transaction's identity
+    /// (`{account}@{wallet}`) is registered on.
+    pub fn wallet_auth(&self, index: BlobIndex) -> Result<WalletAuth, OrderbookError> {
+        let blob = self.blob(index)?;
+        let identity = self.calldata.identity.clone();
+        let Some((_, wallet)) = identity.0.rsplit_once('@') else {
+            return Err(OrderbookError::NoWallet {
+                identity: identity.0,
+            });
+        };
+        if blob.contract_name.0 != wallet {
+            return Err(invalid(
+                index,
+                format!(
+                    "is for contract {}, expected wallet {}",
+                    blob.contract_name, wallet
+                ),
+            ));
+        }
+        Ok(WalletAuth {
+            index,
+            wallet: blob.contract_name.clone(),
+            identity,
+        })
+    }
+
+    /// Finds a blob of the wallet the transaction's identity is registered on
+    pub fn find_wallet_auth(&self) -> Result<WalletAuth, OrderbookError> {
+        self.others()
+            .find_map(|(index, _)| self.wallet_auth(index).ok())
+            .ok_or_else(|| OrderbookError::MissingWalletAuth {
+                identity: self.calldata.identity.0.clone(),
+            })
+    }
+
+    /// Parses the blob at `index` as a token transfer
+    pub fn token_transfer(&self, index: BlobIndex) -> Result<TokenTransfer, OrderbookError> {
+        let blob = self.blob(index)?;
+        let data = StructuredBlobData::<TokenAction>::try_from(blob.data.clone())
+            .map_err(|e| invalid(index, format!("is not a token action: {}", e)))?;
+        match data.parameters {
+            TokenAction::Transfer {
+                sender,
+                recipient,
+                amount,
+            } => Ok(TokenTransfer {
+                index,
+                token: blob.contract_name.clone(),
+                sender,
+                recipient,
+                amount,
+                caller: data.caller,
+            }),
+            action => Err(invalid(index, format!("is not a transfer: {:?}", action))),
+        }
+    }
+
+    /// Finds the transfer of exactly `amount` of `token` from `sender` to `recipient`
+    pub fn expect_transfer(
+        &self,
+        token: &ContractName,
+        sender: &Identity,
+        recipient: &Identity,
+        amount: u128,
+    ) -> Result<TokenTransfer, OrderbookError> {
+        self.find_transfer(token, sender, recipient, amount, |_| true)
+            .ok_or_else(|| OrderbookError::MissingTransfer {
+                token: token.0.clone(),
+                amount,
+                sender: sender.0.clone(),
+                recipient: recipient.0.clone(),
+            })
+    }
+
+    fn find_transfer(
+        &self,
+        token: &ContractName,
+        sender: &Identity,
+        recipient: &Identity,
+        amount: u128,
+        accepted: impl Fn(&TokenTransfer) -> bool,
+    ) -> Option<TokenTransfer> {
+        self.others()
+            .filter(|(_, blob)| &blob.contract_name == token)
+            .filter_map(|(index, _)| self.token_transfer(index).ok())
+            .find(|transfer| {
+                &transfer.sender == sender
+                    && &transfer.recipient == recipient
+                    && transfer.amount == amount
+                    && accepted(transfer)
+            })
+    }
+
+    /// Finds and claims an unclaimed transfer of exactly `amount` of `token` from the
+    /// orderbook's own account to `recipient`. It must be made on behalf of the orderbook blob,
+    /// for the token contract to know the orderbook authorized it.
+    pub fn expect_payout(
+        &mut self,
+        token: &ContractName,
+        recipient: &Identity,
+        amount: u128,
+    ) -> Result<TokenTransfer, OrderbookError> {
+        let sender = self.orderbook_identity()?;
+        let own_index = self.calldata.index;
+        let transfer = self
+            .find_transfer(token, &sender, recipient, amount, |transfer| {
+                transfer.caller == Some(own_index) && !self.claimed.contains(&transfer.index)
+            })
+            .ok_or_else(|| OrderbookError::MissingPayout {
+                token: token.0.clone(),
+                amount,
+                sender: sender.0.clone(),
+                recipient: recipient.0.clone(),
+            })?;
+        self.claim(transfer)
+    }
+
+    /// Checks that the blob at `index` transfers exactly `amount` of `token` from the
+    /// transaction's identity to the orderbook's own account, and claims it. The transfer must
+    /// back this orderbook blob, see [`Self::backs_own_blob`].
+    pub fn expect_deposit(
+        &mut self,
+        index: BlobIndex,
+        token: &ContractName,
+        amount: u128,
+    ) -> Result<TokenTransfer, OrderbookError> {
+        let transfer = self.token_transfer(index)?;
+        let recipient = self.orderbook_identity()?;
+        if &transfer.token != token
+            || transfer.sender != self.calldata.identity
+            || transfer.recipient != recipient
+            || transfer.amount != amount
+        {
+            return Err(invalid(
+                index,
+                format!(
+                    "is not a transfer of {} {} from {} to {}",
+                    amount, token, self.calldata.identity, recipient
+                ),
+            ));
+        }
+        if !self.backs_own_blob(index) {
+            return Err(invalid(
+                index,
+                "backs another blob of the orderbook".to_string(),
+            ));
+        }
+        self.claim(transfer)
+    }
+
+    /// Finds and claims an unclaimed transfer of exactly `amount` of `token` from the
+    /// transaction's identity to the orderbook's own account, backing this orderbook blob
+    pub fn find_deposit(
+        &mut self,
+        token: &ContractName,
+        amount: u128,
+    ) -> Result<TokenTransfer, OrderbookError> {
+        let sender = self.calldata.identity.clone();
+        let recipient = self.orderbook_identity()?;
+        let transfer = self
+            .find_transfer(token, &sender, &recipient, amount, |transfer| {
+                self.backs_own_blob(transfer.index) && !self.claimed.contains(&transfer.index)
+            })
+            .ok_or_else(|| OrderbookError::MissingTransfer {
+                token: token.0.clone(),
+                amount,
+                sender: sender.0.clone(),
+                recipient: recipient.0.clone(),
+            })?;
+        self.claim(transfer)
+    }
+
+    /// Whether the deposit transfer at `index` backs the orderbook blob being executed: it
+    /// precedes it, and no other blob of the orderbook lies between them. A transaction with
+    /// several blobs of the orderbook can't have two of them claim the same transfer.
+    fn backs_own_blob(&self, index: BlobIndex) -> bool {
+        let own_index = self.calldata.index;
+        let Some(own_blob) = self.calldata.blobs.get(&own_index) else {
+            return false;
+        };
+        index < own_index
+            && !self.others().any(|(other, blob)| {
+                index < other && other < own_index && blob.contract_name == own_blob.contract_name
+            })
+    }
+
+    fn claim(&mut self, transfer: TokenTransfer) -> Result<TokenTransfer, OrderbookError> {
+        if !self.claimed.insert(transfer.index) {
+            return Err(OrderbookError::DuplicateTransfer {
+                blob: transfer.index.0,
+            });
+        }
+        Ok(transfer)
+    }
+
+    /// Account of the orderbook in the token contracts, named after its contract
+    fn orderbook_identity(&self) -> Result<Identity, OrderbookError> {
+        let index = self.calldata.index;
+        let orderbook = (self.calldata.blobs.get(&index))
+            .ok_or(OrderbookError::BlobNotFound { blob: index.0 })?;
+        Ok(Identity(orderbook.contract_name.0.clone()))
+    }
+
+    /// Parses the blob at `index` as a secp256k1 signature, checking it was made for `identity`
+    pub fn secp256k1(
+        &self,
+        index: BlobIndex,
+        identity: &Identity,
+    ) -> Result<Secp256k1Blob, OrderbookError> {
+        let blob = self.blob(index)?;
+        if blob.contract_name.0 != "secp256k1" {
+            return Err(invalid(
+                index,
+                format!("is for contract {}, expected secp256k1", blob.contract_name),
+            ));
+        }
+        let signature = borsh::from_slice::<Secp256k1Blob>(&blob.data.0)
+            .map_err(|e| invalid(index, format!("is not a secp256k1 blob: {}", e)))?;
+        if &signature.identity != identity {
+            return Err(invalid(
+                index,
+                format!(
+                    "is a signature of {}, expected {}",
+                    signature.identity, identity
+                ),
+            ));
+        }
+        Ok(signature)
+    }
+
+    /// Finds the secp256k1 signature of `identity` signing `data`
+    pub fn find_secp256k1(
+        &self,
+        identity: &Identity,
+        data: &[u8; 32],
+    ) -> Result<Secp256k1Blob, OrderbookError> {
+        self.others()
+            .filter(|(_, blob)| blob.contract_name.0 == "secp256k1")
+            .filter_map(|(index, _)| self.secp256k1(index, identity).ok())
+            .find(|signature| &signature.data == data)
+            .ok_or_else(|| OrderbookError::MissingSignature {
+                identity: identity.0.clone(),
+            })
+    }
+}
+
+fn invalid(index: BlobIndex, reason: String) -> OrderbookError {
+    OrderbookError::InvalidBlob {
+        blob: index.0,
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdk::{BlobData, TxHash};
+
+    fn transfer_blob(token: &str, sender: &str, recipient: &str, amount: u128) -> Blob {
+        Blob {
+            contract_name: token.into(),
+            data: StructuredBlobData {
+                caller: None,
+                callees: None,
+                parameters: TokenAction::Transfer {
+                    sender: sender.into(),
+                    recipient: recipient.into(),
+                    amount,
+                },
+            }
+            .into(),
+        }
+    }
+
+    /// Calldata of the orderbook blob, appended after `blobs`
+    fn calldata(blobs: Vec<Blob>) -> Calldata {
+        let index = BlobIndex(blobs.len());
+        Calldata {
+            tx_hash: TxHash(String::new()),
+            identity: "alice@wallet".into(),
+            tx_blob_count: blobs.len() + 1,
+            blobs: blobs
+                .into_iter()
+                .chain([Blob {
+                    contract_name: "orderbook".into(),
+                    data: BlobData(vec![]),
+                }])
+                .collect::<Vec<_>>()
+                .into(),
+            index,
+            tx_ctx: None,
+            private_input: vec![],
+        }
+    }
+
+    #[test_log::test]
+    fn test_companion_blobs() {
+        let signature = Secp256k1Blob {
+            identity: "alice@wallet".into(),
+            data: [1; 32],
+            public_key: [2; 33],
+            signature: [3; 64],
+        };
+        let calldata = calldata(vec![
+            Blob {
+                contract_name: "secp256k1".into(),
+                data: BlobData(borsh::to_vec(&signature).unwrap()),
+            },
+            Blob {
+                contract_name: "wallet".into(),
+                data: BlobData(vec![]),
+            },
+            transfer_blob("hyllar", "alice@wallet", "orderbook", 10),
+        ]);
+        let blobs = CompanionBlobs::new(&calldata);
+
+        assert_eq!(blobs.wallet_auth(BlobIndex(1)).unwrap().wallet.0, "wallet");
+        assert!(blobs.wallet_auth(BlobIndex(2)).is_err());
+
+        let transfer = blobs.token_transfer(BlobIndex(2)).unwrap();
+        assert_eq!(transfer.token.0, "hyllar");
+        assert_eq!(transfer.amount, 10);
+        assert_eq!(
+            blobs
+                .expect_transfer(
+                    &"hyllar".into(),
+                    &"alice@wallet".into(),
+                    &"orderbook".into(),
+                    10
+                )
+                .unwrap(),
+            transfer
+        );
+        assert!(blobs
+            .expect_transfer(
+                &"hyllar".into(),
+                &"alice@wallet".into(),
+                &"orderbook".into(),
+                11
+            )
+            .is_err());
+        assert!(blobs.token_transfer(BlobIndex(1)).is_err());
+
+        let identity = "alice@wallet".into();
+        assert_eq!(blobs.secp256k1(BlobIndex(0), &identity).unwrap(), signature);
+        assert_eq!(
+            blobs.find_secp256k1(&identity, &[1; 32]).unwrap(),
+            signature
+        );
+        assert!(blobs.find_secp256k1(&identity, &[0; 32]).is_err());
+        assert!(blobs.secp256k1(BlobIndex(0), &"bob@wallet".into()).is_err());
+
+        // The orderbook blob is not a companion blob
+        assert!(blobs.token_transfer(BlobIndex(3)).is_err());
+
+        let payout = payout_blob(
+            "hyllar".into(),
+            &"orderbook".into(),
+            "cold@wallet".into(),
+            10,
+            BlobIndex(1),
+        );
+        let calldata = self::calldata(vec![payout]);
+        let mut blobs = CompanionBlobs::new(&calldata);
+        assert!(blobs
+            .expect_payout(&"hyllar".into(), &"alice@wallet".into(), 10)
+            .is_err());
+        assert!(blobs
+            .expect_payout(&"hyllar".into(), &"cold@wallet".into(), 10)
+            .is_ok());
+        // A payout backs a single withdrawal
+        assert!(blobs
+            .expect_payout(&"hyllar".into(), &"cold@wallet".into(), 10)
+            .is_err());
+
+        // Payouts must be made on behalf of the orderbook blob
+        let calldata = self::calldata(vec![transfer_blob(
+            "hyllar",
+            "orderbook",
+            "cold@wallet",
+            10,
+        )]);
+        let mut blobs = CompanionBlobs::new(&calldata);
+        assert!(blobs
+            .expect_payout(&"hyllar".into(), &"cold@wallet".into(), 10)
+            .is_err());
+    }
+
+    #[test_log::test]
+    fn test_deposits_back_a_single_orderbook_blob() {
+        let transfer = || transfer_blob("hyllar", "alice@wallet", "orderbook", 10);
+        let calldata = calldata(vec![transfer(), transfer()]);
+        let mut blobs = CompanionBlobs::new(&calldata);
+        assert!(blobs
+            .expect_deposit(BlobIndex(0), &"hyllar".into(), 10)
+            .is_ok());
+        assert!(matches!(
+            blobs.expect_deposit(BlobIndex(0), &"hyllar".into(), 10),
+            Err(OrderbookError::DuplicateTransfer { blob: 0 })
+        ));
+        assert_eq!(
+            blobs.find_deposit(&"hyllar".into(), 10).unwrap().index,
+            BlobIndex(1)
+        );
+        assert!(blobs.find_deposit(&"hyllar".into(), 10).is_err());
+
+        // The transfer precedes another blob of the orderbook, which it backs instead
+        let other_orderbook_blob = Blob {
+            contract_name: "orderbook".into(),
+            data: BlobData(vec![]),
+        };
+        let calldata = self::calldata(vec![transfer(), other_orderbook_blob]);
+        let mut blobs = CompanionBlobs::new(&calldata);
+        assert!(blobs
+            .expect_deposit(BlobIndex(0), &"hyllar".into(), 10)
+            .is_err());
+        assert!(blobs.find_deposit(&"hyllar".into(), 10).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::OrderType;
+
+/// Resting orders of one side of a pair's book, grouped by price level. The orders of a level
+/// are in time priority.
+#[derive(
+    Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub struct PriceLevels(BTreeMap<u128, VecDeque<String>>);
+
+impl PriceLevels {
+    /// Queues an order at the end of its price level
+    pub fn insert(&mut self, price: u128, order_id: String) {
+        self.0.entry(price).or_default().push_back(order_id);
+    }
+
+    /// Queues an order in its price level, before the first order `queued_after` holds for
+    pub fn insert_before(
+        &mut self,
+        price: u128,
+        order_id: String,
+        queued_after: impl Fn(&String) -> bool,
+    ) {
+        let level = self.0.entry(price).or_default();
+        let position = level.iter().position(queued_after).unwrap_or(level.len());
+        level.insert(position, order_id);
+    }
+
+    /// Removes an order from its price level, and the level if it is left empty
+    pub fn remove(&mut self, price: u128, order_id: &str) {
+        if let Some(level) = self.0.get_mut(&price) {
+            level.retain(|id| id != order_id);
+            if level.is_empty() {
+                self.0.remove(&price);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, order_id: &str) -> bool {
+        self.0.values().flatten().any(|id| id == order_id)
+    }
+
+    /// Best price of the side holding the `side` orders: the highest bid or the lowest ask
+    pub fn best_price(&self, side: &OrderType) -> Option<u128> {
+        match side {
+            OrderType::Buy => self.0.last_key_value(),
+            OrderType::Sell => self.0.first_key_value(),
+        }
+        .map(|(price, _)| *price)
+    }
+
+    /// Price levels of the side holding the `side` orders, best price first
+    pub fn best_first(
+        &self,
+        side: &OrderType,
+    ) -> Box<dyn Iterator<Item = (u128, &VecDeque<String>)> + '_> {
+        let levels = self.0.iter().map(|(price, ids)| (*price, ids));
+        match side {
+            OrderType::Buy => Box::new(levels.rev()),
+            OrderType::Sell => Box::new(levels),
+        }
+    }
+
+    /// Orders of the side holding the `side` orders, in matching order
+    pub fn order_ids(&self, side: &OrderType) -> impl Iterator<Item = &String> {
+        self.best_first(side).flat_map(|(_, ids)| ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_log::test]
+    fn test_levels_in_matching_order() {
+        let mut levels = PriceLevels::default();
+        levels.insert(1000, "a".to_string());
+        levels.insert(900, "b".to_string());
+        levels.insert(1000, "c".to_string());
+
+        let bids: Vec<&String> = levels.order_ids(&OrderType::Buy).collect();
+        assert_eq!(bids, ["a", "c", "b"]);
+        let asks: Vec<&String> = levels.order_ids(&OrderType::Sell).collect();
+        assert_eq!(asks, ["b", "a", "c"]);
+        assert_eq!(levels.best_price(&OrderType::Buy), Some(1000));
+        assert_eq!(levels.best_price(&OrderType::Sell), Some(900));
+
+        levels.remove(900, "b");
+        assert_eq!(levels.best_price(&OrderType::Sell), Some(1000));
+        levels.remove(1000, "a");
+        levels.remove(1000, "c");
+        assert!(levels.is_empty());
+    }
+
+    #[test_log::test]
+    fn test_insert_before() {
+        let mut levels = PriceLevels::default();
+        levels.insert(1000, "a".to_string());
+        levels.insert(1000, "c".to_string());
+        levels.insert_before(1000, "b".to_string(), |id| id.as_str() > "b");
+        levels.insert_before(1000, "d".to_string(), |id| id.as_str() > "d");
+
+        let asks: Vec<&String> = levels.order_ids(&OrderType::Sell).collect();
+        assert_eq!(asks, ["a", "b", "c", "d"]);
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::events::Topic, quote_amount, OrderType, Orderbook, OrderbookAction, OrderbookEvent,
+    Rounding, FEE_POOL,
+};
+
+/// Trading activity of a pair
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairActivity {
+    /// Number of fills
+    pub trades: u64,
+    /// Quantity of base token traded
+    pub volume: u128,
+    /// Quantity of quote token traded, at the makers' prices
+    pub notional: u128,
+}
+
+/// Tokens moved in and out of the orderbook
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenFlows {
+    pub deposits: u128,
+    pub withdrawals: u128,
+}
+
+impl TokenFlows {
+    pub fn net(&self) -> i128 {
+        (self.deposits as i128).saturating_sub(self.withdrawals as i128)
+    }
+}
+
+/// Quantity of base token resting in the book of a pair
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpenInterest {
+    pub bids: u128,
+    pub asks: u128,
+}
+
+/// Activity of the orderbook over a period, accumulated from the executed transactions.
+///
+/// Pairs are keyed as their topic, i.e. `"{base}-{quote}"`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarketActivity {
+    pub pairs: BTreeMap<String, PairActivity>,
+    /// Fees collected in the fee pool, by token
+    pub fees: BTreeMap<String, u128>,
+    pub flows: BTreeMap<String, TokenFlows>,
+    /// Number of times the optimistic state diverged from the settled one and was rolled back
+    pub divergences: u64,
+}
+
+impl MarketActivity {
+    /// Accounts for a transaction that executed `action` on the `before` state, emitting `events`
+    pub fn record_tx(
+        &mut self,
+        before: &Orderbook,
+        action: &OrderbookAction,
+        events: &[OrderbookEvent],
+    ) {
+        self.record_flows(before, action);
+
+        let mut fee_balances = before.get_balance_for_account(FEE_POOL).unwrap_or_default();
+        for event in events {
+            match event {
+                OrderbookEvent::TradeExecuted {
+                    pair,
+                    price,
+                    quantity,
+                    ..
+                } => {
+                    let activity = self.pairs.entry(Topic::pair(pair).to_string()).or_default();
+                    activity.trades += 1;
+                    activity.volume += *quantity;
+                    let notional =
+                        quote_amount(*quantity, *price, before.base_scale(pair), Rounding::Down);
+                    activity.notional = activity
+                        .notional
+                        .saturating_add(notional.unwrap_or(u128::MAX));
+                }
+                OrderbookEvent::BalanceUpdated {
+                    user,
+                    token,
+                    amount,
+                } if user == FEE_POOL => {
+                    let previous = fee_balances.insert(token.clone(), *amount).unwrap_or(0);
+                    *self.fees.entry(token.clone()).or_default() += amount.saturating_sub(previous);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn record_flows(&mut self, before: &Orderbook, action: &OrderbookAction) {
+        match action {
+            OrderbookAction::Deposit { token, amount }
+            | OrderbookAction::DepositFromWallet { token, amount, .. } => {
+                self.flows.entry(token.clone()).or_default().deposits += *amount;
+            }
+            OrderbookAction::Withdraw { token, amount, .. } => {
+                self.flows.entry(token.clone()).or_default().withdrawals += *amount;
+            }
+            // Requested withdrawals only leave the orderbook once claimed
+            OrderbookAction::ClaimWithdraw { withdrawal_id } => {
+                if let Some(withdrawal) = before.pending_withdrawals.get(withdrawal_id) {
+                    let flows = self.flows.entry(withdrawal.token.clone()).or_default();
+                    flows.withdrawals += withdrawal.amount;
+                }
+            }
+            OrderbookAction::Batch(actions) => {
+                for action in actions {
+                    self.record_flows(before, action);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn record_divergence(&mut self) {
+        self.divergences += 1;
+    }
+}
+
+/// Quantities resting in the book of each pair of `state`
+pub fn open_interest(state: &Orderbook) -> BTreeMap<String, OpenInterest> {
+    let mut open_interest: BTreeMap<String, OpenInterest> = BTreeMap::new();
+    for order in state.orders.values() {
+        let pair = open_interest
+            .entry(Topic::pair(&order.pair).to_string())
+            .or_default();
+        match order.order_type {
+            OrderType::Buy => pair.bids += order.quantity,
+            OrderType::Sell => pair.asks += order.quantity,
+        }
+    }
+    open_interest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Order, OrderStatus, TimeInForce, TokenPair};
+    use sdk::hyle_model_utils::TimestampMs;
+
+    fn pair() -> TokenPair {
+        ("ETH".to_string(), "USD".to_string())
+    }
+
+    fn order(owner: &str, order_id: &str, order_type: OrderType, quantity: u128) -> Order {
+        Order {
+            owner: owner.to_string(),
+            order_id: order_id.to_string(),
+            order_type,
+            price: Some(2000),
+            trigger_price: None,
+            pair: pair(),
+            quantity,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        }
+    }
+
+    #[test_log::test]
+    fn test_fills_are_counted_once() {
+        let mut before = Orderbook::init(Default::default(), "admin@orderbook".to_string());
+        before.orders.insert(
+            "sell1".to_string(),
+            order("alice@wallet", "sell1", OrderType::Sell, 5),
+        );
+        before
+            .balances
+            .entry(FEE_POOL.to_string())
+            .or_default()
+            .insert("USD".to_string(), 10);
+        let mut after = before.clone();
+        after.orders.get_mut("sell1").unwrap().quantity = 3;
+
+        // Bob buys 2 from alice, the price improvement goes to the fee pool
+        let action = OrderbookAction::CreateOrder {
+            order_type: OrderType::Buy,
+            price: Some(2100),
+            pair: pair(),
+            quantity: 2,
+            time_in_force: TimeInForce::GoodTilCancelled,
+            trigger_price: None,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
+        };
+        let events = vec![
+            OrderbookEvent::TradeExecuted {
+                pair: pair(),
+                price: 2000,
+                quantity: 2,
+                maker_order_id: "sell1".to_string(),
+                taker_order_id: "buy1".to_string(),
+                maker: "alice@wallet".to_string(),
+                taker: "bob@wallet".to_string(),
+                timestamp: TimestampMs(0),
+            },
+            OrderbookEvent::OrderUpdate {
+                order_id: "sell1".to_string(),
+                remaining_quantity: 3,
+                pair: pair(),
+                executed_price: Some(2000),
+            },
+            OrderbookEvent::OrderExecuted {
+                order_id: "buy1".to_string(),
+                pair: pair(),
+                executed_price: Some(2000),
+            },
+            OrderbookEvent::BalanceUpdated {
+                user: FEE_POOL.to_string(),
+                token: "USD".to_string(),
+                amount: 210,
+            },
+        ];
+
+        let mut activity = MarketActivity::default();
+        activity.record_tx(&before, &action, &events);
+        assert_eq!(
+            activity.pairs["ETH-USD"],
+            PairActivity {
+                trades: 1,
+                volume: 2,
+                notional: 4000,
+            }
+        );
+        assert_eq!(activity.fees["USD"], 200);
+        assert_eq!(open_interest(&after)["ETH-USD"].asks, 3);
+    }
+
+    #[test_log::test]
+    fn test_token_flows() {
+        let state = Orderbook::init(Default::default(), "admin@orderbook".to_string());
+        let mut activity = MarketActivity::default();
+        let deposit = OrderbookAction::Deposit {
+            token: "USD".to_string(),
+            amount: 100,
+        };
+        let withdraw = OrderbookAction::Withdraw {
+            token: "USD".to_string(),
+            amount: 30,
+            recipient: None,
+        };
+        activity.record_tx(&state, &deposit, &[]);
+        activity.record_tx(&state, &withdraw, &[]);
+        activity.record_tx(&state, &withdraw, &[]);
+        assert_eq!(activity.flows["USD"].net(), 40);
+    }
+}
@@ -0,0 +1,190 @@
+use std::{collections::BTreeMap, fmt};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::{client::events::Topic, OrderbookEvent, TokenPair};
+
+/// Maximum number of pending alerts of a single user
+pub const MAX_ALERTS_PER_USER: usize = 32;
+
+/// Side of the threshold a trade must happen on to trigger an alert
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AlertDirection {
+    Above,
+    Below,
+}
+
+/// Price alert rule registered by a client. It is triggered once, by the first fill of `pair`
+/// at or beyond `threshold`, and pushed on the user's topic.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriceAlert {
+    pub user: String,
+    pub pair: TokenPair,
+    pub threshold: u128,
+    pub direction: AlertDirection,
+}
+
+/// A price alert that was triggered, and the price of the fill that triggered it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TriggeredAlert {
+    pub alert: PriceAlert,
+    pub price: u128,
+}
+
+impl PriceAlert {
+    pub fn triggered_by(&self, price: u128) -> bool {
+        match self.direction {
+            AlertDirection::Above => price >= self.threshold,
+            AlertDirection::Below => price <= self.threshold,
+        }
+    }
+}
+
+impl fmt::Display for TriggeredAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.alert.direction {
+            AlertDirection::Above => "above",
+            AlertDirection::Below => "below",
+        };
+        write!(
+            f,
+            "Price alert: {} traded at {}, {} {}",
+            Topic::pair(&self.alert.pair),
+            self.price,
+            direction,
+            self.alert.threshold
+        )
+    }
+}
+
+/// Pending price alerts of all users
+#[derive(Debug, Default, Clone)]
+pub struct PriceAlerts {
+    alerts: BTreeMap<String, Vec<PriceAlert>>,
+}
+
+impl PriceAlerts {
+    pub fn add(&mut self, alert: PriceAlert) -> anyhow::Result<()> {
+        let alerts = self.alerts.entry(alert.user.clone()).or_default();
+        if alerts.contains(&alert) {
+            return Ok(());
+        }
+        if alerts.len() >= MAX_ALERTS_PER_USER {
+            bail!(
+                "User {} already has {} price alerts",
+                alert.user,
+                MAX_ALERTS_PER_USER
+            );
+        }
+        alerts.push(alert);
+        Ok(())
+    }
+
+    /// Removes all pending alerts of `user`
+    pub fn clear(&mut self, user: &str) {
+        self.alerts.remove(user);
+    }
+
+    /// Removes and returns the alerts triggered by the fills among `events`
+    pub fn trigger(&mut self, events: &[OrderbookEvent]) -> Vec<TriggeredAlert> {
+        let fills: Vec<(&TokenPair, u128)> = events
+            .iter()
+            .filter_map(|event| match event {
+                OrderbookEvent::OrderExecuted {
+                    pair,
+                    executed_price: Some(price),
+                    ..
+                }
+                | OrderbookEvent::OrderUpdate {
+                    pair,
+                    executed_price: Some(price),
+                    ..
+                } => Some((pair, *price)),
+                _ => None,
+            })
+            .collect();
+        if fills.is_empty() {
+            return vec![];
+        }
+
+        let mut triggered = vec![];
+        for alerts in self.alerts.values_mut() {
+            alerts.retain(|alert| {
+                let fill = fills
+                    .iter()
+                    .find(|(pair, price)| *pair == &alert.pair && alert.triggered_by(*price));
+                match fill {
+                    Some((_, price)) => {
+                        triggered.push(TriggeredAlert {
+                            alert: alert.clone(),
+                            price: *price,
+                        });
+                        false
+                    }
+                    None => true,
+                }
+            });
+        }
+        self.alerts.retain(|_, alerts| !alerts.is_empty());
+        triggered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> TokenPair {
+        ("ETH".to_string(), "USD".to_string())
+    }
+
+    fn alert(user: &str, threshold: u128, direction: AlertDirection) -> PriceAlert {
+        PriceAlert {
+            user: user.to_string(),
+            pair: pair(),
+            threshold,
+            direction,
+        }
+    }
+
+    fn fill(price: u128) -> OrderbookEvent {
+        OrderbookEvent::OrderExecuted {
+            order_id: "sell1".to_string(),
+            pair: pair(),
+            executed_price: Some(price),
+        }
+    }
+
+    #[test_log::test]
+    fn test_price_alerts() {
+        let mut alerts = PriceAlerts::default();
+        alerts
+            .add(alert("alice@wallet", 2100, AlertDirection::Above))
+            .unwrap();
+        alerts
+            .add(alert("bob@wallet", 1900, AlertDirection::Below))
+            .unwrap();
+
+        assert!(alerts.trigger(&[fill(2000)]).is_empty());
+
+        let triggered = alerts.trigger(&[fill(2000), fill(2100)]);
+        assert_eq!(
+            triggered,
+            vec![TriggeredAlert {
+                alert: alert("alice@wallet", 2100, AlertDirection::Above),
+                price: 2100,
+            }]
+        );
+        assert_eq!(
+            triggered[0].to_string(),
+            "Price alert: ETH-USD traded at 2100, above 2100"
+        );
+
+        // Alerts only trigger once
+        assert!(alerts.trigger(&[fill(2200)]).is_empty());
+
+        alerts.clear("bob@wallet");
+        assert!(alerts.trigger(&[fill(1800)]).is_empty());
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use sdk::hyle_model_utils::TimestampMs;
+
+use crate::{history::Candle, quote_amount, Orderbook, OrderbookEvent, Rounding, TokenPair};
+
+/// Intervals the candles of each pair are cached in: a minute, five minutes, an hour and a day
+pub const CANDLE_INTERVALS_MS: [u128; 4] = [60_000, 300_000, 3_600_000, 86_400_000];
+
+/// Number of most recent candles cached for each pair and interval
+pub const MAX_CACHED_CANDLES: usize = 2000;
+
+/// Candles of the pairs in each of the [`CANDLE_INTERVALS_MS`], updated with each trade so that
+/// range queries don't aggregate the trade history again.
+///
+/// The contract only keeps the recent candles of a single interval. The cache is seeded with
+/// them, and keeps the candles of the longer intervals for longer.
+#[derive(Debug, Default, Clone)]
+pub struct CandleCache {
+    /// Candles of each pair and interval, oldest first, starting at multiples of the interval
+    candles: BTreeMap<(TokenPair, u128), VecDeque<Candle>>,
+}
+
+impl CandleCache {
+    /// Cache of the candles `state` keeps
+    pub fn new(state: &Orderbook) -> Self {
+        let mut cache = CandleCache::default();
+        cache.reseed(state);
+        cache
+    }
+
+    /// Adds the trades among `events`, emitted by `state`
+    pub fn record(&mut self, state: &Orderbook, events: &[OrderbookEvent]) {
+        for event in events {
+            let OrderbookEvent::TradeExecuted {
+                pair,
+                price,
+                quantity,
+                timestamp,
+                ..
+            } = event
+            else {
+                continue;
+            };
+            let scale = state.base_scale(pair);
+            let trade = Candle {
+                timestamp: timestamp.clone(),
+                open: *price,
+                high: *price,
+                low: *price,
+                close: *price,
+                volume: *quantity,
+                quote_volume: quote_amount(*quantity, *price, scale, Rounding::Down)
+                    .unwrap_or(u128::MAX),
+            };
+            for interval in CANDLE_INTERVALS_MS {
+                self.merge(pair, interval, &trade);
+            }
+        }
+    }
+
+    /// Rebuilds the cached candles covered by the candles `state` keeps, dropping the trades
+    /// that are no longer part of it, e.g. after a rollback. Older candles are left untouched.
+    pub fn reseed(&mut self, state: &Orderbook) {
+        for (pair, history) in &state.trade_history {
+            let kept_interval = history.candle_interval_ms();
+            let Some(oldest) = history.candles().next() else {
+                continue;
+            };
+            for interval in CANDLE_INTERVALS_MS {
+                if kept_interval == 0 || interval % kept_interval != 0 {
+                    continue;
+                }
+                // The candle the oldest kept one falls in can have older trades, which the
+                // contract dropped: it is only rebuilt from the next one
+                let from = oldest.timestamp.0.div_ceil(interval) * interval;
+                if let Some(candles) = self.candles.get_mut(&(pair.clone(), interval)) {
+                    candles.retain(|candle| candle.timestamp.0 < from);
+                }
+                for kept in history.candles().filter(|c| c.timestamp.0 >= from) {
+                    self.merge(pair, interval, kept);
+                }
+            }
+        }
+    }
+
+    /// Candles of `pair` starting in `[from, to)`, or `None` when `interval` is not one of the
+    /// [`CANDLE_INTERVALS_MS`]
+    pub fn range(
+        &self,
+        pair: &TokenPair,
+        interval: u128,
+        from: &TimestampMs,
+        to: &TimestampMs,
+    ) -> Option<Vec<Candle>> {
+        if !CANDLE_INTERVALS_MS.contains(&interval) {
+            return None;
+        }
+        let Some(candles) = self.candles.get(&(pair.clone(), interval)) else {
+            return Some(vec![]);
+        };
+        let first = candles.partition_point(|candle| candle.timestamp.0 < from.0);
+        let range = (candles.range(first..))
+            .take_while(|candle| candle.timestamp.0 < to.0)
+            .cloned()
+            .collect();
+        Some(range)
+    }
+
+    /// Adds `trades`, which happened after the ones cached, to the candle of `interval` they
+    /// fall in
+    fn merge(&mut self, pair: &TokenPair, interval: u128, trades: &Candle) {
+        let start = TimestampMs(trades.timestamp.0 / interval * interval);
+        let candles = self.candles.entry((pair.clone(), interval)).or_default();
+        match candles.binary_search_by_key(&&start, |candle| &candle.timestamp) {
+            Ok(index) => candles[index].merge(trades),
+            Err(index) => candles.insert(
+                index,
+                Candle {
+                    timestamp: start,
+                    ..trades.clone()
+                },
+            ),
+        }
+        while candles.len() > MAX_CACHED_CANDLES {
+            candles.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        history::{HistoryRetention, Trade},
+        OrderType,
+    };
+
+    fn pair() -> TokenPair {
+        ("ETH".to_string(), "USD".to_string())
+    }
+
+    fn trade(timestamp: u128, price: u128) -> OrderbookEvent {
+        OrderbookEvent::TradeExecuted {
+            pair: pair(),
+            price,
+            quantity: 1,
+            maker_order_id: "order1".to_string(),
+            taker_order_id: "order2".to_string(),
+            maker: "alice@wallet".to_string(),
+            taker: "bob@wallet".to_string(),
+            timestamp: TimestampMs(timestamp),
+        }
+    }
+
+    fn ohlc(candles: &[Candle]) -> Vec<(u128, u128, u128, u128, u128, u128)> {
+        (candles.iter())
+            .map(|c| (c.timestamp.0, c.open, c.high, c.low, c.close, c.volume))
+            .collect()
+    }
+
+    #[test_log::test]
+    fn test_candle_cache() {
+        let hour = 3_600_000;
+        let mut state = Orderbook::init(Default::default(), "admin@orderbook".to_string());
+        let mut cache = CandleCache::new(&state);
+        let events = vec![trade(0, 10), trade(61_000, 12), trade(hour + 100_000, 8)];
+        cache.record(&state, &events);
+
+        let minutes = cache.range(&pair(), 60_000, &TimestampMs(0), &TimestampMs(hour));
+        assert_eq!(
+            ohlc(&minutes.unwrap()),
+            vec![(0, 10, 10, 10, 10, 1), (60_000, 12, 12, 12, 12, 1)]
+        );
+        let hours = cache.range(&pair(), hour, &TimestampMs(0), &TimestampMs(u128::MAX));
+        assert_eq!(
+            ohlc(&hours.unwrap()),
+            vec![(0, 10, 12, 10, 12, 2), (hour, 8, 8, 8, 8, 1)]
+        );
+        assert!(cache
+            .range(&pair(), 1000, &TimestampMs(0), &TimestampMs(hour))
+            .is_none());
+
+        // The last trade is rolled back: the contract only kept the first two
+        let history = state.trade_history.entry(pair()).or_default();
+        for (timestamp, price) in [(0, 10), (61_000, 12)] {
+            let trade = Trade {
+                id: 0,
+                timestamp: TimestampMs(timestamp),
+                price,
+                quantity: 1,
+                taker_side: OrderType::Buy,
+                maker: "alice@wallet".to_string(),
+                taker: "bob@wallet".to_string(),
+            };
+            history.record(&HistoryRetention::default(), 1, trade);
+        }
+        cache.reseed(&state);
+        let hours = cache.range(&pair(), hour, &TimestampMs(0), &TimestampMs(u128::MAX));
+        assert_eq!(ohlc(&hours.unwrap()), vec![(0, 10, 12, 10, 12, 2)]);
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{client::events::Topic, OrderbookEvent, TokenPair};
+
+/// Maximum number of channels a single WebSocket connection subscribes to
+pub const MAX_SUBSCRIPTIONS_PER_CONNECTION: usize = 64;
+
+/// Events a WebSocket client subscribes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    /// Trades executed on a pair
+    Trades,
+    /// Orders of a pair being created, filled, updated or cancelled
+    Orders,
+    /// Price levels of a pair, see [`crate::client::depth::DepthMessage`]
+    Depth,
+}
+
+/// Channel requested by a client, e.g. `{"channel": "trades", "pair": "ETH-USD"}`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Subscription {
+    pub channel: Channel,
+    /// Pair formatted as `"{base}-{quote}"`
+    pub pair: String,
+}
+
+/// Reply to a subscription request, published on the topic of the subscription
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionReply {
+    Subscribed(Subscription),
+    Unsubscribed(Subscription),
+    Error {
+        subscription: Subscription,
+        message: String,
+    },
+}
+
+impl Subscription {
+    /// Pair of the channel, parsed from its `"{base}-{quote}"` format
+    pub fn token_pair(&self) -> anyhow::Result<TokenPair> {
+        let (base, quote) = (self.pair.split_once('-'))
+            .ok_or(anyhow!("Invalid pair '{}', expected BASE-QUOTE", self.pair))?;
+        Ok((base.to_string(), quote.to_string()))
+    }
+
+    /// Topic the channel is published on, which the client registers to receive it, e.g.
+    /// `"trades:ETH-USD"`
+    pub fn topic(&self) -> String {
+        let channel = match self.channel {
+            Channel::Trades => "trades",
+            Channel::Orders => "orders",
+            Channel::Depth => "depth",
+        };
+        format!("{channel}:{}", self.pair)
+    }
+
+    /// Whether `event` is published on the channel. The depth channel publishes the price levels
+    /// derived from the events, not the events themselves.
+    pub fn matches(&self, event: &OrderbookEvent) -> bool {
+        let Ok(pair) = self.token_pair() else {
+            return false;
+        };
+        if event.topic() != Topic::pair(&pair) {
+            return false;
+        }
+        match self.channel {
+            Channel::Trades => matches!(event, OrderbookEvent::TradeExecuted { .. }),
+            Channel::Orders => matches!(
+                event,
+                OrderbookEvent::OrderCreated { .. }
+                    | OrderbookEvent::StopOrderPlaced { .. }
+                    | OrderbookEvent::StopOrderTriggered { .. }
+                    | OrderbookEvent::OrderCancelled { .. }
+                    | OrderbookEvent::OrderExecuted { .. }
+                    | OrderbookEvent::OrderUpdate { .. }
+                    | OrderbookEvent::OrdersLinked { .. }
+            ),
+            Channel::Depth => false,
+        }
+    }
+}
+
+impl SubscriptionReply {
+    /// Topic the reply is published on
+    pub fn topic(&self) -> String {
+        match self {
+            SubscriptionReply::Subscribed(subscription)
+            | SubscriptionReply::Unsubscribed(subscription)
+            | SubscriptionReply::Error { subscription, .. } => subscription.topic(),
+        }
+    }
+}
+
+/// Channels the WebSocket connections subscribed to. A channel is published as long as one
+/// connection is subscribed to it.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    /// Addresses of the connections subscribed to each channel
+    connections: BTreeMap<Subscription, BTreeSet<String>>,
+}
+
+impl Subscriptions {
+    /// Subscribes `connection` to a channel. Subscribing again to the same channel is a no-op.
+    pub fn subscribe(
+        &mut self,
+        connection: &str,
+        subscription: Subscription,
+    ) -> anyhow::Result<()> {
+        subscription.token_pair()?;
+        if (self.connections.get(&subscription)).is_some_and(|c| c.contains(connection)) {
+            return Ok(());
+        }
+        let count = (self.connections.values())
+            .filter(|connections| connections.contains(connection))
+            .count();
+        if count >= MAX_SUBSCRIPTIONS_PER_CONNECTION {
+            bail!(
+                "Too many subscriptions, at most {} per connection",
+                MAX_SUBSCRIPTIONS_PER_CONNECTION
+            );
+        }
+        (self.connections.entry(subscription).or_default()).insert(connection.to_string());
+        Ok(())
+    }
+
+    pub fn unsubscribe(
+        &mut self,
+        connection: &str,
+        subscription: &Subscription,
+    ) -> anyhow::Result<()> {
+        let Some(connections) = self.connections.get_mut(subscription) else {
+            bail!("Not subscribed to {}", subscription.topic());
+        };
+        if !connections.remove(connection) {
+            bail!("Not subscribed to {}", subscription.topic());
+        }
+        if connections.is_empty() {
+            self.connections.remove(subscription);
+        }
+        Ok(())
+    }
+
+    /// Topics of the subscribed channels `event` is published on
+    pub fn topics_of(&self, event: &OrderbookEvent) -> Vec<String> {
+        (self.connections.keys())
+            .filter(|subscription| subscription.matches(event))
+            .map(Subscription::topic)
+            .collect()
+    }
+
+    /// Pairs whose depth channel is subscribed to
+    pub fn depth_pairs(&self) -> Vec<TokenPair> {
+        (self.connections.keys())
+            .filter(|subscription| subscription.channel == Channel::Depth)
+            .filter_map(|subscription| subscription.token_pair().ok())
+            .collect()
+    }
+
+    /// Topics of all the subscribed channels
+    pub fn topics(&self) -> Vec<String> {
+        self.connections.keys().map(Subscription::topic).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(channel: Channel, pair: &str) -> Subscription {
+        Subscription {
+            channel,
+            pair: pair.to_string(),
+        }
+    }
+
+    #[test_log::test]
+    fn test_subscription_format() {
+        let trades: Subscription =
+            serde_json::from_str(r#"{"channel": "trades", "pair": "ETH-USD"}"#).unwrap();
+        assert_eq!(trades, subscription(Channel::Trades, "ETH-USD"));
+        assert_eq!(trades.topic(), "trades:ETH-USD");
+        assert_eq!(
+            trades.token_pair().unwrap(),
+            ("ETH".to_string(), "USD".to_string())
+        );
+        assert!(subscription(Channel::Orders, "ETHUSD")
+            .token_pair()
+            .is_err());
+        assert!(
+            serde_json::from_str::<Subscription>(r#"{"channel": "book", "pair": "ETH-USD"}"#)
+                .is_err()
+        );
+        assert_eq!(
+            serde_json::to_string(&SubscriptionReply::Subscribed(trades)).unwrap(),
+            r#"{"subscribed":{"channel":"trades","pair":"ETH-USD"}}"#
+        );
+    }
+
+    #[test_log::test]
+    fn test_subscriptions() {
+        let trades = subscription(Channel::Trades, "ETH-USD");
+        let orders = subscription(Channel::Orders, "ETH-USD");
+        let mut subscriptions = Subscriptions::default();
+        subscriptions
+            .subscribe("1.2.3.4:1", trades.clone())
+            .unwrap();
+        subscriptions
+            .subscribe("1.2.3.4:2", trades.clone())
+            .unwrap();
+        subscriptions
+            .subscribe("1.2.3.4:2", orders.clone())
+            .unwrap();
+
+        let trade = OrderbookEvent::TradeExecuted {
+            pair: ("ETH".to_string(), "USD".to_string()),
+            price: 2000,
+            quantity: 1,
+            maker_order_id: "order1".to_string(),
+            taker_order_id: "order2".to_string(),
+            maker: "alice@wallet".to_string(),
+            taker: "bob@wallet".to_string(),
+            timestamp: sdk::hyle_model_utils::TimestampMs(0),
+        };
+        assert_eq!(subscriptions.topics_of(&trade), vec!["trades:ETH-USD"]);
+
+        // The channel is published until its last connection unsubscribes
+        subscriptions.unsubscribe("1.2.3.4:1", &trades).unwrap();
+        assert_eq!(subscriptions.topics_of(&trade), vec!["trades:ETH-USD"]);
+        assert!(subscriptions.unsubscribe("1.2.3.4:1", &trades).is_err());
+        subscriptions.unsubscribe("1.2.3.4:2", &trades).unwrap();
+        assert!(subscriptions.topics_of(&trade).is_empty());
+        assert_eq!(subscriptions.topics(), vec!["orders:ETH-USD"]);
+
+        // Depth channels only publish price levels
+        let depth = subscription(Channel::Depth, "ETH-USD");
+        subscriptions.subscribe("1.2.3.4:2", depth.clone()).unwrap();
+        assert_eq!(depth.topic(), "depth:ETH-USD");
+        assert!(subscriptions.topics_of(&trade).is_empty());
+        assert_eq!(
+            subscriptions.depth_pairs(),
+            vec![("ETH".to_string(), "USD".to_string())]
+        );
+        subscriptions.unsubscribe("1.2.3.4:2", &depth).unwrap();
+
+        for i in 0..MAX_SUBSCRIPTIONS_PER_CONNECTION {
+            let pair = format!("T{i}-USD");
+            (subscriptions.subscribe("1.2.3.4:3", subscription(Channel::Trades, &pair))).unwrap();
+        }
+        assert!(subscriptions.subscribe("1.2.3.4:3", orders).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::{
+        channels::{Channel, Subscription},
+        events::Topic,
+    },
+    OrderType, Orderbook, OrderbookEvent, TokenPair,
+};
+
+/// A full snapshot of the depth of a pair follows every delta whose sequence number is a
+/// multiple of it
+pub const DEPTH_SNAPSHOT_INTERVAL: u64 = 100;
+
+/// Change of a price level of a pair, quantities counting only the visible part of icebergs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LevelChange {
+    Added {
+        side: OrderType,
+        price: u128,
+        quantity: u128,
+        orders: usize,
+    },
+    Changed {
+        side: OrderType,
+        price: u128,
+        quantity: u128,
+        orders: usize,
+    },
+    Removed {
+        side: OrderType,
+        price: u128,
+    },
+}
+
+/// Message published on the depth channel of a pair, e.g. `"depth:ETH-USD"`.
+///
+/// Sequence numbers are the ones of the book of the pair, see [`Orderbook::book_seq`]: each
+/// delta has the sequence number following the previous one. A client that receives a delta out
+/// of sequence waits for the next snapshot, and applies the deltas following it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DepthMessage {
+    /// All the price levels of the pair, best first, as in [`crate::indexer::PairDepth`]
+    Snapshot {
+        pair: String,
+        seq: u64,
+        bids: Vec<(u128, u128, usize)>,
+        asks: Vec<(u128, u128, usize)>,
+    },
+    /// Price levels changed by a transaction
+    Delta {
+        pair: String,
+        seq: u64,
+        changes: Vec<LevelChange>,
+    },
+}
+
+impl DepthMessage {
+    /// Snapshot of all the price levels of `pair` in `state`
+    pub fn snapshot(state: &Orderbook, pair: &TokenPair) -> Self {
+        let depth = state.get_pair_depth(&pair.0, &pair.1, usize::MAX);
+        DepthMessage::Snapshot {
+            pair: Topic::pair(pair).to_string(),
+            seq: state.book_seq(pair),
+            bids: depth.bids,
+            asks: depth.asks,
+        }
+    }
+
+    /// Topic of the depth channel the message is published on
+    pub fn topic(&self) -> String {
+        let (DepthMessage::Snapshot { pair, .. } | DepthMessage::Delta { pair, .. }) = self;
+        let subscription = Subscription {
+            channel: Channel::Depth,
+            pair: pair.clone(),
+        };
+        subscription.topic()
+    }
+}
+
+/// Depth messages of the `pairs` whose book was changed by `events`, the events of a transaction
+/// that turned `before` into `after`. The engine reports each changed book with a
+/// [`OrderbookEvent::BookHash`], which gives a delta, followed by a snapshot when one is due.
+pub fn depth_updates(
+    before: &Orderbook,
+    after: &Orderbook,
+    events: &[OrderbookEvent],
+    pairs: &[TokenPair],
+) -> Vec<DepthMessage> {
+    let mut messages = vec![];
+    for event in events {
+        let OrderbookEvent::BookHash { pair, seq, .. } = event else {
+            continue;
+        };
+        if !pairs.contains(pair) {
+            continue;
+        }
+        messages.push(DepthMessage::Delta {
+            pair: Topic::pair(pair).to_string(),
+            seq: *seq,
+            changes: level_changes(before, after, pair),
+        });
+        if seq % DEPTH_SNAPSHOT_INTERVAL == 0 {
+            messages.push(DepthMessage::snapshot(after, pair));
+        }
+    }
+    messages
+}
+
+/// Price levels of `pair` that differ between `before` and `after`, bids first
+fn level_changes(before: &Orderbook, after: &Orderbook, pair: &TokenPair) -> Vec<LevelChange> {
+    let levels = |state: &Orderbook| {
+        let depth = state.get_pair_depth(&pair.0, &pair.1, usize::MAX);
+        let side = |levels: Vec<(u128, u128, usize)>| -> BTreeMap<u128, (u128, usize)> {
+            (levels.into_iter())
+                .map(|(price, quantity, orders)| (price, (quantity, orders)))
+                .collect()
+        };
+        [
+            (OrderType::Buy, side(depth.bids)),
+            (OrderType::Sell, side(depth.asks)),
+        ]
+    };
+
+    let mut changes = vec![];
+    for ((side, old), (_, new)) in levels(before).into_iter().zip(levels(after)) {
+        for (&price, &(quantity, orders)) in &new {
+            match old.get(&price) {
+                None => changes.push(LevelChange::Added {
+                    side: side.clone(),
+                    price,
+                    quantity,
+                    orders,
+                }),
+                Some(&level) if level != (quantity, orders) => changes.push(LevelChange::Changed {
+                    side: side.clone(),
+                    price,
+                    quantity,
+                    orders,
+                }),
+                Some(_) => {}
+            }
+        }
+        for price in old.keys().filter(|price| !new.contains_key(price)) {
+            changes.push(LevelChange::Removed {
+                side: side.clone(),
+                price: *price,
+            });
+        }
+    }
+    changes
+}
@@ -0,0 +1,366 @@
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::OrderbookError, Orderbook, OrderbookEvent, TokenPair};
+
+const GLOBAL_TOPIC: &str = "global";
+
+/// WebSocket topic on which an [`OrderbookEvent`] is published
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Topic {
+    /// Events about a user's balances, and failures of the user's transactions.
+    /// The topic is the user identity itself.
+    User(String),
+    /// Order events of a pair, formatted as `"{base}-{quote}"`
+    Pair(TokenPair),
+    /// Events concerning every user, such as maker rewards distributions
+    Global,
+}
+
+impl Topic {
+    pub fn user(user: impl Into<String>) -> Self {
+        Topic::User(user.into())
+    }
+
+    pub fn pair(pair: &TokenPair) -> Self {
+        Topic::Pair(pair.clone())
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Topic::User(user) => write!(f, "{user}"),
+            Topic::Pair((base, quote)) => write!(f, "{base}-{quote}"),
+            Topic::Global => write!(f, "{GLOBAL_TOPIC}"),
+        }
+    }
+}
+
+impl FromStr for Topic {
+    type Err = std::convert::Infallible;
+
+    /// Identities always contain an `@`, so anything else with a `-` is a pair topic
+    fn from_str(topic: &str) -> Result<Self, Self::Err> {
+        if topic == GLOBAL_TOPIC {
+            return Ok(Topic::Global);
+        }
+        if !topic.contains('@') {
+            if let Some((base, quote)) = topic.split_once('-') {
+                return Ok(Topic::Pair((base.to_string(), quote.to_string())));
+            }
+        }
+        Ok(Topic::User(topic.to_string()))
+    }
+}
+
+impl OrderbookEvent {
+    /// Topic the event is published on
+    pub fn topic(&self) -> Topic {
+        match self {
+            OrderbookEvent::BalanceUpdated { user, .. }
+            | OrderbookEvent::FeeCharged { user, .. }
+            | OrderbookEvent::UserRegistered { user }
+            | OrderbookEvent::ReferrerRegistered { user, .. }
+            | OrderbookEvent::ReferralRewarded { referrer: user, .. }
+            | OrderbookEvent::WithdrawalClaimed { user, .. }
+            | OrderbookEvent::WithdrawalCancelled { user, .. } => Topic::user(user.clone()),
+            OrderbookEvent::WithdrawalRequested { withdrawal, .. } => {
+                Topic::user(withdrawal.owner.clone())
+            }
+            OrderbookEvent::OrderCreated { order } | OrderbookEvent::StopOrderPlaced { order } => {
+                Topic::pair(&order.pair)
+            }
+            OrderbookEvent::OrderCancelled { pair, .. }
+            | OrderbookEvent::StopOrderTriggered { pair, .. }
+            | OrderbookEvent::TradeExecuted { pair, .. }
+            | OrderbookEvent::OrderExecuted { pair, .. }
+            | OrderbookEvent::OrderUpdate { pair, .. }
+            | OrderbookEvent::BookHash { pair, .. }
+            | OrderbookEvent::MarketConfigured { pair, .. }
+            | OrderbookEvent::PairCreated { pair }
+            | OrderbookEvent::PairDelisted { pair }
+            | OrderbookEvent::OrdersLinked { pair, .. }
+            | OrderbookEvent::AuctionCleared { pair, .. }
+            | OrderbookEvent::LiquidityRewardsDistributed { pair, .. } => Topic::pair(pair),
+            OrderbookEvent::MarketHalted { pair } | OrderbookEvent::MarketResumed { pair } => {
+                pair.as_ref().map_or(Topic::Global, Topic::pair)
+            }
+            OrderbookEvent::MakerRewardsDistributed { .. }
+            | OrderbookEvent::TokenConfigured { .. }
+            | OrderbookEvent::TokenAdded { .. }
+            | OrderbookEvent::TokenRemoved { .. }
+            | OrderbookEvent::LaneAdded { .. }
+            | OrderbookEvent::LaneRemoved { .. }
+            | OrderbookEvent::DustThresholdUpdated { .. }
+            | OrderbookEvent::DustSwept { .. }
+            | OrderbookEvent::FeeScheduleUpdated { .. } => Topic::Global,
+        }
+    }
+}
+
+/// Event published to WebSocket clients, with its sequence number. Sequence numbers grow by one
+/// with each event of the orderbook, see [`Orderbook::event_seq`], so that clients can detect the
+/// events they missed and fetch the state again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SequencedEvent {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: OrderbookEvent,
+}
+
+/// Numbers `events`, the last events emitted by `state`
+pub fn sequence_events(state: &Orderbook, events: Vec<OrderbookEvent>) -> Vec<SequencedEvent> {
+    let first = state.event_seq().saturating_sub(events.len() as u64) + 1;
+    (first..)
+        .zip(events)
+        .map(|(seq, event)| SequencedEvent { seq, event })
+        .collect()
+}
+
+/// Decodes the events emitted by the contract from the `program_outputs` of a `HyleOutput`
+pub fn decode_events(program_outputs: &[u8]) -> anyhow::Result<Vec<OrderbookEvent>> {
+    borsh::from_slice(program_outputs).context("Failed to decode OrderbookEvents")
+}
+
+/// Decodes the events of a successful execution, or returns the contract's error, an
+/// [`OrderbookError`] when the contract reported one
+pub fn decode_execution_result(
+    success: bool,
+    program_outputs: &[u8],
+) -> anyhow::Result<Vec<OrderbookEvent>> {
+    if success {
+        decode_events(program_outputs)
+    } else if let Some(error) = OrderbookError::decode(program_outputs) {
+        Err(anyhow::Error::new(error))
+    } else {
+        Err(anyhow!(
+            "{}",
+            String::from_utf8_lossy(program_outputs).into_owned()
+        ))
+    }
+}
+
+/// Events bringing a client that followed `old` up to date with `new`.
+///
+/// Used when the optimistic state is rolled back: orders that disappeared are sent as cancelled,
+/// new ones as created, changed books with their new hash, and every changed balance with its
+/// new amount.
+pub fn correction_events(old: &Orderbook, new: &Orderbook) -> Vec<OrderbookEvent> {
+    let mut events = vec![];
+
+    for (order_id, order) in &old.orders {
+        match new.orders.get(order_id) {
+            None => events.push(OrderbookEvent::OrderCancelled {
+                order_id: order_id.clone(),
+                pair: order.pair.clone(),
+            }),
+            Some(new_order) if new_order.quantity != order.quantity => {
+                events.push(OrderbookEvent::OrderUpdate {
+                    order_id: order_id.clone(),
+                    remaining_quantity: new_order.quantity,
+                    pair: new_order.pair.clone(),
+                    executed_price: None,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    for (order_id, order) in &new.orders {
+        if !old.orders.contains_key(order_id) {
+            events.push(OrderbookEvent::OrderCreated {
+                order: order.clone(),
+            });
+        }
+    }
+
+    let pairs: BTreeSet<&TokenPair> = [old, new]
+        .into_iter()
+        .flat_map(|state| state.buy_orders.keys().chain(state.sell_orders.keys()))
+        .collect();
+    for pair in pairs {
+        let hash = new.book_hash(pair);
+        if hash != old.book_hash(pair) || new.book_seq(pair) != old.book_seq(pair) {
+            events.push(OrderbookEvent::BookHash {
+                pair: pair.clone(),
+                hash,
+                seq: new.book_seq(pair),
+            });
+        }
+    }
+
+    let balance = |state: &Orderbook, user: &str, token: &str| {
+        state
+            .balances
+            .get(user)
+            .and_then(|balances| balances.get(token))
+            .copied()
+            .unwrap_or_default()
+    };
+    let accounts: BTreeSet<(&String, &String)> = old
+        .balances
+        .iter()
+        .chain(new.balances.iter())
+        .flat_map(|(user, balances)| balances.keys().map(move |token| (user, token)))
+        .collect();
+    for (user, token) in accounts {
+        let amount = balance(new, user, token);
+        if balance(old, user, token) != amount {
+            events.push(OrderbookEvent::BalanceUpdated {
+                user: user.clone(),
+                token: token.clone(),
+                amount,
+            });
+        }
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Order, OrderStatus, OrderType};
+
+    fn pair() -> TokenPair {
+        ("ETH".to_string(), "USD".to_string())
+    }
+
+    #[test_log::test]
+    fn test_topic_format() {
+        assert_eq!(Topic::pair(&pair()).to_string(), "ETH-USD");
+        assert_eq!(Topic::user("alice@wallet").to_string(), "alice@wallet");
+
+        assert_eq!(Topic::Global.to_string(), "global");
+
+        assert_eq!("ETH-USD".parse::<Topic>().unwrap(), Topic::pair(&pair()));
+        assert_eq!("global".parse::<Topic>().unwrap(), Topic::Global);
+        assert_eq!(
+            "alice-bob@wallet".parse::<Topic>().unwrap(),
+            Topic::user("alice-bob@wallet")
+        );
+    }
+
+    #[test_log::test]
+    fn test_event_topics() {
+        let order = Order {
+            owner: "alice@wallet".to_string(),
+            order_id: "order1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(2000),
+            trigger_price: None,
+            pair: pair(),
+            quantity: 1,
+            timestamp: sdk::hyle_model_utils::TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        let events = vec![
+            OrderbookEvent::OrderCreated { order },
+            OrderbookEvent::BalanceUpdated {
+                user: "alice@wallet".to_string(),
+                token: "USD".to_string(),
+                amount: 0,
+            },
+        ];
+
+        let decoded = decode_events(&borsh::to_vec(&events).unwrap()).unwrap();
+        let topics: Vec<String> = decoded.iter().map(|e| e.topic().to_string()).collect();
+        assert_eq!(topics, vec!["ETH-USD", "alice@wallet"]);
+
+        let err = decode_execution_result(false, b"Insufficient balance").unwrap_err();
+        assert_eq!(err.to_string(), "Insufficient balance");
+        let error = OrderbookError::OrderNotFound {
+            order_id: "order1".to_string(),
+        };
+        let err = decode_execution_result(false, error.encode().as_bytes()).unwrap_err();
+        assert_eq!(err.downcast_ref::<OrderbookError>(), Some(&error));
+    }
+
+    #[test_log::test]
+    fn test_sequenced_events() {
+        let mut state = Orderbook::init(Default::default(), "admin@orderbook".to_string());
+        state.event_seq = 5;
+        let events = vec![
+            OrderbookEvent::UserRegistered {
+                user: "alice@wallet".to_string(),
+            },
+            OrderbookEvent::UserRegistered {
+                user: "bob@wallet".to_string(),
+            },
+        ];
+
+        let sequenced = sequence_events(&state, events);
+        let seqs: Vec<u64> = sequenced.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, vec![4, 5]);
+        // Clients still find the event under its variant name
+        assert_eq!(
+            serde_json::to_string(&sequenced[0]).unwrap(),
+            r#"{"seq":4,"UserRegistered":{"user":"alice@wallet"}}"#
+        );
+    }
+
+    #[test_log::test]
+    fn test_correction_events() {
+        let mut old = Orderbook::init(Default::default(), "admin@orderbook".to_string());
+        old.balances.insert(
+            "alice@wallet".to_string(),
+            [("USD".to_string(), 10), ("ETH".to_string(), 1)].into(),
+        );
+        let order = Order {
+            owner: "alice@wallet".to_string(),
+            order_id: "order1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: pair(),
+            quantity: 2,
+            timestamp: sdk::hyle_model_utils::TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        old.orders.insert("order1".to_string(), order.clone());
+
+        let mut new = old.clone();
+        new.orders.remove("order1");
+        new.orders.insert(
+            "order2".to_string(),
+            Order {
+                order_id: "order2".to_string(),
+                ..order
+            },
+        );
+        new.balances
+            .get_mut("alice@wallet")
+            .unwrap()
+            .insert("USD".to_string(), 0);
+
+        let events = correction_events(&old, &new);
+        assert_eq!(events.len(), 3);
+        assert!(matches!(
+            &events[0],
+            OrderbookEvent::OrderCancelled { order_id, .. } if order_id == "order1"
+        ));
+        assert!(matches!(
+            &events[1],
+            OrderbookEvent::OrderCreated { order } if order.order_id == "order2"
+        ));
+        assert!(matches!(
+            &events[2],
+            OrderbookEvent::BalanceUpdated { user, token, amount }
+                if user == "alice@wallet" && token == "USD" && *amount == 0
+        ));
+
+        assert!(correction_events(&new, &new).is_empty());
+    }
+}
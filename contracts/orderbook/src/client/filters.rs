@@ -0,0 +1,427 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use anyhow::{anyhow, bail};
+
+use crate::{client::events::Topic, Orderbook, OrderbookAction, OrderbookEvent};
+
+const FILTER_SEPARATOR: char = '|';
+
+/// Restricts the events published on a topic, so that clients only receive the significant ones.
+///
+/// Each criterion only applies to the events it concerns, e.g. `min_fill` does not drop orders
+/// being created.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SubscriptionFilter {
+    /// Drops fills of a smaller quantity
+    pub min_fill: Option<u128>,
+    /// Drops order and balance events of other users
+    pub owner: Option<String>,
+    /// Drops order events priced more than this percentage away from the mid price
+    pub within_pct: Option<u32>,
+}
+
+/// A topic published with a filter, formatted as `"{topic}|{key}={value},..."`,
+/// e.g. `"ETH-USD|min_fill=10,within_pct=5"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilteredTopic {
+    pub topic: Topic,
+    pub filter: SubscriptionFilter,
+}
+
+/// What filters need to know about an event, but the event does not carry
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EventDetails {
+    /// Owner of the order concerned
+    pub owner: Option<String>,
+    /// Limit price of the order concerned, or price of the trade
+    pub price: Option<u128>,
+    /// Quantity filled, for order executions, updates and trades
+    pub fill: Option<u128>,
+}
+
+impl fmt::Display for SubscriptionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut criteria = vec![];
+        if let Some(min_fill) = self.min_fill {
+            criteria.push(format!("min_fill={min_fill}"));
+        }
+        if let Some(owner) = &self.owner {
+            criteria.push(format!("owner={owner}"));
+        }
+        if let Some(within_pct) = self.within_pct {
+            criteria.push(format!("within_pct={within_pct}"));
+        }
+        write!(f, "{}", criteria.join(","))
+    }
+}
+
+fn number<T: FromStr>(key: &str, value: &str) -> anyhow::Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid value '{}' for filter '{}'", value, key))
+}
+
+impl FromStr for SubscriptionFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(filter: &str) -> Result<Self, Self::Err> {
+        let mut parsed = SubscriptionFilter::default();
+        for criterion in filter.split(',') {
+            let (key, value) = criterion
+                .split_once('=')
+                .ok_or(anyhow!("Invalid filter criterion '{}'", criterion))?;
+            let duplicate = match key {
+                "min_fill" => parsed.min_fill.replace(number(key, value)?).is_some(),
+                "owner" => parsed.owner.replace(value.to_string()).is_some(),
+                "within_pct" => parsed.within_pct.replace(number(key, value)?).is_some(),
+                _ => bail!("Unknown filter '{}'", key),
+            };
+            if duplicate {
+                bail!("Filter '{}' is set twice", key);
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for FilteredTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{FILTER_SEPARATOR}{}", self.topic, self.filter)
+    }
+}
+
+impl FromStr for FilteredTopic {
+    type Err = anyhow::Error;
+
+    fn from_str(topic: &str) -> Result<Self, Self::Err> {
+        let (topic, filter) = topic
+            .split_once(FILTER_SEPARATOR)
+            .ok_or(anyhow!("Topic '{}' has no filter", topic))?;
+        Ok(FilteredTopic {
+            topic: topic.parse()?,
+            filter: filter.parse()?,
+        })
+    }
+}
+
+impl SubscriptionFilter {
+    /// Whether `event` is published on the filtered topic. `mid` is the mid price of the
+    /// event's pair, if both sides of the book are quoted.
+    pub fn matches(
+        &self,
+        event: &OrderbookEvent,
+        details: &EventDetails,
+        mid: Option<u128>,
+    ) -> bool {
+        match event {
+            OrderbookEvent::BalanceUpdated { user, .. }
+            | OrderbookEvent::FeeCharged { user, .. }
+            | OrderbookEvent::UserRegistered { user }
+            | OrderbookEvent::ReferrerRegistered { user, .. }
+            | OrderbookEvent::ReferralRewarded { referrer: user, .. }
+            | OrderbookEvent::WithdrawalClaimed { user, .. }
+            | OrderbookEvent::WithdrawalCancelled { user, .. } => {
+                return !matches!(&self.owner, Some(owner) if owner != user)
+            }
+            OrderbookEvent::WithdrawalRequested { withdrawal, .. } => {
+                return !matches!(&self.owner, Some(owner) if owner != &withdrawal.owner)
+            }
+            // A filtered stream cannot rebuild the book the hash commits to
+            OrderbookEvent::BookHash { .. } => return false,
+            OrderbookEvent::MakerRewardsDistributed { .. }
+            | OrderbookEvent::LiquidityRewardsDistributed { .. }
+            | OrderbookEvent::MarketConfigured { .. }
+            | OrderbookEvent::TokenConfigured { .. }
+            | OrderbookEvent::FeeScheduleUpdated { .. }
+            | OrderbookEvent::TokenAdded { .. }
+            | OrderbookEvent::TokenRemoved { .. }
+            | OrderbookEvent::LaneAdded { .. }
+            | OrderbookEvent::LaneRemoved { .. }
+            | OrderbookEvent::DustThresholdUpdated { .. }
+            | OrderbookEvent::DustSwept { .. }
+            | OrderbookEvent::PairCreated { .. }
+            | OrderbookEvent::PairDelisted { .. }
+            | OrderbookEvent::MarketHalted { .. }
+            | OrderbookEvent::MarketResumed { .. }
+            | OrderbookEvent::AuctionCleared { .. } => return true,
+            // Trades concern the owners of both of their orders
+            OrderbookEvent::TradeExecuted { maker, taker, .. } => {
+                if matches!(&self.owner, Some(owner) if owner != maker && owner != taker) {
+                    return false;
+                }
+                return self.matches_fill_and_price(details, mid);
+            }
+            OrderbookEvent::OrderCreated { .. }
+            | OrderbookEvent::StopOrderPlaced { .. }
+            | OrderbookEvent::StopOrderTriggered { .. }
+            | OrderbookEvent::OrderCancelled { .. }
+            | OrderbookEvent::OrderExecuted { .. }
+            | OrderbookEvent::OrderUpdate { .. }
+            | OrderbookEvent::OrdersLinked { .. } => {}
+        }
+
+        if let Some(owner) = &self.owner {
+            if details.owner.as_ref() != Some(owner) {
+                return false;
+            }
+        }
+        self.matches_fill_and_price(details, mid)
+    }
+
+    fn matches_fill_and_price(&self, details: &EventDetails, mid: Option<u128>) -> bool {
+        if let (Some(min_fill), Some(fill)) = (self.min_fill, details.fill) {
+            if fill < min_fill {
+                return false;
+            }
+        }
+        if let (Some(within_pct), Some(mid), Some(price)) = (self.within_pct, mid, details.price) {
+            if price.abs_diff(mid).saturating_mul(100) > mid.saturating_mul(within_pct as u128) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Details of each of the `events` emitted by `action` of `sender`, which brought the
+/// optimistic state from `before` to `after`.
+///
+/// Orders are looked up in both states; the only orders in neither of them are the ones
+/// `action` created and that got filled right away.
+pub fn event_details(
+    before: &Orderbook,
+    after: &Orderbook,
+    sender: &str,
+    action: &OrderbookAction,
+    events: &[OrderbookEvent],
+) -> Vec<EventDetails> {
+    // Owner, price and remaining quantity of the orders, as the events go
+    let mut orders: BTreeMap<String, (String, Option<u128>, u128)> = BTreeMap::new();
+    let lookup = |order_id: &str| {
+        let order = before
+            .orders
+            .get(order_id)
+            .or(after.orders.get(order_id))
+            .or(before.stop_order(order_id));
+        if let Some(order) = order {
+            return Some((order.owner.clone(), order.price, order.quantity));
+        }
+        let mut next_order_seq = before.next_order_seq;
+        created_order(action, order_id, &mut next_order_seq)
+            .map(|(price, quantity)| (sender.to_string(), price, quantity))
+    };
+
+    let mut details = vec![];
+    for event in events {
+        let (order_id, remaining) = match event {
+            OrderbookEvent::OrderCreated { order } | OrderbookEvent::StopOrderPlaced { order } => {
+                orders.insert(
+                    order.order_id.clone(),
+                    (order.owner.clone(), order.price, order.quantity),
+                );
+                details.push(EventDetails {
+                    owner: Some(order.owner.clone()),
+                    price: order.price,
+                    fill: None,
+                });
+                continue;
+            }
+            OrderbookEvent::TradeExecuted {
+                price, quantity, ..
+            } => {
+                details.push(EventDetails {
+                    owner: None,
+                    price: Some(*price),
+                    fill: Some(*quantity),
+                });
+                continue;
+            }
+            OrderbookEvent::OrderCancelled { order_id, .. }
+            | OrderbookEvent::StopOrderTriggered { order_id, .. }
+            | OrderbookEvent::OrdersLinked { order_id, .. } => (order_id, None),
+            OrderbookEvent::OrderExecuted { order_id, .. } => (order_id, Some(0)),
+            OrderbookEvent::OrderUpdate {
+                order_id,
+                remaining_quantity,
+                ..
+            } => (order_id, Some(*remaining_quantity)),
+            _ => {
+                details.push(EventDetails::default());
+                continue;
+            }
+        };
+
+        let Some((owner, price, quantity)) =
+            orders.get(order_id).cloned().or_else(|| lookup(order_id))
+        else {
+            details.push(EventDetails::default());
+            continue;
+        };
+        let fill = remaining.map(|remaining| quantity.saturating_sub(remaining));
+        orders.insert(
+            order_id.clone(),
+            (owner.clone(), price, remaining.unwrap_or(quantity)),
+        );
+        details.push(EventDetails {
+            owner: Some(owner),
+            price,
+            fill,
+        });
+    }
+    details
+}
+
+/// Price and quantity of the order `order_id`, if `action` creates it. `next_order_seq` numbers
+/// the orders `action` creates.
+fn created_order(
+    action: &OrderbookAction,
+    order_id: &str,
+    next_order_seq: &mut u64,
+) -> Option<(Option<u128>, u128)> {
+    match action {
+        OrderbookAction::CreateOrder {
+            pair,
+            price,
+            quantity,
+            ..
+        } => {
+            let created = Orderbook::order_id(pair, *next_order_seq);
+            *next_order_seq += 1;
+            (created == order_id).then_some((*price, *quantity))
+        }
+        OrderbookAction::Batch(actions) => actions
+            .iter()
+            .find_map(|action| created_order(action, order_id, next_order_seq)),
+        OrderbookAction::OneCancelsOther { first, second } => [first, second]
+            .into_iter()
+            .find_map(|action| created_order(action, order_id, next_order_seq)),
+        OrderbookAction::SubmitSignedOrder { order, .. } => {
+            created_order(order, order_id, next_order_seq)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Order, OrderStatus, OrderType, TimeInForce, TokenPair};
+    use sdk::hyle_model_utils::TimestampMs;
+
+    fn pair() -> TokenPair {
+        ("ETH".to_string(), "USD".to_string())
+    }
+
+    #[test_log::test]
+    fn test_filtered_topic_format() {
+        let topic: FilteredTopic = "ETH-USD|min_fill=10,owner=alice@wallet,within_pct=5"
+            .parse()
+            .unwrap();
+        assert_eq!(topic.topic, Topic::pair(&pair()));
+        assert_eq!(
+            topic.filter,
+            SubscriptionFilter {
+                min_fill: Some(10),
+                owner: Some("alice@wallet".to_string()),
+                within_pct: Some(5),
+            }
+        );
+        assert_eq!(
+            topic.to_string(),
+            "ETH-USD|min_fill=10,owner=alice@wallet,within_pct=5"
+        );
+
+        assert!("ETH-USD".parse::<FilteredTopic>().is_err());
+        assert!("ETH-USD|".parse::<FilteredTopic>().is_err());
+        assert!("ETH-USD|size=10".parse::<FilteredTopic>().is_err());
+        assert!("ETH-USD|min_fill=ten".parse::<FilteredTopic>().is_err());
+        assert!("ETH-USD|min_fill=1,min_fill=2"
+            .parse::<FilteredTopic>()
+            .is_err());
+    }
+
+    #[test_log::test]
+    fn test_filter_fills() {
+        let mut before = Orderbook::init(Default::default(), "admin@orderbook".to_string());
+        let resting = Order {
+            owner: "alice@wallet".to_string(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: pair(),
+            quantity: 5,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        before.orders.insert("sell1".to_string(), resting.clone());
+        let mut after = before.clone();
+        after.orders.get_mut("sell1").unwrap().quantity = 3;
+
+        // Bob's market order of 2 is filled right away by alice's order
+        let action = OrderbookAction::CreateOrder {
+            order_type: OrderType::Buy,
+            price: None,
+            pair: pair(),
+            quantity: 2,
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            trigger_price: None,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
+        };
+        let events = vec![
+            OrderbookEvent::OrderUpdate {
+                order_id: "sell1".to_string(),
+                remaining_quantity: 3,
+                pair: pair(),
+                executed_price: Some(2000),
+            },
+            OrderbookEvent::OrderExecuted {
+                order_id: Orderbook::order_id(&pair(), 0),
+                pair: pair(),
+                executed_price: Some(2000),
+            },
+            OrderbookEvent::BookHash {
+                pair: pair(),
+                hash: Default::default(),
+                seq: 1,
+            },
+        ];
+        let details = event_details(&before, &after, "bob@wallet", &action, &events);
+        assert_eq!(
+            details[..2],
+            [
+                EventDetails {
+                    owner: Some("alice@wallet".to_string()),
+                    price: Some(2000),
+                    fill: Some(2),
+                },
+                EventDetails {
+                    owner: Some("bob@wallet".to_string()),
+                    price: None,
+                    fill: Some(2),
+                },
+            ]
+        );
+
+        let published = |filter: &str, mid: Option<u128>| -> Vec<usize> {
+            let filter: SubscriptionFilter = filter.parse().unwrap();
+            (0..events.len())
+                .filter(|i| filter.matches(&events[*i], &details[*i], mid))
+                .collect()
+        };
+        assert_eq!(published("min_fill=2", None), vec![0, 1]);
+        assert!(published("min_fill=3", None).is_empty());
+        assert_eq!(published("owner=bob@wallet", None), vec![1]);
+        // The market order has no price
+        assert_eq!(published("within_pct=5", Some(2200)), vec![1]);
+        assert_eq!(published("within_pct=10", Some(2200)), vec![0, 1]);
+        assert_eq!(published("within_pct=5", None), vec![0, 1]);
+    }
+}
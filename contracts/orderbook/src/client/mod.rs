@@ -1 +1,11 @@
+pub mod activity;
+pub mod alerts;
+pub mod candles;
+pub mod channels;
+pub mod depth;
+pub mod events;
+pub mod filters;
+pub mod shards;
+pub mod state_diff;
+pub mod tx_builder;
 pub mod tx_executor_handler;
@@ -0,0 +1,365 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::bail;
+use sdk::{BlobTransaction, ContractName};
+
+use crate::{Orderbook, OrderbookAction, TokenPair};
+
+/// Orderbook deployed as several contract instances, each trading its own pairs, so that the
+/// state and the proving cost of an instance only grow with its pairs.
+///
+/// At most one instance is assigned no pair: it trades all the pairs no other instance trades.
+/// Balances are held per instance, users deposit on the instance of the pairs they trade.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderbookShards {
+    shards: BTreeMap<ContractName, BTreeSet<TokenPair>>,
+}
+
+impl OrderbookShards {
+    /// A single instance trading every pair
+    pub fn single(contract_name: ContractName) -> Self {
+        OrderbookShards {
+            shards: BTreeMap::from([(contract_name, BTreeSet::new())]),
+        }
+    }
+
+    pub fn new(shards: BTreeMap<ContractName, BTreeSet<TokenPair>>) -> anyhow::Result<Self> {
+        if shards.is_empty() {
+            bail!("The orderbook needs at least one contract instance");
+        }
+        let catch_all: Vec<&ContractName> = shards
+            .iter()
+            .filter(|(_, pairs)| pairs.is_empty())
+            .map(|(contract_name, _)| contract_name)
+            .collect();
+        if catch_all.len() > 1 {
+            bail!(
+                "Orderbook instances {} and {} both trade every pair",
+                catch_all[0],
+                catch_all[1]
+            );
+        }
+        let mut owners: BTreeMap<&TokenPair, &ContractName> = BTreeMap::new();
+        for (contract_name, pairs) in &shards {
+            for pair in pairs {
+                if let Some(owner) = owners.insert(pair, contract_name) {
+                    bail!(
+                        "Pair {}-{} is traded by both {} and {}",
+                        pair.0,
+                        pair.1,
+                        owner,
+                        contract_name
+                    );
+                }
+            }
+        }
+        Ok(OrderbookShards { shards })
+    }
+
+    pub fn contract_names(&self) -> impl Iterator<Item = &ContractName> {
+        self.shards.keys()
+    }
+
+    pub fn contains(&self, contract_name: &ContractName) -> bool {
+        self.shards.contains_key(contract_name)
+    }
+
+    /// Instance trading the pairs no other instance trades, or the first one if all instances
+    /// are assigned pairs
+    pub fn main_contract(&self) -> &ContractName {
+        self.shards
+            .iter()
+            .find(|(_, pairs)| pairs.is_empty())
+            .or_else(|| self.shards.first_key_value())
+            .map(|(contract_name, _)| contract_name)
+            .expect("The orderbook has at least one contract instance")
+    }
+
+    /// Instance trading `pair`, if any
+    pub fn contract_for_pair(&self, pair: &TokenPair) -> Option<&ContractName> {
+        self.shards
+            .iter()
+            .find(|(_, pairs)| pairs.contains(pair))
+            .or_else(|| self.shards.iter().find(|(_, pairs)| pairs.is_empty()))
+            .map(|(contract_name, _)| contract_name)
+    }
+
+    /// Initial state of each instance, restricted to its pairs
+    pub fn initial_states(&self, initial_state: &Orderbook) -> BTreeMap<ContractName, Orderbook> {
+        self.shards
+            .iter()
+            .map(|(contract_name, pairs)| {
+                (
+                    contract_name.clone(),
+                    initial_state.clone().with_pairs(pairs.clone()),
+                )
+            })
+            .collect()
+    }
+
+    /// Instance `action` must be sent to, given the current `states` of the instances.
+    ///
+    /// Actions on accounts (deposits, withdrawals, registrations...) are not bound to a pair:
+    /// they go to the instance the user trades on, and None is returned.
+    pub fn route(
+        &self,
+        action: &OrderbookAction,
+        states: &BTreeMap<ContractName, Orderbook>,
+    ) -> Option<&ContractName> {
+        match action {
+            OrderbookAction::CreateOrder { pair, .. }
+            | OrderbookAction::ConfigureMarket { pair, .. }
+            | OrderbookAction::PruneExpired { pair }
+            | OrderbookAction::RunAuction { pair }
+            | OrderbookAction::CreatePair { pair }
+            | OrderbookAction::DelistPair { pair }
+            | OrderbookAction::DistributeIncentives { pair, .. }
+            | OrderbookAction::SetHalted {
+                pair: Some(pair), ..
+            } => self.contract_for_pair(pair),
+            OrderbookAction::Cancel { order_id }
+            | OrderbookAction::ModifyOrder { order_id, .. } => {
+                self.contract_names().find(|contract_name| {
+                    states
+                        .get(*contract_name)
+                        .is_some_and(|state| state.orders.contains_key(order_id))
+                })
+            }
+            // All the actions bound to a pair must go to the same instance
+            OrderbookAction::Batch(actions) => {
+                let mut contract_names = actions
+                    .iter()
+                    .filter_map(|action| self.route(action, states));
+                let contract_name = contract_names.next()?;
+                contract_names
+                    .all(|other| other == contract_name)
+                    .then_some(contract_name)
+            }
+            OrderbookAction::OneCancelsOther { first, second } => {
+                let contract_name = self.route(first, states)?;
+                (self.route(second, states)? == contract_name).then_some(contract_name)
+            }
+            OrderbookAction::SubmitSignedOrder { order, .. } => self.route(order, states),
+            OrderbookAction::Deposit { .. }
+            | OrderbookAction::DepositFromWallet { .. }
+            | OrderbookAction::Withdraw { .. }
+            | OrderbookAction::RequestWithdraw { .. }
+            | OrderbookAction::ClaimWithdraw { .. }
+            | OrderbookAction::CancelWithdraw { .. }
+            | OrderbookAction::DistributeMakerRewards { .. }
+            | OrderbookAction::FundIncentives { .. }
+            | OrderbookAction::ConfigureToken { .. }
+            | OrderbookAction::SetFeeSchedule { .. }
+            | OrderbookAction::WithdrawFees { .. }
+            | OrderbookAction::FundInsurance { .. }
+            | OrderbookAction::SetDustThreshold { .. }
+            | OrderbookAction::SweepDust { .. }
+            | OrderbookAction::AddLane { .. }
+            | OrderbookAction::RemoveLane { .. }
+            | OrderbookAction::AddToken { .. }
+            | OrderbookAction::RemoveToken { .. }
+            | OrderbookAction::SetHalted { pair: None, .. }
+            | OrderbookAction::CloseAccount
+            | OrderbookAction::Register { .. }
+            | OrderbookAction::RegisterReferrer { .. } => None,
+        }
+    }
+
+    /// Instance a transaction was sent to
+    pub fn contract_of_tx(&self, tx: &BlobTransaction) -> Option<&ContractName> {
+        tx.blobs
+            .iter()
+            .find_map(|blob| self.shards.get_key_value(&blob.contract_name))
+            .map(|(contract_name, _)| contract_name)
+    }
+}
+
+/// Market data of all the instances merged into a single state, for the APIs. The balances a
+/// user holds on different instances are summed, and so are its trading volumes.
+pub fn merged_state<'a>(states: impl IntoIterator<Item = &'a Orderbook>) -> Option<Orderbook> {
+    let mut states = states.into_iter();
+    let mut merged = states.next()?.clone();
+    for state in states {
+        for (user, balances) in &state.balances {
+            let merged_balances = merged.balances.entry(user.clone()).or_default();
+            for (token, amount) in balances {
+                let merged_amount = merged_balances.entry(token.clone()).or_default();
+                *merged_amount = merged_amount.saturating_add(*amount);
+            }
+        }
+        for (user, reserved) in &state.reserved {
+            let merged_reserved = merged.reserved.entry(user.clone()).or_default();
+            for (token, amount) in reserved {
+                let merged_amount = merged_reserved.entry(token.clone()).or_default();
+                *merged_amount = merged_amount.saturating_add(*amount);
+            }
+        }
+        merged.orders.extend(state.orders.clone());
+        merged.next_order_seq = merged.next_order_seq.max(state.next_order_seq);
+        merged.buy_orders.extend(state.buy_orders.clone());
+        merged.sell_orders.extend(state.sell_orders.clone());
+        merged.stop_orders.extend(state.stop_orders.clone());
+        merged.trade_history.extend(state.trade_history.clone());
+        for (user, closed_orders) in &state.closed_orders {
+            (merged.closed_orders.entry(user.clone()).or_default())
+                .extend(closed_orders.iter().cloned());
+        }
+        for (user, days) in &state.volumes.users {
+            let merged_days = merged.volumes.users.entry(user.clone()).or_default();
+            for (day, volume) in days {
+                let merged_volume = merged_days.entry(*day).or_default();
+                *merged_volume = merged_volume.saturating_add(*volume);
+            }
+        }
+        merged.liquidity.pairs.extend(state.liquidity.pairs.clone());
+        merged.book_seqs.extend(state.book_seqs.clone());
+        // Each instance numbers its own events: the sum grows by each event of any of them
+        merged.event_seq = merged.event_seq.saturating_add(state.event_seq);
+        merged.markets.extend(state.markets.clone());
+        merged.token_decimals.extend(state.token_decimals.clone());
+        merged.dust_thresholds.extend(state.dust_thresholds.clone());
+        merged.fee_schedule = state.fee_schedule.clone();
+        merged.registered.extend(state.registered.clone());
+        merged.referrers.extend(state.referrers.clone());
+        merged.pairs.extend(state.pairs.clone());
+        // Pairs are unrestricted if one of the instances is
+        merged.listed_pairs = (merged.listed_pairs.take())
+            .zip(state.listed_pairs.clone())
+            .map(|(mut listed_pairs, other)| {
+                listed_pairs.extend(other);
+                listed_pairs
+            });
+        merged.paused |= state.paused;
+        merged.halted_pairs.extend(state.halted_pairs.clone());
+        merged.linked_orders.extend(state.linked_orders.clone());
+        merged.auctions.extend(state.auctions.clone());
+        for (token, amount) in &state.deposits {
+            let merged_amount = merged.deposits.entry(token.clone()).or_default();
+            *merged_amount = merged_amount.saturating_add(*amount);
+        }
+        for (user, order_ids) in &state.orders_by_owner {
+            let merged_ids = merged.orders_by_owner.entry(user.clone()).or_default();
+            merged_ids.extend(order_ids.iter().cloned());
+        }
+        for (user, nonce) in &state.nonces {
+            let merged_nonce = merged.nonces.entry(user.clone()).or_default();
+            *merged_nonce = (*merged_nonce).max(*nonce);
+        }
+    }
+    Some(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Order, OrderStatus, OrderType, TimeInForce};
+
+    fn pair(base: &str) -> TokenPair {
+        (base.to_string(), "USD".to_string())
+    }
+
+    fn shards() -> OrderbookShards {
+        OrderbookShards::new(BTreeMap::from([
+            ("orderbook_eth".into(), BTreeSet::from([pair("ETH")])),
+            ("orderbook".into(), BTreeSet::new()),
+        ]))
+        .unwrap()
+    }
+
+    fn order(order_id: &str, pair: TokenPair) -> Order {
+        Order {
+            owner: "alice@wallet".to_string(),
+            order_id: order_id.to_string(),
+            order_type: OrderType::Buy,
+            price: Some(100),
+            trigger_price: None,
+            pair,
+            quantity: 1,
+            timestamp: Default::default(),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        }
+    }
+
+    #[test_log::test]
+    fn test_shards_routing() {
+        assert!(OrderbookShards::new(BTreeMap::from([
+            ("orderbook_a".into(), BTreeSet::from([pair("ETH")])),
+            ("orderbook_b".into(), BTreeSet::from([pair("ETH")])),
+        ]))
+        .is_err());
+        assert!(OrderbookShards::new(BTreeMap::from([
+            ("orderbook_a".into(), BTreeSet::new()),
+            ("orderbook_b".into(), BTreeSet::new()),
+        ]))
+        .is_err());
+
+        let shards = shards();
+        let mut states =
+            shards.initial_states(&Orderbook::init(Default::default(), "admin".to_string()));
+        assert!(states[&"orderbook_eth".into()].trades_pair(&pair("ETH")));
+        assert!(!states[&"orderbook_eth".into()].trades_pair(&pair("BTC")));
+        assert!(states[&"orderbook".into()].trades_pair(&pair("BTC")));
+
+        let create = |base: &str| OrderbookAction::CreateOrder {
+            order_type: OrderType::Buy,
+            price: Some(100),
+            pair: pair(base),
+            quantity: 1,
+            time_in_force: TimeInForce::GoodTilCancelled,
+            trigger_price: None,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
+        };
+        assert_eq!(
+            shards.route(&create("ETH"), &states),
+            Some(&"orderbook_eth".into())
+        );
+        assert_eq!(
+            shards.route(&create("BTC"), &states),
+            Some(&"orderbook".into())
+        );
+
+        states
+            .get_mut(&"orderbook".into())
+            .unwrap()
+            .orders
+            .insert("btc1".to_string(), order("btc1", pair("BTC")));
+        let cancel = |order_id: &str| OrderbookAction::Cancel {
+            order_id: order_id.to_string(),
+        };
+        assert_eq!(
+            shards.route(&cancel("btc1"), &states),
+            Some(&"orderbook".into())
+        );
+        assert_eq!(shards.route(&cancel("unknown"), &states), None);
+    }
+
+    #[test_log::test]
+    fn test_merged_state() {
+        let mut eth = Orderbook::init(Default::default(), "admin".to_string());
+        let mut btc = eth.clone();
+        eth.orders
+            .insert("eth1".to_string(), order("eth1", pair("ETH")));
+        eth.balances
+            .entry("alice@wallet".to_string())
+            .or_default()
+            .insert("USD".to_string(), 10);
+        btc.orders
+            .insert("btc1".to_string(), order("btc1", pair("BTC")));
+        btc.balances
+            .entry("alice@wallet".to_string())
+            .or_default()
+            .insert("USD".to_string(), 5);
+
+        let mut merged = merged_state([&eth, &btc]).unwrap();
+        assert_eq!(merged.orders.len(), 2);
+        assert_eq!(merged.get_balance("alice@wallet", "USD"), 15);
+        assert!(merged_state([]).is_none());
+    }
+}
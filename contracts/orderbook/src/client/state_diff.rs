@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Order, Orderbook};
+
+/// What the optimistic state of an orderbook instance has that its settled state does not yet
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct OrderbookDiff {
+    /// Orders only in the optimistic state
+    pub orders_added: Vec<Order>,
+    /// Ids of the orders only in the settled state
+    pub orders_removed: Vec<String>,
+    /// Orders of both states whose remaining quantity differs, as in the optimistic state
+    pub orders_updated: Vec<Order>,
+    /// Optimistic minus settled balance, of the users and tokens whose balance differs
+    pub balance_deltas: BTreeMap<String, BTreeMap<String, i128>>,
+}
+
+impl OrderbookDiff {
+    pub fn between(settled: &Orderbook, optimistic: &Orderbook) -> Self {
+        let mut diff = OrderbookDiff::default();
+
+        for (order_id, order) in &settled.orders {
+            match optimistic.orders.get(order_id) {
+                None => diff.orders_removed.push(order_id.clone()),
+                Some(new_order) if new_order.quantity != order.quantity => {
+                    diff.orders_updated.push(new_order.clone())
+                }
+                Some(_) => {}
+            }
+        }
+        diff.orders_added = (optimistic.orders.iter())
+            .filter(|(order_id, _)| !settled.orders.contains_key(*order_id))
+            .map(|(_, order)| order.clone())
+            .collect();
+
+        let balance = |state: &Orderbook, user: &str, token: &str| {
+            let amount = (state.balances.get(user))
+                .and_then(|balances| balances.get(token))
+                .copied()
+                .unwrap_or_default();
+            i128::try_from(amount).unwrap_or(i128::MAX)
+        };
+        let accounts: BTreeSet<(&String, &String)> = (settled.balances.iter())
+            .chain(optimistic.balances.iter())
+            .flat_map(|(user, balances)| balances.keys().map(move |token| (user, token)))
+            .collect();
+        for (user, token) in accounts {
+            let delta =
+                balance(optimistic, user, token).saturating_sub(balance(settled, user, token));
+            if delta != 0 {
+                (diff.balance_deltas.entry(user.clone()).or_default()).insert(token.clone(), delta);
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders_added.is_empty()
+            && self.orders_removed.is_empty()
+            && self.orders_updated.is_empty()
+            && self.balance_deltas.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OrderStatus, OrderType};
+
+    fn order(order_id: &str, quantity: u128) -> Order {
+        Order {
+            owner: "alice@wallet".to_string(),
+            order_id: order_id.to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity,
+            timestamp: sdk::hyle_model_utils::TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        }
+    }
+
+    #[test_log::test]
+    fn test_orderbook_diff() {
+        let mut settled = Orderbook::init(Default::default(), "admin@orderbook".to_string());
+        settled.balances.insert(
+            "alice@wallet".to_string(),
+            [("USD".to_string(), 10), ("ETH".to_string(), 3)].into(),
+        );
+        for order_id in ["order1", "order2"] {
+            settled
+                .orders
+                .insert(order_id.to_string(), order(order_id, 2));
+        }
+        assert!(OrderbookDiff::between(&settled, &settled).is_empty());
+
+        let mut optimistic = settled.clone();
+        optimistic.orders.remove("order1");
+        optimistic
+            .orders
+            .insert("order2".to_string(), order("order2", 1));
+        optimistic
+            .orders
+            .insert("order3".to_string(), order("order3", 1));
+        let balances = optimistic.balances.get_mut("alice@wallet").unwrap();
+        balances.insert("USD".to_string(), 4);
+        balances.insert("BTC".to_string(), 1);
+
+        let diff = OrderbookDiff::between(&settled, &optimistic);
+        assert_eq!(diff.orders_removed, vec!["order1"]);
+        let ids = |orders: &[Order]| -> Vec<(String, u128)> {
+            (orders.iter())
+                .map(|order| (order.order_id.clone(), order.quantity))
+                .collect()
+        };
+        assert_eq!(ids(&diff.orders_updated), vec![("order2".to_string(), 1)]);
+        assert_eq!(ids(&diff.orders_added), vec![("order3".to_string(), 1)]);
+        assert_eq!(
+            diff.balance_deltas,
+            BTreeMap::from([(
+                "alice@wallet".to_string(),
+                [("BTC".to_string(), 1), ("USD".to_string(), -6)].into()
+            )])
+        );
+    }
+}
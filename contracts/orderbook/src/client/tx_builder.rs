@@ -0,0 +1,223 @@
+use sdk::{Blob, BlobIndex, BlobTransaction, ContractName, Identity};
+
+use crate::{blobs::payout_blob, OrderbookAction};
+
+/// Authenticates the sender of an orderbook transaction, e.g. a wallet session key.
+pub trait WalletSession {
+    /// Identity the transaction is sent as (e.g. `alice@wallet`)
+    fn identity(&self) -> Identity;
+
+    /// Blobs proving the identity (secp256k1 signature, wallet blob...), in the order
+    /// the wallet contract expects them.
+    fn auth_blobs(&self) -> Vec<Blob>;
+}
+
+/// A full orderbook transaction, with the indices of the blobs it is composed of
+#[derive(Debug, Clone)]
+pub struct OrderbookTx {
+    pub tx: BlobTransaction,
+    pub transfer_blob_index: Option<BlobIndex>,
+    pub orderbook_blob_index: BlobIndex,
+}
+
+/// Builds the transactions the orderbook contract expects:
+/// `[wallet auth blobs..., token transfer blob (optional), orderbook blob]`.
+///
+/// The transfer blob of a withdrawal, paying out the tokens, is added by the builder.
+pub struct OrderbookTxBuilder {
+    orderbook_cn: ContractName,
+    action: OrderbookAction,
+    nonce: u64,
+    transfer: Option<Blob>,
+}
+
+impl OrderbookTxBuilder {
+    /// `nonce` must be above the last nonce of the sender, see [`crate::OrderbookBlob::nonce`]
+    pub fn new(orderbook_cn: ContractName, action: OrderbookAction, nonce: u64) -> Self {
+        OrderbookTxBuilder {
+            orderbook_cn,
+            action,
+            nonce,
+            transfer: None,
+        }
+    }
+
+    /// Adds the token transfer blob backing the action (e.g. for a deposit)
+    pub fn with_transfer(mut self, transfer: Blob) -> Self {
+        self.transfer = Some(transfer);
+        self
+    }
+
+    pub fn build(self, session: &impl WalletSession) -> OrderbookTx {
+        let mut blobs = session.auth_blobs();
+
+        // The payout is made on behalf of the orderbook blob, which follows it
+        let transfer = match &self.action {
+            OrderbookAction::Withdraw {
+                token,
+                amount,
+                recipient,
+            } => Some(payout_blob(
+                token.as_str().into(),
+                &self.orderbook_cn,
+                recipient
+                    .clone()
+                    .map_or_else(|| session.identity(), Identity),
+                *amount,
+                BlobIndex(blobs.len() + 1),
+            )),
+            _ => self.transfer,
+        };
+        let transfer_blob_index = transfer.map(|transfer| {
+            blobs.push(transfer);
+            BlobIndex(blobs.len() - 1)
+        });
+
+        blobs.push(self.action.as_blob(self.orderbook_cn, self.nonce));
+        let orderbook_blob_index = BlobIndex(blobs.len() - 1);
+
+        OrderbookTx {
+            tx: BlobTransaction::new(session.identity(), blobs),
+            transfer_blob_index,
+            orderbook_blob_index,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sdk::{BlobData, Calldata, LaneId, ZkContract};
+
+    use super::*;
+    use crate::{
+        blobs::{deposit_blob, CompanionBlobs},
+        Orderbook, OrderbookEvent,
+    };
+
+    struct TestSession;
+
+    impl WalletSession for TestSession {
+        fn identity(&self) -> Identity {
+            Identity("alice@wallet".to_string())
+        }
+
+        fn auth_blobs(&self) -> Vec<Blob> {
+            vec![
+                Blob {
+                    contract_name: "secp256k1".into(),
+                    data: BlobData(vec![1]),
+                },
+                Blob {
+                    contract_name: "wallet".into(),
+                    data: BlobData(vec![2]),
+                },
+            ]
+        }
+    }
+
+    fn deposit() -> OrderbookAction {
+        OrderbookAction::Deposit {
+            token: "hyllar".to_string(),
+            amount: 10,
+        }
+    }
+
+    fn transfer_blob() -> Blob {
+        deposit_blob(
+            "hyllar".into(),
+            TestSession.identity(),
+            &"orderbook".into(),
+            10,
+        )
+    }
+
+    #[test_log::test]
+    fn test_layout_without_transfer() {
+        let built = OrderbookTxBuilder::new("orderbook".into(), deposit(), 1).build(&TestSession);
+
+        assert_eq!(built.tx.identity, TestSession.identity());
+        assert_eq!(built.tx.blobs.len(), 3);
+        assert_eq!(built.tx.blobs[0].contract_name.0, "secp256k1");
+        assert_eq!(built.tx.blobs[1].contract_name.0, "wallet");
+        assert_eq!(built.transfer_blob_index, None);
+        assert_eq!(built.orderbook_blob_index, BlobIndex(2));
+
+        let orderbook_blob = &built.tx.blobs[2];
+        assert_eq!(orderbook_blob.contract_name.0, "orderbook");
+        assert_eq!(
+            orderbook_blob.data,
+            deposit().as_blob("orderbook".into(), 1).data
+        );
+    }
+
+    #[test_log::test]
+    fn test_layout_with_transfer() {
+        let built = OrderbookTxBuilder::new("orderbook".into(), deposit(), 1)
+            .with_transfer(transfer_blob())
+            .build(&TestSession);
+
+        assert_eq!(built.tx.blobs.len(), 4);
+        assert_eq!(built.tx.blobs[0].contract_name.0, "secp256k1");
+        assert_eq!(built.tx.blobs[1].contract_name.0, "wallet");
+        assert_eq!(built.transfer_blob_index, Some(BlobIndex(2)));
+        assert_eq!(built.tx.blobs[2], transfer_blob());
+        assert_eq!(built.orderbook_blob_index, BlobIndex(3));
+        assert_eq!(built.tx.blobs[3].contract_name.0, "orderbook");
+    }
+
+    #[test_log::test]
+    fn test_withdrawal_payout() {
+        let withdraw = OrderbookAction::Withdraw {
+            token: "hyllar".to_string(),
+            amount: 10,
+            recipient: None,
+        };
+        let built = OrderbookTxBuilder::new("orderbook".into(), withdraw, 1).build(&TestSession);
+
+        assert_eq!(built.transfer_blob_index, Some(BlobIndex(2)));
+        assert_eq!(built.orderbook_blob_index, BlobIndex(3));
+        let calldata = Calldata {
+            tx_hash: sdk::TxHash("tx".to_string()),
+            identity: built.tx.identity.clone(),
+            tx_blob_count: built.tx.blobs.len(),
+            blobs: built.tx.blobs.clone().into(),
+            index: built.orderbook_blob_index,
+            tx_ctx: None,
+            private_input: vec![],
+        };
+        let payout = CompanionBlobs::new(&calldata)
+            .expect_payout(&"hyllar".into(), &TestSession.identity(), 10)
+            .unwrap();
+        assert_eq!(payout.index, BlobIndex(2));
+    }
+
+    #[test_log::test]
+    fn test_built_tx_is_accepted_by_contract() {
+        let built = OrderbookTxBuilder::new("orderbook".into(), deposit(), 1)
+            .with_transfer(transfer_blob())
+            .build(&TestSession);
+
+        let calldata = Calldata {
+            tx_hash: sdk::TxHash("tx".to_string()),
+            identity: built.tx.identity.clone(),
+            tx_blob_count: built.tx.blobs.len(),
+            blobs: built.tx.blobs.clone().into(),
+            index: built.orderbook_blob_index,
+            tx_ctx: Some(sdk::TxContext {
+                lane_id: LaneId::default(),
+                ..Default::default()
+            }),
+            private_input: vec![],
+        };
+
+        let mut orderbook = Orderbook::init(LaneId::default(), "admin@orderbook".to_string());
+        let (output, _, _) = orderbook.execute(&calldata).unwrap();
+        let events: Vec<OrderbookEvent> = borsh::from_slice(&output).unwrap();
+
+        assert!(events.iter().any(|event| matches!(
+            event,
+            OrderbookEvent::BalanceUpdated { user, token, amount }
+                if user == "alice@wallet" && token == "hyllar" && *amount == 10
+        )));
+    }
+}
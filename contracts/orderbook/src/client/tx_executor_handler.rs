@@ -2,11 +2,28 @@ use anyhow::Context;
 use client_sdk::transaction_builder::TxExecutorHandler;
 use sdk::{utils::as_hyle_output, Blob, Calldata, RegisterContractEffect, ZkContract};
 
-use crate::Orderbook;
+use crate::{witness::ZkOrderbook, Orderbook, OrderbookBlob};
 
 impl TxExecutorHandler for Orderbook {
-    fn build_commitment_metadata(&self, _blob: &Blob) -> anyhow::Result<Vec<u8>> {
-        borsh::to_vec(self).context("Failed to encode Orderbook")
+    fn build_commitment_metadata(&self, blob: &Blob) -> anyhow::Result<Vec<u8>> {
+        // Blobs that cannot be parsed fail without touching the state
+        let keys = match OrderbookBlob::action(&blob.data) {
+            Some(action) => self.witness_keys(&action),
+            None => Some(Default::default()),
+        };
+        borsh::to_vec(&self.witness(keys.as_ref())).context("Failed to encode Orderbook witness")
+    }
+
+    fn merge_commitment_metadata(
+        &self,
+        initial: Vec<u8>,
+        next: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let initial: ZkOrderbook =
+            borsh::from_slice(&initial).context("Failed to decode Orderbook witness")?;
+        let next: ZkOrderbook =
+            borsh::from_slice(&next).context("Failed to decode Orderbook witness")?;
+        borsh::to_vec(&initial.merge(next)).context("Failed to encode Orderbook witness")
     }
 
     fn handle(&mut self, calldata: &Calldata) -> anyhow::Result<sdk::HyleOutput> {
@@ -21,12 +38,22 @@ impl TxExecutorHandler for Orderbook {
         ))
     }
 
+    /// The registered commitment only holds hashes, the initial state is sent as
+    /// constructor metadata.
     fn construct_state(
         register_blob: &RegisterContractEffect,
-        _metadata: &Option<Vec<u8>>,
+        metadata: &Option<Vec<u8>>,
     ) -> anyhow::Result<Self> {
-        borsh::from_slice(&register_blob.state_commitment.0)
-            .context("Failed to decode Orderbook state")
+        let metadata = metadata
+            .as_ref()
+            .context("Missing Orderbook initial state in constructor metadata")?;
+        let state: Orderbook =
+            borsh::from_slice(metadata).context("Failed to decode Orderbook state")?;
+        anyhow::ensure!(
+            state.commit() == register_blob.state_commitment,
+            "Orderbook initial state does not match the registered commitment"
+        );
+        Ok(state)
     }
 
     fn get_state_commitment(&self) -> sdk::StateCommitment {
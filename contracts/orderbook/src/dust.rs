@@ -0,0 +1,79 @@
+//! Sweeping of the balances too small to be spent. Partial fills and rounding leave users with
+//! amounts no order can use: once the admin sets the dust threshold of a token, anyone can move
+//! the balances below it to the [`DUST`] account, which also drops the empty balance entries
+//! from the state.
+
+use crate::{error::OrderbookError, Orderbook, OrderbookEvent, DUST, SYSTEM_ACCOUNTS};
+
+impl Orderbook {
+    /// Sets the balance of `token` below which a user balance is swept by
+    /// [`crate::OrderbookAction::SweepDust`]. 0 only sweeps the empty entries.
+    pub fn set_dust_threshold(
+        &mut self,
+        token: String,
+        threshold: u128,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+
+        if threshold == 0 {
+            self.dust_thresholds.remove(&token);
+        } else {
+            self.dust_thresholds.insert(token.clone(), threshold);
+        }
+        Ok(vec![OrderbookEvent::DustThresholdUpdated {
+            token,
+            threshold,
+        }])
+    }
+
+    /// Dust threshold of `token`, see [`Orderbook::set_dust_threshold`]
+    pub fn dust_threshold(&self, token: &str) -> u128 {
+        self.dust_thresholds.get(token).copied().unwrap_or_default()
+    }
+
+    /// Moves the user balances of `token` below its dust threshold to the [`DUST`] account,
+    /// and removes the empty balance entries of all tokens. The accounts of the orderbook
+    /// itself are left untouched.
+    pub fn sweep_dust(&mut self, token: String) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let threshold = self.dust_threshold(&token);
+        let swept: Vec<(String, u128)> = (self.balances.iter())
+            .filter(|(user, _)| !SYSTEM_ACCOUNTS.contains(&user.as_str()))
+            .filter_map(|(user, balances)| Some((user, *balances.get(&token)?)))
+            .filter(|(_, amount)| *amount > 0 && *amount < threshold)
+            .map(|(user, amount)| (user.clone(), amount))
+            .collect();
+
+        let mut events = vec![];
+        for (user, amount) in &swept {
+            self.transfer_tokens(user, DUST, &token, *amount)?;
+            events.push(OrderbookEvent::BalanceUpdated {
+                user: user.clone(),
+                token: token.clone(),
+                amount: 0,
+            });
+        }
+        let amount = swept.iter().map(|(_, amount)| amount).sum();
+        if amount > 0 {
+            events.push(OrderbookEvent::BalanceUpdated {
+                user: DUST.to_string(),
+                token: token.clone(),
+                amount: self.get_balance(DUST, &token),
+            });
+        }
+
+        for (user, balances) in self.balances.iter_mut() {
+            if !SYSTEM_ACCOUNTS.contains(&user.as_str()) {
+                balances.retain(|_, amount| *amount > 0);
+            }
+        }
+        self.balances.retain(|user, balances| {
+            SYSTEM_ACCOUNTS.contains(&user.as_str()) || !balances.is_empty()
+        });
+
+        events.push(OrderbookEvent::DustSwept { token, amount });
+        Ok(events)
+    }
+}
@@ -0,0 +1,696 @@
+//! Errors of the orderbook contract.
+//!
+//! A failed execution reports its [`OrderbookError`] JSON-encoded in the program outputs, see
+//! [`OrderbookError::encode`], so that the server and frontends can tell the failures apart
+//! without parsing messages.
+
+use std::fmt;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+use crate::{OrderType, TokenPair};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+pub enum OrderbookError {
+    // Transaction
+    MissingTxContext,
+    InvalidLaneId,
+    IncompleteCalldata,
+    InvalidCalldata {
+        reason: String,
+    },
+    BlobNotWhitelisted {
+        contract_name: String,
+    },
+    BlobNotFound {
+        blob: usize,
+    },
+    /// A companion blob is not what the action expects
+    InvalidBlob {
+        blob: usize,
+        reason: String,
+    },
+    MissingTransfer {
+        token: String,
+        amount: u128,
+        sender: String,
+        recipient: String,
+    },
+    /// No transfer of the tokens paid out, made on behalf of the orderbook blob
+    MissingPayout {
+        token: String,
+        amount: u128,
+        sender: String,
+        recipient: String,
+    },
+    DuplicateTransfer {
+        blob: usize,
+    },
+    NestedBatch,
+    /// The action cannot be used this way, e.g. a signed order that does not create an order
+    InvalidAction {
+        reason: String,
+    },
+    EncodingFailed,
+    InvalidSnapshot {
+        reason: String,
+    },
+    UnsupportedSnapshotVersion {
+        version: u32,
+    },
+
+    // Identity
+    NoWallet {
+        identity: String,
+    },
+    MissingWalletAuth {
+        identity: String,
+    },
+    MissingSignature {
+        identity: String,
+    },
+    MissingIdentityProof {
+        identity: String,
+    },
+    SignerMismatch {
+        identity: String,
+    },
+    InvalidOrderSignature {
+        owner: String,
+    },
+    InvalidInviteCode {
+        user: String,
+    },
+    InvalidNonce {
+        user: String,
+        nonce: u64,
+        last_nonce: u64,
+    },
+    ActionLimitReached {
+        user: String,
+        limit: u32,
+        block_height: u64,
+    },
+    /// The identity is one of the [`SYSTEM_ACCOUNTS`](crate::SYSTEM_ACCOUNTS)
+    SystemIdentity {
+        identity: String,
+    },
+
+    // Permissions
+    NotAdmin {
+        user: String,
+    },
+    NotOwner {
+        user: String,
+        order_id: String,
+    },
+    NotWithdrawalOwner {
+        user: String,
+        withdrawal_id: u64,
+    },
+
+    // Accounts
+    AccountNotFound {
+        user: String,
+    },
+    /// Funds of the system accounts are only moved from the reserves to the fee pool and dust
+    /// accounts, when orders are filled
+    SystemTransfer {
+        from: String,
+        to: String,
+        token: String,
+    },
+    InsufficientBalance {
+        user: String,
+        token: String,
+        available: u128,
+        required: u128,
+    },
+    BalanceOverflow {
+        user: String,
+        token: String,
+    },
+    DepositsOverflow {
+        token: String,
+    },
+    InsufficientReserve {
+        user: String,
+        token: String,
+        reserved: u128,
+        required: u128,
+    },
+    RegistrationRequired {
+        user: String,
+    },
+    AlreadyRegistered {
+        user: String,
+    },
+    InviteCodesDisabled,
+    /// Orders are rejected for a few blocks after a deposit of the tokens they spend
+    DepositNotSettled {
+        user: String,
+        token: String,
+        settled_at: u64,
+    },
+    NoMakerActivity {
+        epoch: u64,
+    },
+    NoLiquidityPoints {
+        pair: TokenPair,
+        epoch: u64,
+    },
+
+    // Withdrawals
+    WithdrawalNotFound {
+        withdrawal_id: u64,
+    },
+    WithdrawalNotClaimable {
+        withdrawal_id: u64,
+        claimable_at: u64,
+    },
+    WithdrawalDelayed,
+
+    // Orders
+    OrderNotFound {
+        order_id: String,
+    },
+    OrderExists {
+        order_id: String,
+    },
+    OrderExpired {
+        order_id: String,
+    },
+    /// The order breaks a rule that does not depend on the market, e.g. a stop order that is
+    /// not good-til-cancelled
+    InvalidOrder {
+        order_id: String,
+        reason: String,
+    },
+    ZeroPrice,
+    QuantityBelowMinimum {
+        order_id: String,
+        quantity: u128,
+        min_quantity: u128,
+    },
+    InvalidLotSize {
+        order_id: String,
+        quantity: u128,
+        lot_size: u128,
+    },
+    InvalidTickSize {
+        order_id: String,
+        price: u128,
+        tick_size: u128,
+    },
+    NotionalBelowMinimum {
+        order_id: String,
+        notional: u128,
+        min_notional: u128,
+    },
+    OpenOrderLimitReached {
+        user: String,
+        pair: TokenPair,
+        limit: u32,
+    },
+    /// The resting orders of `user` on `pair` would be worth `notional`, above `limit`
+    NotionalLimitExceeded {
+        user: String,
+        pair: TokenPair,
+        notional: u128,
+        limit: u128,
+    },
+    PriceOutOfBand {
+        order_id: String,
+        price: u128,
+        band_bps: u16,
+        reference: u128,
+    },
+    /// A market order found no order of `side` to trade with
+    NoLiquidity {
+        order_id: String,
+        side: OrderType,
+    },
+    FillOrKillUnfilled {
+        order_id: String,
+        available: u128,
+        quantity: u128,
+    },
+    FillOrKillSelfTrade {
+        order_id: String,
+        user: String,
+    },
+    CrossedBook {
+        pair: TokenPair,
+        best_bid: u128,
+        best_ask: u128,
+    },
+    AmountOverflow {
+        quantity: u128,
+        price: u128,
+    },
+    PriceBelow {
+        price: u128,
+        reference: u128,
+    },
+    SharesExceedAmount {
+        shares: Vec<u128>,
+        amount: u128,
+    },
+
+    // Markets
+    OrderbookHalted,
+    PairHalted {
+        pair: TokenPair,
+    },
+    /// The orderbook, or `pair`, is already halted or trading
+    HaltUnchanged {
+        pair: Option<TokenPair>,
+        halted: bool,
+    },
+    PairNotListed {
+        pair: TokenPair,
+    },
+    PairAlreadyListed {
+        pair: TokenPair,
+    },
+    PairOnOtherInstance {
+        pair: TokenPair,
+    },
+    SameTokenPair {
+        pair: TokenPair,
+    },
+    /// `pair` is traded the other way around
+    InvertedPair {
+        pair: TokenPair,
+    },
+    NotAuctionPair {
+        pair: TokenPair,
+    },
+    AuctionNotDue {
+        pair: TokenPair,
+    },
+    TokenNotAccepted {
+        token: String,
+    },
+    TokenAlreadyAccepted {
+        token: String,
+    },
+    TokenWithdrawOnly {
+        token: String,
+    },
+    LaneAlreadyAccepted {
+        lane_id: String,
+    },
+    LaneNotAccepted {
+        lane_id: String,
+    },
+    LastLane {
+        lane_id: String,
+    },
+    TokenHasOpenOrders {
+        token: String,
+    },
+    InvalidDecimals {
+        token: String,
+        decimals: u8,
+    },
+    InvalidFees {
+        maker_bps: u16,
+        taker_bps: u16,
+    },
+    FeeTiersUnordered {
+        min_volume: u128,
+    },
+    InvalidReferralShare {
+        referral_bps: u16,
+    },
+    InvalidReferrer {
+        user: String,
+        referrer: String,
+    },
+    ReferrerAlreadySet {
+        user: String,
+        referrer: String,
+    },
+}
+
+impl OrderbookError {
+    /// Program outputs of an execution that failed with this error
+    pub fn encode(&self) -> String {
+        // Serializing an enum of strings and integers cannot fail
+        serde_json::to_string(self).unwrap_or_else(|_| self.to_string())
+    }
+
+    /// Error of a failed execution from its program outputs, if the orderbook reported one
+    pub fn decode(program_outputs: &[u8]) -> Option<Self> {
+        serde_json::from_slice(program_outputs).ok()
+    }
+}
+
+impl fmt::Display for OrderbookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use OrderbookError::*;
+        match self {
+            MissingTxContext => write!(f, "tx_ctx is missing"),
+            InvalidLaneId => write!(f, "Invalid lane id"),
+            IncompleteCalldata => write!(f, "Calldata is not composed with all tx's blobs"),
+            InvalidCalldata { reason } => write!(f, "Invalid calldata: {reason}"),
+            BlobNotWhitelisted { contract_name } => {
+                write!(f, "Blob with contract name {contract_name} is not whitelisted")
+            }
+            BlobNotFound { blob } => write!(f, "Blob {blob} not found in transaction"),
+            InvalidBlob { blob, reason } => write!(f, "Blob {blob} {reason}"),
+            MissingTransfer {
+                token,
+                amount,
+                sender,
+                recipient,
+            } => write!(
+                f,
+                "No transfer of {amount} {token} from {sender} to {recipient} found in transaction"
+            ),
+            MissingPayout {
+                token,
+                amount,
+                sender,
+                recipient,
+            } => write!(
+                f,
+                "No transfer of {amount} {token} from {sender} to {recipient} called by the orderbook found in transaction"
+            ),
+            DuplicateTransfer { blob } => write!(f, "Transfer blob {blob} backs several deposits"),
+            NestedBatch => write!(f, "Batches cannot be nested"),
+            InvalidAction { reason } => write!(f, "{reason}"),
+            EncodingFailed => write!(f, "Failed to encode OrderbookEvents"),
+            InvalidSnapshot { reason } => write!(f, "Invalid orderbook snapshot: {reason}"),
+            UnsupportedSnapshotVersion { version } => {
+                write!(f, "Unsupported orderbook snapshot version {version}")
+            }
+            NoWallet { identity } => write!(f, "Identity {identity} has no wallet"),
+            SystemIdentity { identity } => {
+                write!(f, "Identity {identity} is reserved to the orderbook")
+            }
+            MissingWalletAuth { identity } => {
+                write!(f, "No blob of the wallet of {identity} found in transaction")
+            }
+            MissingSignature { identity } => {
+                write!(f, "No secp256k1 signature of {identity} found in transaction")
+            }
+            MissingIdentityProof { identity } => write!(
+                f,
+                "No wallet blob or secp256k1 signature of {identity} in transaction"
+            ),
+            SignerMismatch { identity } => {
+                write!(f, "Identity {identity} is not the key of its signature")
+            }
+            InvalidOrderSignature { owner } => write!(
+                f,
+                "Signature of the order of {owner} does not match its secp256k1 blob"
+            ),
+            InvalidInviteCode { user } => write!(
+                f,
+                "Invite code of user {user} is not signed by the orderbook invite key"
+            ),
+            InvalidNonce {
+                user,
+                nonce,
+                last_nonce,
+            } => write!(
+                f,
+                "Nonce {nonce} of user {user} is not above its last nonce {last_nonce}"
+            ),
+            ActionLimitReached {
+                user,
+                limit,
+                block_height,
+            } => write!(
+                f,
+                "User {user} reached the limit of {limit} actions in block {block_height}"
+            ),
+            NotAdmin { user } => write!(f, "User {user} is not the orderbook admin"),
+            NotOwner { user, order_id } => {
+                write!(f, "User {user} is not the owner of order {order_id}")
+            }
+            NotWithdrawalOwner {
+                user,
+                withdrawal_id,
+            } => write!(f, "Withdrawal {withdrawal_id} is not owned by {user}"),
+            AccountNotFound { user } => write!(f, "No account found for user {user}"),
+            SystemTransfer { from, to, token } => write!(
+                f,
+                "Transfers of {token} from {from} to {to} would mix the funds of the orderbook accounts"
+            ),
+            InsufficientBalance {
+                user,
+                token,
+                available,
+                required,
+            } => write!(
+                f,
+                "Insufficient balance: user {user} has {available} {token} tokens, requires {required}"
+            ),
+            BalanceOverflow { user, token } => {
+                write!(f, "Balance of user {user} in {token} tokens would overflow")
+            }
+            DepositsOverflow { token } => write!(f, "Deposits of {token} tokens would overflow"),
+            InsufficientReserve {
+                user,
+                token,
+                reserved,
+                required,
+            } => write!(
+                f,
+                "User {user} has {reserved} {token} tokens reserved, {required} are needed"
+            ),
+            RegistrationRequired { user } => write!(
+                f,
+                "User {user} must register with an invite code before depositing"
+            ),
+            AlreadyRegistered { user } => write!(f, "User {user} is already registered"),
+            InviteCodesDisabled => write!(f, "The orderbook does not require invite codes"),
+            DepositNotSettled {
+                user,
+                token,
+                settled_at,
+            } => write!(
+                f,
+                "User {user} tried to execute an order too soon after the last deposit of {token}: orders are accepted from block {settled_at}"
+            ),
+            NoMakerActivity { epoch } => {
+                write!(f, "No maker activity to reward during epoch {epoch}")
+            }
+            NoLiquidityPoints { pair, epoch } => write!(
+                f,
+                "No liquidity points to reward on pair {}-{} during epoch {epoch}",
+                pair.0, pair.1
+            ),
+            WithdrawalNotFound { withdrawal_id } => {
+                write!(f, "No pending withdrawal with id {withdrawal_id}")
+            }
+            WithdrawalNotClaimable {
+                withdrawal_id,
+                claimable_at,
+            } => write!(
+                f,
+                "Withdrawal {withdrawal_id} can only be claimed from block {claimable_at}"
+            ),
+            WithdrawalDelayed => write!(f, "Withdrawals must be requested with RequestWithdraw"),
+            OrderNotFound { order_id } => write!(f, "Order {order_id} not found"),
+            OrderExists { order_id } => write!(f, "Order with id {order_id} already exists"),
+            OrderExpired { order_id } => write!(f, "Order {order_id} expired"),
+            InvalidOrder { order_id, reason } => write!(f, "Order {order_id} {reason}"),
+            ZeroPrice => write!(f, "Price cannot be zero"),
+            QuantityBelowMinimum {
+                order_id,
+                quantity,
+                min_quantity,
+            } => write!(
+                f,
+                "Quantity {quantity} of order {order_id} is below the minimum of {min_quantity}"
+            ),
+            InvalidLotSize {
+                order_id,
+                quantity,
+                lot_size,
+            } => write!(
+                f,
+                "Quantity {quantity} of order {order_id} is not a multiple of the lot size {lot_size}"
+            ),
+            InvalidTickSize {
+                order_id,
+                price,
+                tick_size,
+            } => write!(
+                f,
+                "Price {price} of order {order_id} is not a multiple of the tick size {tick_size}"
+            ),
+            NotionalBelowMinimum {
+                order_id,
+                notional,
+                min_notional,
+            } => write!(
+                f,
+                "Order {order_id} is worth {notional}, below the minimum of {min_notional}"
+            ),
+            OpenOrderLimitReached { user, pair, limit } => write!(
+                f,
+                "User {user} already has {limit} open orders on pair {}-{}",
+                pair.0, pair.1
+            ),
+            NotionalLimitExceeded {
+                user,
+                pair,
+                notional,
+                limit,
+            } => write!(
+                f,
+                "Orders of user {user} on pair {}-{} would be worth {notional}, above the limit of {limit}",
+                pair.0, pair.1
+            ),
+            PriceOutOfBand {
+                order_id,
+                price,
+                band_bps,
+                reference,
+            } => write!(
+                f,
+                "Price {price} of order {order_id} is more than {band_bps} bps away from {reference}"
+            ),
+            NoLiquidity { order_id, side } => {
+                let side = match side {
+                    OrderType::Buy => "buy",
+                    OrderType::Sell => "sell",
+                };
+                write!(f, "No matching {side} orders for market order {order_id}")
+            }
+            FillOrKillUnfilled {
+                order_id,
+                available,
+                quantity,
+            } => write!(
+                f,
+                "Fill-or-kill order {order_id} cannot be filled entirely: {available} available out of {quantity}"
+            ),
+            FillOrKillSelfTrade { order_id, user } => write!(
+                f,
+                "Fill-or-kill order {order_id} would trade with orders of user {user}"
+            ),
+            CrossedBook {
+                pair,
+                best_bid,
+                best_ask,
+            } => write!(
+                f,
+                "Order would leave the {}/{} book crossed: best bid {best_bid} >= best ask {best_ask}",
+                pair.0, pair.1
+            ),
+            AmountOverflow { quantity, price } => {
+                write!(f, "Amount of {quantity} tokens at price {price} overflows")
+            }
+            PriceBelow { price, reference } => {
+                write!(f, "Price {price} is below price {reference}")
+            }
+            SharesExceedAmount { shares, amount } => write!(
+                f,
+                "Shares {shares:?} exceed the amount {amount} they are paid from"
+            ),
+            OrderbookHalted => write!(f, "Trading is halted on the orderbook"),
+            PairHalted { pair } => write!(f, "Trading is halted on pair {}-{}", pair.0, pair.1),
+            HaltUnchanged { pair, halted } => {
+                let state = if *halted { "halted" } else { "trading" };
+                match pair {
+                    Some(pair) => write!(f, "Pair {}-{} is already {state}", pair.0, pair.1),
+                    None => write!(f, "The orderbook is already {state}"),
+                }
+            }
+            PairNotListed { pair } => write!(f, "Pair {}-{} is not listed", pair.0, pair.1),
+            PairAlreadyListed { pair } => {
+                write!(f, "Pair {}-{} is already listed", pair.0, pair.1)
+            }
+            PairOnOtherInstance { pair } => write!(
+                f,
+                "Pair {}-{} is traded on another orderbook instance",
+                pair.0, pair.1
+            ),
+            SameTokenPair { pair } => write!(
+                f,
+                "Pair {}-{} trades a token against itself",
+                pair.0, pair.1
+            ),
+            InvertedPair { pair } => write!(
+                f,
+                "Pair {}-{} is traded as {}-{}",
+                pair.0, pair.1, pair.1, pair.0
+            ),
+            NotAuctionPair { pair } => write!(
+                f,
+                "Pair {}-{} does not trade in batch auctions",
+                pair.0, pair.1
+            ),
+            AuctionNotDue { pair } => write!(
+                f,
+                "Auction interval of pair {}-{} has not elapsed",
+                pair.0, pair.1
+            ),
+            TokenNotAccepted { token } => write!(f, "Token {token} is not accepted"),
+            TokenAlreadyAccepted { token } => write!(f, "Token {token} is already accepted"),
+            TokenWithdrawOnly { token } => {
+                write!(f, "Token {token} was removed, it can only be withdrawn")
+            }
+            LaneAlreadyAccepted { lane_id } => write!(f, "Lane {lane_id} is already accepted"),
+            LaneNotAccepted { lane_id } => write!(f, "Lane {lane_id} is not accepted"),
+            LastLane { lane_id } => {
+                write!(f, "Lane {lane_id} is the only lane of the orderbook")
+            }
+            TokenHasOpenOrders { token } => write!(f, "Token {token} has open orders"),
+            InvalidDecimals { token, decimals } => {
+                write!(f, "Token {token} cannot have {decimals} decimals")
+            }
+            InvalidFees {
+                maker_bps,
+                taker_bps,
+            } => write!(
+                f,
+                "Fees cannot exceed 10000 bps, got {maker_bps} bps for makers and {taker_bps} bps for takers"
+            ),
+            FeeTiersUnordered { min_volume } => write!(
+                f,
+                "Fee tiers must have increasing volumes, the tier of {min_volume} is out of order"
+            ),
+            InvalidReferralShare { referral_bps } => write!(
+                f,
+                "Referral share cannot exceed 10000 bps, got {referral_bps} bps"
+            ),
+            InvalidReferrer { user, referrer } => {
+                write!(f, "User {user} cannot be referred by {referrer}")
+            }
+            ReferrerAlreadySet { user, referrer } => {
+                write!(f, "User {user} was already referred by {referrer}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for OrderbookError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_log::test]
+    fn test_encoding() {
+        let error = OrderbookError::InsufficientBalance {
+            user: "alice@wallet".to_string(),
+            token: "USD".to_string(),
+            available: 10,
+            required: u128::MAX,
+        };
+        let encoded = error.encode();
+        assert!(encoded.starts_with(r#"{"InsufficientBalance":{"user":"alice@wallet""#));
+        assert_eq!(OrderbookError::decode(encoded.as_bytes()), Some(error));
+
+        // Messages of older contracts are not decoded
+        assert_eq!(OrderbookError::decode(b"Insufficient balance"), None);
+    }
+}
@@ -0,0 +1,404 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, VecDeque};
+
+use sdk::hyle_model_utils::TimestampMs;
+
+use crate::{quote_amount, OrderType, Rounding};
+
+/// Length of the window of the statistics of a pair, see [`TradeHistory::stats`]
+pub const STATS_WINDOW_MS: u128 = 24 * 3_600_000;
+
+/// Duration of the buckets the statistics of a pair are aggregated in
+pub const STATS_BUCKET_MS: u128 = 3_600_000;
+
+/// How long the fees charged on a pair are kept, see [`TradeHistory::fees`]
+pub const FEES_RETENTION_MS: u128 = 30 * 24 * 3_600_000;
+
+/// How much trade history of a pair the contract keeps
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+pub struct HistoryRetention {
+    /// Number of most recent trades kept
+    pub trades: u32,
+    /// Duration of a candle in milliseconds. 0 keeps no candles.
+    pub candle_interval_ms: u128,
+    /// Number of most recent candles kept
+    pub candles: u32,
+}
+
+impl Default for HistoryRetention {
+    /// The last thousand trades, and a day of one-minute candles
+    fn default() -> Self {
+        HistoryRetention {
+            trades: 1000,
+            candle_interval_ms: 60_000,
+            candles: 1440,
+        }
+    }
+}
+
+/// Trade executed on a pair
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+pub struct Trade {
+    /// Sequence number of the trade on its pair, set by [`TradeHistory::record`]
+    pub id: u64,
+    /// Time the taker order was placed at
+    pub timestamp: TimestampMs,
+    /// Price of the maker order
+    pub price: u128,
+    /// Quantity of base token traded
+    pub quantity: u128,
+    /// Side of the order that took the liquidity
+    pub taker_side: OrderType,
+    /// Owner of the resting order
+    pub maker: String,
+    /// Owner of the order that took the liquidity
+    pub taker: String,
+}
+
+/// Trades executed in an interval
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+pub struct Candle {
+    /// Start of the interval
+    pub timestamp: TimestampMs,
+    pub open: u128,
+    pub high: u128,
+    pub low: u128,
+    pub close: u128,
+    /// Quantity of base token traded
+    pub volume: u128,
+    /// Quantity of quote token traded
+    pub quote_volume: u128,
+}
+
+impl Candle {
+    /// Candle of `trade` alone, `scale` being the number of base token units in a whole token
+    fn new(timestamp: TimestampMs, trade: &Trade, scale: u128) -> Self {
+        Candle {
+            timestamp,
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: trade.quantity,
+            quote_volume: quote_amount(trade.quantity, trade.price, scale, Rounding::Down)
+                .unwrap_or(u128::MAX),
+        }
+    }
+
+    /// Adds the trades of `other`, which happened after the ones of this candle
+    pub fn merge(&mut self, other: &Candle) {
+        self.high = self.high.max(other.high);
+        self.low = self.low.min(other.low);
+        self.close = other.close;
+        self.volume = self.volume.saturating_add(other.volume);
+        self.quote_volume = self.quote_volume.saturating_add(other.quote_volume);
+    }
+}
+
+/// Trades of a pair over the last [`STATS_WINDOW_MS`]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairStats {
+    /// Price of the last trade, even an older one
+    pub last_price: Option<u128>,
+    /// Price of the first trade of the window
+    pub open: Option<u128>,
+    /// Quantity of base token traded
+    pub volume: u128,
+    pub trades: u64,
+    pub high: Option<u128>,
+    pub low: Option<u128>,
+}
+
+/// Trades of an hour
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+struct StatsBucket {
+    start: TimestampMs,
+    /// Price of the first trade recorded in the hour
+    open: u128,
+    volume: u128,
+    trades: u64,
+    high: u128,
+    low: u128,
+}
+
+/// Fees charged in an hour, by token
+#[derive(
+    Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub struct FeeBucket {
+    pub start: TimestampMs,
+    pub fees: BTreeMap<String, u128>,
+}
+
+impl FeeBucket {
+    /// Adds the fees of `other`
+    pub fn merge(&mut self, other: &FeeBucket) {
+        for (token, amount) in &other.fees {
+            let total = self.fees.entry(token.clone()).or_default();
+            *total = total.saturating_add(*amount);
+        }
+    }
+}
+
+/// Trade history of a pair, bounded by its [`HistoryRetention`]
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub struct TradeHistory {
+    /// Most recent trades, in execution order
+    trades: VecDeque<Trade>,
+    /// Interval the candles were aggregated over
+    candle_interval_ms: u128,
+    /// Candles of the most recent intervals with trades, oldest first
+    candles: VecDeque<Candle>,
+    /// Price of the last trade
+    last_price: Option<u128>,
+    /// Trades of the hours of the last [`STATS_WINDOW_MS`], oldest first
+    hourly: VecDeque<StatsBucket>,
+    /// Number of trades recorded, kept or not
+    recorded: u64,
+    /// Fees charged in the hours of the last [`FEES_RETENTION_MS`], oldest first
+    fees: VecDeque<FeeBucket>,
+}
+
+impl TradeHistory {
+    /// Records `trade` with the next id of the pair, then drops what `retention` does not keep.
+    /// `scale` is the number of base token units in a whole token.
+    pub fn record(&mut self, retention: &HistoryRetention, scale: u128, mut trade: Trade) {
+        trade.id = self.recorded;
+        self.recorded += 1;
+        self.record_stats(&trade.timestamp, trade.price, trade.quantity);
+
+        if retention.candle_interval_ms != self.candle_interval_ms {
+            // Candles of different intervals cannot be aggregated together
+            self.candles.clear();
+            self.candle_interval_ms = retention.candle_interval_ms;
+        }
+        if let Some(intervals) = trade.timestamp.0.checked_div(self.candle_interval_ms) {
+            let start = TimestampMs(intervals * self.candle_interval_ms);
+            // Trades are recorded with the time their taker was placed at, which is not always
+            // the latest one
+            match self
+                .candles
+                .binary_search_by_key(&&start, |candle| &candle.timestamp)
+            {
+                Ok(index) => self.candles[index].merge(&Candle::new(start, &trade, scale)),
+                Err(index) => self
+                    .candles
+                    .insert(index, Candle::new(start, &trade, scale)),
+            }
+        }
+        while self.candles.len() > retention.candles as usize {
+            self.candles.pop_front();
+        }
+
+        self.trades.push_back(trade);
+        while self.trades.len() > retention.trades as usize {
+            self.trades.pop_front();
+        }
+    }
+
+    fn record_stats(&mut self, timestamp: &TimestampMs, price: u128, quantity: u128) {
+        self.last_price = Some(price);
+        let start = TimestampMs(timestamp.0 / STATS_BUCKET_MS * STATS_BUCKET_MS);
+        let index = match self.hourly.binary_search_by_key(&&start, |b| &b.start) {
+            Ok(index) => index,
+            Err(index) => {
+                let bucket = StatsBucket {
+                    start,
+                    open: price,
+                    volume: 0,
+                    trades: 0,
+                    high: price,
+                    low: price,
+                };
+                self.hourly.insert(index, bucket);
+                index
+            }
+        };
+        let bucket = &mut self.hourly[index];
+        bucket.volume = bucket.volume.saturating_add(quantity);
+        bucket.trades += 1;
+        bucket.high = bucket.high.max(price);
+        bucket.low = bucket.low.min(price);
+
+        let latest = self.hourly.back().map(|b| b.start.0).unwrap_or_default();
+        while (self.hourly.front()).is_some_and(|b| b.start.0 + STATS_WINDOW_MS <= latest) {
+            self.hourly.pop_front();
+        }
+    }
+
+    /// Records a fee of `amount` of `token` charged for a trade executed at `timestamp`
+    pub fn record_fee(&mut self, timestamp: &TimestampMs, token: &str, amount: u128) {
+        let start = TimestampMs(timestamp.0 / STATS_BUCKET_MS * STATS_BUCKET_MS);
+        let index = match self.fees.binary_search_by_key(&&start, |b| &b.start) {
+            Ok(index) => index,
+            Err(index) => {
+                let bucket = FeeBucket {
+                    start,
+                    fees: BTreeMap::new(),
+                };
+                self.fees.insert(index, bucket);
+                index
+            }
+        };
+        let total = self.fees[index].fees.entry(token.to_string()).or_default();
+        *total = total.saturating_add(amount);
+
+        let latest = self.fees.back().map(|b| b.start.0).unwrap_or_default();
+        while (self.fees.front()).is_some_and(|b| b.start.0 + FEES_RETENTION_MS <= latest) {
+            self.fees.pop_front();
+        }
+    }
+
+    /// Statistics of the trades of the [`STATS_WINDOW_MS`] before `now`, to the hour
+    pub fn stats(&self, now: &TimestampMs) -> PairStats {
+        let mut stats = PairStats {
+            last_price: self.last_price,
+            ..Default::default()
+        };
+        for bucket in (self.hourly.iter()).filter(|b| b.start.0 + STATS_WINDOW_MS > now.0) {
+            stats.open = stats.open.or(Some(bucket.open));
+            stats.volume = stats.volume.saturating_add(bucket.volume);
+            stats.trades += bucket.trades;
+            stats.high = Some(stats.high.map_or(bucket.high, |high| high.max(bucket.high)));
+            stats.low = Some(stats.low.map_or(bucket.low, |low| low.min(bucket.low)));
+        }
+        stats
+    }
+
+    /// Trades kept, in execution order
+    pub fn trades(&self) -> impl DoubleEndedIterator<Item = &Trade> {
+        self.trades.iter()
+    }
+
+    /// Candles kept, oldest first
+    pub fn candles(&self) -> impl Iterator<Item = &Candle> {
+        self.candles.iter()
+    }
+
+    /// Hourly fees kept, oldest first
+    pub fn fees(&self) -> impl Iterator<Item = &FeeBucket> {
+        self.fees.iter()
+    }
+
+    /// Duration of the candles kept, in milliseconds
+    pub fn candle_interval_ms(&self) -> u128 {
+        self.candle_interval_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(timestamp: u128, price: u128, quantity: u128) -> Trade {
+        Trade {
+            id: 0,
+            timestamp: TimestampMs(timestamp),
+            price,
+            quantity,
+            taker_side: OrderType::Buy,
+            maker: "maker".to_string(),
+            taker: "taker".to_string(),
+        }
+    }
+
+    #[test_log::test]
+    fn test_candle_volumes() {
+        let mut history = TradeHistory::default();
+        // Two trades in the same millisecond are both kept
+        for (price, quantity) in [(2000, 150), (2100, 50)] {
+            history.record(&HistoryRetention::default(), 100, trade(0, price, quantity));
+        }
+        assert_eq!(history.trades().count(), 2);
+        let candle = history.candles().next().unwrap();
+        assert_eq!((candle.volume, candle.quote_volume), (200, 3000 + 1050));
+    }
+
+    #[test_log::test]
+    fn test_retention() {
+        let retention = HistoryRetention {
+            trades: 2,
+            candle_interval_ms: 1000,
+            candles: 2,
+        };
+        let mut history = TradeHistory::default();
+        for (timestamp, price) in [(0, 10), (500, 12), (1500, 8), (2500, 9), (1200, 11)] {
+            history.record(&retention, 1, trade(timestamp, price, 1));
+        }
+
+        let trades: Vec<_> = history.trades().map(|t| (t.timestamp.0, t.price)).collect();
+        assert_eq!(trades, vec![(2500, 9), (1200, 11)]);
+        // The first candle is dropped, the late trade goes to its own interval
+        let candles: Vec<_> = history
+            .candles()
+            .map(|c| (c.timestamp.0, c.open, c.high, c.low, c.close))
+            .collect();
+        assert_eq!(candles, vec![(1000, 8, 11, 8, 11), (2000, 9, 9, 9, 9)]);
+
+        // Changing the interval starts the candles over
+        let retention = HistoryRetention {
+            candle_interval_ms: 60_000,
+            ..retention
+        };
+        history.record(&retention, 1, trade(3000, 7, 1));
+        assert_eq!(history.candles().count(), 1);
+        assert_eq!(history.candle_interval_ms(), 60_000);
+    }
+    #[test_log::test]
+    fn test_fee_retention() {
+        let hour = STATS_BUCKET_MS;
+        let mut history = TradeHistory::default();
+        history.record_fee(&TimestampMs(0), "USD", 10);
+        history.record_fee(&TimestampMs(hour - 1), "USD", 5);
+        history.record_fee(&TimestampMs(hour), "ETH", 1);
+        let fees: Vec<_> = history
+            .fees()
+            .map(|b| (b.start.0, b.fees.clone()))
+            .collect();
+        assert_eq!(
+            fees,
+            vec![
+                (0, [("USD".to_string(), 15)].into()),
+                (hour, [("ETH".to_string(), 1)].into())
+            ]
+        );
+
+        // The first hour is dropped once it is older than the retention
+        history.record_fee(&TimestampMs(FEES_RETENTION_MS), "ETH", 1);
+        let starts: Vec<_> = history.fees().map(|b| b.start.0).collect();
+        assert_eq!(starts, vec![hour, FEES_RETENTION_MS]);
+    }
+
+    #[test_log::test]
+    fn test_stats() {
+        let hour = 3_600_000;
+        let mut history = TradeHistory::default();
+        assert_eq!(history.stats(&TimestampMs(0)), PairStats::default());
+
+        let retention = HistoryRetention::default();
+        for (timestamp, price, quantity) in [(0, 100, 1), (2 * hour, 120, 2), (25 * hour, 90, 3)] {
+            history.record(&retention, 1, trade(timestamp, price, quantity));
+        }
+        // The trades of the first hour left the window
+        let stats = PairStats {
+            last_price: Some(90),
+            open: Some(120),
+            volume: 5,
+            trades: 2,
+            high: Some(120),
+            low: Some(90),
+        };
+        assert_eq!(history.stats(&TimestampMs(25 * hour)), stats);
+
+        // Without trades, only the last price is left
+        let stats = history.stats(&TimestampMs(50 * hour));
+        assert_eq!(
+            (stats.last_price, stats.trades, stats.high),
+            (Some(90), 0, None)
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use sdk::hyle_model_utils::TimestampMs;
+
+use crate::mul_div;
+
+/// Maker activity of a user during the current epoch
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub struct MakerStats {
+    /// Base quantity of the user's resting orders that got filled
+    pub volume: u128,
+    /// Resting base quantity multiplied by the time it stayed in the book, in ms
+    pub quoted_depth: u128,
+    /// Base quantity currently resting in the book
+    pub resting_quantity: u128,
+    /// Last time `quoted_depth` was accrued
+    pub last_update: TimestampMs,
+}
+
+/// Accounting of maker activity, rewarded by the admin at the end of each epoch
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub struct MakerIncentives {
+    // Current epoch, incremented at each distribution
+    pub epoch: u64,
+    // Activity of each maker during the current epoch
+    pub makers: BTreeMap<String, MakerStats>,
+    // Rewards distributed to each user so far, per token
+    pub rewards: BTreeMap<String, BTreeMap<String, u128>>,
+}
+
+impl MakerStats {
+    fn accrue(&mut self, now: &TimestampMs) {
+        let elapsed = now.0.saturating_sub(self.last_update.0);
+        self.quoted_depth = self
+            .quoted_depth
+            .saturating_add(self.resting_quantity.saturating_mul(elapsed));
+        self.last_update = now.clone();
+    }
+}
+
+impl MakerIncentives {
+    fn maker_mut(&mut self, user: &str, now: &TimestampMs) -> &mut MakerStats {
+        let stats = self
+            .makers
+            .entry(user.to_string())
+            .or_insert_with(|| MakerStats {
+                last_update: now.clone(),
+                ..Default::default()
+            });
+        stats.accrue(now);
+        stats
+    }
+
+    /// An order of `user` starts resting in the book
+    pub fn add_resting(&mut self, user: &str, quantity: u128, now: &TimestampMs) {
+        let stats = self.maker_mut(user, now);
+        stats.resting_quantity = stats.resting_quantity.saturating_add(quantity);
+    }
+
+    /// An order of `user` leaves the book without being filled
+    pub fn remove_resting(&mut self, user: &str, quantity: u128, now: &TimestampMs) {
+        let stats = self.maker_mut(user, now);
+        stats.resting_quantity = stats.resting_quantity.saturating_sub(quantity);
+    }
+
+    /// A resting order of `maker` got (partially) filled
+    pub fn record_fill(&mut self, maker: &str, quantity: u128, now: &TimestampMs) {
+        self.remove_resting(maker, quantity, now);
+        let stats = self.maker_mut(maker, now);
+        stats.volume = stats.volume.saturating_add(quantity);
+    }
+
+    /// Splits `amount` between makers: half pro-rata to the filled volume, half pro-rata to the
+    /// time-weighted quoted depth. When nobody scored on one criteria, the other one gets it all.
+    pub fn shares(&mut self, amount: u128, now: &TimestampMs) -> BTreeMap<String, u128> {
+        for stats in self.makers.values_mut() {
+            stats.accrue(now);
+        }
+        let total_volume =
+            (self.makers.values()).fold(0, |total: u128, s| total.saturating_add(s.volume));
+        let total_depth =
+            (self.makers.values()).fold(0, |total: u128, s| total.saturating_add(s.quoted_depth));
+
+        let (volume_amount, depth_amount) = match (total_volume, total_depth) {
+            (0, 0) => return BTreeMap::new(),
+            (0, _) => (0, amount),
+            (_, 0) => (amount, 0),
+            _ => (amount / 2, amount - amount / 2),
+        };
+
+        self.makers
+            .iter()
+            .map(|(user, stats)| {
+                let mut share = 0;
+                if volume_amount > 0 {
+                    share += mul_div(volume_amount, stats.volume, total_volume);
+                }
+                if depth_amount > 0 {
+                    share += mul_div(depth_amount, stats.quoted_depth, total_depth);
+                }
+                (user.clone(), share)
+            })
+            .filter(|(_, share)| *share > 0)
+            .collect()
+    }
+
+    /// Records the distributed rewards and starts a new epoch. Returns the closed epoch.
+    pub fn close_epoch(&mut self, token: &str, shares: &BTreeMap<String, u128>) -> u64 {
+        for (user, share) in shares {
+            let rewards = self
+                .rewards
+                .entry(user.clone())
+                .or_default()
+                .entry(token.to_string())
+                .or_default();
+            *rewards = rewards.saturating_add(*share);
+        }
+
+        // Only makers still quoting carry over to the next epoch
+        self.makers.retain(|_, stats| stats.resting_quantity > 0);
+        for stats in self.makers.values_mut() {
+            stats.volume = 0;
+            stats.quoted_depth = 0;
+        }
+
+        self.epoch += 1;
+        self.epoch - 1
+    }
+}
@@ -1,4 +1,5 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
 use std::str;
 
 use anyhow::{anyhow, Result};
@@ -8,8 +9,13 @@ use client_sdk::contract_indexer::{
     utoipa_axum::{router::OpenApiRouter, routes},
     AppError, ContractHandler, ContractHandlerStore,
 };
-use serde::Serialize;
+use serde::{
+    de::{value::StrDeserializer, IntoDeserializer},
+    Deserialize, Serialize,
+};
 
+use crate::history::{FeeBucket, PairStats, Trade, STATS_BUCKET_MS, STATS_WINDOW_MS};
+use crate::incentives::MakerStats;
 use crate::*;
 use client_sdk::contract_indexer::axum;
 use client_sdk::contract_indexer::utoipa;
@@ -17,19 +23,81 @@ use client_sdk::contract_indexer::utoipa;
 impl ContractHandler for Orderbook {
     async fn api(store: ContractHandlerStore<Orderbook>) -> (Router<()>, OpenApi) {
         let (router, api) = OpenApiRouter::default()
+            .routes(routes!(get_state))
+            .routes(routes!(get_snapshot))
             .routes(routes!(get_balances))
             .routes(routes!(get_balance_for_account))
+            .routes(routes!(get_reserved_for_account))
+            .routes(routes!(get_withdrawals_for_account))
             .routes(routes!(get_orders))
             .routes(routes!(get_orders_by_pair))
+            .routes(routes!(get_pair_depth))
             .routes(routes!(get_orders_by_user))
+            .routes(routes!(get_closed_orders_by_user))
+            .routes(routes!(get_order))
             .routes(routes!(get_pair_history))
+            .routes(routes!(get_recent_trades))
             .routes(routes!(get_pair_candles))
+            .routes(routes!(get_pair_stats))
+            .routes(routes!(get_ticker))
+            .routes(routes!(get_markets))
+            .routes(routes!(get_incentives))
+            .routes(routes!(get_maker_incentives))
+            .routes(routes!(get_user_fees))
+            .routes(routes!(get_fee_stats))
             .split_for_parts();
 
         (router.with_state(store), api)
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/state",
+    tag = "Contract",
+    responses(
+        (status = OK, description = "Get json state of contract")
+    )
+)]
+pub async fn get_state(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_state()))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("No state found for contract '{}'", store.contract_name),
+        ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/snapshot",
+    tag = "Contract",
+    responses(
+        (status = OK, description = "Get a borsh snapshot of the state of contract, see Orderbook::import_snapshot")
+    )
+)]
+pub async fn get_snapshot(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    let state = store.state.as_ref().ok_or(AppError(
+        StatusCode::NOT_FOUND,
+        anyhow!("No state found for contract '{}'", store.contract_name),
+    ))?;
+    let snapshot = state
+        .export_snapshot()
+        .map_err(|e| AppError(StatusCode::INTERNAL_SERVER_ERROR, anyhow!(e)))?;
+    Ok((
+        [(axum::http::header::CONTENT_TYPE, "application/octet-stream")],
+        snapshot,
+    ))
+}
+
 #[utoipa::path(
     get,
     path = "/balances",
@@ -83,32 +151,224 @@ pub async fn get_balance_for_account(
         ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/balances/{account}/reserved",
+    tag = "Contract",
+    params(
+        ("account" = String, Path, description = "Account address to fetch reserved tokens for")
+    ),
+    responses(
+        (status = OK, description = "Get the tokens locked by the resting orders of a specific account")
+    )
+)]
+pub async fn get_reserved_for_account(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Path(account): axum::extract::Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_reserved_for_account(&account)))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("No state found for contract '{}'", store.contract_name),
+        ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/balances/{account}/withdrawals",
+    tag = "Contract",
+    params(
+        ("account" = String, Path, description = "Account address to fetch pending withdrawals for")
+    ),
+    responses(
+        (status = OK, description = "Get the withdrawals requested by a specific account and not claimed yet")
+    )
+)]
+pub async fn get_withdrawals_for_account(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Path(account): axum::extract::Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_withdrawals_for_account(&account)))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("No state found for contract '{}'", store.contract_name),
+        ))
+}
+
 #[utoipa::path(
     get,
     path = "/orders",
     tag = "Contract",
+    params(
+        ("limit" = Option<usize>, Query, description = "Maximum number of orders, 100 by default"),
+        ("cursor" = Option<String>, Query, description = "Id of the last order of the previous page"),
+        ("status" = Option<String>, Query, description = "Only the orders with this status, e.g. PartiallyFilled"),
+        ("side" = Option<String>, Query, description = "Only the orders of this side, Buy or Sell")
+    ),
     responses(
-        (status = OK, description = "Get json state of contract")
+        (status = OK, description = "Get the orders resting on the books, by id")
     )
 )]
 pub async fn get_orders(
     State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Query(params): axum::extract::Query<BTreeMap<String, String>>,
 ) -> Result<impl IntoResponse, AppError> {
     let store = state.read().await;
+    let query = OrdersQuery::from_params(|name| params.get(name))
+        .map_err(|e| AppError(StatusCode::BAD_REQUEST, e))?;
     store
         .state
         .as_ref()
-        .map(|state| Json(state.get_orders()))
+        .map(|state| Json(state.get_orders(&query)))
         .ok_or(AppError(
             StatusCode::NOT_FOUND,
             anyhow!("No state found for contract '{}'", store.contract_name),
         ))
 }
 
+/// Number of orders returned by default by the order listings
+pub const DEFAULT_ORDERS_LIMIT: usize = 100;
+
+/// Filters and pagination of the order listings
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrdersQuery {
+    /// Maximum number of orders, of each side for the orders of a pair
+    pub limit: usize,
+    /// Id of the last order of the previous page, the listing resumes after it
+    pub cursor: Option<String>,
+    pub status: Option<OrderStatus>,
+    pub side: Option<OrderType>,
+}
+
+impl Default for OrdersQuery {
+    /// The first [`DEFAULT_ORDERS_LIMIT`] orders
+    fn default() -> Self {
+        OrdersQuery {
+            limit: DEFAULT_ORDERS_LIMIT,
+            cursor: None,
+            status: None,
+            side: None,
+        }
+    }
+}
+
+impl OrdersQuery {
+    /// Reads the `limit`, `cursor`, `status` and `side` query parameters. Statuses and sides
+    /// are spelled as they are serialized, e.g. `PartiallyFilled` or `Buy`.
+    pub fn from_params<'a>(param: impl Fn(&str) -> Option<&'a String>) -> Result<Self> {
+        fn variant<T: for<'de> Deserialize<'de>>(name: &str, value: &str) -> Result<T> {
+            let deserializer: StrDeserializer<serde::de::value::Error> = value.into_deserializer();
+            T::deserialize(deserializer).map_err(|_| anyhow!("Invalid '{}' parameter", name))
+        }
+
+        Ok(OrdersQuery {
+            limit: param("limit")
+                .map(|s| s.parse().map_err(|_| anyhow!("Invalid 'limit' parameter")))
+                .transpose()?
+                .unwrap_or(DEFAULT_ORDERS_LIMIT),
+            cursor: param("cursor").cloned(),
+            status: param("status").map(|s| variant("status", s)).transpose()?,
+            side: param("side").map(|s| variant("side", s)).transpose()?,
+        })
+    }
+
+    fn matches(&self, order: &Order) -> bool {
+        self.status
+            .as_ref()
+            .map_or(true, |status| order.status == *status)
+            && self
+                .side
+                .as_ref()
+                .map_or(true, |side| order.order_type == *side)
+    }
+
+    /// The first `limit` of `orders` matching the filters
+    fn page<'a>(&self, orders: impl Iterator<Item = &'a Order>) -> Vec<Order> {
+        orders
+            .filter(|order| self.matches(order))
+            .take(self.limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Bounds of the ids after the cursor, for the listings ordered by id
+    fn after_cursor(&self) -> (Bound<&String>, Bound<&String>) {
+        (
+            self.cursor
+                .as_ref()
+                .map_or(Bound::Unbounded, Bound::Excluded),
+            Bound::Unbounded,
+        )
+    }
+}
+
 #[derive(Serialize)]
 pub struct PairOrders {
-    buy_orders: Vec<Order>,
-    sell_orders: Vec<Order>,
+    pub buy_orders: Vec<Order>,
+    pub sell_orders: Vec<Order>,
+    pub best_bid: Option<u128>,
+    pub best_ask: Option<u128>,
+}
+
+/// Price levels of each side of a pair, best first, each as `[price, total_quantity,
+/// order_count]`. Only the visible quantity of iceberg orders is counted.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct PairDepth {
+    pub bids: Vec<(u128, u128, usize)>,
+    pub asks: Vec<(u128, u128, usize)>,
+}
+
+/// Number of price levels of each side returned by default by the depth endpoints
+pub const DEFAULT_DEPTH_LEVELS: usize = 20;
+
+#[utoipa::path(
+    get,
+    path = "/orderbook/{base_token}/{quote_token}/depth",
+    tag = "Contract",
+    params(
+        ("base_token" = String, Path, description = "Base token of the pair"),
+        ("quote_token" = String, Path, description = "Quote token of the pair"),
+        ("levels" = Option<usize>, Query, description = "Number of price levels of each side, 20 by default")
+    ),
+    responses(
+        (status = OK, description = "Get the aggregated price levels of a specific token pair")
+    )
+)]
+pub async fn get_pair_depth(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+    axum::extract::Query(params): axum::extract::Query<BTreeMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    let levels = params
+        .get("levels")
+        .map(|s| {
+            s.parse::<usize>().map_err(|_| {
+                AppError(
+                    StatusCode::BAD_REQUEST,
+                    anyhow!("Invalid 'levels' parameter"),
+                )
+            })
+        })
+        .transpose()?
+        .unwrap_or(DEFAULT_DEPTH_LEVELS);
+
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_pair_depth(&base_token, &quote_token, levels)))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("No state found for contract '{}'", store.contract_name),
+        ))
 }
 
 #[utoipa::path(
@@ -117,21 +377,28 @@ pub struct PairOrders {
     tag = "Contract",
     params(
         ("base_token" = String, Path, description = "Base token of the pair"),
-        ("quote_token" = String, Path, description = "Quote token of the pair")
+        ("quote_token" = String, Path, description = "Quote token of the pair"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of orders of each side, 100 by default"),
+        ("cursor" = Option<String>, Query, description = "Id of the last order of the previous page"),
+        ("status" = Option<String>, Query, description = "Only the orders with this status, e.g. PartiallyFilled"),
+        ("side" = Option<String>, Query, description = "Only the orders of this side, Buy or Sell")
     ),
     responses(
-        (status = OK, description = "Get all orders for a specific token pair")
+        (status = OK, description = "Get the orders of a specific token pair, best first")
     )
 )]
 pub async fn get_orders_by_pair(
     State(state): State<ContractHandlerStore<Orderbook>>,
     axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+    axum::extract::Query(params): axum::extract::Query<BTreeMap<String, String>>,
 ) -> Result<impl IntoResponse, AppError> {
     let store = state.read().await;
+    let query = OrdersQuery::from_params(|name| params.get(name))
+        .map_err(|e| AppError(StatusCode::BAD_REQUEST, e))?;
     store
         .state
         .as_ref()
-        .map(|state| Json(state.get_orders_by_pair(&base_token, &quote_token)))
+        .map(|state| Json(state.get_orders_by_pair(&base_token, &quote_token, &query)))
         .ok_or(AppError(
             StatusCode::NOT_FOUND,
             anyhow!(
@@ -145,24 +412,92 @@ pub async fn get_orders_by_pair(
 
 #[utoipa::path(
     get,
-    path = "/orders/user/{address}",
+    path = "/order/{order_id}",
+    tag = "Contract",
+    params(
+        ("order_id" = String, Path, description = "Id of the order")
+    ),
+    responses(
+        (status = OK, description = "Get an open order, or one of the last closed orders of its owner, with its status")
+    )
+)]
+pub async fn get_order(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Path(order_id): axum::extract::Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    store
+        .state
+        .as_ref()
+        .and_then(|state| state.get_order(&order_id))
+        .map(Json)
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!(
+                "No order '{}' found in contract '{}'",
+                order_id,
+                store.contract_name
+            ),
+        ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/orders/user/{address}/closed",
     tag = "Contract",
     params(
         ("address" = String, Path, description = "Address of the user")
     ),
     responses(
-        (status = OK, description = "Get all orders for a specific user")
+        (status = OK, description = "Get the last orders of a specific user that were filled, cancelled or expired")
+    )
+)]
+pub async fn get_closed_orders_by_user(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Path(address): axum::extract::Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_closed_orders_by_user(&address)))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!(
+                "No orders found for user '{}' in contract '{}'",
+                address,
+                store.contract_name
+            ),
+        ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/orders/user/{address}",
+    tag = "Contract",
+    params(
+        ("address" = String, Path, description = "Address of the user"),
+        ("limit" = Option<usize>, Query, description = "Maximum number of orders, 100 by default"),
+        ("cursor" = Option<String>, Query, description = "Id of the last order of the previous page"),
+        ("status" = Option<String>, Query, description = "Only the orders with this status, e.g. PartiallyFilled"),
+        ("side" = Option<String>, Query, description = "Only the orders of this side, Buy or Sell")
+    ),
+    responses(
+        (status = OK, description = "Get the open orders of a specific user, by id")
     )
 )]
 pub async fn get_orders_by_user(
     State(state): State<ContractHandlerStore<Orderbook>>,
     axum::extract::Path(address): axum::extract::Path<String>,
+    axum::extract::Query(params): axum::extract::Query<BTreeMap<String, String>>,
 ) -> Result<impl IntoResponse, AppError> {
     let store = state.read().await;
+    let query = OrdersQuery::from_params(|name| params.get(name))
+        .map_err(|e| AppError(StatusCode::BAD_REQUEST, e))?;
     store
         .state
         .as_ref()
-        .map(|state| Json(state.get_orders_by_user(&address)))
+        .map(|state| Json(state.get_orders_by_user(&address, &query)))
         .ok_or(AppError(
             StatusCode::NOT_FOUND,
             anyhow!(
@@ -179,7 +514,9 @@ pub async fn get_orders_by_user(
     tag = "Contract",
     params(
         ("base_token" = String, Path, description = "Base token of the pair"),
-        ("quote_token" = String, Path, description = "Quote token of the pair")
+        ("quote_token" = String, Path, description = "Quote token of the pair"),
+        ("from" = Option<i64>, Query, description = "Start timestamp in milliseconds, inclusive"),
+        ("to" = Option<i64>, Query, description = "End timestamp in milliseconds, exclusive")
     ),
     responses(
         (status = OK, description = "Get trading history for a specific token pair")
@@ -188,12 +525,30 @@ pub async fn get_orders_by_user(
 pub async fn get_pair_history(
     State(state): State<ContractHandlerStore<Orderbook>>,
     axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+    axum::extract::Query(params): axum::extract::Query<BTreeMap<String, String>>,
 ) -> Result<impl IntoResponse, AppError> {
     let store = state.read().await;
+
+    let bound = |name: &str| {
+        params
+            .get(name)
+            .map(|s| {
+                s.parse::<u128>().map(TimestampMs).map_err(|_| {
+                    AppError(
+                        StatusCode::BAD_REQUEST,
+                        anyhow!("Invalid '{}' parameter", name),
+                    )
+                })
+            })
+            .transpose()
+    };
+    let from = bound("from")?;
+    let to = bound("to")?;
+
     store
         .state
         .as_ref()
-        .map(|state| Json(state.get_pair_history(&base_token, &quote_token)))
+        .map(|state| Json(state.get_pair_history(&base_token, &quote_token, from, to)))
         .ok_or(AppError(
             StatusCode::NOT_FOUND,
             anyhow!(
@@ -205,14 +560,74 @@ pub async fn get_pair_history(
         ))
 }
 
+/// Number of trades returned by default by the recent trades endpoints
+pub const DEFAULT_TRADES_LIMIT: usize = 100;
+
+#[utoipa::path(
+    get,
+    path = "/trades/{base_token}/{quote_token}",
+    tag = "Contract",
+    params(
+        ("base_token" = String, Path, description = "Base token of the pair"),
+        ("quote_token" = String, Path, description = "Quote token of the pair"),
+        ("limit" = Option<usize>, Query, description = "Number of trades, 100 by default"),
+        ("before" = Option<u64>, Query, description = "Only the trades with a lower id, to load older trades")
+    ),
+    responses(
+        (status = OK, description = "Get the most recent trades of a specific token pair, most recent first")
+    )
+)]
+pub async fn get_recent_trades(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+    axum::extract::Query(params): axum::extract::Query<BTreeMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    let limit = params
+        .get("limit")
+        .map(|s| {
+            s.parse::<usize>().map_err(|_| {
+                AppError(
+                    StatusCode::BAD_REQUEST,
+                    anyhow!("Invalid 'limit' parameter"),
+                )
+            })
+        })
+        .transpose()?
+        .unwrap_or(DEFAULT_TRADES_LIMIT);
+    let before = params
+        .get("before")
+        .map(|s| {
+            s.parse::<u64>().map_err(|_| {
+                AppError(
+                    StatusCode::BAD_REQUEST,
+                    anyhow!("Invalid 'before' parameter"),
+                )
+            })
+        })
+        .transpose()?;
+
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_recent_trades(&base_token, &quote_token, limit, before)))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("No state found for contract '{}'", store.contract_name),
+        ))
+}
+
 #[derive(Serialize)]
 pub struct CandleStick {
-    timestamp: TimestampMs,
-    open: u32,
-    high: u32,
-    low: u32,
-    close: u32,
-    volume: u32,
+    pub timestamp: TimestampMs,
+    pub open: u128,
+    pub high: u128,
+    pub low: u128,
+    pub close: u128,
+    /// Quantity of base token traded
+    pub volume: u128,
+    /// Quantity of quote token traded
+    pub quote_volume: u128,
 }
 
 #[utoipa::path(
@@ -279,71 +694,431 @@ pub async fn get_pair_candles(
         ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/orders/stats/{base_token}/{quote_token}",
+    tag = "Contract",
+    params(
+        ("base_token" = String, Path, description = "Base token of the pair"),
+        ("quote_token" = String, Path, description = "Quote token of the pair")
+    ),
+    responses(
+        (status = OK, description = "Get the last price, and the volume, number of trades, high and low of the last 24 hours of a token pair")
+    )
+)]
+pub async fn get_pair_stats(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    let now = now();
+
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_pair_stats(&base_token, &quote_token, &now)))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!(
+                "No stats found for pair '{}/{}' in contract '{}'",
+                base_token,
+                quote_token,
+                store.contract_name
+            ),
+        ))
+}
+
+/// Top of the book of a pair, with its statistics of the last 24 hours
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Ticker {
+    pub best_bid: Option<u128>,
+    pub best_ask: Option<u128>,
+    pub mid: Option<u128>,
+    /// Best ask minus best bid
+    pub spread: Option<u128>,
+    pub last_price: Option<u128>,
+    /// Last price minus the price of the first trade of the last 24 hours
+    pub change: Option<i128>,
+    /// Change in basis points of the price of the first trade of the last 24 hours
+    pub change_bps: Option<i128>,
+    /// Quantity of base token traded over the last 24 hours
+    pub volume: u128,
+}
+
+#[utoipa::path(
+    get,
+    path = "/ticker/{base_token}/{quote_token}",
+    tag = "Contract",
+    params(
+        ("base_token" = String, Path, description = "Base token of the pair"),
+        ("quote_token" = String, Path, description = "Quote token of the pair")
+    ),
+    responses(
+        (status = OK, description = "Get the best bid and ask, spread, last price and change and volume of the last 24 hours of a token pair")
+    )
+)]
+pub async fn get_ticker(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    let now = now();
+
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_ticker(&base_token, &quote_token, &now)))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("No state found for contract '{}'", store.contract_name),
+        ))
+}
+
+/// Summary of a pair in the market list, with its statistics of the last 24 hours
+#[derive(Debug, Serialize, PartialEq)]
+pub struct MarketSummary {
+    pub base_token: String,
+    pub quote_token: String,
+    pub last_price: Option<u128>,
+    pub high: Option<u128>,
+    pub low: Option<u128>,
+    /// Quantity of base token traded over the last 24 hours
+    pub volume: u128,
+    pub open_buy_orders: usize,
+    pub open_sell_orders: usize,
+    pub best_bid: Option<u128>,
+    pub best_ask: Option<u128>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/markets",
+    tag = "Contract",
+    responses(
+        (status = OK, description = "Get the last price, statistics of the last 24 hours, open orders and top of the book of every pair")
+    )
+)]
+pub async fn get_markets(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    let now = now();
+
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_markets(&now)))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("No state found for contract '{}'", store.contract_name),
+        ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/incentives",
+    tag = "Contract",
+    responses(
+        (status = OK, description = "Get maker activity of the current epoch and distributed rewards")
+    )
+)]
+pub async fn get_incentives(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_incentives()))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("No state found for contract '{}'", store.contract_name),
+        ))
+}
+
+#[derive(Serialize)]
+pub struct MakerIncentivesReport {
+    epoch: u64,
+    stats: Option<MakerStats>,
+    rewards: BTreeMap<String, u128>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/incentives/{user}",
+    tag = "Contract",
+    params(
+        ("user" = String, Path, description = "Maker to fetch incentives for")
+    ),
+    responses(
+        (status = OK, description = "Get maker activity of the current epoch and rewards received by a user")
+    )
+)]
+pub async fn get_maker_incentives(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Path(user): axum::extract::Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_maker_incentives(&user)))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("No state found for contract '{}'", store.contract_name),
+        ))
+}
+
+#[derive(Serialize)]
+pub struct UserFeesReport {
+    /// Base quantity traded over the last [`crate::volume::VOLUME_WINDOW_DAYS`] days
+    volume: u128,
+    maker_bps: u16,
+    taker_bps: u16,
+}
+
+#[utoipa::path(
+    get,
+    path = "/fees/{user}",
+    tag = "Contract",
+    params(
+        ("user" = String, Path, description = "User to fetch the fee rates of")
+    ),
+    responses(
+        (status = OK, description = "Get the recent trading volume of a user, and the fee rates of its tier")
+    )
+)]
+pub async fn get_user_fees(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Path(user): axum::extract::Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_user_fees(&user, &now())))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("No state found for contract '{}'", store.contract_name),
+        ))
+}
+
+/// Fees charged on a pair over a window
+#[derive(Debug, Serialize, PartialEq)]
+pub struct PairFees {
+    pub base_token: String,
+    pub quote_token: String,
+    pub fees: BTreeMap<String, u128>,
+}
+
+/// Fees charged over a window, to the hour, see [`Orderbook::get_fee_stats`]
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct FeeStats {
+    /// Total by token
+    pub fees: BTreeMap<String, u128>,
+    pub pairs: Vec<PairFees>,
+    /// Fees of each hour with fees, by token, oldest first
+    pub hourly: Vec<FeeBucket>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats/fees",
+    tag = "Contract",
+    params(
+        ("window" = Option<u64>, Query, description = "Length of the window in milliseconds, 24 hours by default")
+    ),
+    responses(
+        (status = OK, description = "Get the fees collected by token and by pair over a window, with their hourly history")
+    )
+)]
+pub async fn get_fee_stats(
+    State(state): State<ContractHandlerStore<Orderbook>>,
+    axum::extract::Query(params): axum::extract::Query<BTreeMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    let window = params
+        .get("window")
+        .map(|s| {
+            s.parse::<u128>().map_err(|_| {
+                AppError(
+                    StatusCode::BAD_REQUEST,
+                    anyhow!("Invalid 'window' parameter"),
+                )
+            })
+        })
+        .transpose()?
+        .unwrap_or(STATS_WINDOW_MS);
+
+    store
+        .state
+        .as_ref()
+        .map(|state| Json(state.get_fee_stats(window, &now())))
+        .ok_or(AppError(
+            StatusCode::NOT_FOUND,
+            anyhow!("No state found for contract '{}'", store.contract_name),
+        ))
+}
+
+fn now() -> TimestampMs {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| TimestampMs(duration.as_millis()))
+        .unwrap_or(TimestampMs(0))
+}
+
 /// Implementation for indexing purposes
 impl Orderbook {
     pub fn get_state(&self) -> Self {
         self.clone()
     }
-    pub fn get_balances(&self) -> BTreeMap<String, BTreeMap<String, u32>> {
+    pub fn get_balances(&self) -> BTreeMap<String, BTreeMap<String, u128>> {
         self.balances.clone()
     }
 
-    pub fn get_balance_for_account(&self, account: &str) -> Option<BTreeMap<String, u32>> {
+    pub fn get_balance_for_account(&self, account: &str) -> Option<BTreeMap<String, u128>> {
         self.balances.get(account).cloned()
     }
 
-    pub fn get_orders(&self) -> BTreeMap<String, Order> {
-        self.orders.clone()
+    /// Tokens locked by the resting orders of an account, on top of its available balances
+    pub fn get_reserved_for_account(&self, account: &str) -> BTreeMap<String, u128> {
+        self.reserved.get(account).cloned().unwrap_or_default()
     }
 
-    pub fn get_orders_by_pair(&self, base_token: &str, quote_token: &str) -> PairOrders {
-        let pair = (base_token.to_string(), quote_token.to_string());
+    /// Withdrawals requested by an account and not claimed yet, by id
+    pub fn get_withdrawals_for_account(&self, account: &str) -> BTreeMap<u64, PendingWithdrawal> {
+        (self.pending_withdrawals_of(account))
+            .map(|(id, withdrawal)| (*id, withdrawal.clone()))
+            .collect()
+    }
 
-        let buy_orders = self
-            .buy_orders
-            .get(&pair)
-            .map(|ids| {
-                ids.iter()
-                    .filter_map(|id| self.orders.get(id))
-                    .cloned()
-                    .collect()
-            })
-            .unwrap_or_default();
+    /// Page of the orders resting on the books, by id
+    pub fn get_orders(&self, query: &OrdersQuery) -> BTreeMap<String, Order> {
+        let orders = self.orders.range::<String, _>(query.after_cursor());
+        (query.page(orders.map(|(_, order)| order)).into_iter())
+            .map(|order| (order.order_id.clone(), order))
+            .collect()
+    }
 
-        let sell_orders = self
-            .sell_orders
-            .get(&pair)
-            .map(|ids| {
-                ids.iter()
-                    .filter_map(|id| self.orders.get(id))
-                    .cloned()
-                    .collect()
-            })
-            .unwrap_or_default();
+    /// Page of each side of the book of a pair, best first. The cursor only resumes the side
+    /// it is on: the other side is empty, and so are both once the order left the book.
+    pub fn get_orders_by_pair(
+        &self,
+        base_token: &str,
+        quote_token: &str,
+        query: &OrdersQuery,
+    ) -> PairOrders {
+        let pair = (base_token.to_string(), quote_token.to_string());
+        let side = |book: &BTreeMap<TokenPair, PriceLevels>, order_type: OrderType| {
+            if (query.side.as_ref()).is_some_and(|side| *side != order_type) {
+                return Vec::new();
+            }
+            let mut order_ids =
+                (book.get(&pair).into_iter()).flat_map(|levels| levels.order_ids(&order_type));
+            if let Some(cursor) = &query.cursor {
+                order_ids.by_ref().find(|id| *id == cursor);
+            }
+            query.page(order_ids.filter_map(|id| self.orders.get(id)))
+        };
 
         PairOrders {
-            buy_orders,
-            sell_orders,
+            buy_orders: side(&self.buy_orders, OrderType::Buy),
+            sell_orders: side(&self.sell_orders, OrderType::Sell),
+            best_bid: self.best_bid(&pair),
+            best_ask: self.best_ask(&pair),
+        }
+    }
+
+    /// The `levels` best price levels of each side of a pair
+    pub fn get_pair_depth(&self, base_token: &str, quote_token: &str, levels: usize) -> PairDepth {
+        let pair = (base_token.to_string(), quote_token.to_string());
+        let side = |book: &BTreeMap<TokenPair, PriceLevels>, order_type: OrderType| {
+            (book.get(&pair).into_iter())
+                .flat_map(|price_levels| price_levels.best_first(&order_type))
+                .map(|(price, order_ids)| {
+                    let orders = order_ids.iter().filter_map(|id| self.orders.get(id));
+                    let quantity = orders.clone().fold(0, |total: u128, order| {
+                        total.saturating_add(order.visible_quantity())
+                    });
+                    (price, quantity, orders.count())
+                })
+                .filter(|(_, _, count)| *count > 0)
+                .take(levels)
+                .collect()
+        };
+        PairDepth {
+            bids: side(&self.buy_orders, OrderType::Buy),
+            asks: side(&self.sell_orders, OrderType::Sell),
         }
     }
 
-    pub fn get_orders_by_user(&self, address: &str) -> Vec<Order> {
-        self.orders
-            .values()
-            .filter(|order| order.owner == address)
+    /// Page of the orders of a user by id, including its stop orders waiting for their trigger
+    /// price
+    pub fn get_orders_by_user(&self, address: &str, query: &OrdersQuery) -> Vec<Order> {
+        let order_ids = (self.order_ids_of(address).into_iter())
+            .flat_map(|order_ids| order_ids.range::<String, _>(query.after_cursor()));
+        query.page(
+            order_ids
+                .filter_map(|order_id| self.orders.get(order_id).or(self.stop_order(order_id))),
+        )
+    }
+
+    /// Last orders of a user that were filled, cancelled or expired, most recent first
+    pub fn get_closed_orders_by_user(&self, address: &str) -> Vec<Order> {
+        self.closed_orders_of(address).rev().cloned().collect()
+    }
+
+    /// Order with its status, open or among the last closed orders of its owner
+    pub fn get_order(&self, order_id: &str) -> Option<Order> {
+        self.find_order(order_id).cloned()
+    }
+
+    /// Trades of a pair executed in `[from, to)` that are still kept, in execution order
+    pub fn get_pair_history(
+        &self,
+        base_token: &str,
+        quote_token: &str,
+        from: Option<TimestampMs>,
+        to: Option<TimestampMs>,
+    ) -> Vec<Trade> {
+        let pair = (base_token.to_string(), quote_token.to_string());
+        let Some(history) = self.trade_history.get(&pair) else {
+            return Vec::new();
+        };
+        let range = from.unwrap_or(TimestampMs(0))..to.unwrap_or(TimestampMs(u128::MAX));
+        history
+            .trades()
+            .filter(|trade| range.contains(&trade.timestamp))
             .cloned()
             .collect()
     }
 
-    pub fn get_pair_history(
+    /// The `limit` most recent trades of a pair still kept, most recent first. With `before`,
+    /// only the trades with a lower id are returned, so that older trades can be loaded from the
+    /// id of the last trade of a page.
+    pub fn get_recent_trades(
         &self,
         base_token: &str,
         quote_token: &str,
-    ) -> BTreeMap<TimestampMs, u32> {
+        limit: usize,
+        before: Option<u64>,
+    ) -> Vec<Trade> {
         let pair = (base_token.to_string(), quote_token.to_string());
-        self.orders_history.get(&pair).cloned().unwrap_or_default()
+        let Some(history) = self.trade_history.get(&pair) else {
+            return Vec::new();
+        };
+        let before = before.unwrap_or(u64::MAX);
+        history
+            .trades()
+            .rev()
+            .skip_while(|trade| trade.id >= before)
+            .take(limit)
+            .cloned()
+            .collect()
     }
 
+    /// Candles of a pair starting in `[from, to)`, aggregated from the candles the contract
+    /// keeps: `interval` is only exact when it is a multiple of the pair's candle interval
     pub fn get_pair_candles(
         &self,
         base_token: &str,
@@ -353,38 +1128,185 @@ impl Orderbook {
         interval: u128,
     ) -> Vec<CandleStick> {
         let pair = (base_token.to_string(), quote_token.to_string());
-        let history = self.orders_history.get(&pair).cloned().unwrap_or_default();
+        let mut candles: Vec<CandleStick> = Vec::new();
+        let Some(history) = self.trade_history.get(&pair) else {
+            return candles;
+        };
+        if interval == 0 || from.0 >= to.0 {
+            return candles;
+        }
 
-        let mut candles = Vec::new();
-        let mut current_time = from;
+        let range = from.clone()..to;
+        for kept in history.candles().filter(|c| range.contains(&c.timestamp)) {
+            let start = TimestampMs(from.0 + (kept.timestamp.0 - from.0) / interval * interval);
+            match candles.last_mut() {
+                Some(candle) if candle.timestamp == start => {
+                    candle.high = candle.high.max(kept.high);
+                    candle.low = candle.low.min(kept.low);
+                    candle.close = kept.close;
+                    candle.volume = candle.volume.saturating_add(kept.volume);
+                    candle.quote_volume = candle.quote_volume.saturating_add(kept.quote_volume);
+                }
+                _ => candles.push(CandleStick {
+                    timestamp: start,
+                    open: kept.open,
+                    high: kept.high,
+                    low: kept.low,
+                    close: kept.close,
+                    volume: kept.volume,
+                    quote_volume: kept.quote_volume,
+                }),
+            }
+        }
 
-        while current_time.0 < to.0 {
-            let next_time = TimestampMs(current_time.0 + interval);
+        candles
+    }
 
-            let interval_trades: Vec<_> = history
-                .iter()
-                .filter(|(ts, _)| ts.0 >= current_time.0 && ts.0 < next_time.0)
-                .collect();
+    /// Statistics of the trades of a pair over the day before `now`
+    pub fn get_pair_stats(
+        &self,
+        base_token: &str,
+        quote_token: &str,
+        now: &TimestampMs,
+    ) -> PairStats {
+        let pair = (base_token.to_string(), quote_token.to_string());
+        self.trade_history
+            .get(&pair)
+            .map(|history| history.stats(now))
+            .unwrap_or_default()
+    }
+
+    /// Top of the book of a pair, and its statistics of the 24 hours before `now`
+    pub fn get_ticker(&self, base_token: &str, quote_token: &str, now: &TimestampMs) -> Ticker {
+        let pair = (base_token.to_string(), quote_token.to_string());
+        let stats = self.get_pair_stats(base_token, quote_token, now);
+        let (best_bid, best_ask) = self.best_prices(&pair);
 
-            if !interval_trades.is_empty() {
-                let prices: Vec<_> = interval_trades.iter().map(|(_, &price)| price).collect();
-                let volume: u32 = prices.iter().sum();
+        let change = stats.open.zip(stats.last_price).and_then(|(open, last)| {
+            let (open, last) = (i128::try_from(open).ok()?, i128::try_from(last).ok()?);
+            let change = last.checked_sub(open)?;
+            Some((change, change.checked_mul(10_000)?.checked_div(open)?))
+        });
+        Ticker {
+            best_bid,
+            best_ask,
+            mid: self.mid_price(&pair),
+            spread: best_bid
+                .zip(best_ask)
+                .map(|(bid, ask)| ask.saturating_sub(bid)),
+            last_price: stats.last_price,
+            change: change.map(|(change, _)| change),
+            change_bps: change.map(|(_, change_bps)| change_bps),
+            volume: stats.volume,
+        }
+    }
 
-                let candle = CandleStick {
-                    timestamp: current_time,
-                    open: *prices.first().unwrap_or(&0),
-                    high: *prices.iter().max().unwrap_or(&0),
-                    low: *prices.iter().min().unwrap_or(&0),
-                    close: *prices.last().unwrap_or(&0),
-                    volume,
-                };
+    /// Pairs of the market list: the listed ones, or all the pairs configured or traded when
+    /// any pair can be traded
+    pub fn market_pairs(&self) -> BTreeSet<TokenPair> {
+        match &self.listed_pairs {
+            Some(pairs) => pairs.clone(),
+            None => (self.markets.keys())
+                .chain(self.book_seqs.keys())
+                .chain(self.trade_history.keys())
+                .cloned()
+                .collect(),
+        }
+    }
 
-                candles.push(candle);
+    /// Summary of each of the [`Orderbook::market_pairs`], over the 24 hours before `now`
+    pub fn get_markets(&self, now: &TimestampMs) -> Vec<MarketSummary> {
+        let mut open_orders: BTreeMap<&TokenPair, (usize, usize)> = BTreeMap::new();
+        for order in self.orders.values() {
+            let (buy, sell) = open_orders.entry(&order.pair).or_default();
+            match order.order_type {
+                OrderType::Buy => *buy += 1,
+                OrderType::Sell => *sell += 1,
             }
+        }
 
-            current_time = next_time;
+        (self.market_pairs().into_iter())
+            .map(|pair| {
+                let stats = self.get_pair_stats(&pair.0, &pair.1, now);
+                let (best_bid, best_ask) = self.best_prices(&pair);
+                let (open_buy_orders, open_sell_orders) =
+                    open_orders.get(&pair).copied().unwrap_or_default();
+                MarketSummary {
+                    last_price: stats.last_price,
+                    high: stats.high,
+                    low: stats.low,
+                    volume: stats.volume,
+                    open_buy_orders,
+                    open_sell_orders,
+                    best_bid,
+                    best_ask,
+                    base_token: pair.0,
+                    quote_token: pair.1,
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_incentives(&self) -> MakerIncentives {
+        self.incentives.clone()
+    }
+
+    pub fn get_maker_incentives(&self, user: &str) -> MakerIncentivesReport {
+        MakerIncentivesReport {
+            epoch: self.incentives.epoch,
+            stats: self.incentives.makers.get(user).cloned(),
+            rewards: self
+                .incentives
+                .rewards
+                .get(user)
+                .cloned()
+                .unwrap_or_default(),
         }
+    }
 
-        candles
+    /// Fees charged over the `window_ms` before `now`, to the hour, and as long as they are
+    /// kept, see [`crate::history::FEES_RETENTION_MS`]
+    pub fn get_fee_stats(&self, window_ms: u128, now: &TimestampMs) -> FeeStats {
+        let mut stats = FeeStats::default();
+        let mut total = FeeBucket::default();
+        let mut hourly: BTreeMap<TimestampMs, FeeBucket> = BTreeMap::new();
+        for (pair, history) in &self.trade_history {
+            let mut pair_fees = FeeBucket::default();
+            for bucket in history
+                .fees()
+                .filter(|b| b.start.0 + STATS_BUCKET_MS + window_ms > now.0)
+            {
+                pair_fees.merge(bucket);
+                (hourly.entry(bucket.start.clone()))
+                    .or_insert_with(|| FeeBucket {
+                        start: bucket.start.clone(),
+                        fees: BTreeMap::new(),
+                    })
+                    .merge(bucket);
+            }
+            if pair_fees.fees.is_empty() {
+                continue;
+            }
+            total.merge(&pair_fees);
+            stats.pairs.push(PairFees {
+                base_token: pair.0.clone(),
+                quote_token: pair.1.clone(),
+                fees: pair_fees.fees,
+            });
+        }
+        stats.fees = total.fees;
+        stats.hourly = hourly.into_values().collect();
+        stats
+    }
+
+    /// Volume `user` traded over the fee tiers window at `now`, and the rates it pays
+    pub fn get_user_fees(&self, user: &str, now: &TimestampMs) -> UserFeesReport {
+        let volume = self.volumes.volume(user, now);
+        let (maker_bps, taker_bps) = self.fee_schedule.rates(volume);
+        UserFeesReport {
+            volume,
+            maker_bps,
+            taker_bps,
+        }
     }
 }
@@ -0,0 +1,119 @@
+//! Checks of the accounting of the orderbook, run after every transaction by the tests and, with
+//! the `invariants` feature, by the server's optimistic executions.
+//!
+//! Tokens only enter and leave the orderbook with deposits and withdrawals, every other action
+//! moves them between accounts:
+//! - the balances of all accounts, the orderbook's included, and the pending withdrawals add up
+//!   to the tokens deposited and not withdrawn yet,
+//! - the [`RESERVES`] account holds exactly what the resting orders reserve, and the
+//!   reservations recorded for each user add up to what its own resting orders reserve,
+//! - each resting order reserves what its remaining quantity requires.
+//!
+//! Outside of batch-auction pairs, whose orders are only matched by their auctions, the best bid
+//! of each pair is also strictly below its best ask.
+
+use std::collections::BTreeMap;
+
+use crate::{Orderbook, RESERVES};
+
+impl Orderbook {
+    /// Checks that the funds of the orderbook are accounted for and that its books are not
+    /// crossed, see [`crate::invariants`]
+    pub fn check_invariants(&self) -> Result<(), String> {
+        self.check_conservation()?;
+        self.check_reserves()?;
+        self.check_spreads()
+    }
+
+    /// Checks that the balances and pending withdrawals of each token add up to its deposits
+    fn check_conservation(&self) -> Result<(), String> {
+        let mut totals: BTreeMap<&String, u128> = BTreeMap::new();
+        let pending = (self.pending_withdrawals.values()).map(|w| (&w.token, &w.amount));
+        for (token, amount) in self.balances.values().flatten().chain(pending) {
+            let total = totals.entry(token).or_default();
+            *total = total
+                .checked_add(*amount)
+                .ok_or(format!("Balances of {token} tokens overflow"))?;
+        }
+
+        for token in totals.keys().copied().chain(self.deposits.keys()) {
+            let total = totals.get(token).copied().unwrap_or_default();
+            let deposits = self.deposits.get(token).copied().unwrap_or_default();
+            if total != deposits {
+                return Err(format!(
+                    "Balances and withdrawals of {token} tokens add up to {total}, but {deposits} were deposited"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the [`RESERVES`] hold what the resting orders reserve, as recorded for
+    /// their owners
+    fn check_reserves(&self) -> Result<(), String> {
+        let mut reserved: BTreeMap<&String, u128> = BTreeMap::new();
+        let mut reserved_by_owner: BTreeMap<&String, BTreeMap<String, u128>> = BTreeMap::new();
+        for order in self.orders.values() {
+            let required = self
+                .required_reservation(order)
+                .map_err(|e| e.to_string())?;
+            if order.reserved_amount != required {
+                return Err(format!(
+                    "Order {} reserves {}, but its quantity requires {required}",
+                    order.order_id, order.reserved_amount
+                ));
+            }
+            let (token, amount) = (order.reserved_token(), order.reserved_amount);
+            *reserved.entry(token).or_default() += amount;
+            *(reserved_by_owner.entry(&order.owner).or_default())
+                .entry(token.clone())
+                .or_default() += amount;
+        }
+
+        for owner in reserved_by_owner
+            .keys()
+            .copied()
+            .chain(self.reserved.keys())
+        {
+            let expected = reserved_by_owner.get(owner).cloned().unwrap_or_default();
+            let recorded = self.reserved.get(owner).cloned().unwrap_or_default();
+            if expected != recorded {
+                return Err(format!(
+                    "User {owner} has {recorded:?} reserved, but its resting orders reserve {expected:?}"
+                ));
+            }
+        }
+
+        let held = self.balances.get(RESERVES);
+        let held_tokens = held.into_iter().flat_map(|balances| balances.keys());
+        for token in reserved.keys().copied().chain(held_tokens) {
+            let reserved = reserved.get(token).copied().unwrap_or_default();
+            let held = (held.and_then(|balances| balances.get(token)))
+                .copied()
+                .unwrap_or_default();
+            if held != reserved {
+                return Err(format!(
+                    "The reserves hold {held} {token} tokens, but its resting orders reserve {reserved}"
+                ));
+            }
+        }
+        Ok(())
+    }
+    /// Checks that the best bid of each continuously matched pair is below its best ask
+    fn check_spreads(&self) -> Result<(), String> {
+        for pair in self.buy_orders.keys() {
+            if self.market_config(pair).auction_interval_ms != 0 {
+                continue;
+            }
+            if let (Some(bid), Some(ask)) = self.best_prices(pair) {
+                if bid >= ask {
+                    return Err(format!(
+                        "The {}/{} book is crossed: best bid {bid} >= best ask {ask}",
+                        pair.0, pair.1
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
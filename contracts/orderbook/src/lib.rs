@@ -1,14 +1,180 @@
 use borsh::{io::Error, BorshDeserialize, BorshSerialize};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
-use sdk::{hyle_model_utils::TimestampMs, BlockHeight, ContractName, LaneId, RunResult};
+use sdk::{
+    hyle_model_utils::TimestampMs, verifiers::Secp256k1Blob, BlockHeight, ContractName, LaneId,
+    RunResult,
+};
 
 #[cfg(feature = "client")]
 pub mod client;
 #[cfg(feature = "client")]
 pub mod indexer;
+pub mod blobs;
+pub mod book;
+pub mod dust;
+pub mod error;
+pub mod history;
+pub mod incentives;
+#[cfg(any(test, feature = "invariants"))]
+pub mod invariants;
+pub mod liquidity;
+pub mod market;
+pub mod snapshot;
+pub mod volume;
+pub mod withdrawals;
+pub mod witness;
+
+use blobs::CompanionBlobs;
+use book::PriceLevels;
+use error::OrderbookError;
+use history::{Trade, TradeHistory};
+use incentives::MakerIncentives;
+use liquidity::LiquidityPoints;
+use market::{FeeSchedule, MarketConfig};
+use volume::TradingVolumes;
+use withdrawals::PendingWithdrawal;
+
+/// Maximum number of actions a single identity can get executed in one block
+pub const MAX_ACTIONS_PER_BLOCK: u32 = 50;
+
+/// Account holding the funds reserved by the resting orders, see [`Orderbook::reserves`]
+pub const RESERVES: &str = "orderbook:reserves";
+
+/// Account collecting the fees, and the price surplus of the pairs whose taker price policy is
+/// [`market::TakerPricePolicy::FeePool`]
+pub const FEE_POOL: &str = "orderbook:fees";
+
+/// Account holding the operational funds of the orderbook, see [`Orderbook::fund_insurance`]
+pub const INSURANCE: &str = "orderbook:insurance";
+
+/// Account holding the liquidity mining rewards, see [`liquidity`]
+pub const INCENTIVES_POOL: &str = "orderbook:incentives";
+
+/// Account collecting what is left of the quote amounts once rounded, see [`Rounding`]
+pub const DUST: &str = "orderbook:dust";
+
+/// Accounts of the orderbook itself, which users cannot act as. Their funds are never moved
+/// between them, but from the [`RESERVES`] to the [`FEE_POOL`] and [`DUST`] when orders are
+/// filled.
+pub const SYSTEM_ACCOUNTS: [&str; 5] = [RESERVES, FEE_POOL, INSURANCE, INCENTIVES_POOL, DUST];
+
+/// Number of closed orders of each user kept, see [`Orderbook::closed_orders_of`]
+pub const CLOSED_ORDERS_KEPT: usize = 100;
+
+/// Direction quote amounts are rounded in. Amounts paid are rounded up and amounts received
+/// are rounded down, so that a taker never gets more than its fill is worth; the remainders
+/// are swept to the [`DUST`] account instead of being lost or minted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+/// Quote token amount of `quantity` base token units at `price`, prices being in quote token
+/// units per whole base token, i.e. per `scale` units of it. Errors if the amount overflows.
+pub fn quote_amount(
+    quantity: u128,
+    price: u128,
+    scale: u128,
+    rounding: Rounding,
+) -> Result<u128, OrderbookError> {
+    let amount = quantity
+        .checked_mul(price)
+        .ok_or(OrderbookError::AmountOverflow { quantity, price })?;
+    let rounded_up = rounding == Rounding::Up && amount % scale != 0;
+    Ok(amount / scale + u128::from(rounded_up))
+}
+
+/// Difference between the amounts of `quantity` tokens at prices `high` and `low`, rounded down
+fn surplus(quantity: u128, high: u128, low: u128, scale: u128) -> Result<u128, OrderbookError> {
+    let diff = high.checked_sub(low).ok_or(OrderbookError::PriceBelow {
+        price: high,
+        reference: low,
+    })?;
+    quote_amount(quantity, diff, scale, Rounding::Down)
+}
+
+/// What is left of `amount` once the `shares` of it are paid
+fn dust(amount: u128, shares: &[u128]) -> Result<u128, OrderbookError> {
+    shares
+        .iter()
+        .try_fold(amount, |left, share| left.checked_sub(*share))
+        .ok_or_else(|| OrderbookError::SharesExceedAmount {
+            shares: shares.to_vec(),
+            amount,
+        })
+}
+
+/// `a * b / c` rounded down, for pro-rata splits. Saturates instead of overflowing.
+pub(crate) fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    if c == 0 {
+        return 0;
+    }
+    (a / c)
+        .saturating_mul(b)
+        .saturating_add((a % c).saturating_mul(b) / c)
+}
+
+/// Pairs whose book changed in `events`
+fn touched_pairs(events: &[OrderbookEvent]) -> BTreeSet<TokenPair> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            OrderbookEvent::OrderCreated { order } => Some(order.pair.clone()),
+            OrderbookEvent::OrderCancelled { pair, .. }
+            | OrderbookEvent::OrderExecuted { pair, .. }
+            | OrderbookEvent::OrderUpdate { pair, .. } => Some(pair.clone()),
+            OrderbookEvent::BalanceUpdated { .. }
+            | OrderbookEvent::StopOrderPlaced { .. }
+            | OrderbookEvent::StopOrderTriggered { .. }
+            | OrderbookEvent::TradeExecuted { .. }
+            | OrderbookEvent::BookHash { .. }
+            | OrderbookEvent::MakerRewardsDistributed { .. }
+            | OrderbookEvent::LiquidityRewardsDistributed { .. }
+            | OrderbookEvent::MarketConfigured { .. }
+            | OrderbookEvent::TokenConfigured { .. }
+            | OrderbookEvent::FeeCharged { .. }
+            | OrderbookEvent::FeeScheduleUpdated { .. }
+            | OrderbookEvent::UserRegistered { .. }
+            | OrderbookEvent::ReferrerRegistered { .. }
+            | OrderbookEvent::ReferralRewarded { .. }
+            | OrderbookEvent::TokenAdded { .. }
+            | OrderbookEvent::TokenRemoved { .. }
+            | OrderbookEvent::LaneAdded { .. }
+            | OrderbookEvent::LaneRemoved { .. }
+            | OrderbookEvent::DustThresholdUpdated { .. }
+            | OrderbookEvent::DustSwept { .. }
+            | OrderbookEvent::PairCreated { .. }
+            | OrderbookEvent::PairDelisted { .. }
+            | OrderbookEvent::MarketHalted { .. }
+            | OrderbookEvent::MarketResumed { .. }
+            | OrderbookEvent::OrdersLinked { .. }
+            | OrderbookEvent::AuctionCleared { .. }
+            | OrderbookEvent::WithdrawalRequested { .. }
+            | OrderbookEvent::WithdrawalClaimed { .. }
+            | OrderbookEvent::WithdrawalCancelled { .. } => None,
+        })
+        .collect()
+}
 
+/// Ids of the orders, makers or takers, that traded in `events`
+fn traded_order_ids(events: &[OrderbookEvent]) -> BTreeSet<String> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            OrderbookEvent::TradeExecuted {
+                maker_order_id,
+                taker_order_id,
+                ..
+            } => Some([maker_order_id.clone(), taker_order_id.clone()]),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
 
 impl sdk::FullStateRevert for Orderbook {}
 
@@ -16,90 +182,301 @@ impl sdk::ZkContract for Orderbook {
     /// Entry point of the contract's logic
     fn execute(&mut self, calldata: &sdk::Calldata) -> RunResult {
         // Parse contract inputs
-        let (action, ctx) = sdk::utils::parse_raw_calldata::<OrderbookAction>(calldata)?;
+        let (blob, ctx) = sdk::utils::parse_raw_calldata::<OrderbookBlob>(calldata)
+            .map_err(|reason| OrderbookError::InvalidCalldata { reason }.encode())?;
+
+        let events = self
+            .execute_blob(blob, calldata)
+            .map_err(|error| error.encode())?;
+
+        let res = borsh::to_vec(&events).map_err(|_| OrderbookError::EncodingFailed.encode())?;
+
+        Ok((res, ctx, vec![]))
+    }
+
+    /// See [`witness`] for how the state is committed
+    fn commit(&self) -> sdk::StateCommitment {
+        self.state_commitment()
+    }
+}
 
+impl Orderbook {
+    /// Checks the transaction of the orderbook blob `blob`, then executes its action
+    fn execute_blob(
+        &mut self,
+        blob: OrderbookBlob,
+        calldata: &sdk::Calldata,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
         let user = calldata.identity.0.clone();
 
         let Some(tx_ctx) = &calldata.tx_ctx else {
-            return Err("tx_ctx is missing".to_string());
+            return Err(OrderbookError::MissingTxContext);
         };
 
-        if tx_ctx.lane_id != self.lane_id {
-            return Err("Invalid lane id".to_string());
+        if !self.lane_ids.contains(&tx_ctx.lane_id) {
+            return Err(OrderbookError::InvalidLaneId);
         }
 
         // The contract must be provided with all blobs
         if calldata.blobs.len() != calldata.tx_blob_count {
-            return Err("Calldata is not composed with all tx's blobs".to_string());
+            return Err(OrderbookError::IncompleteCalldata);
         }
 
         // Check if blobs in the calldata are all whitelisted
         for (_, blob) in &calldata.blobs {
             if !self.is_blob_whitelisted(&blob.contract_name) {
-                return Err(format!(
-                    "Blob with contract name {} is not whitelisted",
-                    blob.contract_name
-                ));
+                return Err(OrderbookError::BlobNotWhitelisted {
+                    contract_name: blob.contract_name.0.clone(),
+                });
             }
         }
 
+        // The wallet or signature blob proves the transaction was sent by its identity
+        self.verify_identity(calldata)?;
+        if SYSTEM_ACCOUNTS.contains(&user.as_str()) {
+            return Err(OrderbookError::SystemIdentity { identity: user });
+        }
+
+        // Replayed and reordered blobs are rejected
+        self.use_nonce(&user, blob.nonce)?;
+
+        // Keep a single user from filling a whole block, and its proof
+        self.record_action(&user, tx_ctx.block_height)?;
+
         // Execute the given action
+        let mut blobs = CompanionBlobs::new(calldata);
+        let events = self.execute_action(blob.action, user, &mut blobs, tx_ctx)?;
+
+        let pairs = touched_pairs(&events);
+        self.accrue_liquidity_points(&pairs, &tx_ctx.timestamp);
+
+        // Let clients check their local books against the updated ones
+        let mut events = events;
+        events.extend(self.book_hash_events(pairs));
+        self.event_seq += events.len() as u64;
+        Ok(events)
+    }
+
+    /// Executes `action` sent by `user`. A failure fails the whole transaction, and none of its
+    /// effects are kept.
+    fn execute_action(
+        &mut self,
+        action: OrderbookAction,
+        user: String,
+        blobs: &mut CompanionBlobs,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
         let events = match action {
             OrderbookAction::CreateOrder {
-                order_id,
                 order_type,
                 price,
                 pair,
                 quantity,
+                time_in_force,
+                trigger_price,
+                self_trade_prevention,
+                expires_at,
+                display_quantity,
             } => {
                 let order = Order {
                     owner: user,
-                    order_id,
+                    order_id: Self::order_id(&pair, self.next_order_seq),
                     order_type,
                     price,
+                    trigger_price,
                     pair,
                     quantity,
                     timestamp: tx_ctx.timestamp.clone(),
+                    expires_at,
+                    display_quantity,
+                    hidden_quantity: 0,
+                    filled_quantity: 0,
+                    status: OrderStatus::Open,
+                    reserved_amount: 0,
                 };
-                if self.orders.contains_key(&order.order_id) {
-                    return Err(format!("Order with id {} already exists", order.order_id));
+                if self.orders.contains_key(&order.order_id)
+                    || self.stop_order(&order.order_id).is_some()
+                {
+                    return Err(OrderbookError::OrderExists {
+                        order_id: order.order_id,
+                    });
                 }
-                self.execute_order(order, tx_ctx)?
+                if !self.trades_pair(&order.pair) {
+                    return Err(OrderbookError::PairOnOtherInstance { pair: order.pair });
+                }
+                if !self.is_pair_listed(&order.pair) {
+                    return Err(OrderbookError::PairNotListed { pair: order.pair });
+                }
+                self.ensure_canonical(&order.pair)?;
+                self.ensure_trading(&order.pair)?;
+                self.market_config(&order.pair).check_auction_order(
+                    &order,
+                    &time_in_force,
+                    &self_trade_prevention,
+                )?;
+                let events = if order.trigger_price.is_some() {
+                    self.place_stop_order(order, time_in_force, self_trade_prevention)?
+                } else {
+                    self.execute_order_with_policy(
+                        order,
+                        time_in_force,
+                        self_trade_prevention,
+                        tx_ctx,
+                    )?
+                };
+                self.next_order_seq += 1;
+                events
             }
-            OrderbookAction::Cancel { order_id } => self.cancel_order(order_id, user)?,
+            OrderbookAction::Cancel { order_id } => self.cancel_order(order_id, user, tx_ctx)?,
+            OrderbookAction::ModifyOrder {
+                order_id,
+                new_price,
+                new_quantity,
+            } => self.modify_order(order_id, new_price, new_quantity, user, tx_ctx)?,
             OrderbookAction::Deposit { token, amount } => {
-                // TODO: assert there is a transfer blob for that token
+                // The tokens enter the orderbook account in the same transaction
+                blobs.find_deposit(&token.clone().into(), amount)?;
                 self.deposit(token, amount, user, tx_ctx)?
             }
-            OrderbookAction::Withdraw { token, amount } => {
-                // TODO: assert there is a transfer blob for that token
+            OrderbookAction::DepositFromWallet {
+                token,
+                amount,
+                transfer,
+            } => {
+                blobs.expect_deposit(transfer, &token.clone().into(), amount)?;
+                self.deposit(token, amount, user, tx_ctx)?
+            }
+            OrderbookAction::Withdraw {
+                token,
+                amount,
+                recipient,
+            } => {
+                if self.withdrawal_delay > 0 {
+                    return Err(OrderbookError::WithdrawalDelayed);
+                }
+                // The tokens must leave the orderbook account in the same transaction
+                let recipient = recipient.unwrap_or_else(|| user.clone());
+                blobs.expect_payout(&token.clone().into(), &recipient.into(), amount)?;
                 self.withdraw(token, amount, user)?
             }
+            OrderbookAction::RequestWithdraw {
+                token,
+                amount,
+                recipient,
+            } => {
+                let recipient = recipient.unwrap_or_else(|| user.clone());
+                self.request_withdraw(token, amount, user, recipient, tx_ctx)?
+            }
+            OrderbookAction::ClaimWithdraw { withdrawal_id } => {
+                self.claim_withdraw(withdrawal_id, user, blobs, tx_ctx)?
+            }
+            OrderbookAction::CancelWithdraw { withdrawal_id } => {
+                self.cancel_withdraw(withdrawal_id, user)?
+            }
+            OrderbookAction::DistributeMakerRewards { token, amount } => {
+                self.distribute_maker_rewards(token, amount, user, tx_ctx)?
+            }
+            OrderbookAction::FundIncentives { token, amount } => {
+                self.fund_incentives(token, amount, user)?
+            }
+            OrderbookAction::DistributeIncentives {
+                pair,
+                token,
+                amount,
+            } => self.distribute_incentives(pair, token, amount, user, tx_ctx)?,
+            OrderbookAction::CloseAccount => self.close_account(user, blobs, tx_ctx)?,
+            OrderbookAction::PruneExpired { pair } => self.prune_expired(&pair, tx_ctx)?,
+            OrderbookAction::RunAuction { pair } => self.run_auction(&pair, tx_ctx)?,
+            OrderbookAction::Register {
+                invite_code_signature,
+            } => {
+                // The secp256k1 blob proves the invite code was signed by the invite key
+                let invite = blobs
+                    .find_secp256k1(blobs.identity(), &Orderbook::invite_code_digest(&user))?;
+                self.register(user, invite_code_signature, &invite)?
+            }
+            OrderbookAction::RegisterReferrer { referrer } => {
+                self.register_referrer(user, referrer)?
+            }
+            OrderbookAction::SubmitSignedOrder {
+                owner,
+                nonce,
+                order,
+                signature,
+            } => self.submit_signed_order(owner, nonce, *order, signature, blobs, tx_ctx)?,
+            OrderbookAction::ConfigureMarket { pair, config } => {
+                self.configure_market(pair, config, user)?
+            }
+            OrderbookAction::ConfigureToken { token, decimals } => {
+                self.configure_token(token, decimals, user)?
+            }
+            OrderbookAction::SetFeeSchedule { schedule } => {
+                self.set_fee_schedule(schedule, user)?
+            }
+            OrderbookAction::WithdrawFees { token, amount } => {
+                self.withdraw_fees(token, amount, user)?
+            }
+            OrderbookAction::FundInsurance { token, amount } => {
+                self.fund_insurance(token, amount, user)?
+            }
+            OrderbookAction::SetDustThreshold { token, threshold } => {
+                self.set_dust_threshold(token, threshold, user)?
+            }
+            OrderbookAction::SweepDust { token } => self.sweep_dust(token)?,
+            OrderbookAction::AddLane { lane_id } => self.add_lane(lane_id, user)?,
+            OrderbookAction::RemoveLane { lane_id } => self.remove_lane(lane_id, user)?,
+            OrderbookAction::AddToken { token } => self.add_token(token, user)?,
+            OrderbookAction::RemoveToken { token } => self.remove_token(token, user, tx_ctx)?,
+            OrderbookAction::CreatePair { pair } => self.create_pair(pair, user)?,
+            OrderbookAction::DelistPair { pair } => self.delist_pair(pair, user, tx_ctx)?,
+            OrderbookAction::SetHalted { pair, halted } => self.set_halted(pair, halted, user)?,
+            OrderbookAction::OneCancelsOther { first, second } => {
+                self.create_linked_orders(*first, *second, user, blobs, tx_ctx)?
+            }
+            OrderbookAction::Batch(actions) => {
+                let mut events = vec![];
+                for action in actions {
+                    if matches!(action, OrderbookAction::Batch(_)) {
+                        return Err(OrderbookError::NestedBatch);
+                    }
+                    // A transfer backs a single deposit, see [`CompanionBlobs`]
+                    events.extend(self.execute_action(action, user.clone(), blobs, tx_ctx)?);
+                }
+                events
+            }
         };
-
-        let res =
-            borsh::to_vec(&events).map_err(|_| "Failed to encode OrderbookEvents".to_string())?;
-
-        Ok((res, ctx, vec![]))
-    }
-
-    /// In this example, we serialize the full state on-chain.
-    fn commit(&self) -> sdk::StateCommitment {
-        sdk::StateCommitment(self.as_bytes().expect("Failed to encode Orderbook"))
+        Ok(events)
     }
-}
 
-impl Orderbook {
     pub fn deposit(
         &mut self,
         token: String,
-        amount: u32,
+        amount: u128,
         user: String,
         tx_ctx: &sdk::TxContext,
-    ) -> Result<Vec<OrderbookEvent>, String> {
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if self.invite_key.is_some() && !self.registered.contains(&user) {
+            return Err(OrderbookError::RegistrationRequired { user });
+        }
+        if self
+            .withdraw_only_tokens
+            .contains(&ContractName::from(token.as_str()))
+        {
+            return Err(OrderbookError::TokenWithdrawOnly { token });
+        }
+
         let balance = self.get_balance_mut(&user, &token);
-        *balance += amount;
+        *balance =
+            (balance.checked_add(amount)).ok_or_else(|| OrderbookError::BalanceOverflow {
+                user: user.clone(),
+                token: token.clone(),
+            })?;
         let balance = *balance;
+        let deposits = self.deposits.entry(token.clone()).or_default();
+        *deposits =
+            (deposits.checked_add(amount)).ok_or_else(|| OrderbookError::DepositsOverflow {
+                token: token.clone(),
+            })?;
 
         let latest_deposit_block_height = self.get_latest_deposit_mut(&user, &token);
         *latest_deposit_block_height = tx_ctx.block_height;
@@ -114,1668 +491,8944 @@ impl Orderbook {
     pub fn withdraw(
         &mut self,
         token: String,
-        amount: u32,
+        amount: u128,
         user: String,
-    ) -> Result<Vec<OrderbookEvent>, String> {
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
         let balance = self.get_balance_mut(&user, &token);
 
         if *balance < amount {
-            return Err(format!(
-                "Could not withdraw: Insufficient balance: user {} has {} {} tokens, trying to withdraw {}",
-                user, balance, token, amount
-            ));
+            return Err(OrderbookError::InsufficientBalance {
+                available: *balance,
+                user,
+                token,
+                required: amount,
+            });
         }
 
         *balance -= amount;
+        let balance = *balance;
+        if let Some(deposits) = self.deposits.get_mut(&token) {
+            *deposits = deposits.saturating_sub(amount);
+        }
         Ok(vec![OrderbookEvent::BalanceUpdated {
             user,
             token,
-            amount: *balance,
+            amount: balance,
         }])
     }
 
-    pub fn cancel_order(
+    /// Cancels all orders of the user, withdraws all of its balances and forgets the account.
+    /// Without a withdrawal delay, each balance must be paid out in the same transaction.
+    pub fn close_account(
         &mut self,
-        order_id: String,
         user: String,
-    ) -> Result<Vec<OrderbookEvent>, String> {
-        let order = self
-            .orders
-            .get(&order_id)
-            .ok_or(format!("Order {order_id} not found"))?
-            .clone();
+        blobs: &mut CompanionBlobs,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let order_ids = self.orders_by_owner.get(&user).cloned().unwrap_or_default();
 
-        if order.owner != user {
-            return Err(format!(
-                "User {} is not the owner of order {}",
-                user, order_id
-            ));
+        if order_ids.is_empty() && !self.balances.contains_key(&user) {
+            return Err(OrderbookError::AccountNotFound { user });
         }
 
-        let user = order.owner.clone();
-        let required_token = match &order.order_type {
-            OrderType::Buy => order.pair.1.clone(),
-            OrderType::Sell => order.pair.0.clone(),
-        };
-
-        // Refund the reserved amount to the user
-        self.transfer_tokens(
-            "orderbook",
-            &user,
-            &required_token,
-            order.quantity,
-        )?;
-
-        // Now that all operations have succeeded, remove the order from storage
-        self.orders.remove(&order_id);
+        let mut events = vec![];
+        for order_id in order_ids {
+            // Linked orders are cancelled together
+            if self.is_open(&order_id) {
+                events.extend(self.cancel_order(order_id, user.clone(), tx_ctx)?);
+            }
+        }
 
-        // Remove from orders list
-        match order.order_type {
-            OrderType::Buy => {
-                if let Some(orders) = self.buy_orders.get_mut(&order.pair) {
-                    orders.retain(|id| id != &order_id);
+        let balances = self.balances.get(&user).cloned().unwrap_or_default();
+        for (token, amount) in balances {
+            if self.withdrawal_delay > 0 {
+                // The balances are claimed once the delay elapsed, after the account is gone
+                if amount > 0 {
+                    events.extend(self.request_withdraw(
+                        token,
+                        amount,
+                        user.clone(),
+                        user.clone(),
+                        tx_ctx,
+                    )?);
                 }
-            }
-            OrderType::Sell => {
-                if let Some(orders) = self.sell_orders.get_mut(&order.pair) {
-                    orders.retain(|id| id != &order_id);
+            } else {
+                if amount > 0 {
+                    blobs.expect_payout(&token.clone().into(), &user.clone().into(), amount)?;
                 }
+                events.extend(self.withdraw(token, amount, user.clone())?);
             }
         }
 
-        let user_balance = self.get_balance(&user, &required_token);
+        self.balances.remove(&user);
+        self.latest_deposit.remove(&user);
 
-        Ok(vec![
-            OrderbookEvent::OrderCancelled { order_id, pair: order.pair },
-            OrderbookEvent::BalanceUpdated {
-                user,
-                token: required_token.to_string(),
-                amount: user_balance,
-            },
-        ])
+        Ok(events)
     }
 
-    fn execute_order(&mut self, mut order: Order, tx_ctx: &sdk::TxContext) -> Result<Vec<OrderbookEvent>, String> {
-        let mut events = Vec::new();
+    pub fn register(
+        &mut self,
+        user: String,
+        invite_code_signature: Vec<u8>,
+        invite: &Secp256k1Blob,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let Some(invite_key) = &self.invite_key else {
+            return Err(OrderbookError::InviteCodesDisabled);
+        };
+        if invite.public_key.as_slice() != invite_key.as_slice()
+            || invite.signature.as_slice() != invite_code_signature.as_slice()
+        {
+            return Err(OrderbookError::InvalidInviteCode { user });
+        }
+        if !self.registered.insert(user.clone()) {
+            return Err(OrderbookError::AlreadyRegistered { user });
+        }
+
+        Ok(vec![OrderbookEvent::UserRegistered { user }])
+    }
 
-        // Check if user has enough balance for the order
-        let user = order.owner.clone();
-        let mut transfers_to_process: Vec<(String, String, String, u32)> = vec![];
-        let mut order_to_insert: Option<Order> = None;
+    /// Records that `user` was referred by `referrer`, who gets a share of the taker fees `user`
+    /// pays from then on. A user's referrer cannot be changed.
+    pub fn register_referrer(
+        &mut self,
+        user: String,
+        referrer: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if referrer == user || SYSTEM_ACCOUNTS.contains(&referrer.as_str()) {
+            return Err(OrderbookError::InvalidReferrer { user, referrer });
+        }
+        if let Some(current) = self.referrers.get(&user) {
+            return Err(OrderbookError::ReferrerAlreadySet {
+                user,
+                referrer: current.clone(),
+            });
+        }
 
+        self.referrers.insert(user.clone(), referrer.clone());
+        Ok(vec![OrderbookEvent::ReferrerRegistered { user, referrer }])
+    }
 
-        let (required_token, required_amount) = match order.order_type {
-            OrderType::Buy => (
-                order.pair.1.clone(),
-                order.price.map(|p| order.quantity * p)
-            ),
-            OrderType::Sell => (
-                order.pair.0.clone(),
-                Some(order.quantity)
-            ),
-        };
+    /// Referrer of `user`, see [`OrderbookAction::RegisterReferrer`]
+    pub fn referrer_of(&self, user: &str) -> Option<&String> {
+        self.referrers.get(user)
+    }
 
-        let user_balance = self.get_balance(&user, &required_token);
-        let latest_deposit_block_height = self.get_latest_deposit(&user, &required_token);
+    pub fn distribute_maker_rewards(
+        &mut self,
+        token: String,
+        amount: u128,
+        user: String,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
 
-        if tx_ctx.block_height < latest_deposit_block_height + 5 {
-            return Err(format!(
-                "User {} tried to execute an order too soon after the last deposit block height: {:?} < {}. 5 blocks are required between deposits and order execution.",
-                user, tx_ctx.block_height, latest_deposit_block_height.0 + 5
-            ));
+        let shares = self.incentives.shares(amount, &tx_ctx.timestamp);
+        if shares.is_empty() {
+            return Err(OrderbookError::NoMakerActivity {
+                epoch: self.incentives.epoch,
+            });
         }
 
-        // For limit orders, verify sufficient balance
-        if let Some(amount) = required_amount {
-            if user_balance < amount {
-                return Err(format!(
-                    "Insufficient balance for {:?} order: user {} has {} {} tokens, requires {}",
-                    order.order_type,
-                    user,
-                    user_balance, 
-                    required_token,
-                    amount
-                ));
-            }
+        let mut events = vec![];
+        for (maker, share) in &shares {
+            self.transfer_tokens(&user, maker, &token, *share)?;
+            events.push(OrderbookEvent::BalanceUpdated {
+                user: maker.clone(),
+                token: token.clone(),
+                amount: self.get_balance(maker, &token),
+            });
         }
+        events.push(OrderbookEvent::BalanceUpdated {
+            user: user.clone(),
+            token: token.clone(),
+            amount: self.get_balance(&user, &token),
+        });
+
+        let epoch = self.incentives.close_epoch(&token, &shares);
+        events.push(OrderbookEvent::MakerRewardsDistributed {
+            epoch,
+            token,
+            amount: shares.values().sum(),
+        });
+        Ok(events)
+    }
 
-        // Try to fill already existing orders
-        match &order.order_type {
-            OrderType::Buy => {
-                let sell_orders_option = self.sell_orders.get_mut(&order.pair);
-
-                if sell_orders_option.is_none() && order.price.is_some() {
-                    // If there are no sell orders and this is a limit order, add it to the orderbook
-
-                    self.orders.insert(order.order_id.clone(), order.clone());
-                    self.buy_orders
-                        .entry(order.pair.clone())
-                        .or_default()
-                        .push_back(order.order_id.clone());
-                    events.push(OrderbookEvent::OrderCreated {
-                        order: order.clone(),
-                    });
+    pub fn configure_market(
+        &mut self,
+        pair: TokenPair,
+        config: MarketConfig,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+        self.ensure_canonical(&pair)?;
 
-                    // Remove liquitidy from the user balance
-                    events.push(OrderbookEvent::BalanceUpdated {
-                        user: user.clone(),
-                        token: required_token.clone(),
-                        amount: user_balance - order.quantity * order.price.unwrap(),
-                    });
-                    let orderbook_balance = self.get_balance("orderbook", &required_token);
-                    events.push(OrderbookEvent::BalanceUpdated {
-                        user: "orderbook".into(),
-                        token: required_token.clone(),
-                        amount: orderbook_balance + order.quantity * order.price.unwrap(),
-                    });
-                    self.transfer_tokens(
-                        &user,
-                        "orderbook",
-                        &required_token,
-                        order.quantity * order.price.unwrap(),
-                    )?;
-
-                    return Ok(events);
-                } else if sell_orders_option.is_none() {
-                    // If there are no sell orders and this is a market order, we cannot proceed
-                    return Err(format!(
-                        "No matching sell orders for market order {}",
-                        order.order_id
-                    ));
-                }
+        self.markets.insert(pair.clone(), config.clone());
+        Ok(vec![OrderbookEvent::MarketConfigured { pair, config }])
+    }
 
-                let sell_orders = sell_orders_option.unwrap();
-
-                // Get the lowest price sell order
-                while let Some(order_id) = sell_orders.pop_front() {
-                    let existing_order = self
-                        .orders
-                        .get_mut(&order_id)
-                        .ok_or(format!("Order {order_id} not found"))?;
-
-                    // If the ordrer is a limit order, check if the *selling* price is lower than the limit price
-                    if let Some(price) = order.price {
-                        let existing_order_price = existing_order.price.expect(
-                        "An order has been stored without a price limit. This should never happen",
-                        );
-                        if existing_order_price > price {
-                            // Place the order in buy_orders
-                            order_to_insert = Some(order);
-
-                            // Put back the sell order we popped
-                            sell_orders.push_front(order_id);
-                            break;
-                        }
-                    }
+    pub fn add_token(
+        &mut self,
+        token: String,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+        if !self.accepted_tokens.insert(token.as_str().into()) {
+            return Err(OrderbookError::TokenAlreadyAccepted { token });
+        }
+        self.withdraw_only_tokens
+            .remove(&ContractName::from(token.as_str()));
+        Ok(vec![OrderbookEvent::TokenAdded { token }])
+    }
 
-                    // Update history
-                    self.orders_history
-                        .entry(order.pair.clone())
-                        .or_default()
-                        .insert(order.timestamp.clone(), existing_order.price.unwrap());
-
-                    // There is an order that can be filled
-                    match existing_order.quantity.cmp(&order.quantity) {
-                        std::cmp::Ordering::Greater => {
-                            // The existing order do not fully cover this order
-                            existing_order.quantity -= order.quantity;
-
-                            sell_orders.push_front(order_id);
-
-                            events.push(OrderbookEvent::OrderUpdate {
-                                order_id: existing_order.order_id.clone(),
-                                remaining_quantity: existing_order.quantity,
-                                pair: order.pair.clone()
-                            });
-
-                            // Send token to the order owner
-                            transfers_to_process.push((
-                                user.clone(),
-                                existing_order.owner.clone(),
-                                order.pair.1.clone(),
-                                existing_order.price.unwrap() * order.quantity,
-                            ));
-                            // Send token to the user
-                            transfers_to_process.push((
-                                "orderbook".to_string(),
-                                user.clone(),
-                                order.pair.0.clone(),
-                                order.quantity,
-                            ));
-                            break;
-                        }
-                        std::cmp::Ordering::Equal => {
-                            // The two orders are executed
-                            events.push(OrderbookEvent::OrderExecuted {
-                                order_id: order_id.clone(),
-                                pair: order.pair.clone()
-                            });
-                            events.push(OrderbookEvent::OrderExecuted {
-                                order_id: order.order_id.clone(),
-                                pair: order.pair.clone()
-                            });
-
-                            // Send token to the order owner
-                            transfers_to_process.push((
-                                user.clone(),
-                                existing_order.owner.clone(),
-                                order.pair.1.clone(),
-                                existing_order.price.unwrap() * existing_order.quantity,
-                            ));
-
-                            // Send token to the user
-                            transfers_to_process.push((
-                                "orderbook".to_string(),
-                                user.clone(),
-                                order.pair.0.clone(),
-                                existing_order.quantity,
-                            ));
-
-                            self.orders.remove(&order_id);
-                            break;
-                        }
-                        std::cmp::Ordering::Less => {
-                            // The existing order is fully filled
-                            events.push(OrderbookEvent::OrderExecuted {
-                                order_id: existing_order.order_id.clone(),
-                                pair: order.pair.clone()
-                            });
-                            transfers_to_process.push((
-                                user.clone(),
-                                existing_order.owner.clone(),
-                                order.pair.1.clone(),
-                                existing_order.price.unwrap() * existing_order.quantity,
-                            ));
-                            transfers_to_process.push((
-                                "orderbook".to_string(),
-                                user.clone(),
-                                order.pair.0.clone(),
-                                existing_order.quantity,
-                            ));
-                            order.quantity -= existing_order.quantity;
-
-                            // We DO NOT push bash the order_id back to the sell orders
-                            self.orders.remove(&order_id);
-
-                            // Update the order to insert
-                            order_to_insert = Some(order.clone());
-                        }
-                    }
-                }
-            }
-            OrderType::Sell => {
-                let buy_orders_option = self.buy_orders.get_mut(&order.pair);
-
-                if buy_orders_option.is_none() && order.price.is_some() {
-                    // If there are no buy orders and this is a limit order, add it to the orderbook
-                    self.orders.insert(order.order_id.clone(), order.clone());
-                    self.sell_orders
-                        .entry(order.pair.clone())
-                        .or_default()
-                        .push_back(order.order_id.clone());
-                    events.push(OrderbookEvent::OrderCreated {
-                        order: order.clone(),
-                    });
+    /// Accepts the transactions sequenced on the lane of validator `lane_id`, in addition to the
+    /// current ones
+    pub fn add_lane(
+        &mut self,
+        lane_id: LaneId,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+        if !self.lane_ids.insert(lane_id.clone()) {
+            return Err(OrderbookError::LaneAlreadyAccepted {
+                lane_id: hex::encode(&lane_id.0 .0),
+            });
+        }
+        Ok(vec![OrderbookEvent::LaneAdded { lane_id }])
+    }
 
-                    // Remove liquitidy from the user balance
-                    events.push(OrderbookEvent::BalanceUpdated {
-                        user: user.clone(),
-                        token: required_token.clone(),
-                        amount: user_balance - order.quantity,
-                    });
-                    let orderbook_balance = self.get_balance("orderbook", &required_token);
-                    events.push(OrderbookEvent::BalanceUpdated {
-                        user: "orderbook".into(),
-                        token: required_token.clone(),
-                        amount: orderbook_balance + order.quantity,
-                    });
-                    self.transfer_tokens(
-                        &user,
-                        "orderbook",
-                        &required_token,
-                        order.quantity,
-                    )?;
-
-                    return Ok(events);
-                } else if buy_orders_option.is_none() {
-                    // If there are no buy orders and this is a market order, we cannot proceed
-                    return Err(format!(
-                        "No matching buy orders for market order {}",
-                        order.order_id
-                    ));
-                }
+    /// Stops accepting the transactions sequenced on the lane `lane_id`. The orderbook must
+    /// keep at least one lane.
+    pub fn remove_lane(
+        &mut self,
+        lane_id: LaneId,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+        if !self.lane_ids.contains(&lane_id) {
+            return Err(OrderbookError::LaneNotAccepted {
+                lane_id: hex::encode(&lane_id.0 .0),
+            });
+        }
+        if self.lane_ids.len() == 1 {
+            return Err(OrderbookError::LastLane {
+                lane_id: hex::encode(&lane_id.0 .0),
+            });
+        }
+        self.lane_ids.remove(&lane_id);
+        Ok(vec![OrderbookEvent::LaneRemoved { lane_id }])
+    }
 
-                let buy_orders = buy_orders_option.unwrap();
-
-                while let Some(order_id) = buy_orders.pop_front() {
-                    let existing_order = self
-                        .orders
-                        .get_mut(&order_id)
-                        .ok_or(format!("Order {order_id} not found"))?;
-
-                    // If the ordrer is a limit order, check if the *buying* price is higher than the limit price
-                    if let Some(price) = order.price {
-                        let existing_order_price = existing_order.price.expect(
-                        "An order has been stored without a price limit. This should never happen",
-                        );
-                        if existing_order_price < price {
-                            // Place the order in sell_orders
-                            order_to_insert = Some(order.clone());
-
-                            // Put back the buy order we popped
-                            buy_orders.push_front(order_id);
-                            break;
-                        }
-                    }
+    /// Lanes the transactions of the orderbook can be sequenced on
+    pub fn lane_ids(&self) -> &BTreeSet<LaneId> {
+        &self.lane_ids
+    }
 
-                    // Update history
-                    self.orders_history
-                        .entry(order.pair.clone())
-                        .or_default()
-                        .insert(order.timestamp.clone(), existing_order.price.unwrap());
-
-                    match existing_order.quantity.cmp(&order.quantity) {
-                        std::cmp::Ordering::Greater => {
-                            // The existing order do not fully cover this order
-                            existing_order.quantity -= order.quantity;
-
-                            buy_orders.push_front(order_id);
-
-                            events.push(OrderbookEvent::OrderUpdate {
-                                order_id: existing_order.order_id.clone(),
-                                remaining_quantity: existing_order.quantity,
-                                pair: order.pair.clone()
-                            });
-
-                            // Send token to the order owner
-                            transfers_to_process.push((
-                                user.clone(),
-                                existing_order.owner.clone(),
-                                order.pair.0.clone(),
-                                order.quantity,
-                            ));
-                            // Send token to the user
-                            transfers_to_process.push((
-                                "orderbook".to_string(),
-                                user.clone(),
-                                order.pair.1.clone(),
-                                existing_order.price.unwrap() * order.quantity,
-                            ));
-                            break;
-                        }
-                        std::cmp::Ordering::Equal => {
-                            // The existing order fully covers this order
-                            events.push(OrderbookEvent::OrderExecuted {
-                                order_id: existing_order.order_id.clone(),
-                                pair: order.pair.clone()
-                            });
-                            // Send token to the order owner
-                            transfers_to_process.push((
-                                user.clone(),
-                                existing_order.owner.clone(),
-                                order.pair.0.clone(),
-                                existing_order.quantity,
-                            ));
-                            transfers_to_process.push((
-                                "orderbook".to_string(),
-                                user.clone(),
-                                order.pair.1.clone(),
-                                existing_order.price.unwrap() * existing_order.quantity,
-                            ));
-
-                            self.orders.remove(&order_id);
-                            break;
-                        }
-                        std::cmp::Ordering::Less => {
-                            // The existing order is fully filled
-                            events.push(OrderbookEvent::OrderExecuted {
-                                order_id: existing_order.order_id.clone(),
-                                pair: order.pair.clone()
-                            });
-                            transfers_to_process.push((
-                                user.clone(),
-                                existing_order.owner.clone(),
-                                order.pair.0.clone(),
-                                existing_order.quantity,
-                            ));
-                            transfers_to_process.push((
-                                "orderbook".to_string(),
-                                user.clone(),
-                                order.pair.1.clone(),
-                                existing_order.price.unwrap() * existing_order.quantity,
-                            ));
-                            order.quantity -= existing_order.quantity;
-
-                            // We DO NOT push bash the order_id back to the buy orders
-                            self.orders.remove(&order_id);
-
-                            // Update the order to insert
-                            order_to_insert = Some(order.clone());
-                        }
-                    }
-                }
+    /// Stops accepting deposits of `token`. The pairs of the token are delisted and their
+    /// orders cancelled: their owners get back what they reserved. Balances of the token can
+    /// still be withdrawn.
+    pub fn remove_token(
+        &mut self,
+        token: String,
+        user: String,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+        if !self
+            .accepted_tokens
+            .remove(&ContractName::from(token.as_str()))
+        {
+            return Err(OrderbookError::TokenNotAccepted { token });
+        }
+        self.withdraw_only_tokens.insert(token.as_str().into());
+
+        let pairs: BTreeSet<TokenPair> = (self.orders.values())
+            .chain(self.stop_orders.values().flatten())
+            .map(|order| &order.pair)
+            .chain(self.listed_pairs.iter().flatten())
+            .filter(|pair| pair.0 == token || pair.1 == token)
+            .cloned()
+            .collect();
+        let mut events = vec![];
+        for pair in pairs {
+            events.extend(self.cancel_orders_of_pair(&pair, tx_ctx, |_| true)?);
+            if (self.listed_pairs.as_mut()).is_some_and(|pairs| pairs.remove(&pair)) {
+                events.push(OrderbookEvent::PairDelisted { pair });
             }
         }
+        events.push(OrderbookEvent::TokenRemoved { token });
+        Ok(events)
+    }
 
-        // If there is still some quantity left, we need to insert the order in the orderbook
-        if let Some(order) = order_to_insert {
-            if order.price.is_some() {
-                self.insert_order(order.clone())?;
-                // Remove liquitidy from the user balance
-                let quantity = match order.order_type {
-                    OrderType::Buy => order.quantity * order.price.unwrap(),
-                    OrderType::Sell => order.quantity,
-                };
-
-                transfers_to_process.push((
-                    user.clone(),
-                    "orderbook".to_string(),
-                    required_token,
-                    quantity,
-                ));
-                events.push(OrderbookEvent::OrderCreated { order });
+    pub fn create_pair(
+        &mut self,
+        pair: TokenPair,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+        for token in [&pair.0, &pair.1] {
+            if !self
+                .accepted_tokens
+                .contains(&ContractName::from(token.as_str()))
+            {
+                let token = token.clone();
+                return Err(OrderbookError::TokenNotAccepted { token });
             }
         }
+        self.ensure_canonical(&pair)?;
+        if !self
+            .listed_pairs
+            .get_or_insert_with(Default::default)
+            .insert(pair.clone())
+        {
+            return Err(OrderbookError::PairAlreadyListed { pair });
+        }
+        Ok(vec![OrderbookEvent::PairCreated { pair }])
+    }
 
-        // Updating balances
-        // If not limit order: assert that total balance in user_to_fund is equal to the order quantity
-        let mut ids = BTreeMap::<String, BTreeSet<String>>::new();
-        for (from, to, token, amout) in transfers_to_process {
-            self.transfer_tokens(&from, &to, &token, amout)?;
-            let t = ids.entry(token.clone()).or_default();
-            t.insert(from.clone());
-            t.insert(to.clone());
+    /// Delists `pair`, cancelling its orders: their owners get back what they reserved
+    pub fn delist_pair(
+        &mut self,
+        pair: TokenPair,
+        user: String,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
         }
-        for (token, users) in ids {
-            for user in users {
-                let user_balance = self.get_balance(&user, &token);
-                events.push(OrderbookEvent::BalanceUpdated {
-                    user: user.clone(),
-                    token: token.clone(),
-                    amount: user_balance,
-                });
-            }
+        let listed = (self.listed_pairs.as_mut()).is_some_and(|pairs| pairs.remove(&pair));
+        if !listed {
+            return Err(OrderbookError::PairNotListed { pair });
         }
 
+        let mut events = self.cancel_orders_of_pair(&pair, tx_ctx, |_| true)?;
+        events.push(OrderbookEvent::PairDelisted { pair });
         Ok(events)
     }
-}
 
-#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Default, Debug, Clone)]
-pub struct Orderbook {
-    // Validator public key of the lane this orderbook is running on
-    lane_id: LaneId,
-    // Map of user address to token balances
-    balances: BTreeMap<String, BTreeMap<String, u32>>,
-    // Map of user address to token latest deposit block height
-    latest_deposit: BTreeMap<String, BTreeMap<String, BlockHeight>>,
-    // All orders indexed by order_id
-    orders: BTreeMap<String, Order>,
-    // Buy orders sorted by price (highest first) for each token pair
-    buy_orders: BTreeMap<TokenPair, VecDeque<String>>,
-    // Sell orders sorted by price (lowest first) for each token pair
-    sell_orders: BTreeMap<TokenPair, VecDeque<String>>,
-    // History of orders executed, indexed by token pair and timestamp
-    orders_history: BTreeMap<TokenPair, BTreeMap<TimestampMs, u32>>,
-    // Accepted tokens
-    accepted_tokens: BTreeSet<ContractName>,
-}
-
-impl Orderbook {
-    fn transfer_tokens(
+    /// Halts or resumes the trading of `pair`, or of every pair if `None`
+    pub fn set_halted(
         &mut self,
-        from: &str,
-        to: &str,
-        token: &str,
-        amount: u32,
-    ) -> Result<(), String> {
-        // Deduct from sender
-        let from_balance = self
-            .balances
-            .get_mut(from)
-            .ok_or(format!("User {} not found", from))?
-            .get_mut(token)
-            .ok_or(format!("Token {} not found for user {}", token, from))?;
-
-        if *from_balance < amount {
-            return Err(format!(
-                "Could not transfer: Insufficient balance: user {} has {} {} tokens, trying to transfer {}",
-                from, from_balance, token, amount
-            ));
+        pair: Option<TokenPair>,
+        halted: bool,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+        let changed = match &pair {
+            None => std::mem::replace(&mut self.paused, halted) != halted,
+            Some(pair) if halted => self.halted_pairs.insert(pair.clone()),
+            Some(pair) => self.halted_pairs.remove(pair),
+        };
+        if !changed {
+            return Err(OrderbookError::HaltUnchanged { pair, halted });
         }
-        *from_balance -= amount;
 
-        // Add to receiver
-        let to_balances = self.balances.entry(to.to_string()).or_default();
-        let to_balance = to_balances.entry(token.to_string()).or_default();
-        *to_balance += amount;
+        Ok(vec![if halted {
+            OrderbookEvent::MarketHalted { pair }
+        } else {
+            OrderbookEvent::MarketResumed { pair }
+        }])
+    }
 
+    /// Errors if orders on `pair` are not accepted while the orderbook or the pair is halted
+    fn ensure_trading(&self, pair: &TokenPair) -> Result<(), OrderbookError> {
+        if self.paused {
+            return Err(OrderbookError::OrderbookHalted);
+        }
+        if self.halted_pairs.contains(pair) {
+            return Err(OrderbookError::PairHalted { pair: pair.clone() });
+        }
         Ok(())
     }
 
-    pub fn get_balance_mut(&mut self, user: &str, token: &str) -> &mut u32 {
-        self.balances
-            .entry(user.to_string())
-            .or_default()
-            .entry(token.to_owned())
-            .or_default()
+    pub fn configure_token(
+        &mut self,
+        token: String,
+        decimals: u8,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+        if 10u128.checked_pow(decimals.into()).is_none() {
+            return Err(OrderbookError::InvalidDecimals { token, decimals });
+        }
+        // The amounts reserved by the resting orders depend on the decimals
+        let has_orders = (self
+            .orders
+            .values()
+            .chain(self.stop_orders.values().flatten()))
+        .any(|order| order.pair.0 == token || order.pair.1 == token);
+        if has_orders {
+            return Err(OrderbookError::TokenHasOpenOrders { token });
+        }
+
+        self.token_decimals.insert(token.clone(), decimals);
+        Ok(vec![OrderbookEvent::TokenConfigured { token, decimals }])
     }
 
-    pub fn get_balance(&mut self, user: &str, token: &str) -> u32 {
-        *self.get_balance_mut(user, token)
+    pub fn set_fee_schedule(
+        &mut self,
+        schedule: FeeSchedule,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+        schedule.validate()?;
+
+        self.fee_schedule = schedule.clone();
+        Ok(vec![OrderbookEvent::FeeScheduleUpdated { schedule }])
     }
 
-    pub fn get_latest_deposit_mut(&mut self, user: &str, token: &str) -> &mut BlockHeight {
-        self.latest_deposit
-            .entry(user.to_string())
-            .or_default()
-            .entry(token.to_owned())
-            .or_default()
+    /// Moves fees accrued in the [`FEE_POOL`] account to the admin's balance, from which they
+    /// can be withdrawn
+    pub fn withdraw_fees(
+        &mut self,
+        token: String,
+        amount: u128,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+
+        self.transfer_tokens(FEE_POOL, &user, &token, amount)?;
+        Ok(vec![
+            OrderbookEvent::BalanceUpdated {
+                user: FEE_POOL.to_string(),
+                token: token.clone(),
+                amount: self.get_balance(FEE_POOL, &token),
+            },
+            OrderbookEvent::BalanceUpdated {
+                amount: self.get_balance(&user, &token),
+                user,
+                token,
+            },
+        ])
     }
 
-    pub fn get_latest_deposit(&mut self, user: &str, token: &str) -> BlockHeight {
-        *self.get_latest_deposit_mut(user, token)
+    /// Moves `amount` of `token` from the admin's balance to the [`INSURANCE`] account. The
+    /// insurance fund is kept apart from the reserves and the fees, and cannot be withdrawn.
+    pub fn fund_insurance(
+        &mut self,
+        token: String,
+        amount: u128,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+
+        self.transfer_tokens(&user, INSURANCE, &token, amount)?;
+        Ok(vec![
+            OrderbookEvent::BalanceUpdated {
+                user: INSURANCE.to_string(),
+                token: token.clone(),
+                amount: self.get_balance(INSURANCE, &token),
+            },
+            OrderbookEvent::BalanceUpdated {
+                amount: self.get_balance(&user, &token),
+                user,
+                token,
+            },
+        ])
     }
 
-    fn insert_order(&mut self, order: Order) -> Result<(), String> {
-        // Function only called for Limit orders
-        let price = order.price.unwrap();
-        if price == 0 {
-            return Err("Price cannot be zero".to_string());
+    /// Queues a stop order until a trade on its pair crosses its trigger price. Nothing is
+    /// reserved in the meantime: the order is cancelled if it cannot be executed once triggered.
+    pub fn place_stop_order(
+        &mut self,
+        order: Order,
+        time_in_force: TimeInForce,
+        self_trade_prevention: Option<SelfTradePrevention>,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if time_in_force != TimeInForce::GoodTilCancelled {
+            return Err(OrderbookError::InvalidOrder {
+                order_id: order.order_id,
+                reason: "is a stop order, which must be good-til-cancelled".to_string(),
+            });
         }
-        let order_list = match order.order_type {
-            OrderType::Buy => self.buy_orders.entry(order.pair.clone()).or_default(),
-            OrderType::Sell => self.sell_orders.entry(order.pair.clone()).or_default(),
+        if self_trade_prevention.is_some() {
+            return Err(OrderbookError::InvalidOrder {
+                order_id: order.order_id,
+                reason: "is a stop order, which cannot have a self-trade prevention policy"
+                    .to_string(),
+            });
+        }
+        self.market_config(&order.pair)
+            .check_order(&order, self.base_scale(&order.pair))?;
+        self.check_open_order_limit(&order)?;
+
+        self.stop_orders
+            .entry(order.pair.clone())
+            .or_default()
+            .push(order.clone());
+        self.index_order(&order);
+        Ok(vec![OrderbookEvent::StopOrderPlaced { order }])
+    }
+
+    pub fn cancel_order(
+        &mut self,
+        order_id: String,
+        user: String,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if let Some(stop_order) = self.stop_order(&order_id) {
+            if stop_order.owner != user {
+                return Err(OrderbookError::NotOwner { user, order_id });
+            }
+            // Nothing was reserved for the order yet
+            let stop_order = stop_order.clone();
+            let pair = stop_order.pair.clone();
+            if let Some(stop_orders) = self.stop_orders.get_mut(&pair) {
+                stop_orders.retain(|order| order.order_id != order_id);
+            }
+            self.unindex_order(&user, &order_id);
+            let status = stop_order.closing_status(&tx_ctx.timestamp);
+            self.archive_order(stop_order, status);
+            let mut events = self.cancel_linked_order(&order_id, tx_ctx)?;
+            events.insert(0, OrderbookEvent::OrderCancelled { order_id, pair });
+            return Ok(events);
+        }
+
+        let order = self
+            .orders
+            .get(&order_id)
+            .ok_or_else(|| OrderbookError::OrderNotFound {
+                order_id: order_id.clone(),
+            })?
+            .clone();
+
+        if order.owner != user {
+            return Err(OrderbookError::NotOwner { user, order_id });
+        }
+
+        let user = order.owner.clone();
+        let required_token = order.reserved_token().clone();
+
+        // Refund the reserved amount to the user
+        self.release(&user, &user, &required_token, order.reserved_amount)?;
+
+        // Now that all operations have succeeded, remove the order from storage
+        self.orders.remove(&order_id);
+        self.unindex_order(&user, &order_id);
+        self.archive_order(order.clone(), order.closing_status(&tx_ctx.timestamp));
+        self.incentives
+            .remove_resting(&user, order.quantity, &tx_ctx.timestamp);
+
+        // Remove from its price level
+        let levels = match order.order_type {
+            OrderType::Buy => self.buy_orders.get_mut(&order.pair),
+            OrderType::Sell => self.sell_orders.get_mut(&order.pair),
         };
+        if let (Some(levels), Some(price)) = (levels, order.price) {
+            levels.remove(price, &order_id);
+        }
 
-        let insert_pos = order_list
-            .iter()
-            .position(|id| {
-                let other_order = self.orders.get(id).unwrap();
-                // To be inserted, the order must be <> than the current one
-                match order.order_type {
-                    OrderType::Buy => other_order.price.unwrap_or(0) < price,
-                    OrderType::Sell => other_order.price.unwrap_or(0) > price,
-                }
-            })
-            .unwrap_or(order_list.len());
+        let user_balance = self.get_balance(&user, &required_token);
+        let linked_events = self.cancel_linked_order(&order_id, tx_ctx)?;
 
-        order_list.insert(insert_pos, order.order_id.clone());
-        self.orders.insert(order.order_id.clone(), order.clone());
-        Ok(())
+        let mut events = vec![
+            OrderbookEvent::OrderCancelled { order_id, pair: order.pair },
+            OrderbookEvent::BalanceUpdated {
+                user,
+                token: required_token.to_string(),
+                amount: user_balance,
+            },
+        ];
+        events.extend(linked_events);
+        Ok(events)
     }
 
-    pub fn is_blob_whitelisted(&self, contract_name: &ContractName) -> bool {
-        self.accepted_tokens.contains(contract_name) || contract_name.0 == "orderbook" || contract_name.0 == "wallet" || contract_name.0 == "secp256k1"
+    /// Creates the order of a [`OrderbookAction::SubmitSignedOrder`], on behalf of its owner
+    fn submit_signed_order(
+        &mut self,
+        owner: String,
+        nonce: u64,
+        order: OrderbookAction,
+        signature: Vec<u8>,
+        blobs: &mut CompanionBlobs,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if !matches!(order, OrderbookAction::CreateOrder { .. }) {
+            return Err(OrderbookError::InvalidAction {
+                reason: "Signed orders must be created with CreateOrder".to_string(),
+            });
+        }
+        // The secp256k1 blob proves the owner signed the order, whoever relays it
+        let digest = Orderbook::signed_order_digest(&owner, nonce, &order);
+        let signed = blobs.find_secp256k1(&sdk::Identity(owner.clone()), &digest)?;
+        Self::check_signer(&owner, &signed)?;
+        if signed.signature.as_slice() != signature.as_slice() {
+            return Err(OrderbookError::InvalidOrderSignature { owner });
+        }
+        // The owner's own limits apply, not the relayer's
+        self.use_nonce(&owner, nonce)?;
+        self.record_action(&owner, tx_ctx.block_height)?;
+        self.execute_action(order, owner, blobs, tx_ctx)
     }
 
-    pub fn as_bytes(&self) -> Result<Vec<u8>, Error> {
-        borsh::to_vec(self)
+    /// Creates the orders of [`OrderbookAction::OneCancelsOther`], and links them unless one of
+    /// them already traded or did not rest
+    fn create_linked_orders(
+        &mut self,
+        first: OrderbookAction,
+        second: OrderbookAction,
+        user: String,
+        blobs: &mut CompanionBlobs,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let (
+            OrderbookAction::CreateOrder { pair, .. },
+            OrderbookAction::CreateOrder {
+                pair: second_pair, ..
+            },
+        ) = (&first, &second)
+        else {
+            return Err(OrderbookError::InvalidAction {
+                reason: "One-cancels-other orders must both be created with CreateOrder"
+                    .to_string(),
+            });
+        };
+        if pair != second_pair {
+            return Err(OrderbookError::InvalidAction {
+                reason: format!(
+                    "One-cancels-other orders must be on the same pair, not {}-{} and {}-{}",
+                    pair.0, pair.1, second_pair.0, second_pair.1
+                ),
+            });
+        }
+        let pair = pair.clone();
+        let first_id = Self::order_id(&pair, self.next_order_seq);
+        let second_id = Self::order_id(&pair, self.next_order_seq + 1);
+
+        let mut events = self.execute_action(first, user.clone(), blobs, tx_ctx)?;
+        events.extend(self.execute_action(second, user.clone(), blobs, tx_ctx)?);
+
+        let traded = traded_order_ids(&events);
+        let first_done = traded.contains(&first_id) || !self.is_open(&first_id);
+        let second_done = traded.contains(&second_id) || !self.is_open(&second_id);
+        if !first_done && !second_done {
+            self.linked_orders
+                .insert(first_id.clone(), second_id.clone());
+            self.linked_orders
+                .insert(second_id.clone(), first_id.clone());
+            events.push(OrderbookEvent::OrdersLinked {
+                pair,
+                order_id: first_id,
+                linked_order_id: second_id,
+            });
+            return Ok(events);
+        }
+
+        // What is left of the other order is cancelled right away
+        if first_done && self.is_open(&second_id) {
+            events.extend(self.cancel_order(second_id.clone(), user.clone(), tx_ctx)?);
+        }
+        if second_done && self.is_open(&first_id) {
+            events.extend(self.cancel_order(first_id, user, tx_ctx)?);
+        }
+        Ok(events)
     }
-}
 
+    /// Cancels the order linked to `order_id`, if it is still open, and unlinks them
+    fn cancel_linked_order(
+        &mut self,
+        order_id: &str,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let Some(linked_order_id) = self.linked_orders.remove(order_id) else {
+            return Ok(vec![]);
+        };
+        self.linked_orders.remove(&linked_order_id);
+        let owner = self
+            .orders
+            .get(&linked_order_id)
+            .or(self.stop_order(&linked_order_id))
+            .map(|order| order.owner.clone());
+        match owner {
+            Some(owner) => self.cancel_order(linked_order_id, owner, tx_ctx),
+            None => Ok(vec![]),
+        }
+    }
 
-impl Orderbook {
-    pub fn init(lane_id: LaneId) -> Self {
-        let mut balances = BTreeMap::new();
-        balances.insert("orderbook".to_string(), BTreeMap::new());
+    /// Changes the price and quantity of a resting order in a single transaction. The order
+    /// keeps its place in its price level unless its price changes: it is then matched again
+    /// like a new order.
+    pub fn modify_order(
+        &mut self,
+        order_id: String,
+        new_price: u128,
+        new_quantity: u128,
+        user: String,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let order = self
+            .orders
+            .get(&order_id)
+            .ok_or_else(|| OrderbookError::OrderNotFound {
+                order_id: order_id.clone(),
+            })?
+            .clone();
 
-        let accepted_tokens = BTreeSet::from([
-            "oranj".into(),
-            "hyllar".into(),
-        ]);
+        if order.owner != user {
+            return Err(OrderbookError::NotOwner { user, order_id });
+        }
+        if order.is_expired(&tx_ctx.timestamp) {
+            return Err(OrderbookError::OrderExpired { order_id });
+        }
+        self.ensure_trading(&order.pair)?;
+        if new_price == 0 {
+            return Err(OrderbookError::ZeroPrice);
+        }
+        if new_quantity == 0 {
+            return Err(OrderbookError::InvalidOrder {
+                order_id,
+                reason: "cannot be modified to a zero quantity, cancel it instead".to_string(),
+            });
+        }
+        let modified = Order {
+            price: Some(new_price),
+            quantity: new_quantity,
+            ..order.clone()
+        };
+        self.market_config(&order.pair)
+            .check_order(&modified, self.base_scale(&order.pair))?;
+
+        if order.price != Some(new_price) {
+            // The order loses its time priority, and may trade at its new price. It leaves the
+            // book without being closed, so it stays linked to its one-cancels-other order.
+            let token = order.reserved_token().clone();
+            self.release(&user, &user, &token, order.reserved_amount)?;
+            self.orders.remove(&order_id);
+            self.unindex_order(&user, &order_id);
+            self.incentives
+                .remove_resting(&user, order.quantity, &tx_ctx.timestamp);
+            let levels = match order.order_type {
+                OrderType::Buy => self.buy_orders.get_mut(&order.pair),
+                OrderType::Sell => self.sell_orders.get_mut(&order.pair),
+            };
+            if let (Some(levels), Some(price)) = (levels, order.price) {
+                levels.remove(price, &order_id);
+            }
+            let replacement = Order {
+                price: Some(new_price),
+                quantity: new_quantity,
+                timestamp: tx_ctx.timestamp.clone(),
+                hidden_quantity: 0,
+                filled_quantity: 0,
+                status: OrderStatus::Open,
+                reserved_amount: 0,
+                ..order
+            };
+            let events = self.execute_order(replacement, TimeInForce::GoodTilCancelled, tx_ctx)?;
+            // What rests again is the same order, updated rather than created
+            let events = events.into_iter().map(|event| match event {
+                OrderbookEvent::OrderCreated { order } if order.order_id == order_id => {
+                    OrderbookEvent::OrderUpdate {
+                        order_id: order.order_id,
+                        remaining_quantity: order.quantity,
+                        pair: order.pair,
+                        executed_price: None,
+                    }
+                }
+                event => event,
+            });
+            return Ok(events.collect());
+        }
+        if new_quantity <= order.quantity {
+            return self.reduce_order(&order, order.quantity - new_quantity, tx_ctx);
+        }
 
-        Orderbook {
-            lane_id,
-            balances,
-            latest_deposit: BTreeMap::new(),
-            orders: BTreeMap::new(),
-            buy_orders: BTreeMap::new(),
-            sell_orders: BTreeMap::new(),
-            orders_history: BTreeMap::new(),
-            accepted_tokens
+        let increment = new_quantity - order.quantity;
+        let mut increased = order.clone();
+        increased.quantity = new_quantity;
+        if increased.display_quantity.is_some() {
+            // The shown slice of an iceberg order stays the same
+            increased.hidden_quantity += increment;
         }
+        increased.reserved_amount = self.required_reservation(&increased)?;
+        let token = order.reserved_token().clone();
+        let amount = dust(increased.reserved_amount, &[order.reserved_amount])?;
+        self.ensure_deposit_settled(&user, &token, tx_ctx)?;
+        self.reserve(&user, &token, amount)?;
+        self.incentives
+            .add_resting(&user, increment, &tx_ctx.timestamp);
+        self.orders.insert(order_id.clone(), increased);
+
+        Ok(vec![
+            OrderbookEvent::OrderUpdate {
+                order_id,
+                remaining_quantity: new_quantity,
+                pair: order.pair,
+                executed_price: None,
+            },
+            OrderbookEvent::BalanceUpdated {
+                amount: self.get_balance(&user, &token),
+                user,
+                token,
+            },
+        ])
     }
 
-    pub fn partial_commit(&self) -> sdk::StateCommitment {
-        let mut partial_state = self.clone();
-        partial_state.latest_deposit = Default::default();
-        partial_state.orders_history = Default::default();
+    /// Funds deposited are only available for orders 5 blocks after the deposit
+    fn ensure_deposit_settled(
+        &mut self,
+        user: &str,
+        token: &str,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<(), OrderbookError> {
+        let latest_deposit_block_height = self.get_latest_deposit(user, token);
 
-        // Reset all order timestamps to 0
-        for (_, order) in partial_state.orders.iter_mut() {
-            order.timestamp = TimestampMs(0);
+        if tx_ctx.block_height < latest_deposit_block_height + 5 {
+            return Err(OrderbookError::DepositNotSettled {
+                user: user.to_string(),
+                token: token.to_string(),
+                settled_at: latest_deposit_block_height.0 + 5,
+            });
         }
+        Ok(())
+    }
 
-        sdk::StateCommitment(borsh::to_vec(&partial_state).expect("Failed to encode Orderbook partial state"))
+    fn execute_order(
+        &mut self,
+        order: Order,
+        time_in_force: TimeInForce,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        self.execute_order_with_policy(order, time_in_force, None, tx_ctx)
     }
-}
 
-/// Enum representing possible calls to the contract functions.
-#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Debug, Clone)]
-pub enum OrderbookAction {
-    CreateOrder {
-        order_id: String,
-        order_type: OrderType,
-        price: Option<u32>,
-        pair: TokenPair,
-        quantity: u32,
-    },
-    Cancel {
-        order_id: String,
-    },
-    Deposit {
-        token: String,
-        amount: u32,
-    },
-    Withdraw {
-        token: String,
-        amount: u32,
-    },
-}
+    /// Matches `order` against the book, and rests what is left of it. Without a
+    /// `self_trade_prevention` policy, the order can trade with orders of the same owner.
+    fn execute_order_with_policy(
+        &mut self,
+        mut order: Order,
+        time_in_force: TimeInForce,
+        self_trade_prevention: Option<SelfTradePrevention>,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        // Expired orders are never matched
+        let mut events = self.prune_expired(&order.pair, tx_ctx)?;
+        let market = self.market_config(&order.pair);
+        let auction = market.auction_interval_ms != 0;
+        if auction && self.auction_due(&order.pair, &tx_ctx.timestamp) {
+            // The order arrived after the auction cut: it waits for the next auction
+            events.extend(self.run_auction(&order.pair, tx_ctx)?);
+        }
+        if let Some(expires_at) = &order.expires_at {
+            if time_in_force != TimeInForce::GoodTilCancelled {
+                return Err(OrderbookError::InvalidOrder {
+                    order_id: order.order_id,
+                    reason: "cannot expire unless it is good-til-cancelled".to_string(),
+                });
+            }
+            if *expires_at <= tx_ctx.timestamp {
+                return Err(OrderbookError::OrderExpired {
+                    order_id: order.order_id,
+                });
+            }
+        }
+        if order.display_quantity == Some(0) {
+            return Err(OrderbookError::InvalidOrder {
+                order_id: order.order_id,
+                reason: "cannot have a zero display quantity".to_string(),
+            });
+        }
+        // Dust orders, off-tick prices and prices far from the book are rejected
+        market.check_order(&order, self.base_scale(&order.pair))?;
+        market.check_price_band(&order, self.band_reference(&order.pair))?;
 
-#[derive(Debug, Serialize, Deserialize, Clone, BorshSerialize, BorshDeserialize)]
-pub struct Order {
-    pub owner: String,
-    pub order_id: String,
-    pub order_type: OrderType,
-    pub price: Option<u32>,
-    pub pair: TokenPair,
-    pub quantity: u32,
-    pub timestamp: TimestampMs,
-}
+        // Check if user has enough balance for the order
+        let user = order.owner.clone();
+        let pair = order.pair.clone();
+        let mut transfers_to_process: Vec<(String, String, String, u128)> = vec![];
+        // Owner and filled quantity of each resting order matched
+        let mut maker_fills: Vec<(String, u128)> = vec![];
+        // Price of the last trade, activating the stop orders it crosses
+        let mut last_price = None;
+        let scale = self.base_scale(&pair);
 
-#[derive(Debug, Serialize, Deserialize, Clone, BorshSerialize, BorshDeserialize)]
-pub enum OrderType {
-    Buy,
-    Sell,
-}
+        let (required_token, required_amount) = match order.order_type {
+            OrderType::Buy => (
+                order.pair.1.clone(),
+                order
+                    .price
+                    .map(|p| quote_amount(order.quantity, p, scale, Rounding::Up))
+                    .transpose()?,
+            ),
+            OrderType::Sell => (order.pair.0.clone(), Some(order.quantity)),
+        };
 
-pub type TokenPair = (String, String);
+        let user_balance = self.get_balance(&user, &required_token);
+        self.ensure_deposit_settled(&user, &required_token, tx_ctx)?;
 
-#[derive(Debug, Serialize, Deserialize, Clone, BorshSerialize, BorshDeserialize)]
-pub enum OrderbookEvent {
-    OrderCreated {
-        order: Order,
-    },
-    OrderCancelled {
-        order_id: String,
-        pair: TokenPair,
-    },
-    OrderExecuted {
-        order_id: String,
-        pair: TokenPair,
-    },
-    OrderUpdate {
-        order_id: String,
-        remaining_quantity: u32,
-        pair: TokenPair,
-    },
-    BalanceUpdated {
-        user: String,
-        token: String,
-        amount: u32,
-    },
+        // For limit orders, verify sufficient balance
+        if let Some(amount) = required_amount {
+            if user_balance < amount {
+                return Err(OrderbookError::InsufficientBalance {
+                    user,
+                    token: required_token,
+                    available: user_balance,
+                    required: amount,
+                });
+            }
+        }
+
+        // Try to fill already existing orders
+        let resting_orders = match order.order_type {
+            OrderType::Buy => self.sell_orders.get(&pair),
+            OrderType::Sell => self.buy_orders.get(&pair),
+        };
+        let has_resting_orders = resting_orders.is_some_and(|levels| !levels.is_empty());
+        if order.price.is_none() && !has_resting_orders {
+            // If there are no orders to fill and this is a market order, we cannot proceed
+            let side = match order.order_type {
+                OrderType::Buy => OrderType::Sell,
+                OrderType::Sell => OrderType::Buy,
+            };
+            return Err(OrderbookError::NoLiquidity {
+                order_id: order.order_id,
+                side,
+            });
+        }
+
+        // Fill-or-kill orders are checked before anything is matched
+        if time_in_force == TimeInForce::FillOrKill {
+            let available = self
+                .crossing_orders(&order)
+                .fold(0, |total: u128, resting| {
+                    total.saturating_add(resting.quantity)
+                });
+            if available < order.quantity {
+                return Err(OrderbookError::FillOrKillUnfilled {
+                    order_id: order.order_id,
+                    available,
+                    quantity: order.quantity,
+                });
+            }
+            // Preventing a self-trade would leave the order partially filled
+            let self_trade = self
+                .crossing_orders(&order)
+                .any(|resting| resting.owner == user);
+            if self_trade && self_trade_prevention.is_some() {
+                return Err(OrderbookError::FillOrKillSelfTrade {
+                    order_id: order.order_id,
+                    user,
+                });
+            }
+        }
+
+        // Fill the best price level, as allocated by the pair's matching mode, until the order
+        // is filled or the book no longer crosses its limit price. Orders of batch-auction
+        // pairs are only matched by the auctions.
+        while order.quantity > 0 && !auction {
+            let Some((price, level)) = self.best_level(&order) else {
+                break;
+            };
+
+            // Prices at which the maker sells or buys, and the taker pays or gets paid
+            let (maker_price, taker_price) = market.taker_price.fill_prices(price, order.price);
+
+            // A level whose orders cannot be filled would be matched forever: the order rests,
+            // and is rejected if it crosses it
+            let fills = market.matching.allocate(&level, order.quantity);
+            if fills.is_empty() {
+                break;
+            }
+            for (order_id, quantity) in fills {
+                let existing_order = self.orders.get_mut(&order_id).ok_or_else(|| {
+                    OrderbookError::OrderNotFound {
+                        order_id: order_id.clone(),
+                    }
+                })?;
+                if let Some(policy) = self_trade_prevention.filter(|_| existing_order.owner == user)
+                {
+                    // The orders changed: the level is allocated again
+                    let resting = existing_order.clone();
+                    events.extend(self.prevent_self_trade(policy, &mut order, resting, tx_ctx)?);
+                    break;
+                }
+                let reserved_before = existing_order.reserved_amount;
+                existing_order.quantity -= quantity;
+                existing_order.record_fill(quantity);
+                existing_order.reserved_amount = match existing_order.order_type {
+                    OrderType::Buy => {
+                        quote_amount(existing_order.quantity, price, scale, Rounding::Up)?
+                    }
+                    OrderType::Sell => existing_order.quantity,
+                };
+                // The maker pays the trade from what it no longer needs reserved
+                let released = dust(reserved_before, &[existing_order.reserved_amount])?;
+                let maker = existing_order.owner.clone();
+                let remaining_quantity = existing_order.quantity;
+
+                // Update history
+                let trade = Trade {
+                    id: 0,
+                    timestamp: order.timestamp.clone(),
+                    price: maker_price,
+                    quantity,
+                    taker_side: order.order_type.clone(),
+                    maker: maker.clone(),
+                    taker: user.clone(),
+                };
+                self.trade_history.entry(pair.clone()).or_default().record(
+                    &market.history,
+                    scale,
+                    trade,
+                );
+                maker_fills.push((maker.clone(), quantity));
+                last_price = Some(maker_price);
+                let trade = OrderbookEvent::TradeExecuted {
+                    pair: pair.clone(),
+                    price: maker_price,
+                    quantity,
+                    maker_order_id: order_id.clone(),
+                    taker_order_id: order.order_id.clone(),
+                    maker: maker.clone(),
+                    taker: user.clone(),
+                    timestamp: order.timestamp.clone(),
+                };
+
+                events.push(trade);
+                if remaining_quantity > 0 {
+                    // The existing order is partially filled
+                    events.push(OrderbookEvent::OrderUpdate {
+                        order_id: order_id.clone(),
+                        remaining_quantity,
+                        pair: pair.clone(),
+                        executed_price: Some(maker_price),
+                    });
+                    if existing_order.visible_quantity() == 0 {
+                        // The shown slice of an iceberg order is consumed: the next one goes to
+                        // the back of the price level
+                        existing_order.hide_quantity();
+                        existing_order.timestamp = tx_ctx.timestamp.clone();
+                        let resting_orders = match order.order_type {
+                            OrderType::Buy => self.sell_orders.get_mut(&pair),
+                            OrderType::Sell => self.buy_orders.get_mut(&pair),
+                        };
+                        if let Some(levels) = resting_orders {
+                            levels.remove(price, &order_id);
+                            levels.insert(price, order_id.clone());
+                        }
+                    }
+                } else {
+                    // The existing order is fully filled
+                    if let Some(filled) = self.orders.remove(&order_id) {
+                        self.archive_order(filled, OrderStatus::Filled);
+                    }
+                    self.unindex_order(&maker, &order_id);
+                    let resting_orders = match order.order_type {
+                        OrderType::Buy => self.sell_orders.get_mut(&pair),
+                        OrderType::Sell => self.buy_orders.get_mut(&pair),
+                    };
+                    if let Some(levels) = resting_orders {
+                        levels.remove(price, &order_id);
+                    }
+                    events.push(OrderbookEvent::OrderExecuted {
+                        order_id: order_id.clone(),
+                        pair: pair.clone(),
+                        executed_price: Some(maker_price),
+                    });
+                    if quantity == order.quantity && matches!(order.order_type, OrderType::Buy) {
+                        // The two orders are executed. Sell takers are not reported as executed.
+                        events.push(OrderbookEvent::OrderExecuted {
+                            order_id: order.order_id.clone(),
+                            pair: pair.clone(),
+                            executed_price: Some(taker_price),
+                        });
+                    }
+                }
+
+                // Fees are taken from what each side receives, at the rates of the volume each
+                // side traded before the transaction
+                let maker_volume = self.volumes.volume(&maker, &tx_ctx.timestamp);
+                let taker_volume = self.volumes.volume(&user, &tx_ctx.timestamp);
+                let (maker_fee, taker_fee) = match order.order_type {
+                    OrderType::Buy => {
+                        // Send token to the order owner, and the locked base token to the user.
+                        // The user only pays its limit price if the surplus goes to the fee pool.
+                        let paid = quote_amount(quantity, taker_price, scale, Rounding::Up)?;
+                        let to_maker = quote_amount(quantity, maker_price, scale, Rounding::Down)?;
+                        let to_fee_pool = surplus(quantity, taker_price, maker_price, scale)?;
+                        let maker_fee = self.fee_schedule.maker_fee(to_maker, maker_volume);
+                        let taker_fee = self.fee_schedule.taker_fee(quantity, taker_volume);
+                        // The base token comes from the maker's reservation
+                        self.debit_reservation(&maker, &pair.0, released)?;
+                        transfers_to_process.push((
+                            user.clone(),
+                            maker.clone(),
+                            pair.1.clone(),
+                            to_maker - maker_fee,
+                        ));
+                        transfers_to_process.push((
+                            user.clone(),
+                            FEE_POOL.to_string(),
+                            pair.1.clone(),
+                            maker_fee,
+                        ));
+                        transfers_to_process.push((
+                            RESERVES.to_string(),
+                            user.clone(),
+                            pair.0.clone(),
+                            quantity - taker_fee,
+                        ));
+                        transfers_to_process.push((
+                            RESERVES.to_string(),
+                            FEE_POOL.to_string(),
+                            pair.0.clone(),
+                            taker_fee,
+                        ));
+                        transfers_to_process.push((
+                            user.clone(),
+                            FEE_POOL.to_string(),
+                            pair.1.clone(),
+                            to_fee_pool,
+                        ));
+                        transfers_to_process.push((
+                            user.clone(),
+                            DUST.to_string(),
+                            pair.1.clone(),
+                            dust(paid, &[to_maker, to_fee_pool])?,
+                        ));
+                        ((pair.1.clone(), maker_fee), (pair.0.clone(), taker_fee))
+                    }
+                    OrderType::Sell => {
+                        // Send token to the order owner, and pay the user from the quote token the
+                        // order owner locked at its price. What the user does not get goes back to
+                        // the order owner, or to the fee pool.
+                        let to_user = quote_amount(quantity, taker_price, scale, Rounding::Down)?;
+                        let to_maker = surplus(quantity, price, maker_price, scale)?;
+                        let to_fee_pool = surplus(quantity, maker_price, taker_price, scale)?;
+                        let maker_fee = self.fee_schedule.maker_fee(quantity, maker_volume);
+                        let taker_fee = self.fee_schedule.taker_fee(to_user, taker_volume);
+                        // The quote token comes from the maker's reservation
+                        self.debit_reservation(&maker, &pair.1, released)?;
+                        transfers_to_process.push((
+                            user.clone(),
+                            maker.clone(),
+                            pair.0.clone(),
+                            quantity - maker_fee,
+                        ));
+                        transfers_to_process.push((
+                            user.clone(),
+                            FEE_POOL.to_string(),
+                            pair.0.clone(),
+                            maker_fee,
+                        ));
+                        transfers_to_process.push((
+                            RESERVES.to_string(),
+                            user.clone(),
+                            pair.1.clone(),
+                            to_user - taker_fee,
+                        ));
+                        transfers_to_process.push((
+                            RESERVES.to_string(),
+                            FEE_POOL.to_string(),
+                            pair.1.clone(),
+                            taker_fee,
+                        ));
+                        transfers_to_process.push((
+                            RESERVES.to_string(),
+                            maker.clone(),
+                            pair.1.clone(),
+                            to_maker,
+                        ));
+                        transfers_to_process.push((
+                            RESERVES.to_string(),
+                            FEE_POOL.to_string(),
+                            pair.1.clone(),
+                            to_fee_pool,
+                        ));
+                        transfers_to_process.push((
+                            RESERVES.to_string(),
+                            DUST.to_string(),
+                            pair.1.clone(),
+                            dust(released, &[to_user, to_maker, to_fee_pool])?,
+                        ));
+                        ((pair.0.clone(), maker_fee), (pair.1.clone(), taker_fee))
+                    }
+                };
+                for (payer, fee_order_id, (token, amount)) in [
+                    (&maker, &order_id, maker_fee),
+                    (&user, &order.order_id, taker_fee.clone()),
+                ] {
+                    if amount > 0 {
+                        let history = self.trade_history.entry(pair.clone()).or_default();
+                        history.record_fee(&order.timestamp, &token, amount);
+                        events.push(OrderbookEvent::FeeCharged {
+                            pair: pair.clone(),
+                            order_id: fee_order_id.clone(),
+                            user: payer.clone(),
+                            token,
+                            amount,
+                        });
+                    }
+                }
+                // The referrer of the taker gets its share of the taker fee
+                let (token, fee) = taker_fee;
+                let referrer = self.referrers.get(&user).cloned();
+                let reward = self.fee_schedule.referral_reward(fee);
+                if let Some(referrer) = referrer.filter(|_| reward > 0) {
+                    transfers_to_process.push((
+                        FEE_POOL.to_string(),
+                        referrer.clone(),
+                        token.clone(),
+                        reward,
+                    ));
+                    events.push(OrderbookEvent::ReferralRewarded {
+                        pair: pair.clone(),
+                        order_id: order.order_id.clone(),
+                        referrer,
+                        referee: user.clone(),
+                        token,
+                        amount: reward,
+                    });
+                }
+                order.quantity -= quantity;
+                order.record_fill(quantity);
+            }
+        }
+
+        // Matching can stop short of what the check above counted, e.g. at a level the matching
+        // mode allocates nothing to: fill-or-kill orders are never left partially filled
+        if time_in_force == TimeInForce::FillOrKill && order.quantity > 0 {
+            return Err(OrderbookError::FillOrKillUnfilled {
+                order_id: order.order_id,
+                available: order.filled_quantity,
+                quantity: order.filled_quantity + order.quantity,
+            });
+        }
+
+        // If there is still some quantity left, we need to insert the order in the orderbook.
+        // Immediate-or-cancel orders never rest: what is left is cancelled.
+        let rests = time_in_force == TimeInForce::GoodTilCancelled;
+        if order.price.is_some() && rests && order.quantity > 0 {
+            order.hide_quantity();
+            order.reserved_amount = self.required_reservation(&order)?;
+            self.check_open_order_limit(&order)?;
+            self.check_notional_limit(&order)?;
+            self.insert_order(order.clone())?;
+            self.incentives
+                .add_resting(&user, order.quantity, &tx_ctx.timestamp);
+            // Remove liquitidy from the user balance
+            let quantity = order.reserved_amount;
+
+            self.credit_reservation(&user, &required_token, quantity);
+            transfers_to_process.push((
+                user.clone(),
+                RESERVES.to_string(),
+                required_token,
+                quantity,
+            ));
+            events.push(OrderbookEvent::OrderCreated { order });
+        } else {
+            // Orders that do not rest are closed right away
+            let cancelled = events.iter().any(|event| {
+                matches!(event, OrderbookEvent::OrderCancelled { order_id, .. }
+                    if order_id == &order.order_id)
+            });
+            let status = if order.quantity == 0 && !cancelled {
+                OrderStatus::Filled
+            } else {
+                OrderStatus::Cancelled
+            };
+            self.archive_order(order, status);
+        }
+
+        // Updating balances
+        // If not limit order: assert that total balance in user_to_fund is equal to the order quantity
+        let mut ids = BTreeMap::<String, BTreeSet<String>>::new();
+        for (from, to, token, amout) in transfers_to_process {
+            // Price surpluses are only transferred by some of the taker price policies
+            if amout == 0 {
+                continue;
+            }
+            self.transfer_tokens(&from, &to, &token, amout)?;
+            let t = ids.entry(token.clone()).or_default();
+            t.insert(from.clone());
+            t.insert(to.clone());
+        }
+        for (token, users) in ids {
+            for user in users {
+                let user_balance = self.get_balance(&user, &token);
+                events.push(OrderbookEvent::BalanceUpdated {
+                    user: user.clone(),
+                    token: token.clone(),
+                    amount: user_balance,
+                });
+            }
+        }
+
+        let filled = maker_fills
+            .iter()
+            .fold(0, |total: u128, (_, quantity)| total + quantity);
+        self.volumes.record(&user, filled, &tx_ctx.timestamp);
+        for (maker, quantity) in maker_fills {
+            self.incentives
+                .record_fill(&maker, quantity, &tx_ctx.timestamp);
+            self.volumes.record(&maker, quantity, &tx_ctx.timestamp);
+        }
+
+        // Orders that traded cancel their one-cancels-other orders
+        for order_id in traded_order_ids(&events) {
+            events.extend(self.cancel_linked_order(&order_id, tx_ctx)?);
+        }
+
+        // Early returns above only happen when the opposite side of the book is empty,
+        // so they cannot cross it. The book of a batch-auction pair crosses until its auction.
+        if !auction {
+            self.ensure_book_not_crossed(&pair)?;
+        }
+
+        if let Some(last_price) = last_price {
+            events.extend(self.trigger_stop_orders(&pair, last_price, tx_ctx)?);
+        }
+
+        Ok(events)
+    }
+
+    /// Cancels the orders of `pair`, resting or waiting for their trigger price, that expired
+    /// at the transaction's timestamp, and refunds what they reserved. Also drops the trading
+    /// volumes that no longer count for the fee tiers.
+    pub fn prune_expired(
+        &mut self,
+        pair: &TokenPair,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        self.volumes.prune(&tx_ctx.timestamp);
+        self.cancel_orders_of_pair(pair, tx_ctx, |order| order.is_expired(&tx_ctx.timestamp))
+    }
+
+    /// Cancels the resting and stop orders of `pair` that are `cancelled`, on behalf of their
+    /// owners
+    fn cancel_orders_of_pair(
+        &mut self,
+        pair: &TokenPair,
+        tx_ctx: &sdk::TxContext,
+        cancelled: impl Fn(&Order) -> bool,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let orders: Vec<(String, String)> = (self.orders.values())
+            .chain(self.stop_orders.get(pair).into_iter().flatten())
+            .filter(|order| &order.pair == pair && cancelled(order))
+            .map(|order| (order.order_id.clone(), order.owner.clone()))
+            .collect();
+
+        let mut events = vec![];
+        for (order_id, owner) in orders {
+            // Linked orders are cancelled together
+            if self.is_open(&order_id) {
+                events.extend(self.cancel_order(order_id, owner, tx_ctx)?);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Whether the interval of the batch auctions of `pair` elapsed since the last one
+    fn auction_due(&self, pair: &TokenPair, now: &TimestampMs) -> bool {
+        let interval = self.market_config(pair).auction_interval_ms;
+        self.auctions
+            .get(pair)
+            .map_or(true, |last| now.0 >= last.0.saturating_add(interval))
+    }
+
+    /// Matches the crossed orders of batch-auction `pair` at a single clearing price, see
+    /// [`market::clearing_price`]. Orders are filled in price, then time priority.
+    /// Auctions are timed by block timestamps, so they only settle on sequenced executions.
+    pub fn run_auction(
+        &mut self,
+        pair: &TokenPair,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if self.market_config(pair).auction_interval_ms == 0 {
+            return Err(OrderbookError::NotAuctionPair { pair: pair.clone() });
+        }
+        if !self.auction_due(pair, &tx_ctx.timestamp) {
+            return Err(OrderbookError::AuctionNotDue { pair: pair.clone() });
+        }
+        self.auctions.insert(pair.clone(), tx_ctx.timestamp.clone());
+        let mut events = self.prune_expired(pair, tx_ctx)?;
+
+        let side = |levels: &BTreeMap<TokenPair, PriceLevels>, side: OrderType| -> Vec<Order> {
+            (levels.get(pair).into_iter())
+                .flat_map(|levels| levels.order_ids(&side))
+                .filter_map(|order_id| self.orders.get(order_id).cloned())
+                .collect()
+        };
+        let bids = side(&self.buy_orders, OrderType::Buy);
+        let asks = side(&self.sell_orders, OrderType::Sell);
+        let limits = |orders: &[Order]| -> Vec<(u128, u128)> {
+            (orders.iter())
+                .map(|order| (order.price.unwrap_or_default(), order.quantity))
+                .collect()
+        };
+        let Some((price, quantity)) = market::clearing_price(&limits(&bids), &limits(&asks)) else {
+            return Ok(events);
+        };
+        events.push(OrderbookEvent::AuctionCleared {
+            pair: pair.clone(),
+            price,
+            quantity,
+        });
+
+        // Both sides are filled up to the quantity cleared, then matched with each other
+        let fills = |orders: Vec<Order>| -> Vec<(Order, u128)> {
+            let mut left = quantity;
+            orders
+                .into_iter()
+                .map(|order| {
+                    let fill = order.quantity.min(left);
+                    left -= fill;
+                    (order, fill)
+                })
+                .filter(|(_, fill)| *fill > 0)
+                .collect()
+        };
+        let mut bids = fills(bids).into_iter();
+        let mut asks = fills(asks).into_iter();
+        let (mut bid, mut ask) = (bids.next(), asks.next());
+        let mut balances = BTreeMap::<String, BTreeSet<String>>::new();
+        while let (Some((buy, buy_left)), Some((sell, sell_left))) = (&mut bid, &mut ask) {
+            let fill = (*buy_left).min(*sell_left);
+            events.extend(self.fill_auction_orders(
+                buy,
+                sell,
+                fill,
+                price,
+                &mut balances,
+                tx_ctx,
+            )?);
+            *buy_left -= fill;
+            *sell_left -= fill;
+            buy.quantity -= fill;
+            sell.quantity -= fill;
+            // The buyer got back part of its reservation, which later fills must not count again
+            buy.reserved_amount = self.required_reservation(buy)?;
+            if *buy_left == 0 {
+                bid = bids.next();
+            }
+            if *sell_left == 0 {
+                ask = asks.next();
+            }
+        }
+
+        for (token, users) in balances {
+            for user in users {
+                events.push(OrderbookEvent::BalanceUpdated {
+                    amount: self.get_balance(&user, &token),
+                    user,
+                    token: token.clone(),
+                });
+            }
+        }
+        for order_id in traded_order_ids(&events) {
+            events.extend(self.cancel_linked_order(&order_id, tx_ctx)?);
+        }
+        self.ensure_book_not_crossed(pair)?;
+        Ok(events)
+    }
+
+    /// Trades `quantity` between resting orders `buy` and `sell` at the auction `price`. Both
+    /// sides pay the maker fee. The users whose balances changed are added to `balances`.
+    fn fill_auction_orders(
+        &mut self,
+        buy: &Order,
+        sell: &Order,
+        quantity: u128,
+        price: u128,
+        balances: &mut BTreeMap<String, BTreeSet<String>>,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let pair = &buy.pair;
+        let scale = self.base_scale(pair);
+        let mut filled_buy = buy.clone();
+        filled_buy.quantity -= quantity;
+        filled_buy.reserved_amount = self.required_reservation(&filled_buy)?;
+        // The buyer reserved its limit price, and gets back what it does not pay
+        let released = dust(buy.reserved_amount, &[filled_buy.reserved_amount])?;
+        let paid = quote_amount(quantity, price, scale, Rounding::Down)?;
+        let now = &tx_ctx.timestamp;
+        let seller_fee = self
+            .fee_schedule
+            .maker_fee(paid, self.volumes.volume(&sell.owner, now));
+        let buyer_fee = self
+            .fee_schedule
+            .maker_fee(quantity, self.volumes.volume(&buy.owner, now));
+        self.debit_reservation(&buy.owner, &pair.1, released)?;
+        self.debit_reservation(&sell.owner, &pair.0, quantity)?;
+        let transfers = [
+            (sell.owner.as_str(), &pair.1, paid - seller_fee),
+            (FEE_POOL, &pair.1, seller_fee),
+            (buy.owner.as_str(), &pair.0, quantity - buyer_fee),
+            (FEE_POOL, &pair.0, buyer_fee),
+            (buy.owner.as_str(), &pair.1, dust(released, &[paid])?),
+        ];
+        for (user, token, amount) in transfers {
+            if amount == 0 {
+                continue;
+            }
+            self.transfer_tokens(RESERVES, user, token, amount)?;
+            balances
+                .entry(token.clone())
+                .or_default()
+                .insert(user.to_string());
+        }
+
+        // The oldest order is the maker
+        let (maker, taker) = if buy.timestamp < sell.timestamp {
+            (buy, sell)
+        } else {
+            (sell, buy)
+        };
+        let trade = Trade {
+            id: 0,
+            timestamp: tx_ctx.timestamp.clone(),
+            price,
+            quantity,
+            taker_side: taker.order_type.clone(),
+            maker: maker.owner.clone(),
+            taker: taker.owner.clone(),
+        };
+        let retention = self.market_config(pair).history;
+        self.trade_history
+            .entry(pair.clone())
+            .or_default()
+            .record(&retention, scale, trade);
+        let mut events = vec![OrderbookEvent::TradeExecuted {
+            pair: pair.clone(),
+            price,
+            quantity,
+            maker_order_id: maker.order_id.clone(),
+            taker_order_id: taker.order_id.clone(),
+            maker: maker.owner.clone(),
+            taker: taker.owner.clone(),
+            timestamp: tx_ctx.timestamp.clone(),
+        }];
+        for (order, user, token, fee) in [
+            (sell, &sell.owner, &pair.1, seller_fee),
+            (buy, &buy.owner, &pair.0, buyer_fee),
+        ] {
+            if fee > 0 {
+                let history = self.trade_history.entry(pair.clone()).or_default();
+                history.record_fee(&tx_ctx.timestamp, token, fee);
+                events.push(OrderbookEvent::FeeCharged {
+                    pair: pair.clone(),
+                    order_id: order.order_id.clone(),
+                    user: user.clone(),
+                    token: token.clone(),
+                    amount: fee,
+                });
+            }
+        }
+
+        let reservations = [filled_buy.reserved_amount, sell.quantity - quantity];
+        for (order, reserved_amount) in [buy, sell].into_iter().zip(reservations) {
+            self.incentives
+                .record_fill(&order.owner, quantity, &tx_ctx.timestamp);
+            self.volumes
+                .record(&order.owner, quantity, &tx_ctx.timestamp);
+            let remaining_quantity = order.quantity - quantity;
+            if let Some(resting) = self.orders.get_mut(&order.order_id) {
+                resting.quantity = remaining_quantity;
+                resting.reserved_amount = reserved_amount;
+                resting.record_fill(quantity);
+            }
+            if remaining_quantity > 0 {
+                events.push(OrderbookEvent::OrderUpdate {
+                    order_id: order.order_id.clone(),
+                    remaining_quantity,
+                    pair: pair.clone(),
+                    executed_price: Some(price),
+                });
+                continue;
+            }
+            if let Some(filled) = self.orders.remove(&order.order_id) {
+                self.archive_order(filled, OrderStatus::Filled);
+            }
+            self.unindex_order(&order.owner, &order.order_id);
+            let levels = match order.order_type {
+                OrderType::Buy => self.buy_orders.get_mut(pair),
+                OrderType::Sell => self.sell_orders.get_mut(pair),
+            };
+            if let (Some(levels), Some(order_price)) = (levels, order.price) {
+                levels.remove(order_price, &order.order_id);
+            }
+            events.push(OrderbookEvent::OrderExecuted {
+                order_id: order.order_id.clone(),
+                pair: pair.clone(),
+                executed_price: Some(price),
+            });
+        }
+        Ok(events)
+    }
+
+    /// Applies `policy` instead of matching `order` against `resting`, of the same owner
+    fn prevent_self_trade(
+        &mut self,
+        policy: SelfTradePrevention,
+        order: &mut Order,
+        resting: Order,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let cancel_newest = OrderbookEvent::OrderCancelled {
+            order_id: order.order_id.clone(),
+            pair: order.pair.clone(),
+        };
+        match policy {
+            SelfTradePrevention::CancelNewest => {
+                order.quantity = 0;
+                Ok(vec![cancel_newest])
+            }
+            SelfTradePrevention::CancelOldest => {
+                self.cancel_order(resting.order_id, resting.owner, tx_ctx)
+            }
+            SelfTradePrevention::DecrementAndCancel => {
+                let decrement = order.quantity.min(resting.quantity);
+                order.quantity -= decrement;
+                let mut events = if decrement == resting.quantity {
+                    self.cancel_order(resting.order_id, resting.owner, tx_ctx)?
+                } else {
+                    self.reduce_order(&resting, decrement, tx_ctx)?
+                };
+                if order.quantity == 0 {
+                    events.push(cancel_newest);
+                }
+                Ok(events)
+            }
+        }
+    }
+
+    /// Takes `decrement` off the quantity of a resting order, and refunds what it no longer
+    /// needs reserved
+    fn reduce_order(
+        &mut self,
+        order: &Order,
+        decrement: u128,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let mut reduced = order.clone();
+        reduced.quantity -= decrement;
+        // Iceberg orders keep their shown slice as long as they can
+        reduced.hidden_quantity = reduced.hidden_quantity.saturating_sub(decrement);
+        reduced.reserved_amount = self.required_reservation(&reduced)?;
+        let token = order.reserved_token().clone();
+        let refund = dust(order.reserved_amount, &[reduced.reserved_amount])?;
+        self.release(&order.owner, &order.owner, &token, refund)?;
+        self.incentives
+            .remove_resting(&order.owner, decrement, &tx_ctx.timestamp);
+        self.orders.insert(order.order_id.clone(), reduced.clone());
+
+        Ok(vec![
+            OrderbookEvent::OrderUpdate {
+                order_id: reduced.order_id,
+                remaining_quantity: reduced.quantity,
+                pair: reduced.pair,
+                executed_price: None,
+            },
+            OrderbookEvent::BalanceUpdated {
+                amount: self.get_balance(&order.owner, &token),
+                user: order.owner.clone(),
+                token,
+            },
+        ])
+    }
+
+    /// Executes the stop orders of `pair` crossed by a trade at `last_price`, in the order they
+    /// were placed. Trades of the activated orders may in turn trigger other stop orders.
+    fn trigger_stop_orders(
+        &mut self,
+        pair: &TokenPair,
+        last_price: u128,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let mut events = vec![];
+        while let Some(mut order) = self.take_triggered_stop_order(pair, last_price) {
+            order.timestamp = tx_ctx.timestamp.clone();
+            events.push(OrderbookEvent::StopOrderTriggered {
+                order_id: order.order_id.clone(),
+                pair: pair.clone(),
+                last_price,
+            });
+
+            // An order which can no longer be executed (e.g. its owner's balance went down) is
+            // cancelled without failing the transaction that triggered it. The checks catch the
+            // failures before anything is matched.
+            let executed = self.check_triggered_order(&order, tx_ctx).and_then(|()| {
+                self.execute_order(order.clone(), TimeInForce::GoodTilCancelled, tx_ctx)
+            });
+            match executed {
+                Ok(order_events) => events.extend(order_events),
+                Err(_) => {
+                    let order_id = order.order_id.clone();
+                    self.archive_order(order, OrderStatus::Cancelled);
+                    events.push(OrderbookEvent::OrderCancelled {
+                        order_id: order_id.clone(),
+                        pair: pair.clone(),
+                    });
+                    events.extend(self.cancel_linked_order(&order_id, tx_ctx)?);
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Checks what may have changed since a stop order was placed: the market config, the
+    /// liquidity, and its owner's balance and resting orders. What is left of a limit order is
+    /// counted against the limits as if nothing filled.
+    fn check_triggered_order(
+        &mut self,
+        order: &Order,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<(), OrderbookError> {
+        let market = self.market_config(&order.pair);
+        market.check_order(order, self.base_scale(&order.pair))?;
+        market.check_price_band(order, self.band_reference(&order.pair))?;
+
+        let (token, required) = match order.order_type {
+            OrderType::Buy => (&order.pair.1, self.max_buy_cost(order)?),
+            OrderType::Sell => (&order.pair.0, order.quantity),
+        };
+        self.ensure_deposit_settled(&order.owner, token, tx_ctx)?;
+        let available = self.get_balance(&order.owner, token);
+        if available < required {
+            return Err(OrderbookError::InsufficientBalance {
+                user: order.owner.clone(),
+                token: token.clone(),
+                available,
+                required,
+            });
+        }
+
+        if order.price.is_none() {
+            if self.crossing_orders(order).next().is_none() {
+                let side = match order.order_type {
+                    OrderType::Buy => OrderType::Sell,
+                    OrderType::Sell => OrderType::Buy,
+                };
+                return Err(OrderbookError::NoLiquidity {
+                    order_id: order.order_id.clone(),
+                    side,
+                });
+            }
+            return Ok(());
+        }
+        self.check_open_order_limit(order)?;
+        self.check_notional_limit(order)
+    }
+
+    /// Most a buy order can take from its owner's balance: what it pays to the orders it
+    /// crosses, each fill being rounded up, and the reservation of what is left of a limit order
+    fn max_buy_cost(&self, order: &Order) -> Result<u128, OrderbookError> {
+        let market = self.market_config(&order.pair);
+        let scale = self.base_scale(&order.pair);
+        let mut left = order.quantity;
+        let mut cost: u128 = 0;
+        for resting in self.crossing_orders(order) {
+            if left == 0 {
+                break;
+            }
+            let quantity = left.min(resting.quantity);
+            left -= quantity;
+            let maker_price = resting.price.unwrap_or_default();
+            let (_, taker_price) = market.taker_price.fill_prices(maker_price, order.price);
+            // Iceberg orders are filled one shown slice at a time
+            let fills = match resting.display_quantity {
+                Some(display_quantity) => quantity.div_ceil(display_quantity) + 1,
+                None => 1,
+            };
+            let paid = quote_amount(quantity, taker_price, scale, Rounding::Up)?;
+            cost = cost.saturating_add(paid).saturating_add(fills);
+        }
+        if let Some(price) = order.price {
+            let reserved = quote_amount(left, price, scale, Rounding::Up)?;
+            cost = cost.saturating_add(reserved);
+        }
+        Ok(cost)
+    }
+
+    /// Removes the first stop order of `pair` crossed by a trade at `last_price`
+    fn take_triggered_stop_order(&mut self, pair: &TokenPair, last_price: u128) -> Option<Order> {
+        let stop_orders = self.stop_orders.get_mut(pair)?;
+        let index = stop_orders.iter().position(|order| {
+            match (&order.order_type, order.trigger_price) {
+                (OrderType::Buy, Some(trigger_price)) => last_price >= trigger_price,
+                (OrderType::Sell, Some(trigger_price)) => last_price <= trigger_price,
+                (_, None) => true,
+            }
+        })?;
+        let order = stop_orders.remove(index);
+        self.unindex_order(&order.owner, &order.order_id);
+        Some(order)
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Default, Debug, Clone)]
+pub struct Orderbook {
+    // Validator public keys of the lanes this orderbook is running on. Several lanes are accepted
+    // while the operator rotates validators.
+    lane_ids: BTreeSet<LaneId>,
+    // Map of user address to token balances
+    balances: BTreeMap<String, BTreeMap<String, u128>>,
+    // Map of user address to token latest deposit block height
+    latest_deposit: BTreeMap<String, BTreeMap<String, BlockHeight>>,
+    // All orders indexed by order_id
+    orders: BTreeMap<String, Order>,
+    // Number of orders created with CreateOrder, numbering the ids of the next ones
+    next_order_seq: u64,
+    // Buy orders sorted by price (highest first) for each token pair
+    #[serde(with = "map_as_entries")]
+    buy_orders: BTreeMap<TokenPair, PriceLevels>,
+    // Sell orders sorted by price (lowest first) for each token pair
+    #[serde(with = "map_as_entries")]
+    sell_orders: BTreeMap<TokenPair, PriceLevels>,
+    // Stop orders waiting for their trigger price, in placement order for each token pair
+    #[serde(with = "map_as_entries")]
+    stop_orders: BTreeMap<TokenPair, Vec<Order>>,
+    // Recent trades and candles of each token pair, bounded by the retention of its market
+    // config. Not committed.
+    #[serde(with = "map_as_entries")]
+    trade_history: BTreeMap<TokenPair, TradeHistory>,
+    // Accepted tokens
+    accepted_tokens: BTreeSet<ContractName>,
+    // Tokens removed by the admin: they can no longer be deposited nor traded, only withdrawn
+    withdraw_only_tokens: BTreeSet<ContractName>,
+    // Number of changes applied to each token pair's book
+    #[serde(with = "map_as_entries")]
+    book_seqs: BTreeMap<TokenPair, u64>,
+    // Number of events emitted by the orderbook, which is the sequence number of the last one
+    event_seq: u64,
+    // Identity allowed to send admin actions
+    admin: String,
+    // Trading parameters of the pairs configured by the admin
+    #[serde(with = "map_as_entries")]
+    markets: BTreeMap<TokenPair, MarketConfig>,
+    // Number of decimals of the tokens configured by the admin, 0 by default
+    token_decimals: BTreeMap<String, u8>,
+    // Balance of each token below which user balances can be swept to the dust account
+    dust_thresholds: BTreeMap<String, u128>,
+    // Fees charged on every fill, set by the admin
+    fee_schedule: FeeSchedule,
+    // Maker activity accounting for liquidity incentives
+    incentives: MakerIncentives,
+    // Recent trading volume of each user, which its fee tier depends on
+    volumes: TradingVolumes,
+    // Liquidity mining points of the makers of each pair
+    liquidity: LiquidityPoints,
+    // Number of actions of each user per block, only the current block is kept
+    actions_per_block: BTreeMap<BlockHeight, BTreeMap<String, u32>>,
+    // Compressed secp256k1 public key signing the invite codes. If set, users must register
+    // with an invite code before their first deposit.
+    invite_key: Option<Vec<u8>>,
+    // Users registered with an invite code
+    registered: BTreeSet<String>,
+    // Referrer of each referred user
+    referrers: BTreeMap<String, String>,
+    // Proof of their identity transactions must carry, set by the deployment
+    identity_verification: IdentityVerification,
+    // Pairs traded on this instance when the orderbook is sharded across several contracts,
+    // every pair if empty
+    pairs: BTreeSet<TokenPair>,
+    // Pairs listed by the admin. Any pair can be traded until the first one is listed.
+    listed_pairs: Option<BTreeSet<TokenPair>>,
+    // Set by the admin to reject new orders on every pair, during incidents
+    paused: bool,
+    // Pairs on which new orders are rejected, set by the admin
+    halted_pairs: BTreeSet<TokenPair>,
+    // Last nonce used by each user, kept when its account is closed
+    nonces: BTreeMap<String, u64>,
+    // Open one-cancels-other orders, each indexed by the id of the other
+    linked_orders: BTreeMap<String, String>,
+    // Time of the last auction of each batch-auction pair. It depends on block timestamps, so
+    // auctions only settle on sequenced executions and are left out of partial commitments
+    #[serde(with = "map_as_entries")]
+    auctions: BTreeMap<TokenPair, TimestampMs>,
+    // Ids of the open orders of each user, including its stop orders
+    orders_by_owner: BTreeMap<String, BTreeSet<String>>,
+    // Amount of each token deposited and not withdrawn yet, which the balances of all accounts
+    // add up to
+    deposits: BTreeMap<String, u128>,
+    // Tokens the reserves account holds for the resting orders of each user
+    reserved: BTreeMap<String, BTreeMap<String, u128>>,
+    // Number of blocks a withdrawal must wait before it can be claimed, set by the deployment.
+    // Withdrawals are immediate if 0.
+    withdrawal_delay: u64,
+    // Withdrawals requested and not claimed yet, by id
+    pending_withdrawals: BTreeMap<u64, PendingWithdrawal>,
+    next_withdrawal_id: u64,
+    // Last orders of each user that were filled, cancelled or expired, oldest first
+    closed_orders: BTreeMap<String, VecDeque<Order>>,
 }
 
-impl OrderbookAction {
-    pub fn as_blob(&self, contract_name: sdk::ContractName) -> sdk::Blob {
-        sdk::Blob {
-            contract_name,
-            data: sdk::BlobData(borsh::to_vec(self).expect("Failed to encode OrderbookAction")),
-        }
-    }
-}
+impl Orderbook {
+    fn transfer_tokens(
+        &mut self,
+        from: &str,
+        to: &str,
+        token: &str,
+        amount: u128,
+    ) -> Result<(), OrderbookError> {
+        let system = |account: &str| SYSTEM_ACCOUNTS.contains(&account);
+        let settlement = from == RESERVES && (to == FEE_POOL || to == DUST);
+        if system(from) && system(to) && !settlement {
+            return Err(OrderbookError::SystemTransfer {
+                from: from.to_string(),
+                to: to.to_string(),
+                token: token.to_string(),
+            });
+        }
+
+        // Deduct from sender
+        let insufficient = |available| OrderbookError::InsufficientBalance {
+            user: from.to_string(),
+            token: token.to_string(),
+            available,
+            required: amount,
+        };
+        let from_balance = (self.balances.get_mut(from))
+            .and_then(|balances| balances.get_mut(token))
+            .ok_or_else(|| insufficient(0))?;
+        if *from_balance < amount {
+            return Err(insufficient(*from_balance));
+        }
+        *from_balance -= amount;
+
+        // Add to receiver
+        let to_balances = self.balances.entry(to.to_string()).or_default();
+        let to_balance = to_balances.entry(token.to_string()).or_default();
+        *to_balance =
+            (to_balance.checked_add(amount)).ok_or_else(|| OrderbookError::BalanceOverflow {
+                user: to.to_string(),
+                token: token.to_string(),
+            })?;
+
+        Ok(())
+    }
+
+    /// Moves `amount` of `token` from the balance of `user` to the [`RESERVES`], reserved for the
+    /// orders of `user`
+    fn reserve(&mut self, user: &str, token: &str, amount: u128) -> Result<(), OrderbookError> {
+        self.transfer_tokens(user, RESERVES, token, amount)?;
+        self.credit_reservation(user, token, amount);
+        Ok(())
+    }
+
+    /// Pays `amount` of `token` reserved for the orders of `owner` to `to`
+    fn release(
+        &mut self,
+        owner: &str,
+        to: &str,
+        token: &str,
+        amount: u128,
+    ) -> Result<(), OrderbookError> {
+        self.debit_reservation(owner, token, amount)?;
+        self.transfer_tokens(RESERVES, to, token, amount)
+    }
+
+    /// Records that the [`RESERVES`] hold `amount` more of `token` for `owner`. It cannot
+    /// overflow as long as the balance of the [`RESERVES`] does not.
+    fn credit_reservation(&mut self, owner: &str, token: &str, amount: u128) {
+        let reserved = (self.reserved.entry(owner.to_string()).or_default())
+            .entry(token.to_string())
+            .or_default();
+        *reserved = reserved.saturating_add(amount);
+    }
+
+    /// Takes `amount` off the `token` reserved for the orders of `owner`
+    fn debit_reservation(
+        &mut self,
+        owner: &str,
+        token: &str,
+        amount: u128,
+    ) -> Result<(), OrderbookError> {
+        let insufficient = |reserved| OrderbookError::InsufficientReserve {
+            user: owner.to_string(),
+            token: token.to_string(),
+            reserved,
+            required: amount,
+        };
+        let Some(reservations) = self.reserved.get_mut(owner) else {
+            return Err(insufficient(0));
+        };
+        let reserved = reservations.entry(token.to_string()).or_default();
+        if *reserved < amount {
+            return Err(insufficient(*reserved));
+        }
+        *reserved -= amount;
+        if *reserved == 0 {
+            reservations.remove(token);
+            if reservations.is_empty() {
+                self.reserved.remove(owner);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_balance_mut(&mut self, user: &str, token: &str) -> &mut u128 {
+        self.balances
+            .entry(user.to_string())
+            .or_default()
+            .entry(token.to_owned())
+            .or_default()
+    }
+
+    fn record_action(
+        &mut self,
+        user: &str,
+        block_height: BlockHeight,
+    ) -> Result<(), OrderbookError> {
+        // Counters of previous blocks are not needed anymore
+        self.actions_per_block = self.actions_per_block.split_off(&block_height);
+
+        let count = self
+            .actions_per_block
+            .entry(block_height)
+            .or_default()
+            .entry(user.to_string())
+            .or_default();
+        if *count >= MAX_ACTIONS_PER_BLOCK {
+            return Err(OrderbookError::ActionLimitReached {
+                user: user.to_string(),
+                limit: MAX_ACTIONS_PER_BLOCK,
+                block_height: block_height.0,
+            });
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    fn use_nonce(&mut self, user: &str, nonce: u64) -> Result<(), OrderbookError> {
+        let last_nonce = self.nonces.entry(user.to_string()).or_default();
+        if nonce <= *last_nonce {
+            return Err(OrderbookError::InvalidNonce {
+                user: user.to_string(),
+                nonce,
+                last_nonce: *last_nonce,
+            });
+        }
+        *last_nonce = nonce;
+        Ok(())
+    }
+
+    /// Last nonce used by `user`, see [`OrderbookBlob::nonce`]
+    pub fn last_nonce(&self, user: &str) -> u64 {
+        self.nonces.get(user).copied().unwrap_or_default()
+    }
+
+    pub fn get_balance(&mut self, user: &str, token: &str) -> u128 {
+        *self.get_balance_mut(user, token)
+    }
+
+    /// Balance of `token` of the system account `account`
+    fn system_balance(&self, account: &str, token: &str) -> u128 {
+        (self.balances.get(account))
+            .and_then(|balances| balances.get(token))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Amount of `token` reserved by the resting orders
+    pub fn reserves(&self, token: &str) -> u128 {
+        self.system_balance(RESERVES, token)
+    }
+
+    /// Amount of `token` collected as fees and not withdrawn by the admin
+    pub fn fee_pool(&self, token: &str) -> u128 {
+        self.system_balance(FEE_POOL, token)
+    }
+
+    /// Amount of `token` in the insurance fund
+    pub fn insurance_fund(&self, token: &str) -> u128 {
+        self.system_balance(INSURANCE, token)
+    }
+
+    pub fn get_latest_deposit_mut(&mut self, user: &str, token: &str) -> &mut BlockHeight {
+        self.latest_deposit
+            .entry(user.to_string())
+            .or_default()
+            .entry(token.to_owned())
+            .or_default()
+    }
+
+    pub fn get_latest_deposit(&mut self, user: &str, token: &str) -> BlockHeight {
+        *self.get_latest_deposit_mut(user, token)
+    }
+
+    fn insert_order(&mut self, order: Order) -> Result<(), OrderbookError> {
+        // Function only called for Limit orders
+        let price = order.price.unwrap();
+        if price == 0 {
+            return Err(OrderbookError::ZeroPrice);
+        }
+        // Resting orders are matched first, so an order crossing the book can only reach here
+        // if the matching stopped early. Orders of batch-auction pairs cross until the auction.
+        if self.market_config(&order.pair).auction_interval_ms == 0 {
+            let (best_bid, best_ask) = match order.order_type {
+                OrderType::Buy => (Some(price), self.best_ask(&order.pair)),
+                OrderType::Sell => (self.best_bid(&order.pair), Some(price)),
+            };
+            if let (Some(best_bid), Some(best_ask)) = (best_bid, best_ask) {
+                if best_bid >= best_ask {
+                    return Err(OrderbookError::CrossedBook {
+                        pair: order.pair,
+                        best_bid,
+                        best_ask,
+                    });
+                }
+            }
+        }
+        let levels = match order.order_type {
+            OrderType::Buy => self.buy_orders.entry(order.pair.clone()).or_default(),
+            OrderType::Sell => self.sell_orders.entry(order.pair.clone()).or_default(),
+        };
+        // Orders of the same timestamp keep their arrival order
+        let orders = &self.orders;
+        levels.insert_before(price, order.order_id.clone(), |order_id| {
+            orders
+                .get(order_id)
+                .is_some_and(|resting| resting.timestamp > order.timestamp)
+        });
+        self.orders.insert(order.order_id.clone(), order.clone());
+        self.index_order(&order);
+        Ok(())
+    }
+
+    /// Side of the book `order` is matched against, and the type of its orders
+    fn resting_side(&self, order: &Order) -> Option<(&PriceLevels, OrderType)> {
+        match order.order_type {
+            OrderType::Buy => Some((self.sell_orders.get(&order.pair)?, OrderType::Sell)),
+            OrderType::Sell => Some((self.buy_orders.get(&order.pair)?, OrderType::Buy)),
+        }
+    }
+
+    /// Price and resting orders, in time priority, of the best level of the book `order` can
+    /// be filled by, if it crosses the order's limit price
+    fn best_level(&self, order: &Order) -> Option<(u128, Vec<(String, u128)>)> {
+        let (levels, side) = self.resting_side(order)?;
+        let (price, order_ids) = levels.best_first(&side).next()?;
+        if !order.crosses(price) {
+            return None;
+        }
+        Some((
+            price,
+            order_ids
+                .iter()
+                .filter_map(|order_id| self.orders.get(order_id))
+                .map(|resting| (resting.order_id.clone(), resting.visible_quantity()))
+                .collect(),
+        ))
+    }
+
+    /// Resting orders that cross the limit price of `order`, in matching order
+    fn crossing_orders<'a>(&'a self, order: &'a Order) -> impl Iterator<Item = &'a Order> {
+        self.resting_side(order)
+            .into_iter()
+            .flat_map(|(levels, side)| {
+                levels
+                    .best_first(&side)
+                    .take_while(|(price, _)| order.crosses(*price))
+                    .flat_map(|(_, order_ids)| order_ids)
+            })
+            .filter_map(|order_id| self.orders.get(order_id))
+    }
+
+    /// Whether `order_id` is resting in a book or waiting for its trigger price
+    pub fn is_open(&self, order_id: &str) -> bool {
+        self.orders.contains_key(order_id) || self.stop_order(order_id).is_some()
+    }
+
+    /// Order linked to `order_id`, see [`OrderbookAction::OneCancelsOther`]
+    pub fn linked_order(&self, order_id: &str) -> Option<&String> {
+        self.linked_orders.get(order_id)
+    }
+
+    /// Ids of the open orders of `user`, including its stop orders
+    pub fn order_ids_of(&self, user: &str) -> Option<&BTreeSet<String>> {
+        self.orders_by_owner.get(user)
+    }
+
+    /// Adds `order` to the open orders of its owner
+    fn index_order(&mut self, order: &Order) {
+        (self.orders_by_owner.entry(order.owner.clone()).or_default())
+            .insert(order.order_id.clone());
+    }
+
+    /// Keeps `order`, which no longer rests on the book or never did, in the closed orders of
+    /// its owner
+    fn archive_order(&mut self, mut order: Order, status: OrderStatus) {
+        order.status = status;
+        // What the order reserved was refunded or traded
+        order.reserved_amount = 0;
+        let closed_orders = self.closed_orders.entry(order.owner.clone()).or_default();
+        closed_orders.push_back(order);
+        if closed_orders.len() > CLOSED_ORDERS_KEPT {
+            closed_orders.pop_front();
+        }
+    }
+
+    /// Last orders of `user` that were filled, cancelled or expired, oldest first. At most
+    /// [`CLOSED_ORDERS_KEPT`] are kept.
+    pub fn closed_orders_of(&self, user: &str) -> impl DoubleEndedIterator<Item = &Order> {
+        self.closed_orders.get(user).into_iter().flatten()
+    }
+
+    /// Order `order_id`, open or among the last closed orders of its owner
+    pub fn find_order(&self, order_id: &str) -> Option<&Order> {
+        (self.orders.get(order_id))
+            .or(self.stop_order(order_id))
+            .or_else(|| {
+                (self.closed_orders.values().flatten()).find(|order| order.order_id == order_id)
+            })
+    }
+
+    /// Removes order `order_id` from the open orders of `owner`
+    fn unindex_order(&mut self, owner: &str, order_id: &str) {
+        if let Some(order_ids) = self.orders_by_owner.get_mut(owner) {
+            order_ids.remove(order_id);
+            if order_ids.is_empty() {
+                self.orders_by_owner.remove(owner);
+            }
+        }
+    }
+
+    /// Stop order waiting for its trigger price
+    pub fn stop_order(&self, order_id: &str) -> Option<&Order> {
+        self.stop_orders
+            .values()
+            .flatten()
+            .find(|order| order.order_id == order_id)
+    }
+
+    /// Trading parameters of a pair, the defaults if the admin did not configure it
+    pub fn market_config(&self, pair: &TokenPair) -> MarketConfig {
+        self.markets.get(pair).cloned().unwrap_or_default()
+    }
+
+    /// Number of decimals of a token, 0 if the admin did not configure it
+    pub fn token_decimals(&self, token: &str) -> u8 {
+        self.token_decimals.get(token).copied().unwrap_or_default()
+    }
+
+    /// Units of the base token of a pair in one whole token, the one prices are quoted for
+    pub fn base_scale(&self, pair: &TokenPair) -> u128 {
+        // Decimals are checked to fit when configured
+        10u128.pow(self.token_decimals(&pair.0).into())
+    }
+
+    /// Tokens a resting order needs reserved for its quantity: quote tokens at its price for a
+    /// buy order, base tokens for a sell order
+    pub(crate) fn required_reservation(&self, order: &Order) -> Result<u128, OrderbookError> {
+        match order.order_type {
+            OrderType::Buy => {
+                let price = order.price.unwrap_or_default();
+                quote_amount(
+                    order.quantity,
+                    price,
+                    self.base_scale(&order.pair),
+                    Rounding::Up,
+                )
+            }
+            OrderType::Sell => Ok(order.quantity),
+        }
+    }
+
+    /// Resting orders of `user` on `pair`
+    fn resting_orders_of<'a>(
+        &'a self,
+        user: &str,
+        pair: &'a TokenPair,
+    ) -> impl Iterator<Item = &'a Order> + 'a {
+        (self.orders_by_owner.get(user).into_iter().flatten())
+            .filter_map(|order_id| self.orders.get(order_id))
+            .filter(move |order| &order.pair == pair)
+    }
+
+    /// Checks that the owner of `order` can open one more order on its pair, see
+    /// [`MarketConfig::max_open_orders`]
+    fn check_open_order_limit(&self, order: &Order) -> Result<(), OrderbookError> {
+        let limit = self.market_config(&order.pair).max_open_orders;
+        if limit == 0 {
+            return Ok(());
+        }
+        let stop_orders = (self.stop_orders.get(&order.pair).into_iter().flatten())
+            .filter(|stop_order| stop_order.owner == order.owner);
+        let open_orders =
+            self.resting_orders_of(&order.owner, &order.pair).count() + stop_orders.count();
+        if open_orders >= limit as usize {
+            return Err(OrderbookError::OpenOrderLimitReached {
+                user: order.owner.clone(),
+                pair: order.pair.clone(),
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that the resting orders of the owner of `order` on its pair stay within
+    /// [`MarketConfig::max_user_notional`] once `order` rests
+    fn check_notional_limit(&self, order: &Order) -> Result<(), OrderbookError> {
+        let limit = self.market_config(&order.pair).max_user_notional;
+        if limit == 0 {
+            return Ok(());
+        }
+        let scale = self.base_scale(&order.pair);
+        let notional = |order: &Order| {
+            quote_amount(
+                order.quantity,
+                order.price.unwrap_or_default(),
+                scale,
+                Rounding::Down,
+            )
+        };
+        let mut total = notional(order)?;
+        for resting in self.resting_orders_of(&order.owner, &order.pair) {
+            total = total.saturating_add(notional(resting)?);
+        }
+        if total > limit {
+            return Err(OrderbookError::NotionalLimitExceeded {
+                user: order.owner.clone(),
+                pair: order.pair.clone(),
+                notional: total,
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// Highest price of the buy orders of a pair
+    pub fn best_bid(&self, pair: &TokenPair) -> Option<u128> {
+        self.buy_orders.get(pair)?.best_price(&OrderType::Buy)
+    }
+
+    /// Lowest price of the sell orders of a pair
+    pub fn best_ask(&self, pair: &TokenPair) -> Option<u128> {
+        self.sell_orders.get(pair)?.best_price(&OrderType::Sell)
+    }
+
+    /// Best bid and best ask of a pair
+    pub fn best_prices(&self, pair: &TokenPair) -> (Option<u128>, Option<u128>) {
+        (self.best_bid(pair), self.best_ask(pair))
+    }
+
+    /// Price the price band of a pair is centered on: its mid price, or the best price of its
+    /// only quoted side
+    pub fn band_reference(&self, pair: &TokenPair) -> Option<u128> {
+        let (bid, ask) = self.best_prices(pair);
+        self.mid_price(pair).or(bid).or(ask)
+    }
+
+    /// Post-condition of every order execution: the best bid must be strictly below the best ask
+    fn ensure_book_not_crossed(&self, pair: &TokenPair) -> Result<(), OrderbookError> {
+        if let (Some(best_bid), Some(best_ask)) = self.best_prices(pair) {
+            if best_bid >= best_ask {
+                return Err(OrderbookError::CrossedBook {
+                    pair: pair.clone(),
+                    best_bid,
+                    best_ask,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash of a pair's book: sha256 of the borsh-encoded `(buy orders, sell orders)`, each
+    /// being the list of `(order_id, price, quantity)` in matching order.
+    pub fn book_hash(&self, pair: &TokenPair) -> String {
+        let book_side = |side: &BTreeMap<TokenPair, PriceLevels>,
+                         order_type: OrderType|
+         -> Vec<(&String, Option<u128>, u128)> {
+            side.get(pair)
+                .into_iter()
+                .flat_map(|levels| levels.order_ids(&order_type))
+                .filter_map(|order_id| self.orders.get(order_id))
+                .map(|order| (&order.order_id, order.price, order.quantity))
+                .collect()
+        };
+        let book = (
+            book_side(&self.buy_orders, OrderType::Buy),
+            book_side(&self.sell_orders, OrderType::Sell),
+        );
+        let bytes = borsh::to_vec(&book).expect("Failed to encode book");
+        hex::encode(Sha256::digest(bytes))
+    }
+
+    pub fn book_seq(&self, pair: &TokenPair) -> u64 {
+        self.book_seqs.get(pair).copied().unwrap_or_default()
+    }
+
+    /// Number of events emitted by the orderbook. The events of each transaction are numbered
+    /// up to it, so that clients can tell when they missed some.
+    pub fn event_seq(&self) -> u64 {
+        self.event_seq
+    }
+
+    /// Bumps the sequence number of the `pairs` whose book changed, and returns their new hash
+    fn book_hash_events(&mut self, pairs: BTreeSet<TokenPair>) -> Vec<OrderbookEvent> {
+        pairs
+            .into_iter()
+            .map(|pair| {
+                let seq = self.book_seqs.entry(pair.clone()).or_default();
+                *seq += 1;
+                let seq = *seq;
+                OrderbookEvent::BookHash {
+                    hash: self.book_hash(&pair),
+                    pair,
+                    seq,
+                }
+            })
+            .collect()
+    }
+
+    /// Whether blobs of `contract_name` can be part of orderbook transactions. Transfers of
+    /// removed tokens are still accepted, for their withdrawals.
+    pub fn is_blob_whitelisted(&self, contract_name: &ContractName) -> bool {
+        self.accepted_tokens.contains(contract_name)
+            || self.withdraw_only_tokens.contains(contract_name)
+            || contract_name.0 == "orderbook"
+            || contract_name.0 == "wallet"
+            || contract_name.0 == "secp256k1"
+    }
+
+    pub fn as_bytes(&self) -> Result<Vec<u8>, Error> {
+        borsh::to_vec(self)
+    }
+}
+
+
+impl Orderbook {
+    pub fn init(lane_id: LaneId, admin: String) -> Self {
+        let mut balances = BTreeMap::new();
+        balances.insert(RESERVES.to_string(), BTreeMap::new());
+
+        let accepted_tokens = BTreeSet::from([
+            "oranj".into(),
+            "hyllar".into(),
+        ]);
+
+        Orderbook {
+            lane_ids: BTreeSet::from([lane_id]),
+            balances,
+            latest_deposit: BTreeMap::new(),
+            orders: BTreeMap::new(),
+            next_order_seq: 0,
+            buy_orders: BTreeMap::new(),
+            sell_orders: BTreeMap::new(),
+            stop_orders: BTreeMap::new(),
+            trade_history: BTreeMap::new(),
+            accepted_tokens,
+            withdraw_only_tokens: BTreeSet::new(),
+            book_seqs: BTreeMap::new(),
+            event_seq: 0,
+            admin,
+            markets: BTreeMap::new(),
+            token_decimals: BTreeMap::new(),
+            dust_thresholds: BTreeMap::new(),
+            fee_schedule: FeeSchedule::default(),
+            incentives: MakerIncentives::default(),
+            volumes: TradingVolumes::default(),
+            liquidity: LiquidityPoints::default(),
+            actions_per_block: BTreeMap::new(),
+            invite_key: None,
+            registered: BTreeSet::new(),
+            referrers: BTreeMap::new(),
+            identity_verification: IdentityVerification::default(),
+            pairs: BTreeSet::new(),
+            listed_pairs: None,
+            paused: false,
+            halted_pairs: BTreeSet::new(),
+            nonces: BTreeMap::new(),
+            linked_orders: BTreeMap::new(),
+            auctions: BTreeMap::new(),
+            orders_by_owner: BTreeMap::new(),
+            deposits: BTreeMap::new(),
+            reserved: BTreeMap::new(),
+            withdrawal_delay: 0,
+            pending_withdrawals: BTreeMap::new(),
+            next_withdrawal_id: 0,
+            closed_orders: BTreeMap::new(),
+        }
+    }
+
+    /// Id of the `seq`-th order created with [`OrderbookAction::CreateOrder`], on `pair`.
+    ///
+    /// Ids are assigned by the contract so that they cannot collide. They only depend on the
+    /// state, for the witness of a transaction to hold the entry of the order it creates.
+    pub fn order_id(pair: &TokenPair, seq: u64) -> String {
+        format!("{}-{}-{}", pair.0, pair.1, seq)
+    }
+
+    /// Sequence number of the next order created, see [`Orderbook::order_id`]
+    pub fn next_order_seq(&self) -> u64 {
+        self.next_order_seq
+    }
+
+    /// Gates deposits behind invite codes signed by `invite_key`, see [`OrderbookAction::Register`]
+    pub fn with_invite_key(mut self, invite_key: Vec<u8>) -> Self {
+        self.invite_key = Some(invite_key);
+        self
+    }
+
+    /// Requires transactions to prove their identity, see [`IdentityVerification`]
+    pub fn with_identity_verification(mut self, verification: IdentityVerification) -> Self {
+        self.identity_verification = verification;
+        self
+    }
+
+    /// Delays withdrawals by `blocks`, see [`OrderbookAction::RequestWithdraw`]
+    pub fn with_withdrawal_delay(mut self, blocks: u64) -> Self {
+        self.withdrawal_delay = blocks;
+        self
+    }
+
+    /// Restricts the orders of this instance to `pairs`, when the orderbook is sharded across
+    /// several contracts
+    pub fn with_pairs(mut self, pairs: BTreeSet<TokenPair>) -> Self {
+        self.pairs = pairs;
+        self
+    }
+
+    /// Whether orders on `pair` can be created on this instance
+    pub fn trades_pair(&self, pair: &TokenPair) -> bool {
+        self.pairs.is_empty() || self.pairs.contains(pair)
+    }
+
+    /// Whether orders on `pair` are allowed by the admin, see [`OrderbookAction::CreatePair`]
+    pub fn is_pair_listed(&self, pair: &TokenPair) -> bool {
+        (self.listed_pairs.as_ref()).map_or(true, |pairs| pairs.contains(pair))
+    }
+
+    /// Checks that the transaction of `calldata` carries the proof of its identity required by
+    /// the deployment
+    fn verify_identity(&self, calldata: &sdk::Calldata) -> Result<(), OrderbookError> {
+        let companions = CompanionBlobs::new(calldata);
+        match self.identity_verification {
+            IdentityVerification::Trusted => Ok(()),
+            IdentityVerification::Wallet => companions.find_wallet_auth().map(|_| ()),
+            IdentityVerification::WalletOrSecp256k1 => {
+                if companions.find_wallet_auth().is_ok() {
+                    return Ok(());
+                }
+                let identity = &calldata.identity;
+                let blob =
+                    (calldata.blobs.get(&calldata.index)).ok_or(OrderbookError::BlobNotFound {
+                        blob: calldata.index.0,
+                    })?;
+                let digest: [u8; 32] = Sha256::digest(&blob.data.0).into();
+                let signature = companions.find_secp256k1(identity, &digest).map_err(|_| {
+                    OrderbookError::MissingIdentityProof {
+                        identity: identity.0.clone(),
+                    }
+                })?;
+                Self::check_signer(&identity.0, &signature)
+            }
+        }
+    }
+
+    /// Checks that the account of `identity` is the hex-encoded public key of `signature`
+    fn check_signer(identity: &str, signature: &Secp256k1Blob) -> Result<(), OrderbookError> {
+        let account = identity
+            .split_once('@')
+            .map_or(identity, |(account, _)| account);
+        if hex::encode(signature.public_key) != account {
+            return Err(OrderbookError::SignerMismatch {
+                identity: identity.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Data the owner of a [`OrderbookAction::SubmitSignedOrder`] signs
+    pub fn signed_order_digest(owner: &str, nonce: u64, order: &OrderbookAction) -> [u8; 32] {
+        let signed = borsh::to_vec(&(owner, nonce, order)).expect("Failed to encode signed order");
+        Sha256::digest(signed).into()
+    }
+
+    /// Checks that `pair` is not traded the other way around. A pair has a single book: its base
+    /// and quote tokens are the ones it was first listed, configured or traded with.
+    fn ensure_canonical(&self, pair: &TokenPair) -> Result<(), OrderbookError> {
+        if pair.0 == pair.1 {
+            return Err(OrderbookError::SameTokenPair { pair: pair.clone() });
+        }
+        let inverse = (pair.1.clone(), pair.0.clone());
+        let listed = self.listed_pairs.as_ref();
+        let inverse_known = listed.is_some_and(|pairs| pairs.contains(&inverse))
+            || self.markets.contains_key(&inverse)
+            || self.book_seqs.contains_key(&inverse);
+        if inverse_known {
+            return Err(OrderbookError::InvertedPair { pair: pair.clone() });
+        }
+        Ok(())
+    }
+
+    /// Data the invite code of `user` is a signature of
+    pub fn invite_code_digest(user: &str) -> [u8; 32] {
+        Sha256::digest(format!("Invite code for {}", user)).into()
+    }
+
+    pub fn partial_commit(&self) -> sdk::StateCommitment {
+        let mut partial_state = self.clone();
+        partial_state.latest_deposit = Default::default();
+        partial_state.actions_per_block = Default::default();
+        partial_state.trade_history = Default::default();
+        partial_state.closed_orders = Default::default();
+        // Depth accrual, daily volumes, liquidity points and auction times depend on block
+        // timestamps, unknown to optimistic executions
+        partial_state.incentives = Default::default();
+        partial_state.volumes = Default::default();
+        partial_state.liquidity = Default::default();
+        partial_state.auctions = Default::default();
+
+        // Reset all order timestamps to 0
+        for order in partial_state
+            .orders
+            .values_mut()
+            .chain(partial_state.stop_orders.values_mut().flatten())
+        {
+            order.timestamp = TimestampMs(0);
+        }
+        // Claim heights depend on the block of the request, unknown to optimistic executions
+        for withdrawal in partial_state.pending_withdrawals.values_mut() {
+            withdrawal.claimable_at = sdk::BlockHeight(0);
+        }
+
+        sdk::StateCommitment(borsh::to_vec(&partial_state).expect("Failed to encode Orderbook partial state"))
+    }
+}
+
+/// Enum representing possible calls to the contract functions.
+#[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub enum OrderbookAction {
+    /// Creates an order, identified by [`Orderbook::order_id`] in the events it emits
+    CreateOrder {
+        order_type: OrderType,
+        price: Option<u128>,
+        pair: TokenPair,
+        quantity: u128,
+        #[serde(default)]
+        time_in_force: TimeInForce,
+        /// Makes the order a stop order, see [`Order::trigger_price`]
+        #[serde(default)]
+        trigger_price: Option<u128>,
+        /// What to do instead of matching the order with orders of the same owner
+        #[serde(default)]
+        self_trade_prevention: Option<SelfTradePrevention>,
+        /// Makes the order expire, see [`Order::expires_at`]
+        #[serde(default)]
+        expires_at: Option<TimestampMs>,
+        /// Makes the order an iceberg order, see [`Order::display_quantity`]
+        #[serde(default)]
+        display_quantity: Option<u128>,
+    },
+    Cancel {
+        order_id: String,
+    },
+    /// Deposits `amount` of `token` transferred from the caller to the orderbook in the same
+    /// transaction, by a transfer blob preceding the orderbook blob, see [`blobs::deposit_blob`]
+    Deposit {
+        token: String,
+        amount: u128,
+    },
+    /// Withdraws from the caller's balance. The transaction must transfer the tokens from the
+    /// orderbook to the recipient, see [`blobs::payout_blob`].
+    Withdraw {
+        token: String,
+        amount: u128,
+        /// Identity the tokens are transferred to, the caller's own by default
+        recipient: Option<String>,
+    },
+    /// Moves tokens from the caller's balance to a pending withdrawal, claimable with
+    /// [`OrderbookAction::ClaimWithdraw`] once the withdrawal delay of the deployment elapsed.
+    /// The only way to withdraw when the delay is set.
+    RequestWithdraw {
+        token: String,
+        amount: u128,
+        /// Identity the tokens are transferred to, the caller's own by default
+        recipient: Option<String>,
+    },
+    /// Pays out a withdrawal of the caller once its delay elapsed. The transaction must transfer
+    /// the tokens from the orderbook to its recipient, see [`blobs::payout_blob`].
+    ClaimWithdraw {
+        withdrawal_id: u64,
+    },
+    /// Cancels a pending withdrawal, giving the tokens back to its owner. Sent by the owner, or
+    /// by the admin to stop a suspicious withdrawal.
+    CancelWithdraw {
+        withdrawal_id: u64,
+    },
+    /// Admin only: distributes `amount` of `token` from the admin balance to makers, pro-rata
+    /// to their activity during the current epoch, and starts a new epoch.
+    DistributeMakerRewards {
+        token: String,
+        amount: u128,
+    },
+    /// Admin only: moves `amount` of `token` from the admin balance to the [`INCENTIVES_POOL`]
+    FundIncentives {
+        token: String,
+        amount: u128,
+    },
+    /// Admin only: distributes `amount` of `token` from the [`INCENTIVES_POOL`] to the makers
+    /// of `pair`, pro-rata to their liquidity points, and starts a new epoch of the pair
+    DistributeIncentives {
+        pair: TokenPair,
+        token: String,
+        amount: u128,
+    },
+    /// Cancels all of the caller's orders, withdraws all of its balances and removes the account.
+    /// Without a withdrawal delay, the transaction must pay out each balance to the caller, as
+    /// for [`OrderbookAction::Withdraw`].
+    CloseAccount,
+    /// Cancels the expired orders of `pair`, see [`Order::expires_at`], and drops the trading
+    /// volumes older than the fee tiers window. Anyone can send it.
+    PruneExpired {
+        pair: TokenPair,
+    },
+    /// Runs the auction of batch-auction `pair` once its interval elapsed since the last one,
+    /// see [`MarketConfig::auction_interval_ms`]. Anyone can send it, and the first order
+    /// created after that runs it too.
+    RunAuction {
+        pair: TokenPair,
+    },
+    /// Admin only: sets the trading parameters of `pair`
+    ConfigureMarket {
+        pair: TokenPair,
+        config: MarketConfig,
+    },
+    /// Admin only: sets the fees charged on every fill
+    SetFeeSchedule {
+        schedule: FeeSchedule,
+    },
+    /// Admin only: moves `amount` of `token` from the fees accrued to the admin balance
+    WithdrawFees {
+        token: String,
+        amount: u128,
+    },
+    /// Admin only: moves `amount` of `token` from the admin balance to the [`INSURANCE`] fund
+    FundInsurance {
+        token: String,
+        amount: u128,
+    },
+    /// Admin only: sets the number of decimals of `token`, while it has no open orders. Prices
+    /// are in quote token units per whole base token, i.e. per 10^decimals units of it.
+    ConfigureToken {
+        token: String,
+        decimals: u8,
+    },
+    /// Sets the price and quantity left of a resting order. It keeps its time priority unless
+    /// its price changes.
+    ModifyOrder {
+        order_id: String,
+        new_price: u128,
+        new_quantity: u128,
+    },
+    /// Admin only: sets the balance of `token` below which user balances are swept, see
+    /// [`OrderbookAction::SweepDust`]
+    SetDustThreshold {
+        token: String,
+        threshold: u128,
+    },
+    /// Moves the user balances of `token` below its dust threshold to the [`DUST`] account and
+    /// drops the empty balance entries. Anyone can send it.
+    SweepDust {
+        token: String,
+    },
+    /// Admin only: accepts the transactions sequenced on the lane `lane_id`, e.g. before
+    /// rotating the validator the orderbook runs on
+    AddLane {
+        lane_id: LaneId,
+    },
+    /// Admin only: stops accepting the transactions sequenced on the lane `lane_id`
+    RemoveLane {
+        lane_id: LaneId,
+    },
+    /// Admin only: accepts deposits and withdrawals of `token`
+    AddToken {
+        token: String,
+    },
+    /// Admin only: stops accepting deposits of `token`, delisting its pairs and cancelling their
+    /// orders. Its balances can still be withdrawn.
+    RemoveToken {
+        token: String,
+    },
+    /// Admin only: lists `pair`, whose tokens must be accepted. Once a pair is listed, orders
+    /// can only be created on the listed pairs.
+    CreatePair {
+        pair: TokenPair,
+    },
+    /// Admin only: delists `pair`, cancelling its resting and stop orders
+    DelistPair {
+        pair: TokenPair,
+    },
+    /// Admin only: halts or resumes the trading of `pair`, or of every pair if `None`. While
+    /// halted, orders cannot be created or modified, but they can still be cancelled and
+    /// balances withdrawn.
+    SetHalted {
+        pair: Option<TokenPair>,
+        halted: bool,
+    },
+    /// Executes the actions in order, as a single transaction: if one of them fails, none of
+    /// them is applied. Batches cannot be nested.
+    Batch(Vec<OrderbookAction>),
+    /// Creates two orders of the same pair with [`OrderbookAction::CreateOrder`], e.g. a
+    /// take-profit and a stop-loss. Once one of them trades or is cancelled, what is left of the
+    /// other is cancelled.
+    OneCancelsOther {
+        first: Box<OrderbookAction>,
+        second: Box<OrderbookAction>,
+    },
+    /// Creates the order of `owner` on its behalf, so that anyone can relay orders signed
+    /// offline. `order` must be a [`OrderbookAction::CreateOrder`], and `signature` the
+    /// signature of [`Orderbook::signed_order_digest`] by the owner, verified by a secp256k1 blob
+    /// of the same transaction. The account of `owner` is the hex-encoded public key.
+    SubmitSignedOrder {
+        owner: String,
+        /// Nonce of the owner, so that the order is created at most once, see
+        /// [`OrderbookBlob::nonce`]
+        nonce: u64,
+        order: Box<OrderbookAction>,
+        signature: Vec<u8>,
+    },
+    /// Deposits `amount` of `token` sent from the caller's wallet in the same transaction: the
+    /// blob at index `transfer` must transfer them from the caller to the orderbook.
+    DepositFromWallet {
+        token: String,
+        amount: u128,
+        transfer: sdk::BlobIndex,
+    },
+    /// Registers the caller with an invite code: the signature, by the orderbook invite key, of
+    /// [`Orderbook::invite_code_digest`]. It must be verified by a secp256k1 blob of the same
+    /// transaction.
+    Register {
+        invite_code_signature: Vec<u8>,
+    },
+    /// Sets the caller's referrer, who gets [`FeeSchedule::referral_bps`] of the taker fees the
+    /// caller pays
+    RegisterReferrer {
+        referrer: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, BorshSerialize, BorshDeserialize)]
+pub struct Order {
+    pub owner: String,
+    pub order_id: String,
+    pub order_type: OrderType,
+    pub price: Option<u128>,
+    /// For stop orders, the last traded price of the pair activating the order once it is
+    /// crossed: at or above it for buy orders, at or below it for sell orders. Stop orders
+    /// without a price are executed as market orders.
+    pub trigger_price: Option<u128>,
+    pub pair: TokenPair,
+    pub quantity: u128,
+    /// Time priority of the order in its price level: when it was created, or when the last
+    /// slice of an iceberg order was shown
+    pub timestamp: TimestampMs,
+    /// Time from which the order is no longer matched. It is cancelled by the next order on
+    /// its pair, or by [`OrderbookAction::PruneExpired`].
+    #[serde(default)]
+    pub expires_at: Option<TimestampMs>,
+    /// Makes the order an iceberg order once it rests on the book: only this much of its
+    /// quantity is matched at a time. When it is consumed, the next slice is shown and the
+    /// order goes to the back of its price level.
+    #[serde(default)]
+    pub display_quantity: Option<u128>,
+    /// Part of the quantity of a resting iceberg order that is not shown yet
+    #[serde(default)]
+    pub hidden_quantity: u128,
+    /// Quantity of the order filled so far, `quantity` being what is left of it
+    #[serde(default)]
+    pub filled_quantity: u128,
+    #[serde(default)]
+    pub status: OrderStatus,
+    /// Tokens the order holds in reserve while it rests, in its [`Order::reserved_token`].
+    /// Fills and refunds take from it, so an order gives back exactly what it reserved.
+    #[serde(default)]
+    pub reserved_amount: u128,
+}
+
+/// Where an order is in its lifecycle. Orders are open or partially filled while they rest on
+/// the book or wait for their trigger price, and archived once closed, see
+/// [`Orderbook::closed_orders_of`].
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    BorshSerialize,
+    BorshDeserialize,
+)]
+pub enum OrderStatus {
+    #[default]
+    Open,
+    PartiallyFilled,
+    Filled,
+    /// Cancelled by its owner, the admin or the rules of the market, e.g. the remainder of an
+    /// immediate-or-cancel order
+    Cancelled,
+    /// Cancelled once its expiry passed, see [`Order::expires_at`]
+    Expired,
+}
+
+impl Order {
+    /// Records that `quantity` of the order was filled, on top of the quantity already taken
+    /// off it
+    fn record_fill(&mut self, quantity: u128) {
+        self.filled_quantity += quantity;
+        self.status = OrderStatus::PartiallyFilled;
+    }
+
+    /// Quantity of the order, filled or not. Modifying the quantity of an order changes it.
+    pub fn original_quantity(&self) -> u128 {
+        self.quantity + self.filled_quantity
+    }
+
+    /// Token reserved for the order while it rests: the quote token of a buy order, the base
+    /// token of a sell order
+    pub fn reserved_token(&self) -> &String {
+        match self.order_type {
+            OrderType::Buy => &self.pair.1,
+            OrderType::Sell => &self.pair.0,
+        }
+    }
+
+    /// Quantity of the order that can be matched
+    pub fn visible_quantity(&self) -> u128 {
+        self.quantity - self.hidden_quantity
+    }
+
+    /// Status of the order once cancelled at `now`
+    fn closing_status(&self, now: &TimestampMs) -> OrderStatus {
+        if self.is_expired(now) {
+            OrderStatus::Expired
+        } else {
+            OrderStatus::Cancelled
+        }
+    }
+
+    /// Hides what exceeds the display quantity of an iceberg order
+    fn hide_quantity(&mut self) {
+        if let Some(display_quantity) = self.display_quantity {
+            self.hidden_quantity = self.quantity.saturating_sub(display_quantity);
+        }
+    }
+
+    fn is_expired(&self, now: &TimestampMs) -> bool {
+        self.expires_at
+            .as_ref()
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Whether the order's limit price lets it trade at `price`
+    fn crosses(&self, price: u128) -> bool {
+        match (&self.order_type, self.price) {
+            (_, None) => true,
+            (OrderType::Buy, Some(limit)) => price <= limit,
+            (OrderType::Sell, Some(limit)) => price >= limit,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum OrderType {
+    Buy,
+    Sell,
+}
+
+/// How long an order stays on the book once it has been matched against the resting orders
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    BorshSerialize,
+    BorshDeserialize,
+)]
+pub enum TimeInForce {
+    /// What is left rests on the book until it is filled or cancelled
+    #[default]
+    GoodTilCancelled,
+    /// Fills what it can, and the rest is cancelled
+    ImmediateOrCancel,
+    /// Fills the whole quantity right away, or fails
+    FillOrKill,
+}
+
+/// What happens when an order would be matched against a resting order of the same owner
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub enum SelfTradePrevention {
+    /// The incoming order is cancelled, keeping what it filled so far
+    CancelNewest,
+    /// The resting order is cancelled, and the incoming order keeps matching
+    CancelOldest,
+    /// Both orders are reduced by the smaller of their quantities, which cancels it
+    DecrementAndCancel,
+}
+
+/// Proof of their identity the transactions sent to the orderbook must carry, besides their
+/// orderbook blob
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    BorshSerialize,
+    BorshDeserialize,
+)]
+pub enum IdentityVerification {
+    /// The identity of the transaction is trusted as is
+    #[default]
+    Trusted,
+    /// A blob of the wallet of the identity (`{account}@{wallet}`), which authenticates it
+    Wallet,
+    /// A wallet blob, or a secp256k1 signature of the orderbook blob's data by the identity, whose
+    /// account is the hex-encoded public key
+    WalletOrSecp256k1,
+}
+
+pub type TokenPair = (String, String);
+
+#[derive(Debug, Serialize, Deserialize, Clone, BorshSerialize, BorshDeserialize)]
+pub enum OrderbookEvent {
+    OrderCreated {
+        order: Order,
+    },
+    /// A stop order is waiting for its trigger price, see [`Order::trigger_price`]
+    StopOrderPlaced {
+        order: Order,
+    },
+    /// A trade at `last_price` activated a stop order. It is followed by the events of its
+    /// execution, or by its cancellation if it could not be executed.
+    StopOrderTriggered {
+        order_id: String,
+        pair: TokenPair,
+        last_price: u128,
+    },
+    OrderCancelled {
+        order_id: String,
+        pair: TokenPair,
+    },
+    OrderExecuted {
+        order_id: String,
+        pair: TokenPair,
+        /// Price the order's owner traded at, if the event comes from a fill
+        executed_price: Option<u128>,
+    },
+    OrderUpdate {
+        order_id: String,
+        remaining_quantity: u128,
+        pair: TokenPair,
+        /// Price the order's owner traded at, if the event comes from a fill
+        executed_price: Option<u128>,
+    },
+    /// A fill between a resting order and the order matched against it. Emitted before the
+    /// events updating both orders.
+    TradeExecuted {
+        pair: TokenPair,
+        /// Price of the resting order
+        price: u128,
+        quantity: u128,
+        maker_order_id: String,
+        taker_order_id: String,
+        maker: String,
+        taker: String,
+        timestamp: TimestampMs,
+    },
+    BalanceUpdated {
+        user: String,
+        token: String,
+        amount: u128,
+    },
+    /// Emitted after every change of a pair's book, see [`Orderbook::book_hash`]
+    BookHash {
+        pair: TokenPair,
+        hash: String,
+        seq: u64,
+    },
+    MakerRewardsDistributed {
+        epoch: u64,
+        token: String,
+        amount: u128,
+    },
+    LiquidityRewardsDistributed {
+        pair: TokenPair,
+        epoch: u64,
+        token: String,
+        amount: u128,
+    },
+    MarketConfigured {
+        pair: TokenPair,
+        config: MarketConfig,
+    },
+    TokenConfigured {
+        token: String,
+        decimals: u8,
+    },
+    /// A fee taken by the [`FEE_POOL`] account from what `user` received for a fill of its
+    /// order
+    FeeCharged {
+        pair: TokenPair,
+        order_id: String,
+        user: String,
+        token: String,
+        amount: u128,
+    },
+    FeeScheduleUpdated {
+        schedule: FeeSchedule,
+    },
+    UserRegistered {
+        user: String,
+    },
+    ReferrerRegistered {
+        user: String,
+        referrer: String,
+    },
+    /// Share of the taker fee of a fill of `referee`'s order paid to its referrer, out of the
+    /// [`FEE_POOL`] account
+    ReferralRewarded {
+        pair: TokenPair,
+        order_id: String,
+        referrer: String,
+        referee: String,
+        token: String,
+        amount: u128,
+    },
+    TokenAdded {
+        token: String,
+    },
+    TokenRemoved {
+        token: String,
+    },
+    DustThresholdUpdated {
+        token: String,
+        threshold: u128,
+    },
+    /// Balances of `token` below its dust threshold, adding up to `amount`, were swept to the
+    /// [`DUST`] account
+    DustSwept {
+        token: String,
+        amount: u128,
+    },
+    LaneAdded {
+        lane_id: LaneId,
+    },
+    LaneRemoved {
+        lane_id: LaneId,
+    },
+    PairCreated {
+        pair: TokenPair,
+    },
+    PairDelisted {
+        pair: TokenPair,
+    },
+    /// Trading was halted on `pair`, or on every pair if `None`
+    MarketHalted {
+        pair: Option<TokenPair>,
+    },
+    MarketResumed {
+        pair: Option<TokenPair>,
+    },
+    /// Both orders were created by [`OrderbookAction::OneCancelsOther`], and are still open
+    OrdersLinked {
+        pair: TokenPair,
+        order_id: String,
+        linked_order_id: String,
+    },
+    /// The auction of a batch-auction pair matched `quantity` on each side at `price`. It is
+    /// followed by the trades.
+    AuctionCleared {
+        pair: TokenPair,
+        price: u128,
+        quantity: u128,
+    },
+    WithdrawalRequested {
+        withdrawal_id: u64,
+        withdrawal: PendingWithdrawal,
+    },
+    WithdrawalClaimed {
+        withdrawal_id: u64,
+        user: String,
+    },
+    WithdrawalCancelled {
+        withdrawal_id: u64,
+        user: String,
+    },
+}
+
+impl OrderbookAction {
+    /// Orderbook blob of the action, see [`OrderbookBlob::nonce`]
+    pub fn as_blob(&self, contract_name: sdk::ContractName, nonce: u64) -> sdk::Blob {
+        let blob = OrderbookBlob {
+            nonce,
+            action: self.clone(),
+        };
+        sdk::Blob {
+            contract_name,
+            data: sdk::BlobData(borsh::to_vec(&blob).expect("Failed to encode OrderbookBlob")),
+        }
+    }
+}
+
+/// Content of the orderbook blob of a transaction
+#[derive(Debug, Serialize, Deserialize, Clone, BorshSerialize, BorshDeserialize)]
+pub struct OrderbookBlob {
+    /// Must be above the last nonce of the sender, so that an action is executed at most once,
+    /// and never after a later action of the same sender. Nonces do not have to be
+    /// consecutive: a timestamp can be used.
+    pub nonce: u64,
+    pub action: OrderbookAction,
+}
+
+impl OrderbookBlob {
+    /// Action of an orderbook blob, if it can be decoded
+    pub fn action(data: &sdk::BlobData) -> Option<OrderbookAction> {
+        borsh::from_slice::<OrderbookBlob>(&data.0)
+            .ok()
+            .map(|blob| blob.action)
+    }
+}
+
+/// Serializes maps keyed by non-string types (e.g. token pairs) as a list of entries,
+/// so the state can be encoded in JSON.
+mod map_as_entries {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S, K, V>(map: &BTreeMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        K: Serialize,
+        V: Serialize,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, D, K, V>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+    where
+        D: Deserializer<'de>,
+        K: Deserialize<'de> + Ord,
+        V: Deserialize<'de>,
+    {
+        let entries = Vec::<(K, V)>::deserialize(deserializer)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::collections::BTreeMap;
+
+    static TX_CTX: sdk::TxContext = sdk::TxContext {
+            block_height: sdk::BlockHeight(6),
+            lane_id: LaneId(sdk::ValidatorPublicKey(vec![])),
+            timestamp: TimestampMs(0),
+            block_hash: sdk::ConsensusProposalHash(String::new()),
+            chain_id: 0,
+        };
+
+    fn setup() -> (String, String, Orderbook) {
+        let mut orderbook = Orderbook::init(LaneId::default(), "admin".to_string());
+        let eth_user = "eth_user".to_string();
+        let usd_user = "usd_user".to_string();
+
+        let mut eth_token = BTreeMap::new();
+        eth_token.insert("ETH".to_string(), 10);
+        orderbook.balances.insert(eth_user.clone(), eth_token);
+
+        let mut usd_token = BTreeMap::new();
+        usd_token.insert("USD".to_string(), 3000);
+
+        orderbook.balances.insert(usd_user.clone(), usd_token);
+        orderbook.deposits = BTreeMap::from([("ETH".to_string(), 10), ("USD".to_string(), 3000)]);
+
+        orderbook.latest_deposit.insert(
+            eth_user.clone(),
+            BTreeMap::from([("ETH".to_string(), BlockHeight(0))]));
+
+        orderbook.latest_deposit.insert(
+            usd_user.clone(),
+            BTreeMap::from([("USD".to_string(), BlockHeight(0))]),
+        );
+
+        (eth_user, usd_user, orderbook)
+    }
+
+    #[test_log::test]
+    fn test_limit_sell_order_create() {
+        let (eth_user, _, mut orderbook) = setup();
+
+        // Create a limit sell order
+        let order = Order {
+            owner: eth_user.clone(),
+            order_id: "order1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(order.clone(), TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Check that the order was created
+        assert_eq!(events.len(), 3);
+        let created_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderCreated { .. }))
+            .count();
+        let balance_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
+            .count();
+        assert_eq!(created_count, 1);
+        assert_eq!(balance_count, 2);
+
+        // Check that the order is in the sell orders list
+        assert!(orderbook.orders.contains_key("order1"));
+        assert!(orderbook
+            .sell_orders
+            .get(&("ETH".to_string(), "USD".to_string()))
+            .unwrap()
+            .contains("order1"));
+    }
+
+    #[test_log::test]
+    fn test_limit_buy_order_create() {
+        let (_, usd_user, mut orderbook) = setup();
+
+        // Create a limit sell order
+        let order = Order {
+            owner: usd_user.clone(),
+            order_id: "order1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(order.clone(), TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Check that the order was created
+        assert_eq!(events.len(), 3);
+        let created_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderCreated { .. }))
+            .count();
+        let balance_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
+            .count();
+        assert_eq!(created_count, 1);
+        assert_eq!(balance_count, 2);
+
+        // Check that the order is in the sell orders list
+        assert!(orderbook.orders.contains_key("order1"));
+        assert!(orderbook
+            .buy_orders
+            .get(&("ETH".to_string(), "USD".to_string()))
+            .unwrap()
+            .contains("order1"));
+    }
+
+    #[test_log::test]
+    fn test_immediate_or_cancel_never_rests() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(1000),
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Buys 2, only 1 is available: the rest is cancelled rather than left on the book
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(1000),
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity: 2,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::ImmediateOrCancel, &TX_CTX)
+            .unwrap();
+
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, OrderbookEvent::OrderCreated { .. })));
+        assert!(orderbook.orders.is_empty());
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 2000);
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 1);
+    }
+
+    #[test_log::test]
+    fn test_fill_or_kill_fails_unless_fully_filled() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(1000),
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        let buy_order = |quantity: u128| OrderbookAction::CreateOrder {
+            order_type: OrderType::Buy,
+            price: Some(1000),
+            pair: pair.clone(),
+            quantity,
+            time_in_force: TimeInForce::FillOrKill,
+            trigger_price: None,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
+        };
+        let calldata = sdk::Calldata {
+            tx_hash: sdk::TxHash(String::new()),
+            identity: sdk::Identity(usd_user.clone()),
+            blobs: vec![
+                buy_order(2).as_blob("orderbook".into(), orderbook.last_nonce(&usd_user) + 1)
+            ]
+            .into(),
+            tx_blob_count: 1,
+            index: sdk::BlobIndex(0),
+            tx_ctx: Some(TX_CTX.clone()),
+            private_input: vec![],
+        };
+        let err = execute_err(&mut orderbook, &calldata);
+        assert!(
+            err.to_string().contains("cannot be filled entirely"),
+            "{err}"
+        );
+        assert!(orderbook.orders.contains_key("sell1"));
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 3000);
+
+        execute_action(&mut orderbook, &usd_user, buy_order(1));
+        assert!(orderbook.orders.is_empty());
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 1);
+    }
+
+    #[test_log::test]
+    fn test_fill_or_kill_pro_rata() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let config = market::MarketConfig {
+            matching: market::MatchingMode::ProRata,
+            ..Default::default()
+        };
+        orderbook
+            .configure_market(pair.clone(), config, "admin".to_string())
+            .unwrap();
+
+        let order = |order_id: &str, order_type, price, quantity, display_quantity| Order {
+            owner: if order_type == OrderType::Sell {
+                eth_user.clone()
+            } else {
+                usd_user.clone()
+            },
+            order_id: order_id.to_string(),
+            order_type,
+            price: Some(price),
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(
+                order("sell1", OrderType::Sell, 400, 1, None),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        orderbook
+            .execute_order(
+                order("sell2", OrderType::Sell, 500, 5, Some(1)),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        // A level showing nothing is allocated nothing, although the check counts its quantity
+        orderbook.orders.get_mut("sell2").unwrap().hidden_quantity = 5;
+
+        let err = orderbook
+            .clone()
+            .execute_order(
+                order("buy1", OrderType::Buy, 500, 3, None),
+                TimeInForce::FillOrKill,
+                &TX_CTX,
+            )
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                OrderbookError::FillOrKillUnfilled {
+                    available: 1,
+                    quantity: 3,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+
+        orderbook.orders.get_mut("sell2").unwrap().hidden_quantity = 4;
+        orderbook
+            .execute_order(
+                order("buy1", OrderType::Buy, 500, 3, None),
+                TimeInForce::FillOrKill,
+                &TX_CTX,
+            )
+            .unwrap();
+        assert!(!orderbook.orders.contains_key("sell1"));
+        assert_eq!(orderbook.orders["sell2"].quantity, 3);
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 3);
+    }
+
+    #[test_log::test]
+    fn test_limit_order_match_same_quantity_same_price() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit sell order first
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a matching buy order
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Check that the order was executed
+        assert_eq!(events.len(), 7);
+        let executed_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
+            .count();
+        let balance_updated_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
+            .count();
+        assert_eq!(executed_count, 2);
+        // usd_user received ETH
+        // usd_user sent USD
+        // orderbook sent ETH
+        // eth_user received USD
+        assert_eq!(balance_updated_count, 4);
+
+        // Check balances were updated correctly
+        let eth_user_usd = orderbook
+            .balances
+            .get(&eth_user)
+            .unwrap()
+            .get("USD")
+            .unwrap();
+        let usd_user_eth = orderbook
+            .balances
+            .get(&usd_user)
+            .unwrap()
+            .get("ETH")
+            .unwrap();
+
+        assert_eq!(*eth_user_usd, 2000); // Seller received USD
+        assert_eq!(*usd_user_eth, 1); // Buyer received ETH
+    }
+
+    #[test_log::test]
+    fn test_limit_order_match_same_quantity_lower_price() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit sell order first
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a matching buy order
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(1900),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Check that the order was NOT executed
+        assert_eq!(events.len(), 3);
+        let created_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderCreated { .. }))
+            .count();
+        let balance_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
+            .count();
+        assert_eq!(created_count, 1);
+        assert_eq!(balance_count, 2);
+
+        // Check balances were updated correctly
+        let eth_user_usd = orderbook
+            .balances
+            .get(&eth_user)
+            .unwrap()
+            .get("USD")
+            .unwrap_or(&0);
+        let usd_user_eth = orderbook
+            .balances
+            .get(&usd_user)
+            .unwrap()
+            .get("ETH")
+            .unwrap_or(&0);
+
+        assert_eq!(*eth_user_usd, 0); // Seller did not received USD
+        assert_eq!(*usd_user_eth, 0); // Buyer did not received ETH
+
+        // Check user correctly desposited the amount
+        let eth_user_eth = orderbook
+            .balances
+            .get(&eth_user)
+            .unwrap()
+            .get("ETH")
+            .unwrap();
+        let usd_user_usd = orderbook
+            .balances
+            .get(&usd_user)
+            .unwrap()
+            .get("USD")
+            .unwrap();
+
+        assert_eq!(*eth_user_eth, 10 - 1); // Seller did not received USD
+        assert_eq!(*usd_user_usd, 3000 - 1900); // Buyer did not received ETH
+    }
+
+    #[test_log::test]
+    fn test_limit_order_match_same_quantity_lower_price_bis() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit sell order first
+        let sell_order = Order {
+            owner: usd_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(1900),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a matching buy order
+        let buy_order = Order {
+            owner: eth_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Check that the order was NOT executed
+        assert_eq!(events.len(), 3);
+        let created_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderCreated { .. }))
+            .count();
+        let balance_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
+            .count();
+        assert_eq!(created_count, 1);
+        assert_eq!(balance_count, 2);
+
+        // Check balances were updated correctly
+        let eth_user_usd = orderbook
+            .balances
+            .get(&eth_user)
+            .unwrap()
+            .get("USD")
+            .unwrap_or(&0);
+        let usd_user_eth = orderbook
+            .balances
+            .get(&usd_user)
+            .unwrap()
+            .get("ETH")
+            .unwrap_or(&0);
+
+        assert_eq!(*eth_user_usd, 0); // Seller did not received USD
+        assert_eq!(*usd_user_eth, 0); // Buyer did not received ETH
+
+        // Check user correctly desposited the amount
+        let eth_user_eth = orderbook
+            .balances
+            .get(&eth_user)
+            .unwrap()
+            .get("ETH")
+            .unwrap();
+        let usd_user_usd = orderbook
+            .balances
+            .get(&usd_user)
+            .unwrap()
+            .get("USD")
+            .unwrap();
+
+        assert_eq!(*eth_user_eth, 10 - 1); // Seller did not received USD
+        assert_eq!(*usd_user_usd, 3000 - 1900); // Buyer did not received ETH
+    }
+
+    #[test_log::test]
+    fn test_limit_order_match_same_quantity_higher_price() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit sell order first
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a matching buy order
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(2100),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Check that the order was executed
+        assert_eq!(events.len(), 7);
+        let executed_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
+            .count();
+        let balance_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
+            .count();
+        assert_eq!(executed_count, 2);
+        // usd_user received ETH
+        // usd_user sent USD
+        // orderbook sent ETH
+        // eth_user received USD
+        assert_eq!(balance_count, 4);
+
+        // Check balances were updated correctly
+        let eth_user_balances = orderbook.balances.get(&eth_user).unwrap();
+        let usd_user_balances = orderbook.balances.get(&usd_user).unwrap();
+
+        assert_eq!(*eth_user_balances.get("USD").unwrap(), 2000);
+        assert_eq!(*eth_user_balances.get("ETH").unwrap(), 9);
+
+        assert_eq!(*usd_user_balances.get("USD").unwrap(), 1000);
+        assert_eq!(*usd_user_balances.get("ETH").unwrap(), 1);
+    }
+
+    #[test_log::test]
+    fn test_limit_order_match_less_sell_quantity_same_price() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit sell order for 1 ETH
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(1000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a buy order for 2 ETH
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(1000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 2,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Check that the order was NOT executed
+        assert_eq!(events.len(), 8);
+        let executed_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
+            .count();
+        let created_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderCreated { .. }))
+            .count();
+        let balance_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
+            .count();
+        assert_eq!(executed_count, 1);
+        assert_eq!(created_count, 1);
+        // eth_user received USD
+        // usd_user sent USD
+        // usd_user received ETH
+        // orderbook received USD
+        // orderbook sent ETH
+        assert_eq!(balance_count, 5);
+
+        assert_eq!(orderbook.orders.len(), 1);
+        let only_order = orderbook.orders.values().next().unwrap();
+        assert!(matches!(only_order.order_type, OrderType::Buy));
+
+        // Check balances were updated correctly
+        let eth_user_balances = orderbook.balances.get(&eth_user).unwrap();
+        let usd_user_balances = orderbook.balances.get(&usd_user).unwrap();
+        let orderbook_balances = orderbook.balances.get(RESERVES).unwrap();
+
+        assert_eq!(*eth_user_balances.get("USD").unwrap(), 1000);
+        assert_eq!(*eth_user_balances.get("ETH").unwrap(), 9);
+
+        assert_eq!(*usd_user_balances.get("USD").unwrap(), 1000);
+        assert_eq!(*usd_user_balances.get("ETH").unwrap(), 1);
+
+        assert_eq!(*orderbook_balances.get("USD").unwrap(), 1000);
+        assert_eq!(*orderbook_balances.get("ETH").unwrap(), 0);
+    }
+
+    #[test_log::test]
+    fn test_partial_order_execution_same_price() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit sell order for 2 ETH
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 2,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a buy order for 1 ETH
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Check that we got an OrderUpdate event
+        assert!(events.iter().any(|event| matches!(event,
+            OrderbookEvent::OrderUpdate {
+                order_id,
+                remaining_quantity,
+                pair: _,
+                executed_price: _
+            } if order_id == "sell1" && *remaining_quantity == 1
+        )));
+
+        // Check balances were updated correctly
+        let seller_usd = orderbook
+            .balances
+            .get(&eth_user)
+            .unwrap()
+            .get("USD")
+            .unwrap();
+        let buyer_eth = orderbook
+            .balances
+            .get(&usd_user)
+            .unwrap()
+            .get("ETH")
+            .unwrap();
+
+        assert_eq!(*seller_usd, 2000); // Seller received USD for 1 ETH
+        assert_eq!(*buyer_eth, 1); // Buyer received 1 ETH
+
+        // Check that the sell order is still in the orderbook with updated quantity
+        let remaining_order = orderbook.orders.get("sell1").unwrap();
+        assert_eq!(remaining_order.quantity, 1);
+    }
+
+    #[test_log::test]
+    fn test_partial_order_execution_higher_price() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit sell order for 2 ETH
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 2,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a buy order for 1 ETH at a higher price
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(2100),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Check that we got an OrderUpdate event
+        assert!(events.iter().any(|event| matches!(event,
+            OrderbookEvent::OrderUpdate {
+                order_id,
+                remaining_quantity,
+                pair: _,
+                executed_price: _
+            } if order_id == "sell1" && *remaining_quantity == 1
+        )));
+
+        // Check balances were updated correctly
+        let seller_usd = orderbook
+            .balances
+            .get(&eth_user)
+            .unwrap()
+            .get("USD")
+            .unwrap();
+        let buyer_eth = orderbook
+            .balances
+            .get(&usd_user)
+            .unwrap()
+            .get("ETH")
+            .unwrap();
+
+        assert_eq!(*seller_usd, 2000); // Seller received USD for 1 ETH (at sell price)
+        assert_eq!(*buyer_eth, 1); // Buyer received 1 ETH
+
+        // Check that the sell order is still in the orderbook with updated quantity
+        let remaining_order = orderbook.orders.get("sell1").unwrap();
+        assert_eq!(remaining_order.quantity, 1);
+    }
+
+    #[test_log::test]
+    fn test_limit_order_crosses_several_levels() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let other_eth_user = "other_eth_user".to_string();
+        orderbook.balances.insert(
+            other_eth_user.clone(),
+            BTreeMap::from([("ETH".to_string(), 10)]),
+        );
+        let pair = ("ETH".to_string(), "USD".to_string());
+
+        for (owner, order_id, price) in [
+            (&eth_user, "sell1", 500),
+            (&other_eth_user, "sell2", 600),
+            (&eth_user, "sell3", 700),
+        ] {
+            let sell_order = Order {
+                owner: owner.clone(),
+                order_id: order_id.to_string(),
+                order_type: OrderType::Sell,
+                price: Some(price),
+                trigger_price: None,
+                pair: pair.clone(),
+                quantity: 1,
+                timestamp: TimestampMs(0),
+                expires_at: None,
+                display_quantity: None,
+                hidden_quantity: 0,
+                filled_quantity: 0,
+                status: OrderStatus::Open,
+                reserved_amount: 0,
+            };
+            orderbook
+                .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+                .unwrap();
+        }
+
+        // Takes the two levels below its limit price, and the rest rests on the book
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(650),
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity: 3,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        for (order_id, price) in [("sell1", 500), ("sell2", 600)] {
+            assert!(events.iter().any(|event| matches!(event,
+                OrderbookEvent::OrderExecuted { order_id: id, executed_price: Some(p), .. }
+                    if id == order_id && *p == price
+            )));
+        }
+        assert!(events.iter().any(|event| matches!(event,
+            OrderbookEvent::OrderCreated { order } if order.order_id == "buy1" && order.quantity == 1
+        )));
+        assert!(events.iter().any(|event| matches!(event,
+            OrderbookEvent::TradeExecuted { price: 600, quantity: 1, maker_order_id, taker_order_id, maker, taker, .. }
+                if maker_order_id == "sell2" && taker_order_id == "buy1" && maker == &other_eth_user && taker == &usd_user
+        )));
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 500);
+        assert_eq!(orderbook.get_balance(&other_eth_user, "USD"), 600);
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 2);
+        assert_eq!(
+            orderbook.get_balance(&usd_user, "USD"),
+            3000 - 500 - 600 - 650
+        );
+        assert_eq!(orderbook.best_prices(&pair), (Some(650), Some(700)));
+
+        // The other way around, a sell order takes every bid above its limit price
+        let sell_order = Order {
+            owner: other_eth_user.clone(),
+            order_id: "sell4".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(600),
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity: 2,
+            timestamp: TimestampMs(2),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 3);
+        assert_eq!(orderbook.get_balance(&other_eth_user, "USD"), 600 + 650);
+        assert_eq!(orderbook.best_prices(&pair), (None, Some(600)));
+        assert_eq!(orderbook.orders.get("sell4").unwrap().quantity, 1);
+    }
+
+    #[test_log::test]
+    fn test_market_sell_order_against_larger_buy_order() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit buy order first for 2 ETH
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(1000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 2,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a market sell order for 1 ETH
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: None, // Market order
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Order should be executed immediately at the buy order's price
+        assert!(events.iter().any(|event| matches!(event, OrderbookEvent::OrderUpdate { 
+            order_id,
+            remaining_quantity,
+            pair: _,
+            executed_price: _
+        } if order_id == "buy1" && *remaining_quantity == 1)));
+
+        // Check balances were updated correctly
+        let eth_user_usd = orderbook.balances.get(&eth_user).unwrap().get("USD").unwrap();
+        let usd_user_eth = orderbook.balances.get(&usd_user).unwrap().get("ETH").unwrap();
+
+        assert_eq!(*eth_user_usd, 1000); // Seller got the buy order's price
+        assert_eq!(*usd_user_eth, 1); // Buyer got their ETH
+    }
+
+    #[test_log::test]
+    fn test_market_sell_order_against_exact_buy_order() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit buy order first for 1 ETH
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a market sell order for 1 ETH
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: None, // Market order
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        assert_eq!(events.len(), 6);
+        let executed_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
+            .count();
+        let balance_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
+            .count();
+        assert_eq!(executed_count, 1);
+        // eth_user sent ETH
+        // usd_user received ETH
+        // orderbook sent USD
+        // eth_user received USD
+        assert_eq!(balance_count, 4);
+
+        // Assert orderbook is empty
+        assert_eq!(orderbook.orders.len(), 0);
+
+
+        // Check that balances haven't changed
+        let eth_user_balances = orderbook.balances.get(&eth_user).unwrap();
+        let usd_user_balances = orderbook.balances.get(&usd_user).unwrap();
+        let orderbook_balances = orderbook.balances.get(RESERVES).unwrap();
+
+        assert_eq!(*eth_user_balances.get("ETH").unwrap(), 9); // eth_user sold 1 ETH ...
+        assert_eq!(*eth_user_balances.get("USD").unwrap(), 2000); // .. for 2000 USD
+
+        assert_eq!(*usd_user_balances.get("ETH").unwrap(), 1); // usd_user bought 1 ETH ...
+        assert_eq!(*usd_user_balances.get("USD").unwrap(), 1000); // .. for 2000 USD
+
+        assert_eq!(*orderbook_balances.get("ETH").unwrap_or(&0), 0); // orderbook is empty
+        assert_eq!(*orderbook_balances.get("USD").unwrap_or(&0), 0); // orderbook is empty
+    }
+
+    #[test_log::test]
+    fn test_market_sell_order_against_smaller_buy_order() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit buy order first for 1 ETH
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a market sell order for 2 ETH
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: None, // Market order
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 2,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        assert_eq!(events.len(), 6);
+        let executed_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
+            .count();
+        let balance_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
+            .count();
+        assert_eq!(executed_count, 1);
+        // eth_user sent ETH
+        // usd_user received ETH
+        // orderbook sent USD
+        // eth_user received USD
+        assert_eq!(balance_count, 4);
+
+        // Assert orderbook is empty
+        assert_eq!(orderbook.orders.len(), 0);
+
+
+        // Check that balances haven't changed
+        let eth_user_balances = orderbook.balances.get(&eth_user).unwrap();
+        let usd_user_balances = orderbook.balances.get(&usd_user).unwrap();
+        let orderbook_balances = orderbook.balances.get(RESERVES).unwrap();
+
+        assert_eq!(*eth_user_balances.get("ETH").unwrap(), 9); // eth_user sold 1 ETH ...
+        assert_eq!(*eth_user_balances.get("USD").unwrap(), 2000); // .. for 2000 USD
+
+        assert_eq!(*usd_user_balances.get("ETH").unwrap(), 1); // usd_user bought 1 ETH ...
+        assert_eq!(*usd_user_balances.get("USD").unwrap(), 1000); // .. for 2000 USD
+
+        assert_eq!(*orderbook_balances.get("ETH").unwrap_or(&0), 0); // orderbook is empty
+        assert_eq!(*orderbook_balances.get("USD").unwrap_or(&0), 0); // orderbook is empty
+    }
+
+    // Tests with existing sell orders
+    #[test_log::test]
+    fn test_market_buy_order_against_larger_sell_order() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit sell order first for 2 ETH
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 2,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a market buy order for 1 ETH
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: None, // Market order
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Order should be executed immediately at the sell order's price
+        assert!(events.iter().any(|event| matches!(event, OrderbookEvent::OrderUpdate { 
+            order_id,
+            remaining_quantity,
+            pair: _,
+            executed_price: _
+        } if order_id == "sell1" && *remaining_quantity == 1)));
+
+        // Check balances were updated correctly
+        let eth_user_usd = orderbook.balances.get(&eth_user).unwrap().get("USD").unwrap();
+        let usd_user_eth = orderbook.balances.get(&usd_user).unwrap().get("ETH").unwrap();
+
+        assert_eq!(*eth_user_usd, 2000); // Seller got their asking price
+        assert_eq!(*usd_user_eth, 1); // Buyer got their ETH
+    }
+
+    #[test_log::test]
+    fn test_market_buy_order_against_exact_sell_order() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit sell order first for 1 ETH
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a market buy order for 1 ETH
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: None, // Market order
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Both orders should be fully executed
+        assert_eq!(
+            events.iter().filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. })).count(),
+            2
+        );
+
+        // Check balances
+        let eth_user_usd = orderbook.balances.get(&eth_user).unwrap().get("USD").unwrap();
+        let usd_user_eth = orderbook.balances.get(&usd_user).unwrap().get("ETH").unwrap();
+
+        assert_eq!(*eth_user_usd, 2000);
+        assert_eq!(*usd_user_eth, 1);
+    }
+
+    #[test_log::test]
+    fn test_market_buy_order_against_smaller_sell_order() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        // Create a limit sell order first for 1 ETH
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(),
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        // Create a market buy order for 2 ETH
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: None, // Market order
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 2,
+            timestamp: TimestampMs(1),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let events = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
+
+        assert_eq!(events.len(), 6);
+        let executed_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
+            .count();
+        let balance_count = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
+            .count();
+        assert_eq!(executed_count, 1);
+        // eth_user sent ETH
+        // usd_user received ETH
+        // orderbook sent USD
+        // eth_user received USD
+        assert_eq!(balance_count, 4);
+
+        // Assert orderbook is empty
+        assert_eq!(orderbook.orders.len(), 0);
+
+
+        // Check that balances haven't changed
+        let eth_user_balances = orderbook.balances.get(&eth_user).unwrap();
+        let usd_user_balances = orderbook.balances.get(&usd_user).unwrap();
+        let orderbook_balances = orderbook.balances.get(RESERVES).unwrap();
+
+        assert_eq!(*eth_user_balances.get("ETH").unwrap(), 9); // eth_user sold 1 ETH ...
+        assert_eq!(*eth_user_balances.get("USD").unwrap(), 2000); // .. for 2000 USD
+
+        assert_eq!(*usd_user_balances.get("ETH").unwrap(), 1); // usd_user bought 1 ETH ...
+        assert_eq!(*usd_user_balances.get("USD").unwrap(), 1000); // .. for 2000 USD
+
+        assert_eq!(*orderbook_balances.get("ETH").unwrap_or(&0), 0); // orderbook is empty
+        assert_eq!(*orderbook_balances.get("USD").unwrap_or(&0), 0); // orderbook is empty
+    }
+
+    #[test_log::test]
+    fn test_order_execution_blocked_after_recent_deposit() {
+        let (eth_user, _, mut orderbook) = setup();
+        
+        // Set a more recent deposit block height for eth_user
+        *orderbook.get_latest_deposit_mut(&eth_user, "ETH") = BlockHeight(4);
+
+        // Try to create a sell order when deposit was too recent
+        let sell_order = Order {
+            owner: eth_user.clone(),
+            order_id: "sell1".to_string(), 
+            order_type: OrderType::Sell,
+            price: Some(2000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        // Execute order with tx_ctx at block height 6 (< deposit block + 5)
+        let result = orderbook.execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX);
+
+        // Should fail because not enough blocks have passed since deposit
+        assert!(result.is_err());
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("too soon after the last deposit"));
+        assert!(
+            matches!(err, OrderbookError::DepositNotSettled { settled_at: 9, .. }),
+            "{err:?}"
+        );
+
+        // Check no balances were modified
+        let eth_user_eth = orderbook.balances.get(&eth_user).unwrap().get("ETH").unwrap();
+        assert_eq!(*eth_user_eth, 10); // Original balance unchanged
+
+        // Check no orders were created
+        assert_eq!(orderbook.orders.len(), 0);
+    }
+
+    /// Id of the `seq`-th order created with [`OrderbookAction::CreateOrder`] on ETH-USD
+    fn nth_order(seq: u64) -> String {
+        Orderbook::order_id(&("ETH".to_string(), "USD".to_string()), seq)
+    }
+
+    fn execute_action(
+        orderbook: &mut Orderbook,
+        user: &str,
+        action: OrderbookAction,
+    ) -> Vec<OrderbookEvent> {
+        try_execute_action(orderbook, user, action, vec![]).unwrap()
+    }
+
+    /// Executes `action` in a transaction where the orderbook blob follows `companions`
+    fn try_execute_action(
+        orderbook: &mut Orderbook,
+        user: &str,
+        action: OrderbookAction,
+        companions: Vec<sdk::Blob>,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        try_execute_action_in(orderbook, user, action, companions, &TX_CTX)
+    }
+
+    fn try_execute_action_in(
+        orderbook: &mut Orderbook,
+        user: &str,
+        action: OrderbookAction,
+        companions: Vec<sdk::Blob>,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let blobs: Vec<sdk::Blob> = companions
+            .into_iter()
+            .chain([action.as_blob("orderbook".into(), orderbook.last_nonce(user) + 1)])
+            .collect();
+        let calldata = sdk::Calldata {
+            tx_hash: sdk::TxHash(String::new()),
+            identity: sdk::Identity(user.to_string()),
+            tx_blob_count: blobs.len(),
+            index: sdk::BlobIndex(blobs.len() - 1),
+            blobs: blobs.into(),
+            tx_ctx: Some(tx_ctx.clone()),
+            private_input: vec![],
+        };
+        let (output, _, _) = sdk::ZkContract::execute(orderbook, &calldata)
+            .map_err(|output| OrderbookError::decode(output.as_bytes()).unwrap())?;
+        orderbook.check_invariants().unwrap();
+        Ok(borsh::from_slice(&output).unwrap())
+    }
+
+    /// Executes `calldata`, expecting it to fail, and decodes the error from the program outputs
+    fn execute_err(orderbook: &mut Orderbook, calldata: &sdk::Calldata) -> OrderbookError {
+        let output = sdk::ZkContract::execute(orderbook, calldata).unwrap_err();
+        OrderbookError::decode(output.as_bytes()).unwrap()
+    }
+
+    /// Sets the balance of `user`, as if the difference was deposited or withdrawn
+    fn set_balance(orderbook: &mut Orderbook, user: &str, token: &str, amount: u128) {
+        let previous = std::mem::replace(orderbook.get_balance_mut(user, token), amount);
+        let deposits = orderbook.deposits.entry(token.to_string()).or_default();
+        *deposits = *deposits + amount - previous;
+    }
+
+    /// Transfer of `amount` ETH from the orderbook, made on behalf of the orderbook blob
+    /// following it
+    fn eth_payout(recipient: &str, amount: u128) -> sdk::Blob {
+        blobs::payout_blob(
+            "ETH".into(),
+            &"orderbook".into(),
+            recipient.into(),
+            amount,
+            sdk::BlobIndex(1),
+        )
+    }
+
+    /// Transfer of `amount` ETH from `sender` to the orderbook, backing a deposit
+    fn eth_deposit(sender: &str, amount: u128) -> sdk::Blob {
+        blobs::deposit_blob("ETH".into(), sender.into(), &"orderbook".into(), amount)
+    }
+
+    fn create_order(
+        order_type: OrderType,
+        price: Option<u128>,
+        trigger_price: Option<u128>,
+    ) -> OrderbookAction {
+        OrderbookAction::CreateOrder {
+            order_type,
+            price,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            time_in_force: TimeInForce::GoodTilCancelled,
+            trigger_price,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
+        }
+    }
+
+    #[test_log::test]
+    fn test_stop_order_triggered_by_last_price() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(1000), None),
+        );
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(900), None),
+        );
+
+        // Stop-loss: sells at market once the price falls to 1000. Nothing is reserved yet.
+        let events = execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, None, Some(1000)),
+        );
+        assert!(matches!(
+            &events[..],
+            [OrderbookEvent::StopOrderPlaced { .. }]
+        ));
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 10);
+        assert!(orderbook.stop_order(&nth_order(2)).is_some());
+
+        // A trade at 1000 activates it, and it fills the next bid
+        let events = execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(1000), None),
+        );
+        assert!(events.iter().any(|e| matches!(
+            e,
+            OrderbookEvent::StopOrderTriggered { order_id, last_price: 1000, .. } if order_id == &nth_order(2)
+        )));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            OrderbookEvent::OrderExecuted { order_id, executed_price: Some(900), .. } if order_id == &nth_order(1)
+        )));
+        assert!(orderbook.stop_order(&nth_order(2)).is_none());
+        assert!(orderbook.orders.is_empty());
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 8);
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 1900);
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 2);
+    }
+
+    #[test_log::test]
+    fn test_stop_orders_cancelled() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(1000), None),
+        );
+
+        // Only the owner can cancel a pending stop order
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(2000), Some(2000)),
+        );
+        assert!(orderbook
+            .cancel_order(nth_order(1), eth_user.clone(), &TX_CTX)
+            .is_err());
+        let events = execute_action(
+            &mut orderbook,
+            &usd_user,
+            OrderbookAction::Cancel {
+                order_id: nth_order(1),
+            },
+        );
+        assert!(matches!(
+            &events[..],
+            [
+                OrderbookEvent::OrderCancelled { .. },
+                OrderbookEvent::BookHash { .. }
+            ]
+        ));
+        assert!(orderbook.stop_order(&nth_order(1)).is_none());
+
+        // A stop-limit order the user can no longer pay for is cancelled once triggered, without
+        // failing the transaction that triggered it
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(2500), Some(1000)),
+        );
+        let events = execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(1000), None),
+        );
+        assert!(events.iter().any(|e| matches!(
+            e,
+            OrderbookEvent::OrderCancelled { order_id, .. } if order_id == &nth_order(2)
+        )));
+        assert!(orderbook.stop_order(&nth_order(2)).is_none());
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 2000);
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 1);
+    }
+
+    #[test_log::test]
+    fn test_unfunded_market_stop_order() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let broke_user = "broke_user".to_string();
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(1000), None),
+        );
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(1000), None),
+        );
+
+        // Market stop orders reserve nothing: the buy is cancelled once triggered, and the
+        // trades crossing its trigger price still go through
+        execute_action(
+            &mut orderbook,
+            &broke_user,
+            create_order(OrderType::Buy, None, Some(1000)),
+        );
+        for _ in 0..2 {
+            execute_action(
+                &mut orderbook,
+                &usd_user,
+                create_order(OrderType::Buy, Some(1000), None),
+            );
+        }
+        assert!(!orderbook.is_open(&nth_order(2)));
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 2);
+        assert_eq!(orderbook.get_balance(&broke_user, "ETH"), 0);
+    }
+
+    #[test_log::test]
+    fn test_one_cancels_other() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let oco = |take_profit: u128, stop_loss: u128| OrderbookAction::OneCancelsOther {
+            first: Box::new(create_order(OrderType::Sell, Some(take_profit), None)),
+            second: Box::new(create_order(OrderType::Sell, None, Some(stop_loss))),
+        };
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(1000), None),
+        );
+
+        // The stop-loss is cancelled once the take-profit fills
+        let events = execute_action(&mut orderbook, &eth_user, oco(2000, 1000));
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, OrderbookEvent::OrdersLinked { .. })));
+        assert_eq!(orderbook.linked_order(&nth_order(1)), Some(&nth_order(2)));
+        let events = execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(2000), None),
+        );
+        assert!(events.iter().any(|e| matches!(
+            e,
+            OrderbookEvent::OrderCancelled { order_id, .. } if order_id == &nth_order(2)
+        )));
+        assert!(!orderbook.is_open(&nth_order(2)));
+        assert!(orderbook.linked_orders.is_empty());
+
+        // Modified orders stay linked, and cancelling one cancels the other
+        execute_action(&mut orderbook, &eth_user, oco(2500, 900));
+        orderbook
+            .modify_order(nth_order(4), 2600, 1, eth_user.clone(), &TX_CTX)
+            .unwrap();
+        assert_eq!(orderbook.linked_order(&nth_order(4)), Some(&nth_order(5)));
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::Cancel {
+                order_id: nth_order(4),
+            },
+        );
+        assert!(!orderbook.is_open(&nth_order(5)));
+        assert!(orderbook.linked_orders.is_empty());
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 9);
 
-impl From<sdk::StateCommitment> for Orderbook {
-    fn from(state: sdk::StateCommitment) -> Self {
-        borsh::from_slice(&state.0)
-            .map_err(|_| "Could not decode Orderbook state".to_string())
-            .unwrap()
+        // An order filled on creation cancels the other right away
+        let events = execute_action(&mut orderbook, &eth_user, oco(1000, 900));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, OrderbookEvent::OrdersLinked { .. })));
+        assert!(orderbook
+            .orders
+            .values()
+            .all(|order| order.owner != eth_user));
+        assert!(!orderbook.is_open(&nth_order(7)));
+
+        let err = try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::OneCancelsOther {
+                first: Box::new(create_order(OrderType::Sell, Some(2000), None)),
+                second: Box::new(OrderbookAction::Cancel {
+                    order_id: nth_order(0),
+                }),
+            },
+            vec![],
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("must both be created with CreateOrder"),
+            "{err}"
+        );
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::*;
-    use std::collections::BTreeMap;
 
-    static TX_CTX: sdk::TxContext = sdk::TxContext {
-            block_height: sdk::BlockHeight(6),
-            lane_id: LaneId(sdk::ValidatorPublicKey(vec![])),
-            timestamp: TimestampMs(0),
-            block_hash: sdk::ConsensusProposalHash(String::new()),
-            chain_id: 0,
+    #[test_log::test]
+    fn test_orders_by_owner() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let order_ids = |orderbook: &Orderbook, user: &str| -> Vec<String> {
+            orderbook
+                .order_ids_of(user)
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect()
         };
 
-    fn setup() -> (String, String, Orderbook) {
-        let mut orderbook = Orderbook::init(LaneId::default());
-        let eth_user = "eth_user".to_string();
-        let usd_user = "usd_user".to_string();
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(2000), None),
+        );
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, None, Some(900)),
+        );
+        assert_eq!(
+            order_ids(&orderbook, &eth_user),
+            vec![nth_order(0), nth_order(1)]
+        );
+        assert_eq!(
+            orderbook
+                .get_orders_by_user(&eth_user, &indexer::OrdersQuery::default())
+                .len(),
+            2
+        );
 
-        let mut eth_token = BTreeMap::new();
-        eth_token.insert("ETH".to_string(), 10);
-        orderbook.balances.insert(eth_user.clone(), eth_token);
+        // Filled orders leave the index, and so do takers that do not rest
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(2000), None),
+        );
+        assert_eq!(order_ids(&orderbook, &eth_user), vec![nth_order(1)]);
+        assert!(orderbook.order_ids_of(&usd_user).is_none());
+
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::Cancel {
+                order_id: nth_order(1),
+            },
+        );
+        assert!(orderbook.orders_by_owner.is_empty());
+    }
 
-        let mut usd_token = BTreeMap::new();
-        usd_token.insert("USD".to_string(), 3000);
+    #[test_log::test]
+    fn test_reserved_balances() {
+        let (eth_user, usd_user, mut orderbook) = setup();
 
-        orderbook.balances.insert(usd_user.clone(), usd_token);
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(1000), None),
+        );
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(900), None),
+        );
+        assert_eq!(
+            orderbook.reserved[&usd_user],
+            BTreeMap::from([("USD".to_string(), 1900)])
+        );
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 1100);
 
-        orderbook.latest_deposit.insert(
-            eth_user.clone(),
-            BTreeMap::from([("ETH".to_string(), BlockHeight(0))]));
+        // Fills and cancellations take from the reservation of the order owner
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(1000), None),
+        );
+        assert_eq!(orderbook.reserved[&usd_user]["USD"], 900);
+        assert!(!orderbook.reserved.contains_key(&eth_user));
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            OrderbookAction::Cancel {
+                order_id: nth_order(1),
+            },
+        );
+        assert!(orderbook.reserved.is_empty());
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 2000);
+    }
 
-        orderbook.latest_deposit.insert(
-            usd_user.clone(),
-            BTreeMap::from([("USD".to_string(), BlockHeight(0))]),
+    #[test_log::test]
+    fn test_batch_auction() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let config = MarketConfig {
+            auction_interval_ms: 1000,
+            ..Default::default()
+        };
+        orderbook
+            .configure_market(pair.clone(), config, "admin".to_string())
+            .unwrap();
+
+        // Crossing orders wait for the auction
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(1000), None),
+        );
+        let events = execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(1200), None),
+        );
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, OrderbookEvent::TradeExecuted { .. })));
+        assert_eq!(orderbook.orders.len(), 2);
+        let err = try_execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, None, None),
+            vec![],
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("only good-til-cancelled limit orders"),
+            "{err}"
         );
 
-        (eth_user, usd_user, orderbook)
+        let err = orderbook.run_auction(&pair, &tx_ctx_at(999)).unwrap_err();
+        assert!(err.to_string().contains("has not elapsed"), "{err}");
+        let events = orderbook.run_auction(&pair, &tx_ctx_at(1000)).unwrap();
+        assert!(matches!(
+            events[0],
+            OrderbookEvent::AuctionCleared {
+                price: 1100,
+                quantity: 1,
+                ..
+            }
+        ));
+        assert!(orderbook.orders.is_empty());
+        // Both sides trade at the clearing price, the buyer gets back what it reserved above it
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 1100);
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 1900);
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 1);
     }
 
     #[test_log::test]
-    fn test_limit_sell_order_create() {
-        let (eth_user, _, mut orderbook) = setup();
+    fn test_auction_time_not_in_partial_commit() {
+        let (_, _, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let config = MarketConfig {
+            auction_interval_ms: 1000,
+            ..Default::default()
+        };
+        orderbook
+            .configure_market(pair.clone(), config, "admin".to_string())
+            .unwrap();
+        let optimistic = orderbook.clone();
 
-        // Create a limit sell order
-        let order = Order {
-            owner: eth_user.clone(),
-            order_id: "order1".to_string(),
-            order_type: OrderType::Sell,
-            price: Some(2000),
+        // An auction without crossed orders only records its time
+        orderbook.run_auction(&pair, &tx_ctx_at(1000)).unwrap();
+        assert_eq!(orderbook.partial_commit().0, optimistic.partial_commit().0);
+    }
+
+    fn auction_order(order_type: OrderType, price: u128, quantity: u128) -> OrderbookAction {
+        OrderbookAction::CreateOrder {
+            order_type,
+            price: Some(price),
             pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(0),
+            quantity,
+            time_in_force: TimeInForce::GoodTilCancelled,
+            trigger_price: None,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
+        }
+    }
+
+    #[test_log::test]
+    fn test_batch_auction_bid_against_two_asks() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let config = MarketConfig {
+            auction_interval_ms: 1000,
+            ..Default::default()
         };
+        orderbook
+            .configure_market(pair.clone(), config, "admin".to_string())
+            .unwrap();
 
-        let events = orderbook.execute_order(order.clone(), &TX_CTX).unwrap();
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            auction_order(OrderType::Sell, 1000, 1),
+        );
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            auction_order(OrderType::Sell, 1100, 1),
+        );
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            auction_order(OrderType::Buy, 1200, 2),
+        );
+        let events = orderbook.run_auction(&pair, &tx_ctx_at(1000)).unwrap();
+        let OrderbookEvent::AuctionCleared {
+            price, quantity: 2, ..
+        } = events[0]
+        else {
+            panic!("Unexpected auction: {:?}", events[0]);
+        };
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, OrderbookEvent::TradeExecuted { .. }))
+                .count(),
+            2
+        );
+        assert!(orderbook.orders.is_empty());
+        // The bid gets back what it reserved above the clearing price once
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 3000 - 2 * price);
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 2);
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 2 * price);
+        assert_eq!(orderbook.get_balance(RESERVES, "USD"), 0);
+    }
 
-        // Check that the order was created
-        assert_eq!(events.len(), 3);
-        let created_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::OrderCreated { .. }))
-            .count();
-        let balance_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
-            .count();
-        assert_eq!(created_count, 1);
-        assert_eq!(balance_count, 2);
+    #[test_log::test]
+    fn test_batch_auction_keeps_other_reservations() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let config = MarketConfig {
+            auction_interval_ms: 1000,
+            ..Default::default()
+        };
+        orderbook
+            .configure_market(pair.clone(), config, "admin".to_string())
+            .unwrap();
 
-        // Check that the order is in the sell orders list
-        assert!(orderbook.orders.contains_key("order1"));
-        assert!(orderbook
-            .sell_orders
-            .get(&("ETH".to_string(), "USD".to_string()))
+        // A resting bid of the buyer that does not cross
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            auction_order(OrderType::Buy, 500, 1),
+        );
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            auction_order(OrderType::Sell, 1000, 1),
+        );
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            auction_order(OrderType::Sell, 1100, 1),
+        );
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            auction_order(OrderType::Buy, 1200, 2),
+        );
+        let events = orderbook.run_auction(&pair, &tx_ctx_at(1000)).unwrap();
+        let OrderbookEvent::AuctionCleared {
+            price, quantity: 2, ..
+        } = events[0]
+        else {
+            panic!("Unexpected auction: {:?}", events[0]);
+        };
+        assert_eq!(
+            orderbook.get_balance(&usd_user, "USD"),
+            3000 - 500 - 2 * price
+        );
+        assert_eq!(orderbook.get_balance(RESERVES, "USD"), 500);
+
+        let order_id = orderbook
+            .orders
+            .values()
+            .find(|order| order.price == Some(500))
             .unwrap()
-            .contains(&"order1".to_string()));
+            .order_id
+            .clone();
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            OrderbookAction::Cancel { order_id },
+        );
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 3000 - 2 * price);
+        assert_eq!(orderbook.get_balance(RESERVES, "USD"), 0);
     }
 
     #[test_log::test]
-    fn test_limit_buy_order_create() {
-        let (_, usd_user, mut orderbook) = setup();
-
-        // Create a limit sell order
-        let order = Order {
-            owner: usd_user.clone(),
-            order_id: "order1".to_string(),
-            order_type: OrderType::Buy,
-            price: Some(2000),
+    fn test_maker_taker_fees() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        set_balance(&mut orderbook, &eth_user, "ETH", 10_000);
+        set_balance(&mut orderbook, &usd_user, "USD", 10_000_000);
+        let order = |order_type: OrderType, quantity: u128| OrderbookAction::CreateOrder {
+            order_type,
+            price: Some(1000),
             pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(0),
+            quantity,
+            time_in_force: TimeInForce::GoodTilCancelled,
+            trigger_price: None,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
         };
 
-        let events = orderbook.execute_order(order.clone(), &TX_CTX).unwrap();
+        let schedule = FeeSchedule {
+            maker_bps: 10,
+            taker_bps: 20,
+            ..Default::default()
+        };
+        assert!(orderbook
+            .set_fee_schedule(schedule.clone(), eth_user.clone())
+            .is_err());
+        let invalid = FeeSchedule {
+            maker_bps: 10_001,
+            ..Default::default()
+        };
+        assert!(orderbook
+            .set_fee_schedule(invalid, "admin".to_string())
+            .is_err());
+        orderbook
+            .set_fee_schedule(schedule, "admin".to_string())
+            .unwrap();
 
-        // Check that the order was created
-        assert_eq!(events.len(), 3);
-        let created_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::OrderCreated { .. }))
-            .count();
-        let balance_count = events
+        // Each side pays its fee on what it receives
+        execute_action(&mut orderbook, &eth_user, order(OrderType::Sell, 5000));
+        let events = execute_action(&mut orderbook, &usd_user, order(OrderType::Buy, 5000));
+        let fees: Vec<(&str, &str, u128)> = events
             .iter()
-            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
-            .count();
-        assert_eq!(created_count, 1);
-        assert_eq!(balance_count, 2);
+            .filter_map(|e| match e {
+                OrderbookEvent::FeeCharged {
+                    user,
+                    token,
+                    amount,
+                    ..
+                } => Some((user.as_str(), token.as_str(), *amount)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            fees,
+            [
+                (eth_user.as_str(), "USD", 5000),
+                (usd_user.as_str(), "ETH", 10)
+            ]
+        );
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 4_995_000);
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 4990);
 
-        // Check that the order is in the sell orders list
-        assert!(orderbook.orders.contains_key("order1"));
+        execute_action(&mut orderbook, &usd_user, order(OrderType::Buy, 5000));
+        execute_action(&mut orderbook, &eth_user, order(OrderType::Sell, 5000));
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 4990 + 4995);
+        assert_eq!(
+            orderbook.get_balance(&eth_user, "USD"),
+            4_995_000 + 4_990_000
+        );
+        assert_eq!(orderbook.get_balance(FEE_POOL, "ETH"), 15);
+        assert_eq!(orderbook.get_balance(FEE_POOL, "USD"), 15_000);
+
+        // Only the admin collects them
         assert!(orderbook
-            .buy_orders
-            .get(&("ETH".to_string(), "USD".to_string()))
-            .unwrap()
-            .contains(&"order1".to_string()));
+            .withdraw_fees("USD".to_string(), 15_000, eth_user.clone())
+            .is_err());
+        assert!(orderbook
+            .withdraw_fees("USD".to_string(), 15_001, "admin".to_string())
+            .is_err());
+        orderbook
+            .withdraw_fees("USD".to_string(), 15_000, "admin".to_string())
+            .unwrap();
+        assert_eq!(orderbook.get_balance("admin", "USD"), 15_000);
+        assert_eq!(orderbook.get_balance(FEE_POOL, "USD"), 0);
     }
 
     #[test_log::test]
-    fn test_limit_order_match_same_quantity_same_price() {
+    fn test_referral_rewards() {
         let (eth_user, usd_user, mut orderbook) = setup();
-
-        // Create a limit sell order first
-        let sell_order = Order {
-            owner: eth_user.clone(),
-            order_id: "sell1".to_string(),
-            order_type: OrderType::Sell,
-            price: Some(2000),
+        set_balance(&mut orderbook, &eth_user, "ETH", 10_000);
+        set_balance(&mut orderbook, &usd_user, "USD", 10_000_000);
+        let referrer = "referrer@orderbook".to_string();
+        let order = |order_type: OrderType| OrderbookAction::CreateOrder {
+            order_type,
+            price: Some(1000),
             pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(0),
+            quantity: 5000,
+            time_in_force: TimeInForce::GoodTilCancelled,
+            trigger_price: None,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
+        };
+        let register = |referrer: &str| OrderbookAction::RegisterReferrer {
+            referrer: referrer.to_string(),
         };
-        orderbook.execute_order(sell_order, &TX_CTX).unwrap();
 
-        // Create a matching buy order
-        let buy_order = Order {
-            owner: usd_user.clone(),
-            order_id: "buy1".to_string(),
-            order_type: OrderType::Buy,
-            price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(1),
+        let invalid = FeeSchedule {
+            referral_bps: 10_001,
+            ..Default::default()
         };
+        let err = orderbook
+            .set_fee_schedule(invalid, "admin".to_string())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            OrderbookError::InvalidReferralShare {
+                referral_bps: 10_001
+            }
+        );
+        let schedule = FeeSchedule {
+            maker_bps: 10,
+            taker_bps: 20,
+            referral_bps: 5000,
+            ..Default::default()
+        };
+        orderbook
+            .set_fee_schedule(schedule, "admin".to_string())
+            .unwrap();
 
-        let events = orderbook.execute_order(buy_order, &TX_CTX).unwrap();
+        let err =
+            try_execute_action(&mut orderbook, &usd_user, register(&usd_user), vec![]).unwrap_err();
+        assert!(matches!(err, OrderbookError::InvalidReferrer { .. }));
+        let err =
+            try_execute_action(&mut orderbook, &usd_user, register(FEE_POOL), vec![]).unwrap_err();
+        assert!(matches!(err, OrderbookError::InvalidReferrer { .. }));
+        let events = execute_action(&mut orderbook, &usd_user, register(&referrer));
+        assert!(
+            matches!(&events[0], OrderbookEvent::ReferrerRegistered { referrer: r, .. } if r == &referrer)
+        );
+        let err =
+            try_execute_action(&mut orderbook, &usd_user, register(&eth_user), vec![]).unwrap_err();
+        assert_eq!(
+            err,
+            OrderbookError::ReferrerAlreadySet {
+                user: usd_user.clone(),
+                referrer: referrer.clone()
+            }
+        );
+        assert_eq!(orderbook.referrer_of(&usd_user), Some(&referrer));
 
-        // Check that the order was executed
-        assert_eq!(events.len(), 6);
-        let executed_count = events
+        // Half of the 10 ETH taker fee goes to the referrer, the maker fee is not shared
+        execute_action(&mut orderbook, &eth_user, order(OrderType::Sell));
+        let events = execute_action(&mut orderbook, &usd_user, order(OrderType::Buy));
+        let rewards: Vec<(&str, &str, &str, &str, u128)> = events
             .iter()
-            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
-            .count();
-        let balance_updated_count = events
+            .filter_map(|e| match e {
+                OrderbookEvent::ReferralRewarded {
+                    order_id,
+                    referrer,
+                    referee,
+                    token,
+                    amount,
+                    ..
+                } => Some((
+                    order_id.as_str(),
+                    referrer.as_str(),
+                    referee.as_str(),
+                    token.as_str(),
+                    *amount,
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            rewards,
+            [(
+                nth_order(1).as_str(),
+                referrer.as_str(),
+                usd_user.as_str(),
+                "ETH",
+                5
+            )]
+        );
+        assert_eq!(orderbook.get_balance(&referrer, "ETH"), 5);
+        assert_eq!(orderbook.get_balance(FEE_POOL, "ETH"), 5);
+        assert_eq!(orderbook.get_balance(FEE_POOL, "USD"), 5000);
+
+        // Takers without a referrer pay the whole fee to the pool
+        execute_action(&mut orderbook, &usd_user, order(OrderType::Buy));
+        let events = execute_action(&mut orderbook, &eth_user, order(OrderType::Sell));
+        assert!(!events
             .iter()
-            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
-            .count();
-        assert_eq!(executed_count, 2);
-        // usd_user received ETH
-        // usd_user sent USD
-        // orderbook sent ETH
-        // eth_user received USD
-        assert_eq!(balance_updated_count, 4);
+            .any(|e| matches!(e, OrderbookEvent::ReferralRewarded { .. })));
+        assert_eq!(orderbook.get_balance(FEE_POOL, "USD"), 5000 + 10_000);
+        assert_eq!(orderbook.get_balance(&referrer, "USD"), 0);
+    }
 
-        // Check balances were updated correctly
-        let eth_user_usd = orderbook
-            .balances
-            .get(&eth_user)
-            .unwrap()
-            .get("USD")
+    #[test_log::test]
+    fn test_fee_tiers() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        set_balance(&mut orderbook, &eth_user, "ETH", 10_000);
+        set_balance(&mut orderbook, &usd_user, "USD", 10_000_000);
+        let order = |order_type: OrderType| OrderbookAction::CreateOrder {
+            order_type,
+            price: Some(1000),
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1000,
+            time_in_force: TimeInForce::GoodTilCancelled,
+            trigger_price: None,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
+        };
+        let trade = |orderbook: &mut Orderbook, timestamp: u128| {
+            let tx_ctx = tx_ctx_at(timestamp);
+            try_execute_action_in(
+                orderbook,
+                &eth_user,
+                order(OrderType::Sell),
+                vec![],
+                &tx_ctx,
+            )
             .unwrap();
-        let usd_user_eth = orderbook
-            .balances
-            .get(&usd_user)
-            .unwrap()
-            .get("ETH")
+            let events =
+                try_execute_action_in(orderbook, &usd_user, order(OrderType::Buy), vec![], &tx_ctx)
+                    .unwrap();
+            let fees: Vec<u128> = events
+                .iter()
+                .filter_map(|e| match e {
+                    OrderbookEvent::FeeCharged { amount, .. } => Some(*amount),
+                    _ => None,
+                })
+                .collect();
+            fees
+        };
+        let tier = |min_volume: u128, maker_bps: u16, taker_bps: u16| market::FeeTier {
+            min_volume,
+            maker_bps,
+            taker_bps,
+        };
+
+        let unordered = FeeSchedule {
+            maker_bps: 10,
+            taker_bps: 20,
+            tiers: vec![tier(2000, 0, 5), tier(1000, 0, 10)],
+            ..Default::default()
+        };
+        let err = orderbook
+            .set_fee_schedule(unordered, "admin".to_string())
+            .unwrap_err();
+        assert_eq!(err, OrderbookError::FeeTiersUnordered { min_volume: 1000 });
+        let schedule = FeeSchedule {
+            maker_bps: 10,
+            taker_bps: 20,
+            tiers: vec![tier(1000, 0, 10), tier(2000, 0, 5)],
+            ..Default::default()
+        };
+        orderbook
+            .set_fee_schedule(schedule, "admin".to_string())
             .unwrap();
 
-        assert_eq!(*eth_user_usd, 2000); // Seller received USD
-        assert_eq!(*usd_user_eth, 1); // Buyer received ETH
+        // Both sides pay the base rates until they traded enough, on 1_000_000 USD and 1000 ETH
+        assert_eq!(trade(&mut orderbook, 1), [1000, 2]);
+        assert_eq!(orderbook.volumes.volume(&eth_user, &TimestampMs(1)), 1000);
+        assert_eq!(trade(&mut orderbook, 1), [1]);
+        let day = 24 * 60 * 60 * 1000;
+        // 5 bps of 1000 ETH rounds down to no fee at all
+        assert!(trade(&mut orderbook, 29 * day).is_empty());
+
+        // The volume of the first day no longer counts 30 days later
+        assert_eq!(
+            orderbook.volumes.volume(&usd_user, &TimestampMs(30 * day)),
+            1000
+        );
+        assert_eq!(trade(&mut orderbook, 30 * day), [1]);
+
+        let prune = OrderbookAction::PruneExpired {
+            pair: ("ETH".to_string(), "USD".to_string()),
+        };
+        try_execute_action_in(
+            &mut orderbook,
+            &eth_user,
+            prune,
+            vec![],
+            &tx_ctx_at(60 * day),
+        )
+        .unwrap();
+        assert!(orderbook.volumes.users.is_empty());
     }
 
     #[test_log::test]
-    fn test_limit_order_match_same_quantity_lower_price() {
+    fn test_balance_updates_in_deterministic_order() {
         let (eth_user, usd_user, mut orderbook) = setup();
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(1000), None),
+        );
+        let events = execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(1000), None),
+        );
 
-        // Create a limit sell order first
-        let sell_order = Order {
-            owner: eth_user.clone(),
-            order_id: "sell1".to_string(),
-            order_type: OrderType::Sell,
-            price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(0),
-        };
-        orderbook.execute_order(sell_order, &TX_CTX).unwrap();
+        // The zkVM and the optimistic execution emit the same events: balance updates come by
+        // token, then by user
+        let updates: Vec<(&str, &str)> = events
+            .iter()
+            .filter_map(|event| match event {
+                OrderbookEvent::BalanceUpdated { user, token, .. } => {
+                    Some((token.as_str(), user.as_str()))
+                }
+                _ => None,
+            })
+            .collect();
+        let mut sorted = updates.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert!(updates.len() > 2, "{updates:?}");
+        assert_eq!(updates, sorted);
+    }
 
-        // Create a matching buy order
-        let buy_order = Order {
-            owner: usd_user.clone(),
-            order_id: "buy1".to_string(),
-            order_type: OrderType::Buy,
-            price: Some(1900),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(1),
+    #[test_log::test]
+    fn test_self_trade_prevention() {
+        let (eth_user, _, mut orderbook) = setup();
+        set_balance(&mut orderbook, &eth_user, "USD", 3000);
+        let order = |order_type: OrderType, quantity: u128, policy: Option<SelfTradePrevention>| {
+            OrderbookAction::CreateOrder {
+                order_type,
+                price: Some(1000),
+                pair: ("ETH".to_string(), "USD".to_string()),
+                quantity,
+                time_in_force: TimeInForce::GoodTilCancelled,
+                trigger_price: None,
+                self_trade_prevention: policy,
+                expires_at: None,
+                display_quantity: None,
+            }
         };
-        let events = orderbook.execute_order(buy_order, &TX_CTX).unwrap();
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(1000), None),
+        );
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(1000), None),
+        );
+        execute_action(&mut orderbook, &eth_user, order(OrderType::Sell, 3, None));
 
-        // Check that the order was NOT executed
-        assert_eq!(events.len(), 3);
-        let created_count = events
+        // The incoming order is dropped
+        let events = execute_action(
+            &mut orderbook,
+            &eth_user,
+            order(OrderType::Buy, 1, Some(SelfTradePrevention::CancelNewest)),
+        );
+        assert!(events.iter().any(|e| matches!(e, OrderbookEvent::OrderCancelled { order_id, .. } if order_id == &nth_order(3))));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, OrderbookEvent::TradeExecuted { .. })));
+        assert_eq!(orderbook.orders.len(), 3);
+
+        // Both orders are reduced by the smaller quantity, which cancels it
+        let events = execute_action(
+            &mut orderbook,
+            &eth_user,
+            order(
+                OrderType::Buy,
+                1,
+                Some(SelfTradePrevention::DecrementAndCancel),
+            ),
+        );
+        assert!(events.iter().any(|e| matches!(e, OrderbookEvent::OrderCancelled { order_id, .. } if order_id == &nth_order(0))));
+        assert!(events.iter().any(|e| matches!(e, OrderbookEvent::OrderCancelled { order_id, .. } if order_id == &nth_order(4))));
+        let events = execute_action(
+            &mut orderbook,
+            &eth_user,
+            order(
+                OrderType::Buy,
+                2,
+                Some(SelfTradePrevention::DecrementAndCancel),
+            ),
+        );
+        assert!(events.iter().any(|e| matches!(e, OrderbookEvent::OrderCancelled { order_id, .. } if order_id == &nth_order(1))));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            OrderbookEvent::OrderUpdate { order_id, remaining_quantity: 2, executed_price: None, .. } if order_id == &nth_order(2)
+        )));
+        assert_eq!(orderbook.orders[&nth_order(2)].quantity, 2);
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 8);
+
+        // The resting order is cancelled, and the incoming one rests
+        let events = execute_action(
+            &mut orderbook,
+            &eth_user,
+            order(OrderType::Buy, 1, Some(SelfTradePrevention::CancelOldest)),
+        );
+        assert!(events.iter().any(|e| matches!(e, OrderbookEvent::OrderCancelled { order_id, .. } if order_id == &nth_order(2))));
+        assert!(orderbook.orders.contains_key(&nth_order(6)));
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 10);
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 2000);
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, OrderbookEvent::TradeExecuted { .. })));
+
+        // Fill-or-kill and stop orders cannot apply a policy
+        let mut fok = order(OrderType::Sell, 1, Some(SelfTradePrevention::CancelNewest));
+        if let OrderbookAction::CreateOrder { time_in_force, .. } = &mut fok {
+            *time_in_force = TimeInForce::FillOrKill;
+        }
+        let mut stop = order(OrderType::Sell, 1, Some(SelfTradePrevention::CancelNewest));
+        if let OrderbookAction::CreateOrder { trigger_price, .. } = &mut stop {
+            *trigger_price = Some(900);
+        }
+        for action in [fok, stop] {
+            let calldata = sdk::Calldata {
+                tx_hash: sdk::TxHash(String::new()),
+                identity: sdk::Identity(eth_user.clone()),
+                blobs:
+                    vec![action.as_blob("orderbook".into(), orderbook.last_nonce(&eth_user) + 1)]
+                        .into(),
+                tx_blob_count: 1,
+                index: sdk::BlobIndex(0),
+                tx_ctx: Some(TX_CTX.clone()),
+                private_input: vec![],
+            };
+            assert!(sdk::ZkContract::execute(&mut orderbook, &calldata).is_err());
+        }
+    }
+
+    #[test_log::test]
+    fn test_batch() {
+        let (eth_user, _, mut orderbook) = setup();
+
+        let events = execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::Batch(vec![
+                create_order(OrderType::Sell, Some(2000), None),
+                create_order(OrderType::Sell, Some(2100), None),
+            ]),
+        );
+        let created = events
             .iter()
             .filter(|e| matches!(e, OrderbookEvent::OrderCreated { .. }))
             .count();
-        let balance_count = events
+        let hashes = events
             .iter()
-            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
+            .filter(|e| matches!(e, OrderbookEvent::BookHash { .. }))
             .count();
-        assert_eq!(created_count, 1);
-        assert_eq!(balance_count, 2);
+        assert_eq!((created, hashes), (2, 1));
+        assert!(matches!(
+            events.last(),
+            Some(OrderbookEvent::BookHash { .. })
+        ));
+
+        // Stale quotes are replaced in a single transaction
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::Batch(vec![
+                OrderbookAction::Cancel {
+                    order_id: nth_order(0),
+                },
+                create_order(OrderType::Sell, Some(1900), None),
+            ]),
+        );
+        assert!(!orderbook.orders.contains_key(&nth_order(0)));
+        assert!(orderbook.orders.contains_key(&nth_order(2)));
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 8);
+
+        let nested = OrderbookAction::Batch(vec![OrderbookAction::Batch(vec![])]);
+        let calldata = sdk::Calldata {
+            tx_hash: sdk::TxHash(String::new()),
+            identity: sdk::Identity(eth_user.clone()),
+            blobs: vec![nested.as_blob("orderbook".into(), orderbook.last_nonce(&eth_user) + 1)]
+                .into(),
+            tx_blob_count: 1,
+            index: sdk::BlobIndex(0),
+            tx_ctx: Some(TX_CTX.clone()),
+            private_input: vec![],
+        };
+        let err = execute_err(&mut orderbook, &calldata);
+        assert!(err.to_string().contains("nested"), "{err}");
+    }
 
-        // Check balances were updated correctly
-        let eth_user_usd = orderbook
-            .balances
-            .get(&eth_user)
-            .unwrap()
-            .get("USD")
-            .unwrap_or(&0);
-        let usd_user_eth = orderbook
-            .balances
-            .get(&usd_user)
-            .unwrap()
-            .get("ETH")
-            .unwrap_or(&0);
+    #[test_log::test]
+    fn test_pair_governance() {
+        let (eth_user, _, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let admin = "admin".to_string();
+
+        let err = orderbook
+            .add_token("ETH".to_string(), eth_user.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("not the orderbook admin"), "{err}");
+        let err = orderbook
+            .create_pair(pair.clone(), admin.clone())
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("Token ETH is not accepted"),
+            "{err}"
+        );
+        orderbook
+            .add_token("ETH".to_string(), admin.clone())
+            .unwrap();
+        orderbook
+            .add_token("USD".to_string(), admin.clone())
+            .unwrap();
+        assert!(orderbook.is_blob_whitelisted(&"ETH".into()));
 
-        assert_eq!(*eth_user_usd, 0); // Seller did not received USD
-        assert_eq!(*usd_user_eth, 0); // Buyer did not received ETH
+        // Once a pair is listed, the others can no longer be traded
+        assert!(orderbook.is_pair_listed(&("BTC".to_string(), "USD".to_string())));
+        orderbook.create_pair(pair.clone(), admin.clone()).unwrap();
+        assert!(!orderbook.is_pair_listed(&("BTC".to_string(), "USD".to_string())));
 
-        // Check user correctly desposited the amount
-        let eth_user_eth = orderbook
-            .balances
-            .get(&eth_user)
-            .unwrap()
-            .get("ETH")
-            .unwrap();
-        let usd_user_usd = orderbook
-            .balances
-            .get(&usd_user)
-            .unwrap()
-            .get("USD")
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(2000), None),
+        );
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, None, Some(1000)),
+        );
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 9);
+
+        // Delisting refunds the resting orders and drops the stop orders
+        let events = orderbook
+            .delist_pair(pair.clone(), admin.clone(), &TX_CTX)
             .unwrap();
+        let cancelled = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderCancelled { .. }))
+            .count();
+        assert_eq!(cancelled, 2);
+        assert!(matches!(
+            events.last(),
+            Some(OrderbookEvent::PairDelisted { .. })
+        ));
+        assert!(orderbook.orders.is_empty());
+        assert!(orderbook.stop_order(&nth_order(1)).is_none());
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 10);
+
+        let calldata = sdk::Calldata {
+            tx_hash: sdk::TxHash(String::new()),
+            identity: sdk::Identity(eth_user.clone()),
+            blobs: vec![create_order(OrderType::Sell, Some(2000), None)
+                .as_blob("orderbook".into(), orderbook.last_nonce(&eth_user) + 1)]
+            .into(),
+            tx_blob_count: 1,
+            index: sdk::BlobIndex(0),
+            tx_ctx: Some(TX_CTX.clone()),
+            private_input: vec![],
+        };
+        let err = execute_err(&mut orderbook, &calldata);
+        assert!(err.to_string().contains("not listed"), "{err}");
 
-        assert_eq!(*eth_user_eth, 10 - 1); // Seller did not received USD
-        assert_eq!(*usd_user_usd, 3000 - 1900); // Buyer did not received ETH
+        orderbook
+            .remove_token("ETH".to_string(), admin.clone(), &TX_CTX)
+            .unwrap();
+        assert!(!orderbook.accepted_tokens.contains(&"ETH".into()));
     }
 
     #[test_log::test]
-    fn test_limit_order_match_same_quantity_lower_price_bis() {
+    fn test_remove_token() {
         let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let admin = "admin".to_string();
+        orderbook
+            .add_token("ETH".to_string(), admin.clone())
+            .unwrap();
+        orderbook
+            .add_token("USD".to_string(), admin.clone())
+            .unwrap();
+        orderbook.create_pair(pair.clone(), admin.clone()).unwrap();
 
-        // Create a limit sell order first
-        let sell_order = Order {
-            owner: usd_user.clone(),
-            order_id: "sell1".to_string(),
-            order_type: OrderType::Buy,
-            price: Some(1900),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(0),
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(2000), None),
+        );
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, None, Some(1000)),
+        );
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(1000), None),
+        );
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 2000);
+
+        // The orders of the token's pairs are cancelled and their reservations refunded
+        let events = execute_action(
+            &mut orderbook,
+            &admin,
+            OrderbookAction::RemoveToken {
+                token: "ETH".to_string(),
+            },
+        );
+        let cancelled = events
+            .iter()
+            .filter(|e| matches!(e, OrderbookEvent::OrderCancelled { .. }))
+            .count();
+        assert_eq!(cancelled, 3);
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, OrderbookEvent::PairDelisted { pair: p } if p == &pair)));
+        assert!(matches!(
+            events
+                .iter()
+                .rev()
+                .find(|e| !matches!(e, OrderbookEvent::BookHash { .. })),
+            Some(OrderbookEvent::TokenRemoved { .. })
+        ));
+        assert!(orderbook.orders.is_empty() && orderbook.stop_orders.values().all(Vec::is_empty));
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 10);
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 3000);
+        assert!(!orderbook.is_pair_listed(&pair));
+
+        // It can no longer be deposited, but it can still be withdrawn
+        let deposit = OrderbookAction::Deposit {
+            token: "ETH".to_string(),
+            amount: 1,
+        };
+        let err = try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            deposit.clone(),
+            vec![eth_deposit(&eth_user, 1)],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            OrderbookError::TokenWithdrawOnly {
+                token: "ETH".to_string()
+            }
+        );
+        let withdraw = OrderbookAction::Withdraw {
+            token: "ETH".to_string(),
+            amount: 4,
+            recipient: None,
         };
-        orderbook.execute_order(sell_order, &TX_CTX).unwrap();
+        try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            withdraw,
+            vec![eth_payout(&eth_user, 4)],
+        )
+        .unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 6);
+
+        // Accepting it again reopens its deposits
+        orderbook
+            .add_token("ETH".to_string(), admin.clone())
+            .unwrap();
+        try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            deposit,
+            vec![eth_deposit(&eth_user, 1)],
+        )
+        .unwrap();
+    }
 
-        // Create a matching buy order
-        let buy_order = Order {
-            owner: eth_user.clone(),
-            order_id: "buy1".to_string(),
+    #[test_log::test]
+    fn test_lane_rotation() {
+        let (eth_user, _, mut orderbook) = setup();
+        let admin = "admin".to_string();
+        let new_lane = LaneId(sdk::ValidatorPublicKey(vec![1]));
+        let on_new_lane = sdk::TxContext {
+            lane_id: new_lane.clone(),
+            ..TX_CTX.clone()
+        };
+        orderbook.accepted_tokens.insert("ETH".into());
+        let deposit = OrderbookAction::Deposit {
+            token: "ETH".to_string(),
+            amount: 1,
+        };
+
+        let err = try_execute_action_in(
+            &mut orderbook,
+            &eth_user,
+            deposit.clone(),
+            vec![eth_deposit(&eth_user, 1)],
+            &on_new_lane,
+        )
+        .unwrap_err();
+        assert_eq!(err, OrderbookError::InvalidLaneId);
+        let add = OrderbookAction::AddLane {
+            lane_id: new_lane.clone(),
+        };
+        let err = try_execute_action(&mut orderbook, &eth_user, add.clone(), vec![]).unwrap_err();
+        assert!(matches!(err, OrderbookError::NotAdmin { .. }));
+
+        // Both lanes are accepted while the validators are rotated
+        execute_action(&mut orderbook, &admin, add);
+        try_execute_action_in(
+            &mut orderbook,
+            &eth_user,
+            deposit.clone(),
+            vec![eth_deposit(&eth_user, 1)],
+            &on_new_lane,
+        )
+        .unwrap();
+        try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            deposit.clone(),
+            vec![eth_deposit(&eth_user, 1)],
+        )
+        .unwrap();
+
+        let remove = |lane_id: &LaneId| OrderbookAction::RemoveLane {
+            lane_id: lane_id.clone(),
+        };
+        try_execute_action_in(
+            &mut orderbook,
+            &admin,
+            remove(&LaneId::default()),
+            vec![],
+            &on_new_lane,
+        )
+        .unwrap();
+        let err = try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            deposit,
+            vec![eth_deposit(&eth_user, 1)],
+        )
+        .unwrap_err();
+        assert_eq!(err, OrderbookError::InvalidLaneId);
+        let err = try_execute_action_in(
+            &mut orderbook,
+            &admin,
+            remove(&new_lane),
+            vec![],
+            &on_new_lane,
+        )
+        .unwrap_err();
+        assert!(matches!(err, OrderbookError::LastLane { .. }));
+        assert_eq!(orderbook.lane_ids(), &BTreeSet::from([new_lane]));
+    }
+
+    #[test_log::test]
+    fn test_inverted_pairs() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let inverse = ("USD".to_string(), "ETH".to_string());
+        let admin = "admin".to_string();
+        let order_on = |pair: &TokenPair| OrderbookAction::CreateOrder {
             order_type: OrderType::Sell,
-            price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(1),
+            price: Some(1),
+            pair: pair.clone(),
+            quantity: 2000,
+            time_in_force: TimeInForce::GoodTilCancelled,
+            trigger_price: None,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
         };
-        let events = orderbook.execute_order(buy_order, &TX_CTX).unwrap();
 
-        // Check that the order was NOT executed
-        assert_eq!(events.len(), 3);
-        let created_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::OrderCreated { .. }))
-            .count();
-        let balance_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
-            .count();
-        assert_eq!(created_count, 1);
-        assert_eq!(balance_count, 2);
+        // The first order sets the base and quote tokens of the pair
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(2000), None),
+        );
+        let err =
+            try_execute_action(&mut orderbook, &usd_user, order_on(&inverse), vec![]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Pair USD-ETH is traded as ETH-USD"),
+            "{err}"
+        );
+        let itself = ("USD".to_string(), "USD".to_string());
+        let err =
+            try_execute_action(&mut orderbook, &usd_user, order_on(&itself), vec![]).unwrap_err();
+        assert!(
+            err.to_string().contains("trades a token against itself"),
+            "{err}"
+        );
 
-        // Check balances were updated correctly
-        let eth_user_usd = orderbook
-            .balances
-            .get(&eth_user)
-            .unwrap()
-            .get("USD")
-            .unwrap_or(&0);
-        let usd_user_eth = orderbook
-            .balances
-            .get(&usd_user)
-            .unwrap()
-            .get("ETH")
-            .unwrap_or(&0);
+        let err = orderbook
+            .configure_market(inverse.clone(), MarketConfig::default(), admin.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("is traded as ETH-USD"), "{err}");
+        orderbook
+            .add_token("ETH".to_string(), admin.clone())
+            .unwrap();
+        orderbook
+            .add_token("USD".to_string(), admin.clone())
+            .unwrap();
+        let err = orderbook.create_pair(inverse, admin.clone()).unwrap_err();
+        assert!(err.to_string().contains("is traded as ETH-USD"), "{err}");
+        orderbook
+            .create_pair(("ETH".to_string(), "USD".to_string()), admin)
+            .unwrap();
+    }
 
-        assert_eq!(*eth_user_usd, 0); // Seller did not received USD
-        assert_eq!(*usd_user_eth, 0); // Buyer did not received ETH
+    #[test_log::test]
+    fn test_trading_halt() {
+        let (eth_user, _, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let admin = "admin".to_string();
+        let create_order_err = |orderbook: &mut Orderbook| {
+            let calldata = sdk::Calldata {
+                tx_hash: sdk::TxHash(String::new()),
+                identity: sdk::Identity(eth_user.clone()),
+                blobs: vec![create_order(OrderType::Sell, Some(2000), None)
+                    .as_blob("orderbook".into(), orderbook.last_nonce(&eth_user) + 1)]
+                .into(),
+                tx_blob_count: 1,
+                index: sdk::BlobIndex(0),
+                tx_ctx: Some(TX_CTX.clone()),
+                private_input: vec![],
+            };
+            execute_err(orderbook, &calldata)
+        };
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(2000), None),
+        );
 
-        // Check user correctly desposited the amount
-        let eth_user_eth = orderbook
-            .balances
-            .get(&eth_user)
-            .unwrap()
-            .get("ETH")
+        let err = orderbook
+            .set_halted(Some(pair.clone()), true, eth_user.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("not the orderbook admin"), "{err}");
+        let events = orderbook
+            .set_halted(Some(pair.clone()), true, admin.clone())
             .unwrap();
-        let usd_user_usd = orderbook
-            .balances
-            .get(&usd_user)
-            .unwrap()
-            .get("USD")
+        assert!(matches!(
+            &events[..],
+            [OrderbookEvent::MarketHalted { pair: Some(_) }]
+        ));
+        let err = orderbook
+            .set_halted(Some(pair.clone()), true, admin.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("already halted"), "{err}");
+        assert!(create_order_err(&mut orderbook)
+            .to_string()
+            .contains("halted on pair ETH-USD"));
+        let err = orderbook
+            .modify_order(nth_order(0), 2100, 1, eth_user.clone(), &TX_CTX)
+            .unwrap_err();
+        assert!(err.to_string().contains("halted"), "{err}");
+
+        // The whole orderbook stays paused once the pair resumes
+        orderbook.set_halted(None, true, admin.clone()).unwrap();
+        orderbook
+            .set_halted(Some(pair.clone()), false, admin.clone())
             .unwrap();
+        assert!(create_order_err(&mut orderbook)
+            .to_string()
+            .contains("halted on the orderbook"));
 
-        assert_eq!(*eth_user_eth, 10 - 1); // Seller did not received USD
-        assert_eq!(*usd_user_usd, 3000 - 1900); // Buyer did not received ETH
+        // Orders can still be cancelled and funds withdrawn
+        orderbook
+            .cancel_order(nth_order(0), eth_user.clone(), &TX_CTX)
+            .unwrap();
+        orderbook
+            .withdraw("ETH".to_string(), 10, eth_user.clone())
+            .unwrap();
+
+        let events = orderbook.set_halted(None, false, admin.clone()).unwrap();
+        assert!(matches!(
+            &events[..],
+            [OrderbookEvent::MarketResumed { pair: None }]
+        ));
+        assert!(!orderbook.paused);
     }
 
     #[test_log::test]
-    fn test_limit_order_match_same_quantity_higher_price() {
+    fn test_nonces() {
         let (eth_user, usd_user, mut orderbook) = setup();
-
-        // Create a limit sell order first
-        let sell_order = Order {
-            owner: eth_user.clone(),
-            order_id: "sell1".to_string(),
-            order_type: OrderType::Sell,
-            price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(0),
+        let calldata = |user: &str, nonce: u64| sdk::Calldata {
+            tx_hash: sdk::TxHash(String::new()),
+            identity: sdk::Identity(user.to_string()),
+            blobs: vec![
+                create_order(OrderType::Sell, Some(2000), None).as_blob("orderbook".into(), nonce)
+            ]
+            .into(),
+            tx_blob_count: 1,
+            index: sdk::BlobIndex(0),
+            tx_ctx: Some(TX_CTX.clone()),
+            private_input: vec![],
         };
-        orderbook.execute_order(sell_order, &TX_CTX).unwrap();
+        let last_nonce = orderbook.last_nonce(&eth_user);
 
-        // Create a matching buy order
-        let buy_order = Order {
-            owner: usd_user.clone(),
-            order_id: "buy1".to_string(),
-            order_type: OrderType::Buy,
-            price: Some(2100),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(1),
-        };
+        // Nonces may skip values, e.g. when timestamps are used
+        sdk::ZkContract::execute(&mut orderbook, &calldata(&eth_user, last_nonce + 10)).unwrap();
+        assert_eq!(orderbook.last_nonce(&eth_user), last_nonce + 10);
 
-        let events = orderbook.execute_order(buy_order, &TX_CTX).unwrap();
+        // A replayed or late blob is rejected
+        let err = execute_err(&mut orderbook, &calldata(&eth_user, last_nonce + 10));
+        assert!(
+            err.to_string().contains("is not above its last nonce"),
+            "{err}"
+        );
+        let err = execute_err(&mut orderbook, &calldata(&eth_user, last_nonce + 5));
+        assert!(
+            err.to_string().contains("is not above its last nonce"),
+            "{err}"
+        );
+        assert_eq!(orderbook.orders.len(), 1);
 
-        // Check that the order was executed
-        assert_eq!(events.len(), 6);
-        let executed_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
-            .count();
-        let balance_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
-            .count();
-        assert_eq!(executed_count, 2);
-        // usd_user received ETH
-        // usd_user sent USD
-        // orderbook sent ETH
-        // eth_user received USD
-        assert_eq!(balance_count, 4);
+        // Each user has its own sequence, which outlives its account
+        let usd_nonce = orderbook.last_nonce(&usd_user);
+        orderbook.accepted_tokens.insert("USD".into());
+        let payout = blobs::payout_blob(
+            "USD".into(),
+            &"orderbook".into(),
+            usd_user.as_str().into(),
+            3000,
+            sdk::BlobIndex(1),
+        );
+        try_execute_action(
+            &mut orderbook,
+            &usd_user,
+            OrderbookAction::CloseAccount,
+            vec![payout],
+        )
+        .unwrap();
+        assert_eq!(orderbook.last_nonce(&usd_user), usd_nonce + 1);
+    }
 
-        // Check balances were updated correctly
-        let eth_user_balances = orderbook.balances.get(&eth_user).unwrap();
-        let usd_user_balances = orderbook.balances.get(&usd_user).unwrap();
+    #[test_log::test]
+    fn test_order_expiry() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let order = |owner: &str, order_id: &str, order_type: OrderType, expires_at: u128| Order {
+            owner: owner.to_string(),
+            order_id: order_id.to_string(),
+            order_type,
+            price: Some(1000),
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: Some(TimestampMs(expires_at)),
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let err = orderbook
+            .execute_order(
+                order(&eth_user, "ask1", OrderType::Sell, 100),
+                TimeInForce::ImmediateOrCancel,
+                &TX_CTX,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("good-til-cancelled"), "{err}");
+        let err = orderbook
+            .execute_order(
+                order(&eth_user, "ask1", OrderType::Sell, 100),
+                TimeInForce::GoodTilCancelled,
+                &tx_ctx_at(100),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("expired"), "{err}");
+        orderbook
+            .execute_order(
+                order(&eth_user, "ask1", OrderType::Sell, 100),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
 
-        assert_eq!(*eth_user_balances.get("USD").unwrap(), 2000);
-        assert_eq!(*eth_user_balances.get("ETH").unwrap(), 9);
+        // The expired order is cancelled instead of being matched
+        let events = orderbook
+            .execute_order(
+                order(&usd_user, "bid1", OrderType::Buy, 200),
+                TimeInForce::GoodTilCancelled,
+                &tx_ctx_at(100),
+            )
+            .unwrap();
+        assert!(
+            matches!(&events[0], OrderbookEvent::OrderCancelled { order_id, .. } if order_id == "ask1")
+        );
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, OrderbookEvent::TradeExecuted { .. })));
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 10);
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 2000);
 
-        assert_eq!(*usd_user_balances.get("USD").unwrap(), 1000);
-        assert_eq!(*usd_user_balances.get("ETH").unwrap(), 1);
+        assert!(orderbook
+            .prune_expired(&pair, &tx_ctx_at(199))
+            .unwrap()
+            .is_empty());
+        let events = orderbook.prune_expired(&pair, &tx_ctx_at(200)).unwrap();
+        assert!(
+            matches!(&events[0], OrderbookEvent::OrderCancelled { order_id, .. } if order_id == "bid1")
+        );
+        assert!(orderbook.orders.is_empty());
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 3000);
     }
 
     #[test_log::test]
-    fn test_limit_order_match_less_sell_quantity_same_price() {
+    fn test_order_status() {
         let (eth_user, usd_user, mut orderbook) = setup();
+        let order =
+            |order_type: OrderType, price: u128, quantity: u128, expires_at: Option<u128>| {
+                OrderbookAction::CreateOrder {
+                    order_type,
+                    price: Some(price),
+                    pair: ("ETH".to_string(), "USD".to_string()),
+                    quantity,
+                    time_in_force: TimeInForce::GoodTilCancelled,
+                    trigger_price: None,
+                    self_trade_prevention: None,
+                    expires_at: expires_at.map(TimestampMs),
+                    display_quantity: None,
+                }
+            };
+        let status = |orderbook: &Orderbook, seq: u64| {
+            let order = orderbook.find_order(&nth_order(seq)).unwrap();
+            (order.status, order.quantity, order.filled_quantity)
+        };
 
-        // Create a limit sell order for 1 ETH
-        let sell_order = Order {
-            owner: eth_user.clone(),
-            order_id: "sell1".to_string(),
-            order_type: OrderType::Sell,
-            price: Some(1000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(0),
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            order(OrderType::Buy, 1000, 2, None),
+        );
+        assert_eq!(status(&orderbook, 0), (OrderStatus::Open, 2, 0));
+
+        // The taker is filled and archived, the maker rests partially filled
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            order(OrderType::Sell, 1000, 1, None),
+        );
+        assert_eq!(status(&orderbook, 0), (OrderStatus::PartiallyFilled, 1, 1));
+        assert_eq!(status(&orderbook, 1), (OrderStatus::Filled, 0, 1));
+        assert!(!orderbook.orders.contains_key(&nth_order(1)));
+
+        // Cancelled orders keep what they filled
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            OrderbookAction::Cancel {
+                order_id: nth_order(0),
+            },
+        );
+        assert_eq!(status(&orderbook, 0), (OrderStatus::Cancelled, 1, 1));
+
+        // Orders cancelled once expired are expired
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            order(OrderType::Sell, 1100, 1, Some(100)),
+        );
+        let cancel = OrderbookAction::Cancel {
+            order_id: nth_order(2),
         };
-        orderbook.execute_order(sell_order, &TX_CTX).unwrap();
+        try_execute_action_in(&mut orderbook, &eth_user, cancel, vec![], &tx_ctx_at(100)).unwrap();
+        assert_eq!(status(&orderbook, 2), (OrderStatus::Expired, 1, 0));
+
+        let closed: Vec<_> = orderbook
+            .closed_orders_of(&eth_user)
+            .map(|o| o.order_id.clone())
+            .collect();
+        assert_eq!(closed, vec![nth_order(1), nth_order(2)]);
+
+        // Only the most recent closed orders are kept. One block each stays under the action limit.
+        for seq in 3..3 + CLOSED_ORDERS_KEPT as u64 {
+            let tx_ctx = sdk::TxContext {
+                block_height: BlockHeight(TX_CTX.block_height.0 + seq),
+                ..TX_CTX.clone()
+            };
+            let create = order(OrderType::Sell, 1100, 1, None);
+            try_execute_action_in(&mut orderbook, &eth_user, create, vec![], &tx_ctx).unwrap();
+            let cancel = OrderbookAction::Cancel {
+                order_id: nth_order(seq),
+            };
+            try_execute_action_in(&mut orderbook, &eth_user, cancel, vec![], &tx_ctx).unwrap();
+        }
+        assert_eq!(
+            orderbook.closed_orders_of(&eth_user).count(),
+            CLOSED_ORDERS_KEPT
+        );
+        assert!(orderbook.find_order(&nth_order(2)).is_none());
+        assert_eq!(status(&orderbook, 3), (OrderStatus::Cancelled, 1, 0));
+    }
 
-        // Create a buy order for 2 ETH
-        let buy_order = Order {
-            owner: usd_user.clone(),
-            order_id: "buy1".to_string(),
-            order_type: OrderType::Buy,
+    #[test_log::test]
+    fn test_cancel_partially_filled_order() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let order = |order_type: OrderType, quantity: u128| OrderbookAction::CreateOrder {
+            order_type,
             price: Some(1000),
             pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 2,
-            timestamp: TimestampMs(1),
+            quantity,
+            time_in_force: TimeInForce::GoodTilCancelled,
+            trigger_price: None,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
+        };
+        let quantities = |orderbook: &Orderbook, seq: u64| {
+            let order = orderbook.find_order(&nth_order(seq)).unwrap();
+            (
+                order.original_quantity(),
+                order.filled_quantity,
+                order.reserved_amount,
+            )
         };
 
-        let events = orderbook.execute_order(buy_order, &TX_CTX).unwrap();
+        // A buy order only gets back the quote tokens of what it did not fill
+        execute_action(&mut orderbook, &usd_user, order(OrderType::Buy, 3));
+        assert_eq!(quantities(&orderbook, 0), (3, 0, 3000));
+        execute_action(&mut orderbook, &eth_user, order(OrderType::Sell, 1));
+        assert_eq!(quantities(&orderbook, 0), (3, 1, 2000));
+        assert_eq!(orderbook.reserved[&usd_user]["USD"], 2000);
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            OrderbookAction::Cancel {
+                order_id: nth_order(0),
+            },
+        );
+        assert_eq!(quantities(&orderbook, 0), (3, 1, 0));
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 2000);
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 1);
+
+        // A sell order only gets back the base tokens it did not sell
+        execute_action(&mut orderbook, &eth_user, order(OrderType::Sell, 4));
+        execute_action(&mut orderbook, &usd_user, order(OrderType::Buy, 1));
+        assert_eq!(quantities(&orderbook, 2), (4, 1, 3));
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::Cancel {
+                order_id: nth_order(2),
+            },
+        );
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 8);
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 2000);
 
-        // Check that the order was NOT executed
-        assert_eq!(events.len(), 7);
-        let executed_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
-            .count();
-        let created_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::OrderCreated { .. }))
-            .count();
-        let balance_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
-            .count();
-        assert_eq!(executed_count, 1);
-        assert_eq!(created_count, 1);
-        // eth_user received USD
-        // usd_user sent USD
-        // usd_user received ETH
-        // orderbook received USD
-        // orderbook sent ETH
-        assert_eq!(balance_count, 5);
+        assert!(!orderbook.reserved.contains_key(&eth_user));
+        assert_eq!(orderbook.get_balance(RESERVES, "ETH"), 0);
+        assert_eq!(orderbook.get_balance(RESERVES, "USD"), 0);
+    }
 
-        assert_eq!(orderbook.orders.len(), 1);
-        let only_order = orderbook.orders.values().next().unwrap();
-        assert!(matches!(only_order.order_type, OrderType::Buy));
+    #[test_log::test]
+    fn test_price_time_priority() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let order = |owner: &str, order_id: &str, order_type: OrderType, timestamp: u128| Order {
+            owner: owner.to_string(),
+            order_id: order_id.to_string(),
+            order_type,
+            price: Some(1000),
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity: 1,
+            timestamp: TimestampMs(timestamp),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
 
-        // Check balances were updated correctly
-        let eth_user_balances = orderbook.balances.get(&eth_user).unwrap();
-        let usd_user_balances = orderbook.balances.get(&usd_user).unwrap();
-        let orderbook_balances = orderbook
-            .balances
-            .get("orderbook")
+        // A stop order triggered late still has the time priority of its creation
+        orderbook
+            .execute_order(
+                order(&eth_user, "ask1", OrderType::Sell, 5),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        orderbook
+            .execute_order(
+                order(&eth_user, "ask2", OrderType::Sell, 3),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        orderbook
+            .execute_order(
+                order(&eth_user, "ask3", OrderType::Sell, 5),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
             .unwrap();
 
-        assert_eq!(*eth_user_balances.get("USD").unwrap(), 1000);
-        assert_eq!(*eth_user_balances.get("ETH").unwrap(), 9);
-
-        assert_eq!(*usd_user_balances.get("USD").unwrap(), 1000);
-        assert_eq!(*usd_user_balances.get("ETH").unwrap(), 1);
-
-        assert_eq!(*orderbook_balances.get("USD").unwrap(), 1000);
-        assert_eq!(*orderbook_balances.get("ETH").unwrap(), 0);
+        let makers: Vec<String> = (1..=3)
+            .flat_map(|i| {
+                let bid = order(&usd_user, &format!("bid{i}"), OrderType::Buy, 10);
+                orderbook
+                    .execute_order(bid, TimeInForce::GoodTilCancelled, &TX_CTX)
+                    .unwrap()
+            })
+            .filter_map(|event| match event {
+                OrderbookEvent::TradeExecuted { maker_order_id, .. } => Some(maker_order_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(makers, ["ask2", "ask1", "ask3"]);
     }
 
     #[test_log::test]
-    fn test_partial_order_execution_same_price() {
+    fn test_iceberg_order() {
         let (eth_user, usd_user, mut orderbook) = setup();
-
-        // Create a limit sell order for 2 ETH
-        let sell_order = Order {
-            owner: eth_user.clone(),
-            order_id: "sell1".to_string(),
-            order_type: OrderType::Sell,
-            price: Some(2000),
+        let order = |owner: &str,
+                     order_id: &str,
+                     order_type: OrderType,
+                     quantity: u128,
+                     display_quantity: Option<u128>| Order {
+            owner: owner.to_string(),
+            order_id: order_id.to_string(),
+            order_type,
+            price: Some(1000),
+            trigger_price: None,
             pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 2,
+            quantity,
             timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
         };
-        orderbook.execute_order(sell_order, &TX_CTX).unwrap();
 
-        // Create a buy order for 1 ETH
-        let buy_order = Order {
-            owner: usd_user.clone(),
-            order_id: "buy1".to_string(),
-            order_type: OrderType::Buy,
-            price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(1),
+        let err = orderbook
+            .execute_order(
+                order(&eth_user, "ask1", OrderType::Sell, 6, Some(0)),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("zero display quantity"), "{err}");
+        orderbook
+            .execute_order(
+                order(&eth_user, "ask1", OrderType::Sell, 6, Some(2)),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        orderbook
+            .execute_order(
+                order(&eth_user, "ask2", OrderType::Sell, 1, None),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        assert_eq!(orderbook.orders["ask1"].visible_quantity(), 2);
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 3);
+
+        // Only the shown slice is matched, then the order goes behind ask2
+        let events = orderbook
+            .execute_order(
+                order(&usd_user, "bid1", OrderType::Buy, 2, None),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        let trades: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                OrderbookEvent::TradeExecuted {
+                    maker_order_id,
+                    quantity,
+                    ..
+                } => Some((maker_order_id.as_str(), *quantity)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(trades, vec![("ask1", 2)]);
+        assert_eq!(orderbook.orders["ask1"].quantity, 4);
+        assert_eq!(orderbook.orders["ask1"].visible_quantity(), 2);
+
+        let events = orderbook
+            .execute_order(
+                order(&usd_user, "bid2", OrderType::Buy, 1, None),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        assert!(events.iter().any(|event| matches!(event, OrderbookEvent::TradeExecuted { maker_order_id, .. } if maker_order_id == "ask2")));
+        assert_eq!(orderbook.orders["ask1"].quantity, 4);
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 0);
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 3000);
+    }
+
+    #[test_log::test]
+    fn test_modify_order() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let order =
+            |owner: &str, order_id: &str, order_type: OrderType, price: u128, quantity: u128| {
+                Order {
+                    owner: owner.to_string(),
+                    order_id: order_id.to_string(),
+                    order_type,
+                    price: Some(price),
+                    trigger_price: None,
+                    pair: ("ETH".to_string(), "USD".to_string()),
+                    quantity,
+                    timestamp: TimestampMs(0),
+                    expires_at: None,
+                    display_quantity: None,
+                    hidden_quantity: 0,
+                    filled_quantity: 0,
+                    status: OrderStatus::Open,
+                    reserved_amount: 0,
+                }
+            };
+        let maker_of = |events: &[OrderbookEvent]| -> Vec<String> {
+            events
+                .iter()
+                .filter_map(|event| match event {
+                    OrderbookEvent::TradeExecuted { maker_order_id, .. } => {
+                        Some(maker_order_id.clone())
+                    }
+                    _ => None,
+                })
+                .collect()
         };
 
-        let events = orderbook.execute_order(buy_order, &TX_CTX).unwrap();
+        orderbook
+            .execute_order(
+                order(&eth_user, "ask1", OrderType::Sell, 1000, 2),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        orderbook
+            .execute_order(
+                order(&eth_user, "ask2", OrderType::Sell, 1000, 1),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        let err = orderbook
+            .modify_order("ask1".to_string(), 1000, 3, usd_user.clone(), &TX_CTX)
+            .unwrap_err();
+        assert!(err.to_string().contains("not the owner"), "{err}");
+        let err = orderbook
+            .modify_order("ask1".to_string(), 1000, 0, eth_user.clone(), &TX_CTX)
+            .unwrap_err();
+        assert!(err.to_string().contains("zero quantity"), "{err}");
+
+        // Quantity changes lock or refund the difference
+        orderbook
+            .modify_order("ask1".to_string(), 1000, 4, eth_user.clone(), &TX_CTX)
+            .unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 5);
+        orderbook
+            .modify_order("ask1".to_string(), 1000, 1, eth_user.clone(), &TX_CTX)
+            .unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 8);
+        assert_eq!(orderbook.orders["ask1"].quantity, 1);
 
-        // Check that we got an OrderUpdate event
-        assert!(events.iter().any(|event| matches!(event,
-            OrderbookEvent::OrderUpdate {
-                order_id,
-                remaining_quantity,
-                pair: _
-            } if order_id == "sell1" && *remaining_quantity == 1
+        // Moving the price away and back puts the order behind ask2, without closing it
+        let events = orderbook
+            .modify_order("ask1".to_string(), 1100, 1, eth_user.clone(), &TX_CTX)
+            .unwrap();
+        let updates: Vec<_> = events
+            .iter()
+            .filter(|e| {
+                matches!(
+                    e,
+                    OrderbookEvent::OrderUpdate {
+                        remaining_quantity: 1,
+                        ..
+                    }
+                )
+            })
+            .collect();
+        assert_eq!(updates.len(), 1);
+        assert!(!events.iter().any(|e| matches!(
+            e,
+            OrderbookEvent::OrderCancelled { .. } | OrderbookEvent::OrderCreated { .. }
         )));
-
-        // Check balances were updated correctly
-        let seller_usd = orderbook
-            .balances
-            .get(&eth_user)
-            .unwrap()
-            .get("USD")
+        assert_eq!(orderbook.closed_orders_of(&eth_user).count(), 0);
+        assert_eq!(orderbook.orders["ask1"].price, Some(1100));
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 8);
+        orderbook
+            .modify_order("ask1".to_string(), 1000, 1, eth_user.clone(), &TX_CTX)
             .unwrap();
-        let buyer_eth = orderbook
-            .balances
-            .get(&usd_user)
-            .unwrap()
-            .get("ETH")
+        let events = orderbook
+            .execute_order(
+                order(&usd_user, "bid1", OrderType::Buy, 1000, 1),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        assert_eq!(maker_of(&events), vec!["ask2"]);
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 2000);
+
+        // Buy orders reserve quote tokens at their price
+        orderbook
+            .execute_order(
+                order(&usd_user, "bid2", OrderType::Buy, 500, 1),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        orderbook
+            .modify_order("bid2".to_string(), 500, 2, usd_user.clone(), &TX_CTX)
             .unwrap();
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 1000);
 
-        assert_eq!(*seller_usd, 2000); // Seller received USD for 1 ETH
-        assert_eq!(*buyer_eth, 1); // Buyer received 1 ETH
+        // A new price can cross the book
+        let events = orderbook
+            .modify_order("bid2".to_string(), 1000, 2, usd_user.clone(), &TX_CTX)
+            .unwrap();
+        assert_eq!(maker_of(&events), vec!["ask1"]);
+        assert_eq!(orderbook.orders["bid2"].quantity, 1);
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 0);
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 2);
+    }
 
-        // Check that the sell order is still in the orderbook with updated quantity
-        let remaining_order = orderbook.orders.get("sell1").unwrap();
-        assert_eq!(remaining_order.quantity, 1);
+    #[test_log::test]
+    fn test_book_hash_events() {
+        let (eth_user, _, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let empty_book_hash = orderbook.book_hash(&pair);
+
+        let events = execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::CreateOrder {
+                order_type: OrderType::Sell,
+                price: Some(2000),
+                pair: pair.clone(),
+                quantity: 1,
+                time_in_force: TimeInForce::GoodTilCancelled,
+                trigger_price: None,
+                self_trade_prevention: None,
+                expires_at: None,
+                display_quantity: None,
+            },
+        );
+        let Some(OrderbookEvent::BookHash {
+            pair: event_pair,
+            hash,
+            seq,
+        }) = events.last()
+        else {
+            panic!("Expected a BookHash event, got {events:?}");
+        };
+        assert_eq!(event_pair, &pair);
+        assert_eq!(*seq, 1);
+        assert_eq!(hash, &orderbook.book_hash(&pair));
+        assert_ne!(hash, &empty_book_hash);
+
+        let events = execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::Cancel {
+                order_id: nth_order(0),
+            },
+        );
+        let Some(OrderbookEvent::BookHash { hash, seq, .. }) = events.last() else {
+            panic!("Expected a BookHash event, got {events:?}");
+        };
+        assert_eq!(*seq, 2);
+        assert_eq!(hash, &empty_book_hash);
+
+        // Balance changes alone do not touch any book
+        orderbook.accepted_tokens.insert("ETH".into());
+        let withdraw = OrderbookAction::Withdraw {
+            token: "ETH".to_string(),
+            amount: 1,
+            recipient: None,
+        };
+        let events = try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            withdraw,
+            vec![eth_payout(&eth_user, 1)],
+        )
+        .unwrap();
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, OrderbookEvent::BookHash { .. })));
     }
 
     #[test_log::test]
-    fn test_partial_order_execution_higher_price() {
+    fn test_book_never_crossed_after_matching() {
         let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
 
-        // Create a limit sell order for 2 ETH
         let sell_order = Order {
             owner: eth_user.clone(),
             order_id: "sell1".to_string(),
             order_type: OrderType::Sell,
             price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 2,
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity: 1,
             timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
         };
-        orderbook.execute_order(sell_order, &TX_CTX).unwrap();
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap();
 
-        // Create a buy order for 1 ETH at a higher price
         let buy_order = Order {
             owner: usd_user.clone(),
             order_id: "buy1".to_string(),
             order_type: OrderType::Buy,
-            price: Some(2100),
-            pair: ("ETH".to_string(), "USD".to_string()),
+            price: Some(1000),
+            trigger_price: None,
+            pair: pair.clone(),
             quantity: 1,
-            timestamp: TimestampMs(1),
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
         };
-
-        let events = orderbook.execute_order(buy_order, &TX_CTX).unwrap();
-
-        // Check that we got an OrderUpdate event
-        assert!(events.iter().any(|event| matches!(event,
-            OrderbookEvent::OrderUpdate {
-                order_id,
-                remaining_quantity,
-                pair: _
-            } if order_id == "sell1" && *remaining_quantity == 1
-        )));
-
-        // Check balances were updated correctly
-        let seller_usd = orderbook
-            .balances
-            .get(&eth_user)
-            .unwrap()
-            .get("USD")
-            .unwrap();
-        let buyer_eth = orderbook
-            .balances
-            .get(&usd_user)
-            .unwrap()
-            .get("ETH")
+        orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
             .unwrap();
 
-        assert_eq!(*seller_usd, 2000); // Seller received USD for 1 ETH (at sell price)
-        assert_eq!(*buyer_eth, 1); // Buyer received 1 ETH
-
-        // Check that the sell order is still in the orderbook with updated quantity
-        let remaining_order = orderbook.orders.get("sell1").unwrap();
-        assert_eq!(remaining_order.quantity, 1);
+        let (best_bid, best_ask) = orderbook.best_prices(&pair);
+        assert_eq!(best_bid, Some(1000));
+        assert_eq!(best_ask, Some(2000));
     }
 
     #[test_log::test]
-    fn test_market_sell_order_against_larger_buy_order() {
+    fn test_crossed_book_fails_order() {
         let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+
+        // Corrupt the book: a bid rests above an ask
+        for (order_id, order_type, price) in [
+            ("buy1", OrderType::Buy, 2000),
+            ("sell1", OrderType::Sell, 1000),
+        ] {
+            let levels = match order_type {
+                OrderType::Buy => orderbook.buy_orders.entry(pair.clone()).or_default(),
+                OrderType::Sell => orderbook.sell_orders.entry(pair.clone()).or_default(),
+            };
+            levels.insert(price, order_id.to_string());
+            orderbook.orders.insert(
+                order_id.to_string(),
+                Order {
+                    owner: eth_user.clone(),
+                    order_id: order_id.to_string(),
+                    order_type,
+                    price: Some(price),
+                    trigger_price: None,
+                    pair: pair.clone(),
+                    quantity: 1,
+                    timestamp: TimestampMs(0),
+                    expires_at: None,
+                    display_quantity: None,
+                    hidden_quantity: 0,
+                    filled_quantity: 0,
+                    status: OrderStatus::Open,
+                    reserved_amount: 0,
+                },
+            );
+        }
 
-        // Create a limit buy order first for 2 ETH
+        // The buy order does not cross the ask, but the book is left crossed
         let buy_order = Order {
             owner: usd_user.clone(),
-            order_id: "buy1".to_string(),
+            order_id: "buy2".to_string(),
             order_type: OrderType::Buy,
-            price: Some(1000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 2,
-            timestamp: TimestampMs(0),
-        };
-        orderbook.execute_order(buy_order, &TX_CTX).unwrap();
-
-        // Create a market sell order for 1 ETH
-        let sell_order = Order {
-            owner: eth_user.clone(),
-            order_id: "sell1".to_string(),
-            order_type: OrderType::Sell,
-            price: None, // Market order
-            pair: ("ETH".to_string(), "USD".to_string()),
+            price: Some(500),
+            trigger_price: None,
+            pair: pair.clone(),
             quantity: 1,
-            timestamp: TimestampMs(1),
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
         };
+        let err = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap_err();
+        assert!(err.to_string().contains("book crossed"), "{err}");
+    }
 
-        let events = orderbook.execute_order(sell_order, &TX_CTX).unwrap();
+    #[test_log::test]
+    fn test_crossing_order_rejected_when_matching_stops() {
+        let (_, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
 
-        // Order should be executed immediately at the buy order's price
-        assert!(events.iter().any(|event| matches!(event, OrderbookEvent::OrderUpdate { 
-            order_id,
-            remaining_quantity,
-            pair: _
-        } if order_id == "buy1" && *remaining_quantity == 1)));
+        // Corrupt the book: the best ask level only holds an unknown order, which cannot be filled
+        orderbook
+            .sell_orders
+            .entry(pair.clone())
+            .or_default()
+            .insert(1000, "ghost".to_string());
+        assert!(orderbook.check_invariants().is_ok());
 
-        // Check balances were updated correctly
-        let eth_user_usd = orderbook.balances.get(&eth_user).unwrap().get("USD").unwrap();
-        let usd_user_eth = orderbook.balances.get(&usd_user).unwrap().get("ETH").unwrap();
+        // The matching stops at the level, and the order is not rested across it
+        let buy_order = Order {
+            owner: usd_user.clone(),
+            order_id: "buy1".to_string(),
+            order_type: OrderType::Buy,
+            price: Some(1000),
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity: 1,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        let err = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap_err();
+        assert_eq!(
+            err,
+            OrderbookError::CrossedBook {
+                pair: pair.clone(),
+                best_bid: 1000,
+                best_ask: 1000
+            }
+        );
 
-        assert_eq!(*eth_user_usd, 1000); // Seller got the buy order's price
-        assert_eq!(*usd_user_eth, 1); // Buyer got their ETH
+        // A crossed book breaks the invariants
+        orderbook
+            .buy_orders
+            .entry(pair.clone())
+            .or_default()
+            .insert(1000, "ghost2".to_string());
+        let err = orderbook.check_invariants().unwrap_err();
+        assert!(err.contains("book is crossed"), "{err}");
     }
 
     #[test_log::test]
-    fn test_market_sell_order_against_exact_buy_order() {
+    fn test_amount_overflow_fails_order() {
         let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
 
-        // Create a limit buy order first for 1 ETH
+        // The quote amount of the order does not fit in a balance
         let buy_order = Order {
             owner: usd_user.clone(),
             order_id: "buy1".to_string(),
             order_type: OrderType::Buy,
-            price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
+            price: Some(2),
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity: u128::MAX,
             timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
         };
-        orderbook.execute_order(buy_order, &TX_CTX).unwrap();
+        let err = orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &TX_CTX)
+            .unwrap_err();
+        assert!(err.to_string().contains("overflows"), "{err}");
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 3000);
+
+        let err = orderbook
+            .deposit("ETH".to_string(), u128::MAX, eth_user.clone(), &TX_CTX)
+            .unwrap_err();
+        assert!(err.to_string().contains("would overflow"), "{err}");
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 10);
+    }
 
-        // Create a market sell order for 1 ETH
-        let sell_order = Order {
-            owner: eth_user.clone(),
-            order_id: "sell1".to_string(),
-            order_type: OrderType::Sell,
-            price: None, // Market order
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(1),
-        };
+    #[test_log::test]
+    fn test_token_decimals_rounding() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let order =
+            |order_type: OrderType, price: u128, quantity: u128| OrderbookAction::CreateOrder {
+                order_type,
+                price: Some(price),
+                pair: ("ETH".to_string(), "USD".to_string()),
+                quantity,
+                time_in_force: TimeInForce::GoodTilCancelled,
+                trigger_price: None,
+                self_trade_prevention: None,
+                expires_at: None,
+                display_quantity: None,
+            };
+
+        // Prices are in USD per whole ETH, i.e. per 100 units of it
+        assert!(orderbook
+            .configure_token("ETH".to_string(), 2, eth_user.clone())
+            .is_err());
+        assert!(orderbook
+            .configure_token("ETH".to_string(), 39, "admin".to_string())
+            .is_err());
+        orderbook
+            .configure_token("ETH".to_string(), 2, "admin".to_string())
+            .unwrap();
 
-        let events = orderbook.execute_order(sell_order, &TX_CTX).unwrap();
+        // The taker pays 3.33 rounded up, the maker gets it rounded down
+        execute_action(&mut orderbook, &eth_user, order(OrderType::Sell, 333, 3));
+        execute_action(&mut orderbook, &usd_user, order(OrderType::Buy, 333, 1));
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 1);
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 2996);
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 3);
+        assert_eq!(orderbook.get_balance(DUST, "USD"), 1);
+        assert_eq!(orderbook.orders[&nth_order(0)].reserved_amount, 2);
+        let err = orderbook
+            .configure_token("ETH".to_string(), 3, "admin".to_string())
+            .unwrap_err();
+        assert!(err.to_string().contains("open orders"), "{err}");
+
+        // The maker locks 4.5 rounded up, and the taker gets 1.5 rounded down
+        execute_action(&mut orderbook, &usd_user, order(OrderType::Buy, 150, 3));
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 2991);
+        assert_eq!(orderbook.orders[&nth_order(2)].reserved_amount, 5);
+        execute_action(&mut orderbook, &eth_user, order(OrderType::Sell, 150, 1));
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 4);
+        assert_eq!(orderbook.get_balance(DUST, "USD"), 2);
+        assert_eq!(orderbook.orders[&nth_order(2)].reserved_amount, 3);
+
+        // What is left of the lock is refunded
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            OrderbookAction::Cancel {
+                order_id: nth_order(2),
+            },
+        );
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 2994);
+        assert_eq!(orderbook.get_balance(RESERVES, "USD"), 0);
+    }
 
-        assert_eq!(events.len(), 5);
-        let executed_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
-            .count();
-        let balance_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
-            .count();
-        assert_eq!(executed_count, 1);
-        // eth_user sent ETH
-        // usd_user received ETH
-        // orderbook sent USD
-        // eth_user received USD
-        assert_eq!(balance_count, 4);
+    fn tx_ctx_at(timestamp: u128) -> sdk::TxContext {
+        sdk::TxContext {
+            timestamp: TimestampMs(timestamp),
+            ..TX_CTX.clone()
+        }
+    }
 
-        // Assert orderbook is empty
-        assert_eq!(orderbook.orders.len(), 0);
+    #[test_log::test]
+    fn test_sweep_dust() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        set_balance(&mut orderbook, &usd_user, "ETH", 3);
+        set_balance(&mut orderbook, &eth_user, "USD", 0);
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(2000), None),
+        );
 
+        let set_threshold = OrderbookAction::SetDustThreshold {
+            token: "ETH".to_string(),
+            threshold: 5,
+        };
+        let err = try_execute_action(&mut orderbook, &eth_user, set_threshold.clone(), vec![])
+            .unwrap_err();
+        assert!(matches!(err, OrderbookError::NotAdmin { .. }));
+        execute_action(&mut orderbook, "admin", set_threshold);
+        assert_eq!(orderbook.dust_threshold("ETH"), 5);
+
+        // Only the user balances below the threshold are swept, not the reserved tokens
+        let events = execute_action(
+            &mut orderbook,
+            &usd_user,
+            OrderbookAction::SweepDust {
+                token: "ETH".to_string(),
+            },
+        );
+        assert!(matches!(
+            events.last(),
+            Some(OrderbookEvent::DustSwept { amount: 3, .. })
+        ));
+        // Empty entries are dropped, of every token
+        assert!(!orderbook.balances[&usd_user].contains_key("ETH"));
+        assert!(!orderbook.balances[&eth_user].contains_key("USD"));
+        assert_eq!(orderbook.get_balance(DUST, "ETH"), 3);
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 9);
+        assert_eq!(orderbook.get_balance(RESERVES, "ETH"), 1);
+    }
 
-        // Check that balances haven't changed
-        let eth_user_balances = orderbook.balances.get(&eth_user).unwrap();
-        let usd_user_balances = orderbook.balances.get(&usd_user).unwrap();
-        let orderbook_balances = orderbook.balances.get("orderbook").unwrap();
+    #[test_log::test]
+    fn test_pair_depth() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        for price in [1000, 1000, 500, 400] {
+            execute_action(
+                &mut orderbook,
+                &usd_user,
+                create_order(OrderType::Buy, Some(price), None),
+            );
+        }
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(2000), None),
+        );
 
-        assert_eq!(*eth_user_balances.get("ETH").unwrap(), 9); // eth_user sold 1 ETH ...
-        assert_eq!(*eth_user_balances.get("USD").unwrap(), 2000); // .. for 2000 USD
+        let depth = orderbook.get_pair_depth("ETH", "USD", 2);
+        assert_eq!(depth.bids, vec![(1000, 2, 2), (500, 1, 1)]);
+        assert_eq!(depth.asks, vec![(2000, 1, 1)]);
+        assert!(orderbook.get_pair_depth("ETH", "BTC", 2).bids.is_empty());
+    }
 
-        assert_eq!(*usd_user_balances.get("ETH").unwrap(), 1); // usd_user bought 1 ETH ...
-        assert_eq!(*usd_user_balances.get("USD").unwrap(), 1000); // .. for 2000 USD
+    #[test_log::test]
+    fn test_ticker() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        for (price, rests) in [(1000, false), (1100, false), (1500, true)] {
+            execute_action(
+                &mut orderbook,
+                &eth_user,
+                create_order(OrderType::Sell, Some(price), None),
+            );
+            if !rests {
+                execute_action(
+                    &mut orderbook,
+                    &usd_user,
+                    create_order(OrderType::Buy, Some(price), None),
+                );
+            }
+        }
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(500), None),
+        );
 
-        assert_eq!(*orderbook_balances.get("ETH").unwrap_or(&0), 0); // orderbook is empty
-        assert_eq!(*orderbook_balances.get("USD").unwrap_or(&0), 0); // orderbook is empty
+        let ticker = orderbook.get_ticker("ETH", "USD", &TimestampMs(0));
+        assert_eq!(
+            ticker,
+            indexer::Ticker {
+                best_bid: Some(500),
+                best_ask: Some(1500),
+                mid: Some(1000),
+                spread: Some(1000),
+                last_price: Some(1100),
+                change: Some(100),
+                change_bps: Some(1000),
+                volume: 2,
+            }
+        );
+        assert_eq!(
+            orderbook.get_ticker("ETH", "BTC", &TimestampMs(0)).change,
+            None
+        );
     }
 
     #[test_log::test]
-    fn test_market_sell_order_against_smaller_buy_order() {
+    fn test_markets() {
         let (eth_user, usd_user, mut orderbook) = setup();
+        assert!(orderbook.get_markets(&TimestampMs(0)).is_empty());
+        for price in [1000, 1100] {
+            execute_action(
+                &mut orderbook,
+                &eth_user,
+                create_order(OrderType::Sell, Some(price), None),
+            );
+            execute_action(
+                &mut orderbook,
+                &usd_user,
+                create_order(OrderType::Buy, Some(price), None),
+            );
+        }
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(1500), None),
+        );
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(500), None),
+        );
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(400), None),
+        );
 
-        // Create a limit buy order first for 1 ETH
-        let buy_order = Order {
-            owner: usd_user.clone(),
-            order_id: "buy1".to_string(),
-            order_type: OrderType::Buy,
-            price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(0),
+        assert_eq!(
+            orderbook.get_markets(&TimestampMs(0)),
+            vec![indexer::MarketSummary {
+                base_token: "ETH".to_string(),
+                quote_token: "USD".to_string(),
+                last_price: Some(1100),
+                high: Some(1100),
+                low: Some(1000),
+                volume: 2,
+                open_buy_orders: 2,
+                open_sell_orders: 1,
+                best_bid: Some(500),
+                best_ask: Some(1500),
+            }]
+        );
+
+        // Once pairs are listed, only the listed ones are shown
+        orderbook.listed_pairs = Some([("BTC".to_string(), "USD".to_string())].into());
+        let markets = orderbook.get_markets(&TimestampMs(0));
+        assert_eq!(
+            markets
+                .iter()
+                .map(|m| m.base_token.as_str())
+                .collect::<Vec<_>>(),
+            vec!["BTC"]
+        );
+        assert_eq!(
+            (markets[0].last_price, markets[0].open_buy_orders),
+            (None, 0)
+        );
+    }
+
+    #[test_log::test]
+    fn test_fee_stats() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let schedule = FeeSchedule {
+            maker_bps: 100,
+            taker_bps: 200,
+            ..Default::default()
         };
-        
-        orderbook.execute_order(buy_order, &TX_CTX).unwrap();
+        orderbook
+            .set_fee_schedule(schedule, "admin".to_string())
+            .unwrap();
+        let day = 24 * 3_600_000;
+        let mut trade = |timestamp: u128| {
+            let tx_ctx = tx_ctx_at(timestamp);
+            try_execute_action_in(
+                &mut orderbook,
+                &eth_user,
+                create_order(OrderType::Sell, Some(1000), None),
+                vec![],
+                &tx_ctx,
+            )
+            .unwrap();
+            let events = try_execute_action_in(
+                &mut orderbook,
+                &usd_user,
+                create_order(OrderType::Buy, Some(1000), None),
+                vec![],
+                &tx_ctx,
+            )
+            .unwrap();
+            let mut fees: BTreeMap<String, u128> = BTreeMap::new();
+            for event in events {
+                if let OrderbookEvent::FeeCharged { token, amount, .. } = event {
+                    *fees.entry(token).or_default() += amount;
+                }
+            }
+            fees
+        };
+        let first = trade(0);
+        let second = trade(2 * day);
+        assert!(!first.is_empty());
+        assert_eq!(first, second);
+
+        // Only the hours overlapping the window are counted
+        let stats = orderbook.get_fee_stats(day, &TimestampMs(2 * day + 1));
+        assert_eq!(stats.fees, second);
+        assert_eq!(
+            stats.pairs,
+            vec![indexer::PairFees {
+                base_token: "ETH".to_string(),
+                quote_token: "USD".to_string(),
+                fees: second.clone(),
+            }]
+        );
+        assert_eq!(
+            stats.hourly.iter().map(|b| b.start.0).collect::<Vec<_>>(),
+            vec![2 * day]
+        );
 
-        // Create a market sell order for 2 ETH
-        let sell_order = Order {
-            owner: eth_user.clone(),
-            order_id: "sell1".to_string(),
-            order_type: OrderType::Sell,
-            price: None, // Market order
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 2,
-            timestamp: TimestampMs(1),
+        let stats = orderbook.get_fee_stats(3 * day, &TimestampMs(2 * day + 1));
+        let doubled: BTreeMap<String, u128> = first
+            .iter()
+            .map(|(token, amount)| (token.clone(), 2 * amount))
+            .collect();
+        assert_eq!(stats.fees, doubled);
+        assert_eq!(
+            stats.hourly.iter().map(|b| b.start.0).collect::<Vec<_>>(),
+            vec![0, 2 * day]
+        );
+        assert_eq!(
+            orderbook.get_fee_stats(day, &TimestampMs(4 * day)),
+            indexer::FeeStats::default()
+        );
+    }
+
+    #[test_log::test]
+    fn test_orders_pagination() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        for price in [900, 800, 700] {
+            execute_action(
+                &mut orderbook,
+                &usd_user,
+                create_order(OrderType::Buy, Some(price), None),
+            );
+        }
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(1000), None),
+        );
+        let ids = |orders: &[Order]| {
+            orders
+                .iter()
+                .map(|o| o.order_id.clone())
+                .collect::<Vec<_>>()
         };
 
-        let events = orderbook.execute_order(sell_order, &TX_CTX).unwrap();
+        let query = indexer::OrdersQuery {
+            limit: 2,
+            ..Default::default()
+        };
+        let page = orderbook.get_orders_by_pair("ETH", "USD", &query);
+        assert_eq!(
+            (ids(&page.buy_orders), ids(&page.sell_orders)),
+            (vec![nth_order(0), nth_order(1)], vec![nth_order(3)])
+        );
+        let query = indexer::OrdersQuery {
+            cursor: Some(nth_order(1)),
+            ..query
+        };
+        let page = orderbook.get_orders_by_pair("ETH", "USD", &query);
+        assert_eq!(
+            (ids(&page.buy_orders), ids(&page.sell_orders)),
+            (vec![nth_order(2)], vec![])
+        );
 
-        assert_eq!(events.len(), 5);
-        let executed_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
-            .count();
-        let balance_count = events
-            .iter()
-            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
-            .count();
-        assert_eq!(executed_count, 1);
-        // eth_user sent ETH
-        // usd_user received ETH
-        // orderbook sent USD
-        // eth_user received USD
-        assert_eq!(balance_count, 4);
+        let query = indexer::OrdersQuery {
+            cursor: Some(nth_order(0)),
+            side: Some(OrderType::Buy),
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&orderbook.get_orders_by_user(&usd_user, &query)),
+            vec![nth_order(1), nth_order(2)]
+        );
+        let orders = orderbook.get_orders(&indexer::OrdersQuery {
+            side: Some(OrderType::Sell),
+            ..Default::default()
+        });
+        assert_eq!(
+            orders.keys().cloned().collect::<Vec<_>>(),
+            vec![nth_order(3)]
+        );
 
-        // Assert orderbook is empty
-        assert_eq!(orderbook.orders.len(), 0);
+        // Filters are read from the query parameters
+        let params = BTreeMap::from([
+            ("status".to_string(), "PartiallyFilled".to_string()),
+            ("side".to_string(), "Sell".to_string()),
+        ]);
+        let query = indexer::OrdersQuery::from_params(|name| params.get(name)).unwrap();
+        assert_eq!(
+            (query.status, query.side, query.limit),
+            (
+                Some(OrderStatus::PartiallyFilled),
+                Some(OrderType::Sell),
+                indexer::DEFAULT_ORDERS_LIMIT
+            )
+        );
+        let params = BTreeMap::from([("side".to_string(), "Both".to_string())]);
+        assert!(indexer::OrdersQuery::from_params(|name| params.get(name)).is_err());
+    }
 
+    #[test_log::test]
+    fn test_recent_trades() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        for price in [800, 900, 1000] {
+            execute_action(
+                &mut orderbook,
+                &eth_user,
+                create_order(OrderType::Sell, Some(price), None),
+            );
+            execute_action(
+                &mut orderbook,
+                &usd_user,
+                create_order(OrderType::Buy, Some(price), None),
+            );
+        }
 
-        // Check that balances haven't changed
-        let eth_user_balances = orderbook.balances.get(&eth_user).unwrap();
-        let usd_user_balances = orderbook.balances.get(&usd_user).unwrap();
-        let orderbook_balances = orderbook.balances.get("orderbook").unwrap();
+        let trades = orderbook.get_recent_trades("ETH", "USD", 2, None);
+        let page: Vec<_> = trades
+            .iter()
+            .map(|t| (t.id, t.price, t.maker.as_str(), t.taker.as_str()))
+            .collect();
+        assert_eq!(
+            page,
+            vec![
+                (2, 1000, eth_user.as_str(), usd_user.as_str()),
+                (1, 900, eth_user.as_str(), usd_user.as_str())
+            ]
+        );
 
-        assert_eq!(*eth_user_balances.get("ETH").unwrap(), 9); // eth_user sold 1 ETH ...
-        assert_eq!(*eth_user_balances.get("USD").unwrap(), 2000); // .. for 2000 USD
+        // The next page starts below the id of the last trade
+        let older = orderbook.get_recent_trades("ETH", "USD", 2, Some(1));
+        assert_eq!(
+            older.iter().map(|t| (t.id, t.price)).collect::<Vec<_>>(),
+            vec![(0, 800)]
+        );
+        assert!(orderbook
+            .get_recent_trades("ETH", "USD", 2, Some(0))
+            .is_empty());
+        assert!(orderbook
+            .get_recent_trades("ETH", "BTC", 2, None)
+            .is_empty());
+    }
 
-        assert_eq!(*usd_user_balances.get("ETH").unwrap(), 1); // usd_user bought 1 ETH ...
-        assert_eq!(*usd_user_balances.get("USD").unwrap(), 1000); // .. for 2000 USD
+    #[test_log::test]
+    fn test_depth_deltas() {
+        use crate::client::depth::{depth_updates, DepthMessage, LevelChange};
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let pairs = [pair.clone()];
+        let mut deltas = vec![];
+        for (user, order_type) in [
+            (&eth_user, OrderType::Sell),
+            (&eth_user, OrderType::Sell),
+            (&usd_user, OrderType::Buy),
+            (&usd_user, OrderType::Buy),
+        ] {
+            let before = orderbook.clone();
+            let events = execute_action(
+                &mut orderbook,
+                user,
+                create_order(order_type, Some(1000), None),
+            );
+            deltas.extend(depth_updates(&before, &orderbook, &events, &pairs));
+        }
+        assert_eq!(deltas[0].topic(), "depth:ETH-USD");
 
-        assert_eq!(*orderbook_balances.get("ETH").unwrap_or(&0), 0); // orderbook is empty
-        assert_eq!(*orderbook_balances.get("USD").unwrap_or(&0), 0); // orderbook is empty
+        let changes: Vec<(u64, Vec<LevelChange>)> = deltas
+            .into_iter()
+            .map(|delta| match delta {
+                DepthMessage::Delta { pair, seq, changes } if pair == "ETH-USD" => (seq, changes),
+                other => panic!("Unexpected depth message {other:?}"),
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    1,
+                    vec![LevelChange::Added {
+                        side: OrderType::Sell,
+                        price: 1000,
+                        quantity: 1,
+                        orders: 1
+                    }]
+                ),
+                (
+                    2,
+                    vec![LevelChange::Changed {
+                        side: OrderType::Sell,
+                        price: 1000,
+                        quantity: 2,
+                        orders: 2
+                    }]
+                ),
+                (
+                    3,
+                    vec![LevelChange::Changed {
+                        side: OrderType::Sell,
+                        price: 1000,
+                        quantity: 1,
+                        orders: 1
+                    }]
+                ),
+                (
+                    4,
+                    vec![LevelChange::Removed {
+                        side: OrderType::Sell,
+                        price: 1000
+                    }]
+                ),
+            ]
+        );
+
+        // Snapshots carry the sequence number of the book, which the next delta follows
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(900), None),
+        );
+        let snapshot = DepthMessage::snapshot(&orderbook, &pair);
+        assert_eq!(
+            snapshot,
+            DepthMessage::Snapshot {
+                pair: "ETH-USD".to_string(),
+                seq: 5,
+                bids: vec![(900, 1, 1)],
+                asks: vec![]
+            }
+        );
+        let before = orderbook.clone();
+        let events = execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(50), None),
+        );
+        assert!(depth_updates(&before, &orderbook, &events, &[]).is_empty());
     }
 
-    // Tests with existing sell orders
     #[test_log::test]
-    fn test_market_buy_order_against_larger_sell_order() {
+    fn test_event_seq() {
         let (eth_user, usd_user, mut orderbook) = setup();
+        let events = execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(2000), None),
+        );
+        assert_eq!(orderbook.event_seq(), events.len() as u64);
+        let more = execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(2000), None),
+        );
+        assert_eq!(orderbook.event_seq(), (events.len() + more.len()) as u64);
+
+        // Failed transactions emit nothing
+        try_execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(0), None),
+            vec![],
+        )
+        .unwrap_err();
+        assert_eq!(orderbook.event_seq(), (events.len() + more.len()) as u64);
+    }
 
-        // Create a limit sell order first for 2 ETH
-        let sell_order = Order {
-            owner: eth_user.clone(),
-            order_id: "sell1".to_string(),
-            order_type: OrderType::Sell,
-            price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 2,
-            timestamp: TimestampMs(0),
+    #[test_log::test]
+    fn test_user_order_limits() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        set_balance(&mut orderbook, &usd_user, "USD", 10000);
+        let config = MarketConfig {
+            max_open_orders: 3,
+            max_user_notional: 2500,
+            ..Default::default()
         };
-        orderbook.execute_order(sell_order, &TX_CTX).unwrap();
+        orderbook
+            .configure_market(pair.clone(), config, "admin".to_string())
+            .unwrap();
 
-        // Create a market buy order for 1 ETH
-        let buy_order = Order {
-            owner: usd_user.clone(),
-            order_id: "buy1".to_string(),
-            order_type: OrderType::Buy,
-            price: None, // Market order
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(1),
-        };
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(1000), None),
+        );
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(1000), None),
+        );
+        let err = try_execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(600), None),
+            vec![],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            OrderbookError::NotionalLimitExceeded {
+                user: usd_user.clone(),
+                pair: pair.clone(),
+                notional: 2600,
+                limit: 2500
+            }
+        );
+        execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(500), None),
+        );
 
-        let events = orderbook.execute_order(buy_order, &TX_CTX).unwrap();
+        // Stop orders count as open orders
+        let err = try_execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, None, Some(3000)),
+            vec![],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            OrderbookError::OpenOrderLimitReached {
+                user: usd_user.clone(),
+                pair: pair.clone(),
+                limit: 3
+            }
+        );
 
-        // Order should be executed immediately at the sell order's price
-        assert!(events.iter().any(|event| matches!(event, OrderbookEvent::OrderUpdate { 
-            order_id,
-            remaining_quantity,
-            pair: _
-        } if order_id == "sell1" && *remaining_quantity == 1)));
+        // The limits are per user, and orders filled right away do not count
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(2000), None),
+        );
+        let events = execute_action(
+            &mut orderbook,
+            &usd_user,
+            create_order(OrderType::Buy, Some(2000), None),
+        );
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, OrderbookEvent::TradeExecuted { .. })));
+        assert_eq!(
+            orderbook
+                .get_orders_by_user(&usd_user, &indexer::OrdersQuery::default())
+                .len(),
+            3
+        );
+    }
 
-        // Check balances were updated correctly
-        let eth_user_usd = orderbook.balances.get(&eth_user).unwrap().get("USD").unwrap();
-        let usd_user_eth = orderbook.balances.get(&usd_user).unwrap().get("ETH").unwrap();
+    #[test_log::test]
+    fn test_system_accounts() {
+        let (eth_user, _, mut orderbook) = setup();
+        set_balance(&mut orderbook, "admin", "USD", 100);
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(2000), None),
+        );
+        assert_eq!(orderbook.reserves("ETH"), 1);
+
+        // Nobody can act as one of the accounts of the orderbook
+        for account in SYSTEM_ACCOUNTS {
+            let err = try_execute_action(
+                &mut orderbook,
+                account,
+                OrderbookAction::SweepDust {
+                    token: "ETH".to_string(),
+                },
+                vec![],
+            )
+            .unwrap_err();
+            assert_eq!(
+                err,
+                OrderbookError::SystemIdentity {
+                    identity: account.to_string()
+                }
+            );
+        }
 
-        assert_eq!(*eth_user_usd, 2000); // Seller got their asking price
-        assert_eq!(*usd_user_eth, 1); // Buyer got their ETH
+        let fund = OrderbookAction::FundInsurance {
+            token: "USD".to_string(),
+            amount: 60,
+        };
+        let err = try_execute_action(&mut orderbook, &eth_user, fund.clone(), vec![]).unwrap_err();
+        assert!(matches!(err, OrderbookError::NotAdmin { .. }));
+        execute_action(&mut orderbook, "admin", fund);
+        assert_eq!(orderbook.insurance_fund("USD"), 60);
+        assert_eq!(orderbook.get_balance("admin", "USD"), 40);
+
+        // The funds of the accounts are not mixed, but to settle fills
+        let err = orderbook
+            .transfer_tokens(INSURANCE, FEE_POOL, "USD", 10)
+            .unwrap_err();
+        assert!(matches!(err, OrderbookError::SystemTransfer { .. }));
+        let err = orderbook
+            .transfer_tokens(RESERVES, INSURANCE, "ETH", 1)
+            .unwrap_err();
+        assert!(matches!(err, OrderbookError::SystemTransfer { .. }));
+        orderbook
+            .transfer_tokens(RESERVES, FEE_POOL, "ETH", 1)
+            .unwrap();
+        assert_eq!(orderbook.fee_pool("ETH"), 1);
+        assert_eq!(orderbook.reserves("ETH"), 0);
     }
 
     #[test_log::test]
-    fn test_market_buy_order_against_exact_sell_order() {
+    fn test_maker_incentives_accounting() {
         let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
 
-        // Create a limit sell order first for 1 ETH
         let sell_order = Order {
             owner: eth_user.clone(),
             order_id: "sell1".to_string(),
             order_type: OrderType::Sell,
             price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity: 2,
             timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
         };
-        orderbook.execute_order(sell_order, &TX_CTX).unwrap();
+        orderbook
+            .execute_order(sell_order, TimeInForce::GoodTilCancelled, &tx_ctx_at(0))
+            .unwrap();
 
-        // Create a market buy order for 1 ETH
         let buy_order = Order {
             owner: usd_user.clone(),
             order_id: "buy1".to_string(),
             order_type: OrderType::Buy,
-            price: None, // Market order
-            pair: ("ETH".to_string(), "USD".to_string()),
+            price: Some(2000),
+            trigger_price: None,
+            pair: pair.clone(),
             quantity: 1,
-            timestamp: TimestampMs(1),
+            timestamp: TimestampMs(1000),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
         };
+        orderbook
+            .execute_order(buy_order, TimeInForce::GoodTilCancelled, &tx_ctx_at(1000))
+            .unwrap();
 
-        let events = orderbook.execute_order(buy_order, &TX_CTX).unwrap();
+        // The taker is not a maker
+        assert!(!orderbook.incentives.makers.contains_key(&usd_user));
+        let stats = &orderbook.incentives.makers[&eth_user];
+        assert_eq!(stats.volume, 1);
+        assert_eq!(stats.resting_quantity, 1);
+        assert_eq!(stats.quoted_depth, 2 * 1000);
 
-        // Both orders should be fully executed
-        assert_eq!(
-            events.iter().filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. })).count(),
-            2
+        orderbook
+            .cancel_order("sell1".to_string(), eth_user.clone(), &tx_ctx_at(3000))
+            .unwrap();
+        let stats = &orderbook.incentives.makers[&eth_user];
+        assert_eq!(stats.resting_quantity, 0);
+        assert_eq!(stats.quoted_depth, 2 * 1000 + 2000);
+    }
+
+    #[test_log::test]
+    fn test_distribute_maker_rewards() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        orderbook.balances.insert(
+            "admin".to_string(),
+            BTreeMap::from([("HYLLAR".to_string(), 100)]),
         );
 
-        // Check balances
-        let eth_user_usd = orderbook.balances.get(&eth_user).unwrap().get("USD").unwrap();
-        let usd_user_eth = orderbook.balances.get(&usd_user).unwrap().get("ETH").unwrap();
+        // Both orders rest in the book for the same time, with the same quantity
+        for (owner, order_id, order_type, price) in [
+            (&eth_user, "sell1", OrderType::Sell, 2000),
+            (&usd_user, "buy1", OrderType::Buy, 1000),
+        ] {
+            let order = Order {
+                owner: owner.clone(),
+                order_id: order_id.to_string(),
+                order_type,
+                price: Some(price),
+                trigger_price: None,
+                pair: pair.clone(),
+                quantity: 1,
+                timestamp: TimestampMs(0),
+                expires_at: None,
+                display_quantity: None,
+                hidden_quantity: 0,
+                filled_quantity: 0,
+                status: OrderStatus::Open,
+                reserved_amount: 0,
+            };
+            orderbook
+                .execute_order(order, TimeInForce::GoodTilCancelled, &tx_ctx_at(0))
+                .unwrap();
+        }
 
-        assert_eq!(*eth_user_usd, 2000);
-        assert_eq!(*usd_user_eth, 1);
+        let err = orderbook
+            .distribute_maker_rewards(
+                "HYLLAR".to_string(),
+                100,
+                eth_user.clone(),
+                &tx_ctx_at(1000),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("not the orderbook admin"), "{err}");
+
+        let events = orderbook
+            .distribute_maker_rewards(
+                "HYLLAR".to_string(),
+                100,
+                "admin".to_string(),
+                &tx_ctx_at(1000),
+            )
+            .unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "HYLLAR"), 50);
+        assert_eq!(orderbook.get_balance(&usd_user, "HYLLAR"), 50);
+        assert_eq!(orderbook.get_balance("admin", "HYLLAR"), 0);
+        assert_eq!(orderbook.incentives.rewards[&eth_user]["HYLLAR"], 50);
+        assert_eq!(orderbook.incentives.epoch, 1);
+        assert!(matches!(
+            events.last(),
+            Some(OrderbookEvent::MakerRewardsDistributed { epoch: 0, token, amount: 100 }) if token == "HYLLAR"
+        ));
+
+        // The new epoch starts without any activity
+        let err = orderbook
+            .distribute_maker_rewards(
+                "HYLLAR".to_string(),
+                100,
+                "admin".to_string(),
+                &tx_ctx_at(1000),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("No maker activity"), "{err}");
     }
 
     #[test_log::test]
-    fn test_market_buy_order_against_smaller_sell_order() {
+    fn test_liquidity_incentives() {
         let (eth_user, usd_user, mut orderbook) = setup();
-
-        // Create a limit sell order first for 1 ETH
-        let sell_order = Order {
-            owner: eth_user.clone(),
-            order_id: "sell1".to_string(),
-            order_type: OrderType::Sell,
-            price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 1,
-            timestamp: TimestampMs(0),
+        let pair = ("ETH".to_string(), "USD".to_string());
+        set_balance(&mut orderbook, "admin", "HYLLAR", 100);
+        let config = MarketConfig {
+            incentive_band_bps: 500,
+            ..Default::default()
         };
-        orderbook.execute_order(sell_order, &TX_CTX).unwrap();
+        orderbook
+            .configure_market(pair.clone(), config, "admin".to_string())
+            .unwrap();
+        let order =
+            |order_type: OrderType, price: u128, quantity: u128| OrderbookAction::CreateOrder {
+                order_type,
+                price: Some(price),
+                pair: pair.clone(),
+                quantity,
+                time_in_force: TimeInForce::GoodTilCancelled,
+                trigger_price: None,
+                self_trade_prevention: None,
+                expires_at: None,
+                display_quantity: None,
+            };
+        let send =
+            |orderbook: &mut Orderbook, user: &str, action: OrderbookAction, timestamp: u128| {
+                try_execute_action_in(orderbook, user, action, vec![], &tx_ctx_at(timestamp))
+            };
+
+        // Only the orders within 5% of the 990 mid price earn points, once both sides are quoted
+        send(
+            &mut orderbook,
+            &eth_user,
+            order(OrderType::Sell, 1000, 2),
+            0,
+        )
+        .unwrap();
+        send(
+            &mut orderbook,
+            &eth_user,
+            order(OrderType::Sell, 1200, 5),
+            0,
+        )
+        .unwrap();
+        send(&mut orderbook, &usd_user, order(OrderType::Buy, 980, 1), 0).unwrap();
+        send(
+            &mut orderbook,
+            &eth_user,
+            order(OrderType::Sell, 1300, 1),
+            1000,
+        )
+        .unwrap();
+        let points = &orderbook.liquidity.pairs[&pair];
+        assert_eq!(
+            points.points,
+            BTreeMap::from([(eth_user.clone(), 2 * 1000), (usd_user.clone(), 1000)])
+        );
 
-        // Create a market buy order for 2 ETH
-        let buy_order = Order {
-            owner: usd_user.clone(),
-            order_id: "buy1".to_string(),
-            order_type: OrderType::Buy,
-            price: None, // Market order
-            pair: ("ETH".to_string(), "USD".to_string()),
-            quantity: 2,
-            timestamp: TimestampMs(1),
+        // Without a mid price, nothing is in the band anymore
+        send(
+            &mut orderbook,
+            &usd_user,
+            OrderbookAction::Cancel {
+                order_id: nth_order(2),
+            },
+            3000,
+        )
+        .unwrap();
+        assert!(orderbook.liquidity.pairs[&pair].in_band.is_empty());
+
+        let fund = OrderbookAction::FundIncentives {
+            token: "HYLLAR".to_string(),
+            amount: 100,
         };
+        let err = send(&mut orderbook, &eth_user, fund.clone(), 5000).unwrap_err();
+        assert!(matches!(err, OrderbookError::NotAdmin { .. }));
+        send(&mut orderbook, "admin", fund, 5000).unwrap();
+        assert_eq!(orderbook.get_balance(INCENTIVES_POOL, "HYLLAR"), 100);
+
+        let distribute = OrderbookAction::DistributeIncentives {
+            pair: pair.clone(),
+            token: "HYLLAR".to_string(),
+            amount: 100,
+        };
+        let events = send(&mut orderbook, "admin", distribute.clone(), 5000).unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "HYLLAR"), 66);
+        assert_eq!(orderbook.get_balance(&usd_user, "HYLLAR"), 33);
+        assert_eq!(orderbook.get_balance(INCENTIVES_POOL, "HYLLAR"), 1);
+        assert!(matches!(
+            events.last(),
+            Some(OrderbookEvent::LiquidityRewardsDistributed {
+                epoch: 0,
+                amount: 99,
+                ..
+            })
+        ));
 
-        let events = orderbook.execute_order(buy_order, &TX_CTX).unwrap();
+        // The new epoch starts without any points
+        let err = send(&mut orderbook, "admin", distribute, 6000).unwrap_err();
+        assert_eq!(err, OrderbookError::NoLiquidityPoints { pair, epoch: 1 });
+    }
 
-        assert_eq!(events.len(), 5);
-        let executed_count = events
+    #[test_log::test]
+    fn test_close_account() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::CreateOrder {
+                order_type: OrderType::Sell,
+                price: Some(2000),
+                pair: pair.clone(),
+                quantity: 2,
+                time_in_force: TimeInForce::GoodTilCancelled,
+                trigger_price: None,
+                self_trade_prevention: None,
+                expires_at: None,
+                display_quantity: None,
+            },
+        );
+
+        // The balances must be paid out in the same transaction
+        orderbook.accepted_tokens.insert("ETH".into());
+        let err = try_execute_action(
+            &mut orderbook.clone(),
+            &eth_user,
+            OrderbookAction::CloseAccount,
+            vec![],
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, OrderbookError::MissingPayout { amount: 10, .. }),
+            "{err}"
+        );
+
+        let events = try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::CloseAccount,
+            vec![eth_payout(&eth_user, 10)],
+        )
+        .unwrap();
+        assert!(events.iter().any(|event| matches!(
+            event,
+            OrderbookEvent::OrderCancelled { order_id, .. } if order_id == &nth_order(0)
+        )));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            OrderbookEvent::BalanceUpdated { user, token, amount: 0 } if user == &eth_user && token == "ETH"
+        )));
+
+        assert!(orderbook.orders.is_empty());
+        assert!(!orderbook.balances.contains_key(&eth_user));
+        assert!(!orderbook.latest_deposit.contains_key(&eth_user));
+        assert!(orderbook.balances.contains_key(&usd_user));
+        assert_eq!(orderbook.get_balance(RESERVES, "ETH"), 0);
+
+        // Nothing left to close
+        let err = try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::CloseAccount,
+            vec![],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("No account found"), "{err}");
+    }
+
+    #[test_log::test]
+    fn test_actions_per_block_limit() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+
+        for _ in 0..MAX_ACTIONS_PER_BLOCK {
+            orderbook.record_action(&eth_user, BlockHeight(6)).unwrap();
+        }
+        let err = orderbook
+            .record_action(&eth_user, BlockHeight(6))
+            .unwrap_err();
+        assert!(err.to_string().contains("reached the limit"), "{err}");
+
+        // Other users are not affected
+        orderbook.record_action(&usd_user, BlockHeight(6)).unwrap();
+
+        // The counter is reset in the next block, and the previous one pruned
+        orderbook.record_action(&eth_user, BlockHeight(7)).unwrap();
+        assert_eq!(orderbook.actions_per_block.len(), 1);
+        assert_eq!(orderbook.actions_per_block[&BlockHeight(7)][&eth_user], 1);
+    }
+
+    #[test_log::test]
+    fn test_trade_history_range() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+
+        let order =
+            |owner: &String, order_id: &str, order_type, price, quantity, timestamp| Order {
+                owner: owner.clone(),
+                order_id: order_id.to_string(),
+                order_type,
+                price: Some(price),
+                trigger_price: None,
+                pair: pair.clone(),
+                quantity,
+                timestamp: TimestampMs(timestamp),
+                expires_at: None,
+                display_quantity: None,
+                hidden_quantity: 0,
+                filled_quantity: 0,
+                status: OrderStatus::Open,
+                reserved_amount: 0,
+            };
+
+        // Trades executed at the same timestamp are all kept
+        for next in [
+            order(&eth_user, "sell1", OrderType::Sell, 1000, 1, 0),
+            order(&eth_user, "sell2", OrderType::Sell, 1100, 1, 0),
+            order(&usd_user, "buy1", OrderType::Buy, 1000, 1, 1000),
+            order(&usd_user, "buy2", OrderType::Buy, 1100, 1, 1000),
+            order(&eth_user, "sell3", OrderType::Sell, 900, 1, 2000),
+            order(&usd_user, "buy3", OrderType::Buy, 900, 1, 70_000),
+        ] {
+            let ts = next.timestamp.0;
+            orderbook
+                .execute_order(next, TimeInForce::GoodTilCancelled, &tx_ctx_at(ts))
+                .unwrap();
+        }
+
+        let history = orderbook.get_pair_history("ETH", "USD", None, None);
+        let trades: Vec<_> = history
             .iter()
-            .filter(|e| matches!(e, OrderbookEvent::OrderExecuted { .. }))
-            .count();
-        let balance_count = events
+            .map(|t| (t.timestamp.0, t.price, &t.taker_side))
+            .collect();
+        assert_eq!(
+            trades,
+            vec![
+                (1000, 1000, &OrderType::Buy),
+                (1000, 1100, &OrderType::Buy),
+                (70_000, 900, &OrderType::Buy)
+            ]
+        );
+
+        let history = orderbook.get_pair_history("ETH", "USD", Some(TimestampMs(1001)), None);
+        assert_eq!(
+            history.iter().map(|t| t.timestamp.0).collect::<Vec<_>>(),
+            vec![70_000]
+        );
+        assert!(orderbook
+            .get_pair_history(
+                "ETH",
+                "USD",
+                Some(TimestampMs(1001)),
+                Some(TimestampMs(70_000))
+            )
+            .is_empty());
+
+        let candles =
+            orderbook.get_pair_candles("ETH", "USD", TimestampMs(0), TimestampMs(120_000), 60_000);
+        let candles: Vec<_> = candles
             .iter()
-            .filter(|e| matches!(e, OrderbookEvent::BalanceUpdated { .. }))
-            .count();
-        assert_eq!(executed_count, 1);
-        // eth_user sent ETH
-        // usd_user received ETH
-        // orderbook sent USD
-        // eth_user received USD
-        assert_eq!(balance_count, 4);
+            .map(|c| {
+                (
+                    c.timestamp.0,
+                    c.open,
+                    c.high,
+                    c.low,
+                    c.close,
+                    c.volume,
+                    c.quote_volume,
+                )
+            })
+            .collect();
+        assert_eq!(
+            candles,
+            vec![
+                (0, 1000, 1100, 1000, 1100, 2, 2100),
+                (60_000, 900, 900, 900, 900, 1, 900)
+            ]
+        );
+    }
 
-        // Assert orderbook is empty
-        assert_eq!(orderbook.orders.len(), 0);
+    #[test_log::test]
+    fn test_withdraw_to_recipient() {
+        let (eth_user, _, mut orderbook) = setup();
+        orderbook.accepted_tokens.insert("ETH".into());
 
+        let withdraw = |recipient: Option<&str>| OrderbookAction::Withdraw {
+            token: "ETH".to_string(),
+            amount: 4,
+            recipient: recipient.map(str::to_string),
+        };
 
-        // Check that balances haven't changed
-        let eth_user_balances = orderbook.balances.get(&eth_user).unwrap();
-        let usd_user_balances = orderbook.balances.get(&usd_user).unwrap();
-        let orderbook_balances = orderbook.balances.get("orderbook").unwrap();
+        let err = try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            withdraw(Some("cold@wallet")),
+            vec![],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("No transfer of 4 ETH"), "{err}");
+        let payouts = vec![eth_payout(&eth_user, 4)];
+        let err = try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            withdraw(Some("cold@wallet")),
+            payouts,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("No transfer of 4 ETH"), "{err}");
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 10);
+
+        let payouts = vec![eth_payout("cold@wallet", 4)];
+        try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            withdraw(Some("cold@wallet")),
+            payouts,
+        )
+        .unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 6);
+
+        // Without a recipient, the tokens must be sent back to the caller
+        let err =
+            try_execute_action(&mut orderbook, &eth_user, withdraw(None), vec![]).unwrap_err();
+        assert!(err.to_string().contains("No transfer of 4 ETH"), "{err}");
+        let payouts = vec![eth_payout(&eth_user, 4)];
+        try_execute_action(&mut orderbook, &eth_user, withdraw(None), payouts).unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 2);
+
+        // Each withdrawal of a batch needs its own payout
+        let batch = OrderbookAction::Batch(vec![withdraw(None), withdraw(None)]);
+        set_balance(&mut orderbook, &eth_user, "ETH", 10);
+        let err = try_execute_action(
+            &mut orderbook.clone(),
+            &eth_user,
+            batch.clone(),
+            vec![eth_payout(&eth_user, 4)],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("No transfer of 4 ETH"), "{err}");
+        let payout = || {
+            blobs::payout_blob(
+                "ETH".into(),
+                &"orderbook".into(),
+                eth_user.as_str().into(),
+                4,
+                sdk::BlobIndex(2),
+            )
+        };
+        try_execute_action(&mut orderbook, &eth_user, batch, vec![payout(), payout()]).unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 2);
+    }
 
-        assert_eq!(*eth_user_balances.get("ETH").unwrap(), 9); // eth_user sold 1 ETH ...
-        assert_eq!(*eth_user_balances.get("USD").unwrap(), 2000); // .. for 2000 USD
+    #[test_log::test]
+    fn test_deposit_from_wallet() {
+        let (eth_user, _, mut orderbook) = setup();
+        orderbook.accepted_tokens.insert("ETH".into());
+        let deposit = |transfer| OrderbookAction::DepositFromWallet {
+            token: "ETH".to_string(),
+            amount: 5,
+            transfer: sdk::BlobIndex(transfer),
+        };
+        let transfer = |sender: &str, amount| {
+            blobs::deposit_blob("ETH".into(), sender.into(), &"orderbook".into(), amount)
+        };
 
-        assert_eq!(*usd_user_balances.get("ETH").unwrap(), 1); // usd_user bought 1 ETH ...
-        assert_eq!(*usd_user_balances.get("USD").unwrap(), 1000); // .. for 2000 USD
+        let err = try_execute_action(&mut orderbook, &eth_user, deposit(0), vec![]).unwrap_err();
+        assert!(
+            err.to_string().contains("is the orderbook blob itself"),
+            "{err}"
+        );
+        for blob in [
+            transfer(&eth_user, 4),
+            transfer("usd_user", 5),
+            eth_payout(&eth_user, 5),
+        ] {
+            let err =
+                try_execute_action(&mut orderbook, &eth_user, deposit(0), vec![blob]).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("is not a transfer of 5 ETH from eth_user to orderbook"),
+                "{err}"
+            );
+        }
 
-        assert_eq!(*orderbook_balances.get("ETH").unwrap_or(&0), 0); // orderbook is empty
-        assert_eq!(*orderbook_balances.get("USD").unwrap_or(&0), 0); // orderbook is empty
+        try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            deposit(0),
+            vec![transfer(&eth_user, 5)],
+        )
+        .unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 15);
+
+        // A transfer cannot back two deposits of a batch
+        let batch = OrderbookAction::Batch(vec![deposit(0), deposit(0)]);
+        let err = try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            batch,
+            vec![transfer(&eth_user, 5)],
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Transfer blob 0 backs several deposits"),
+            "{err}"
+        );
     }
 
     #[test_log::test]
-    fn test_order_execution_blocked_after_recent_deposit() {
+    fn test_deposit_requires_transfer() {
         let (eth_user, _, mut orderbook) = setup();
-        
-        // Set a more recent deposit block height for eth_user
-        *orderbook.get_latest_deposit_mut(&eth_user, "ETH") = BlockHeight(4);
+        orderbook.accepted_tokens.insert("ETH".into());
+        let deposit = OrderbookAction::Deposit {
+            token: "ETH".to_string(),
+            amount: 5,
+        };
 
-        // Try to create a sell order when deposit was too recent
-        let sell_order = Order {
+        for companions in [
+            vec![],
+            vec![eth_deposit(&eth_user, 4)],
+            vec![eth_deposit("usd_user", 5)],
+        ] {
+            let err = try_execute_action(&mut orderbook, &eth_user, deposit.clone(), companions)
+                .unwrap_err();
+            assert!(
+                matches!(err, OrderbookError::MissingTransfer { .. }),
+                "{err}"
+            );
+        }
+        try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            deposit.clone(),
+            vec![eth_deposit(&eth_user, 5)],
+        )
+        .unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 15);
+
+        // Each deposit of a batch needs its own transfer
+        let batch = OrderbookAction::Batch(vec![deposit.clone(), deposit.clone()]);
+        let err = try_execute_action(
+            &mut orderbook.clone(),
+            &eth_user,
+            batch.clone(),
+            vec![eth_deposit(&eth_user, 5)],
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, OrderbookError::MissingTransfer { .. }),
+            "{err}"
+        );
+        try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            batch,
+            vec![eth_deposit(&eth_user, 5), eth_deposit(&eth_user, 5)],
+        )
+        .unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 25);
+    }
+
+    #[test_log::test]
+    fn test_withdrawal_delay() {
+        let (eth_user, _, orderbook) = setup();
+        let mut orderbook = orderbook.with_withdrawal_delay(10);
+        orderbook.accepted_tokens.insert("ETH".into());
+        let request = OrderbookAction::RequestWithdraw {
+            token: "ETH".to_string(),
+            amount: 4,
+            recipient: Some("cold@wallet".to_string()),
+        };
+        let claim = |withdrawal_id| OrderbookAction::ClaimWithdraw { withdrawal_id };
+        let in_block = |height| sdk::TxContext {
+            block_height: sdk::BlockHeight(height),
+            ..TX_CTX.clone()
+        };
+
+        let withdraw = OrderbookAction::Withdraw {
+            token: "ETH".to_string(),
+            amount: 4,
+            recipient: None,
+        };
+        let err = try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            withdraw,
+            vec![eth_payout(&eth_user, 4)],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("must be requested"), "{err}");
+
+        try_execute_action(&mut orderbook, &eth_user, request.clone(), vec![]).unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 6);
+        let pending: Vec<_> = orderbook
+            .pending_withdrawals_of(&eth_user)
+            .map(|(id, w)| (*id, w.claimable_at.0))
+            .collect();
+        assert_eq!(pending, vec![(0, 16)]);
+        // Optimistic executions don't know the block the request is sequenced in
+        let mut optimistic = orderbook.clone();
+        optimistic
+            .pending_withdrawals
+            .get_mut(&0)
+            .unwrap()
+            .claimable_at = sdk::BlockHeight(10);
+        assert_eq!(optimistic.partial_commit().0, orderbook.partial_commit().0);
+
+        let payouts = vec![eth_payout("cold@wallet", 4)];
+        let err = try_execute_action_in(
+            &mut orderbook,
+            &eth_user,
+            claim(0),
+            payouts.clone(),
+            &in_block(15),
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("can only be claimed from block 16"),
+            "{err}"
+        );
+        let err = try_execute_action_in(
+            &mut orderbook,
+            "admin",
+            claim(0),
+            payouts.clone(),
+            &in_block(16),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("is not owned by admin"), "{err}");
+        try_execute_action_in(&mut orderbook, &eth_user, claim(0), payouts, &in_block(16)).unwrap();
+        assert_eq!(orderbook.pending_withdrawals_of(&eth_user).count(), 0);
+        assert_eq!(orderbook.deposits["ETH"], 6);
+
+        // The admin stops a suspicious withdrawal before it can be claimed
+        try_execute_action(&mut orderbook, &eth_user, request, vec![]).unwrap();
+        let err = try_execute_action(
+            &mut orderbook,
+            "usd_user",
+            OrderbookAction::CancelWithdraw { withdrawal_id: 1 },
+            vec![],
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                OrderbookError::NotWithdrawalOwner {
+                    withdrawal_id: 1,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        try_execute_action(
+            &mut orderbook,
+            "admin",
+            OrderbookAction::CancelWithdraw { withdrawal_id: 1 },
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 6);
+        let err = try_execute_action_in(&mut orderbook, &eth_user, claim(1), vec![], &in_block(20))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("No pending withdrawal with id 1"),
+            "{err}"
+        );
+
+        // Closing the account requests the withdrawal of its balances
+        try_execute_action(
+            &mut orderbook,
+            &eth_user,
+            OrderbookAction::CloseAccount,
+            vec![],
+        )
+        .unwrap();
+        let pending: Vec<_> = orderbook
+            .pending_withdrawals_of(&eth_user)
+            .map(|(_, w)| (w.token.as_str(), w.amount))
+            .collect();
+        assert_eq!(pending, vec![("ETH", 6)]);
+    }
+
+    #[test_log::test]
+    fn test_pro_rata_matching() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let other_eth_user = "other_eth_user".to_string();
+        orderbook.balances.insert(
+            other_eth_user.clone(),
+            BTreeMap::from([("ETH".to_string(), 10)]),
+        );
+
+        let config = market::MarketConfig {
+            matching: market::MatchingMode::ProRata,
+            ..Default::default()
+        };
+        let err = orderbook
+            .configure_market(pair.clone(), config.clone(), eth_user.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("not the orderbook admin"), "{err}");
+        orderbook
+            .configure_market(pair.clone(), config.clone(), "admin".to_string())
+            .unwrap();
+        assert_eq!(orderbook.market_config(&pair), config);
+
+        let order = |owner: &String, order_id: &str, order_type, quantity| Order {
+            owner: owner.clone(),
+            order_id: order_id.to_string(),
+            order_type,
+            price: Some(500),
+            trigger_price: None,
+            pair: pair.clone(),
+            quantity,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        orderbook
+            .execute_order(
+                order(&eth_user, "sell1", OrderType::Sell, 2),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        orderbook
+            .execute_order(
+                order(&other_eth_user, "sell2", OrderType::Sell, 6),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+
+        // FIFO would fill sell1 entirely, pro-rata fills both orders by a quarter
+        let events = orderbook
+            .execute_order(
+                order(&usd_user, "buy1", OrderType::Buy, 4),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        assert!(
+            matches!(
+                &events[..4],
+                [
+                    OrderbookEvent::TradeExecuted { quantity: 1, .. },
+                    OrderbookEvent::OrderUpdate {
+                        remaining_quantity: 1,
+                        ..
+                    },
+                    OrderbookEvent::TradeExecuted { quantity: 3, .. },
+                    OrderbookEvent::OrderUpdate {
+                        remaining_quantity: 3,
+                        ..
+                    },
+                ]
+            ),
+            "{events:?}"
+        );
+        assert_eq!(orderbook.orders["sell1"].quantity, 1);
+        assert_eq!(orderbook.orders["sell2"].quantity, 3);
+        assert!(!orderbook.orders.contains_key("buy1"));
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 500);
+        assert_eq!(orderbook.get_balance(&other_eth_user, "USD"), 1500);
+        assert_eq!(orderbook.get_balance(&usd_user, "ETH"), 4);
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 1000);
+    }
+
+    #[test_log::test]
+    fn test_market_order_rules() {
+        let (eth_user, _, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let config = market::MarketConfig {
+            min_quantity: 2,
+            tick_size: 10,
+            ..Default::default()
+        };
+        orderbook
+            .configure_market(pair.clone(), config, "admin".to_string())
+            .unwrap();
+        let order = |order_id: &str, price, trigger_price| Order {
             owner: eth_user.clone(),
-            order_id: "sell1".to_string(), 
+            order_id: order_id.to_string(),
             order_type: OrderType::Sell,
-            price: Some(2000),
-            pair: ("ETH".to_string(), "USD".to_string()),
+            price: Some(price),
+            trigger_price,
+            pair: pair.clone(),
+            quantity: 2,
+            timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+
+        let err = orderbook
+            .execute_order(
+                order("sell1", 2005, None),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("tick size"), "{err}");
+        let err = orderbook
+            .place_stop_order(
+                order("stop1", 2005, Some(1000)),
+                TimeInForce::GoodTilCancelled,
+                None,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("tick size"), "{err}");
+
+        orderbook
+            .execute_order(
+                order("sell1", 2000, None),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        let err = orderbook
+            .modify_order("sell1".to_string(), 2000, 1, eth_user.clone(), &TX_CTX)
+            .unwrap_err();
+        assert!(err.to_string().contains("below the minimum"), "{err}");
+        let err = orderbook
+            .modify_order("sell1".to_string(), 2001, 2, eth_user.clone(), &TX_CTX)
+            .unwrap_err();
+        assert!(err.to_string().contains("tick size"), "{err}");
+        assert_eq!(orderbook.orders["sell1"].price, Some(2000));
+    }
+
+    #[test_log::test]
+    fn test_taker_price_policies() {
+        let (eth_user, usd_user, mut orderbook) = setup();
+        let pair = ("ETH".to_string(), "USD".to_string());
+        let configure = |orderbook: &mut Orderbook, taker_price| {
+            let config = market::MarketConfig {
+                taker_price,
+                ..Default::default()
+            };
+            orderbook
+                .configure_market(pair.clone(), config, "admin".to_string())
+                .unwrap();
+        };
+        let order = |owner: &String, order_id: &str, order_type, price| Order {
+            owner: owner.clone(),
+            order_id: order_id.to_string(),
+            order_type,
+            price: Some(price),
+            trigger_price: None,
+            pair: pair.clone(),
             quantity: 1,
             timestamp: TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        let executed_prices = |events: &[OrderbookEvent]| -> Vec<u128> {
+            events
+                .iter()
+                .filter_map(|event| match event {
+                    OrderbookEvent::OrderExecuted { executed_price, .. } => *executed_price,
+                    _ => None,
+                })
+                .collect()
         };
 
-        // Execute order with tx_ctx at block height 6 (< deposit block + 5)
-        let result = orderbook.execute_order(sell_order, &TX_CTX);
+        // The buyer pays its limit price, the seller gets its own and the surplus is kept
+        configure(&mut orderbook, market::TakerPricePolicy::FeePool);
+        orderbook
+            .execute_order(
+                order(&eth_user, "sell1", OrderType::Sell, 2000),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        let events = orderbook
+            .execute_order(
+                order(&usd_user, "buy1", OrderType::Buy, 2100),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        assert_eq!(executed_prices(&events), vec![2000, 2100]);
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 2000);
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 900);
+        assert_eq!(orderbook.get_balance(FEE_POOL, "USD"), 100);
+
+        // The buyer locked 2000 for its order, and gets refunded what the mid price saves it
+        configure(&mut orderbook, market::TakerPricePolicy::MidPrice);
+        orderbook
+            .execute_order(
+                order(&usd_user, "buy2", OrderType::Buy, 200),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        orderbook
+            .execute_order(
+                order(&eth_user, "sell2", OrderType::Sell, 100),
+                TimeInForce::GoodTilCancelled,
+                &TX_CTX,
+            )
+            .unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "USD"), 2150);
+        assert_eq!(orderbook.get_balance(&usd_user, "USD"), 750);
+        assert_eq!(orderbook.get_balance(RESERVES, "USD"), 0);
+        let prices: Vec<_> = orderbook
+            .get_pair_history("ETH", "USD", None, None)
+            .iter()
+            .map(|t| t.price)
+            .collect();
+        assert_eq!(prices, vec![2000, 150]);
+    }
 
-        // Should fail because not enough blocks have passed since deposit
-        assert!(result.is_err());
-        let err = result.unwrap_err();
-        assert!(err.contains("too soon after the last deposit"));
-        assert!(err.contains("5 blocks are required"));
+    #[test_log::test]
+    fn test_identity_verification() {
+        let (_, _, orderbook) = setup();
+        let mut orderbook = orderbook.with_identity_verification(IdentityVerification::Wallet);
+        let alice = "alice@wallet";
+        set_balance(&mut orderbook, alice, "ETH", 2);
+        let wallet_blob = sdk::Blob {
+            contract_name: "wallet".into(),
+            data: sdk::BlobData(vec![]),
+        };
 
-        // Check no balances were modified
-        let eth_user_eth = orderbook.balances.get(&eth_user).unwrap().get("ETH").unwrap();
-        assert_eq!(*eth_user_eth, 10); // Original balance unchanged
+        let sell = create_order(OrderType::Sell, Some(2000), None);
+        let err = try_execute_action(&mut orderbook, alice, sell.clone(), vec![]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("No blob of the wallet of alice@wallet"),
+            "{err}"
+        );
+        try_execute_action(&mut orderbook, alice, sell.clone(), vec![wallet_blob]).unwrap();
+
+        // Key-based identities can sign the orderbook blob instead
+        let mut orderbook =
+            orderbook.with_identity_verification(IdentityVerification::WalletOrSecp256k1);
+        let public_key = [2; 33];
+        let signer = format!("{}@secp256k1", hex::encode(public_key));
+        set_balance(&mut orderbook, &signer, "ETH", 1);
+        let signature = |identity: &str, orderbook: &Orderbook| {
+            let blob = sell.as_blob("orderbook".into(), orderbook.last_nonce(identity) + 1);
+            let signature = Secp256k1Blob {
+                identity: sdk::Identity(identity.to_string()),
+                data: Sha256::digest(&blob.data.0).into(),
+                public_key,
+                signature: [3; 64],
+            };
+            sdk::Blob {
+                contract_name: "secp256k1".into(),
+                data: sdk::BlobData(borsh::to_vec(&signature).unwrap()),
+            }
+        };
+        let blob = signature(alice, &orderbook);
+        let err = try_execute_action(&mut orderbook, alice, sell.clone(), vec![blob]).unwrap_err();
+        assert!(
+            err.to_string().contains("is not the key of its signature"),
+            "{err}"
+        );
+        let err = try_execute_action(&mut orderbook, &signer, sell.clone(), vec![]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("No wallet blob or secp256k1 signature"),
+            "{err}"
+        );
+        let blob = signature(&signer, &orderbook);
+        try_execute_action(&mut orderbook, &signer, sell, vec![blob]).unwrap();
+        assert_eq!(
+            orderbook.order_ids_of(&signer).map(|ids| ids.len()),
+            Some(1)
+        );
+    }
 
-        // Check no orders were created
-        assert_eq!(orderbook.orders.len(), 0);
+    #[test_log::test]
+    fn test_submit_signed_order() {
+        let (_, relayer, mut orderbook) = setup();
+        let public_key = [2; 33];
+        let owner = format!("{}@secp256k1", hex::encode(public_key));
+        set_balance(&mut orderbook, &owner, "ETH", 2);
+        let sell = create_order(OrderType::Sell, Some(2000), None);
+        let signed = |owner: &str, nonce: u64, order: &OrderbookAction, signature: [u8; 64]| {
+            let action = OrderbookAction::SubmitSignedOrder {
+                owner: owner.to_string(),
+                nonce,
+                order: Box::new(order.clone()),
+                signature: vec![3; 64],
+            };
+            let blob = Secp256k1Blob {
+                identity: sdk::Identity(owner.to_string()),
+                data: Orderbook::signed_order_digest(owner, nonce, order),
+                public_key,
+                signature,
+            };
+            let blob = sdk::Blob {
+                contract_name: "secp256k1".into(),
+                data: sdk::BlobData(borsh::to_vec(&blob).unwrap()),
+            };
+            (action, vec![blob])
+        };
+
+        // The relayer creates the order of the owner
+        let (action, blobs) = signed(&owner, 1, &sell, [3; 64]);
+        try_execute_action(&mut orderbook, &relayer, action.clone(), blobs.clone()).unwrap();
+        assert_eq!(orderbook.orders[&nth_order(0)].owner, owner);
+        assert_eq!(orderbook.last_nonce(&owner), 1);
+        let err = try_execute_action(&mut orderbook, &relayer, action, blobs).unwrap_err();
+        assert!(
+            err.to_string().contains("is not above its last nonce"),
+            "{err}"
+        );
+
+        let (action, blobs) = signed(&owner, 2, &sell, [4; 64]);
+        let err = try_execute_action(&mut orderbook, &relayer, action, blobs).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("does not match its secp256k1 blob"),
+            "{err}"
+        );
+        let (action, blobs) = signed(&relayer, 2, &sell, [3; 64]);
+        let err = try_execute_action(&mut orderbook, &relayer, action, blobs).unwrap_err();
+        assert!(
+            err.to_string().contains("is not the key of its signature"),
+            "{err}"
+        );
+        let cancel = OrderbookAction::Cancel {
+            order_id: nth_order(0),
+        };
+        let (action, blobs) = signed(&owner, 2, &cancel, [3; 64]);
+        let err = try_execute_action(&mut orderbook, &relayer, action, blobs).unwrap_err();
+        assert!(
+            err.to_string().contains("must be created with CreateOrder"),
+            "{err}"
+        );
+    }
+
+    #[test_log::test]
+    fn test_register_with_invite_code() {
+        let (eth_user, _, orderbook) = setup();
+        let mut orderbook = orderbook.with_invite_key(vec![2; 33]);
+
+        let register = |public_key: [u8; 33], signature: [u8; 64], nonce: u64| {
+            let invite = Secp256k1Blob {
+                identity: sdk::Identity(eth_user.clone()),
+                data: Orderbook::invite_code_digest(&eth_user),
+                public_key,
+                signature,
+            };
+            let action = OrderbookAction::Register {
+                invite_code_signature: vec![3; 64],
+            };
+            let blobs = vec![
+                sdk::Blob {
+                    contract_name: "secp256k1".into(),
+                    data: sdk::BlobData(borsh::to_vec(&invite).unwrap()),
+                },
+                action.as_blob("orderbook".into(), nonce),
+            ];
+            sdk::Calldata {
+                tx_hash: sdk::TxHash(String::new()),
+                identity: sdk::Identity(eth_user.clone()),
+                tx_blob_count: blobs.len(),
+                index: sdk::BlobIndex(1),
+                blobs: blobs.into(),
+                tx_ctx: Some(TX_CTX.clone()),
+                private_input: vec![],
+            }
+        };
+
+        let err = orderbook
+            .deposit("ETH".to_string(), 1, eth_user.clone(), &TX_CTX)
+            .unwrap_err();
+        assert!(err.to_string().contains("must register"), "{err}");
+
+        let err = execute_err(&mut orderbook, &register([1; 33], [3; 64], 1));
+        assert!(
+            err.to_string()
+                .contains("not signed by the orderbook invite key"),
+            "{err}"
+        );
+        let err = execute_err(&mut orderbook, &register([2; 33], [4; 64], 2));
+        assert!(
+            matches!(err, OrderbookError::InvalidInviteCode { .. }),
+            "{err:?}"
+        );
+
+        sdk::ZkContract::execute(&mut orderbook, &register([2; 33], [3; 64], 3)).unwrap();
+        orderbook
+            .deposit("ETH".to_string(), 1, eth_user.clone(), &TX_CTX)
+            .unwrap();
+        assert_eq!(orderbook.get_balance(&eth_user, "ETH"), 11);
+
+        let err = orderbook
+            .register(
+                eth_user.clone(),
+                vec![3; 64],
+                &Secp256k1Blob {
+                    identity: sdk::Identity(eth_user.clone()),
+                    data: Orderbook::invite_code_digest(&eth_user),
+                    public_key: [2; 33],
+                    signature: [3; 64],
+                },
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("already registered"), "{err}");
+    }
+
+    #[test_log::test]
+    fn test_snapshot_roundtrip() {
+        let (eth_user, _, mut orderbook) = setup();
+        execute_action(
+            &mut orderbook,
+            &eth_user,
+            create_order(OrderType::Sell, Some(2000), None),
+        );
+
+        let bytes = orderbook.export_snapshot().unwrap();
+        let imported = Orderbook::import_snapshot(&bytes).unwrap();
+        assert_eq!(imported.state_commitment(), orderbook.state_commitment());
+
+        // Snapshots of an unknown layout are rejected
+        let mut snapshot: snapshot::Snapshot = borsh::from_slice(&bytes).unwrap();
+        snapshot.header.version = snapshot::SNAPSHOT_VERSION + 1;
+        let err = Orderbook::import_snapshot(&borsh::to_vec(&snapshot).unwrap()).unwrap_err();
+        assert_eq!(
+            err,
+            OrderbookError::UnsupportedSnapshotVersion {
+                version: snapshot::SNAPSHOT_VERSION + 1
+            }
+        );
+
+        // So are snapshots of other data, and states not matching their commitment
+        let mut snapshot: snapshot::Snapshot = borsh::from_slice(&bytes).unwrap();
+        snapshot.header.schema = *b"hyli-wt\0";
+        let err = Orderbook::import_snapshot(&borsh::to_vec(&snapshot).unwrap()).unwrap_err();
+        assert!(
+            err.to_string().contains("not an orderbook snapshot"),
+            "{err}"
+        );
+        let mut snapshot: snapshot::Snapshot = borsh::from_slice(&bytes).unwrap();
+        snapshot.header.commitment = vec![0; 32];
+        let err = Orderbook::import_snapshot(&borsh::to_vec(&snapshot).unwrap()).unwrap_err();
+        assert!(
+            err.to_string().contains("does not match its commitment"),
+            "{err}"
+        );
+        assert!(Orderbook::import_snapshot(&bytes[..bytes.len() - 1]).is_err());
     }
 }
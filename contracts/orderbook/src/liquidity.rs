@@ -0,0 +1,226 @@
+//! Liquidity mining. Makers earn points on each pair for the liquidity they show close to its
+//! mid price, see [`MarketConfig::incentive_band_bps`](crate::market::MarketConfig), and the
+//! admin rewards them from the [`INCENTIVES_POOL`] at the end of each epoch of the pair.
+//!
+//! The book of a pair only changes with transactions, so the points are accrued after every
+//! transaction changing it, for the liquidity that was in the band since the previous one.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use sdk::hyle_model_utils::TimestampMs;
+
+use crate::{
+    error::OrderbookError, mul_div, OrderType, Orderbook, OrderbookEvent, TokenPair,
+    INCENTIVES_POOL,
+};
+
+const BPS: u128 = 10_000;
+
+/// Points of the makers of a pair during its current epoch
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub struct PairPoints {
+    /// Current epoch of the pair, incremented at each distribution
+    pub epoch: u64,
+    /// Points of each maker: the base quantity it showed in the band, multiplied by the time it
+    /// stayed there, in ms
+    pub points: BTreeMap<String, u128>,
+    /// Base quantity of each maker in the band since the last update
+    pub in_band: BTreeMap<String, u128>,
+    /// Last time the points were accrued
+    pub last_update: TimestampMs,
+}
+
+/// Liquidity points of the pairs whose makers earned some
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub struct LiquidityPoints {
+    pub pairs: BTreeMap<TokenPair, PairPoints>,
+}
+
+impl PairPoints {
+    fn accrue(&mut self, now: &TimestampMs) {
+        let elapsed = now.0.saturating_sub(self.last_update.0);
+        for (maker, quantity) in &self.in_band {
+            let points = self.points.entry(maker.clone()).or_default();
+            *points = points.saturating_add(quantity.saturating_mul(elapsed));
+        }
+        self.last_update = now.clone();
+    }
+}
+
+impl LiquidityPoints {
+    /// Accrues the points of `pair` up to `now`, then records the quantities its makers now
+    /// show in the band
+    pub fn update(&mut self, pair: &TokenPair, in_band: BTreeMap<String, u128>, now: &TimestampMs) {
+        if in_band.is_empty() && !self.pairs.contains_key(pair) {
+            return;
+        }
+        let points = self
+            .pairs
+            .entry(pair.clone())
+            .or_insert_with(|| PairPoints {
+                last_update: now.clone(),
+                ..Default::default()
+            });
+        points.accrue(now);
+        points.in_band = in_band;
+    }
+
+    /// Splits `amount` between the makers of `pair`, pro-rata to their points up to `now`
+    pub fn shares(
+        &mut self,
+        pair: &TokenPair,
+        amount: u128,
+        now: &TimestampMs,
+    ) -> BTreeMap<String, u128> {
+        let Some(points) = self.pairs.get_mut(pair) else {
+            return BTreeMap::new();
+        };
+        points.accrue(now);
+        let total = (points.points.values()).fold(0, |total: u128, p| total.saturating_add(*p));
+        (points.points.iter())
+            .map(|(maker, points)| (maker.clone(), mul_div(amount, *points, total)))
+            .filter(|(_, share)| *share > 0)
+            .collect()
+    }
+
+    /// Starts a new epoch of `pair`. Returns the closed epoch.
+    pub fn close_epoch(&mut self, pair: &TokenPair) -> u64 {
+        let points = self.pairs.entry(pair.clone()).or_default();
+        points.points.clear();
+        points.epoch += 1;
+        points.epoch - 1
+    }
+
+    /// Current epoch of `pair`
+    pub fn epoch(&self, pair: &TokenPair) -> u64 {
+        self.pairs.get(pair).map_or(0, |points| points.epoch)
+    }
+}
+
+impl Orderbook {
+    /// Mid price of a pair, if both sides of its book are quoted
+    pub fn mid_price(&self, pair: &TokenPair) -> Option<u128> {
+        match self.best_prices(pair) {
+            (Some(bid), Some(ask)) => Some(bid + ask.saturating_sub(bid) / 2),
+            _ => None,
+        }
+    }
+
+    /// Visible base quantity of each maker of `pair` priced within its incentive band
+    fn quantities_in_band(&self, pair: &TokenPair) -> BTreeMap<String, u128> {
+        let band_bps = self.market_config(pair).incentive_band_bps;
+        let mut in_band = BTreeMap::new();
+        let Some(mid) = self.mid_price(pair).filter(|_| band_bps > 0) else {
+            return in_band;
+        };
+        let max_deviation = mul_div(mid, band_bps.into(), BPS);
+        let sides = [
+            (self.buy_orders.get(pair), OrderType::Buy),
+            (self.sell_orders.get(pair), OrderType::Sell),
+        ];
+        let order_ids = sides.iter().flat_map(|(levels, order_type)| {
+            (levels.iter()).flat_map(move |levels| levels.order_ids(order_type))
+        });
+        for order in order_ids.filter_map(|order_id| self.orders.get(order_id)) {
+            if order
+                .price
+                .is_some_and(|price| price.abs_diff(mid) <= max_deviation)
+            {
+                let quantity: &mut u128 = in_band.entry(order.owner.clone()).or_default();
+                *quantity = quantity.saturating_add(order.visible_quantity());
+            }
+        }
+        in_band
+    }
+
+    /// Accrues the liquidity points of the `pairs` whose book changed at `now`
+    pub(crate) fn accrue_liquidity_points(
+        &mut self,
+        pairs: &BTreeSet<TokenPair>,
+        now: &TimestampMs,
+    ) {
+        for pair in pairs {
+            let in_band = self.quantities_in_band(pair);
+            self.liquidity.update(pair, in_band, now);
+        }
+    }
+
+    /// Moves `amount` of `token` from the admin's balance to the [`INCENTIVES_POOL`]
+    pub fn fund_incentives(
+        &mut self,
+        token: String,
+        amount: u128,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+
+        self.transfer_tokens(&user, INCENTIVES_POOL, &token, amount)?;
+        Ok(vec![
+            OrderbookEvent::BalanceUpdated {
+                user: INCENTIVES_POOL.to_string(),
+                token: token.clone(),
+                amount: self.get_balance(INCENTIVES_POOL, &token),
+            },
+            OrderbookEvent::BalanceUpdated {
+                amount: self.get_balance(&user, &token),
+                user,
+                token,
+            },
+        ])
+    }
+
+    /// Distributes `amount` of `token` from the [`INCENTIVES_POOL`] to the makers of `pair`,
+    /// pro-rata to their liquidity points, and starts a new epoch of the pair
+    pub fn distribute_incentives(
+        &mut self,
+        pair: TokenPair,
+        token: String,
+        amount: u128,
+        user: String,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        if user != self.admin {
+            return Err(OrderbookError::NotAdmin { user });
+        }
+
+        let shares = self.liquidity.shares(&pair, amount, &tx_ctx.timestamp);
+        if shares.is_empty() {
+            return Err(OrderbookError::NoLiquidityPoints {
+                epoch: self.liquidity.epoch(&pair),
+                pair,
+            });
+        }
+
+        let mut events = vec![];
+        for (maker, share) in &shares {
+            self.transfer_tokens(INCENTIVES_POOL, maker, &token, *share)?;
+            events.push(OrderbookEvent::BalanceUpdated {
+                user: maker.clone(),
+                token: token.clone(),
+                amount: self.get_balance(maker, &token),
+            });
+        }
+        events.push(OrderbookEvent::BalanceUpdated {
+            user: INCENTIVES_POOL.to_string(),
+            token: token.clone(),
+            amount: self.get_balance(INCENTIVES_POOL, &token),
+        });
+
+        let epoch = self.liquidity.close_epoch(&pair);
+        events.push(OrderbookEvent::LiquidityRewardsDistributed {
+            pair,
+            epoch,
+            token,
+            amount: shares.values().sum(),
+        });
+        Ok(events)
+    }
+}
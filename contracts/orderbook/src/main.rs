@@ -1,6 +1,6 @@
 #![no_main]
 
-use orderbook::Orderbook;
+use orderbook::witness::ZkOrderbook;
 use sdk::{
     guest::{execute, GuestEnv, SP1Env},
     Calldata,
@@ -12,6 +12,6 @@ fn main() {
     let env = SP1Env {};
     let (commitment_metadata, calldata): (Vec<u8>, Vec<Calldata>) = env.read();
 
-    let output = execute::<Orderbook>(&commitment_metadata, &calldata);
+    let output = execute::<ZkOrderbook>(&commitment_metadata, &calldata);
     env.commit(output);
 }
@@ -0,0 +1,534 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+
+use crate::{
+    error::OrderbookError, history::HistoryRetention, mul_div, quote_amount, Order, Rounding,
+    SelfTradePrevention, TimeInForce,
+};
+
+/// Trading parameters of a pair, set by the admin
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub struct MarketConfig {
+    /// How taker orders are allocated between the resting orders of the best price level
+    pub matching: MatchingMode,
+    /// Where the difference between a taker's limit price and the maker's price goes
+    pub taker_price: TakerPricePolicy,
+    /// Smallest quantity of an order, in base token units. 0 allows any quantity.
+    pub min_quantity: u128,
+    /// Smallest quote token amount of a limit order. 0 allows any amount.
+    pub min_notional: u128,
+    /// Prices must be a multiple of it. 0 allows any price.
+    pub tick_size: u128,
+    /// Quantities must be a multiple of it. 0 allows any quantity.
+    pub lot_size: u128,
+    /// Limit orders priced further than this from the book, in basis points of its mid price,
+    /// are rejected. 0 disables the band.
+    pub price_band_bps: u16,
+    /// Makes the pair a batch-auction market: orders are not matched on arrival, but all at
+    /// once at a single price, at most once per interval, see [`clearing_price`]. 0 matches
+    /// orders continuously.
+    pub auction_interval_ms: u128,
+    /// Trade history kept by the contract
+    pub history: HistoryRetention,
+    /// Resting orders priced within this from the mid price, in basis points of it, earn
+    /// liquidity points, see [`crate::liquidity`]. 0 disables them.
+    pub incentive_band_bps: u16,
+    /// Most orders a user can have open on the pair, stop orders included. 0 allows any number.
+    pub max_open_orders: u32,
+    /// Most quote token amount the resting orders of a user on the pair can be worth. 0 allows
+    /// any amount.
+    pub max_user_notional: u128,
+}
+
+impl MarketConfig {
+    /// Checks the price and quantity of an incoming order, `scale` being the number of base
+    /// token units in a whole token
+    pub fn check_order(&self, order: &Order, scale: u128) -> Result<(), OrderbookError> {
+        if order.quantity < self.min_quantity {
+            return Err(OrderbookError::QuantityBelowMinimum {
+                order_id: order.order_id.clone(),
+                quantity: order.quantity,
+                min_quantity: self.min_quantity,
+            });
+        }
+        if self.lot_size != 0 && order.quantity % self.lot_size != 0 {
+            return Err(OrderbookError::InvalidLotSize {
+                order_id: order.order_id.clone(),
+                quantity: order.quantity,
+                lot_size: self.lot_size,
+            });
+        }
+        let Some(price) = order.price else {
+            return Ok(());
+        };
+        if self.tick_size != 0 && price % self.tick_size != 0 {
+            return Err(OrderbookError::InvalidTickSize {
+                order_id: order.order_id.clone(),
+                price,
+                tick_size: self.tick_size,
+            });
+        }
+        let notional = quote_amount(order.quantity, price, scale, Rounding::Down)?;
+        if notional < self.min_notional {
+            return Err(OrderbookError::NotionalBelowMinimum {
+                order_id: order.order_id.clone(),
+                notional,
+                min_notional: self.min_notional,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that an incoming order can wait for the auction of a batch-auction pair: only
+    /// plain good-til-cancelled limit orders can
+    pub fn check_auction_order(
+        &self,
+        order: &Order,
+        time_in_force: &TimeInForce,
+        self_trade_prevention: &Option<SelfTradePrevention>,
+    ) -> Result<(), OrderbookError> {
+        if self.auction_interval_ms == 0 {
+            return Ok(());
+        }
+        if order.price.is_none()
+            || order.trigger_price.is_some()
+            || order.display_quantity.is_some()
+            || time_in_force != &TimeInForce::GoodTilCancelled
+            || self_trade_prevention.is_some()
+        {
+            return Err(OrderbookError::InvalidOrder {
+                order_id: order.order_id.clone(),
+                reason: format!(
+                    "cannot wait for the auction of pair {}-{}: only good-til-cancelled limit orders can",
+                    order.pair.0, order.pair.1
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that the price of an incoming limit order is within the band around
+    /// `reference`, see [`Orderbook::band_reference`](crate::Orderbook::band_reference). Orders
+    /// on an empty book and market orders are not checked.
+    pub fn check_price_band(
+        &self,
+        order: &Order,
+        reference: Option<u128>,
+    ) -> Result<(), OrderbookError> {
+        let (Some(price), Some(reference)) = (order.price, reference) else {
+            return Ok(());
+        };
+        if self.price_band_bps == 0 {
+            return Ok(());
+        }
+        let max_deviation = mul_div(reference, self.price_band_bps.into(), BPS);
+        if price.abs_diff(reference) > max_deviation {
+            return Err(OrderbookError::PriceOutOfBand {
+                order_id: order.order_id.clone(),
+                price,
+                band_bps: self.price_band_bps,
+                reference,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Fees charged on every fill, in basis points of what each side receives. They are rounded
+/// down, and go to the [`FEE_POOL`](crate::FEE_POOL) account, but for the share of the taker
+/// fees paid to the referrers.
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub struct FeeSchedule {
+    pub maker_bps: u16,
+    pub taker_bps: u16,
+    /// Rates of the users who traded enough over the last
+    /// [`VOLUME_WINDOW_DAYS`](crate::volume::VOLUME_WINDOW_DAYS) days, by increasing volume.
+    /// Users pay the rates of the highest tier they reached.
+    #[serde(default)]
+    pub tiers: Vec<FeeTier>,
+    /// Share of the taker fees of referred users paid to their referrer, in basis points of
+    /// the fee
+    #[serde(default)]
+    pub referral_bps: u16,
+}
+
+/// Rates of the users whose traded base quantity reached `min_volume`
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub struct FeeTier {
+    pub min_volume: u128,
+    pub maker_bps: u16,
+    pub taker_bps: u16,
+}
+
+const BPS: u128 = 10_000;
+
+impl FeeSchedule {
+    pub fn validate(&self) -> Result<(), OrderbookError> {
+        let tier_rates = self
+            .tiers
+            .iter()
+            .map(|tier| (tier.maker_bps, tier.taker_bps));
+        for (maker_bps, taker_bps) in [(self.maker_bps, self.taker_bps)]
+            .into_iter()
+            .chain(tier_rates)
+        {
+            if u128::from(maker_bps.max(taker_bps)) > BPS {
+                return Err(OrderbookError::InvalidFees {
+                    maker_bps,
+                    taker_bps,
+                });
+            }
+        }
+        if u128::from(self.referral_bps) > BPS {
+            return Err(OrderbookError::InvalidReferralShare {
+                referral_bps: self.referral_bps,
+            });
+        }
+        for tiers in self.tiers.windows(2) {
+            if tiers[1].min_volume <= tiers[0].min_volume {
+                return Err(OrderbookError::FeeTiersUnordered {
+                    min_volume: tiers[1].min_volume,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Maker and taker rates of a user who traded `volume` over the volume window
+    pub fn rates(&self, volume: u128) -> (u16, u16) {
+        let tier = self
+            .tiers
+            .iter()
+            .rev()
+            .find(|tier| tier.min_volume <= volume);
+        tier.map_or((self.maker_bps, self.taker_bps), |tier| {
+            (tier.maker_bps, tier.taker_bps)
+        })
+    }
+
+    /// Fee of the owner of a resting order receiving `amount`, who traded `volume`
+    pub fn maker_fee(&self, amount: u128, volume: u128) -> u128 {
+        mul_div(amount, self.rates(volume).0.into(), BPS)
+    }
+
+    /// Fee of the owner of an incoming order receiving `amount`, who traded `volume`
+    pub fn taker_fee(&self, amount: u128, volume: u128) -> u128 {
+        mul_div(amount, self.rates(volume).1.into(), BPS)
+    }
+
+    /// Share of a taker fee of `fee` paid to the taker's referrer
+    pub fn referral_reward(&self, fee: u128) -> u128 {
+        mul_div(fee, self.referral_bps.into(), BPS)
+    }
+}
+
+/// Allocation of a taker order's quantity between the resting orders of a price level
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub enum MatchingMode {
+    /// Strict time priority: the oldest order is filled first
+    #[default]
+    Fifo,
+    /// Each resting order gets a share proportional to its quantity. The rounding leftover goes
+    /// to the orders in time priority.
+    ProRata,
+}
+
+/// Price a fill is executed at when the taker's limit price is better than the maker's, e.g. a
+/// buy at 2100 filling a sell at 2000
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub enum TakerPricePolicy {
+    /// Both trade at the maker's price: the taker keeps the surplus, as it never gets more
+    /// than the maker price taken from its balance
+    #[default]
+    MakerPrice,
+    /// Both trade halfway between the two prices, rounded in the taker's favour
+    MidPrice,
+    /// The maker trades at its price, the taker at its limit price, and the surplus goes to
+    /// the [`FEE_POOL`](crate::FEE_POOL) account
+    FeePool,
+}
+
+impl TakerPricePolicy {
+    /// Prices the maker and the taker trade at, when a taker order limited at `limit` fills a
+    /// resting order priced at `maker_price`. Market orders always trade at the maker price.
+    pub fn fill_prices(&self, maker_price: u128, limit: Option<u128>) -> (u128, u128) {
+        let Some(limit) = limit else {
+            return (maker_price, maker_price);
+        };
+        match self {
+            TakerPricePolicy::MakerPrice => (maker_price, maker_price),
+            TakerPricePolicy::MidPrice => {
+                let half_surplus = limit.abs_diff(maker_price) / 2;
+                let mid = if limit > maker_price {
+                    maker_price + half_surplus
+                } else {
+                    maker_price - half_surplus
+                };
+                (mid, mid)
+            }
+            TakerPricePolicy::FeePool => (maker_price, limit),
+        }
+    }
+}
+
+/// Uniform price and quantity of a batch auction between `bids` and `asks`, given as limit
+/// price and quantity, or `None` if they do not cross.
+///
+/// The price maximizes the quantity traded, then minimizes the quantity left unfilled at that
+/// price. When several prices qualify, the auction clears halfway between the lowest and the
+/// highest of them.
+pub fn clearing_price(bids: &[(u128, u128)], asks: &[(u128, u128)]) -> Option<(u128, u128)> {
+    let total = |orders: &[(u128, u128)], eligible: &dyn Fn(u128) -> bool| {
+        (orders.iter())
+            .filter(|(price, _)| eligible(*price))
+            .fold(0, |total: u128, (_, quantity)| {
+                total.saturating_add(*quantity)
+            })
+    };
+    let prices: BTreeSet<u128> = bids.iter().chain(asks).map(|(price, _)| *price).collect();
+
+    // Quantity traded, quantity left unfilled, lowest and highest price
+    let mut best: Option<(u128, u128, u128, u128)> = None;
+    for price in prices {
+        let demand = total(bids, &|bid| bid >= price);
+        let supply = total(asks, &|ask| ask <= price);
+        let (volume, imbalance) = (demand.min(supply), demand.abs_diff(supply));
+        if volume == 0 {
+            continue;
+        }
+        match &mut best {
+            Some((best_volume, best_imbalance, _, highest))
+                if (volume, imbalance) == (*best_volume, *best_imbalance) =>
+            {
+                *highest = price;
+            }
+            Some((best_volume, best_imbalance, _, _))
+                if volume < *best_volume
+                    || (volume == *best_volume && imbalance > *best_imbalance) => {}
+            _ => best = Some((volume, imbalance, price, price)),
+        }
+    }
+    best.map(|(volume, _, lowest, highest)| (lowest + (highest - lowest) / 2, volume))
+}
+
+impl MatchingMode {
+    /// Splits `quantity` between the resting orders of `level`, given in time priority with
+    /// their quantity. Orders that get nothing are left out.
+    pub fn allocate(&self, level: &[(String, u128)], quantity: u128) -> Vec<(String, u128)> {
+        let mut fills: Vec<(String, u128)> = match self {
+            MatchingMode::Fifo => level.iter().map(|(id, _)| (id.clone(), 0)).collect(),
+            MatchingMode::ProRata => {
+                let total = level
+                    .iter()
+                    .fold(0, |total: u128, (_, q)| total.saturating_add(*q));
+                level
+                    .iter()
+                    .map(|(id, q)| (id.clone(), mul_div(*q, quantity, total).min(*q)))
+                    .collect()
+            }
+        };
+
+        // What is left is filled in time priority
+        let mut left = quantity - fills.iter().map(|(_, fill)| *fill).sum::<u128>();
+        for ((_, fill), (_, resting)) in fills.iter_mut().zip(level) {
+            let extra = left.min(resting - *fill);
+            *fill += extra;
+            left -= extra;
+        }
+
+        fills.retain(|(_, fill)| *fill > 0);
+        fills
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OrderStatus;
+
+    fn level() -> Vec<(String, u128)> {
+        vec![
+            ("a".to_string(), 10),
+            ("b".to_string(), 30),
+            ("c".to_string(), 60),
+        ]
+    }
+
+    fn fills(mode: MatchingMode, quantity: u128) -> Vec<(String, u128)> {
+        mode.allocate(&level(), quantity)
+    }
+
+    fn expected(fills: &[(&str, u128)]) -> Vec<(String, u128)> {
+        fills
+            .iter()
+            .map(|(id, fill)| (id.to_string(), *fill))
+            .collect()
+    }
+
+    #[test_log::test]
+    fn test_fifo_allocation() {
+        assert_eq!(
+            fills(MatchingMode::Fifo, 25),
+            expected(&[("a", 10), ("b", 15)])
+        );
+        assert_eq!(
+            fills(MatchingMode::Fifo, 200),
+            expected(&[("a", 10), ("b", 30), ("c", 60)])
+        );
+    }
+
+    #[test_log::test]
+    fn test_pro_rata_allocation() {
+        assert_eq!(
+            fills(MatchingMode::ProRata, 50),
+            expected(&[("a", 5), ("b", 15), ("c", 30)])
+        );
+        // 0.7, 2.1 and 4.2 are rounded down, the leftover goes to the oldest order
+        assert_eq!(
+            fills(MatchingMode::ProRata, 7),
+            expected(&[("a", 1), ("b", 2), ("c", 4)])
+        );
+        assert_eq!(fills(MatchingMode::ProRata, 1), expected(&[("a", 1)]));
+        assert_eq!(
+            fills(MatchingMode::ProRata, 200),
+            expected(&[("a", 10), ("b", 30), ("c", 60)])
+        );
+    }
+
+    #[test_log::test]
+    fn test_order_size_and_tick() {
+        let config = MarketConfig {
+            min_quantity: 10,
+            min_notional: 500,
+            tick_size: 50,
+            lot_size: 5,
+            ..Default::default()
+        };
+        let order = |price, quantity| Order {
+            owner: "alice@wallet".to_string(),
+            order_id: "order1".to_string(),
+            order_type: crate::OrderType::Buy,
+            price,
+            trigger_price: None,
+            pair: ("ETH".to_string(), "USD".to_string()),
+            quantity,
+            timestamp: sdk::hyle_model_utils::TimestampMs(0),
+            expires_at: None,
+            display_quantity: None,
+            hidden_quantity: 0,
+            filled_quantity: 0,
+            status: OrderStatus::Open,
+            reserved_amount: 0,
+        };
+        let check = |price, quantity| config.check_order(&order(price, quantity), 100);
+
+        assert!(check(Some(2000), 25).is_ok());
+        assert!(check(Some(2000), 5)
+            .unwrap_err()
+            .to_string()
+            .contains("below the minimum"));
+        assert!(check(Some(2000), 12)
+            .unwrap_err()
+            .to_string()
+            .contains("lot size"));
+        assert!(check(Some(2020), 25)
+            .unwrap_err()
+            .to_string()
+            .contains("tick size"));
+        // 20 units at 200 per 100 units are worth 40
+        assert!(check(Some(200), 20)
+            .unwrap_err()
+            .to_string()
+            .contains("worth 40"));
+        // Market orders have no price to check
+        assert!(check(None, 25).is_ok());
+        assert!(MarketConfig::default()
+            .check_order(&order(Some(1), 1), 100)
+            .is_ok());
+    }
+
+    #[test_log::test]
+    fn test_price_band() {
+        let config = MarketConfig {
+            price_band_bps: 1000,
+            ..Default::default()
+        };
+        let check = |price, reference| {
+            let order = Order {
+                owner: "alice@wallet".to_string(),
+                order_id: "order1".to_string(),
+                order_type: crate::OrderType::Sell,
+                price,
+                trigger_price: None,
+                pair: ("ETH".to_string(), "USD".to_string()),
+                quantity: 1,
+                timestamp: sdk::hyle_model_utils::TimestampMs(0),
+                expires_at: None,
+                display_quantity: None,
+                hidden_quantity: 0,
+                filled_quantity: 0,
+                status: OrderStatus::Open,
+                reserved_amount: 0,
+            };
+            config.check_price_band(&order, reference)
+        };
+
+        assert!(check(Some(1800), Some(2000)).is_ok());
+        assert!(check(Some(2200), Some(2000)).is_ok());
+        assert!(check(Some(1799), Some(2000))
+            .unwrap_err()
+            .to_string()
+            .contains("1000 bps"));
+        assert!(check(Some(2201), Some(2000)).is_err());
+        assert!(check(Some(1), None).is_ok());
+        assert!(check(None, Some(2000)).is_ok());
+    }
+
+    #[test_log::test]
+    fn test_clearing_price() {
+        let bids = [(105, 5), (100, 5)];
+        // 5 trade from 102 to 105
+        assert_eq!(clearing_price(&bids, &[(95, 4), (102, 4)]), Some((103, 5)));
+        // 5 trade from 100 to 105, but fewer asks are left unfilled from 104
+        assert_eq!(clearing_price(&bids, &[(100, 5), (104, 3)]), Some((104, 5)));
+        // 10 trade from 90 to 100
+        assert_eq!(clearing_price(&bids, &[(90, 10)]), Some((95, 10)));
+        assert_eq!(clearing_price(&bids, &[(106, 10)]), None);
+        assert_eq!(clearing_price(&[], &[(90, 10)]), None);
+    }
+
+    #[test_log::test]
+    fn test_taker_fill_prices() {
+        // A buy at 2101 or a sell at 1899 filling an order at 2000
+        for (limit, mid) in [(2101, 2050), (1899, 1950)] {
+            assert_eq!(
+                TakerPricePolicy::MakerPrice.fill_prices(2000, Some(limit)),
+                (2000, 2000)
+            );
+            assert_eq!(
+                TakerPricePolicy::MidPrice.fill_prices(2000, Some(limit)),
+                (mid, mid)
+            );
+            assert_eq!(
+                TakerPricePolicy::FeePool.fill_prices(2000, Some(limit)),
+                (2000, limit)
+            );
+        }
+        assert_eq!(
+            TakerPricePolicy::FeePool.fill_prices(2000, None),
+            (2000, 2000)
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Snapshots of the whole orderbook state, to bootstrap a node or backfill an indexer without
+//! replaying the chain from genesis.
+//!
+//! A snapshot is the borsh encoding of a [`SnapshotHeader`] followed by the borsh-encoded
+//! state, framed by its length. The header tags the schema of the state, so that snapshots of
+//! older layouts can be migrated on import, and carries the state commitment the imported state
+//! is checked against.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::{error::OrderbookError, Orderbook};
+
+/// Tag of the orderbook snapshots, distinguishing them from other borsh data
+pub const SNAPSHOT_SCHEMA: [u8; 8] = *b"hyli-ob\0";
+
+/// Layout of the state written by [`Orderbook::export_snapshot`]. Bump it whenever the borsh
+/// layout of [`Orderbook`] changes, and migrate the previous layout in
+/// [`Orderbook::import_snapshot`].
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct SnapshotHeader {
+    pub schema: [u8; 8],
+    pub version: u32,
+    /// [`sdk::ZkContract::commit`] of the state
+    pub commitment: Vec<u8>,
+}
+
+/// Snapshot as written by [`Orderbook::export_snapshot`]
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Snapshot {
+    pub header: SnapshotHeader,
+    pub state: Vec<u8>,
+}
+
+impl Orderbook {
+    /// Encodes the state in a snapshot of the current [`SNAPSHOT_VERSION`]
+    pub fn export_snapshot(&self) -> Result<Vec<u8>, OrderbookError> {
+        let invalid = |e: std::io::Error| OrderbookError::InvalidSnapshot {
+            reason: e.to_string(),
+        };
+        let snapshot = Snapshot {
+            header: SnapshotHeader {
+                schema: SNAPSHOT_SCHEMA,
+                version: SNAPSHOT_VERSION,
+                commitment: self.state_commitment().0,
+            },
+            state: borsh::to_vec(self).map_err(invalid)?,
+        };
+        borsh::to_vec(&snapshot).map_err(invalid)
+    }
+
+    /// Decodes a snapshot written by [`Orderbook::export_snapshot`], checking the decoded state
+    /// against the commitment of its header
+    pub fn import_snapshot(bytes: &[u8]) -> Result<Self, OrderbookError> {
+        let invalid = |e: std::io::Error| OrderbookError::InvalidSnapshot {
+            reason: e.to_string(),
+        };
+        let Snapshot { header, state } = borsh::from_slice(bytes).map_err(invalid)?;
+        if header.schema != SNAPSHOT_SCHEMA {
+            return Err(OrderbookError::InvalidSnapshot {
+                reason: "not an orderbook snapshot".to_string(),
+            });
+        }
+        let orderbook: Orderbook = match header.version {
+            SNAPSHOT_VERSION => borsh::from_slice(&state).map_err(invalid)?,
+            version => return Err(OrderbookError::UnsupportedSnapshotVersion { version }),
+        };
+        if orderbook.state_commitment().0 != header.commitment {
+            return Err(OrderbookError::InvalidSnapshot {
+                reason: "the state does not match its commitment".to_string(),
+            });
+        }
+        Ok(orderbook)
+    }
+}
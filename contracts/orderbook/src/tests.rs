@@ -2,7 +2,7 @@
 mod tests {
     use crate::*;
     use sdk::Identity;
-    use std::collections::HashMap;
+    use std::collections::BTreeMap;
 
     fn setup() -> Orderbook {
         Orderbook::default()
@@ -16,7 +16,7 @@ mod tests {
         amount2: u32,
     ) -> Identity {
         let user = Identity::from([1u8; 32]);
-        let mut balances = HashMap::new();
+        let mut balances = BTreeMap::new();
         balances.insert(token1.to_string(), amount1);
         balances.insert(token2.to_string(), amount2);
         orderbook.balances.insert(user.clone(), balances);
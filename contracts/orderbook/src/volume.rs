@@ -0,0 +1,86 @@
+//! Trading volume of each user over a rolling window, which the fee tiers of
+//! [`FeeSchedule`](crate::market::FeeSchedule) are based on.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use sdk::hyle_model_utils::TimestampMs;
+
+/// Number of days, the current one included, whose volume counts for the fee tiers
+pub const VOLUME_WINDOW_DAYS: u64 = 30;
+
+const DAY_MS: u128 = 24 * 60 * 60 * 1000;
+
+/// Base quantity traded by each user, as maker or taker, in daily buckets
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize,
+)]
+pub struct TradingVolumes {
+    // Volume of each user, by day since the epoch. Days that left the window are dropped when
+    // the user trades again, or by `prune`.
+    pub users: BTreeMap<String, BTreeMap<u64, u128>>,
+}
+
+fn day(now: &TimestampMs) -> u64 {
+    (now.0 / DAY_MS) as u64
+}
+
+/// First day of the window ending on the day of `now`
+fn window_start(now: &TimestampMs) -> u64 {
+    day(now).saturating_sub(VOLUME_WINDOW_DAYS - 1)
+}
+
+impl TradingVolumes {
+    /// `user` traded `quantity` at `now`. Trades at timestamp 0, that of optimistic executions
+    /// which do not know the block time, are not recorded.
+    pub fn record(&mut self, user: &str, quantity: u128, now: &TimestampMs) {
+        if quantity == 0 || now.0 == 0 {
+            return;
+        }
+        let days = self.users.entry(user.to_string()).or_default();
+        let start = window_start(now);
+        days.retain(|day, _| *day >= start);
+        let volume = days.entry(day(now)).or_default();
+        *volume = volume.saturating_add(quantity);
+    }
+
+    /// Volume of `user` over the last [`VOLUME_WINDOW_DAYS`] days. Without a block time, at
+    /// timestamp 0, the window is unknown and the volume is 0, so that optimistic executions
+    /// charge the base fees rather than tiers based on every past day.
+    pub fn volume(&self, user: &str, now: &TimestampMs) -> u128 {
+        if now.0 == 0 {
+            return 0;
+        }
+        let days = self.users.get(user).into_iter();
+        days.flat_map(|days| days.range(window_start(now)..))
+            .fold(0, |total: u128, (_, volume)| total.saturating_add(*volume))
+    }
+
+    /// Drops the days that left the window, and the users that did not trade since
+    pub fn prune(&mut self, now: &TimestampMs) {
+        let start = window_start(now);
+        for days in self.users.values_mut() {
+            days.retain(|day, _| *day >= start);
+        }
+        self.users.retain(|_, days| !days.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_log::test]
+    fn test_volume_without_block_time() {
+        let mut volumes = TradingVolumes::default();
+        volumes.record("alice", 100, &TimestampMs(1));
+        volumes.record("alice", 200, &TimestampMs(20 * DAY_MS));
+        volumes.record("alice", 300, &TimestampMs(0));
+        assert_eq!(volumes.volume("alice", &TimestampMs(20 * DAY_MS)), 300);
+        assert_eq!(volumes.volume("alice", &TimestampMs(40 * DAY_MS)), 200);
+
+        // Without a block time, no window applies, not even one counting every past day
+        assert_eq!(volumes.volume("alice", &TimestampMs(0)), 0);
+    }
+}
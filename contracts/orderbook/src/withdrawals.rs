@@ -0,0 +1,172 @@
+//! Two-step withdrawals. Once a deployment sets a withdrawal delay, balances only leave the
+//! orderbook with a [`crate::OrderbookAction::RequestWithdraw`], claimed with
+//! [`crate::OrderbookAction::ClaimWithdraw`] once the delay elapsed. In between, the tokens are
+//! out of the user's balance, and the admin can cancel a suspicious withdrawal.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+use sdk::BlockHeight;
+
+use crate::{blobs::CompanionBlobs, error::OrderbookError, Orderbook, OrderbookEvent};
+
+/// Withdrawal requested with [`crate::OrderbookAction::RequestWithdraw`], not claimed yet
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+pub struct PendingWithdrawal {
+    pub owner: String,
+    pub token: String,
+    pub amount: u128,
+    /// Identity the tokens are transferred to
+    pub recipient: String,
+    /// First block the withdrawal can be claimed in
+    pub claimable_at: BlockHeight,
+}
+
+impl Orderbook {
+    /// Moves `amount` of `token` from the balance of `user` to a withdrawal to `recipient`,
+    /// claimable once the withdrawal delay elapsed
+    pub fn request_withdraw(
+        &mut self,
+        token: String,
+        amount: u128,
+        user: String,
+        recipient: String,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let balance = self.get_balance_mut(&user, &token);
+        if *balance < amount {
+            return Err(OrderbookError::InsufficientBalance {
+                available: *balance,
+                user,
+                token,
+                required: amount,
+            });
+        }
+        *balance -= amount;
+        let balance = *balance;
+
+        let withdrawal_id = self.next_withdrawal_id;
+        self.next_withdrawal_id += 1;
+        let withdrawal = PendingWithdrawal {
+            owner: user.clone(),
+            token: token.clone(),
+            amount,
+            recipient,
+            claimable_at: BlockHeight(tx_ctx.block_height.0 + self.withdrawal_delay),
+        };
+        self.pending_withdrawals
+            .insert(withdrawal_id, withdrawal.clone());
+
+        Ok(vec![
+            OrderbookEvent::BalanceUpdated {
+                user,
+                token,
+                amount: balance,
+            },
+            OrderbookEvent::WithdrawalRequested {
+                withdrawal_id,
+                withdrawal,
+            },
+        ])
+    }
+
+    /// Pays out the pending withdrawal `withdrawal_id` of `user`. The transaction must transfer
+    /// the tokens from the orderbook to its recipient.
+    pub fn claim_withdraw(
+        &mut self,
+        withdrawal_id: u64,
+        user: String,
+        blobs: &mut CompanionBlobs,
+        tx_ctx: &sdk::TxContext,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let withdrawal = self.pending_withdrawal(withdrawal_id)?;
+        if withdrawal.owner != user {
+            return Err(OrderbookError::NotWithdrawalOwner {
+                user,
+                withdrawal_id,
+            });
+        }
+        if tx_ctx.block_height < withdrawal.claimable_at {
+            return Err(OrderbookError::WithdrawalNotClaimable {
+                withdrawal_id,
+                claimable_at: withdrawal.claimable_at.0,
+            });
+        }
+        blobs.expect_payout(
+            &withdrawal.token.clone().into(),
+            &withdrawal.recipient.clone().into(),
+            withdrawal.amount,
+        )?;
+
+        let withdrawal = self.pending_withdrawals.remove(&withdrawal_id);
+        if let Some(withdrawal) = withdrawal {
+            if let Some(deposits) = self.deposits.get_mut(&withdrawal.token) {
+                *deposits = deposits.saturating_sub(withdrawal.amount);
+            }
+        }
+        Ok(vec![OrderbookEvent::WithdrawalClaimed {
+            withdrawal_id,
+            user,
+        }])
+    }
+
+    /// Cancels the pending withdrawal `withdrawal_id`, giving the tokens back to its owner.
+    /// Only its owner and the admin can cancel it.
+    pub fn cancel_withdraw(
+        &mut self,
+        withdrawal_id: u64,
+        user: String,
+    ) -> Result<Vec<OrderbookEvent>, OrderbookError> {
+        let withdrawal = self.pending_withdrawal(withdrawal_id)?;
+        if withdrawal.owner != user && user != self.admin {
+            return Err(OrderbookError::NotWithdrawalOwner {
+                user,
+                withdrawal_id,
+            });
+        }
+        let PendingWithdrawal {
+            owner,
+            token,
+            amount,
+            ..
+        } = withdrawal.clone();
+        self.pending_withdrawals.remove(&withdrawal_id);
+
+        let balance = self.get_balance_mut(&owner, &token);
+        *balance = balance.saturating_add(amount);
+        let balance = *balance;
+        Ok(vec![
+            OrderbookEvent::BalanceUpdated {
+                user: owner.clone(),
+                token,
+                amount: balance,
+            },
+            OrderbookEvent::WithdrawalCancelled {
+                withdrawal_id,
+                user: owner,
+            },
+        ])
+    }
+
+    fn pending_withdrawal(&self, withdrawal_id: u64) -> Result<&PendingWithdrawal, OrderbookError> {
+        self.pending_withdrawals
+            .get(&withdrawal_id)
+            .ok_or(OrderbookError::WithdrawalNotFound { withdrawal_id })
+    }
+
+    /// Pending withdrawals of `user`, by id
+    pub fn pending_withdrawals_of<'a>(
+        &'a self,
+        user: &'a str,
+    ) -> impl Iterator<Item = (&'a u64, &'a PendingWithdrawal)> + 'a {
+        self.pending_withdrawals
+            .iter()
+            .filter(move |(_, withdrawal)| withdrawal.owner == user)
+    }
+
+    /// Number of blocks between the request of a withdrawal and its claim, 0 if withdrawals
+    /// are immediate
+    pub fn withdrawal_delay(&self) -> u64 {
+        self.withdrawal_delay
+    }
+}
@@ -0,0 +1,740 @@
+//! Commitment of the orderbook state, and partial witnesses of it for zkVM executions.
+//!
+//! The state is committed as `core_hash || tree_root`:
+//! - the core (accounts, stop orders, accepted tokens, admin...) is hashed as a whole. Orderbook blobs do not
+//!   name their sender, so the accounts a transaction touches are unknown when its witness is
+//!   built, and the whole core is part of every witness.
+//! - orders and books are the entries of a Merkle tree of `2^TREE_DEPTH` buckets. A witness only
+//!   carries the buckets holding the entries a batch touches, and the hashes of the subtrees
+//!   around them.
+//!
+//! `trade_history` and `closed_orders` are indexer data the contract never reads, they are not
+//! committed.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    book::PriceLevels,
+    incentives::MakerIncentives,
+    liquidity::LiquidityPoints,
+    market::{FeeSchedule, MarketConfig},
+    volume::TradingVolumes,
+    withdrawals::PendingWithdrawal,
+    IdentityVerification, Order, OrderType, Orderbook, OrderbookAction, OrderbookBlob, TokenPair,
+};
+
+/// Orders and books are spread over `2^TREE_DEPTH` buckets
+pub const TREE_DEPTH: u8 = 16;
+
+type Hash = [u8; 32];
+
+/// Hash of empty subtrees
+const EMPTY: Hash = [0; 32];
+
+/// Entry of the state tree
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, BorshSerialize, BorshDeserialize)]
+pub enum StateKey {
+    Order(String),
+    Book(TokenPair),
+}
+
+impl StateKey {
+    fn bucket(&self) -> u32 {
+        let hash = Sha256::digest(borsh::to_vec(self).expect("Failed to encode StateKey"));
+        u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) >> (32 - TREE_DEPTH)
+    }
+}
+
+/// Committed part of the state that is not in the tree
+#[derive(BorshSerialize)]
+struct CoreState<'a> {
+    lane_ids: &'a BTreeSet<sdk::LaneId>,
+    balances: &'a BTreeMap<String, BTreeMap<String, u128>>,
+    latest_deposit: &'a BTreeMap<String, BTreeMap<String, sdk::BlockHeight>>,
+    next_order_seq: &'a u64,
+    event_seq: &'a u64,
+    stop_orders: &'a BTreeMap<TokenPair, Vec<Order>>,
+    accepted_tokens: &'a BTreeSet<sdk::ContractName>,
+    withdraw_only_tokens: &'a BTreeSet<sdk::ContractName>,
+    admin: &'a String,
+    markets: &'a BTreeMap<TokenPair, MarketConfig>,
+    token_decimals: &'a BTreeMap<String, u8>,
+    dust_thresholds: &'a BTreeMap<String, u128>,
+    fee_schedule: &'a FeeSchedule,
+    incentives: &'a MakerIncentives,
+    volumes: &'a TradingVolumes,
+    liquidity: &'a LiquidityPoints,
+    actions_per_block: &'a BTreeMap<sdk::BlockHeight, BTreeMap<String, u32>>,
+    invite_key: &'a Option<Vec<u8>>,
+    registered: &'a BTreeSet<String>,
+    referrers: &'a BTreeMap<String, String>,
+    identity_verification: &'a IdentityVerification,
+    pairs: &'a BTreeSet<TokenPair>,
+    listed_pairs: &'a Option<BTreeSet<TokenPair>>,
+    paused: &'a bool,
+    halted_pairs: &'a BTreeSet<TokenPair>,
+    nonces: &'a BTreeMap<String, u64>,
+    linked_orders: &'a BTreeMap<String, String>,
+    auctions: &'a BTreeMap<TokenPair, sdk::hyle_model_utils::TimestampMs>,
+    orders_by_owner: &'a BTreeMap<String, BTreeSet<String>>,
+    deposits: &'a BTreeMap<String, u128>,
+    reserved: &'a BTreeMap<String, BTreeMap<String, u128>>,
+    withdrawal_delay: &'a u64,
+    pending_withdrawals: &'a BTreeMap<u64, PendingWithdrawal>,
+    next_withdrawal_id: &'a u64,
+}
+
+#[derive(BorshSerialize)]
+struct BookEntry<'a> {
+    buy: Option<&'a PriceLevels>,
+    sell: Option<&'a PriceLevels>,
+    seq: Option<&'a u64>,
+}
+
+fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    if left == &EMPTY && right == &EMPTY {
+        return EMPTY;
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+fn hash_bucket(entries: &[(StateKey, Vec<u8>)]) -> Hash {
+    if entries.is_empty() {
+        return EMPTY;
+    }
+    Sha256::digest(borsh::to_vec(entries).expect("Failed to encode bucket")).into()
+}
+
+/// Whether the subtree at (`level`, `prefix`) holds one of `buckets`
+fn holds_any(buckets: &BTreeSet<u32>, level: u8, prefix: u32) -> bool {
+    let shift = TREE_DEPTH - level;
+    buckets
+        .range(prefix << shift..(prefix + 1) << shift)
+        .next()
+        .is_some()
+}
+
+/// Hashes of the non-empty nodes of each level, from the root (level 0) to the buckets.
+/// `pruned` subtrees are given by their hash.
+fn tree_levels(
+    buckets: BTreeMap<u32, Hash>,
+    pruned: &BTreeMap<(u8, u32), Hash>,
+) -> Vec<BTreeMap<u32, Hash>> {
+    let mut levels = vec![];
+    let mut nodes = buckets;
+    for level in (0..=TREE_DEPTH).rev() {
+        nodes.extend(
+            pruned
+                .range((level, 0)..=(level, u32::MAX))
+                .map(|(&(_, prefix), hash)| (prefix, *hash)),
+        );
+        let mut parents = BTreeMap::new();
+        if level > 0 {
+            for &prefix in nodes.keys() {
+                let parent = prefix >> 1;
+                let left = nodes.get(&(parent << 1)).unwrap_or(&EMPTY);
+                let right = nodes.get(&(parent << 1 | 1)).unwrap_or(&EMPTY);
+                parents.insert(parent, hash_node(left, right));
+            }
+        }
+        levels.push(std::mem::replace(&mut nodes, parents));
+    }
+    levels.reverse();
+    levels
+}
+
+fn state_commitment(core_hash: Hash, levels: &[BTreeMap<u32, Hash>]) -> sdk::StateCommitment {
+    let root = levels[0].get(&0).unwrap_or(&EMPTY);
+    sdk::StateCommitment([core_hash.as_slice(), root.as_slice()].concat())
+}
+
+impl Orderbook {
+    fn core_hash(&self) -> Hash {
+        let core = CoreState {
+            lane_ids: &self.lane_ids,
+            balances: &self.balances,
+            latest_deposit: &self.latest_deposit,
+            next_order_seq: &self.next_order_seq,
+            event_seq: &self.event_seq,
+            stop_orders: &self.stop_orders,
+            accepted_tokens: &self.accepted_tokens,
+            withdraw_only_tokens: &self.withdraw_only_tokens,
+            admin: &self.admin,
+            markets: &self.markets,
+            token_decimals: &self.token_decimals,
+            dust_thresholds: &self.dust_thresholds,
+            fee_schedule: &self.fee_schedule,
+            incentives: &self.incentives,
+            volumes: &self.volumes,
+            liquidity: &self.liquidity,
+            actions_per_block: &self.actions_per_block,
+            invite_key: &self.invite_key,
+            registered: &self.registered,
+            referrers: &self.referrers,
+            identity_verification: &self.identity_verification,
+            pairs: &self.pairs,
+            listed_pairs: &self.listed_pairs,
+            paused: &self.paused,
+            halted_pairs: &self.halted_pairs,
+            nonces: &self.nonces,
+            linked_orders: &self.linked_orders,
+            auctions: &self.auctions,
+            orders_by_owner: &self.orders_by_owner,
+            deposits: &self.deposits,
+            reserved: &self.reserved,
+            withdrawal_delay: &self.withdrawal_delay,
+            pending_withdrawals: &self.pending_withdrawals,
+            next_withdrawal_id: &self.next_withdrawal_id,
+        };
+        Sha256::digest(borsh::to_vec(&core).expect("Failed to encode Orderbook core state")).into()
+    }
+
+    /// Encoded tree entries of the state, grouped by bucket
+    fn tree_buckets(&self) -> BTreeMap<u32, Vec<(StateKey, Vec<u8>)>> {
+        let orders = self.orders.iter().map(|(order_id, order)| {
+            let value = borsh::to_vec(order).expect("Failed to encode Order");
+            (StateKey::Order(order_id.clone()), value)
+        });
+
+        let pairs: BTreeSet<&TokenPair> = self
+            .buy_orders
+            .keys()
+            .chain(self.sell_orders.keys())
+            .chain(self.book_seqs.keys())
+            .collect();
+        let books = pairs.into_iter().map(|pair| {
+            let book = BookEntry {
+                buy: self.buy_orders.get(pair),
+                sell: self.sell_orders.get(pair),
+                seq: self.book_seqs.get(pair),
+            };
+            let value = borsh::to_vec(&book).expect("Failed to encode book");
+            (StateKey::Book(pair.clone()), value)
+        });
+
+        let mut buckets: BTreeMap<u32, Vec<(StateKey, Vec<u8>)>> = BTreeMap::new();
+        for (key, value) in orders.chain(books) {
+            buckets.entry(key.bucket()).or_default().push((key, value));
+        }
+        for entries in buckets.values_mut() {
+            entries.sort();
+        }
+        buckets
+    }
+
+    pub(crate) fn state_commitment(&self) -> sdk::StateCommitment {
+        let buckets = self
+            .tree_buckets()
+            .into_iter()
+            .map(|(bucket, entries)| (bucket, hash_bucket(&entries)))
+            .collect();
+        state_commitment(self.core_hash(), &tree_levels(buckets, &BTreeMap::new()))
+    }
+
+    /// Tree entries `action` may read or write, `None` meaning all of them
+    pub fn witness_keys(&self, action: &OrderbookAction) -> Option<BTreeSet<StateKey>> {
+        let mut keys = BTreeSet::new();
+        let mut next_order_seq = self.next_order_seq;
+        self.add_witness_keys(action, &mut next_order_seq, &mut keys)?;
+        Some(keys)
+    }
+
+    /// Adds the keys of `action` to `keys`, `next_order_seq` numbering the orders it creates
+    fn add_witness_keys(
+        &self,
+        action: &OrderbookAction,
+        next_order_seq: &mut u64,
+        keys: &mut BTreeSet<StateKey>,
+    ) -> Option<()> {
+        let pair = match action {
+            OrderbookAction::CreateOrder { pair, .. } => {
+                keys.insert(StateKey::Order(Orderbook::order_id(pair, *next_order_seq)));
+                *next_order_seq += 1;
+                Some(pair.clone())
+            }
+            OrderbookAction::Cancel { order_id }
+            | OrderbookAction::ModifyOrder { order_id, .. } => {
+                keys.insert(StateKey::Order(order_id.clone()));
+                // Cancelling a stop order may cancel its linked order, in the book
+                (self.orders.get(order_id))
+                    .or(self.stop_order(order_id))
+                    .map(|order| order.pair.clone())
+            }
+            OrderbookAction::PruneExpired { pair }
+            | OrderbookAction::RunAuction { pair }
+            | OrderbookAction::DelistPair { pair } => Some(pair.clone()),
+            OrderbookAction::Batch(actions) => {
+                for action in actions {
+                    self.add_witness_keys(action, next_order_seq, keys)?;
+                }
+                None
+            }
+            OrderbookAction::OneCancelsOther { first, second } => {
+                self.add_witness_keys(first, next_order_seq, keys)?;
+                self.add_witness_keys(second, next_order_seq, keys)?;
+                None
+            }
+            OrderbookAction::SubmitSignedOrder { order, .. } => {
+                self.add_witness_keys(order, next_order_seq, keys)?;
+                None
+            }
+            // A pair is rejected once its inverse is configured, listed or has a book
+            OrderbookAction::ConfigureMarket { pair, .. }
+            | OrderbookAction::CreatePair { pair } => {
+                keys.insert(StateKey::Book((pair.1.clone(), pair.0.clone())));
+                keys.insert(StateKey::Book(pair.clone()));
+                None
+            }
+            // The orders of the sender are only known once the blob is executed
+            OrderbookAction::CloseAccount => return None,
+            // Removing a token cancels the orders of all of its pairs
+            OrderbookAction::RemoveToken { .. } => return None,
+            // The decimals of a token are only changed while none of its pairs has orders
+            OrderbookAction::ConfigureToken { .. } => return None,
+            OrderbookAction::Deposit { .. }
+            | OrderbookAction::DepositFromWallet { .. }
+            | OrderbookAction::Withdraw { .. }
+            | OrderbookAction::RequestWithdraw { .. }
+            | OrderbookAction::ClaimWithdraw { .. }
+            | OrderbookAction::CancelWithdraw { .. }
+            | OrderbookAction::DistributeMakerRewards { .. }
+            | OrderbookAction::FundIncentives { .. }
+            | OrderbookAction::DistributeIncentives { .. }
+            | OrderbookAction::SetFeeSchedule { .. }
+            | OrderbookAction::WithdrawFees { .. }
+            | OrderbookAction::FundInsurance { .. }
+            | OrderbookAction::SetDustThreshold { .. }
+            | OrderbookAction::SweepDust { .. }
+            | OrderbookAction::AddLane { .. }
+            | OrderbookAction::RemoveLane { .. }
+            | OrderbookAction::AddToken { .. }
+            | OrderbookAction::SetHalted { .. }
+            | OrderbookAction::Register { .. }
+            | OrderbookAction::RegisterReferrer { .. } => None,
+        };
+
+        if let Some(pair) = pair {
+            // Matching, book hashes and crossed book checks go through the whole book
+            let order_ids = self
+                .buy_orders
+                .get(&pair)
+                .into_iter()
+                .flat_map(|levels| levels.order_ids(&OrderType::Buy))
+                .chain(
+                    self.sell_orders
+                        .get(&pair)
+                        .into_iter()
+                        .flat_map(|levels| levels.order_ids(&OrderType::Sell)),
+                );
+            keys.extend(order_ids.map(|order_id| StateKey::Order(order_id.clone())));
+            // Orders on a pair are rejected once its inverse has a book
+            keys.insert(StateKey::Book((pair.1.clone(), pair.0.clone())));
+            keys.insert(StateKey::Book(pair));
+        }
+        Some(())
+    }
+
+    /// Witness of the state for a transaction touching `keys` (`None` for all entries)
+    pub fn witness(&self, keys: Option<&BTreeSet<StateKey>>) -> ZkOrderbook {
+        let tree_buckets = self.tree_buckets();
+        let buckets: BTreeSet<u32> = match keys {
+            Some(keys) => keys.iter().map(StateKey::bucket).collect(),
+            None => tree_buckets.keys().copied().collect(),
+        };
+
+        let mut state = self.clone_core();
+        for bucket in &buckets {
+            for (key, _) in tree_buckets.get(bucket).into_iter().flatten() {
+                state.copy_entry(self, key);
+            }
+        }
+
+        let levels = tree_levels(
+            tree_buckets
+                .iter()
+                .map(|(bucket, entries)| (*bucket, hash_bucket(entries)))
+                .collect(),
+            &BTreeMap::new(),
+        );
+        let mut pruned = BTreeMap::new();
+        for (level, nodes) in levels.iter().enumerate() {
+            let level = level as u8;
+            for (&prefix, hash) in nodes {
+                let parent_holds_any = level == 0 || holds_any(&buckets, level - 1, prefix >> 1);
+                if parent_holds_any && !holds_any(&buckets, level, prefix) {
+                    pruned.insert((level, prefix), *hash);
+                }
+            }
+        }
+
+        ZkOrderbook {
+            state,
+            buckets,
+            pruned,
+        }
+    }
+
+    /// Copy of the state without any tree entry
+    fn clone_core(&self) -> Orderbook {
+        Orderbook {
+            lane_ids: self.lane_ids.clone(),
+            balances: self.balances.clone(),
+            latest_deposit: self.latest_deposit.clone(),
+            orders: BTreeMap::new(),
+            next_order_seq: self.next_order_seq,
+            buy_orders: BTreeMap::new(),
+            sell_orders: BTreeMap::new(),
+            stop_orders: self.stop_orders.clone(),
+            trade_history: BTreeMap::new(),
+            closed_orders: BTreeMap::new(),
+            accepted_tokens: self.accepted_tokens.clone(),
+            withdraw_only_tokens: self.withdraw_only_tokens.clone(),
+            book_seqs: BTreeMap::new(),
+            event_seq: self.event_seq,
+            admin: self.admin.clone(),
+            markets: self.markets.clone(),
+            token_decimals: self.token_decimals.clone(),
+            dust_thresholds: self.dust_thresholds.clone(),
+            fee_schedule: self.fee_schedule.clone(),
+            incentives: self.incentives.clone(),
+            volumes: self.volumes.clone(),
+            liquidity: self.liquidity.clone(),
+            actions_per_block: self.actions_per_block.clone(),
+            invite_key: self.invite_key.clone(),
+            registered: self.registered.clone(),
+            referrers: self.referrers.clone(),
+            identity_verification: self.identity_verification,
+            pairs: self.pairs.clone(),
+            listed_pairs: self.listed_pairs.clone(),
+            paused: self.paused,
+            halted_pairs: self.halted_pairs.clone(),
+            nonces: self.nonces.clone(),
+            linked_orders: self.linked_orders.clone(),
+            auctions: self.auctions.clone(),
+            orders_by_owner: self.orders_by_owner.clone(),
+            deposits: self.deposits.clone(),
+            reserved: self.reserved.clone(),
+            withdrawal_delay: self.withdrawal_delay,
+            pending_withdrawals: self.pending_withdrawals.clone(),
+            next_withdrawal_id: self.next_withdrawal_id,
+        }
+    }
+
+    fn copy_entry(&mut self, from: &Orderbook, key: &StateKey) {
+        match key {
+            StateKey::Order(order_id) => {
+                if let Some(order) = from.orders.get(order_id) {
+                    self.orders.insert(order_id.clone(), order.clone());
+                }
+            }
+            StateKey::Book(pair) => {
+                if let Some(ids) = from.buy_orders.get(pair) {
+                    self.buy_orders.insert(pair.clone(), ids.clone());
+                }
+                if let Some(ids) = from.sell_orders.get(pair) {
+                    self.sell_orders.insert(pair.clone(), ids.clone());
+                }
+                if let Some(seq) = from.book_seqs.get(pair) {
+                    self.book_seqs.insert(pair.clone(), *seq);
+                }
+            }
+        }
+    }
+}
+
+/// Orderbook state executed in the zkVM: the core state, and the tree entries of some buckets
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct ZkOrderbook {
+    state: Orderbook,
+    /// Buckets whose entries are all in `state`
+    buckets: BTreeSet<u32>,
+    /// Hashes of the non-empty subtrees holding none of `buckets`, by (level, prefix)
+    pruned: BTreeMap<(u8, u32), Hash>,
+}
+
+impl sdk::FullStateRevert for ZkOrderbook {}
+
+impl sdk::ZkContract for ZkOrderbook {
+    fn execute(&mut self, calldata: &sdk::Calldata) -> sdk::RunResult {
+        if let Ok((blob, _)) = sdk::utils::parse_raw_calldata::<OrderbookBlob>(calldata) {
+            self.ensure_witnessed(&blob.action);
+        }
+        self.state.execute(calldata)
+    }
+
+    fn commit(&self) -> sdk::StateCommitment {
+        for &(level, prefix) in self.pruned.keys() {
+            assert!(
+                level <= TREE_DEPTH && !holds_any(&self.buckets, level, prefix),
+                "Pruned subtree ({level}, {prefix}) overlaps the witness"
+            );
+        }
+
+        let mut buckets = BTreeMap::new();
+        for (bucket, entries) in self.state.tree_buckets() {
+            assert!(
+                self.buckets.contains(&bucket),
+                "Entries {:?} are not part of the witness",
+                entries.iter().map(|(key, _)| key).collect::<Vec<_>>()
+            );
+            buckets.insert(bucket, hash_bucket(&entries));
+        }
+        state_commitment(self.state.core_hash(), &tree_levels(buckets, &self.pruned))
+    }
+}
+
+impl ZkOrderbook {
+    /// Panics rather than executing `action` on entries missing from the witness, which would
+    /// be seen as absent and make the transaction fail in the proof only.
+    fn ensure_witnessed(&self, action: &OrderbookAction) {
+        match self.state.witness_keys(action) {
+            Some(keys) => {
+                for key in keys {
+                    assert!(
+                        self.buckets.contains(&key.bucket()),
+                        "{key:?} is not part of the witness"
+                    );
+                }
+            }
+            None => assert!(self.pruned.is_empty(), "The witness is not complete"),
+        }
+    }
+
+    /// Witness of a batch, from the witness of its first transactions (`self`, taken on the
+    /// initial state) and the one of the next transaction (taken on the resulting state).
+    ///
+    /// Buckets missing from `self` were not touched by the previous transactions, so their
+    /// entries and hashes in `next` are still the initial ones.
+    pub fn merge(mut self, next: ZkOrderbook) -> ZkOrderbook {
+        let new_buckets: BTreeSet<u32> = next.buckets.difference(&self.buckets).copied().collect();
+        for (bucket, entries) in next.state.tree_buckets() {
+            if new_buckets.contains(&bucket) {
+                for (key, _) in entries {
+                    self.state.copy_entry(&next.state, &key);
+                }
+            }
+        }
+        self.buckets.extend(new_buckets);
+
+        let mut pruned = next.pruned;
+        pruned.extend(self.pruned);
+        pruned.retain(|&(level, prefix), _| !holds_any(&self.buckets, level, prefix));
+        self.pruned = pruned;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sdk::{hyle_model_utils::TimestampMs, ZkContract};
+
+    use super::*;
+    use crate::{Order, OrderStatus, OrderType, TimeInForce};
+
+    fn pair(base: &str) -> TokenPair {
+        (base.to_string(), "USD".to_string())
+    }
+
+    fn state() -> Orderbook {
+        let mut orderbook = Orderbook::init(Default::default(), "admin".to_string());
+        orderbook.balances.insert(
+            "alice".to_string(),
+            BTreeMap::from([("ETH".to_string(), 100), ("BTC".to_string(), 100)]),
+        );
+        orderbook.balances.insert(
+            "bob".to_string(),
+            BTreeMap::from([("USD".to_string(), 1_000_000)]),
+        );
+        for (i, base) in ["ETH", "BTC", "ETH", "BTC"].into_iter().enumerate() {
+            let order = Order {
+                owner: "alice".to_string(),
+                order_id: format!("sell{i}"),
+                order_type: OrderType::Sell,
+                price: Some(2000 + i as u128),
+                trigger_price: None,
+                pair: pair(base),
+                quantity: 1,
+                timestamp: TimestampMs(0),
+                expires_at: None,
+                display_quantity: None,
+                hidden_quantity: 0,
+                filled_quantity: 0,
+                status: OrderStatus::Open,
+                reserved_amount: 0,
+            };
+            orderbook
+                .execute_order(order, TimeInForce::GoodTilCancelled, &tx_ctx())
+                .unwrap();
+        }
+        orderbook
+    }
+
+    fn tx_ctx() -> sdk::TxContext {
+        sdk::TxContext {
+            block_height: sdk::BlockHeight(10),
+            ..Default::default()
+        }
+    }
+
+    fn calldata(user: &str, action: &OrderbookAction, nonce: u64) -> sdk::Calldata {
+        sdk::Calldata {
+            tx_hash: sdk::TxHash(String::new()),
+            identity: sdk::Identity(user.to_string()),
+            blobs: vec![action.as_blob("orderbook".into(), nonce)].into(),
+            tx_blob_count: 1,
+            index: sdk::BlobIndex(0),
+            tx_ctx: Some(tx_ctx()),
+            private_input: vec![],
+        }
+    }
+
+    fn buy(base: &str) -> OrderbookAction {
+        OrderbookAction::CreateOrder {
+            order_type: OrderType::Buy,
+            price: Some(2000),
+            pair: pair(base),
+            quantity: 1,
+            time_in_force: TimeInForce::GoodTilCancelled,
+            trigger_price: None,
+            self_trade_prevention: None,
+            expires_at: None,
+            display_quantity: None,
+        }
+    }
+
+    /// Executes `action`, reverting the state if it fails
+    fn execute<Z: ZkContract + Clone>(
+        state: &mut Z,
+        user: &str,
+        action: &OrderbookAction,
+        nonce: u64,
+    ) {
+        let initial = state.clone();
+        if state.execute(&calldata(user, action, nonce)).is_err() {
+            *state = initial;
+        }
+    }
+
+    /// Runs `txs` on the full state and on their merged witnesses, and checks both agree.
+    /// Returns the witness of the batch, before execution.
+    fn assert_witness_execution(
+        mut full: Orderbook,
+        txs: &[(&str, OrderbookAction)],
+    ) -> ZkOrderbook {
+        let initial_commitment = full.commit();
+        let mut witness: Option<ZkOrderbook> = None;
+        // The position of a transaction is a valid nonce for all users
+        for (nonce, (user, action)) in (1..).zip(txs) {
+            let next = full.witness(full.witness_keys(action).as_ref());
+            witness = Some(match witness {
+                Some(witness) => witness.merge(next),
+                None => next,
+            });
+            execute(&mut full, user, action, nonce);
+        }
+
+        let witness = witness.unwrap();
+        assert_eq!(witness.commit(), initial_commitment);
+        let mut zk_state = witness.clone();
+        for (nonce, (user, action)) in (1..).zip(txs) {
+            execute(&mut zk_state, user, action, nonce);
+        }
+        assert_eq!(zk_state.commit(), full.commit());
+        witness
+    }
+
+    #[test_log::test]
+    fn test_witness_only_holds_touched_entries() {
+        let full = state();
+        let witness = assert_witness_execution(full.clone(), &[("bob", buy("ETH"))]);
+        assert!(borsh::to_vec(&witness).unwrap().len() < borsh::to_vec(&full).unwrap().len());
+
+        // The BTC book is pruned
+        assert!(!witness.state.sell_orders.contains_key(&pair("BTC")));
+        assert!(!witness.state.orders.contains_key("sell1"));
+        assert!(witness.state.orders.contains_key("sell0"));
+    }
+
+    #[test_log::test]
+    fn test_merged_witnesses() {
+        assert_witness_execution(
+            state(),
+            &[
+                ("bob", buy("ETH")),
+                (
+                    "alice",
+                    OrderbookAction::Cancel {
+                        order_id: "sell3".to_string(),
+                    },
+                ),
+                // Fails, the order is not owned by bob
+                (
+                    "bob",
+                    OrderbookAction::Cancel {
+                        order_id: "sell2".to_string(),
+                    },
+                ),
+                ("bob", buy("BTC")),
+                ("bob", OrderbookAction::Batch(vec![buy("ETH"), buy("ETH")])),
+                ("alice", OrderbookAction::CloseAccount),
+            ],
+        );
+    }
+
+    #[test_log::test]
+    fn test_configure_token_with_resting_orders() {
+        // Fails as ETH pairs have resting orders, which the witness must show
+        let configure = OrderbookAction::ConfigureToken {
+            token: "ETH".to_string(),
+            decimals: 6,
+        };
+        assert!(state().witness_keys(&configure).is_none());
+        assert_witness_execution(state(), &[("admin", configure)]);
+    }
+
+    #[test_log::test]
+    fn test_inverted_pair_with_a_book() {
+        let mut full = state();
+        full.book_seqs.insert(pair("ETH"), 1);
+        // Fails as the ETH/USD book exists, which the witness must show
+        let inverse = ("USD".to_string(), "ETH".to_string());
+        let configure = OrderbookAction::ConfigureMarket {
+            pair: inverse.clone(),
+            config: Default::default(),
+        };
+        let witness = assert_witness_execution(full.clone(), &[("admin", configure.clone())]);
+        assert!(witness.state.book_seqs.contains_key(&pair("ETH")));
+        execute(&mut full, "admin", &configure, 1);
+        assert!(!full.markets.contains_key(&inverse));
+    }
+
+    #[test_log::test]
+    #[should_panic(expected = "is not part of the witness")]
+    fn test_missing_entry_panics() {
+        let full = state();
+        let deposit = OrderbookAction::Deposit {
+            token: "USD".to_string(),
+            amount: 1,
+        };
+        let mut witness = full.witness(full.witness_keys(&deposit).as_ref());
+        let _ = witness.execute(&calldata("bob", &buy("ETH"), 1));
+    }
+
+    #[test_log::test]
+    fn test_tampered_witness_changes_commitment() {
+        let full = state();
+        let mut witness = full.witness(full.witness_keys(&buy("ETH")).as_ref());
+        witness.state.orders.get_mut("sell0").unwrap().quantity += 1;
+        assert_ne!(witness.commit(), full.commit());
+
+        let mut witness = full.witness(full.witness_keys(&buy("ETH")).as_ref());
+        let hash = witness.pruned.values_mut().next().unwrap();
+        hash[0] ^= 1;
+        assert_ne!(witness.commit(), full.commit());
+    }
+}
@@ -2,7 +2,7 @@ import React, { useState } from 'react';
 import { useNavigate } from 'react-router-dom';
 import styled from 'styled-components';
 import { theme } from '../../styles/theme';
-import { deposit } from '../../models/Orderbook';
+import { deposit, depositTransfer } from '../../models/Orderbook';
 import { nodeService } from '../../services/NodeService';
 import type { BlobTransaction, Identity } from 'hyli';
 import { useAppContext } from '../../contexts/AppContext';
@@ -399,10 +399,12 @@ const DepositForm: React.FC = () => {
         const identity: Identity = wallet?.address as Identity;
 
         const [blob0, blob1] = createIdentityBlobs();
+        // The orderbook only credits the tokens transferred to it in the same transaction
+        const transferBlob = depositTransfer(selectedCurrency, identity, numericAmount);
 
         const blobTx: BlobTransaction = {
             identity,
-            blobs: [blob0, blob1, orderbookBlob],
+            blobs: [blob0, blob1, transferBlob, orderbookBlob],
         };
 
         try {
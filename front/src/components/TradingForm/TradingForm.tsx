@@ -288,8 +288,6 @@ export const TradingForm: React.FC<TradingFormProps> = ({ marketPrice }) => {
         return;
     }
 
-    const orderId = crypto.randomUUID(); // Generate a unique order ID
-
     let price: number | null = null;
     if (activeTab === 'limit') {
       const numericLimitPrice = parseFloat(limitPrice);
@@ -301,7 +299,6 @@ export const TradingForm: React.FC<TradingFormProps> = ({ marketPrice }) => {
     }
 
     const orderbookBlob = createOrder(
-      orderId,
       orderType === 'buy' ? OrderbookOrderType.Buy : OrderbookOrderType.Sell,
       price, 
       currentPair,
@@ -14,23 +14,41 @@ export type BorshOrderType =
     | { Buy: Unit }
     | { Sell: Unit };
 
+export type BorshTimeInForce =
+    | { GoodTilCancelled: Unit }
+    | { ImmediateOrCancel: Unit }
+    | { FillOrKill: Unit };
+
+export type BorshSelfTradePrevention =
+    | { CancelNewest: Unit }
+    | { CancelOldest: Unit }
+    | { DecrementAndCancel: Unit };
+
 export interface Order {
     owner: string;
     order_id: string;
     order_type: OrderType; 
     price: number | null;
+    trigger_price: number | null;
     pair: TokenPair;
     quantity: number;
+    expires_at: number | null;
+    display_quantity: number | null;
+    hidden_quantity: number;
 }
 
 export type OrderbookAction =
     | {
           CreateOrder: {
-              order_id: string;
               order_type: BorshOrderType; 
-              price: number | null;
+              price: bigint | null;
               pair: TokenPair;
-              quantity: number;
+              quantity: bigint;
+              time_in_force: BorshTimeInForce;
+              trigger_price: bigint | null;
+              self_trade_prevention: BorshSelfTradePrevention | null;
+              expires_at: bigint | null;
+              display_quantity: bigint | null;
           };
       }
     | {
@@ -41,13 +59,14 @@ export type OrderbookAction =
     | {
           Deposit: {
               token: string;
-              amount: number;
+              amount: bigint;
           };
       }
     | {
           Withdraw: {
               token: string;
-              amount: number;
+              amount: bigint;
+              recipient: string | null;
           };
       };
 
@@ -57,6 +76,18 @@ export type OrderbookEvent =
               order: Order;
           };
       }
+    | {
+          StopOrderPlaced: {
+              order: Order;
+          };
+      }
+    | {
+          StopOrderTriggered: {
+              order_id: string;
+              pair: TokenPair;
+              last_price: number;
+          };
+      }
     | {
           OrderCancelled: {
               order_id: string;
@@ -67,6 +98,7 @@ export type OrderbookEvent =
           OrderExecuted: {
               order_id: string;
               pair: TokenPair;
+              executed_price: number | null;
           };
       }
     | {
@@ -74,6 +106,28 @@ export type OrderbookEvent =
               order_id: string;
               remaining_quantity: number;
               pair: TokenPair;
+              executed_price: number | null;
+          };
+      }
+    | {
+          TradeExecuted: {
+              pair: TokenPair;
+              price: number;
+              quantity: number;
+              maker_order_id: string;
+              taker_order_id: string;
+              maker: string;
+              taker: string;
+              timestamp: number;
+          };
+      }
+    | {
+          FeeCharged: {
+              pair: TokenPair;
+              order_id: string;
+              user: string;
+              token: string;
+              amount: number;
           };
       }
     | {
@@ -90,6 +144,18 @@ export const orderTypeSchema = BorshSchema.Enum({
     Sell: BorshSchema.Unit,
 });
 
+export const timeInForceSchema = BorshSchema.Enum({
+    GoodTilCancelled: BorshSchema.Unit,
+    ImmediateOrCancel: BorshSchema.Unit,
+    FillOrKill: BorshSchema.Unit,
+});
+
+export const selfTradePreventionSchema = BorshSchema.Enum({
+    CancelNewest: BorshSchema.Unit,
+    CancelOldest: BorshSchema.Unit,
+    DecrementAndCancel: BorshSchema.Unit,
+});
+
 export const tokenPairSchema = BorshSchema.Struct({
     0: BorshSchema.String,
     1: BorshSchema.String,
@@ -97,41 +163,65 @@ export const tokenPairSchema = BorshSchema.Struct({
 
 export const orderbookActionSchema = BorshSchema.Enum({
     CreateOrder: BorshSchema.Struct({
-        order_id: BorshSchema.String,
         order_type: orderTypeSchema, 
-        price: BorshSchema.Option(BorshSchema.u32),
+        price: BorshSchema.Option(BorshSchema.u128),
         pair: tokenPairSchema,
-        quantity: BorshSchema.u32,
+        quantity: BorshSchema.u128,
+        time_in_force: timeInForceSchema,
+        trigger_price: BorshSchema.Option(BorshSchema.u128),
+        self_trade_prevention: BorshSchema.Option(selfTradePreventionSchema),
+        expires_at: BorshSchema.Option(BorshSchema.u128),
+        display_quantity: BorshSchema.Option(BorshSchema.u128),
     }),
     Cancel: BorshSchema.Struct({
         order_id: BorshSchema.String,
     }),
     Deposit: BorshSchema.Struct({
         token: BorshSchema.String,
-        amount: BorshSchema.u32,
+        amount: BorshSchema.u128,
     }),
     Withdraw: BorshSchema.Struct({
         token: BorshSchema.String,
-        amount: BorshSchema.u32,
+        amount: BorshSchema.u128,
+        recipient: BorshSchema.Option(BorshSchema.String),
     }),
 });
 
+// Orderbook blobs carry a nonce, which must increase for each action of a user
+export const orderbookBlobSchema = BorshSchema.Struct({
+    nonce: BorshSchema.u64,
+    action: orderbookActionSchema,
+});
+
+// The time in ms, bumped if needed so that two actions sent in the same ms are both accepted
+let lastNonce = 0n;
+const nextNonce = (): bigint => {
+    const now = BigInt(Date.now());
+    lastNonce = now > lastNonce ? now : lastNonce + 1n;
+    return lastNonce;
+};
+
 // Serialization/Deserialization functions
 export const deserializeOrderbookAction = (data: number[]): OrderbookAction => {
-    return borshDeserialize(orderbookActionSchema, new Uint8Array(data)) as OrderbookAction;
+    const blob = borshDeserialize(orderbookBlobSchema, new Uint8Array(data)) as { action: OrderbookAction };
+    return blob.action;
 };
 
 export const serializeOrderbookAction = (action: OrderbookAction): Uint8Array => {
-    return borshSerialize(orderbookActionSchema, action);
+    return borshSerialize(orderbookBlobSchema, { nonce: nextNonce(), action });
 };
 
 // Helper functions to create actions
 export const createOrder = (
-    order_id: string,
     order_type_enum_val: OrderType, 
     price: number | null,
     pair: TokenPair,
     quantity: number,
+    time_in_force: BorshTimeInForce = { GoodTilCancelled: {} },
+    trigger_price: number | null = null,
+    self_trade_prevention: BorshSelfTradePrevention | null = null,
+    expires_at: number | null = null,
+    display_quantity: number | null = null,
 ): Blob => {
     const borshOrderType: BorshOrderType = order_type_enum_val === OrderType.Buy
         ? { Buy: {} }
@@ -139,15 +229,19 @@ export const createOrder = (
 
     const actionParams: OrderbookAction = {
         CreateOrder: {
-            order_id,
             order_type: borshOrderType,
-            price,
+            price: price === null ? null : BigInt(price),
             pair,
-            quantity,
+            quantity: BigInt(quantity),
+            time_in_force,
+            trigger_price: trigger_price === null ? null : BigInt(trigger_price),
+            self_trade_prevention,
+            expires_at: expires_at === null ? null : BigInt(expires_at),
+            display_quantity: display_quantity === null ? null : BigInt(display_quantity),
         },
     };
 
-    const serializedBytes = borshSerialize(orderbookActionSchema, actionParams);
+    const serializedBytes = serializeOrderbookAction(actionParams);
 
     const blob: Blob = {
         contract_name: "orderbook",
@@ -180,7 +274,7 @@ export const deposit = (
     const action: OrderbookAction = {
         Deposit: {
             token,
-            amount,
+            amount: BigInt(amount),
         },
     };
 
@@ -194,11 +288,13 @@ export const deposit = (
 export const withdraw = (
     token: string,
     amount: number,
+    recipient: string | null = null,
 ): Blob => {
     const action: OrderbookAction = {
         Withdraw: {
             token,
-            amount,
+            amount: BigInt(amount),
+            recipient,
         },
     };
 
@@ -207,4 +303,71 @@ export const withdraw = (
         data: Array.from(serializeOrderbookAction(action)),
     };
     return blob;
-}; 
\ No newline at end of file
+};
+
+// Token transfer blob, as encoded by the token contracts
+const tokenTransferSchema = BorshSchema.Struct({
+    caller: BorshSchema.Option(BorshSchema.u64),
+    callees: BorshSchema.Option(BorshSchema.Vec(BorshSchema.u64)),
+    parameters: BorshSchema.Enum({
+        Transfer: BorshSchema.Struct({
+            sender: BorshSchema.String,
+            recipient: BorshSchema.String,
+            amount: BorshSchema.u128,
+        }),
+    }),
+});
+
+// Transfer of withdrawn tokens from the orderbook to the recipient, which must be sent in
+// the same transaction as the withdrawal. It is made on behalf of the orderbook blob, at
+// `orderbook_blob_index` in the transaction.
+export const payout = (
+    token: string,
+    recipient: string,
+    amount: number,
+    orderbook_blob_index: number,
+): Blob => {
+    const transfer = {
+        caller: BigInt(orderbook_blob_index),
+        callees: null,
+        parameters: {
+            Transfer: {
+                sender: "orderbook",
+                recipient,
+                amount: BigInt(amount),
+            },
+        },
+    };
+
+    const blob: Blob = {
+        contract_name: token,
+        data: Array.from(borshSerialize(tokenTransferSchema, transfer)),
+    };
+    return blob;
+};
+
+// Transfer of deposited tokens from the sender to the orderbook, which must precede the
+// deposit's orderbook blob in the same transaction.
+export const depositTransfer = (
+    token: string,
+    sender: string,
+    amount: number,
+): Blob => {
+    const transfer = {
+        caller: null,
+        callees: null,
+        parameters: {
+            Transfer: {
+                sender,
+                recipient: "orderbook",
+                amount: BigInt(amount),
+            },
+        },
+    };
+
+    const blob: Blob = {
+        contract_name: token,
+        data: Array.from(borshSerialize(tokenTransferSchema, transfer)),
+    };
+    return blob;
+};
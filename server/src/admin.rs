@@ -0,0 +1,216 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Json, Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use hyle_modules::{bus::BusClientSender, module_bus_client};
+use orderbook::Orderbook;
+use secp256k1::{Message, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    rollup_executor::RollupExecutorCommand,
+    wallet_auth::{AuthenticatedUser, WalletAuth},
+};
+
+/// Header carrying the admin API key
+pub const ADMIN_API_KEY_HEADER: &str = "x-api-key";
+
+/// Proof batching parameters handed to the AutoProver
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProverTuning {
+    pub buffer_blocks: u32,
+    pub max_txs_per_proof: usize,
+    pub tx_working_window_size: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InviteCodeRequest {
+    pub identity: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InviteCode {
+    /// Hex encoded compact signature, to send in the `Register` action and its secp256k1 blob
+    pub signature: String,
+    /// Hex encoded compressed public key, for the secp256k1 blob
+    pub public_key: String,
+}
+
+/// Partial update of the [`ProverTuning`]
+#[derive(Deserialize, Debug, Default)]
+pub struct ProverTuningUpdate {
+    pub buffer_blocks: Option<u32>,
+    pub max_txs_per_proof: Option<usize>,
+    pub tx_working_window_size: Option<usize>,
+}
+
+impl ProverTuning {
+    /// Loads the tuning persisted by the admin API, falling back to `default` (from the config).
+    pub fn load_or(path: &Path, default: ProverTuning) -> Self {
+        std::fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or(default)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let bytes = serde_json::to_vec_pretty(self).context("encoding prover tuning")?;
+        std::fs::write(path, bytes).context("writing prover tuning")
+    }
+
+    fn apply(&mut self, update: ProverTuningUpdate) {
+        if let Some(buffer_blocks) = update.buffer_blocks {
+            self.buffer_blocks = buffer_blocks;
+        }
+        if let Some(max_txs_per_proof) = update.max_txs_per_proof {
+            self.max_txs_per_proof = max_txs_per_proof;
+        }
+        if let Some(tx_working_window_size) = update.tx_working_window_size {
+            self.tx_working_window_size = tx_working_window_size;
+        }
+    }
+}
+
+module_bus_client! {
+#[derive(Debug)]
+pub struct AdminBusClient {
+    sender(RollupExecutorCommand),
+}
+}
+
+#[derive(Clone)]
+pub struct AdminCtx {
+    pub api_key: String,
+    pub prover_tuning: Arc<RwLock<ProverTuning>>,
+    pub prover_tuning_path: PathBuf,
+    /// Key signing the invite codes users register with
+    pub invite_key: SecretKey,
+    pub bus: Arc<Mutex<AdminBusClient>>,
+    /// Authenticates the orderbook admin by a signature of their wallet, as an alternative to
+    /// the API key
+    pub wallet_auth: WalletAuth,
+    pub admin_identity: String,
+}
+
+/// Routes reserved to operators, guarded by a static API key or a wallet signature of the
+/// orderbook admin
+pub fn router(ctx: AdminCtx) -> Router {
+    Router::new()
+        .route(
+            "/admin/prover/tuning",
+            get(get_prover_tuning).put(update_prover_tuning),
+        )
+        .route("/admin/optimistic/resync", post(resync_optimistic_state))
+        .route("/admin/invite_codes", post(create_invite_code))
+        .route_layer(middleware::from_fn_with_state(ctx.clone(), require_api_key))
+        .with_state(ctx)
+}
+
+async fn require_api_key(State(ctx): State<AdminCtx>, request: Request, next: Next) -> Response {
+    let api_key = request
+        .headers()
+        .get(ADMIN_API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if let Some(api_key) = api_key {
+        if !api_keys_match(api_key, &ctx.api_key) {
+            return (StatusCode::UNAUTHORIZED, "Invalid admin API key").into_response();
+        }
+        return next.run(request).await;
+    }
+
+    match ctx.wallet_auth.authenticate(request).await {
+        Ok((AuthenticatedUser(user), request)) if user == ctx.admin_identity => {
+            next.run(request).await
+        }
+        Ok((AuthenticatedUser(user), _)) => (
+            StatusCode::UNAUTHORIZED,
+            format!("User {} is not the orderbook admin", user),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::UNAUTHORIZED, e).into_response(),
+    }
+}
+
+/// Compares the API keys in constant time, so that response times do not reveal how much of a
+/// guessed key is right. Hashing first also hides the length of the expected key.
+fn api_keys_match(api_key: &str, expected: &str) -> bool {
+    let api_key = Sha256::digest(api_key.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    api_key
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+// --------------------------------------------------------
+//     Routes
+// --------------------------------------------------------
+
+async fn get_prover_tuning(State(ctx): State<AdminCtx>) -> impl IntoResponse {
+    Json(ctx.prover_tuning.read().await.clone())
+}
+
+/// Persists the new batching parameters, which the running provers pick up, see
+/// [`crate::prover_batching::TunedAutoProver`].
+async fn update_prover_tuning(
+    State(ctx): State<AdminCtx>,
+    Json(update): Json<ProverTuningUpdate>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut tuning = ctx.prover_tuning.write().await;
+    let mut updated = tuning.clone();
+    updated.apply(update);
+
+    if updated.max_txs_per_proof == 0 || updated.tx_working_window_size == 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "max_txs_per_proof and tx_working_window_size must be positive".to_string(),
+        ));
+    }
+
+    updated
+        .save(&ctx.prover_tuning_path)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")))?;
+    *tuning = updated.clone();
+
+    Ok(Json(updated))
+}
+
+/// Makes the executor rebuild the optimistic states from the settled ones, as it does when they
+/// diverge. Clients are told to refetch the state once it is done.
+async fn resync_optimistic_state(
+    State(ctx): State<AdminCtx>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    ctx.bus
+        .lock()
+        .await
+        .send(RollupExecutorCommand::Resync)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")))?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Signs the invite code `identity` registers with on the orderbook
+async fn create_invite_code(
+    State(ctx): State<AdminCtx>,
+    Json(request): Json<InviteCodeRequest>,
+) -> impl IntoResponse {
+    let secp = Secp256k1::new();
+    let digest = Orderbook::invite_code_digest(&request.identity);
+    let signature = secp.sign_ecdsa(&Message::from_digest(digest), &ctx.invite_key);
+    Json(InviteCode {
+        signature: hex::encode(signature.serialize_compact()),
+        public_key: hex::encode(ctx.invite_key.public_key(&secp).serialize()),
+    })
+}
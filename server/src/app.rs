@@ -1,13 +1,20 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use axum::{
-    extract::{Json, State},
-    http::Method,
-    response::IntoResponse,
-    routing::get,
+    extract::{Extension, Json, State},
+    http::{Method, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
     Router,
 };
+use client_sdk::rest_client::{NodeApiClient, NodeApiHttpClient};
 use hyle_modules::{
     bus::{BusClientSender, SharedMessageBus},
     log_warn, module_bus_client, module_handle_messages,
@@ -16,34 +23,136 @@ use hyle_modules::{
         BuildApiContextInner, Module,
     },
 };
-use orderbook::{Orderbook, OrderbookEvent};
-use sdk::{hyle_model_utils::TimestampMs, ContractName};
+use orderbook::{
+    client::{
+        alerts::{PriceAlert, PriceAlerts},
+        candles::CandleCache,
+        channels::{Channel, Subscription, SubscriptionReply, Subscriptions},
+        depth::{depth_updates, DepthMessage},
+        events::{correction_events, decode_events, sequence_events, SequencedEvent, Topic},
+        filters::{event_details, EventDetails, FilteredTopic, SubscriptionFilter},
+        shards::{merged_state, OrderbookShards},
+    },
+    error::OrderbookError,
+    history::STATS_WINDOW_MS,
+    indexer::{OrdersQuery, DEFAULT_DEPTH_LEVELS, DEFAULT_TRADES_LIMIT},
+    Orderbook, OrderbookAction, OrderbookBlob, OrderbookEvent, TokenPair,
+};
+use sdk::{
+    hyle_model_utils::TimestampMs, BlobTransaction, BlockHeight, ContractName, Hashed, HyleOutput,
+    TxHash,
+};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 use tower_http::cors::{Any, CorsLayer};
 
-use crate::rollup_executor::RollupExecutorEvent;
+use crate::{
+    order_entry::{
+        CancelRequest, OptimisticExecution, OrderEntry, OrderEntryReply, OrderRequest,
+        PendingSubmissions, Submission, SubmissionReply, SUBMISSION_TIMEOUT,
+    },
+    private_channel::{PrivateChannelAuth, PrivateChannelReply, PrivateChannels},
+    rollup_executor::{ContractBox, RollupExecutor, RollupExecutorEvent},
+    state_diff::UnsettledStates,
+    tx_lifecycle::TxTracker,
+    wallet_auth::{now_ms, require_wallet_signature, AuthenticatedUser, SessionKeys, WalletAuth},
+};
+
+#[cfg(test)]
+mod decode_tests;
+
+/// Maximum number of filtered topics published at the same time
+const MAX_FILTERED_TOPICS: usize = 1024;
+
+/// Published on the global topic when the optimistic state was rebuilt by an operator
+pub const RESYNC_NOTICE: &str = "Optimistic state resynced, refetch the orderbook state";
 
 pub struct OrderbookModule {
     bus: OrderbookModuleBusClient,
-    orderbook_cn: ContractName,
+    shards: OrderbookShards,
+    /// Optimistic state of each orderbook instance
+    shard_states: BTreeMap<ContractName, Orderbook>,
+    /// Market data of all the orderbook instances merged, served by the API
     contract: Arc<RwLock<Orderbook>>,
+    sync_status: Arc<RwLock<SyncStatus>>,
+    /// Candles of the pairs, updated with each trade
+    candles: Arc<RwLock<CandleCache>>,
+    /// Filtered topics requested by clients, by the exact name they subscribed with
+    filtered_topics: BTreeMap<String, FilteredTopic>,
+    /// Price alerts registered by clients, evaluated against the fills
+    price_alerts: PriceAlerts,
+    /// Channels the WebSocket connections subscribed to
+    subscriptions: Subscriptions,
+    /// Session keys the private endpoints authenticate users with
+    session_keys: Arc<SessionKeys>,
+    /// Node the order entries received over the WebSocket are submitted to
+    node: Arc<NodeApiHttpClient>,
+    /// Order entries and REST submissions awaiting the execution of their transaction
+    submissions: Arc<PendingSubmissions>,
+    /// Private channels the events of each user are published on
+    private_channels: PrivateChannels,
 }
 
 pub struct OrderbookModuleCtx {
     pub api: Arc<BuildApiContextInner>,
-    pub orderbook_cn: ContractName,
-    pub default_state: Orderbook,
+    /// Contract instances the orderbook is deployed as
+    pub shards: OrderbookShards,
+    pub default_states: BTreeMap<ContractName, Orderbook>,
+    /// Data directory of the rollup executor, whose saved state is served at startup
+    pub data_directory: PathBuf,
+    /// Lifecycle of the orderbook transactions, filled by the executor and the prover
+    pub tx_tracker: Arc<TxTracker>,
+    /// Session keys learnt from the users' transactions, shared with the admin API
+    pub session_keys: Arc<SessionKeys>,
+    /// Settled and optimistic states of the executor, recorded by it
+    pub unsettled_states: Arc<UnsettledStates>,
+    /// Node the order entries received over the WebSocket are submitted to
+    pub node: Arc<NodeApiHttpClient>,
 }
 
 /// Messages received from WebSocket clients that will be processed by the system
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct OrderbookWsInMessage();
+pub enum OrderbookWsInMessage {
+    /// Starts publishing a [`FilteredTopic`], e.g. `"ETH-USD|min_fill=10"`. The client still
+    /// has to register the same topic to receive its events.
+    SubscribeFiltered(String),
+    /// Registers a price alert, pushed on the user's private channels once a fill triggers it
+    SetPriceAlert(PriceAlert),
+    /// Removes all pending price alerts of a user
+    ClearPriceAlerts(String),
+    /// Subscribes the connection to a channel, e.g.
+    /// `{"subscribe": {"channel": "trades", "pair": "ETH-USD"}}`. The channel is published on
+    /// [`Subscription::topic`], which the client registers to receive it along with a
+    /// [`SubscriptionReply`].
+    #[serde(rename = "subscribe")]
+    Subscribe(Subscription),
+    /// Unsubscribes the connection from a channel, which stops being published once no
+    /// connection is subscribed to it
+    #[serde(rename = "unsubscribe")]
+    Unsubscribe(Subscription),
+    /// Submits an order or a cancellation signed by a session key of its user, see
+    /// [`OrderEntry`]. The replies are published on the user's private channels.
+    #[serde(rename = "order")]
+    SubmitOrder(OrderEntry),
+    /// Opens a private channel of a user, see [`PrivateChannelAuth`]. The client registers
+    /// [`PrivateChannelAuth::topic`] first, to receive the [`PrivateChannelReply`] and then the
+    /// events of the user.
+    #[serde(rename = "authenticate")]
+    Authenticate(PrivateChannelAuth),
+}
+
+/// Whether the optimistic state is behind the block stream
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncStatus {
+    pub catching_up: bool,
+    /// Blocks skipped by the stream, being backfilled
+    pub missing_blocks: Option<(BlockHeight, BlockHeight)>,
+}
 
 module_bus_client! {
 #[derive(Debug)]
 pub struct OrderbookModuleBusClient {
-    sender(WsTopicMessage<OrderbookEvent>),
+    sender(WsTopicMessage<SequencedEvent>),
     sender(WsTopicMessage<String>),
     receiver(WsInMessage<OrderbookWsInMessage>),
     receiver(RollupExecutorEvent),
@@ -54,11 +163,35 @@ impl Module for OrderbookModule {
     type Context = Arc<OrderbookModuleCtx>;
 
     async fn build(bus: SharedMessageBus, ctx: Self::Context) -> Result<Self> {
-        let contract = Arc::new(RwLock::new(ctx.default_state.clone()));
+        // Serve the state the executor resumes from, rather than an empty book until the next event
+        let shard_states: BTreeMap<ContractName, Orderbook> = ctx
+            .default_states
+            .iter()
+            .map(|(contract_name, default_state)| {
+                let state = RollupExecutor::saved_optimistic_state::<Orderbook>(
+                    &ctx.data_directory,
+                    contract_name,
+                )
+                .unwrap_or_else(|| default_state.clone());
+                (contract_name.clone(), state)
+            })
+            .collect();
+        let initial_state =
+            merged_state(shard_states.values()).context("No orderbook contract instance")?;
+        let candles = Arc::new(RwLock::new(CandleCache::new(&initial_state)));
+        let contract = Arc::new(RwLock::new(initial_state));
+        let sync_status = Arc::new(RwLock::new(SyncStatus::default()));
+        let submissions = Arc::new(PendingSubmissions::default());
 
         let state = RouterCtx {
-            orderbook_cn: ctx.orderbook_cn.clone(),
+            shards: ctx.shards.clone(),
             contract: contract.clone(),
+            sync_status: sync_status.clone(),
+            candles: candles.clone(),
+            tx_tracker: ctx.tx_tracker.clone(),
+            unsettled_states: ctx.unsettled_states.clone(),
+            node: ctx.node.clone(),
+            submissions: submissions.clone(),
         };
 
         let cors = CorsLayer::new()
@@ -66,32 +199,84 @@ impl Module for OrderbookModule {
             .allow_methods(vec![Method::GET, Method::POST])
             .allow_headers(Any);
 
+        // Private data and actions of the user whose wallet signed the request
+        let private = Router::new()
+            .route("/api/private/balances", get(get_own_balances))
+            .route("/api/private/orders", get(get_own_orders))
+            .route("/api/orders", post(post_order))
+            .route("/api/cancel", post(post_cancel))
+            .route_layer(middleware::from_fn_with_state(
+                WalletAuth {
+                    session_keys: ctx.session_keys.clone(),
+                },
+                require_wallet_signature,
+            ));
+
         let api = Router::new()
             .route("/_health", get(health))
             .route("/api/config", get(get_config))
+            .route("/api/status", get(get_status))
+            .route("/api/txs/{hash}", get(get_tx_lifecycle))
+            .route("/api/state/diff", get(get_state_diff))
+            .route(
+                "/api/shards/{base_token}/{quote_token}",
+                get(get_pair_contract),
+            )
             .route("/api/optimistic/state", get(get_state))
             .route("/api/optimistic/balances", get(get_balances))
             .route(
                 "/api/optimistic/balances/{account}",
                 get(get_balance_for_account),
             )
+            .route(
+                "/api/optimistic/balances/{account}/reserved",
+                get(get_reserved_for_account),
+            )
+            .route(
+                "/api/optimistic/balances/{account}/withdrawals",
+                get(get_withdrawals_for_account),
+            )
             .route("/api/optimistic/orders", get(get_orders))
             .route(
                 "/api/optimistic/orders/pair/{base_token}/{quote_token}",
                 get(get_orders_by_pair),
             )
+            .route(
+                "/api/optimistic/orderbook/{base_token}/{quote_token}/depth",
+                get(get_pair_depth),
+            )
             .route(
                 "/api/optimistic/orders/user/{address}",
                 get(get_orders_by_user),
             )
+            .route(
+                "/api/optimistic/orders/user/{address}/closed",
+                get(get_closed_orders_by_user),
+            )
+            .route("/api/optimistic/order/{order_id}", get(get_order))
             .route(
                 "/api/optimistic/orders/history/{base_token}/{quote_token}",
                 get(get_pair_history),
             )
+            .route(
+                "/api/optimistic/trades/{base_token}/{quote_token}",
+                get(get_recent_trades),
+            )
             .route(
                 "/api/optimistic/orders/candles/{base_token}/{quote_token}",
                 get(get_pair_candles),
             )
+            .route(
+                "/api/optimistic/orders/stats/{base_token}/{quote_token}",
+                get(get_pair_stats),
+            )
+            .route(
+                "/api/optimistic/ticker/{base_token}/{quote_token}",
+                get(get_ticker),
+            )
+            .route("/api/optimistic/markets", get(get_markets))
+            .route("/api/optimistic/stats/fees", get(get_fee_stats))
+            .merge(private)
             .with_state(state)
             .layer(cors);
 
@@ -105,7 +290,17 @@ impl Module for OrderbookModule {
         Ok(OrderbookModule {
             bus,
             contract,
-            orderbook_cn: ctx.orderbook_cn.clone(),
+            sync_status,
+            candles,
+            shards: ctx.shards.clone(),
+            shard_states,
+            filtered_topics: BTreeMap::new(),
+            price_alerts: PriceAlerts::default(),
+            subscriptions: Subscriptions::default(),
+            session_keys: ctx.session_keys.clone(),
+            node: ctx.node.clone(),
+            submissions,
+            private_channels: PrivateChannels::default(),
         })
     }
 
@@ -117,6 +312,10 @@ impl Module for OrderbookModule {
                 self.handle_rollup_executor_event(event).await?;
             }
 
+            listen<WsInMessage<OrderbookWsInMessage>> msg => {
+                self.handle_ws_message(msg).await?;
+            }
+
         };
 
         Ok(())
@@ -126,108 +325,546 @@ impl Module for OrderbookModule {
 impl OrderbookModule {
     async fn handle_rollup_executor_event(&mut self, event: RollupExecutorEvent) -> Result<()> {
         match event {
-            RollupExecutorEvent::TxExecutionSuccess(_, hyle_outputs, optimistic_contracts) => {
+            RollupExecutorEvent::TxExecutionSuccess(tx, hyle_outputs, optimistic_contracts) => {
                 tracing::error!("received TxExecutionSuccess");
-                let mut events = vec![];
-                for (hyle_output, contract_name) in hyle_outputs {
-                    if contract_name != self.orderbook_cn {
-                        continue;
-                    }
-                    let evts: Vec<OrderbookEvent> = borsh::from_slice(&hyle_output.program_outputs)
-                        .expect("output comes from contract, should always be valid");
+                self.session_keys.learn(&tx);
+                // Transactions that only touch other contracts (e.g. the wallet) have no events
+                let Some(orderbook_cn) = self.shards.contract_of_tx(&tx).cloned() else {
+                    return Ok(());
+                };
+                let (events, decode_errors) =
+                    decode_outputs(&orderbook_cn, &tx.hashed(), &hyle_outputs);
 
-                    for event in evts {
-                        events.push(event);
+                // Let the sender know its transaction went through, even if its events can't be published
+                for error in decode_errors {
+                    tracing::error!(
+                        tx_hash = %error.tx_hash,
+                        contract_name = %error.contract_name,
+                        "Failed to decode outputs: {}",
+                        error.error
+                    );
+                    for topic in self.topics_of(&Topic::user(tx.identity.0.clone()), |_| true) {
+                        self.bus.send(WsTopicMessage {
+                            topic,
+                            message: error.to_string(),
+                        })?;
                     }
                 }
 
                 // Update contract state for optimistic RestAPI
                 // TODO: il faudra retirer l'indexer de l'app, et le mettre direct dans le module RollupExecutor
                 // TODO: cela permettra de ne pas avoir à envoyer le state de l'orderbook à chaque transaction successful
+                let mut filter_context = vec![(EventDetails::default(), None); events.len()];
+                let mut depth_messages = vec![];
                 {
                     if let Some(orderbook_contract) = optimistic_contracts
-                        .get(&self.orderbook_cn)
+                        .get(&orderbook_cn)
                         .expect("Orderbook contract not found")
                         .downcast::<Orderbook>()
                     {
-                        let mut contract_guard = self.contract.write().await;
-                        *contract_guard = orderbook_contract.clone();
-                    }
-                }
-
-                // Send events to all clients
-                tracing::debug!("Sending events: {:?}", events);
-                for event in events {
-                    let event_clone = event.clone();
-                    match &event {
-                        OrderbookEvent::BalanceUpdated { user, .. } => {
-                            _ = log_warn!(
-                                self.bus.send(WsTopicMessage {
-                                    topic: user.clone(),
-                                    message: event_clone,
-                                }),
-                                "Failed to send balance update"
-                            );
-                        }
-                        OrderbookEvent::OrderCancelled { pair, .. }
-                        | OrderbookEvent::OrderExecuted { pair, .. }
-                        | OrderbookEvent::OrderUpdate { pair, .. } => {
-                            let pair = format!("{}-{}", pair.0, pair.1);
-                            _ = log_warn!(
-                                self.bus.send(WsTopicMessage {
-                                    topic: pair,
-                                    message: event_clone,
-                                }),
-                                "Failed to send order event"
+                        let before = self
+                            .shard_states
+                            .insert(orderbook_cn.clone(), orderbook_contract.clone());
+                        let depth_pairs = self.subscriptions.depth_pairs();
+                        if !depth_pairs.is_empty() {
+                            depth_messages = depth_updates(
+                                before.as_ref().unwrap_or(orderbook_contract),
+                                orderbook_contract,
+                                &events,
+                                &depth_pairs,
                             );
                         }
-                        OrderbookEvent::OrderCreated { order } => {
-                            let pair = format!("{}-{}", order.pair.0, order.pair.1);
-                            _ = log_warn!(
-                                self.bus.send(WsTopicMessage {
-                                    topic: pair,
-                                    message: event_clone,
-                                }),
-                                "Failed to send order created event"
+                        self.candles
+                            .write()
+                            .await
+                            .record(orderbook_contract, &events);
+                        if !self.filtered_topics.is_empty() || !self.private_channels.is_empty() {
+                            filter_context = self.filter_context(
+                                &orderbook_cn,
+                                &tx,
+                                before.as_ref().unwrap_or(orderbook_contract),
+                                orderbook_contract,
+                                &events,
                             );
                         }
+                        self.update_merged_state().await;
                     }
                 }
+
+                for triggered in self.price_alerts.trigger(&events) {
+                    for topic in
+                        self.topics_of(&Topic::user(triggered.alert.user.clone()), |_| true)
+                    {
+                        self.bus.send(WsTopicMessage {
+                            topic,
+                            message: triggered.to_string(),
+                        })?;
+                    }
+                }
+
+                self.resolve_submission(
+                    &tx.identity.0,
+                    &tx.hashed(),
+                    OptimisticExecution::Executed {
+                        events: events.clone(),
+                    },
+                )?;
+
+                // Send events to all clients, numbered so that they can detect the ones they missed
+                tracing::debug!("Sending events: {:?}", events);
+                let events = sequence_events(&*self.contract.read().await, events);
+                for (event, (details, mid)) in events.into_iter().zip(filter_context) {
+                    let topics = self.topics_of(&event.event.topic(), |filter| {
+                        filter.matches(&event.event, &details, mid)
+                    });
+                    for topic in topics
+                        .into_iter()
+                        .chain(self.subscriptions.topics_of(&event.event))
+                        .chain(self.order_owner_topics(&event.event, &details))
+                    {
+                        _ = log_warn!(
+                            self.bus.send(WsTopicMessage {
+                                topic,
+                                message: event.clone(),
+                            }),
+                            "Failed to send orderbook event"
+                        );
+                    }
+                }
+                for message in depth_messages {
+                    self.send_depth(&message)?;
+                }
                 Ok(())
             }
             RollupExecutorEvent::Rollback(optimistic_contracts) => {
                 tracing::error!("received TxExecutionRollback");
-                {
-                    if let Some(orderbook_contract) = optimistic_contracts
-                        .get(&self.orderbook_cn)
-                        .expect("Orderbook contract not found")
-                        .downcast::<Orderbook>()
+                let before = self.contract.read().await.clone();
+                self.update_shard_states(&optimistic_contracts);
+                let after = self.update_merged_state().await;
+                let corrections = correction_events(&before, &after);
+                self.candles.write().await.reseed(&after);
+                // Bring clients back in sync with the rolled back state. Corrections are not
+                // filtered: clients need all of them to get back in sync. They all carry the
+                // sequence number of the rolled back state, which events are numbered from again.
+                tracing::debug!("Sending corrections: {:?}", corrections);
+                for event in corrections {
+                    let topics = self.topics_of(&event.topic(), |_| true);
+                    for topic in topics
+                        .into_iter()
+                        .chain(self.subscriptions.topics_of(&event))
                     {
-                        let mut contract_guard = self.contract.write().await;
-                        *contract_guard = orderbook_contract.clone();
+                        _ = log_warn!(
+                            self.bus.send(WsTopicMessage {
+                                topic,
+                                message: SequencedEvent {
+                                    seq: after.event_seq(),
+                                    event: event.clone(),
+                                },
+                            }),
+                            "Failed to send orderbook correction"
+                        );
                     }
                 }
-                // Handle reverted transactions
-                // We would probably just want to notify clients about the failure
-                // todo!("Handle reverted transactions");
+                // Book sequence numbers were rolled back too, depth deltas restart from them
+                self.send_depth_snapshots(&after, &self.subscriptions.depth_pairs())?;
                 Ok(())
             }
-            RollupExecutorEvent::FailedTx(identity, tx_hash, message) => {
+            RollupExecutorEvent::Resync(optimistic_contracts) => {
+                tracing::warn!("received Resync");
+                self.update_shard_states(&optimistic_contracts);
+                let state = self.update_merged_state().await;
+                self.candles.write().await.reseed(&state);
+                // Clients can not tell what changed, they have to fetch the state again
+                let topics = self.topics_of(&Topic::Global, |_| true);
+                for topic in topics.into_iter().chain(self.subscriptions.topics()) {
+                    self.bus.send(WsTopicMessage {
+                        topic,
+                        message: RESYNC_NOTICE.to_string(),
+                    })?;
+                }
+                self.send_depth_snapshots(&state, &self.subscriptions.depth_pairs())?;
+                Ok(())
+            }
+            RollupExecutorEvent::CatchingUp(from, to) => {
+                *self.sync_status.write().await = SyncStatus {
+                    catching_up: true,
+                    missing_blocks: Some((from, to)),
+                };
+                Ok(())
+            }
+            RollupExecutorEvent::CaughtUp(_) => {
+                *self.sync_status.write().await = SyncStatus::default();
+                Ok(())
+            }
+            RollupExecutorEvent::FailedTx(identity, tx_hash, message, error) => {
                 tracing::error!("received FailedTx");
-                self.bus.send(WsTopicMessage {
-                    topic: identity.to_string(),
+                self.resolve_submission(
+                    &identity.0,
+                    &tx_hash,
+                    OptimisticExecution::Failed {
+                        message: message.clone(),
+                    },
+                )?;
+                let failure = TxFailed {
                     message: format!("Transaction {} failed: {}", tx_hash, message),
-                })?;
+                    tx_hash,
+                    error,
+                };
+                for topic in self.topics_of(&Topic::user(identity.0), |_| true) {
+                    self.bus.send(WsTopicMessage {
+                        topic,
+                        message: failure.to_message(),
+                    })?;
+                }
+                Ok(())
+            }
+            RollupExecutorEvent::TxExpired(identity, tx_hash) => {
+                tracing::warn!("received TxExpired");
+                self.resolve_submission(
+                    &identity.0,
+                    &tx_hash,
+                    OptimisticExecution::Failed {
+                        message: "Expired before being sequenced".to_string(),
+                    },
+                )?;
+                for topic in self.topics_of(&Topic::user(identity.0), |_| true) {
+                    self.bus.send(WsTopicMessage {
+                        topic,
+                        message: format!(
+                            "Transaction {} expired locally: it was not sequenced in time",
+                            tx_hash
+                        ),
+                    })?;
+                }
                 Ok(())
             }
         }
     }
+
+    /// Takes the states of all the orderbook instances among `optimistic_contracts`
+    fn update_shard_states(&mut self, optimistic_contracts: &BTreeMap<ContractName, ContractBox>) {
+        for contract_name in self.shards.contract_names() {
+            if let Some(orderbook_contract) = optimistic_contracts
+                .get(contract_name)
+                .expect("Orderbook contract not found")
+                .downcast::<Orderbook>()
+            {
+                self.shard_states
+                    .insert(contract_name.clone(), orderbook_contract.clone());
+            }
+        }
+    }
+
+    /// Publishes the merged state of the orderbook instances to the API, and returns it
+    async fn update_merged_state(&self) -> Orderbook {
+        let mut contract_guard = self.contract.write().await;
+        if let Some(merged) = merged_state(self.shard_states.values()) {
+            *contract_guard = merged;
+        }
+        contract_guard.clone()
+    }
+
+    async fn handle_ws_message(&mut self, msg: WsInMessage<OrderbookWsInMessage>) -> Result<()> {
+        match msg.message {
+            OrderbookWsInMessage::SubscribeFiltered(topic) => {
+                if self.filtered_topics.contains_key(&topic) {
+                    return Ok(());
+                }
+                if self.filtered_topics.len() >= MAX_FILTERED_TOPICS {
+                    tracing::warn!("Too many filtered topics, ignoring {}", topic);
+                    return Ok(());
+                }
+                match topic.parse::<FilteredTopic>() {
+                    Ok(FilteredTopic {
+                        topic: Topic::User(_),
+                        ..
+                    }) => tracing::warn!("User topics are private, ignoring {}", topic),
+                    Ok(filtered) => {
+                        self.filtered_topics.insert(topic, filtered);
+                    }
+                    Err(e) => tracing::warn!("Invalid filtered topic {}: {:#}", topic, e),
+                }
+            }
+            OrderbookWsInMessage::SetPriceAlert(alert) => {
+                if let Err(e) = self.price_alerts.add(alert) {
+                    tracing::warn!("Ignoring price alert: {:#}", e);
+                }
+            }
+            OrderbookWsInMessage::ClearPriceAlerts(user) => self.price_alerts.clear(&user),
+            OrderbookWsInMessage::Subscribe(subscription) => {
+                let subscribed = match subscription.token_pair() {
+                    Ok(pair) if !self.contract.read().await.is_pair_listed(&pair) => {
+                        Err(anyhow!("Pair {} is not listed", subscription.pair))
+                    }
+                    _ => self
+                        .subscriptions
+                        .subscribe(&msg.addr, subscription.clone()),
+                };
+                let depth_pair = match (&subscribed, subscription.channel) {
+                    (Ok(()), Channel::Depth) => subscription.token_pair().ok(),
+                    _ => None,
+                };
+                let reply = match subscribed {
+                    Ok(()) => SubscriptionReply::Subscribed(subscription),
+                    Err(e) => SubscriptionReply::Error {
+                        subscription,
+                        message: e.to_string(),
+                    },
+                };
+                self.send_reply(reply)?;
+                // Deltas are applied on top of a snapshot
+                if let Some(pair) = depth_pair {
+                    let contract = self.contract.clone();
+                    let state = contract.read().await;
+                    self.send_depth_snapshots(&state, &[pair])?;
+                }
+            }
+            OrderbookWsInMessage::Unsubscribe(subscription) => {
+                let reply = match self.subscriptions.unsubscribe(&msg.addr, &subscription) {
+                    Ok(()) => SubscriptionReply::Unsubscribed(subscription),
+                    Err(e) => SubscriptionReply::Error {
+                        subscription,
+                        message: e.to_string(),
+                    },
+                };
+                self.send_reply(reply)?;
+            }
+            OrderbookWsInMessage::SubmitOrder(entry) => {
+                let reply = self.submit_order_entry(&entry).await;
+                self.send_entry_reply(&entry.identity, reply)?;
+            }
+            OrderbookWsInMessage::Authenticate(auth) => {
+                let reply = match auth.authenticate(&self.session_keys, now_ms()) {
+                    Ok(()) => {
+                        self.private_channels.open(&auth.identity, auth.topic());
+                        PrivateChannelReply::Opened {
+                            identity: auth.identity.clone(),
+                        }
+                    }
+                    Err(message) => PrivateChannelReply::Refused { message },
+                };
+                self.bus.send(WsTopicMessage {
+                    topic: auth.topic(),
+                    message: serde_json::to_string(&reply)?,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the transaction of `entry` and sends it to the node
+    async fn submit_order_entry(&mut self, entry: &OrderEntry) -> OrderEntryReply {
+        if let Err(e) = entry.authenticate(&self.session_keys, now_ms()) {
+            return entry.reject(e);
+        }
+        let Some(contract_name) = self.shards.route(&entry.action, &self.shard_states) else {
+            return entry.reject("No orderbook instance trades this pair or order");
+        };
+        let tx = entry.submission().build(contract_name.clone()).tx;
+        let tx_hash = tx.hashed();
+        if !self
+            .submissions
+            .insert_entry(&tx_hash, entry.request_id.clone())
+        {
+            return entry.reject("Too many pending orders, retry later");
+        }
+        match self.node.send_tx_blob(tx).await {
+            Ok(tx_hash) => OrderEntryReply::Submitted {
+                request_id: entry.request_id.clone(),
+                tx_hash,
+            },
+            Err(e) => {
+                self.submissions.forget(&tx_hash);
+                entry.reject(format!("Could not submit the transaction: {e:#}"))
+            }
+        }
+    }
+
+    /// Hands the execution of `tx_hash` to the submission awaiting it, replying to the order
+    /// entry of `user` it may be
+    fn resolve_submission(
+        &mut self,
+        user: &str,
+        tx_hash: &TxHash,
+        execution: OptimisticExecution,
+    ) -> Result<()> {
+        if let Some((request_id, execution)) = self.submissions.resolve(tx_hash, execution) {
+            let reply = OrderEntryReply::executed(request_id, tx_hash.clone(), execution);
+            self.send_entry_reply(user, reply)?;
+        }
+        Ok(())
+    }
+
+    /// Publishes `reply` on the topics of `user`
+    fn send_entry_reply(&mut self, user: &str, reply: OrderEntryReply) -> Result<()> {
+        let message = serde_json::to_string(&reply)?;
+        for topic in self.topics_of(&Topic::user(user), |_| true) {
+            self.bus.send(WsTopicMessage {
+                topic,
+                message: message.clone(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Publishes `message` on the depth channel of its pair
+    fn send_depth(&mut self, message: &DepthMessage) -> Result<()> {
+        self.bus.send(WsTopicMessage {
+            topic: message.topic(),
+            message: serde_json::to_string(message)?,
+        })?;
+        Ok(())
+    }
+
+    /// Publishes a snapshot of the depth of each of `pairs` in `state`
+    fn send_depth_snapshots(&mut self, state: &Orderbook, pairs: &[TokenPair]) -> Result<()> {
+        for pair in pairs {
+            self.send_depth(&DepthMessage::snapshot(state, pair))?;
+        }
+        Ok(())
+    }
+
+    /// Publishes `reply` on the topic of its subscription
+    fn send_reply(&mut self, reply: SubscriptionReply) -> Result<()> {
+        self.bus.send(WsTopicMessage {
+            topic: reply.topic(),
+            message: serde_json::to_string(&reply)?,
+        })?;
+        Ok(())
+    }
+
+    /// `topic` itself, followed by its filtered topics whose filter is accepted. User topics
+    /// are only published on the private channels of the user.
+    fn topics_of(
+        &self,
+        topic: &Topic,
+        accepts: impl Fn(&SubscriptionFilter) -> bool,
+    ) -> Vec<String> {
+        if let Topic::User(user) = topic {
+            return self.private_channels.topics_of(user);
+        }
+        std::iter::once(topic.to_string())
+            .chain(
+                self.filtered_topics
+                    .iter()
+                    .filter(|(_, filtered)| &filtered.topic == topic && accepts(&filtered.filter))
+                    .map(|(name, _)| name.clone()),
+            )
+            .collect()
+    }
+
+    /// Private channels of the users whose orders `event` concerns, so that they follow the
+    /// lifecycle of their orders without subscribing to the whole pair
+    fn order_owner_topics(&self, event: &OrderbookEvent, details: &EventDetails) -> Vec<String> {
+        let owners: BTreeSet<&String> = match event {
+            OrderbookEvent::TradeExecuted { maker, taker, .. } => [maker, taker].into(),
+            _ if matches!(event.topic(), Topic::Pair(_)) => details.owner.iter().collect(),
+            _ => BTreeSet::new(),
+        };
+        owners
+            .into_iter()
+            .flat_map(|owner| self.private_channels.topics_of(owner))
+            .collect()
+    }
+
+    /// Details and mid price of the pair of each of the `events` of `tx`, for the filters
+    fn filter_context(
+        &self,
+        orderbook_cn: &ContractName,
+        tx: &BlobTransaction,
+        before: &Orderbook,
+        after: &Orderbook,
+        events: &[OrderbookEvent],
+    ) -> Vec<(EventDetails, Option<u128>)> {
+        let action = tx
+            .blobs
+            .iter()
+            .find(|blob| &blob.contract_name == orderbook_cn)
+            .and_then(|blob| OrderbookBlob::action(&blob.data));
+        let details = match action {
+            Some(action) => event_details(before, after, &tx.identity.0, &action, events),
+            None => vec![EventDetails::default(); events.len()],
+        };
+        let mids = events.iter().map(|event| match event.topic() {
+            Topic::Pair(pair) => after.mid_price(&pair),
+            _ => None,
+        });
+        details.into_iter().zip(mids).collect()
+    }
+}
+
+/// Outputs of a contract in a transaction that could not be decoded
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputDecodeError {
+    pub tx_hash: TxHash,
+    pub contract_name: ContractName,
+    pub error: String,
+}
+
+impl fmt::Display for OutputDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Outputs of contract {} in transaction {} could not be decoded: {}",
+            self.contract_name, self.tx_hash, self.error
+        )
+    }
+}
+
+/// Failure of a transaction, sent to its sender as `{"TxFailed": {..}}` like the orderbook
+/// events so that frontends can parse it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TxFailed {
+    pub tx_hash: TxHash,
+    pub message: String,
+    /// Error reported by the orderbook, None if the transaction failed in another contract
+    pub error: Option<OrderbookError>,
+}
+
+impl TxFailed {
+    pub fn to_message(&self) -> String {
+        serde_json::json!({ "TxFailed": self }).to_string()
+    }
+}
+
+/// Decodes the outputs of a transaction according to the contract that produced them.
+///
+/// Only the orderbook events are published; outputs of the other contracts of the transaction
+/// (e.g. the wallet) are skipped. Undecodable outputs are reported instead of aborting the
+/// whole transaction.
+pub fn decode_outputs(
+    orderbook_cn: &ContractName,
+    tx_hash: &TxHash,
+    outputs: &[(HyleOutput, ContractName)],
+) -> (Vec<OrderbookEvent>, Vec<OutputDecodeError>) {
+    let mut events = vec![];
+    let mut errors = vec![];
+    for (hyle_output, contract_name) in outputs {
+        if contract_name != orderbook_cn {
+            continue;
+        }
+        match decode_events(&hyle_output.program_outputs) {
+            Ok(decoded) => events.extend(decoded),
+            Err(e) => errors.push(OutputDecodeError {
+                tx_hash: tx_hash.clone(),
+                contract_name: contract_name.clone(),
+                error: format!("{e:#}"),
+            }),
+        }
+    }
+    (events, errors)
 }
 
 #[derive(Clone)]
 struct RouterCtx {
-    pub orderbook_cn: ContractName,
+    pub shards: OrderbookShards,
     pub contract: Arc<RwLock<Orderbook>>,
+    pub sync_status: Arc<RwLock<SyncStatus>>,
+    pub candles: Arc<RwLock<CandleCache>>,
+    pub tx_tracker: Arc<TxTracker>,
+    pub unsettled_states: Arc<UnsettledStates>,
+    pub node: Arc<NodeApiHttpClient>,
+    pub submissions: Arc<PendingSubmissions>,
 }
 
 async fn health() -> impl IntoResponse {
@@ -245,10 +882,40 @@ struct ConfigResponse {
 
 async fn get_config(State(ctx): State<RouterCtx>) -> impl IntoResponse {
     Json(ConfigResponse {
-        contract_name: ctx.orderbook_cn.0,
+        contract_name: ctx.shards.main_contract().0.clone(),
     })
 }
 
+async fn get_status(State(ctx): State<RouterCtx>) -> impl IntoResponse {
+    Json(ctx.sync_status.read().await.clone())
+}
+
+async fn get_tx_lifecycle(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Path(hash): axum::extract::Path<String>,
+) -> impl IntoResponse {
+    match ctx.tx_tracker.get(&TxHash(hash)) {
+        Some(lifecycle) => Json(lifecycle).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Transactions the optimistic states have on top of the settled ones, and what they change
+async fn get_state_diff(State(ctx): State<RouterCtx>) -> impl IntoResponse {
+    Json(ctx.unsettled_states.diff())
+}
+
+/// Orderbook instance the actions on a pair must be sent to
+async fn get_pair_contract(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+) -> impl IntoResponse {
+    match ctx.shards.contract_for_pair(&(base_token, quote_token)) {
+        Some(contract_name) => Json(contract_name.clone()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
 async fn get_state(State(ctx): State<RouterCtx>) -> impl IntoResponse {
     let contract = ctx.contract.read().await;
     Json(contract.get_state())
@@ -268,45 +935,227 @@ async fn get_balance_for_account(
     Json(balance)
 }
 
-async fn get_orders(State(ctx): State<RouterCtx>) -> impl IntoResponse {
+async fn get_reserved_for_account(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Path(account): axum::extract::Path<String>,
+) -> impl IntoResponse {
     let contract = ctx.contract.read().await;
-    Json(contract.get_orders())
+    Json(contract.get_reserved_for_account(&account))
+}
+
+async fn get_withdrawals_for_account(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Path(account): axum::extract::Path<String>,
+) -> impl IntoResponse {
+    let contract = ctx.contract.read().await;
+    Json(contract.get_withdrawals_for_account(&account))
+}
+
+/// Orders resting on the books, see [`OrdersQuery`] for the filters and pagination
+async fn get_orders(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let query = match OrdersQuery::from_params(|name| params.get(name)) {
+        Ok(query) => query,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    let contract = ctx.contract.read().await;
+    Json(contract.get_orders(&query)).into_response()
+}
+
+async fn get_own_balances(
+    State(ctx): State<RouterCtx>,
+    Extension(AuthenticatedUser(user)): Extension<AuthenticatedUser>,
+) -> impl IntoResponse {
+    let contract = ctx.contract.read().await;
+    Json(contract.get_balance_for_account(&user))
+}
+
+async fn get_own_orders(
+    State(ctx): State<RouterCtx>,
+    Extension(AuthenticatedUser(user)): Extension<AuthenticatedUser>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let query = match OrdersQuery::from_params(|name| params.get(name)) {
+        Ok(query) => query,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    let contract = ctx.contract.read().await;
+    Json(contract.get_orders_by_user(&user, &query)).into_response()
+}
+
+async fn post_order(
+    State(ctx): State<RouterCtx>,
+    Extension(AuthenticatedUser(user)): Extension<AuthenticatedUser>,
+    Json(request): Json<OrderRequest>,
+) -> impl IntoResponse {
+    submit(&ctx, request.submission(user)).await
+}
+
+async fn post_cancel(
+    State(ctx): State<RouterCtx>,
+    Extension(AuthenticatedUser(user)): Extension<AuthenticatedUser>,
+    Json(request): Json<CancelRequest>,
+) -> impl IntoResponse {
+    submit(&ctx, request.submission(user)).await
+}
+
+/// Builds the transaction of `submission`, sends it to the node and waits for its optimistic
+/// execution
+async fn submit(ctx: &RouterCtx, submission: Submission) -> Response {
+    let pair = match &submission.action {
+        OrderbookAction::CreateOrder { pair, .. } => Some(pair.clone()),
+        OrderbookAction::Cancel { order_id } => {
+            let contract = ctx.contract.read().await;
+            contract.get_order(order_id).map(|order| order.pair)
+        }
+        _ => None,
+    };
+    let Some(contract_name) = pair.and_then(|pair| ctx.shards.contract_for_pair(&pair)) else {
+        return (
+            StatusCode::NOT_FOUND,
+            "No orderbook instance trades this pair or order",
+        )
+            .into_response();
+    };
+
+    let tx = submission.build(contract_name.clone()).tx;
+    let tx_hash = tx.hashed();
+    // Awaited before sending, the execution can be quicker than the node's reply
+    let Some(execution) = ctx.submissions.wait(&tx_hash) else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many pending orders, retry later",
+        )
+            .into_response();
+    };
+    if let Err(e) = ctx.node.send_tx_blob(tx).await {
+        ctx.submissions.forget(&tx_hash);
+        return (
+            StatusCode::BAD_GATEWAY,
+            format!("Could not submit the transaction: {e:#}"),
+        )
+            .into_response();
+    }
+
+    let execution = match tokio::time::timeout(SUBMISSION_TIMEOUT, execution).await {
+        Ok(Ok(execution)) => execution,
+        _ => {
+            ctx.submissions.forget(&tx_hash);
+            OptimisticExecution::Pending
+        }
+    };
+    Json(SubmissionReply { tx_hash, execution }).into_response()
 }
 
 async fn get_orders_by_pair(
     State(ctx): State<RouterCtx>,
     axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
+    let query = match OrdersQuery::from_params(|name| params.get(name)) {
+        Ok(query) => query,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
     let contract = ctx.contract.read().await;
-    let orders = contract.get_orders_by_pair(&base_token, &quote_token);
-    Json(orders)
+    let orders = contract.get_orders_by_pair(&base_token, &quote_token, &query);
+    Json(orders).into_response()
+}
+
+/// Aggregated price levels of a pair, `?levels=N` of each side
+async fn get_pair_depth(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let contract = ctx.contract.read().await;
+
+    let levels = params
+        .get("levels")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_DEPTH_LEVELS);
+
+    Json(contract.get_pair_depth(&base_token, &quote_token, levels))
 }
 
 async fn get_orders_by_user(
     State(ctx): State<RouterCtx>,
     axum::extract::Path(address): axum::extract::Path<String>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
+    let query = match OrdersQuery::from_params(|name| params.get(name)) {
+        Ok(query) => query,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
     let contract = ctx.contract.read().await;
-    let orders = contract.get_orders_by_user(&address);
-    Json(orders)
+    let orders = contract.get_orders_by_user(&address, &query);
+    Json(orders).into_response()
+}
+
+async fn get_closed_orders_by_user(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Path(address): axum::extract::Path<String>,
+) -> impl IntoResponse {
+    let contract = ctx.contract.read().await;
+    Json(contract.get_closed_orders_by_user(&address))
+}
+
+/// Order with its status, so users can find out what happened to it once it left the book
+async fn get_order(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Path(order_id): axum::extract::Path<String>,
+) -> impl IntoResponse {
+    let contract = ctx.contract.read().await;
+    match contract.get_order(&order_id) {
+        Some(order) => Json(order).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
 }
 
 async fn get_pair_history(
     State(ctx): State<RouterCtx>,
     axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
     let contract = ctx.contract.read().await;
-    let history = contract.get_pair_history(&base_token, &quote_token);
+
+    let from = params
+        .get("from")
+        .and_then(|s| s.parse::<i64>().ok())
+        .map(|ts| TimestampMs(ts as u128));
+
+    let to = params
+        .get("to")
+        .and_then(|s| s.parse::<i64>().ok())
+        .map(|ts| TimestampMs(ts as u128));
+
+    let history = contract.get_pair_history(&base_token, &quote_token, from, to);
     Json(history)
 }
 
-async fn get_pair_candles(
+/// Most recent trades of a pair, `?limit=N` of them, older than the trade `?before=id`
+async fn get_recent_trades(
     State(ctx): State<RouterCtx>,
     axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
     axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
     let contract = ctx.contract.read().await;
 
+    let limit = params
+        .get("limit")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_TRADES_LIMIT);
+    let before = params.get("before").and_then(|s| s.parse::<u64>().ok());
+
+    Json(contract.get_recent_trades(&base_token, &quote_token, limit, before))
+}
+
+async fn get_pair_candles(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> impl IntoResponse {
     let from = params
         .get("from")
         .and_then(|s| s.parse::<i64>().ok())
@@ -325,6 +1174,68 @@ async fn get_pair_candles(
         .map(|i| i as u128)
         .unwrap_or(3600000); // 1 hour by default
 
-    let candles = contract.get_pair_candles(&base_token, &quote_token, from, to, interval);
-    Json(candles)
+    // The usual intervals are cached, others are aggregated from the candles of the contract
+    let pair = (base_token, quote_token);
+    if let Some(candles) = ctx.candles.read().await.range(&pair, interval, &from, &to) {
+        return Json(candles).into_response();
+    }
+    let contract = ctx.contract.read().await;
+    Json(contract.get_pair_candles(&pair.0, &pair.1, from, to, interval)).into_response()
+}
+
+async fn get_pair_stats(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+) -> impl IntoResponse {
+    let contract = ctx.contract.read().await;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| TimestampMs(duration.as_millis()))
+        .unwrap_or(TimestampMs(0));
+
+    Json(contract.get_pair_stats(&base_token, &quote_token, &now))
+}
+
+async fn get_ticker(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Path((base_token, quote_token)): axum::extract::Path<(String, String)>,
+) -> impl IntoResponse {
+    let contract = ctx.contract.read().await;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| TimestampMs(duration.as_millis()))
+        .unwrap_or(TimestampMs(0));
+
+    Json(contract.get_ticker(&base_token, &quote_token, &now))
+}
+
+/// Fees collected by token and by pair over the `window` milliseconds before now, 24 hours by
+/// default, with their hourly history
+async fn get_fee_stats(
+    State(ctx): State<RouterCtx>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let window = match params.get("window").map(|s| s.parse::<u128>()).transpose() {
+        Ok(window) => window.unwrap_or(STATS_WINDOW_MS),
+        Err(_) => {
+            return (StatusCode::BAD_REQUEST, "Invalid 'window' parameter").into_response();
+        }
+    };
+    let contract = ctx.contract.read().await;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| TimestampMs(duration.as_millis()))
+        .unwrap_or(TimestampMs(0));
+
+    Json(contract.get_fee_stats(window, &now)).into_response()
+}
+
+async fn get_markets(State(ctx): State<RouterCtx>) -> impl IntoResponse {
+    let contract = ctx.contract.read().await;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| TimestampMs(duration.as_millis()))
+        .unwrap_or(TimestampMs(0));
+
+    Json(contract.get_markets(&now))
 }
@@ -0,0 +1,77 @@
+//! Decoding of the outputs of transactions mixing the orderbook with other contracts.
+
+use orderbook::{Order, OrderStatus, OrderType, OrderbookEvent};
+use sdk::{hyle_model_utils::TimestampMs, ContractName, HyleOutput, TxHash};
+
+use super::decode_outputs;
+
+fn orderbook_cn() -> ContractName {
+    "orderbook".into()
+}
+
+fn output(program_outputs: Vec<u8>) -> HyleOutput {
+    HyleOutput {
+        success: true,
+        program_outputs,
+        ..Default::default()
+    }
+}
+
+fn events() -> Vec<OrderbookEvent> {
+    vec![
+        OrderbookEvent::OrderCreated {
+            order: Order {
+                owner: "alice@wallet".to_string(),
+                order_id: "order1".to_string(),
+                order_type: OrderType::Buy,
+                price: Some(2000),
+                trigger_price: None,
+                pair: ("hyllar".to_string(), "oranj".to_string()),
+                quantity: 1,
+                timestamp: TimestampMs(0),
+                expires_at: None,
+                display_quantity: None,
+                hidden_quantity: 0,
+                filled_quantity: 0,
+                status: OrderStatus::Open,
+                reserved_amount: 0,
+            },
+        },
+        OrderbookEvent::BalanceUpdated {
+            user: "alice@wallet".to_string(),
+            token: "oranj".to_string(),
+            amount: 0,
+        },
+    ]
+}
+
+#[test]
+fn test_wallet_outputs_are_skipped() {
+    let outputs = vec![
+        // The wallet outputs a plain message, which is not a list of orderbook events
+        (output(b"Session key is valid".to_vec()), "wallet".into()),
+        (output(borsh::to_vec(&events()).unwrap()), orderbook_cn()),
+    ];
+
+    let (decoded, errors) = decode_outputs(&orderbook_cn(), &TxHash("tx".into()), &outputs);
+    assert_eq!(decoded.len(), 2);
+    assert!(matches!(decoded[0], OrderbookEvent::OrderCreated { .. }));
+    assert!(errors.is_empty());
+}
+
+#[test]
+fn test_undecodable_outputs_are_reported() {
+    let outputs = vec![
+        (output(vec![]), "wallet".into()),
+        (output(vec![0xff; 3]), orderbook_cn()),
+        (output(borsh::to_vec(&events()).unwrap()), orderbook_cn()),
+    ];
+
+    let (decoded, errors) = decode_outputs(&orderbook_cn(), &TxHash("tx".into()), &outputs);
+    // The valid outputs are still published
+    assert_eq!(decoded.len(), 2);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].contract_name, orderbook_cn());
+    assert_eq!(errors[0].tx_hash, TxHash("tx".into()));
+    assert!(errors[0].to_string().contains("could not be decoded"));
+}
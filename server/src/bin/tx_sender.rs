@@ -1,10 +1,25 @@
-use anyhow::{Context, Result};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
 use clap::{command, Parser, Subcommand};
 use client_sdk::rest_client::{NodeApiClient, NodeApiHttpClient};
 use hyle_modules::utils::logger::setup_tracing;
-use orderbook::{OrderType, OrderbookAction};
-use sdk::{BlobTransaction, ContractName};
-use server::conf::Conf;
+use orderbook::{
+    blobs::{deposit_blob, payout_blob},
+    Order, OrderStatus, OrderType, Orderbook, OrderbookAction, SelfTradePrevention, TimeInForce,
+};
+use rand::Rng;
+use sdk::{hyle_model_utils::TimestampMs, BlobIndex, BlobTransaction, ContractName};
+use server::{
+    conf::Conf,
+    tx_lifecycle::{TxLifecycle, TxStage},
+};
+use tokio::task::JoinSet;
 
 #[derive(Parser, Debug)]
 #[command(version, about = "Send transactions to a node", long_about = None)]
@@ -15,6 +30,10 @@ pub struct Args {
     #[arg(long, default_value = "orderbook")]
     pub orderbook_cn: String,
 
+    /// Orderbook server url. Defaults to localhost on the configured REST port
+    #[arg(long)]
+    pub server_url: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -23,37 +42,103 @@ pub struct Args {
 enum Commands {
     /// Create a new order
     CreateOrder {
-        #[arg(long)]
-        order_id: String,
         #[arg(long)]
         order_type: String,
         #[arg(long)]
-        price: Option<u32>,
+        price: Option<u128>,
         #[arg(long)]
         pair_token1: String,
         #[arg(long)]
         pair_token2: String,
         #[arg(long)]
-        quantity: u32,
+        quantity: u128,
+        /// "gtc", "ioc" or "fok"
+        #[arg(long, default_value = "gtc")]
+        time_in_force: String,
+        /// Makes the order a stop order, activated once the last traded price crosses it
+        #[arg(long)]
+        trigger_price: Option<u128>,
+        /// Instead of trading with the sender's own orders: "cancel-newest", "cancel-oldest" or
+        /// "decrement-and-cancel"
+        #[arg(long)]
+        self_trade_prevention: Option<String>,
+        /// Time the order expires at, in milliseconds since the epoch
+        #[arg(long)]
+        expires_at: Option<u128>,
+        /// Makes the order an iceberg order, only showing this much of its quantity at a time
+        #[arg(long)]
+        display_quantity: Option<u128>,
     },
     /// Cancel an existing order
     Cancel {
         #[arg(long)]
         order_id: String,
     },
+    /// Change the price and quantity of a resting order
+    ModifyOrder {
+        #[arg(long)]
+        order_id: String,
+        #[arg(long)]
+        new_price: u128,
+        #[arg(long)]
+        new_quantity: u128,
+    },
     /// Deposit tokens
     Deposit {
         #[arg(long)]
         token: String,
         #[arg(long)]
-        amount: u32,
+        amount: u128,
     },
     /// Withdraw tokens
     Withdraw {
         #[arg(long)]
         token: String,
         #[arg(long)]
-        amount: u32,
+        amount: u128,
+        /// Identity to send the tokens to, instead of the sender's
+        #[arg(long)]
+        recipient: Option<String>,
+    },
+    /// Cancel all orders, withdraw all balances and remove the account
+    CloseAccount,
+    /// Cancel the expired orders of a pair
+    PruneExpired {
+        #[arg(long)]
+        pair_token1: String,
+        #[arg(long)]
+        pair_token2: String,
+    },
+    /// Send randomized order flow from generated identities and report error rates and the
+    /// latency of the optimistic executions
+    Stress {
+        /// Transactions rate, e.g. "50/s" or "600/m"
+        #[arg(long, default_value = "10/s")]
+        rate: String,
+        /// Duration of the test, e.g. "30s", "10m" or "1h"
+        #[arg(long, default_value = "1m")]
+        duration: String,
+        /// Pair to trade, as "BASE/QUOTE"
+        #[arg(long, default_value = "ETH/USD")]
+        pair: String,
+        /// Number of identities sending orders
+        #[arg(long, default_value_t = 10)]
+        identities: usize,
+        /// Price around which orders are placed
+        #[arg(long, default_value_t = 2000)]
+        mid_price: u128,
+    },
+    /// Dump the full orderbook state served by the server to a file
+    ExportState {
+        /// Which state to export: "settled" or "optimistic"
+        #[arg(long, default_value = "settled")]
+        source: String,
+        /// Output file
+        #[arg(long, default_value = "state.json")]
+        out: PathBuf,
+        /// Output format: "json" or "borsh"
+        #[arg(long, default_value = "json")]
+        format: String,
     },
 }
 
@@ -67,39 +152,185 @@ async fn main() -> Result<()> {
     let client = NodeApiHttpClient::new(config.node_url).context("build node client")?;
 
     let action = match args.command {
+        Commands::ExportState {
+            source,
+            out,
+            format,
+        } => {
+            let server_url = args
+                .server_url
+                .unwrap_or(format!("http://localhost:{}", config.rest_server_port));
+            return export_state(&server_url, &args.orderbook_cn, &source, &out, &format).await;
+        }
+        Commands::Stress {
+            rate,
+            duration,
+            pair,
+            identities,
+            mid_price,
+        } => {
+            let Some((base, quote)) = pair.split_once('/') else {
+                bail!("Invalid pair. Must be formatted as 'BASE/QUOTE'");
+            };
+            let server_url = args
+                .server_url
+                .unwrap_or(format!("http://localhost:{}", config.rest_server_port));
+            // Ids of the orders to cancel are predicted from the number of orders created
+            let state = fetch_state(&server_url, &args.orderbook_cn, "optimistic").await?;
+            let stress = StressTest {
+                client: Arc::new(client),
+                http: reqwest::Client::new(),
+                server_url,
+                contract_name: ContractName(args.orderbook_cn),
+                pair: (base.to_string(), quote.to_string()),
+                identities: (0..identities)
+                    .map(|i| format!("stress{}_{}@orderbook", i, uuid::Uuid::new_v4().simple()))
+                    .collect(),
+                mid_price,
+                next_order_seq: state.next_order_seq(),
+                next_nonce: 1,
+            };
+            return stress
+                .run(parse_rate(&rate)?, parse_duration(&duration)?)
+                .await;
+        }
         Commands::CreateOrder {
-            order_id,
             order_type,
             price,
             pair_token1,
             pair_token2,
             quantity,
+            time_in_force,
+            trigger_price,
+            self_trade_prevention,
+            expires_at,
+            display_quantity,
         } => {
             let order_type = match order_type.to_lowercase().as_str() {
                 "buy" => OrderType::Buy,
                 "sell" => OrderType::Sell,
                 _ => anyhow::bail!("Invalid order type. Must be 'buy' or 'sell'"),
             };
+            let time_in_force = match time_in_force.to_lowercase().as_str() {
+                "gtc" => TimeInForce::GoodTilCancelled,
+                "ioc" => TimeInForce::ImmediateOrCancel,
+                "fok" => TimeInForce::FillOrKill,
+                _ => anyhow::bail!("Invalid time in force. Must be 'gtc', 'ioc' or 'fok'"),
+            };
+            let self_trade_prevention = match self_trade_prevention.as_deref() {
+                None => None,
+                Some("cancel-newest") => Some(SelfTradePrevention::CancelNewest),
+                Some("cancel-oldest") => Some(SelfTradePrevention::CancelOldest),
+                Some("decrement-and-cancel") => Some(SelfTradePrevention::DecrementAndCancel),
+                Some(_) => anyhow::bail!(
+                    "Invalid self-trade prevention. Must be 'cancel-newest', 'cancel-oldest' or 'decrement-and-cancel'"
+                ),
+            };
 
             OrderbookAction::CreateOrder {
-                order_id,
                 order_type,
                 price,
                 pair: (pair_token1, pair_token2),
                 quantity,
+                time_in_force,
+                trigger_price,
+                self_trade_prevention,
+                expires_at: expires_at.map(TimestampMs),
+                display_quantity,
             }
         }
         Commands::Cancel { order_id } => OrderbookAction::Cancel { order_id },
+        Commands::ModifyOrder {
+            order_id,
+            new_price,
+            new_quantity,
+        } => OrderbookAction::ModifyOrder {
+            order_id,
+            new_price,
+            new_quantity,
+        },
         Commands::Deposit { token, amount } => OrderbookAction::Deposit { token, amount },
-        Commands::Withdraw { token, amount } => OrderbookAction::Withdraw { token, amount },
+        Commands::Withdraw {
+            token,
+            amount,
+            recipient,
+        } => OrderbookAction::Withdraw {
+            token,
+            amount,
+            recipient,
+        },
+        Commands::CloseAccount => OrderbookAction::CloseAccount,
+        Commands::PruneExpired {
+            pair_token1,
+            pair_token2,
+        } => OrderbookAction::PruneExpired {
+            pair: (pair_token1, pair_token2),
+        },
     };
 
     tracing::info!("Action to be sent: {:?}", action);
 
     // Create the blob for the action
-    let blob = action.as_blob(ContractName(args.orderbook_cn));
+    let orderbook_cn = ContractName(args.orderbook_cn);
+    let identity = "txsender@orderbook";
+    let mut blobs = vec![];
+    match &action {
+        OrderbookAction::Withdraw {
+            token,
+            amount,
+            recipient,
+        } => {
+            // The orderbook blob, coming next, pays out the tokens
+            let recipient = recipient.as_deref().unwrap_or(identity);
+            blobs.push(payout_blob(
+                token.as_str().into(),
+                &orderbook_cn,
+                recipient.into(),
+                *amount,
+                BlobIndex(1),
+            ));
+        }
+        OrderbookAction::Deposit { token, amount } => {
+            // The orderbook blob, coming next, credits the tokens transferred to it
+            blobs.push(deposit_blob(
+                token.as_str().into(),
+                identity.into(),
+                &orderbook_cn,
+                *amount,
+            ));
+        }
+        OrderbookAction::CloseAccount if config.withdrawal_delay_blocks == 0 => {
+            // The orderbook blob, coming next, pays out every balance, including the
+            // reservations of the orders it cancels
+            let server_url = args
+                .server_url
+                .unwrap_or(format!("http://localhost:{}", config.rest_server_port));
+            let state = fetch_state(&server_url, &orderbook_cn.0, "optimistic").await?;
+            let mut balances = state.get_balance_for_account(identity).unwrap_or_default();
+            for (token, reserved) in state.get_reserved_for_account(identity) {
+                *balances.entry(token).or_default() += reserved;
+            }
+            balances.retain(|_, amount| *amount > 0);
+            for (token, amount) in &balances {
+                blobs.push(payout_blob(
+                    token.as_str().into(),
+                    &orderbook_cn,
+                    identity.into(),
+                    *amount,
+                    BlobIndex(balances.len()),
+                ));
+            }
+        }
+        _ => {}
+    }
+    // The sender identity is shared by all runs, the current time keeps its nonces increasing
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("reading the system time")?
+        .as_millis() as u64;
+    blobs.push(action.as_blob(orderbook_cn, nonce));
 
-    let blob_tx = BlobTransaction::new("txsender@orderbook", vec![blob]);
+    let blob_tx = BlobTransaction::new(identity, blobs);
 
     // Send transaction
     let tx_hash = client.send_tx_blob(blob_tx).await?;
@@ -108,3 +339,340 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Fetches the orderbook state from the server and writes it normalized on disk
+async fn export_state(
+    server_url: &str,
+    orderbook_cn: &str,
+    source: &str,
+    out: &Path,
+    format: &str,
+) -> Result<()> {
+    let state = fetch_state(server_url, orderbook_cn, source).await?;
+
+    let bytes = match format {
+        "json" => serde_json::to_vec_pretty(&state)?,
+        "borsh" => borsh::to_vec(&state)?,
+        _ => bail!("Invalid format. Must be 'json' or 'borsh'"),
+    };
+    std::fs::write(out, bytes).context("writing state dump")?;
+
+    println!("Exported {} state to {}", source, out.display());
+    Ok(())
+}
+
+/// Fetches the settled or optimistic orderbook state from the server
+async fn fetch_state(server_url: &str, orderbook_cn: &str, source: &str) -> Result<Orderbook> {
+    let url = match source {
+        "settled" => format!("{server_url}/v1/indexer/contract/{orderbook_cn}/state"),
+        "optimistic" => format!("{server_url}/api/optimistic/state"),
+        _ => bail!("Invalid source. Must be 'settled' or 'optimistic'"),
+    };
+
+    // Decoding into the contract type validates the dump against the current state layout
+    reqwest::get(&url)
+        .await
+        .context("fetching orderbook state")?
+        .error_for_status()?
+        .json()
+        .await
+        .context("decoding orderbook state")
+}
+
+/// Parses a rate such as "50/s", "600/m" or "3600/h" into a number of transactions per second
+fn parse_rate(rate: &str) -> Result<f64> {
+    let (count, unit) = rate.split_once('/').unwrap_or((rate, "s"));
+    let count: f64 = count.trim().parse().context("parsing rate")?;
+    let per_secs = match unit.trim() {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => bail!("Invalid rate unit. Must be 's', 'm' or 'h'"),
+    };
+    if count <= 0.0 {
+        bail!("Rate must be positive");
+    }
+    Ok(count / per_secs)
+}
+
+/// Parses a duration such as "30s", "10m" or "1h"
+fn parse_duration(duration: &str) -> Result<Duration> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(split);
+    let value: u64 = value.parse().context("parsing duration")?;
+    let secs = match unit {
+        "" | "s" => value,
+        "m" => value * 60,
+        "h" => value * 3600,
+        _ => bail!("Invalid duration unit. Must be 's', 'm' or 'h'"),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+/// Longest time a stress transaction waits for its optimistic execution
+const OPTIMISTIC_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct StressTest {
+    client: Arc<NodeApiHttpClient>,
+    /// Client of the server, which reports the optimistic executions
+    http: reqwest::Client,
+    server_url: String,
+    contract_name: ContractName,
+    pair: (String, String),
+    identities: Vec<String>,
+    mid_price: u128,
+    /// Sequence number of the next order created, see [`Orderbook::order_id`]
+    next_order_seq: u64,
+    /// Shared by the identities, which are all new
+    next_nonce: u64,
+}
+
+#[derive(Default)]
+struct StressStats {
+    sent: usize,
+    errors: usize,
+    latencies: Vec<Duration>,
+}
+
+impl StressStats {
+    /// Records the outcome of a transaction, tracking the order it created
+    fn record(
+        &mut self,
+        (result, created): (Result<Duration>, Option<(String, String)>),
+        open_orders: &mut Vec<(String, String)>,
+    ) {
+        self.sent += 1;
+        match result {
+            Ok(latency) => {
+                self.latencies.push(latency);
+                open_orders.extend(created);
+            }
+            Err(e) => {
+                self.errors += 1;
+                tracing::debug!("Transaction rejected: {:?}", e);
+            }
+        }
+    }
+
+    fn percentile(sorted: &[Duration], p: f64) -> Duration {
+        if sorted.is_empty() {
+            return Duration::ZERO;
+        }
+        let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+        sorted[index]
+    }
+
+    fn report(&self, elapsed: Duration) {
+        let mut latencies = self.latencies.clone();
+        latencies.sort();
+        println!(
+            "[{:>6.1}s] sent: {}, accepted: {}, errors: {} ({:.2}%), latency p50: {:?}, p95: {:?}, p99: {:?}, max: {:?}",
+            elapsed.as_secs_f64(),
+            self.sent,
+            latencies.len(),
+            self.errors,
+            if self.sent == 0 { 0.0 } else { self.errors as f64 * 100.0 / self.sent as f64 },
+            Self::percentile(&latencies, 0.50),
+            Self::percentile(&latencies, 0.95),
+            Self::percentile(&latencies, 0.99),
+            latencies.last().copied().unwrap_or_default(),
+        );
+    }
+}
+
+impl StressTest {
+    /// Sends a transaction and waits for its optimistic execution, which the latency is
+    /// measured up to
+    fn send(
+        &self,
+        identity: String,
+        blobs: Vec<sdk::Blob>,
+    ) -> impl Future<Output = Result<Duration>> {
+        let client = self.client.clone();
+        let http = self.http.clone();
+        let server_url = self.server_url.clone();
+        async move {
+            let start = Instant::now();
+            let tx_hash = client
+                .send_tx_blob(BlobTransaction::new(identity, blobs))
+                .await?;
+
+            let url = format!("{server_url}/api/txs/{tx_hash}");
+            while start.elapsed() < OPTIMISTIC_TIMEOUT {
+                let response = http.get(&url).send().await?;
+                // Not received from the mempool yet
+                if response.status() != reqwest::StatusCode::NOT_FOUND {
+                    let lifecycle: TxLifecycle = response.error_for_status()?.json().await?;
+                    match lifecycle.stage() {
+                        Some(TxStage::Failed) => bail!("Transaction {tx_hash} failed"),
+                        Some(TxStage::TimedOut) => bail!("Transaction {tx_hash} timed out"),
+                        Some(TxStage::Received) | None => {}
+                        Some(_) => return Ok(start.elapsed()),
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            bail!("Transaction {tx_hash} was not executed within {OPTIMISTIC_TIMEOUT:?}")
+        }
+    }
+
+    fn blob(&mut self, action: &OrderbookAction) -> sdk::Blob {
+        self.next_nonce += 1;
+        action.as_blob(self.contract_name.clone(), self.next_nonce - 1)
+    }
+
+    /// Funds every identity, then waits for the deposits to be usable by orders
+    async fn fund_identities(&mut self) -> Result<()> {
+        let start_height = self.client.get_block_height().await?;
+        for identity in self.identities.clone() {
+            for (token, amount) in [
+                (self.pair.0.clone(), 1_000_000),
+                (self.pair.1.clone(), 1_000_000_000),
+            ] {
+                let transfer = deposit_blob(
+                    token.as_str().into(),
+                    identity.as_str().into(),
+                    &self.contract_name,
+                    amount,
+                );
+                let action = OrderbookAction::Deposit { token, amount };
+                let blob = self.blob(&action);
+                self.send(identity.clone(), vec![transfer, blob])
+                    .await
+                    .context("funding stress identities")?;
+            }
+        }
+
+        // Orders are rejected for 5 blocks after a deposit
+        println!("Waiting for deposits to be usable...");
+        while self.client.get_block_height().await?.0 < start_height.0 + 6 {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        Ok(())
+    }
+
+    /// Takes a random order of `open_orders` that still rests in the optimistic state. The
+    /// ones that were filled or cancelled meanwhile are dropped.
+    async fn take_resting_order(
+        &self,
+        open_orders: &mut Vec<(String, String)>,
+    ) -> Result<Option<(String, String)>> {
+        while !open_orders.is_empty() {
+            let index = rand::rng().random_range(0..open_orders.len());
+            let (identity, order_id) = open_orders.swap_remove(index);
+            let url = format!("{}/api/optimistic/order/{order_id}", self.server_url);
+            let response = self.http.get(&url).send().await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                continue;
+            }
+            let order: Order = response.error_for_status()?.json().await?;
+            if matches!(
+                order.status,
+                OrderStatus::Open | OrderStatus::PartiallyFilled
+            ) {
+                return Ok(Some((identity, order_id)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Order created by a random identity, along with its id. Ids are only predicted: they are
+    /// off if other clients create orders, or if some creations fail.
+    fn random_order(&mut self) -> (String, OrderbookAction, String) {
+        let mut rng = rand::rng();
+        let identity = self.identities[rng.random_range(0..self.identities.len())].clone();
+        let order_type = if rng.random_bool(0.5) {
+            OrderType::Buy
+        } else {
+            OrderType::Sell
+        };
+        let spread = (self.mid_price / 20).max(1);
+        let price = match order_type {
+            OrderType::Buy => self.mid_price.saturating_sub(rng.random_range(0..=spread)),
+            OrderType::Sell => self.mid_price + rng.random_range(0..=spread),
+        };
+        let order_id = Orderbook::order_id(&self.pair, self.next_order_seq);
+        self.next_order_seq += 1;
+
+        (
+            identity,
+            OrderbookAction::CreateOrder {
+                order_type,
+                price: Some(price.max(1)),
+                pair: self.pair.clone(),
+                quantity: rng.random_range(1..=5),
+                time_in_force: TimeInForce::GoodTilCancelled,
+                trigger_price: None,
+                self_trade_prevention: None,
+                expires_at: None,
+                display_quantity: None,
+            },
+            order_id,
+        )
+    }
+
+    async fn run(mut self, rate: f64, duration: Duration) -> Result<()> {
+        if self.identities.is_empty() {
+            bail!("At least one identity is required");
+        }
+        self.fund_identities().await?;
+
+        println!(
+            "Sending {:.1} tx/s for {:?} on {}/{} from {} identities",
+            rate,
+            duration,
+            self.pair.0,
+            self.pair.1,
+            self.identities.len()
+        );
+
+        let mut ticker = tokio::time::interval(Duration::from_secs_f64(1.0 / rate));
+        let mut tasks = JoinSet::new();
+        let mut stats = StressStats::default();
+        let mut open_orders = vec![];
+        let start = Instant::now();
+        let mut last_report = start;
+
+        while start.elapsed() < duration {
+            ticker.tick().await;
+
+            // Cancel one of our resting orders from time to time. Orders are only cancelled
+            // once their creation was executed.
+            let cancel = if rand::rng().random_bool(0.1) {
+                self.take_resting_order(&mut open_orders).await?
+            } else {
+                None
+            };
+            let (identity, action, created) = match cancel {
+                Some((identity, order_id)) => {
+                    (identity, OrderbookAction::Cancel { order_id }, None)
+                }
+                None => {
+                    let (identity, action, order_id) = self.random_order();
+                    (identity.clone(), action, Some((identity, order_id)))
+                }
+            };
+            let blob = self.blob(&action);
+            let sent = self.send(identity, vec![blob]);
+            tasks.spawn(async move { (sent.await, created) });
+
+            while let Some(result) = tasks.try_join_next() {
+                stats.record(result?, &mut open_orders);
+            }
+            if last_report.elapsed() >= Duration::from_secs(10) {
+                stats.report(start.elapsed());
+                last_report = Instant::now();
+            }
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            stats.record(result?, &mut open_orders);
+        }
+        stats.report(start.elapsed());
+
+        Ok(())
+    }
+}
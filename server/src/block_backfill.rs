@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use hyle_modules::{
+    bus::{BusClientSender, SharedMessageBus},
+    log_error, module_bus_client, module_handle_messages,
+    modules::Module,
+};
+use sdk::{Block, BlockHeight, NodeStateEvent};
+use tracing::warn;
+
+/// Number of recent blocks kept to fill the gaps of other modules
+pub const MAX_RECORDED_BLOCKS: usize = 1000;
+
+/// Asks for the blocks from `from` to `to` (included) again, after they were skipped
+#[derive(Debug, Clone)]
+pub struct BlockBackfillRequest {
+    pub from: BlockHeight,
+    pub to: BlockHeight,
+}
+
+/// Answer to a [`BlockBackfillRequest`], with the requested blocks that are still recorded
+#[derive(Debug, Clone)]
+pub struct BackfilledBlocks(pub Vec<Block>);
+
+module_bus_client! {
+#[derive(Debug)]
+pub struct BlockBackfillBusClient {
+    sender(BackfilledBlocks),
+    receiver(NodeStateEvent),
+    receiver(BlockBackfillRequest),
+}
+}
+
+/// Records the latest blocks of the DA stream, and sends them again to the modules that skipped
+/// some of them, e.g. after lagging behind the bus.
+pub struct BlockBackfill {
+    bus: BlockBackfillBusClient,
+    blocks: VecDeque<Block>,
+}
+
+impl Module for BlockBackfill {
+    type Context = ();
+
+    async fn build(bus: SharedMessageBus, _ctx: Self::Context) -> Result<Self> {
+        let bus = BlockBackfillBusClient::new_from_bus(bus.new_handle()).await;
+        Ok(BlockBackfill {
+            bus,
+            blocks: VecDeque::new(),
+        })
+    }
+
+    async fn run(&mut self) -> Result<()> {
+        module_handle_messages! {
+            on_self self,
+            listen<NodeStateEvent> event => {
+                let NodeStateEvent::NewBlock(block) = event;
+                self.record(*block);
+            }
+
+            listen<BlockBackfillRequest> request => {
+                _ = log_error!(self.handle_request(request), "handle block backfill request");
+            }
+        };
+
+        Ok(())
+    }
+}
+
+impl BlockBackfill {
+    fn record(&mut self, block: Block) {
+        if self
+            .blocks
+            .back()
+            .is_some_and(|last| last.block_height >= block.block_height)
+        {
+            return;
+        }
+        self.blocks.push_back(block);
+        if self.blocks.len() > MAX_RECORDED_BLOCKS {
+            self.blocks.pop_front();
+        }
+    }
+
+    fn handle_request(&mut self, request: BlockBackfillRequest) -> Result<()> {
+        let blocks: Vec<Block> = self
+            .blocks
+            .iter()
+            .filter(|block| block.block_height >= request.from && block.block_height <= request.to)
+            .cloned()
+            .collect();
+        let expected = request.to.0 - request.from.0 + 1;
+        if (blocks.len() as u64) < expected {
+            warn!(
+                from = request.from.0,
+                to = request.to.0,
+                "Only {} of the {} requested blocks are still recorded",
+                blocks.len(),
+                expected
+            );
+        }
+        self.bus.send(BackfilledBlocks(blocks))?;
+        Ok(())
+    }
+}
@@ -1,7 +1,14 @@
+use anyhow::{anyhow, Context};
 use config::{Config, Environment, File};
 use hyle_modules::modules::websocket::WebSocketConfig;
+use orderbook::{client::shards::OrderbookShards, IdentityVerification, TokenPair};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use crate::{prover_batching::AdaptiveBatchingConf, settlement_report::SettlementReportConf};
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Conf {
@@ -19,6 +26,18 @@ pub struct Conf {
 
     pub rest_server_port: u16,
     pub rest_server_max_body_size: usize,
+    /// API key required on admin routes. Admin routes are disabled when unset.
+    pub admin_api_key: Option<String>,
+    /// Identity allowed to send admin actions to the orderbook contract (e.g. maker rewards)
+    pub orderbook_admin: String,
+    /// Proof of their identity the transactions sent to the orderbook must carry. Unless
+    /// "Trusted", the admin identity must be authenticated by a wallet too.
+    #[serde(default)]
+    pub identity_verification: IdentityVerification,
+    /// Number of blocks between the request of a withdrawal and its claim, during which the
+    /// admin can cancel it. Withdrawals are immediate when 0.
+    #[serde(default)]
+    pub withdrawal_delay_blocks: u64,
 
     pub buffer_blocks: u32,
     pub max_txs_per_proof: usize,
@@ -27,6 +46,28 @@ pub struct Conf {
     pub websocket: WebSocketConfig,
 
     pub tx_working_window_size: usize,
+
+    /// Number of times a failed proof submission is retried before giving up
+    pub proof_submission_max_retries: u32,
+    /// Number of retries after which a proof submission raises an alert
+    pub proof_submission_alert_threshold: u32,
+
+    /// Number of blocks an optimistic transaction can wait to be sequenced before being dropped.
+    /// 0 disables the eviction.
+    pub unsequenced_tx_timeout_blocks: u64,
+
+    /// Contract instances the orderbook is sharded across, with the pairs each one trades,
+    /// formatted as `"{base}-{quote}"`. An instance without pairs trades all the other ones.
+    /// The orderbook runs as a single instance when empty.
+    #[serde(default)]
+    pub orderbook_shards: BTreeMap<String, Vec<String>>,
+
+    /// Bounds within which `buffer_blocks` and `max_txs_per_proof` follow the load.
+    /// The batching is fixed when unset.
+    pub adaptive_batching: Option<AdaptiveBatchingConf>,
+
+    /// Time of the daily settlement report. No report is produced when unset.
+    pub settlement_report: Option<SettlementReportConf>,
 }
 
 impl Conf {
@@ -51,4 +92,28 @@ impl Conf {
             .try_deserialize()?;
         Ok(conf)
     }
+
+    /// Orderbook instances to deploy, a single `orderbook_cn` instance if not sharded
+    pub fn orderbook_shards(&self, orderbook_cn: &str) -> anyhow::Result<OrderbookShards> {
+        if self.orderbook_shards.is_empty() {
+            return Ok(OrderbookShards::single(orderbook_cn.into()));
+        }
+        let mut shards = BTreeMap::new();
+        for (contract_name, pairs) in &self.orderbook_shards {
+            let pairs = pairs
+                .iter()
+                .map(|pair| parse_pair(pair))
+                .collect::<anyhow::Result<BTreeSet<TokenPair>>>()
+                .with_context(|| format!("reading pairs of orderbook instance {contract_name}"))?;
+            shards.insert(contract_name.clone().into(), pairs);
+        }
+        OrderbookShards::new(shards)
+    }
+}
+
+fn parse_pair(pair: &str) -> anyhow::Result<TokenPair> {
+    let (base, quote) = pair
+        .split_once('-')
+        .ok_or(anyhow!("Invalid pair '{}', expected BASE-QUOTE", pair))?;
+    Ok((base.to_string(), quote.to_string()))
 }
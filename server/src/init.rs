@@ -8,6 +8,8 @@ pub struct ContractInit {
     pub name: ContractName,
     pub program_id: Vec<u8>,
     pub initial_state: StateCommitment,
+    /// Data the contract needs, besides its commitment, to rebuild its initial state
+    pub constructor_metadata: Option<Vec<u8>>,
 }
 
 pub async fn init_node(
@@ -47,6 +49,7 @@ async fn init_contract(
                 program_id: ProgramId(contract.program_id.to_vec()),
                 state_commitment: contract.initial_state,
                 contract_name: contract.name.clone(),
+                constructor_metadata: contract.constructor_metadata,
                 ..Default::default()
             })
             .await?;
@@ -1,4 +1,15 @@
+pub mod admin;
 pub mod app;
+pub mod block_backfill;
 pub mod conf;
 pub mod init;
+pub mod node_client;
+pub mod order_entry;
+pub mod private_channel;
+pub mod proof_cache;
+pub mod prover_batching;
 pub mod rollup_executor;
+pub mod settlement_report;
+pub mod state_diff;
+pub mod tx_lifecycle;
+pub mod wallet_auth;
@@ -8,22 +8,33 @@ use hyle_modules::{
     modules::{
         contract_state_indexer::{ContractStateIndexer, ContractStateIndexerCtx},
         da_listener::{DAListener, DAListenerConf},
-        prover::{AutoProver, AutoProverCtx},
+        prover::AutoProverCtx,
         rest::{RestApi, RestApiRunContext},
         websocket::WebSocketModule,
         BuildApiContextInner, ModulesHandler,
     },
     utils::logger::setup_tracing,
 };
-use orderbook::{Orderbook, OrderbookEvent};
+use orderbook::{client::events::SequencedEvent, Orderbook};
 use prometheus::Registry;
 use sdk::{api::NodeInfo, info, ContractName, ZkContract};
 use secp256k1::PublicKey;
 use secp256k1::Secp256k1;
 use secp256k1::SecretKey;
+use server::admin::{self, AdminBusClient, AdminCtx, ProverTuning};
+use server::block_backfill::BlockBackfill;
 use server::conf::Conf;
 use server::init;
+use server::node_client::RetryingNodeClient;
+use server::proof_cache::CachingProver;
+use server::prover_batching::{
+    adapt_prover_batching, ProofLatencies, TunedAutoProver, TunedAutoProverCtx,
+};
 use server::rollup_executor::{RollupExecutor, RollupExecutorCtx};
+use server::settlement_report::{SettlementReporter, SettlementReporterCtx};
+use server::state_diff::UnsettledStates;
+use server::tx_lifecycle::TxTracker;
+use server::wallet_auth::{SessionKeys, WalletAuth};
 use server::{
     app::{OrderbookModule, OrderbookModuleCtx, OrderbookWsInMessage},
     rollup_executor::ContractBox,
@@ -31,7 +42,7 @@ use server::{
 use sp1_sdk::{Prover, ProverClient};
 use std::env;
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    collections::BTreeMap,
     sync::{Arc, Mutex},
 };
 use tracing::error;
@@ -77,6 +88,7 @@ async fn main() -> Result<()> {
 
     info!("Building Proving Key");
     let prover = client_sdk::helpers::sp1::SP1Prover::new(pk).await;
+    let program_id = prover.program_id().expect("getting program id");
 
     let validator_lane_id = node_client
         .get_node_info()
@@ -91,13 +103,35 @@ async fn main() -> Result<()> {
         return Ok(());
     };
 
-    let default_state = Orderbook::init(validator_lane_id.clone());
+    let secp = Secp256k1::new();
+    let secret_key =
+        hex::decode(env::var("INVITE_CODE_PKEY").unwrap_or(
+            "0000000000000001000000000000000100000000000000010000000000000001".to_string(),
+        ))
+        .expect("INVITE_CODE_PKEY must be a hex string");
+    let secret_key = SecretKey::from_slice(&secret_key).expect("32 bytes, within curve order");
+    let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+
+    // Deposits are gated behind invite codes signed by the same key as wallet registrations
+    let default_state = Orderbook::init(validator_lane_id.clone(), config.orderbook_admin.clone())
+        .with_invite_key(public_key.serialize().to_vec())
+        .with_identity_verification(config.identity_verification)
+        .with_withdrawal_delay(config.withdrawal_delay_blocks);
 
-    let contracts = vec![init::ContractInit {
-        name: args.orderbook_cn.clone().into(),
-        program_id: prover.program_id().expect("getting program id").0,
-        initial_state: default_state.commit(),
-    }];
+    let shards = config.orderbook_shards(&args.orderbook_cn)?;
+    let default_states = shards.initial_states(&default_state);
+
+    let mut contracts = vec![];
+    for (contract_name, state) in &default_states {
+        contracts.push(init::ContractInit {
+            name: contract_name.clone(),
+            program_id: program_id.0.clone(),
+            initial_state: state.commit(),
+            constructor_metadata: Some(
+                borsh::to_vec(state).context("encoding orderbook initial state")?,
+            ),
+        });
+    }
 
     match init::init_node(node_client.clone(), indexer_client.clone(), contracts).await {
         Ok(_) => {}
@@ -117,21 +151,21 @@ async fn main() -> Result<()> {
         openapi: Default::default(),
     });
 
+    let tx_tracker = Arc::new(TxTracker::default());
+    let session_keys = Arc::new(SessionKeys::default());
+    let unsettled_states = Arc::new(UnsettledStates::default());
+
     let orderbook_ctx = Arc::new(OrderbookModuleCtx {
         api: api_ctx.clone(),
-        orderbook_cn: args.orderbook_cn.clone().into(),
-        default_state: default_state.clone(),
+        shards: shards.clone(),
+        default_states: default_states.clone(),
+        data_directory: config.data_directory.clone(),
+        tx_tracker: tx_tracker.clone(),
+        session_keys: session_keys.clone(),
+        unsettled_states: unsettled_states.clone(),
+        node: node_client.clone(),
     });
 
-    let secp = Secp256k1::new();
-    let secret_key =
-        hex::decode(env::var("INVITE_CODE_PKEY").unwrap_or(
-            "0000000000000001000000000000000100000000000000010000000000000001".to_string(),
-        ))
-        .expect("INVITE_CODE_PKEY must be a hex string");
-    let secret_key = SecretKey::from_slice(&secret_key).expect("32 bytes, within curve order");
-    let public_key = PublicKey::from_secret_key(&secp, &secret_key);
-
     let hyli_password = env::var("HYLI_PASSWORD").unwrap_or("hylisecure".to_string());
     let wallet_constructor = WalletConstructor::new(hyli_password, public_key.serialize());
     let wallet = Wallet::new(&Some(wallet_constructor.clone())).expect("must succeed");
@@ -140,62 +174,164 @@ async fn main() -> Result<()> {
         .build_module::<OrderbookModule>(orderbook_ctx.clone())
         .await?;
 
-    let initial_contracts = BTreeMap::from([
-        (
-            args.orderbook_cn.clone().into(),
-            ContractBox::new(default_state.clone()),
-        ),
-        (args.wallet_cn.clone().into(), ContractBox::new(wallet)),
-    ]);
+    if let Some(settlement_report) = config.settlement_report.clone() {
+        handler
+            .build_module::<SettlementReporter>(SettlementReporterCtx {
+                api: api_ctx.clone(),
+                conf: settlement_report,
+                shards: shards.clone(),
+                default_states: default_states.clone(),
+                data_directory: config.data_directory.clone(),
+                signing_key: secret_key,
+            })
+            .await?;
+    }
+
+    let prover_tuning_path = config.data_directory.join("prover_tuning.json");
+    let prover_tuning = ProverTuning::load_or(
+        &prover_tuning_path,
+        ProverTuning {
+            buffer_blocks: config.buffer_blocks,
+            max_txs_per_proof: config.max_txs_per_proof,
+            tx_working_window_size: config.tx_working_window_size,
+        },
+    );
+    let shared_prover_tuning = Arc::new(tokio::sync::RwLock::new(prover_tuning));
+    let proof_latencies = Arc::new(ProofLatencies::default());
+
+    if let Some(adaptive_batching) = config.adaptive_batching.clone() {
+        tokio::spawn(adapt_prover_batching(
+            adaptive_batching,
+            shared_prover_tuning.clone(),
+            prover_tuning_path.clone(),
+            tx_tracker.clone(),
+            proof_latencies.clone(),
+        ));
+    }
+
+    if let Some(api_key) = config.admin_api_key.clone() {
+        let admin_router = admin::router(AdminCtx {
+            api_key,
+            prover_tuning: shared_prover_tuning.clone(),
+            prover_tuning_path: prover_tuning_path.clone(),
+            invite_key: secret_key,
+            bus: Arc::new(tokio::sync::Mutex::new(
+                AdminBusClient::new_from_bus(bus.new_handle()).await,
+            )),
+            wallet_auth: WalletAuth {
+                session_keys: session_keys.clone(),
+            },
+            admin_identity: config.orderbook_admin.clone(),
+        });
+        if let Ok(mut guard) = api_ctx.router.lock() {
+            if let Some(router) = guard.take() {
+                guard.replace(router.merge(admin_router));
+            }
+        }
+    }
+
+    let mut initial_contracts: BTreeMap<ContractName, ContractBox> = default_states
+        .iter()
+        .map(|(contract_name, state)| (contract_name.clone(), ContractBox::new(state.clone())))
+        .collect();
+    initial_contracts.insert(args.wallet_cn.clone().into(), ContractBox::new(wallet));
 
     handler
         .build_module::<RollupExecutor>(RollupExecutorCtx {
             data_directory: config.data_directory.clone(),
             initial_contracts,
             validator_lane_id,
-            watched_contracts: BTreeSet::from([args.orderbook_cn.clone().into()]),
+            unsequenced_tx_timeout_blocks: config.unsequenced_tx_timeout_blocks,
+            tx_tracker: tx_tracker.clone(),
+            unsettled_states: unsettled_states.clone(),
+            watched_contracts: shards.contract_names().cloned().collect(),
+            // Every contract but the wallet is an orderbook instance
             contract_deserializer: |state: Vec<u8>, contract_name: &ContractName| {
                 match contract_name.0.as_str() {
-                    "orderbook" => ContractBox::new(
-                        borsh::from_slice::<Orderbook>(&state)
-                            .expect("Deserializing orderbook state"),
-                    ),
                     "wallet" => ContractBox::new(
                         borsh::from_slice::<Wallet>(&state).expect("Deserializing orderbook state"),
                     ),
-                    _ => panic!("Unknown contract name: {}", contract_name.0),
+                    _ => ContractBox::new(
+                        borsh::from_slice::<Orderbook>(&state)
+                            .expect("Deserializing orderbook state"),
+                    ),
                 }
             },
         })
         .await?;
 
     handler
-        .build_module::<WebSocketModule<OrderbookWsInMessage, OrderbookEvent>>(
+        .build_module::<WebSocketModule<OrderbookWsInMessage, SequencedEvent>>(
             config.websocket.clone(),
         )
         .await?;
 
-    handler
-        .build_module::<ContractStateIndexer<Orderbook>>(ContractStateIndexerCtx {
-            contract_name: args.orderbook_cn.clone().into(),
-            data_directory: config.data_directory.clone(),
-            api: api_ctx.clone(),
-        })
-        .await?;
+    for contract_name in shards.contract_names() {
+        handler
+            .build_module::<ContractStateIndexer<Orderbook>>(ContractStateIndexerCtx {
+                contract_name: contract_name.clone(),
+                data_directory: config.data_directory.clone(),
+                api: api_ctx.clone(),
+            })
+            .await?;
+    }
 
-    handler
-        .build_module::<AutoProver<Orderbook>>(Arc::new(AutoProverCtx {
-            data_directory: config.data_directory.clone(),
-            prover: Arc::new(prover),
-            contract_name: args.orderbook_cn.clone().into(),
-            node: node_client.clone(),
-            default_state,
-            buffer_blocks: config.buffer_blocks,
-            max_txs_per_proof: config.max_txs_per_proof,
-            tx_working_window_size: config.tx_working_window_size,
-            api: Some(api_ctx.clone()),
-        }))
-        .await?;
+    let prover = Arc::new(
+        CachingProver::new(
+            prover,
+            program_id,
+            sdk::verifiers::SP1_4.into(),
+            config.data_directory.join("proof_cache"),
+        )?
+        .with_tx_tracker(tx_tracker.clone())
+        .with_latencies(proof_latencies.clone()),
+    );
+
+    // Proofs that were being generated when the server stopped are resumed right away
+    tokio::spawn({
+        let prover = prover.clone();
+        let node = RetryingNodeClient::new(
+            node_client.clone(),
+            config.proof_submission_max_retries,
+            config.proof_submission_alert_threshold,
+        );
+        async move {
+            if let Err(e) = prover.resume_pending(&node).await {
+                error!("Error resuming pending proofs: {:?}", e);
+            }
+        }
+    });
+
+    // Each orderbook instance is proven separately, sharing the proof cache
+    for (contract_name, default_state) in default_states {
+        let node = Arc::new(RetryingNodeClient::new(
+            node_client.clone(),
+            config.proof_submission_max_retries,
+            config.proof_submission_alert_threshold,
+        ));
+        let data_directory = config.data_directory.clone();
+        let prover = prover.clone();
+        let api = api_ctx.clone();
+        handler
+            .build_module::<TunedAutoProver>(TunedAutoProverCtx {
+                auto_prover: Box::new(move |tuning: &ProverTuning| AutoProverCtx {
+                    data_directory: data_directory.clone(),
+                    prover: prover.clone(),
+                    contract_name: contract_name.clone(),
+                    node: node.clone(),
+                    default_state: default_state.clone(),
+                    buffer_blocks: tuning.buffer_blocks,
+                    max_txs_per_proof: tuning.max_txs_per_proof,
+                    tx_working_window_size: tuning.tx_working_window_size,
+                    api: Some(api.clone()),
+                }),
+                tuning: shared_prover_tuning.clone(),
+            })
+            .await?;
+    }
+
+    // Sends blocks skipped by the executor again
+    handler.build_module::<BlockBackfill>(()).await?;
 
     // This module connects to the da_address and receives all the blocks²
     handler
@@ -0,0 +1,145 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use client_sdk::rest_client::NodeApiClient;
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use sdk::{
+    api::{APIRegisterContract, APIStaking, NodeInfo},
+    BlobTransaction, BlockHeight, ConsensusInfo, Contract, ContractName, ProofTransaction, TxHash,
+    UnsettledBlobTransaction,
+};
+use tracing::{error, warn};
+
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Node client retrying proof submissions with exponential backoff and jitter.
+///
+/// All other calls are forwarded as is to the inner client.
+pub struct RetryingNodeClient<C> {
+    inner: Arc<C>,
+    max_retries: u32,
+    alert_threshold: u32,
+    retries_counter: Counter<u64>,
+    alerts_counter: Counter<u64>,
+}
+
+impl<C> RetryingNodeClient<C> {
+    pub fn new(inner: Arc<C>, max_retries: u32, alert_threshold: u32) -> Self {
+        let meter = global::meter("orderbook");
+        Self {
+            inner,
+            max_retries,
+            alert_threshold,
+            retries_counter: meter.u64_counter("proof_submission_retries").build(),
+            alerts_counter: meter.u64_counter("proof_submission_alerts").build(),
+        }
+    }
+}
+
+/// Exponential backoff capped at `MAX_BACKOFF`, with up to 50% of random jitter
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF);
+    let jitter_ms = rand::random_range(0..=delay.as_millis() as u64 / 2);
+    delay + Duration::from_millis(jitter_ms)
+}
+
+impl<C> NodeApiClient for RetryingNodeClient<C>
+where
+    C: NodeApiClient + Send + Sync,
+{
+    fn register_contract(
+        &self,
+        tx: APIRegisterContract,
+    ) -> Pin<Box<dyn Future<Output = Result<TxHash>> + Send + '_>> {
+        self.inner.register_contract(tx)
+    }
+
+    fn send_tx_blob(
+        &self,
+        tx: BlobTransaction,
+    ) -> Pin<Box<dyn Future<Output = Result<TxHash>> + Send + '_>> {
+        self.inner.send_tx_blob(tx)
+    }
+
+    fn send_tx_proof(
+        &self,
+        tx: ProofTransaction,
+    ) -> Pin<Box<dyn Future<Output = Result<TxHash>> + Send + '_>> {
+        Box::pin(async move {
+            let contract_name = tx.contract_name.0.clone();
+            let mut attempt = 0;
+            loop {
+                match self.inner.send_tx_proof(tx.clone()).await {
+                    Ok(tx_hash) => return Ok(tx_hash),
+                    Err(e) if attempt >= self.max_retries => {
+                        error!(
+                            "🚨 Giving up proof submission for {} after {} retries: {:?}",
+                            contract_name, attempt, e
+                        );
+                        return Err(e);
+                    }
+                    Err(e) => {
+                        attempt += 1;
+                        self.retries_counter
+                            .add(1, &[KeyValue::new("contract", contract_name.clone())]);
+                        if attempt == self.alert_threshold {
+                            self.alerts_counter
+                                .add(1, &[KeyValue::new("contract", contract_name.clone())]);
+                            error!(
+                                "🚨 Proof submission for {} has been retried {} times: {:?}",
+                                contract_name, attempt, e
+                            );
+                        }
+                        let delay = backoff(attempt - 1);
+                        warn!(
+                            "Proof submission for {} failed, retrying in {:?}: {:?}",
+                            contract_name, delay, e
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+            }
+        })
+    }
+
+    fn get_consensus_info(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<ConsensusInfo>> + Send + '_>> {
+        self.inner.get_consensus_info()
+    }
+
+    fn get_consensus_staking_state(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<APIStaking>> + Send + '_>> {
+        self.inner.get_consensus_staking_state()
+    }
+
+    fn get_node_info(&self) -> Pin<Box<dyn Future<Output = Result<NodeInfo>> + Send + '_>> {
+        self.inner.get_node_info()
+    }
+
+    fn metrics(&self) -> Pin<Box<dyn Future<Output = Result<String>> + Send + '_>> {
+        self.inner.metrics()
+    }
+
+    fn get_block_height(&self) -> Pin<Box<dyn Future<Output = Result<BlockHeight>> + Send + '_>> {
+        self.inner.get_block_height()
+    }
+
+    fn get_contract(
+        &self,
+        contract_name: ContractName,
+    ) -> Pin<Box<dyn Future<Output = Result<Contract>> + Send + '_>> {
+        self.inner.get_contract(contract_name)
+    }
+
+    fn get_unsettled_tx(
+        &self,
+        blob_tx_hash: TxHash,
+    ) -> Pin<Box<dyn Future<Output = Result<UnsettledBlobTransaction>> + Send + '_>> {
+        self.inner.get_unsettled_tx(blob_tx_hash)
+    }
+}
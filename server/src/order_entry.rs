@@ -0,0 +1,332 @@
+//! Order entry over the WebSocket, so that traders don't pay an HTTP round-trip per order.
+//!
+//! Clients send their orders and cancellations signed with a session key, like the requests
+//! to the private endpoints, along with the blobs proving their identity to the wallet. The
+//! server builds the transaction around them, submits it to the node, and replies on the
+//! user's private channels as the transaction goes, see [`crate::private_channel`].
+//!
+//! Clients that don't keep a WebSocket open can send their orders and cancellations to the
+//! REST endpoints instead, signed like the private ones. Both become a [`Submission`], and
+//! await its optimistic execution in the same [`PendingSubmissions`].
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use orderbook::{
+    client::tx_builder::{OrderbookTx, OrderbookTxBuilder, WalletSession},
+    OrderType, OrderbookAction, OrderbookBlob, OrderbookEvent, SelfTradePrevention, TimeInForce,
+    TokenPair,
+};
+use sdk::{hyle_model_utils::TimestampMs, Blob, ContractName, Identity, TxHash};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::wallet_auth::{request_digest, SessionKeys, SignedRequest};
+
+#[cfg(test)]
+mod entry_tests;
+
+/// Method and path the signature of an order entry is computed with, see [`request_digest`]
+pub const ORDER_ENTRY_METHOD: &str = "WS";
+pub const ORDER_ENTRY_PATH: &str = "/ws/order";
+
+/// Maximum number of submissions awaiting their optimistic execution
+pub const MAX_PENDING_SUBMISSIONS: usize = 10_000;
+
+/// Longest time a REST submission waits for the optimistic execution of its transaction
+pub const SUBMISSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Order or cancellation sent by a client over the WebSocket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderEntry {
+    /// Chosen by the client, echoed in the replies
+    pub request_id: String,
+    pub identity: String,
+    /// A `CreateOrder` or a `Cancel`
+    pub action: OrderbookAction,
+    /// See [`OrderbookBlob::nonce`]
+    pub nonce: u64,
+    /// Blobs proving the identity, see [`WalletSession::auth_blobs`]
+    pub auth_blobs: Vec<Blob>,
+    /// Time of the signature, in milliseconds since the epoch
+    pub timestamp_ms: u128,
+    /// Hex encoded compressed secp256k1 public key of the session
+    pub public_key: String,
+    /// Hex encoded compact signature of the [`request_digest`] of the orderbook blob data
+    pub signature: String,
+}
+
+/// Reply to an [`OrderEntry`], published on the private channels of its user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderEntryReply {
+    /// Nothing was submitted
+    Rejected { request_id: String, message: String },
+    /// The transaction was sent to the node
+    Submitted { request_id: String, tx_hash: TxHash },
+    /// The transaction was executed on the optimistic state
+    Executed {
+        request_id: String,
+        tx_hash: TxHash,
+        events: Vec<OrderbookEvent>,
+    },
+    /// The transaction failed or expired
+    Failed {
+        request_id: String,
+        tx_hash: TxHash,
+        message: String,
+    },
+}
+
+impl OrderEntryReply {
+    /// Reply to the entry `request_id` once its transaction `tx_hash` was executed
+    pub fn executed(request_id: String, tx_hash: TxHash, execution: OptimisticExecution) -> Self {
+        match execution {
+            OptimisticExecution::Executed { events } => OrderEntryReply::Executed {
+                request_id,
+                tx_hash,
+                events,
+            },
+            OptimisticExecution::Failed { message } => OrderEntryReply::Failed {
+                request_id,
+                tx_hash,
+                message,
+            },
+            OptimisticExecution::Pending => OrderEntryReply::Submitted {
+                request_id,
+                tx_hash,
+            },
+        }
+    }
+}
+
+impl OrderEntry {
+    /// Action the entry submits on behalf of its user
+    pub fn submission(&self) -> Submission {
+        Submission {
+            identity: self.identity.clone(),
+            action: self.action.clone(),
+            nonce: self.nonce,
+            auth_blobs: self.auth_blobs.clone(),
+        }
+    }
+
+    /// Checks that the entry is an order or a cancellation signed recently by a session key of
+    /// its user
+    pub fn authenticate(&self, session_keys: &SessionKeys, now_ms: u128) -> Result<(), String> {
+        if !matches!(
+            self.action,
+            OrderbookAction::CreateOrder { .. } | OrderbookAction::Cancel { .. }
+        ) {
+            return Err("Only orders and cancellations can be sent over the WebSocket".into());
+        }
+        let signed = SignedRequest::from_hex(
+            self.identity.clone(),
+            self.timestamp_ms,
+            &self.public_key,
+            &self.signature,
+        )?;
+        let digest = request_digest(
+            self.timestamp_ms,
+            ORDER_ENTRY_METHOD,
+            ORDER_ENTRY_PATH,
+            &self.submission().blob_data(),
+        );
+        session_keys.verify(&signed, digest, now_ms)
+    }
+
+    pub fn reject(&self, message: impl Into<String>) -> OrderEntryReply {
+        OrderEntryReply::Rejected {
+            request_id: self.request_id.clone(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Order sent to `POST /api/orders` by the user whose wallet signed the request, see
+/// [`OrderbookAction::CreateOrder`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderRequest {
+    pub order_type: OrderType,
+    pub price: Option<u128>,
+    pub pair: TokenPair,
+    pub quantity: u128,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    #[serde(default)]
+    pub trigger_price: Option<u128>,
+    #[serde(default)]
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    #[serde(default)]
+    pub expires_at: Option<TimestampMs>,
+    #[serde(default)]
+    pub display_quantity: Option<u128>,
+    /// See [`OrderbookBlob::nonce`]
+    pub nonce: u64,
+    /// Blobs proving the identity, see [`WalletSession::auth_blobs`]
+    pub auth_blobs: Vec<Blob>,
+}
+
+/// Cancellation sent to `POST /api/cancel` by the user whose wallet signed the request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelRequest {
+    pub order_id: String,
+    /// See [`OrderbookBlob::nonce`]
+    pub nonce: u64,
+    /// Blobs proving the identity, see [`WalletSession::auth_blobs`]
+    pub auth_blobs: Vec<Blob>,
+}
+
+impl OrderRequest {
+    pub fn submission(self, identity: String) -> Submission {
+        Submission {
+            identity,
+            action: OrderbookAction::CreateOrder {
+                order_type: self.order_type,
+                price: self.price,
+                pair: self.pair,
+                quantity: self.quantity,
+                time_in_force: self.time_in_force,
+                trigger_price: self.trigger_price,
+                self_trade_prevention: self.self_trade_prevention,
+                expires_at: self.expires_at,
+                display_quantity: self.display_quantity,
+            },
+            nonce: self.nonce,
+            auth_blobs: self.auth_blobs,
+        }
+    }
+}
+
+impl CancelRequest {
+    pub fn submission(self, identity: String) -> Submission {
+        Submission {
+            identity,
+            action: OrderbookAction::Cancel {
+                order_id: self.order_id,
+            },
+            nonce: self.nonce,
+            auth_blobs: self.auth_blobs,
+        }
+    }
+}
+
+/// Action the server submits on behalf of the user who signed an order entry or a REST request
+#[derive(Debug, Clone)]
+pub struct Submission {
+    pub identity: String,
+    pub action: OrderbookAction,
+    pub nonce: u64,
+    pub auth_blobs: Vec<Blob>,
+}
+
+impl WalletSession for Submission {
+    fn identity(&self) -> Identity {
+        Identity(self.identity.clone())
+    }
+
+    fn auth_blobs(&self) -> Vec<Blob> {
+        self.auth_blobs.clone()
+    }
+}
+
+impl Submission {
+    /// Data of the orderbook blob of the submission, which the client of an order entry signs
+    pub fn blob_data(&self) -> Vec<u8> {
+        borsh::to_vec(&OrderbookBlob {
+            nonce: self.nonce,
+            action: self.action.clone(),
+        })
+        .expect("Failed to encode OrderbookBlob")
+    }
+
+    /// Transaction of the submission, sent to the orderbook instance `contract_name`
+    pub fn build(&self, contract_name: ContractName) -> OrderbookTx {
+        OrderbookTxBuilder::new(contract_name, self.action.clone(), self.nonce).build(self)
+    }
+}
+
+/// Reply to a REST submission
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubmissionReply {
+    pub tx_hash: TxHash,
+    pub execution: OptimisticExecution,
+}
+
+/// Outcome of the optimistic execution of a submitted transaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptimisticExecution {
+    Executed {
+        events: Vec<OrderbookEvent>,
+    },
+    /// The transaction failed or expired
+    Failed {
+        message: String,
+    },
+    /// Not executed within the [`SUBMISSION_TIMEOUT`], see `GET /api/txs/{hash}` for its progress
+    Pending,
+}
+
+/// Submitter awaiting the optimistic execution of a transaction
+#[derive(Debug)]
+pub enum Waiter {
+    /// An order entry, replied to on the private channels of its user
+    Entry { request_id: String },
+    /// A REST request, replied to once executed
+    Request(oneshot::Sender<OptimisticExecution>),
+}
+
+/// Submissions awaiting the optimistic execution of their transaction, by transaction hash
+#[derive(Debug, Default)]
+pub struct PendingSubmissions {
+    waiters: Mutex<HashMap<TxHash, Waiter>>,
+}
+
+impl PendingSubmissions {
+    /// Starts awaiting the execution of `tx_hash` for the order entry `request_id`, unless
+    /// [`MAX_PENDING_SUBMISSIONS`] already are
+    pub fn insert_entry(&self, tx_hash: &TxHash, request_id: String) -> bool {
+        self.insert(tx_hash, Waiter::Entry { request_id })
+    }
+
+    /// Starts awaiting the execution of `tx_hash` for a REST request, unless
+    /// [`MAX_PENDING_SUBMISSIONS`] already are
+    pub fn wait(&self, tx_hash: &TxHash) -> Option<oneshot::Receiver<OptimisticExecution>> {
+        let (sender, receiver) = oneshot::channel();
+        self.insert(tx_hash, Waiter::Request(sender))
+            .then_some(receiver)
+    }
+
+    fn insert(&self, tx_hash: &TxHash, waiter: Waiter) -> bool {
+        let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        if waiters.len() >= MAX_PENDING_SUBMISSIONS {
+            return false;
+        }
+        waiters.insert(tx_hash.clone(), waiter);
+        true
+    }
+
+    /// Hands the execution of `tx_hash` to the REST request awaiting it. The execution is
+    /// returned along with the request id of the order entry awaiting it, for the caller to reply.
+    pub fn resolve(
+        &self,
+        tx_hash: &TxHash,
+        execution: OptimisticExecution,
+    ) -> Option<(String, OptimisticExecution)> {
+        let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        match waiters.remove(tx_hash)? {
+            Waiter::Entry { request_id } => Some((request_id, execution)),
+            Waiter::Request(sender) => {
+                // The request may have timed out
+                let _ = sender.send(execution);
+                None
+            }
+        }
+    }
+
+    /// Stops awaiting the execution of `tx_hash`
+    pub fn forget(&self, tx_hash: &TxHash) {
+        let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        waiters.remove(tx_hash);
+    }
+}
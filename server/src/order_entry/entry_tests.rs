@@ -0,0 +1,149 @@
+use orderbook::{OrderType, OrderbookAction, TimeInForce};
+use sdk::{verifiers::Secp256k1Blob, Blob, BlobData, BlobIndex, BlobTransaction, Identity, TxHash};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+use super::{
+    CancelRequest, OptimisticExecution, OrderEntry, PendingSubmissions, ORDER_ENTRY_METHOD,
+    ORDER_ENTRY_PATH,
+};
+use crate::wallet_auth::{request_digest, SessionKeys};
+
+const USER: &str = "alice@wallet";
+const NOW: u128 = 1_700_000_000_000;
+
+fn create_order() -> OrderbookAction {
+    OrderbookAction::CreateOrder {
+        order_type: OrderType::Buy,
+        price: Some(2000),
+        pair: ("ETH".to_string(), "USD".to_string()),
+        quantity: 1,
+        time_in_force: TimeInForce::default(),
+        trigger_price: None,
+        self_trade_prevention: None,
+        expires_at: None,
+        display_quantity: None,
+    }
+}
+
+/// Entry of `action` signed with a session key of the user, which `session_keys` learnt
+fn signed_entry(action: OrderbookAction, session_keys: &SessionKeys) -> OrderEntry {
+    let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+    let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key).serialize();
+    let auth_blob = Blob {
+        contract_name: "secp256k1".into(),
+        data: BlobData(
+            borsh::to_vec(&Secp256k1Blob {
+                identity: Identity(USER.to_string()),
+                data: [0; 32],
+                public_key,
+                signature: [0; 64],
+            })
+            .unwrap(),
+        ),
+    };
+    session_keys.learn(&BlobTransaction::new(USER, vec![auth_blob.clone()]));
+
+    let mut entry = OrderEntry {
+        request_id: "1".to_string(),
+        identity: USER.to_string(),
+        action,
+        nonce: 7,
+        auth_blobs: vec![auth_blob],
+        timestamp_ms: NOW,
+        public_key: hex::encode(public_key),
+        signature: String::new(),
+    };
+    let digest = request_digest(
+        NOW,
+        ORDER_ENTRY_METHOD,
+        ORDER_ENTRY_PATH,
+        &entry.submission().blob_data(),
+    );
+    let signature = Secp256k1::new()
+        .sign_ecdsa(&Message::from_digest(digest), &secret_key)
+        .serialize_compact();
+    entry.signature = hex::encode(signature);
+    entry
+}
+
+#[test]
+fn signed_orders_are_accepted() {
+    let session_keys = SessionKeys::default();
+    let entry = signed_entry(create_order(), &session_keys);
+    assert_eq!(entry.authenticate(&session_keys, NOW), Ok(()));
+
+    let submission = entry.submission();
+    let built = submission.build("orderbook".into());
+    assert_eq!(built.tx.identity, Identity(USER.to_string()));
+    assert_eq!(built.tx.blobs[0], entry.auth_blobs[0]);
+    assert_eq!(built.orderbook_blob_index, BlobIndex(1));
+    assert_eq!(built.tx.blobs[1].data.0, submission.blob_data());
+
+    // The signature covers the nonce and the action
+    let mut replayed = entry.clone();
+    replayed.nonce += 1;
+    assert!(replayed.authenticate(&session_keys, NOW).is_err());
+}
+
+#[test]
+fn only_orders_and_cancellations_are_accepted() {
+    let session_keys = SessionKeys::default();
+    let withdraw = OrderbookAction::Withdraw {
+        token: "USD".to_string(),
+        amount: 10,
+        recipient: None,
+    };
+    let entry = signed_entry(withdraw, &session_keys);
+    let err = entry.authenticate(&session_keys, NOW).unwrap_err();
+    assert!(err.contains("Only orders and cancellations"), "{err}");
+}
+
+#[test]
+fn cancellations_are_built_for_the_signing_user() {
+    // The nonce is required, as the server cannot tell which ones the user has in flight
+    assert!(
+        serde_json::from_str::<CancelRequest>(r#"{"order_id": "order1", "auth_blobs": []}"#)
+            .is_err()
+    );
+    let request: CancelRequest =
+        serde_json::from_str(r#"{"order_id": "order1", "nonce": 3, "auth_blobs": []}"#).unwrap();
+    let submission = request.submission(USER.to_string());
+    assert_eq!(submission.nonce, 3);
+    assert!(
+        matches!(&submission.action, OrderbookAction::Cancel { order_id } if order_id == "order1")
+    );
+
+    let built = submission.build("orderbook".into());
+    assert_eq!(built.tx.identity, Identity(USER.to_string()));
+    assert_eq!(built.orderbook_blob_index, BlobIndex(0));
+}
+
+#[test]
+fn submissions_receive_their_execution_once() {
+    let submissions = PendingSubmissions::default();
+    let tx_hash = TxHash("tx1".to_string());
+    let mut execution = submissions.wait(&tx_hash).unwrap();
+    let failed = || OptimisticExecution::Failed {
+        message: "Insufficient balance".to_string(),
+    };
+    submissions.resolve(&tx_hash, failed());
+    submissions.resolve(&tx_hash, failed());
+    assert!(matches!(
+        execution.try_recv(),
+        Ok(OptimisticExecution::Failed { message }) if message == "Insufficient balance"
+    ));
+
+    // A submission that timed out is no longer awaited
+    let mut execution = submissions.wait(&tx_hash).unwrap();
+    submissions.forget(&tx_hash);
+    submissions.resolve(&tx_hash, failed());
+    assert!(execution.try_recv().is_err());
+
+    // Order entries get their execution back, to be replied to
+    assert!(submissions.insert_entry(&tx_hash, "1".to_string()));
+    assert!(matches!(
+        submissions.resolve(&tx_hash, failed()),
+        Some((request_id, OptimisticExecution::Failed { .. })) if request_id == "1"
+    ));
+    assert!(submissions.resolve(&tx_hash, failed()).is_none());
+}
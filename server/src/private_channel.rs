@@ -0,0 +1,108 @@
+//! Private WebSocket channels, streaming the events of a single user.
+//!
+//! Events of a user (its balances, the lifecycle of its orders, its failed transactions) used to
+//! be published on a topic named after its identity, which anyone could register. They are now
+//! only published on private channels: a client opens one by signing a challenge with a session
+//! key of the user, and receives the events on a topic derived from that signature, which no one
+//! else knows.
+
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::wallet_auth::{request_digest, SessionKeys, SignedRequest};
+
+#[cfg(test)]
+mod channel_tests;
+
+/// Method and path the signature opening a private channel is computed with, over an empty body,
+/// see [`request_digest`]
+pub const PRIVATE_CHANNEL_METHOD: &str = "WS";
+pub const PRIVATE_CHANNEL_PATH: &str = "/ws/private";
+
+/// Maximum number of private channels open for a single user, the oldest ones are closed first
+pub const MAX_CHANNELS_PER_USER: usize = 8;
+
+/// Request to open the private channel of a user, sent over the WebSocket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrivateChannelAuth {
+    pub identity: String,
+    /// Time of the signature, in milliseconds since the epoch
+    pub timestamp_ms: u128,
+    /// Hex encoded compressed secp256k1 public key of the session
+    pub public_key: String,
+    /// Hex encoded compact signature of the [`request_digest`] of an empty body
+    pub signature: String,
+}
+
+/// Reply to a [`PrivateChannelAuth`], published on [`PrivateChannelAuth::topic`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivateChannelReply {
+    /// The events of the user are now published on the topic
+    Opened {
+        identity: String,
+    },
+    Refused {
+        message: String,
+    },
+}
+
+impl PrivateChannelAuth {
+    /// Checks that the request was signed recently by a session key of its user
+    pub fn authenticate(&self, session_keys: &SessionKeys, now_ms: u128) -> Result<(), String> {
+        let signed = SignedRequest::from_hex(
+            self.identity.clone(),
+            self.timestamp_ms,
+            &self.public_key,
+            &self.signature,
+        )?;
+        let digest = request_digest(
+            self.timestamp_ms,
+            PRIVATE_CHANNEL_METHOD,
+            PRIVATE_CHANNEL_PATH,
+            &[],
+        );
+        session_keys.verify(&signed, digest, now_ms)
+    }
+
+    /// Topic of the channel, which the client registers before sending the request:
+    /// `"private:"` followed by the hex encoded SHA-256 of the hex encoded signature
+    pub fn topic(&self) -> String {
+        let hash = Sha256::digest(self.signature.to_lowercase().as_bytes());
+        format!("private:{}", hex::encode(hash))
+    }
+}
+
+/// Topics of the private channels open for each user
+#[derive(Debug, Default)]
+pub struct PrivateChannels {
+    topics: HashMap<String, VecDeque<String>>,
+}
+
+impl PrivateChannels {
+    pub fn is_empty(&self) -> bool {
+        self.topics.is_empty()
+    }
+
+    /// Opens a channel of `user` on `topic`, closing its oldest channel if it has too many
+    pub fn open(&mut self, user: &str, topic: String) {
+        let topics = self.topics.entry(user.to_string()).or_default();
+        if topics.contains(&topic) {
+            return;
+        }
+        if topics.len() >= MAX_CHANNELS_PER_USER {
+            topics.pop_front();
+        }
+        topics.push_back(topic);
+    }
+
+    /// Topics of the open channels of `user`
+    pub fn topics_of(&self, user: &str) -> Vec<String> {
+        self.topics
+            .get(user)
+            .map(|topics| topics.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
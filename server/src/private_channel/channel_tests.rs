@@ -0,0 +1,73 @@
+use sdk::{verifiers::Secp256k1Blob, Blob, BlobData, BlobTransaction, Identity};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+use super::{
+    PrivateChannelAuth, PrivateChannels, MAX_CHANNELS_PER_USER, PRIVATE_CHANNEL_METHOD,
+    PRIVATE_CHANNEL_PATH,
+};
+use crate::wallet_auth::{request_digest, SessionKeys};
+
+const USER: &str = "alice@wallet";
+const NOW: u128 = 1_700_000_000_000;
+
+/// Request of the user signed with a session key, which `session_keys` learnt
+fn signed_auth(session_keys: &SessionKeys) -> PrivateChannelAuth {
+    let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+    let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key).serialize();
+    let auth_blob = Blob {
+        contract_name: "secp256k1".into(),
+        data: BlobData(
+            borsh::to_vec(&Secp256k1Blob {
+                identity: Identity(USER.to_string()),
+                data: [0; 32],
+                public_key,
+                signature: [0; 64],
+            })
+            .unwrap(),
+        ),
+    };
+    session_keys.learn(&BlobTransaction::new(USER, vec![auth_blob]));
+
+    let digest = request_digest(NOW, PRIVATE_CHANNEL_METHOD, PRIVATE_CHANNEL_PATH, &[]);
+    let signature = Secp256k1::new()
+        .sign_ecdsa(&Message::from_digest(digest), &secret_key)
+        .serialize_compact();
+    PrivateChannelAuth {
+        identity: USER.to_string(),
+        timestamp_ms: NOW,
+        public_key: hex::encode(public_key),
+        signature: hex::encode(signature),
+    }
+}
+
+#[test]
+fn channels_are_opened_by_a_session_key_of_the_user() {
+    let session_keys = SessionKeys::default();
+    let auth = signed_auth(&session_keys);
+    assert_eq!(auth.authenticate(&session_keys, NOW), Ok(()));
+    assert!(auth.topic().starts_with("private:"));
+    assert_ne!(auth.topic(), format!("private:{USER}"));
+
+    // The signature is bound to its identity
+    let mut impersonated = auth.clone();
+    impersonated.identity = "bob@wallet".to_string();
+    assert!(impersonated.authenticate(&session_keys, NOW).is_err());
+
+    let err = auth.authenticate(&session_keys, NOW + 60_000).unwrap_err();
+    assert!(err.contains("expired"), "{err}");
+}
+
+#[test]
+fn oldest_channels_are_closed_first() {
+    let mut channels = PrivateChannels::default();
+    assert!(channels.topics_of(USER).is_empty());
+    for i in 0..=MAX_CHANNELS_PER_USER {
+        channels.open(USER, format!("private:{i}"));
+    }
+    channels.open(USER, format!("private:{MAX_CHANNELS_PER_USER}"));
+
+    let topics = channels.topics_of(USER);
+    assert_eq!(topics.len(), MAX_CHANNELS_PER_USER);
+    assert_eq!(topics[0], "private:1");
+    assert!(channels.topics_of("bob@wallet").is_empty());
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use borsh::{BorshDeserialize, BorshSerialize};
+use client_sdk::{helpers::ClientSdkProver, rest_client::NodeApiClient};
+use sdk::{Calldata, ContractName, ProgramId, ProofData, ProofTransaction, TxHash, Verifier};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::{
+    prover_batching::ProofLatencies,
+    tx_lifecycle::{TxStage, TxTracker},
+};
+
+#[cfg(test)]
+mod cache_tests;
+
+/// Prover wrapper that stores every generated proof on disk, keyed by
+/// (program id, commitment metadata hash, calldata hash).
+///
+/// Re-proving the exact same batch (after a restart or a reorg) returns the cached proof.
+/// Batches being proven are journaled on disk until their proof is generated, so that
+/// proving can be resumed right after a restart with [`CachingProver::resume_pending`].
+pub struct CachingProver<P> {
+    inner: P,
+    program_id: ProgramId,
+    verifier: Verifier,
+    cache_directory: PathBuf,
+    pending_directory: PathBuf,
+    in_flight: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    tx_tracker: Option<Arc<TxTracker>>,
+    latencies: Option<Arc<ProofLatencies>>,
+}
+
+/// A batch waiting for its proof
+#[derive(BorshSerialize, BorshDeserialize)]
+struct ProvingJob {
+    commitment_metadata: Vec<u8>,
+    calldata: Vec<Calldata>,
+}
+
+impl<P> CachingProver<P> {
+    pub fn new(
+        inner: P,
+        program_id: ProgramId,
+        verifier: Verifier,
+        cache_directory: PathBuf,
+    ) -> Result<Self> {
+        let pending_directory = cache_directory.join("pending");
+        std::fs::create_dir_all(&pending_directory).context("creating proof cache directory")?;
+        Ok(Self {
+            inner,
+            program_id,
+            verifier,
+            cache_directory,
+            pending_directory,
+            in_flight: Mutex::new(HashMap::new()),
+            tx_tracker: None,
+            latencies: None,
+        })
+    }
+
+    /// Records the transactions of every proven batch as proven
+    pub fn with_tx_tracker(mut self, tx_tracker: Arc<TxTracker>) -> Self {
+        self.tx_tracker = Some(tx_tracker);
+        self
+    }
+
+    /// Records the generation time of every proof that was not cached
+    pub fn with_latencies(mut self, latencies: Arc<ProofLatencies>) -> Self {
+        self.latencies = Some(latencies);
+        self
+    }
+
+    fn record_proven<'a>(&self, tx_hashes: impl IntoIterator<Item = &'a TxHash>) {
+        if let Some(tx_tracker) = &self.tx_tracker {
+            for tx_hash in tx_hashes {
+                tx_tracker.update(tx_hash, TxStage::Proven);
+            }
+        }
+    }
+
+    fn cache_key(&self, commitment_metadata: &[u8], calldata: &[Calldata]) -> Result<String> {
+        let calldata = borsh::to_vec(calldata).context("encoding calldata")?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(&self.program_id.0);
+        hasher.update(Sha256::digest(commitment_metadata));
+        hasher.update(Sha256::digest(&calldata));
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    fn cache_path(&self, key: &str) -> PathBuf {
+        self.cache_directory.join(format!("{key}.proof"))
+    }
+
+    fn pending_path(&self, key: &str) -> PathBuf {
+        self.pending_directory.join(format!("{key}.job"))
+    }
+
+    /// Lock held while a given batch is being proven, so concurrent requests for
+    /// the same batch wait for the first proof instead of proving it twice.
+    fn key_lock(&self, key: &str) -> Arc<tokio::sync::Mutex<()>> {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.entry(key.to_string()).or_default().clone()
+    }
+
+    fn release_key_lock(&self, key: &str) {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.remove(key);
+    }
+}
+
+impl<P> CachingProver<P>
+where
+    P: ClientSdkProver<Vec<Calldata>> + Send + Sync,
+{
+    /// Proves all the batches that were still pending when the server stopped, and submits
+    /// their proofs to `node`.
+    ///
+    /// Generated proofs also land in the cache, so the AutoProver gets them instantly
+    /// once it has caught up with the DA and requests the same batches again.
+    /// A job that cannot be read, proven or submitted is logged and does not stop the others.
+    pub async fn resume_pending(&self, node: &impl NodeApiClient) -> Result<()> {
+        let mut jobs = vec![];
+        for entry in std::fs::read_dir(&self.pending_directory)? {
+            let path = entry?.path();
+            // Leftovers of a write interrupted by the stop, the job itself was never journaled
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                _ = std::fs::remove_file(&path);
+                continue;
+            }
+            match read_job(&path) {
+                Ok(job) => jobs.push((path, job)),
+                Err(e) => {
+                    warn!("Dropping unreadable proving job {:?}: {:?}", path, e);
+                    _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+
+        if !jobs.is_empty() {
+            info!("🔁 Resuming {} pending proving jobs", jobs.len());
+        }
+        for (path, job) in jobs {
+            let Some(contract_name) = job.contract_name() else {
+                warn!("Dropping proving job {:?} without calldata", path);
+                _ = std::fs::remove_file(&path);
+                continue;
+            };
+            let proof = match self.prove(job.commitment_metadata, job.calldata).await {
+                Ok(proof) => proof,
+                Err(e) => {
+                    warn!("Failed to resume proving job {:?}: {:?}", path, e);
+                    continue;
+                }
+            };
+            let tx = ProofTransaction {
+                contract_name: contract_name.clone(),
+                program_id: self.program_id.clone(),
+                verifier: self.verifier.clone(),
+                proof,
+            };
+            match node.send_tx_proof(tx).await {
+                Ok(tx_hash) => info!("Submitted resumed proof for {}: {}", contract_name, tx_hash),
+                Err(e) => warn!(
+                    "Failed to submit resumed proof for {}: {:?}",
+                    contract_name, e
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ProvingJob {
+    /// Contract proven by the batch
+    fn contract_name(&self) -> Option<ContractName> {
+        let calldata = self.calldata.first()?;
+        let blob = calldata.blobs.get(&calldata.index)?;
+        Some(blob.contract_name.clone())
+    }
+}
+
+fn read_job(path: &Path) -> Result<ProvingJob> {
+    let bytes = std::fs::read(path)?;
+    borsh::from_slice(&bytes).context("decoding proving job")
+}
+
+fn write_atomically(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, bytes)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+impl<P> ClientSdkProver<Vec<Calldata>> for CachingProver<P>
+where
+    P: ClientSdkProver<Vec<Calldata>> + Send + Sync,
+{
+    fn prove(
+        &self,
+        commitment_metadata: Vec<u8>,
+        calldata: Vec<Calldata>,
+    ) -> Pin<Box<dyn Future<Output = Result<ProofData>> + Send + '_>> {
+        Box::pin(async move {
+            let key = self.cache_key(&commitment_metadata, &calldata)?;
+            let path = self.cache_path(&key);
+
+            let key_lock = self.key_lock(&key);
+            let _guard = key_lock.lock().await;
+
+            let pending_path = self.pending_path(&key);
+
+            if let Ok(proof) = std::fs::read(&path) {
+                info!("♻️ Reusing cached proof {}", key);
+                _ = std::fs::remove_file(&pending_path);
+                self.release_key_lock(&key);
+                self.record_proven(calldata.iter().map(|c| &c.tx_hash));
+                return Ok(ProofData(proof));
+            }
+
+            let job = ProvingJob {
+                commitment_metadata,
+                calldata,
+            };
+            if let Err(e) =
+                borsh::to_vec(&job).and_then(|bytes| write_atomically(&pending_path, &bytes))
+            {
+                warn!("Failed to journal proving job {}: {:?}", key, e);
+            }
+
+            let tx_hashes: Vec<TxHash> = job.calldata.iter().map(|c| c.tx_hash.clone()).collect();
+            let started = Instant::now();
+            let proof = match self
+                .inner
+                .prove(job.commitment_metadata, job.calldata)
+                .await
+            {
+                Ok(proof) => proof,
+                Err(e) => {
+                    self.release_key_lock(&key);
+                    return Err(e);
+                }
+            };
+            if let Some(latencies) = &self.latencies {
+                latencies.record(started.elapsed());
+            }
+
+            // Failing to cache must not fail the proof itself
+            match write_atomically(&path, &proof.0) {
+                Ok(()) => debug!("Cached proof {}", key),
+                Err(e) => warn!("Failed to cache proof {}: {:?}", key, e),
+            }
+            _ = std::fs::remove_file(&pending_path);
+            self.release_key_lock(&key);
+            self.record_proven(&tx_hashes);
+
+            Ok(proof)
+        })
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    future::Future,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use client_sdk::{helpers::ClientSdkProver, rest_client::NodeApiClient};
+use sdk::{
+    api::{APIRegisterContract, APIStaking, NodeInfo},
+    Blob, BlobData, BlobIndex, BlobTransaction, BlockHeight, Calldata, ConsensusInfo, Contract,
+    ContractName, ProgramId, ProofData, ProofTransaction, TxHash, UnsettledBlobTransaction,
+};
+
+use super::CachingProver;
+
+/// Prover answering with the hashes of the proven transactions, or never when stuck
+#[derive(Default)]
+struct FakeProver {
+    stuck: bool,
+    proofs: AtomicUsize,
+}
+
+impl ClientSdkProver<Vec<Calldata>> for FakeProver {
+    fn prove(
+        &self,
+        _commitment_metadata: Vec<u8>,
+        calldata: Vec<Calldata>,
+    ) -> Pin<Box<dyn Future<Output = Result<ProofData>> + Send + '_>> {
+        Box::pin(async move {
+            if self.stuck {
+                std::future::pending::<()>().await;
+            }
+            self.proofs.fetch_add(1, Ordering::SeqCst);
+            let hashes: Vec<String> = calldata.into_iter().map(|c| c.tx_hash.0).collect();
+            Ok(ProofData(hashes.join(",").into_bytes()))
+        })
+    }
+}
+
+/// Node recording the submitted proofs
+#[derive(Default)]
+struct FakeNode {
+    proofs: Mutex<Vec<ProofTransaction>>,
+}
+
+impl NodeApiClient for FakeNode {
+    fn register_contract(
+        &self,
+        _tx: APIRegisterContract,
+    ) -> Pin<Box<dyn Future<Output = Result<TxHash>> + Send + '_>> {
+        unimplemented!()
+    }
+
+    fn send_tx_blob(
+        &self,
+        _tx: BlobTransaction,
+    ) -> Pin<Box<dyn Future<Output = Result<TxHash>> + Send + '_>> {
+        unimplemented!()
+    }
+
+    fn send_tx_proof(
+        &self,
+        tx: ProofTransaction,
+    ) -> Pin<Box<dyn Future<Output = Result<TxHash>> + Send + '_>> {
+        self.proofs.lock().unwrap().push(tx);
+        Box::pin(async { Ok(TxHash("proof".to_string())) })
+    }
+
+    fn get_consensus_info(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<ConsensusInfo>> + Send + '_>> {
+        unimplemented!()
+    }
+
+    fn get_consensus_staking_state(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<APIStaking>> + Send + '_>> {
+        unimplemented!()
+    }
+
+    fn get_node_info(&self) -> Pin<Box<dyn Future<Output = Result<NodeInfo>> + Send + '_>> {
+        unimplemented!()
+    }
+
+    fn metrics(&self) -> Pin<Box<dyn Future<Output = Result<String>> + Send + '_>> {
+        unimplemented!()
+    }
+
+    fn get_block_height(&self) -> Pin<Box<dyn Future<Output = Result<BlockHeight>> + Send + '_>> {
+        unimplemented!()
+    }
+
+    fn get_contract(
+        &self,
+        _contract_name: ContractName,
+    ) -> Pin<Box<dyn Future<Output = Result<Contract>> + Send + '_>> {
+        unimplemented!()
+    }
+
+    fn get_unsettled_tx(
+        &self,
+        _blob_tx_hash: TxHash,
+    ) -> Pin<Box<dyn Future<Output = Result<UnsettledBlobTransaction>> + Send + '_>> {
+        unimplemented!()
+    }
+}
+
+fn caching_prover(directory: &Path, inner: FakeProver) -> CachingProver<FakeProver> {
+    CachingProver::new(
+        inner,
+        ProgramId(vec![1]),
+        "test".into(),
+        directory.to_path_buf(),
+    )
+    .unwrap()
+}
+
+fn calldata(tx_hash: &str) -> Calldata {
+    let blobs = vec![Blob {
+        contract_name: "orderbook".into(),
+        data: BlobData(vec![]),
+    }];
+    Calldata {
+        tx_hash: TxHash(tx_hash.to_string()),
+        identity: "alice@orderbook".into(),
+        tx_blob_count: blobs.len(),
+        blobs: blobs.into(),
+        index: BlobIndex(0),
+        tx_ctx: None,
+        private_input: vec![],
+    }
+}
+
+#[tokio::test]
+async fn proofs_interrupted_by_a_restart_are_resumed_and_submitted() {
+    let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+
+    // The server stops while the batch is being proven
+    let stopped = caching_prover(
+        &directory,
+        FakeProver {
+            stuck: true,
+            ..Default::default()
+        },
+    );
+    let proving = stopped.prove(vec![], vec![calldata("tx1"), calldata("tx2")]);
+    assert!(tokio::time::timeout(Duration::from_millis(50), proving)
+        .await
+        .is_err());
+    drop(stopped);
+
+    // Along with writes that did not complete
+    let pending = directory.join("pending");
+    std::fs::write(pending.join("interrupted.tmp"), b"partial").unwrap();
+    std::fs::write(pending.join("corrupted.job"), b"garbage").unwrap();
+
+    let prover = caching_prover(&directory, FakeProver::default());
+    let node = FakeNode::default();
+    prover.resume_pending(&node).await.unwrap();
+
+    let proofs = node.proofs.lock().unwrap().clone();
+    assert_eq!(proofs.len(), 1);
+    assert_eq!(
+        proofs[0].contract_name,
+        ContractName("orderbook".to_string())
+    );
+    assert_eq!(proofs[0].proof.0, b"tx1,tx2");
+    assert_eq!(std::fs::read_dir(&pending).unwrap().count(), 0);
+
+    // The AutoProver gets the resumed proof from the cache
+    let proof = prover
+        .prove(vec![], vec![calldata("tx1"), calldata("tx2")])
+        .await
+        .unwrap();
+    assert_eq!(proof.0, b"tx1,tx2");
+    assert_eq!(prover.inner.proofs.load(Ordering::SeqCst), 1);
+
+    _ = std::fs::remove_dir_all(&directory);
+}
@@ -0,0 +1,240 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use hyle_modules::{
+    bus::SharedMessageBus,
+    module_bus_client, module_handle_messages,
+    modules::{
+        prover::{AutoProver, AutoProverCtx},
+        Module,
+    },
+};
+use orderbook::Orderbook;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{oneshot, RwLock},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+use crate::{admin::ProverTuning, tx_lifecycle::TxTracker};
+
+#[cfg(test)]
+mod adapt_tests;
+
+/// Bounds within which the prover batching follows the load
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AdaptiveBatchingConf {
+    pub min_buffer_blocks: u32,
+    pub max_buffer_blocks: u32,
+    pub min_txs_per_proof: usize,
+    pub max_txs_per_proof: usize,
+    /// Proof generation time above which batches grow, in milliseconds
+    pub target_proof_latency_ms: u64,
+    /// Time between two adjustments, in seconds
+    pub interval_secs: u64,
+}
+
+/// Load observed since the last adjustment
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObservedLoad {
+    /// Transactions received per second
+    pub tx_rate: f64,
+    /// Mean time to generate a proof, None if nothing was proven
+    pub proof_latency: Option<Duration>,
+}
+
+/// Proof generation times, measured by the prover
+#[derive(Debug, Default)]
+pub struct ProofLatencies {
+    samples: Mutex<Vec<Duration>>,
+}
+
+impl ProofLatencies {
+    pub fn record(&self, latency: Duration) {
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        samples.push(latency);
+    }
+
+    /// Mean of the latencies recorded since the last call
+    pub fn take_mean(&self) -> Option<Duration> {
+        let samples = std::mem::take(&mut *self.samples.lock().unwrap_or_else(|e| e.into_inner()));
+        let count = u32::try_from(samples.len())
+            .ok()
+            .filter(|count| *count > 0)?;
+        Some(samples.iter().sum::<Duration>() / count)
+    }
+}
+
+impl AdaptiveBatchingConf {
+    /// Batching to use under `load`, starting from `current`.
+    ///
+    /// Proofs slower than the target mean the prover lags behind: batches grow to amortize the
+    /// proving cost. When the transactions received during the target latency would not even
+    /// fill half a batch, batches shrink so that transactions are proven sooner.
+    pub fn adapt(&self, current: &ProverTuning, load: &ObservedLoad) -> ProverTuning {
+        let target = Duration::from_millis(self.target_proof_latency_ms);
+        let expected_txs = load.tx_rate * target.as_secs_f64();
+
+        let mut next = current.clone();
+        match load.proof_latency {
+            Some(latency) if latency > target => {
+                next.max_txs_per_proof = current.max_txs_per_proof.saturating_mul(2);
+                next.buffer_blocks = current.buffer_blocks.saturating_add(1);
+            }
+            _ if expected_txs < (current.max_txs_per_proof / 2) as f64 => {
+                next.max_txs_per_proof = current.max_txs_per_proof / 2;
+                next.buffer_blocks = current.buffer_blocks.saturating_sub(1);
+            }
+            _ => {}
+        }
+        next.max_txs_per_proof = next
+            .max_txs_per_proof
+            .clamp(self.min_txs_per_proof, self.max_txs_per_proof);
+        next.buffer_blocks = next
+            .buffer_blocks
+            .clamp(self.min_buffer_blocks, self.max_buffer_blocks);
+        next
+    }
+}
+
+/// Adjusts the prover batching to the observed load every `interval_secs`, and persists it
+/// like the admin API does. The running [`TunedAutoProver`]s pick up each adjustment within
+/// [`TUNING_CHECK_INTERVAL`], so batches follow bursts as they happen.
+pub async fn adapt_prover_batching(
+    conf: AdaptiveBatchingConf,
+    tuning: Arc<RwLock<ProverTuning>>,
+    tuning_path: PathBuf,
+    tx_tracker: Arc<TxTracker>,
+    latencies: Arc<ProofLatencies>,
+) {
+    // Each adjustment is applied before the load it causes is measured
+    let interval = Duration::from_secs(conf.interval_secs).max(TUNING_CHECK_INTERVAL);
+    let mut received = tx_tracker.received_count();
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let now_received = tx_tracker.received_count();
+        let load = ObservedLoad {
+            tx_rate: now_received.saturating_sub(received) as f64 / interval.as_secs_f64(),
+            proof_latency: latencies.take_mean(),
+        };
+        received = now_received;
+
+        let mut tuning = tuning.write().await;
+        let next = conf.adapt(&tuning, &load);
+        if next == *tuning {
+            continue;
+        }
+        info!(
+            tx_rate = load.tx_rate,
+            proof_latency_ms = load.proof_latency.map(|latency| latency.as_millis() as u64),
+            "Adapting prover batching to {:?}",
+            next
+        );
+        if let Err(e) = next.save(&tuning_path) {
+            warn!("Failed to persist adapted prover batching: {:#}", e);
+        }
+        *tuning = next;
+    }
+}
+
+/// Time between two checks of the shared tuning by the [`TunedAutoProver`]
+const TUNING_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct TunedAutoProverCtx {
+    /// Builds the context of the AutoProver, batching with the given tuning
+    pub auto_prover: Box<dyn Fn(&ProverTuning) -> AutoProverCtx<Orderbook> + Send + Sync>,
+    /// Tuning updated by the admin API and the adaptive batching
+    pub tuning: Arc<RwLock<ProverTuning>>,
+}
+
+module_bus_client! {
+#[derive(Debug)]
+pub struct TunedAutoProverBusClient {
+}
+}
+
+/// AutoProver following the shared [`ProverTuning`].
+///
+/// The AutoProver reads its batching parameters when it is built, so it is rebuilt whenever
+/// the tuning changes. The running one is stopped and persisted first, and its replacement
+/// resumes from the persisted state, as it does after a restart.
+pub struct TunedAutoProver {
+    bus: TunedAutoProverBusClient,
+    shared_bus: SharedMessageBus,
+    ctx: TunedAutoProverCtx,
+}
+
+/// AutoProver running in its own task, until it is told to stop
+struct RunningProver {
+    tuning: ProverTuning,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl Module for TunedAutoProver {
+    type Context = TunedAutoProverCtx;
+
+    async fn build(bus: SharedMessageBus, ctx: Self::Context) -> Result<Self> {
+        Ok(TunedAutoProver {
+            bus: TunedAutoProverBusClient::new_from_bus(bus.new_handle()).await,
+            shared_bus: bus.new_handle(),
+            ctx,
+        })
+    }
+
+    async fn run(&mut self) -> Result<()> {
+        let mut running = self.start().await?;
+        let mut interval = tokio::time::interval(TUNING_CHECK_INTERVAL);
+
+        module_handle_messages! {
+            on_self self,
+
+            _ = interval.tick() => {
+                if running.task.is_finished() {
+                    break;
+                }
+                if *self.ctx.tuning.read().await != running.tuning {
+                    running.stop().await?;
+                    running = self.start().await?;
+                }
+            }
+        };
+
+        running.stop().await
+    }
+}
+
+impl TunedAutoProver {
+    async fn start(&self) -> Result<RunningProver> {
+        let tuning = self.ctx.tuning.read().await.clone();
+        info!("Starting the AutoProver with {:?}", tuning);
+        let mut prover = AutoProver::<Orderbook>::build(
+            self.shared_bus.new_handle(),
+            Arc::new((self.ctx.auto_prover)(&tuning)),
+        )
+        .await?;
+
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            tokio::select! {
+                result = prover.run() => result?,
+                _ = stopped => {}
+            }
+            prover.persist().await
+        });
+        Ok(RunningProver { tuning, stop, task })
+    }
+}
+
+impl RunningProver {
+    async fn stop(self) -> Result<()> {
+        _ = self.stop.send(());
+        self.task.await?
+    }
+}
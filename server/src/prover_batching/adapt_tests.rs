@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use super::{AdaptiveBatchingConf, ObservedLoad, ProofLatencies};
+use crate::admin::ProverTuning;
+
+fn conf() -> AdaptiveBatchingConf {
+    AdaptiveBatchingConf {
+        min_buffer_blocks: 0,
+        max_buffer_blocks: 3,
+        min_txs_per_proof: 10,
+        max_txs_per_proof: 400,
+        target_proof_latency_ms: 10_000,
+        interval_secs: 30,
+    }
+}
+
+fn tuning(buffer_blocks: u32, max_txs_per_proof: usize) -> ProverTuning {
+    ProverTuning {
+        buffer_blocks,
+        max_txs_per_proof,
+        tx_working_window_size: 500,
+    }
+}
+
+fn load(tx_rate: f64, proof_latency_secs: Option<u64>) -> ObservedLoad {
+    ObservedLoad {
+        tx_rate,
+        proof_latency: proof_latency_secs.map(Duration::from_secs),
+    }
+}
+
+#[test]
+fn slow_proofs_grow_batches_within_bounds() {
+    assert_eq!(
+        conf().adapt(&tuning(0, 100), &load(20.0, Some(15))),
+        tuning(1, 200)
+    );
+    assert_eq!(
+        conf().adapt(&tuning(3, 300), &load(20.0, Some(15))),
+        tuning(3, 400)
+    );
+}
+
+#[test]
+fn quiet_periods_shrink_batches_within_bounds() {
+    // 1 tx/s over the 10s target does not fill half of 100 txs
+    assert_eq!(
+        conf().adapt(&tuning(2, 100), &load(1.0, Some(2))),
+        tuning(1, 50)
+    );
+    assert_eq!(
+        conf().adapt(&tuning(0, 15), &load(0.0, None)),
+        tuning(0, 10)
+    );
+}
+
+#[test]
+fn steady_load_keeps_batching() {
+    assert_eq!(
+        conf().adapt(&tuning(1, 100), &load(8.0, Some(5))),
+        tuning(1, 100)
+    );
+}
+
+#[test]
+fn proof_latencies_are_averaged_once() {
+    let latencies = ProofLatencies::default();
+    assert_eq!(latencies.take_mean(), None);
+    latencies.record(Duration::from_secs(2));
+    latencies.record(Duration::from_secs(4));
+    assert_eq!(latencies.take_mean(), Some(Duration::from_secs(3)));
+    assert_eq!(latencies.take_mean(), None);
+}
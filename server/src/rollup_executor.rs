@@ -6,13 +6,15 @@ use hyle_modules::{
     log_error, module_bus_client, module_handle_messages,
     modules::Module,
 };
-use orderbook::Orderbook;
+use orderbook::{error::OrderbookError, Orderbook};
 use sdk::{
-    BlobTransaction, Block, BlockHeight, Calldata, ContractName, Hashed, HyleOutput, Identity,
-    LaneId, MempoolStatusEvent, NodeStateEvent, TransactionData, TxContext, TxHash,
+    BlobTransaction, Block, BlockHeight, Calldata, ConsensusProposalHash, ContractName, Hashed,
+    HyleOutput, Identity, LaneId, MempoolStatusEvent, NodeStateEvent, TransactionData, TxContext,
+    TxHash,
 };
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::fmt;
+use std::sync::Arc;
 use std::{
     any::{Any, TypeId},
     collections::BTreeSet,
@@ -20,15 +22,50 @@ use std::{
 use std::{
     fmt::Debug,
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    path::{Path, PathBuf},
     vec,
 };
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+use crate::{
+    block_backfill::{BackfilledBlocks, BlockBackfillRequest},
+    state_diff::UnsettledStates,
+    tx_lifecycle::{TxStage, TxTracker},
+};
+
+#[cfg(test)]
+mod chaos_tests;
+mod execution_cache;
+
+use execution_cache::ExecutionCache;
+
+/// Number of processed blocks the executor can roll back on a reorganization of the chain
+const MAX_REORG_DEPTH: usize = 32;
+
+/// What the executor knew once a block was processed, to roll back to on a reorganization
+struct BlockCheckpoint {
+    block_height: BlockHeight,
+    hash: ConsensusProposalHash,
+    settled_states: BTreeMap<ContractName, ContractBox>,
+    unsettled_sequenced_txs: Vec<(BlobTransaction, TxContext)>,
+    /// Transactions the block sequenced
+    sequenced_txs: Vec<(BlobTransaction, TxContext)>,
+}
 
 pub struct RollupExecutor {
     bus: RollupExecutorBusClient,
     data_directory: PathBuf,
     store: RollupExecutorStore,
+    unsequenced_tx_timeout_blocks: u64,
+    /// Blocks received after a gap in the stream, processed once the gap is backfilled
+    pending_blocks: BTreeMap<BlockHeight, Block>,
+    tx_tracker: Arc<TxTracker>,
+    unsettled_states: Arc<UnsettledStates>,
+    /// Executions of the unsettled transactions, reused by the reruns
+    execution_cache: ExecutionCache,
+    /// Last processed blocks, oldest first. Not persisted: a reorganization of blocks processed
+    /// before a restart can't be rolled back.
+    checkpoints: VecDeque<BlockCheckpoint>,
 }
 
 impl Deref for RollupExecutor {
@@ -200,6 +237,12 @@ pub struct RollupExecutorCtx {
     pub initial_contracts: BTreeMap<ContractName, ContractBox>,
     pub validator_lane_id: LaneId,
     pub contract_deserializer: fn(Vec<u8>, &ContractName) -> ContractBox,
+    /// Number of blocks after which an unsequenced transaction is dropped. 0 disables eviction.
+    pub unsequenced_tx_timeout_blocks: u64,
+    /// Where the lifecycle of the watched transactions is recorded
+    pub tx_tracker: Arc<TxTracker>,
+    /// Where the settled and optimistic states are recorded, to serve their difference
+    pub unsettled_states: Arc<UnsettledStates>,
 }
 
 #[derive(Debug, Clone)]
@@ -211,20 +254,41 @@ pub enum RollupExecutorEvent {
         // TODO: Remove this field, and make an nested api to get optimistic states
         BTreeMap<ContractName, ContractBox>,
     ),
-    /// Event sent when a BlobTransaction fails
-    FailedTx(Identity, TxHash, String),
+    /// Event sent when a BlobTransaction fails, with the error reported by the orderbook if any
+    FailedTx(Identity, TxHash, String, Option<OrderbookError>),
+    /// Event sent when an unsequenced BlobTransaction is dropped because it was not sequenced in time
+    TxExpired(Identity, TxHash),
     /// Event sent when a blob is reverted
     /// After a revert, the contract state is recalculated
     /// TODO: Remove the field, and make an nested api to get optimistic states
     Rollback(BTreeMap<ContractName, ContractBox>),
+    /// Event sent when the optimistic states were rebuilt, on an operator's request or after a
+    /// reorganization of the chain
+    Resync(BTreeMap<ContractName, ContractBox>),
+    /// Event sent when blocks from the first to the second height (included) were skipped.
+    /// No block is processed until they are backfilled.
+    CatchingUp(BlockHeight, BlockHeight),
+    /// Event sent once the skipped blocks were backfilled and processed, up to the given height
+    CaughtUp(BlockHeight),
+}
+
+/// Commands sent to the executor by operators
+#[derive(Debug, Clone)]
+pub enum RollupExecutorCommand {
+    /// Rebuilds the optimistic states from the settled states and the unsettled transactions,
+    /// as when the optimistic commitments diverge
+    Resync,
 }
 
 module_bus_client! {
 #[derive(Debug)]
 pub struct RollupExecutorBusClient {
     sender(RollupExecutorEvent),
+    sender(BlockBackfillRequest),
     receiver(NodeStateEvent),
+    receiver(BackfilledBlocks),
     receiver(MempoolStatusEvent),
+    receiver(RollupExecutorCommand),
 }
 }
 impl Module for RollupExecutor {
@@ -249,11 +313,19 @@ impl Module for RollupExecutor {
             },
         };
 
-        Ok(RollupExecutor {
+        let executor = RollupExecutor {
             bus,
             store,
             data_directory,
-        })
+            unsequenced_tx_timeout_blocks: ctx.unsequenced_tx_timeout_blocks,
+            pending_blocks: BTreeMap::new(),
+            tx_tracker: ctx.tx_tracker,
+            unsettled_states: ctx.unsettled_states,
+            execution_cache: ExecutionCache::default(),
+            checkpoints: VecDeque::new(),
+        };
+        executor.record_unsettled_states();
+        Ok(executor)
     }
 
     async fn run(&mut self) -> Result<()> {
@@ -261,10 +333,22 @@ impl Module for RollupExecutor {
             on_self self,
             listen<NodeStateEvent> event => {
                 _ = log_error!(self.handle_node_state_event(event).await, "handle note state event")
+                self.record_unsettled_states();
             }
 
             listen<MempoolStatusEvent> event => {
                 _ = log_error!(self.handle_mempool_status_event(event).await, "handle mempool status event");
+                self.record_unsettled_states();
+            }
+
+            listen<BackfilledBlocks> blocks => {
+                _ = log_error!(self.handle_backfilled_blocks(blocks).await, "handle backfilled blocks");
+                self.record_unsettled_states();
+            }
+
+            listen<RollupExecutorCommand> command => {
+                _ = log_error!(self.handle_command(command), "handle rollup executor command");
+                self.record_unsettled_states();
             }
         };
 
@@ -281,36 +365,251 @@ impl Module for RollupExecutor {
 }
 
 impl RollupExecutor {
+    /// Shares the current states, and the transactions between them
+    fn record_unsettled_states(&self) {
+        self.unsettled_states.record_settled(&self.settled_states);
+        self.unsettled_states.record_optimistic(
+            &self.optimistic_states,
+            &self.unsettled_sequenced_txs,
+            &self.unsettled_unsequenced_txs,
+        );
+    }
+
+    /// Optimistic state of `contract_name` saved on disk by the executor, if any.
+    ///
+    /// This is the state the executor resumes from when it is built on the same data directory.
+    pub fn saved_optimistic_state<T: BorshDeserialize>(
+        data_directory: &Path,
+        contract_name: &ContractName,
+    ) -> Option<T> {
+        let file = data_directory.join("rollup_executor.bin");
+        let store = Self::load_from_disk::<DeserRollupExecutorStore>(file.as_path())?;
+        let state = store.optimistic_states.get(contract_name)?;
+        borsh::from_slice(state).ok()
+    }
+
     async fn handle_node_state_event(&mut self, event: NodeStateEvent) -> Result<()> {
         match event {
-            NodeStateEvent::NewBlock(block) => {
-                self.block_height = block.block_height;
-                let mut should_rerun = false;
-
-                // Add all new sequenced transactions to unsettled_sequenced_txs
-                should_rerun = should_rerun || self.process_new_sequenced_transactions(&block)?;
-
-                // Handle successful transactions
-                // This means execute the transaction on top of settled_contracts and remove it from unsettled_sequenced_txs/unsettled_unsequenced_txs
-                should_rerun = should_rerun || self.process_successful_transactions(&block)?;
-
-                // Handle failed/timedout transactions
-                // This means remove the transaction from unsettled_sequenced_txs/unsettled_unsequenced_txs
-                should_rerun = should_rerun || self.process_failed_transactions(&block)?;
-                // and re-execute the transaction from from unsettled_sequenced_txs + unsettled_unsequenced_txs
-
-                // Rerun the transaction from unsettled_sequenced_txs + unsettled_unsequenced_txs
-                // starting from settled state; and compare the "optimistic state commitments" on watched contracts
-                // This means reexecution at every block. This is inefficient for now.
-                if should_rerun {
-                    if let Err(e) = self.rerun_from_settled() {
-                        self.bus.send(RollupExecutorEvent::Rollback(
-                            self.optimistic_states.clone(),
-                        ))?;
-                        tracing::warn!("{:?}", e);
-                    }
-                }
+            NodeStateEvent::NewBlock(block) => self.receive_block(*block),
+        }
+    }
+
+    async fn handle_backfilled_blocks(&mut self, blocks: BackfilledBlocks) -> Result<()> {
+        for block in blocks.0 {
+            self.receive_block(block)?;
+        }
+        Ok(())
+    }
+
+    /// Processes the blocks in height order. Skipped blocks would corrupt the settled states, so
+    /// blocks after a gap are held back, and the missing ones requested again.
+    fn receive_block(&mut self, block: Block) -> Result<()> {
+        if self.forks_from_processed_blocks(&block) {
+            self.roll_back_to_parent_of(&block)?;
+        }
+
+        // Blocks can be received twice, settling the same transactions twice would corrupt the settled states
+        if block.block_height.0 > 0 && block.block_height <= self.block_height {
+            debug!(
+                block_height = block.block_height.0,
+                "Ignoring already processed block"
+            );
+            return Ok(());
+        }
+
+        // The first block of a new executor sets the height the stream starts from
+        if self.block_height.0 > 0 && block.block_height.0 > self.block_height.0 + 1 {
+            let catching_up = self.pending_blocks.is_empty();
+            self.pending_blocks.insert(block.block_height, block);
+            if catching_up {
+                self.request_missing_blocks()?;
+            }
+            return Ok(());
+        }
+
+        let was_catching_up = !self.pending_blocks.is_empty();
+        self.process_block(block)?;
+        while let Some(entry) = self.pending_blocks.first_entry() {
+            if entry.key().0 > self.block_height.0 + 1 {
+                break;
+            }
+            let block = entry.remove();
+            if block.block_height > self.block_height {
+                self.process_block(block)?;
+            }
+        }
+
+        if !was_catching_up {
+            return Ok(());
+        }
+        if self.pending_blocks.is_empty() {
+            info!(
+                block_height = self.block_height.0,
+                "Caught up with the block stream"
+            );
+            self.bus
+                .send(RollupExecutorEvent::CaughtUp(self.block_height))?;
+        } else {
+            // There is another gap further
+            self.request_missing_blocks()?;
+        }
+        Ok(())
+    }
+
+    /// Whether `block` replaces a processed block, i.e. it has the height of a processed block but
+    /// another hash, or it follows the last processed height but not the last processed block
+    fn forks_from_processed_blocks(&self, block: &Block) -> bool {
+        let Some(last) = self.checkpoints.back() else {
+            return false;
+        };
+        if block.block_height <= last.block_height {
+            return self
+                .checkpoints
+                .iter()
+                .find(|checkpoint| checkpoint.block_height == block.block_height)
+                .is_some_and(|checkpoint| checkpoint.hash != block.hash);
+        }
+        block.block_height.0 == last.block_height.0 + 1 && block.parent_hash != last.hash
+    }
+
+    /// Rolls the settled states back to the parent of `block`, the last block the reorganized
+    /// chain has in common with the processed one, so that the new canonical blocks are applied
+    /// on top of it. Transactions sequenced by the abandoned blocks wait to be sequenced again.
+    fn roll_back_to_parent_of(&mut self, block: &Block) -> Result<()> {
+        let Some(ancestor) = self
+            .checkpoints
+            .iter()
+            .rposition(|checkpoint| checkpoint.hash == block.parent_hash)
+        else {
+            anyhow::bail!(
+                "Block {} at height {} forks from the processed blocks before the last {} ones, settled states can't be rolled back",
+                block.hash,
+                block.block_height,
+                self.checkpoints.len()
+            );
+        };
+        let abandoned = self.checkpoints.split_off(ancestor + 1);
+        let ancestor = &self.checkpoints[ancestor];
+        warn!(
+            from = ancestor.block_height.0 + 1,
+            to = self.block_height.0,
+            "Chain reorganization, rolling back the blocks after {}",
+            ancestor.hash
+        );
+
+        self.store.block_height = ancestor.block_height;
+        self.store.settled_states = ancestor.settled_states.clone();
+        self.store.unsettled_sequenced_txs = ancestor.unsettled_sequenced_txs.clone();
+        // Blocks held after a gap may come from the abandoned chain
+        self.pending_blocks.clear();
+
+        let mut unsequenced = vec![];
+        for (tx, tx_ctx) in abandoned.into_iter().flat_map(|c| c.sequenced_txs) {
+            let tx_hash = tx.hashed();
+            if !self.is_unsettled(&tx_hash)
+                && !unsequenced
+                    .iter()
+                    .any(|(known, _): &(BlobTransaction, TxContext)| known.hashed() == tx_hash)
+            {
+                unsequenced.push((tx, tx_ctx));
+            }
+        }
+        unsequenced.append(&mut self.store.unsettled_unsequenced_txs);
+        self.store.unsettled_unsequenced_txs = unsequenced;
+
+        if let Err(e) = self.rerun_from_settled() {
+            warn!("Reorganization changed the optimistic states: {:?}", e);
+        }
+        self.bus
+            .send(RollupExecutorEvent::Resync(self.optimistic_states.clone()))?;
+        Ok(())
+    }
+
+    fn request_missing_blocks(&mut self) -> Result<()> {
+        let Some(next) = self.pending_blocks.keys().next() else {
+            return Ok(());
+        };
+        let from = BlockHeight(self.block_height.0 + 1);
+        let to = BlockHeight(next.0 - 1);
+        warn!(
+            from = from.0,
+            to = to.0,
+            "Blocks were skipped, requesting them before processing newer ones"
+        );
+        self.bus.send(RollupExecutorEvent::CatchingUp(from, to))?;
+        _ = log_error!(
+            self.bus.send(BlockBackfillRequest { from, to }),
+            "request skipped blocks"
+        );
+        Ok(())
+    }
+
+    fn process_block(&mut self, block: Block) -> Result<()> {
+        self.block_height = block.block_height;
+        // Every step must run, even once a rerun is already required
+
+        // Add all new sequenced transactions to unsettled_sequenced_txs
+        let sequenced_txs = self.process_new_sequenced_transactions(&block)?;
+        let sequenced = !sequenced_txs.is_empty();
+
+        // Handle successful transactions
+        // This means execute the transaction on top of settled_contracts and remove it from unsettled_sequenced_txs/unsettled_unsequenced_txs
+        let settled = self.process_successful_transactions(&block)?;
+
+        // Handle failed/timedout transactions
+        // This means remove the transaction from unsettled_sequenced_txs/unsettled_unsequenced_txs
+        let failed = self.process_failed_transactions(&block)?;
+        // and re-execute the transaction from from unsettled_sequenced_txs + unsettled_unsequenced_txs
 
+        // Drop unsequenced transactions that waited too long, their optimistic effects are reverted by the rerun
+        let evicted = self.evict_stale_unsequenced_transactions()?;
+
+        let should_rerun = sequenced || settled || failed || evicted;
+
+        let unsettled: HashSet<TxHash> = self
+            .unsettled_sequenced_txs
+            .iter()
+            .chain(self.unsettled_unsequenced_txs.iter())
+            .map(|(tx, _)| tx.hashed())
+            .collect();
+        self.execution_cache
+            .retain(|tx_hash| unsettled.contains(tx_hash));
+
+        // Rerun the transaction from unsettled_sequenced_txs + unsettled_unsequenced_txs
+        // starting from settled state; and compare the "optimistic state commitments" on watched contracts
+        // This means reexecution at every block. This is inefficient for now.
+        if should_rerun {
+            if let Err(e) = self.rerun_from_settled() {
+                self.bus.send(RollupExecutorEvent::Rollback(
+                    self.optimistic_states.clone(),
+                ))?;
+                tracing::warn!("{:?}", e);
+            }
+        }
+
+        self.checkpoints.push_back(BlockCheckpoint {
+            block_height: block.block_height,
+            hash: block.hash,
+            settled_states: self.settled_states.clone(),
+            unsettled_sequenced_txs: self.unsettled_sequenced_txs.clone(),
+            sequenced_txs,
+        });
+        if self.checkpoints.len() > MAX_REORG_DEPTH {
+            self.checkpoints.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn handle_command(&mut self, command: RollupExecutorCommand) -> Result<()> {
+        match command {
+            RollupExecutorCommand::Resync => {
+                info!("Resyncing optimistic states from settled states");
+                if let Err(e) = self.rerun_from_settled() {
+                    tracing::warn!("Resync changed the optimistic states: {:?}", e);
+                }
+                self.bus
+                    .send(RollupExecutorEvent::Resync(self.optimistic_states.clone()))?;
                 Ok(())
             }
         }
@@ -328,20 +627,28 @@ impl RollupExecutor {
                     if !self.should_keep_transaction(&blob_tx) {
                         return Ok(());
                     }
+                    // The same transaction can be received several times
+                    if self.is_unsettled(&blob_tx.hashed()) {
+                        debug!(tx_hash =% blob_tx.hashed(), "Ignoring duplicated transaction");
+                        return Ok(());
+                    }
+                    self.tx_tracker.record(&blob_tx.hashed(), TxStage::Received);
                     self.unsettled_unsequenced_txs
                         .push((blob_tx.clone(), tx_ctx.clone()));
-                    let hyle_outputs = match Self::execute_blob_tx(
-                        &mut self.optimistic_states,
+                    let hyle_outputs = match self.execution_cache.execute(
+                        &mut self.store.optimistic_states,
                         &blob_tx,
                         Some(tx_ctx),
                     ) {
                         Ok(outputs) => outputs,
                         Err(e) => {
+                            self.tx_tracker.record(&blob_tx.hashed(), TxStage::Failed);
                             // If the execution fails, we send a failed tx event
                             self.bus.send(RollupExecutorEvent::FailedTx(
                                 blob_tx.identity.clone(),
                                 blob_tx.hashed(),
-                                e.to_string(),
+                                format!("{e:#}"),
+                                e.downcast_ref::<OrderbookError>().cloned(),
                             ))?;
                             debug!("Error while executing optimistic transaction: {:?}", e);
                             return Ok(());
@@ -351,6 +658,8 @@ impl RollupExecutor {
                         tx_hash =% blob_tx.hashed(),
                         "🧙 Executed optimistic transaction"
                     );
+                    self.tx_tracker
+                        .record(&blob_tx.hashed(), TxStage::OptimisticallyExecuted);
 
                     self.bus.send(RollupExecutorEvent::TxExecutionSuccess(
                         blob_tx,
@@ -364,24 +673,38 @@ impl RollupExecutor {
         }
     }
 
-    fn process_new_sequenced_transactions(&mut self, block: &Block) -> Result<bool> {
-        let mut should_rerun = false;
+    /// Returns the transactions the block sequenced
+    fn process_new_sequenced_transactions(
+        &mut self,
+        block: &Block,
+    ) -> Result<Vec<(BlobTransaction, TxContext)>> {
+        let mut sequenced_txs = vec![];
         for (tx_id, tx) in block.txs.iter() {
             if let TransactionData::Blob(blob_tx) = &tx.transaction_data {
                 if !self.should_keep_transaction(blob_tx) {
                     continue;
                 }
+                // Already sequenced (e.g. the same block was received twice)
+                if self
+                    .unsettled_sequenced_txs
+                    .iter()
+                    .any(|(tx, _)| tx.hashed() == tx_id.1)
+                {
+                    continue;
+                }
 
                 let tx_ctx = block.build_tx_ctx(&blob_tx.hashed())?;
-                self.unsettled_sequenced_txs.push((blob_tx.clone(), tx_ctx));
+                self.unsettled_sequenced_txs
+                    .push((blob_tx.clone(), tx_ctx.clone()));
+                sequenced_txs.push((blob_tx.clone(), tx_ctx));
+                self.tx_tracker.record(&tx_id.1, TxStage::Sequenced);
 
                 // Remove duplicates from unsequenced
                 self.unsettled_unsequenced_txs
                     .retain(|(tx, _)| tx.hashed() != tx_id.1);
-                should_rerun = true;
             }
         }
-        Ok(should_rerun)
+        Ok(sequenced_txs)
     }
 
     fn process_successful_transactions(&mut self, block: &Block) -> Result<bool> {
@@ -394,6 +717,7 @@ impl RollupExecutor {
                         continue;
                     }
                     self.remove_transaction_from_unsettled(tx_hash);
+                    self.tx_tracker.record(tx_hash, TxStage::Settled);
                     let tx_ctx = block.build_tx_ctx(&blob_tx.hashed())?;
                     should_rerun = true;
                     if let Err(e) =
@@ -425,9 +749,47 @@ impl RollupExecutor {
         for tx_hash in failed_txs {
             should_rerun = should_rerun || self.remove_transaction_from_unsettled(&tx_hash);
         }
+        for tx_hash in &block.timed_out_txs {
+            self.tx_tracker.update(tx_hash, TxStage::TimedOut);
+        }
+        for tx_hash in &block.failed_txs {
+            self.tx_tracker.update(tx_hash, TxStage::Failed);
+        }
         Ok(should_rerun)
     }
 
+    /// Removes unsequenced transactions received more than `unsequenced_tx_timeout_blocks` blocks ago
+    /// and notifies their submitters.
+    fn evict_stale_unsequenced_transactions(&mut self) -> Result<bool> {
+        if self.unsequenced_tx_timeout_blocks == 0 {
+            return Ok(false);
+        }
+        let current_height = self.block_height.0;
+        let timeout = self.unsequenced_tx_timeout_blocks;
+
+        let (stale_txs, fresh_txs): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.unsettled_unsequenced_txs)
+                .into_iter()
+                .partition(|(_, tx_ctx)| {
+                    current_height.saturating_sub(tx_ctx.block_height.0) > timeout
+                });
+        self.unsettled_unsequenced_txs = fresh_txs;
+
+        for (blob_tx, _) in &stale_txs {
+            info!(
+                tx_hash =% blob_tx.hashed(),
+                "⌛ Evicting unsequenced transaction"
+            );
+            self.tx_tracker.record(&blob_tx.hashed(), TxStage::TimedOut);
+            self.bus.send(RollupExecutorEvent::TxExpired(
+                blob_tx.identity.clone(),
+                blob_tx.hashed(),
+            ))?;
+        }
+
+        Ok(!stale_txs.is_empty())
+    }
+
     /// Checks if there is a blob related to a handled optimistic contract
     fn should_keep_transaction(&self, blob_tx: &BlobTransaction) -> bool {
         blob_tx
@@ -436,6 +798,13 @@ impl RollupExecutor {
             .any(|blob| self.optimistic_states.contains_key(&blob.contract_name))
     }
 
+    fn is_unsettled(&self, tx_hash: &TxHash) -> bool {
+        self.unsettled_sequenced_txs
+            .iter()
+            .chain(self.unsettled_unsequenced_txs.iter())
+            .any(|(tx, _)| &tx.hashed() == tx_hash)
+    }
+
     fn remove_transaction_from_unsettled(&mut self, tx_hash: &TxHash) -> bool {
         let initial_sequenced_len = self.unsettled_sequenced_txs.len();
         let initial_unsequenced_len = self.unsettled_unsequenced_txs.len();
@@ -496,15 +865,33 @@ impl RollupExecutor {
                 }
                 Ok(hyle_output) => {
                     if !hyle_output.success {
-                        anyhow::bail!(
-                            "Hyle output for tx {} on blob index {} for {} is not successful: {:?}",
+                        let message = format!(
+                            "Hyle output for tx {} on blob index {} for {} is not successful",
                             blob_tx.hashed(),
                             calldata.index,
                             blob.contract_name,
+                        );
+                        // The orderbook reports a typed error, kept for the frontends
+                        if let Some(error) = OrderbookError::decode(&hyle_output.program_outputs) {
+                            return Err(anyhow::Error::new(error).context(message));
+                        }
+                        anyhow::bail!(
+                            "{message}: {:?}",
                             String::from_utf8(hyle_output.program_outputs.clone())
                                 .unwrap_or(hex::encode(&hyle_output.program_outputs)),
                         );
                     }
+                    #[cfg(feature = "invariants")]
+                    if let Some(Err(e)) = contract
+                        .downcast::<Orderbook>()
+                        .map(Orderbook::check_invariants)
+                    {
+                        tracing::error!(
+                            "Tx {} broke the invariants of {}: {e}",
+                            blob_tx.hashed(),
+                            blob.contract_name
+                        );
+                    }
                     hyle_outputs.push((hyle_output, blob.contract_name.clone()));
                 }
             }
@@ -519,10 +906,12 @@ impl RollupExecutor {
     pub fn rerun_from_settled(&mut self) -> Result<()> {
         let mut optimistic_commits = BTreeMap::new();
         for contract_name in &self.watched_contracts {
-            // WARN: This part is specific to orderbook
-            if contract_name.0 == "orderbook" {
-                let orderbook_contract_box = self.optimistic_states.get(contract_name).unwrap();
-                let orderbook_contract = orderbook_contract_box.downcast::<Orderbook>().unwrap();
+            // WARN: This part is specific to orderbook, which can be sharded across instances
+            if let Some(orderbook_contract) = self
+                .optimistic_states
+                .get(contract_name)
+                .and_then(|contract| contract.downcast::<Orderbook>())
+            {
                 let commitment = orderbook_contract.partial_commit();
                 optimistic_commits.insert(contract_name.clone(), commitment);
             }
@@ -533,25 +922,30 @@ impl RollupExecutor {
                 .insert(contract_name.clone(), state.clone());
         }
 
-        // Re-execute all sequenced_unsettled transactions
-        for (blob_tx, tx_ctx) in self.unsettled_sequenced_txs.clone() {
+        // Re-execute all sequenced_unsettled transactions, then all unsequenced_unsettled ones.
+        // Replays on unchanged states reuse the cached executions.
+        for (blob_tx, tx_ctx) in self
+            .store
+            .unsettled_sequenced_txs
+            .iter()
+            .chain(self.store.unsettled_unsequenced_txs.iter())
+        {
             // A reexecution cannot actually fail. Only hyle_output.success can be false
             // What matters is the optimistic commitments comparaison
-            let _ =
-                Self::execute_blob_tx(&mut self.optimistic_states, &blob_tx, Some(tx_ctx.clone()));
-        }
-
-        // Re-execute all unsequenced_unsettled transactions
-        for (blob_tx, tx_ctx) in self.unsettled_unsequenced_txs.clone() {
-            let _ =
-                Self::execute_blob_tx(&mut self.optimistic_states, &blob_tx, Some(tx_ctx.clone()));
+            let _ = self.execution_cache.execute(
+                &mut self.store.optimistic_states,
+                blob_tx,
+                Some(tx_ctx.clone()),
+            );
         }
 
         for contract_name in &self.watched_contracts {
             // WARN: This part is specific to orderbook
-            if contract_name.0 == "orderbook" {
-                let orderbook_contract_box = self.optimistic_states.get(contract_name).unwrap();
-                let orderbook_contract = orderbook_contract_box.downcast::<Orderbook>().unwrap();
+            if let Some(orderbook_contract) = self
+                .optimistic_states
+                .get(contract_name)
+                .and_then(|contract| contract.downcast::<Orderbook>())
+            {
                 let new_commitment = orderbook_contract.partial_commit();
 
                 if new_commitment != optimistic_commits[contract_name] {
@@ -0,0 +1,935 @@
+//! Chaos tests: adverse block and mempool sequences fed to the rollup executor.
+//!
+//! Each scenario checks that the executor converges back to the settled state, and that the
+//! corrections sent to WS clients on rollbacks bring their view in line with the new state.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use hyle_modules::{
+    bus::{metrics::BusMetrics, BusClientReceiver, SharedMessageBus},
+    module_bus_client,
+    modules::Module,
+};
+use orderbook::{
+    blobs::deposit_blob,
+    client::{
+        events::{correction_events, decode_events},
+        tx_builder::{OrderbookTxBuilder, WalletSession},
+    },
+    indexer::OrdersQuery,
+    Orderbook, OrderbookAction, OrderbookEvent,
+};
+use sdk::{
+    hyle_model_utils::TimestampMs, Blob, BlobData, BlobTransaction, Block, BlockHeight,
+    ConsensusProposalHash, ContractName, DataProposalHash, Hashed, Identity, LaneId,
+    MempoolStatusEvent, NodeStateEvent, Transaction, TransactionData, TxHash, TxId,
+    ValidatorPublicKey,
+};
+
+use super::{ContractBox, RollupExecutor, RollupExecutorCtx, RollupExecutorEvent};
+use crate::{
+    block_backfill::{BackfilledBlocks, BlockBackfillRequest},
+    tx_lifecycle::TxStage,
+};
+
+const TIMEOUT_BLOCKS: u64 = 3;
+
+module_bus_client! {
+#[derive(Debug)]
+struct ChaosBusClient {
+    receiver(RollupExecutorEvent),
+}
+}
+
+module_bus_client! {
+#[derive(Debug)]
+struct BackfillBusClient {
+    receiver(BlockBackfillRequest),
+}
+}
+
+struct TestSession(&'static str);
+
+impl WalletSession for TestSession {
+    fn identity(&self) -> Identity {
+        Identity(self.0.to_string())
+    }
+
+    fn auth_blobs(&self) -> Vec<Blob> {
+        vec![Blob {
+            contract_name: "wallet".into(),
+            data: BlobData(vec![]),
+        }]
+    }
+}
+
+/// What a WS client following the events knows of the orderbook
+#[derive(Debug, Default, PartialEq)]
+struct ClientView {
+    balances: BTreeMap<(String, String), u128>,
+    orders: BTreeMap<String, u128>,
+}
+
+impl ClientView {
+    fn of(state: &Orderbook) -> Self {
+        let mut view = ClientView::default();
+        for (user, balances) in state.get_balances() {
+            for (token, amount) in balances {
+                view.balances.insert((user.clone(), token), amount);
+            }
+        }
+        let all = OrdersQuery {
+            limit: usize::MAX,
+            ..Default::default()
+        };
+        for (order_id, order) in state.get_orders(&all) {
+            view.orders.insert(order_id, order.quantity);
+        }
+        view.normalized()
+    }
+
+    fn apply(mut self, events: &[OrderbookEvent]) -> Self {
+        for event in events {
+            match event {
+                OrderbookEvent::OrderCreated { order } => {
+                    self.orders.insert(order.order_id.clone(), order.quantity);
+                }
+                OrderbookEvent::OrderCancelled { order_id, .. }
+                | OrderbookEvent::OrderExecuted { order_id, .. } => {
+                    self.orders.remove(order_id);
+                }
+                OrderbookEvent::OrderUpdate {
+                    order_id,
+                    remaining_quantity,
+                    ..
+                } => {
+                    self.orders.insert(order_id.clone(), *remaining_quantity);
+                }
+                OrderbookEvent::BalanceUpdated {
+                    user,
+                    token,
+                    amount,
+                } => {
+                    self.balances.insert((user.clone(), token.clone()), *amount);
+                }
+                OrderbookEvent::StopOrderPlaced { .. }
+                | OrderbookEvent::StopOrderTriggered { .. }
+                | OrderbookEvent::TradeExecuted { .. }
+                | OrderbookEvent::BookHash { .. }
+                | OrderbookEvent::MakerRewardsDistributed { .. }
+                | OrderbookEvent::LiquidityRewardsDistributed { .. }
+                | OrderbookEvent::MarketConfigured { .. }
+                | OrderbookEvent::TokenConfigured { .. }
+                | OrderbookEvent::FeeCharged { .. }
+                | OrderbookEvent::FeeScheduleUpdated { .. }
+                | OrderbookEvent::UserRegistered { .. }
+                | OrderbookEvent::ReferrerRegistered { .. }
+                | OrderbookEvent::ReferralRewarded { .. }
+                | OrderbookEvent::TokenAdded { .. }
+                | OrderbookEvent::TokenRemoved { .. }
+                | OrderbookEvent::LaneAdded { .. }
+                | OrderbookEvent::LaneRemoved { .. }
+                | OrderbookEvent::DustThresholdUpdated { .. }
+                | OrderbookEvent::DustSwept { .. }
+                | OrderbookEvent::PairCreated { .. }
+                | OrderbookEvent::PairDelisted { .. }
+                | OrderbookEvent::MarketHalted { .. }
+                | OrderbookEvent::MarketResumed { .. }
+                | OrderbookEvent::OrdersLinked { .. }
+                | OrderbookEvent::AuctionCleared { .. }
+                | OrderbookEvent::WithdrawalRequested { .. }
+                | OrderbookEvent::WithdrawalClaimed { .. }
+                | OrderbookEvent::WithdrawalCancelled { .. } => {}
+            }
+        }
+        self.normalized()
+    }
+
+    /// Empty balances are equivalent to missing ones
+    fn normalized(mut self) -> Self {
+        self.balances.retain(|_, amount| *amount > 0);
+        self
+    }
+}
+
+struct Chaos {
+    executor: RollupExecutor,
+    events: ChaosBusClient,
+    backfill_requests: BackfillBusClient,
+    lane_id: LaneId,
+    /// State held by the app module, updated from the executor events
+    app_state: Orderbook,
+    received: Vec<RollupExecutorEvent>,
+}
+
+fn orderbook_cn() -> ContractName {
+    "orderbook".into()
+}
+
+fn deposit(user: &'static str, amount: u128) -> BlobTransaction {
+    orderbook_tx(
+        user,
+        OrderbookAction::Deposit {
+            token: "hyllar".to_string(),
+            amount,
+        },
+    )
+}
+
+fn withdraw(user: &'static str, amount: u128) -> BlobTransaction {
+    orderbook_tx(
+        user,
+        OrderbookAction::Withdraw {
+            token: "hyllar".to_string(),
+            amount,
+            recipient: None,
+        },
+    )
+}
+
+fn orderbook_tx(user: &'static str, action: OrderbookAction) -> BlobTransaction {
+    // Shared by all users, which only need increasing nonces
+    static NONCE: AtomicU64 = AtomicU64::new(1);
+    let nonce = NONCE.fetch_add(1, Ordering::Relaxed);
+    let mut builder = OrderbookTxBuilder::new(orderbook_cn(), action.clone(), nonce);
+    // Deposits are backed by a transfer to the orderbook
+    if let OrderbookAction::Deposit { token, amount } = action {
+        let sender = TestSession(user).identity();
+        builder = builder.with_transfer(deposit_blob(
+            token.as_str().into(),
+            sender,
+            &orderbook_cn(),
+            amount,
+        ));
+    }
+    builder.build(&TestSession(user)).tx
+}
+
+/// Content of a block, as far as the executor is concerned
+#[derive(Default)]
+struct ChaosBlock<'a> {
+    sequenced: Vec<&'a BlobTransaction>,
+    successful: Vec<&'a BlobTransaction>,
+    failed: Vec<&'a BlobTransaction>,
+    timed_out: Vec<&'a BlobTransaction>,
+}
+
+impl Chaos {
+    async fn new() -> Self {
+        let lane_id = LaneId(ValidatorPublicKey(vec![1, 2, 3]));
+        let initial_state = Orderbook::init(lane_id.clone(), "admin@orderbook".to_string());
+
+        let bus = SharedMessageBus::new(BusMetrics::global("chaos".to_string()));
+        let events = ChaosBusClient::new_from_bus(bus.new_handle()).await;
+        let backfill_requests = BackfillBusClient::new_from_bus(bus.new_handle()).await;
+        let executor = RollupExecutor::build(
+            bus.new_handle(),
+            RollupExecutorCtx {
+                watched_contracts: BTreeSet::from([orderbook_cn()]),
+                // Never written to, the store is only saved when the module shuts down
+                data_directory: std::env::temp_dir().join(uuid::Uuid::new_v4().to_string()),
+                initial_contracts: BTreeMap::from([(
+                    orderbook_cn(),
+                    ContractBox::new(initial_state.clone()),
+                )]),
+                validator_lane_id: lane_id.clone(),
+                contract_deserializer: |state, _| {
+                    ContractBox::new(borsh::from_slice::<Orderbook>(&state).unwrap())
+                },
+                unsequenced_tx_timeout_blocks: TIMEOUT_BLOCKS,
+                tx_tracker: Default::default(),
+                unsettled_states: Default::default(),
+            },
+        )
+        .await
+        .unwrap();
+
+        Chaos {
+            executor,
+            events,
+            backfill_requests,
+            lane_id,
+            app_state: initial_state,
+            received: vec![],
+        }
+    }
+
+    async fn mempool(&mut self, tx: &BlobTransaction) {
+        self.executor
+            .handle_mempool_status_event(MempoolStatusEvent::WaitingDissemination {
+                parent_data_proposal_hash: DataProposalHash::default(),
+                tx: Transaction {
+                    version: 1,
+                    transaction_data: TransactionData::Blob(tx.clone()),
+                },
+            })
+            .await
+            .unwrap();
+        self.drain_events();
+    }
+
+    async fn block(&mut self, block_height: u64, content: ChaosBlock<'_>) {
+        let block = self.make_block(block_height, content);
+        self.receive(block).await;
+    }
+
+    async fn receive(&mut self, block: Block) {
+        self.executor
+            .handle_node_state_event(NodeStateEvent::NewBlock(Box::new(block)))
+            .await
+            .unwrap();
+        self.drain_events();
+    }
+
+    /// Block `hash` of a chain, following the block `parent_hash`
+    fn chained_block(
+        &self,
+        block_height: u64,
+        hash: &str,
+        parent_hash: &str,
+        content: ChaosBlock<'_>,
+    ) -> Block {
+        Block {
+            hash: ConsensusProposalHash(hash.to_string()),
+            parent_hash: ConsensusProposalHash(parent_hash.to_string()),
+            ..self.make_block(block_height, content)
+        }
+    }
+
+    fn make_block(&self, block_height: u64, content: ChaosBlock<'_>) -> Block {
+        let hashes = |txs: &[&BlobTransaction]| -> Vec<TxHash> {
+            txs.iter().map(|tx| tx.hashed()).collect()
+        };
+        // A transaction appears once in the block, whatever happens to it
+        let mut txs: Vec<&BlobTransaction> = vec![];
+        for tx in content
+            .sequenced
+            .iter()
+            .chain(content.successful.iter())
+            .chain(content.failed.iter())
+        {
+            if !txs.iter().any(|known| known.hashed() == tx.hashed()) {
+                txs.push(tx);
+            }
+        }
+        Block {
+            block_height: BlockHeight(block_height),
+            block_timestamp: TimestampMs(block_height as u128 * 1000),
+            txs: txs
+                .iter()
+                .map(|tx| {
+                    (
+                        TxId(DataProposalHash::default(), tx.hashed()),
+                        Transaction {
+                            version: 1,
+                            transaction_data: TransactionData::Blob((*tx).clone()),
+                        },
+                    )
+                })
+                .collect(),
+            lane_ids: txs
+                .iter()
+                .map(|tx| (tx.hashed(), self.lane_id.clone()))
+                .collect(),
+            successful_txs: hashes(&content.successful),
+            failed_txs: hashes(&content.failed),
+            timed_out_txs: hashes(&content.timed_out),
+            ..Default::default()
+        }
+    }
+
+    /// Mirrors what the app module does with the executor events, checking that rollback
+    /// corrections bring the clients' view in line with the rolled back state.
+    fn drain_events(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            match &event {
+                RollupExecutorEvent::TxExecutionSuccess(_, _, states) => {
+                    self.app_state = orderbook_state(states);
+                }
+                RollupExecutorEvent::Rollback(states) => {
+                    let new_state = orderbook_state(states);
+                    let corrections = correction_events(&self.app_state, &new_state);
+                    assert_eq!(
+                        ClientView::of(&self.app_state).apply(&corrections),
+                        ClientView::of(&new_state),
+                        "corrections do not bring clients back in sync"
+                    );
+                    self.app_state = new_state;
+                }
+                RollupExecutorEvent::Resync(states) => {
+                    self.app_state = orderbook_state(states);
+                }
+                RollupExecutorEvent::FailedTx(..)
+                | RollupExecutorEvent::TxExpired(..)
+                | RollupExecutorEvent::CatchingUp(..)
+                | RollupExecutorEvent::CaughtUp(..) => {}
+            }
+            self.received.push(event);
+        }
+    }
+
+    fn take_received(&mut self) -> Vec<RollupExecutorEvent> {
+        std::mem::take(&mut self.received)
+    }
+
+    fn optimistic(&self) -> &Orderbook {
+        self.executor.optimistic_states[&orderbook_cn()]
+            .downcast::<Orderbook>()
+            .unwrap()
+    }
+
+    fn settled(&self) -> &Orderbook {
+        self.executor.settled_states[&orderbook_cn()]
+            .downcast::<Orderbook>()
+            .unwrap()
+    }
+
+    fn optimistic_balance(&self, user: &str) -> u128 {
+        balance(self.optimistic(), user)
+    }
+
+    fn settled_balance(&self, user: &str) -> u128 {
+        balance(self.settled(), user)
+    }
+
+    fn stages(&self, tx: &BlobTransaction) -> Vec<TxStage> {
+        self.executor
+            .tx_tracker
+            .get(&tx.hashed())
+            .map(|lifecycle| lifecycle.transitions.iter().map(|t| t.stage).collect())
+            .unwrap_or_default()
+    }
+
+    /// Once every transaction is settled, the optimistic state, the settled state and the
+    /// state known by the app must all agree.
+    fn assert_converged(&self) {
+        assert!(self.executor.unsettled_sequenced_txs.is_empty());
+        assert!(self.executor.unsettled_unsequenced_txs.is_empty());
+        assert_eq!(
+            self.optimistic().partial_commit(),
+            self.settled().partial_commit()
+        );
+        assert_eq!(
+            ClientView::of(&self.app_state),
+            ClientView::of(self.optimistic())
+        );
+    }
+}
+
+fn orderbook_state(states: &BTreeMap<ContractName, ContractBox>) -> Orderbook {
+    states[&orderbook_cn()]
+        .downcast::<Orderbook>()
+        .unwrap()
+        .clone()
+}
+
+fn balance(state: &Orderbook, user: &str) -> u128 {
+    state
+        .get_balance_for_account(user)
+        .and_then(|balances| balances.get("hyllar").copied())
+        .unwrap_or_default()
+}
+
+fn success_events(events: &[RollupExecutorEvent]) -> Vec<OrderbookEvent> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            RollupExecutorEvent::TxExecutionSuccess(_, outputs, _) => Some(outputs),
+            _ => None,
+        })
+        .flatten()
+        .flat_map(|(output, _)| decode_events(&output.program_outputs).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn duplicated_mempool_events_are_executed_once() {
+    let mut chaos = Chaos::new().await;
+    let tx = deposit("alice@wallet", 100);
+
+    chaos.mempool(&tx).await;
+    chaos.mempool(&tx).await;
+    assert_eq!(success_events(&chaos.take_received()).len(), 1);
+    assert_eq!(chaos.optimistic_balance("alice@wallet"), 100);
+
+    chaos
+        .block(
+            1,
+            ChaosBlock {
+                sequenced: vec![&tx],
+                ..Default::default()
+            },
+        )
+        .await;
+    // The same tx gossiped again once sequenced
+    chaos.mempool(&tx).await;
+    assert_eq!(chaos.optimistic_balance("alice@wallet"), 100);
+
+    chaos
+        .block(
+            2,
+            ChaosBlock {
+                successful: vec![&tx],
+                ..Default::default()
+            },
+        )
+        .await;
+    assert_eq!(chaos.settled_balance("alice@wallet"), 100);
+    chaos.assert_converged();
+}
+
+#[tokio::test]
+async fn duplicated_blocks_are_processed_once() {
+    let mut chaos = Chaos::new().await;
+    let tx = deposit("alice@wallet", 100);
+
+    chaos.mempool(&tx).await;
+    for _ in 0..2 {
+        chaos
+            .block(
+                1,
+                ChaosBlock {
+                    sequenced: vec![&tx],
+                    ..Default::default()
+                },
+            )
+            .await;
+    }
+    assert_eq!(chaos.executor.unsettled_sequenced_txs.len(), 1);
+
+    for _ in 0..2 {
+        chaos
+            .block(
+                2,
+                ChaosBlock {
+                    successful: vec![&tx],
+                    ..Default::default()
+                },
+            )
+            .await;
+    }
+    assert_eq!(chaos.settled_balance("alice@wallet"), 100);
+    chaos.assert_converged();
+}
+
+#[tokio::test]
+async fn sequenced_and_settled_in_the_same_block() {
+    let mut chaos = Chaos::new().await;
+    let tx = deposit("alice@wallet", 100);
+
+    chaos.mempool(&tx).await;
+    chaos
+        .block(
+            1,
+            ChaosBlock {
+                sequenced: vec![&tx],
+                successful: vec![&tx],
+                ..Default::default()
+            },
+        )
+        .await;
+
+    assert_eq!(chaos.settled_balance("alice@wallet"), 100);
+    chaos.assert_converged();
+}
+
+#[tokio::test]
+async fn reordered_transactions_fail_on_chain() {
+    let mut chaos = Chaos::new().await;
+    let deposit_tx = deposit("alice@wallet", 100);
+    let withdraw_tx = withdraw("alice@wallet", 100);
+
+    // Optimistically, the withdrawal comes after the deposit and succeeds
+    chaos.mempool(&deposit_tx).await;
+    chaos.mempool(&withdraw_tx).await;
+    assert_eq!(chaos.optimistic_balance("alice@wallet"), 0);
+
+    // But it is sequenced first, and fails
+    chaos
+        .block(
+            1,
+            ChaosBlock {
+                sequenced: vec![&withdraw_tx, &deposit_tx],
+                ..Default::default()
+            },
+        )
+        .await;
+    chaos
+        .block(
+            2,
+            ChaosBlock {
+                failed: vec![&withdraw_tx],
+                successful: vec![&deposit_tx],
+                ..Default::default()
+            },
+        )
+        .await;
+
+    assert!(chaos
+        .take_received()
+        .iter()
+        .any(|event| matches!(event, RollupExecutorEvent::Rollback(_))));
+    assert_eq!(chaos.settled_balance("alice@wallet"), 100);
+    assert_eq!(chaos.optimistic_balance("alice@wallet"), 100);
+    chaos.assert_converged();
+}
+
+#[tokio::test]
+async fn failed_transaction_is_rolled_back() {
+    let mut chaos = Chaos::new().await;
+    let tx = deposit("alice@wallet", 100);
+
+    chaos.mempool(&tx).await;
+    assert_eq!(chaos.optimistic_balance("alice@wallet"), 100);
+
+    chaos
+        .block(
+            1,
+            ChaosBlock {
+                sequenced: vec![&tx],
+                failed: vec![&tx],
+                ..Default::default()
+            },
+        )
+        .await;
+
+    assert_eq!(chaos.optimistic_balance("alice@wallet"), 0);
+    chaos.assert_converged();
+}
+
+#[tokio::test]
+async fn missing_settlement_keeps_optimistic_state_until_timeout() {
+    let mut chaos = Chaos::new().await;
+    let tx = deposit("alice@wallet", 100);
+
+    chaos.mempool(&tx).await;
+    chaos
+        .block(
+            1,
+            ChaosBlock {
+                sequenced: vec![&tx],
+                ..Default::default()
+            },
+        )
+        .await;
+
+    // Sequenced txs are never evicted locally, only the chain decides of their fate
+    for height in 2..10 {
+        chaos.block(height, ChaosBlock::default()).await;
+    }
+    assert_eq!(chaos.executor.unsettled_sequenced_txs.len(), 1);
+    assert_eq!(chaos.optimistic_balance("alice@wallet"), 100);
+
+    chaos
+        .block(
+            10,
+            ChaosBlock {
+                timed_out: vec![&tx],
+                ..Default::default()
+            },
+        )
+        .await;
+    assert_eq!(chaos.optimistic_balance("alice@wallet"), 0);
+    chaos.assert_converged();
+}
+
+#[tokio::test]
+async fn delayed_blocks_evict_unsequenced_transactions() {
+    let mut chaos = Chaos::new().await;
+    let tx = deposit("alice@wallet", 100);
+
+    chaos.mempool(&tx).await;
+    chaos.take_received();
+
+    for height in 1..=TIMEOUT_BLOCKS + 1 {
+        chaos.block(height, ChaosBlock::default()).await;
+    }
+
+    let received = chaos.take_received();
+    assert!(received.iter().any(
+        |event| matches!(event, RollupExecutorEvent::TxExpired(_, hash) if *hash == tx.hashed())
+    ));
+    assert_eq!(chaos.optimistic_balance("alice@wallet"), 0);
+    chaos.assert_converged();
+
+    // The transaction finally lands on chain
+    chaos
+        .block(
+            TIMEOUT_BLOCKS + 2,
+            ChaosBlock {
+                sequenced: vec![&tx],
+                successful: vec![&tx],
+                ..Default::default()
+            },
+        )
+        .await;
+    assert_eq!(chaos.settled_balance("alice@wallet"), 100);
+    chaos.assert_converged();
+}
+
+#[tokio::test]
+async fn evicting_stale_transactions_reruns_the_fresh_ones() {
+    let mut chaos = Chaos::new().await;
+    let stale = deposit("alice@wallet", 100);
+    let fresh = deposit("bob@wallet", 50);
+
+    chaos.mempool(&stale).await;
+    for height in 1..=2 {
+        chaos.block(height, ChaosBlock::default()).await;
+    }
+    chaos.mempool(&fresh).await;
+    chaos.take_received();
+
+    // Only the transaction received more than TIMEOUT_BLOCKS blocks ago is evicted
+    chaos.block(3, ChaosBlock::default()).await;
+    assert_eq!(chaos.executor.unsettled_unsequenced_txs.len(), 2);
+    chaos.block(TIMEOUT_BLOCKS + 1, ChaosBlock::default()).await;
+    let received = chaos.take_received();
+    let expired: Vec<&TxHash> = received
+        .iter()
+        .filter_map(|event| match event {
+            RollupExecutorEvent::TxExpired(identity, hash) => {
+                assert_eq!(identity, &stale.identity);
+                Some(hash)
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(expired, vec![&stale.hashed()]);
+    assert_eq!(chaos.stages(&stale).last(), Some(&TxStage::TimedOut));
+
+    // The rerun drops the effects of the evicted transaction and keeps the fresh one's, and
+    // the app learns it through a rollback
+    assert!(received
+        .iter()
+        .any(|event| matches!(event, RollupExecutorEvent::Rollback(_))));
+    assert_eq!(chaos.optimistic_balance("alice@wallet"), 0);
+    assert_eq!(chaos.optimistic_balance("bob@wallet"), 50);
+    assert_eq!(balance(&chaos.app_state, "alice@wallet"), 0);
+    assert_eq!(balance(&chaos.app_state, "bob@wallet"), 50);
+
+    chaos
+        .block(
+            TIMEOUT_BLOCKS + 2,
+            ChaosBlock {
+                sequenced: vec![&fresh],
+                successful: vec![&fresh],
+                ..Default::default()
+            },
+        )
+        .await;
+    assert_eq!(chaos.settled_balance("bob@wallet"), 50);
+    chaos.assert_converged();
+}
+
+#[tokio::test]
+async fn skipped_blocks_are_backfilled_before_newer_ones() {
+    let mut chaos = Chaos::new().await;
+    let tx = deposit("alice@wallet", 100);
+
+    chaos.mempool(&tx).await;
+    chaos
+        .block(
+            1,
+            ChaosBlock {
+                sequenced: vec![&tx],
+                ..Default::default()
+            },
+        )
+        .await;
+    chaos.take_received();
+
+    // The block settling the deposit is skipped by the stream
+    let skipped = chaos.make_block(
+        2,
+        ChaosBlock {
+            successful: vec![&tx],
+            ..Default::default()
+        },
+    );
+    chaos.block(3, ChaosBlock::default()).await;
+    assert!(matches!(
+        chaos.take_received()[..],
+        [RollupExecutorEvent::CatchingUp(
+            BlockHeight(2),
+            BlockHeight(2)
+        )]
+    ));
+    let request = chaos.backfill_requests.try_recv().unwrap();
+    assert_eq!((request.from, request.to), (BlockHeight(2), BlockHeight(2)));
+    assert_eq!(chaos.executor.block_height, BlockHeight(1));
+    assert_eq!(chaos.settled_balance("alice@wallet"), 0);
+
+    chaos
+        .executor
+        .handle_backfilled_blocks(BackfilledBlocks(vec![skipped]))
+        .await
+        .unwrap();
+    chaos.drain_events();
+    assert!(chaos
+        .take_received()
+        .iter()
+        .any(|event| matches!(event, RollupExecutorEvent::CaughtUp(BlockHeight(3)))));
+    assert_eq!(chaos.executor.block_height, BlockHeight(3));
+    assert_eq!(chaos.settled_balance("alice@wallet"), 100);
+    chaos.assert_converged();
+}
+
+#[tokio::test]
+async fn tx_lifecycle_is_tracked_until_settlement() {
+    let mut chaos = Chaos::new().await;
+    let deposit_tx = deposit("alice@wallet", 100);
+    let withdraw_tx = withdraw("alice@wallet", 100);
+
+    chaos.mempool(&deposit_tx).await;
+    chaos.mempool(&withdraw_tx).await;
+    chaos
+        .block(
+            1,
+            ChaosBlock {
+                sequenced: vec![&withdraw_tx, &deposit_tx],
+                ..Default::default()
+            },
+        )
+        .await;
+    // Receiving the same block twice records nothing more
+    chaos
+        .block(
+            1,
+            ChaosBlock {
+                sequenced: vec![&withdraw_tx, &deposit_tx],
+                ..Default::default()
+            },
+        )
+        .await;
+    chaos
+        .block(
+            2,
+            ChaosBlock {
+                failed: vec![&withdraw_tx],
+                successful: vec![&deposit_tx],
+                ..Default::default()
+            },
+        )
+        .await;
+
+    assert_eq!(
+        chaos.stages(&deposit_tx),
+        vec![
+            TxStage::Received,
+            TxStage::OptimisticallyExecuted,
+            TxStage::Sequenced,
+            TxStage::Settled,
+        ]
+    );
+    assert_eq!(
+        chaos.stages(&withdraw_tx),
+        vec![
+            TxStage::Received,
+            TxStage::OptimisticallyExecuted,
+            TxStage::Sequenced,
+            TxStage::Failed,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn reruns_reuse_cached_executions() {
+    let mut chaos = Chaos::new().await;
+    let first = deposit("alice@wallet", 100);
+    let second = deposit("bob@wallet", 50);
+
+    chaos.mempool(&first).await;
+    chaos.mempool(&second).await;
+    for (height, tx) in [(1, &first), (2, &second)] {
+        chaos
+            .block(
+                height,
+                ChaosBlock {
+                    sequenced: vec![tx],
+                    ..Default::default()
+                },
+            )
+            .await;
+    }
+
+    // The second rerun replays the first transaction on the same settled state
+    assert!(chaos.executor.execution_cache.hits() > 0);
+    assert_eq!(chaos.optimistic_balance("alice@wallet"), 100);
+    assert_eq!(chaos.optimistic_balance("bob@wallet"), 50);
+
+    chaos
+        .block(
+            3,
+            ChaosBlock {
+                successful: vec![&first, &second],
+                ..Default::default()
+            },
+        )
+        .await;
+    chaos.assert_converged();
+}
+
+#[tokio::test]
+async fn reorgs_roll_settled_states_back() {
+    let mut chaos = Chaos::new().await;
+    let alice_tx = deposit("alice@wallet", 100);
+    let bob_tx = deposit("bob@wallet", 50);
+
+    chaos.mempool(&alice_tx).await;
+    chaos.mempool(&bob_tx).await;
+    for (height, hash, parent_hash, tx) in [(1, "a1", "a0", &alice_tx), (2, "a2", "a1", &bob_tx)] {
+        let block = chaos.chained_block(
+            height,
+            hash,
+            parent_hash,
+            ChaosBlock {
+                sequenced: vec![tx],
+                successful: vec![tx],
+                ..Default::default()
+            },
+        );
+        chaos.receive(block).await;
+    }
+    assert_eq!(chaos.settled_balance("bob@wallet"), 50);
+    chaos.take_received();
+
+    // Block 2 is replaced by one that does not settle bob's deposit yet
+    let block = chaos.chained_block(2, "b2", "a1", ChaosBlock::default());
+    chaos.receive(block).await;
+    assert!(chaos
+        .take_received()
+        .iter()
+        .any(|event| matches!(event, RollupExecutorEvent::Resync(_))));
+    assert_eq!(chaos.executor.block_height, BlockHeight(2));
+    assert_eq!(chaos.settled_balance("alice@wallet"), 100);
+    assert_eq!(chaos.settled_balance("bob@wallet"), 0);
+    assert_eq!(chaos.optimistic_balance("bob@wallet"), 50);
+    assert_eq!(chaos.executor.unsettled_unsequenced_txs.len(), 1);
+
+    let block = chaos.chained_block(
+        3,
+        "b3",
+        "b2",
+        ChaosBlock {
+            sequenced: vec![&bob_tx],
+            successful: vec![&bob_tx],
+            ..Default::default()
+        },
+    );
+    chaos.receive(block).await;
+    assert_eq!(chaos.settled_balance("bob@wallet"), 50);
+    chaos.assert_converged();
+
+    // A fork from a block that is not retained can't be rolled back
+    let block = chaos.chained_block(3, "c3", "c2", ChaosBlock::default());
+    assert!(chaos
+        .executor
+        .handle_node_state_event(NodeStateEvent::NewBlock(Box::new(block)))
+        .await
+        .is_err());
+    assert_eq!(chaos.settled_balance("bob@wallet"), 50);
+}
@@ -0,0 +1,151 @@
+//! Memoized transaction executions, reused when the optimistic states are re-run from the
+//! settled states.
+//!
+//! A rerun mostly replays the same transactions on the same states as before, so each execution
+//! is keyed by the transaction hash and a digest of the pre-states of the contracts it touches.
+//! The digest covers the whole encoded states rather than their commitments, as the
+//! commitments leave out parts of the state (e.g. the trade history) that the execution updates.
+
+use std::collections::{BTreeMap, HashMap};
+
+use orderbook::error::OrderbookError;
+use sdk::{BlobTransaction, ContractName, Hashed, HyleOutput, TxContext, TxHash};
+use sha2::{Digest, Sha256};
+
+use super::{ContractBox, RollupExecutor};
+
+/// Number of executions kept for each transaction, on different pre-states
+const MAX_EXECUTIONS_PER_TX: usize = 4;
+
+type Outputs = Vec<(HyleOutput, ContractName)>;
+
+struct CachedExecution {
+    pre_state: [u8; 32],
+    /// Post-states of the touched contracts with the outputs, or the execution error
+    result: Result<(BTreeMap<ContractName, ContractBox>, Outputs), CachedError>,
+}
+
+/// Message of a failed execution, with the error the orderbook reported if any
+struct CachedError {
+    message: String,
+    error: Option<OrderbookError>,
+}
+
+#[derive(Default)]
+pub struct ExecutionCache {
+    executions: HashMap<TxHash, Vec<CachedExecution>>,
+    hits: u64,
+}
+
+impl ExecutionCache {
+    /// Same as [`RollupExecutor::execute_blob_tx`], reusing the result of a previous execution
+    /// of `blob_tx` on the same states
+    pub fn execute(
+        &mut self,
+        contracts: &mut BTreeMap<ContractName, ContractBox>,
+        blob_tx: &BlobTransaction,
+        tx_ctx: Option<TxContext>,
+    ) -> anyhow::Result<Outputs> {
+        let Some(pre_state) = pre_state_digest(contracts, blob_tx, &tx_ctx) else {
+            return RollupExecutor::execute_blob_tx(contracts, blob_tx, tx_ctx);
+        };
+        let tx_hash = blob_tx.hashed();
+
+        let cached = self
+            .executions
+            .get(&tx_hash)
+            .and_then(|executions| executions.iter().find(|e| e.pre_state == pre_state));
+        if let Some(cached) = cached {
+            self.hits += 1;
+            return match &cached.result {
+                Ok((post_states, outputs)) => {
+                    for (contract_name, state) in post_states {
+                        contracts.insert(contract_name.clone(), state.clone());
+                    }
+                    Ok(outputs.clone())
+                }
+                Err(CachedError {
+                    message,
+                    error: Some(error),
+                }) => Err(anyhow::Error::new(error.clone()).context(message.clone())),
+                Err(CachedError {
+                    message,
+                    error: None,
+                }) => Err(anyhow::anyhow!("{}", message)),
+            };
+        }
+
+        let result = RollupExecutor::execute_blob_tx(contracts, blob_tx, tx_ctx);
+        let cached_result = match &result {
+            Ok(outputs) => Ok((touched_contracts(contracts, blob_tx), outputs.clone())),
+            Err(e) => Err(match e.downcast_ref::<OrderbookError>() {
+                Some(error) => CachedError {
+                    message: e.to_string(),
+                    error: Some(error.clone()),
+                },
+                None => CachedError {
+                    message: format!("{e:#}"),
+                    error: None,
+                },
+            }),
+        };
+        let executions = self.executions.entry(tx_hash).or_default();
+        if executions.len() >= MAX_EXECUTIONS_PER_TX {
+            executions.remove(0);
+        }
+        executions.push(CachedExecution {
+            pre_state,
+            result: cached_result,
+        });
+        result
+    }
+
+    /// Drops the executions of the transactions that are not kept, e.g. once settled
+    pub fn retain(&mut self, keep: impl Fn(&TxHash) -> bool) {
+        self.executions.retain(|tx_hash, _| keep(tx_hash));
+    }
+
+    /// Number of executions that were reused
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+}
+
+fn touched_contracts(
+    contracts: &BTreeMap<ContractName, ContractBox>,
+    blob_tx: &BlobTransaction,
+) -> BTreeMap<ContractName, ContractBox> {
+    blob_tx
+        .blobs
+        .iter()
+        .filter_map(|blob| {
+            contracts
+                .get(&blob.contract_name)
+                .map(|state| (blob.contract_name.clone(), state.clone()))
+        })
+        .collect()
+}
+
+/// Digest of the context and of the states `blob_tx` is executed on. None if the transaction
+/// touches none of the contracts, or if a state cannot be encoded.
+fn pre_state_digest(
+    contracts: &BTreeMap<ContractName, ContractBox>,
+    blob_tx: &BlobTransaction,
+    tx_ctx: &Option<TxContext>,
+) -> Option<[u8; 32]> {
+    let touched: BTreeMap<&ContractName, &ContractBox> = blob_tx
+        .blobs
+        .iter()
+        .filter_map(|blob| {
+            contracts
+                .get(&blob.contract_name)
+                .map(|state| (&blob.contract_name, state))
+        })
+        .collect();
+    if touched.is_empty() {
+        return None;
+    }
+
+    let encoded = borsh::to_vec(&(tx_ctx, touched)).ok()?;
+    Some(Sha256::digest(encoded).into())
+}
@@ -0,0 +1,362 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use hyle_modules::{
+    bus::SharedMessageBus,
+    log_error, module_bus_client, module_handle_messages,
+    modules::{BuildApiContextInner, Module},
+};
+use orderbook::{
+    client::{
+        activity::{open_interest, MarketActivity, OpenInterest, PairActivity, TokenFlows},
+        shards::{merged_state, OrderbookShards},
+    },
+    Orderbook, OrderbookBlob,
+};
+use sdk::{BlobTransaction, ContractName, Hashed, HyleOutput};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    app::decode_outputs,
+    rollup_executor::{ContractBox, RollupExecutor, RollupExecutorEvent},
+};
+
+#[cfg(test)]
+mod report_tests;
+
+const DAY_MS: u128 = 24 * 60 * 60 * 1000;
+
+/// Activity of the day being accounted, saved when the module stops
+const PENDING_ACTIVITY_FILE: &str = "pending.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SettlementReportConf {
+    /// Time of day the report of the past 24 hours is produced, in seconds after midnight UTC
+    pub at_secs_utc: u64,
+}
+
+/// End-of-day report of the orderbook activity
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SettlementReport {
+    /// Day the reported period started on, as `YYYY-MM-DD`
+    pub date: String,
+    pub from_ms: u128,
+    pub to_ms: u128,
+    pub pairs: BTreeMap<String, PairActivity>,
+    pub fees: BTreeMap<String, u128>,
+    pub flows: BTreeMap<String, TokenFlows>,
+    /// Deposits minus withdrawals, by token
+    pub net_flows: BTreeMap<String, i128>,
+    /// Quantities resting in the books when the report was produced
+    pub open_interest: BTreeMap<String, OpenInterest>,
+    /// Number of times the optimistic state diverged from the settled one and was rolled back
+    pub divergences: u64,
+}
+
+/// Report signed by the operator key, the one signing the invite codes
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignedSettlementReport {
+    pub report: SettlementReport,
+    /// Hex encoded compressed public key
+    pub public_key: String,
+    /// Hex encoded compact signature of [`SettlementReport::digest`]
+    pub signature: String,
+}
+
+impl SettlementReport {
+    pub fn new(from_ms: u128, to_ms: u128, activity: MarketActivity, state: &Orderbook) -> Self {
+        SettlementReport {
+            date: date_of(to_ms.saturating_sub(DAY_MS)),
+            from_ms,
+            to_ms,
+            net_flows: activity
+                .flows
+                .iter()
+                .map(|(token, flows)| (token.clone(), flows.net()))
+                .collect(),
+            pairs: activity.pairs,
+            fees: activity.fees,
+            flows: activity.flows,
+            open_interest: open_interest(state),
+            divergences: activity.divergences,
+        }
+    }
+
+    /// What the operator signs: the SHA-256 of the JSON encoded report
+    pub fn digest(&self) -> Result<[u8; 32]> {
+        let encoded = serde_json::to_vec(self).context("encoding settlement report")?;
+        Ok(Sha256::digest(encoded).into())
+    }
+
+    pub fn sign(self, key: &SecretKey) -> Result<SignedSettlementReport> {
+        let secp = Secp256k1::new();
+        let signature = secp.sign_ecdsa(&Message::from_digest(self.digest()?), key);
+        Ok(SignedSettlementReport {
+            report: self,
+            public_key: hex::encode(PublicKey::from_secret_key(&secp, key).serialize()),
+            signature: hex::encode(signature.serialize_compact()),
+        })
+    }
+}
+
+/// UTC date of `timestamp_ms`, as `YYYY-MM-DD`
+pub fn date_of(timestamp_ms: u128) -> String {
+    // Civil date from the number of days since 1970-01-01, in the proleptic Gregorian calendar
+    let days = (timestamp_ms / DAY_MS) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// First time a report is due after `now_ms`, given the time of day reports are produced at
+pub fn next_report_time(now_ms: u128, at_secs_utc: u64) -> u128 {
+    let at_ms = (at_secs_utc as u128 * 1000) % DAY_MS;
+    let today = now_ms - now_ms % DAY_MS + at_ms;
+    if today > now_ms {
+        today
+    } else {
+        today + DAY_MS
+    }
+}
+
+/// Dates are the only file names served, so that requests can't escape the reports directory
+fn is_report_date(date: &str) -> bool {
+    date.len() == 10
+        && date.chars().enumerate().all(|(i, c)| {
+            if i == 4 || i == 7 {
+                c == '-'
+            } else {
+                c.is_ascii_digit()
+            }
+        })
+}
+
+module_bus_client! {
+#[derive(Debug)]
+pub struct SettlementReporterBusClient {
+    receiver(RollupExecutorEvent),
+}
+}
+
+pub struct SettlementReporterCtx {
+    pub api: Arc<BuildApiContextInner>,
+    pub conf: SettlementReportConf,
+    pub shards: OrderbookShards,
+    pub default_states: BTreeMap<ContractName, Orderbook>,
+    pub data_directory: PathBuf,
+    pub signing_key: SecretKey,
+}
+
+/// Produces a signed report of the orderbook activity every day, written to the `reports`
+/// directory and served on `/reports/{date}`.
+///
+/// The activity is accounted from the optimistic executions: transactions that are rolled back
+/// afterwards stay in the report, and the rollbacks are counted as divergences.
+pub struct SettlementReporter {
+    bus: SettlementReporterBusClient,
+    conf: SettlementReportConf,
+    shards: OrderbookShards,
+    shard_states: BTreeMap<ContractName, Orderbook>,
+    reports_directory: PathBuf,
+    signing_key: SecretKey,
+    /// Start of the period being accounted, and its activity so far
+    period_start_ms: u128,
+    activity: MarketActivity,
+}
+
+impl Module for SettlementReporter {
+    type Context = SettlementReporterCtx;
+
+    async fn build(bus: SharedMessageBus, ctx: Self::Context) -> Result<Self> {
+        let reports_directory = ctx.data_directory.join("reports");
+        std::fs::create_dir_all(&reports_directory).context("creating reports directory")?;
+
+        let shard_states = ctx
+            .default_states
+            .iter()
+            .map(|(contract_name, default_state)| {
+                let state = RollupExecutor::saved_optimistic_state::<Orderbook>(
+                    &ctx.data_directory,
+                    contract_name,
+                )
+                .unwrap_or_else(|| default_state.clone());
+                (contract_name.clone(), state)
+            })
+            .collect();
+        let (period_start_ms, activity) =
+            std::fs::read(reports_directory.join(PENDING_ACTIVITY_FILE))
+                .ok()
+                .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+                .unwrap_or_else(|| (now_ms(), MarketActivity::default()));
+
+        let api = Router::new()
+            .route("/reports/{date}", get(get_report))
+            .with_state(reports_directory.clone());
+        if let Ok(mut guard) = ctx.api.router.lock() {
+            if let Some(router) = guard.take() {
+                guard.replace(router.merge(api));
+            }
+        }
+
+        Ok(SettlementReporter {
+            bus: SettlementReporterBusClient::new_from_bus(bus.new_handle()).await,
+            conf: ctx.conf,
+            shards: ctx.shards,
+            shard_states,
+            reports_directory,
+            signing_key: ctx.signing_key,
+            period_start_ms,
+            activity,
+        })
+    }
+
+    async fn run(&mut self) -> Result<()> {
+        let mut next_report_ms = next_report_time(now_ms(), self.conf.at_secs_utc);
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
+
+        module_handle_messages! {
+            on_self self,
+
+            listen<RollupExecutorEvent> event => {
+                self.handle_rollup_executor_event(event);
+            }
+
+            _ = interval.tick() => {
+                if now_ms() >= next_report_ms {
+                    _ = log_error!(self.produce_report(next_report_ms), "produce settlement report");
+                    next_report_ms = next_report_time(now_ms(), self.conf.at_secs_utc);
+                }
+            }
+        };
+
+        let pending = serde_json::to_vec(&(self.period_start_ms, &self.activity))?;
+        let _ = log_error!(
+            std::fs::write(self.reports_directory.join(PENDING_ACTIVITY_FILE), pending),
+            "Saving pending settlement activity"
+        );
+
+        Ok(())
+    }
+}
+
+impl SettlementReporter {
+    fn handle_rollup_executor_event(&mut self, event: RollupExecutorEvent) {
+        match event {
+            RollupExecutorEvent::TxExecutionSuccess(tx, hyle_outputs, optimistic_contracts) => {
+                self.record_tx(&tx, &hyle_outputs);
+                self.update_shard_states(&optimistic_contracts);
+            }
+            RollupExecutorEvent::Rollback(optimistic_contracts) => {
+                self.activity.record_divergence();
+                self.update_shard_states(&optimistic_contracts);
+            }
+            RollupExecutorEvent::Resync(optimistic_contracts) => {
+                self.update_shard_states(&optimistic_contracts);
+            }
+            RollupExecutorEvent::FailedTx(..)
+            | RollupExecutorEvent::TxExpired(..)
+            | RollupExecutorEvent::CatchingUp(..)
+            | RollupExecutorEvent::CaughtUp(..) => {}
+        }
+    }
+
+    fn record_tx(&mut self, tx: &BlobTransaction, hyle_outputs: &[(HyleOutput, ContractName)]) {
+        let Some(orderbook_cn) = self.shards.contract_of_tx(tx) else {
+            return;
+        };
+        let Some(before) = self.shard_states.get(orderbook_cn) else {
+            return;
+        };
+        let Some(action) = tx
+            .blobs
+            .iter()
+            .find(|blob| &blob.contract_name == orderbook_cn)
+            .and_then(|blob| OrderbookBlob::action(&blob.data))
+        else {
+            return;
+        };
+        let (events, _) = decode_outputs(orderbook_cn, &tx.hashed(), hyle_outputs);
+        self.activity.record_tx(before, &action, &events);
+    }
+
+    fn update_shard_states(&mut self, optimistic_contracts: &BTreeMap<ContractName, ContractBox>) {
+        for contract_name in self.shards.contract_names() {
+            if let Some(state) = optimistic_contracts
+                .get(contract_name)
+                .and_then(|state| state.downcast::<Orderbook>())
+            {
+                self.shard_states
+                    .insert(contract_name.clone(), state.clone());
+            }
+        }
+    }
+
+    /// Signs and writes the report of the period ending at `to_ms`, and starts the next one
+    fn produce_report(&mut self, to_ms: u128) -> Result<()> {
+        let state = merged_state(self.shard_states.values()).context("No orderbook state")?;
+        let activity = std::mem::take(&mut self.activity);
+        let report = SettlementReport::new(self.period_start_ms, to_ms, activity, &state);
+        self.period_start_ms = to_ms;
+
+        let path = self.reports_directory.join(format!("{}.json", report.date));
+        let signed = report.sign(&self.signing_key)?;
+        let bytes = serde_json::to_vec_pretty(&signed).context("encoding settlement report")?;
+        std::fs::write(&path, bytes).context("writing settlement report")?;
+        tracing::info!("Settlement report written to {}", path.display());
+        Ok(())
+    }
+}
+
+// --------------------------------------------------------
+//     Routes
+// --------------------------------------------------------
+
+async fn get_report(
+    State(reports_directory): State<PathBuf>,
+    axum::extract::Path(date): axum::extract::Path<String>,
+) -> Response {
+    if !is_report_date(&date) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    match read_report(&reports_directory, &date) {
+        Some(report) => axum::Json(report).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+fn read_report(reports_directory: &Path, date: &str) -> Option<SignedSettlementReport> {
+    let bytes = std::fs::read(reports_directory.join(format!("{date}.json"))).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default()
+}
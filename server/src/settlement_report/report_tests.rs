@@ -0,0 +1,56 @@
+use orderbook::{client::activity::MarketActivity, Orderbook};
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1, SecretKey};
+
+use super::{date_of, is_report_date, next_report_time, SettlementReport, DAY_MS};
+
+/// 2026-10-16T00:00:00Z
+const OCT_16: u128 = 1_792_108_800_000;
+
+#[test]
+fn dates_are_formatted_in_utc() {
+    assert_eq!(date_of(0), "1970-01-01");
+    assert_eq!(date_of(951_782_400_000), "2000-02-29");
+    assert_eq!(date_of(1_735_603_200_000 + DAY_MS - 1), "2024-12-31");
+    assert_eq!(date_of(OCT_16), "2026-10-16");
+
+    assert!(is_report_date("2026-10-16"));
+    assert!(!is_report_date("../secrets"));
+    assert!(!is_report_date("2026-10-16.json"));
+}
+
+#[test]
+fn reports_are_due_at_the_configured_time() {
+    let six_am = 6 * 3600;
+    // Before 6:00, the report is due the same day
+    assert_eq!(
+        next_report_time(OCT_16 + 1000, six_am),
+        OCT_16 + 6 * 3600 * 1000
+    );
+    // At or after 6:00, it is due the next day
+    assert_eq!(
+        next_report_time(OCT_16 + 6 * 3600 * 1000, six_am),
+        OCT_16 + DAY_MS + 6 * 3600 * 1000
+    );
+    assert_eq!(next_report_time(OCT_16, 0), OCT_16 + DAY_MS);
+}
+
+#[test]
+fn reports_are_dated_and_signed() {
+    let state = Orderbook::init(Default::default(), "admin@orderbook".to_string());
+    let mut activity = MarketActivity::default();
+    activity.record_divergence();
+
+    let to_ms = OCT_16 + 6 * 3600 * 1000;
+    let report = SettlementReport::new(to_ms - DAY_MS, to_ms, activity, &state);
+    assert_eq!(report.date, "2026-10-15");
+    assert_eq!(report.divergences, 1);
+
+    let key = SecretKey::from_slice(&[7; 32]).unwrap();
+    let signed = report.clone().sign(&key).unwrap();
+    let public_key = PublicKey::from_slice(&hex::decode(&signed.public_key).unwrap()).unwrap();
+    let signature = Signature::from_compact(&hex::decode(&signed.signature).unwrap()).unwrap();
+    let digest = Message::from_digest(report.digest().unwrap());
+    assert!(Secp256k1::verification_only()
+        .verify_ecdsa(&digest, &signature, &public_key)
+        .is_ok());
+}
@@ -0,0 +1,91 @@
+//! What the optimistic states of the orderbook instances have that their settled states don't
+//! yet, so that operators and frontends can see what is still unsettled.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use orderbook::{client::state_diff::OrderbookDiff, Orderbook};
+use sdk::{BlobTransaction, ContractName, Hashed, TxContext, TxHash};
+use serde::Serialize;
+
+use crate::rollup_executor::ContractBox;
+
+#[cfg(test)]
+mod diff_tests;
+
+/// Settled and optimistic states of the orderbook instances, recorded by the executor
+#[derive(Debug, Default)]
+pub struct UnsettledStates {
+    states: Mutex<RecordedStates>,
+}
+
+#[derive(Debug, Default)]
+struct RecordedStates {
+    settled: BTreeMap<ContractName, Orderbook>,
+    optimistic: BTreeMap<ContractName, Orderbook>,
+    sequenced_txs: Vec<TxHash>,
+    unsequenced_txs: Vec<TxHash>,
+}
+
+/// Unsettled transactions, and the differences they make to the settled states
+#[derive(Debug, Default, Serialize)]
+pub struct StateDiff {
+    /// Transactions sequenced but not settled yet, in execution order
+    pub sequenced_txs: Vec<TxHash>,
+    /// Transactions executed optimistically but not sequenced yet, in execution order
+    pub unsequenced_txs: Vec<TxHash>,
+    /// Differences of the orderbook instances whose optimistic state is not settled
+    pub contracts: BTreeMap<String, OrderbookDiff>,
+}
+
+impl UnsettledStates {
+    /// Records the settled states, when they change
+    pub fn record_settled(&self, settled: &BTreeMap<ContractName, ContractBox>) {
+        let mut states = self.states.lock().unwrap_or_else(|e| e.into_inner());
+        states.settled = orderbooks(settled);
+    }
+
+    /// Records the optimistic states, and the unsettled transactions executed on top of the
+    /// settled states to reach them
+    pub fn record_optimistic(
+        &self,
+        optimistic: &BTreeMap<ContractName, ContractBox>,
+        sequenced_txs: &[(BlobTransaction, TxContext)],
+        unsequenced_txs: &[(BlobTransaction, TxContext)],
+    ) {
+        let mut states = self.states.lock().unwrap_or_else(|e| e.into_inner());
+        states.optimistic = orderbooks(optimistic);
+        states.sequenced_txs = sequenced_txs.iter().map(|(tx, _)| tx.hashed()).collect();
+        states.unsequenced_txs = unsequenced_txs.iter().map(|(tx, _)| tx.hashed()).collect();
+    }
+
+    pub fn diff(&self) -> StateDiff {
+        let states = self.states.lock().unwrap_or_else(|e| e.into_inner());
+        let contracts = states
+            .optimistic
+            .iter()
+            .filter_map(|(contract_name, optimistic)| {
+                let settled = states.settled.get(contract_name)?;
+                let diff = OrderbookDiff::between(settled, optimistic);
+                (!diff.is_empty()).then(|| (contract_name.0.clone(), diff))
+            })
+            .collect();
+        StateDiff {
+            sequenced_txs: states.sequenced_txs.clone(),
+            unsequenced_txs: states.unsequenced_txs.clone(),
+            contracts,
+        }
+    }
+}
+
+/// States of the orderbook instances among `states`
+fn orderbooks(states: &BTreeMap<ContractName, ContractBox>) -> BTreeMap<ContractName, Orderbook> {
+    states
+        .iter()
+        .filter_map(|(contract_name, state)| {
+            Some((
+                contract_name.clone(),
+                state.downcast::<Orderbook>()?.clone(),
+            ))
+        })
+        .collect()
+}
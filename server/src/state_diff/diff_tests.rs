@@ -0,0 +1,48 @@
+use std::collections::BTreeMap;
+
+use orderbook::Orderbook;
+use sdk::{BlobTransaction, ContractName, Hashed, TxContext};
+
+use super::UnsettledStates;
+use crate::rollup_executor::ContractBox;
+
+const CONTRACT: &str = "orderbook";
+
+fn states(state: Orderbook) -> BTreeMap<ContractName, ContractBox> {
+    BTreeMap::from([(ContractName(CONTRACT.to_string()), ContractBox::new(state))])
+}
+
+#[test]
+fn settled_states_have_no_diff() {
+    let settled = Orderbook::init(Default::default(), "admin@orderbook".to_string());
+    let unsettled_states = UnsettledStates::default();
+    assert!(unsettled_states.diff().contracts.is_empty());
+
+    unsettled_states.record_settled(&states(settled.clone()));
+    unsettled_states.record_optimistic(&states(settled), &[], &[]);
+    let diff = unsettled_states.diff();
+    assert!(diff.contracts.is_empty());
+    assert!(diff.sequenced_txs.is_empty() && diff.unsequenced_txs.is_empty());
+}
+
+#[test]
+fn unsettled_deposits_are_balance_deltas() {
+    let settled = Orderbook::init(Default::default(), "admin@orderbook".to_string());
+    let mut optimistic = settled.clone();
+    let tx_ctx = TxContext::default();
+    optimistic
+        .deposit("USD".to_string(), 100, "alice@wallet".to_string(), &tx_ctx)
+        .unwrap();
+    let tx = BlobTransaction::new("alice@wallet", vec![]);
+
+    let unsettled_states = UnsettledStates::default();
+    unsettled_states.record_settled(&states(settled));
+    unsettled_states.record_optimistic(&states(optimistic), &[], &[(tx.clone(), tx_ctx)]);
+
+    let diff = unsettled_states.diff();
+    assert!(diff.sequenced_txs.is_empty());
+    assert_eq!(diff.unsequenced_txs, vec![tx.hashed()]);
+    let contract_diff = &diff.contracts[CONTRACT];
+    assert!(contract_diff.orders_added.is_empty());
+    assert_eq!(contract_diff.balance_deltas["alice@wallet"]["USD"], 100);
+}
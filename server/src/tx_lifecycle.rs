@@ -0,0 +1,120 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sdk::{hyle_model_utils::TimestampMs, TxHash};
+use serde::{Deserialize, Serialize};
+
+/// Number of transactions whose lifecycle is kept, the oldest ones are forgotten first
+pub const MAX_TRACKED_TXS: usize = 100_000;
+
+/// Step of an orderbook transaction's way to settlement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxStage {
+    /// Received from the mempool
+    Received,
+    /// Executed on the optimistic state
+    OptimisticallyExecuted,
+    /// Included in a block
+    Sequenced,
+    /// Part of a batch whose proof was generated
+    Proven,
+    /// Settled on chain
+    Settled,
+    /// Failed, optimistically or on chain
+    Failed,
+    /// Timed out on chain, or dropped for not being sequenced in time
+    TimedOut,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxTransition {
+    pub stage: TxStage,
+    pub timestamp: TimestampMs,
+}
+
+/// Stages a transaction went through, in the order they were reached
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TxLifecycle {
+    pub transitions: Vec<TxTransition>,
+}
+
+impl TxLifecycle {
+    /// Latest stage reached
+    pub fn stage(&self) -> Option<TxStage> {
+        self.transitions.last().map(|transition| transition.stage)
+    }
+}
+
+/// Lifecycles of the recent orderbook transactions, recorded by the executor and the prover
+#[derive(Debug, Default)]
+pub struct TxTracker {
+    txs: Mutex<TrackedTxs>,
+    /// Number of transactions received since the start
+    received: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct TrackedTxs {
+    lifecycles: HashMap<TxHash, TxLifecycle>,
+    /// Tracking order, to forget the oldest transactions
+    order: VecDeque<TxHash>,
+}
+
+impl TxTracker {
+    /// Records that `tx_hash` reached `stage`, starting to track it if needed
+    pub fn record(&self, tx_hash: &TxHash, stage: TxStage) {
+        let mut txs = self.txs.lock().unwrap_or_else(|e| e.into_inner());
+        if !txs.lifecycles.contains_key(tx_hash) {
+            txs.order.push_back(tx_hash.clone());
+            if txs.order.len() > MAX_TRACKED_TXS {
+                if let Some(oldest) = txs.order.pop_front() {
+                    txs.lifecycles.remove(&oldest);
+                }
+            }
+        }
+        let lifecycle = txs.lifecycles.entry(tx_hash.clone()).or_default();
+        // Blocks and proofs can be received twice
+        if lifecycle.stage() == Some(stage) {
+            return;
+        }
+        if stage == TxStage::Received {
+            self.received.fetch_add(1, Ordering::Relaxed);
+        }
+        lifecycle.transitions.push(TxTransition {
+            stage,
+            timestamp: now(),
+        });
+    }
+
+    /// Records that `tx_hash` reached `stage`, if it is already tracked
+    pub fn update(&self, tx_hash: &TxHash, stage: TxStage) {
+        if self.get(tx_hash).is_some() {
+            self.record(tx_hash, stage);
+        }
+    }
+
+    pub fn received_count(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn get(&self, tx_hash: &TxHash) -> Option<TxLifecycle> {
+        let txs = self.txs.lock().unwrap_or_else(|e| e.into_inner());
+        txs.lifecycles.get(tx_hash).cloned()
+    }
+}
+
+fn now() -> TimestampMs {
+    TimestampMs(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis())
+            .unwrap_or_default(),
+    )
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sdk::{verifiers::Secp256k1Blob, BlobTransaction};
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1};
+use sha2::{Digest, Sha256};
+
+#[cfg(test)]
+mod auth_tests;
+
+/// Header carrying the identity of the user signing the request
+pub const IDENTITY_HEADER: &str = "x-identity";
+/// Header carrying the time of the signature, in milliseconds since the epoch
+pub const TIMESTAMP_HEADER: &str = "x-timestamp";
+/// Header carrying the hex encoded compressed secp256k1 public key of the session
+pub const PUBLIC_KEY_HEADER: &str = "x-public-key";
+/// Header carrying the hex encoded compact secp256k1 signature of the request
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// Time a signed request stays valid, in milliseconds
+pub const MAX_SIGNATURE_AGE_MS: u128 = 30_000;
+/// Largest body a signed request can have
+pub const MAX_SIGNED_BODY_SIZE: usize = 1024 * 1024;
+
+/// User whose wallet signature of the request was verified, added to the request extensions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedUser(pub String);
+
+/// Signature headers of a request
+#[derive(Debug, Clone)]
+pub struct SignedRequest {
+    pub identity: String,
+    pub timestamp_ms: u128,
+    pub public_key: [u8; 33],
+    pub signature: [u8; 64],
+}
+
+impl SignedRequest {
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, String> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or(format!("Missing {} header", name))
+        };
+        let timestamp_ms = header(TIMESTAMP_HEADER)?
+            .parse()
+            .map_err(|_| format!("Invalid {} header", TIMESTAMP_HEADER))?;
+        Self::from_hex(
+            header(IDENTITY_HEADER)?.to_string(),
+            timestamp_ms,
+            header(PUBLIC_KEY_HEADER)?,
+            header(SIGNATURE_HEADER)?,
+        )
+    }
+
+    /// Signature of a message received outside of an HTTP request, e.g. over the WebSocket
+    pub fn from_hex(
+        identity: String,
+        timestamp_ms: u128,
+        public_key: &str,
+        signature: &str,
+    ) -> Result<Self, String> {
+        let decode = |name: &str, value: &str| {
+            hex::decode(value).map_err(|_| format!("The {} is not hex encoded", name))
+        };
+        Ok(SignedRequest {
+            identity,
+            timestamp_ms,
+            public_key: decode("public key", public_key)?
+                .try_into()
+                .map_err(|_| "Public key must be 33 bytes long".to_string())?,
+            signature: decode("signature", signature)?
+                .try_into()
+                .map_err(|_| "Signature must be 64 bytes long".to_string())?,
+        })
+    }
+}
+
+/// What the wallet signs to authenticate a request: its timestamp, method, path and body
+pub fn request_digest(timestamp_ms: u128, method: &str, path: &str, body: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{timestamp_ms}\n{method}\n{path}\n"));
+    hasher.update(body);
+    hasher.finalize().into()
+}
+
+/// Session keys users authenticate their transactions with.
+///
+/// Keys are learnt from the secp256k1 blobs of the users' successful transactions: the wallet
+/// contract only accepts keys registered on the user's account, so a key seen in a successful
+/// transaction of a user is one the chain authenticates that user with.
+#[derive(Debug, Default)]
+pub struct SessionKeys {
+    keys: RwLock<HashMap<String, BTreeSet<[u8; 33]>>>,
+}
+
+impl SessionKeys {
+    /// Records the keys `tx` was signed with by its sender
+    pub fn learn(&self, tx: &BlobTransaction) {
+        let signatures: Vec<Secp256k1Blob> = tx
+            .blobs
+            .iter()
+            .filter(|blob| blob.contract_name.0 == "secp256k1")
+            .filter_map(|blob| borsh::from_slice::<Secp256k1Blob>(&blob.data.0).ok())
+            .filter(|signature| signature.identity == tx.identity)
+            .collect();
+        if signatures.is_empty() {
+            return;
+        }
+        let mut keys = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        let user_keys = keys.entry(tx.identity.0.clone()).or_default();
+        for signature in signatures {
+            user_keys.insert(signature.public_key);
+        }
+    }
+
+    /// Checks that `request` was signed recently, with a session key of its user, over `digest`
+    pub fn verify(
+        &self,
+        request: &SignedRequest,
+        digest: [u8; 32],
+        now_ms: u128,
+    ) -> Result<(), String> {
+        if now_ms.abs_diff(request.timestamp_ms) > MAX_SIGNATURE_AGE_MS {
+            return Err("Signature expired".to_string());
+        }
+        let known = self
+            .keys
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&request.identity)
+            .is_some_and(|keys| keys.contains(&request.public_key));
+        if !known {
+            return Err(format!(
+                "Public key is not a session key of {}",
+                request.identity
+            ));
+        }
+        let public_key =
+            PublicKey::from_slice(&request.public_key).map_err(|_| "Invalid public key")?;
+        let signature =
+            Signature::from_compact(&request.signature).map_err(|_| "Invalid signature")?;
+        Secp256k1::verification_only()
+            .verify_ecdsa(&Message::from_digest(digest), &signature, &public_key)
+            .map_err(|_| "Signature does not match the request".to_string())
+    }
+}
+
+/// Authenticates requests with a signature of a wallet session key
+#[derive(Clone)]
+pub struct WalletAuth {
+    pub session_keys: Arc<SessionKeys>,
+}
+
+impl WalletAuth {
+    /// Verifies the signature of `request`, handing it back with its body once read
+    pub async fn authenticate(
+        &self,
+        request: Request,
+    ) -> Result<(AuthenticatedUser, Request), String> {
+        let signed = SignedRequest::from_headers(request.headers())?;
+        let (parts, body) = request.into_parts();
+        let body = axum::body::to_bytes(body, MAX_SIGNED_BODY_SIZE)
+            .await
+            .map_err(|e| format!("Could not read request body: {e}"))?;
+        let digest = request_digest(
+            signed.timestamp_ms,
+            parts.method.as_str(),
+            parts.uri.path(),
+            &body,
+        );
+        self.session_keys.verify(&signed, digest, now_ms())?;
+        Ok((
+            AuthenticatedUser(signed.identity),
+            Request::from_parts(parts, Body::from(body)),
+        ))
+    }
+}
+
+/// Rejects requests that are not signed by a session key of their user
+pub async fn require_wallet_signature(
+    State(auth): State<WalletAuth>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match auth.authenticate(request).await {
+        Ok((user, mut request)) => {
+            request.extensions_mut().insert(user);
+            next.run(request).await
+        }
+        Err(e) => (StatusCode::UNAUTHORIZED, e).into_response(),
+    }
+}
+
+pub fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default()
+}
@@ -0,0 +1,88 @@
+use sdk::{verifiers::Secp256k1Blob, Blob, BlobData, BlobTransaction, Identity};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+use super::{request_digest, SessionKeys, SignedRequest, MAX_SIGNATURE_AGE_MS};
+
+const USER: &str = "alice@wallet";
+const NOW: u128 = 1_700_000_000_000;
+
+fn session_key(seed: u8) -> (SecretKey, [u8; 33]) {
+    let secret_key = SecretKey::from_slice(&[seed; 32]).unwrap();
+    let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key).serialize();
+    (secret_key, public_key)
+}
+
+/// Transaction of `identity` carrying a secp256k1 blob signed with `public_key`
+fn signed_tx(identity: &str, public_key: [u8; 33]) -> BlobTransaction {
+    let blob = Secp256k1Blob {
+        identity: Identity(identity.to_string()),
+        data: [0; 32],
+        public_key,
+        signature: [0; 64],
+    };
+    BlobTransaction::new(
+        identity,
+        vec![Blob {
+            contract_name: "secp256k1".into(),
+            data: BlobData(borsh::to_vec(&blob).unwrap()),
+        }],
+    )
+}
+
+fn sign(secret_key: &SecretKey, public_key: [u8; 33], timestamp_ms: u128) -> SignedRequest {
+    let digest = request_digest(timestamp_ms, "GET", "/api/private/orders", b"");
+    let signature = Secp256k1::new()
+        .sign_ecdsa(&Message::from_digest(digest), secret_key)
+        .serialize_compact();
+    SignedRequest {
+        identity: USER.to_string(),
+        timestamp_ms,
+        public_key,
+        signature,
+    }
+}
+
+#[test]
+fn requests_signed_by_a_session_key_are_accepted() {
+    let (secret_key, public_key) = session_key(1);
+    let session_keys = SessionKeys::default();
+    session_keys.learn(&signed_tx(USER, public_key));
+
+    let request = sign(&secret_key, public_key, NOW);
+    let digest = request_digest(NOW, "GET", "/api/private/orders", b"");
+    assert_eq!(session_keys.verify(&request, digest, NOW + 1_000), Ok(()));
+
+    // The signature covers the request: it can't be replayed on another path
+    let other_digest = request_digest(NOW, "GET", "/api/private/balances", b"");
+    assert!(session_keys.verify(&request, other_digest, NOW).is_err());
+}
+
+#[test]
+fn unknown_session_keys_are_rejected() {
+    let (secret_key, public_key) = session_key(1);
+    let session_keys = SessionKeys::default();
+    // The key was used by another user, or not on behalf of its sender
+    session_keys.learn(&signed_tx("bob@wallet", public_key));
+    let mut tx = signed_tx(USER, public_key);
+    tx.identity = Identity("mallory@wallet".to_string());
+    session_keys.learn(&tx);
+
+    let request = sign(&secret_key, public_key, NOW);
+    let digest = request_digest(NOW, "GET", "/api/private/orders", b"");
+    let err = session_keys.verify(&request, digest, NOW).unwrap_err();
+    assert!(err.contains("not a session key"), "{err}");
+}
+
+#[test]
+fn stale_signatures_are_rejected() {
+    let (secret_key, public_key) = session_key(1);
+    let session_keys = SessionKeys::default();
+    session_keys.learn(&signed_tx(USER, public_key));
+
+    let request = sign(&secret_key, public_key, NOW);
+    let digest = request_digest(NOW, "GET", "/api/private/orders", b"");
+    let err = session_keys
+        .verify(&request, digest, NOW + MAX_SIGNATURE_AGE_MS + 1)
+        .unwrap_err();
+    assert!(err.contains("expired"), "{err}");
+}
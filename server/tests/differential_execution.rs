@@ -0,0 +1,348 @@
+//! Differential testing between the optimistic executor and the zkVM execution.
+//!
+//! The same transaction batches are run through `RollupExecutor::execute_blob_tx`, which the
+//! server relies on to answer optimistically, and through `sdk::guest::execute`, which is what
+//! the SP1 guest proves. Any divergence means users are shown a state that will never settle.
+
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use client_sdk::transaction_builder::TxExecutorHandler;
+use orderbook::{
+    blobs::deposit_blob,
+    client::tx_builder::{OrderbookTxBuilder, WalletSession},
+    witness::ZkOrderbook,
+    OrderType, Orderbook, OrderbookAction, SelfTradePrevention, TimeInForce,
+};
+use rand::{rngs::StdRng, seq::IndexedRandom, Rng, SeedableRng};
+use sdk::{
+    guest::execute, hyle_model_utils::TimestampMs, Blob, BlobData, BlobTransaction, BlockHeight,
+    Calldata, ContractName, Hashed, Identity, LaneId, TxContext,
+};
+use server::rollup_executor::{ContractBox, RollupExecutor};
+
+const BASE: &str = "hyllar";
+const QUOTE: &str = "oranj";
+const USERS: [&str; 3] = ["alice@wallet", "bob@wallet", "carol@wallet"];
+const ADMIN: &str = "admin@orderbook";
+
+struct TestSession(&'static str);
+
+impl WalletSession for TestSession {
+    fn identity(&self) -> Identity {
+        Identity(self.0.to_string())
+    }
+
+    fn auth_blobs(&self) -> Vec<Blob> {
+        vec![Blob {
+            contract_name: "wallet".into(),
+            data: BlobData(vec![]),
+        }]
+    }
+}
+
+fn orderbook_cn() -> ContractName {
+    "orderbook".into()
+}
+
+fn tx_ctx(block_height: u64) -> TxContext {
+    TxContext {
+        lane_id: LaneId::default(),
+        block_height: BlockHeight(block_height),
+        timestamp: TimestampMs(block_height as u128 * 1000),
+        ..Default::default()
+    }
+}
+
+fn orderbook_tx(user: &'static str, action: OrderbookAction) -> BlobTransaction {
+    // Shared by all users, which only need increasing nonces
+    static NONCE: AtomicU64 = AtomicU64::new(1);
+    let nonce = NONCE.fetch_add(1, Ordering::Relaxed);
+    let mut builder = OrderbookTxBuilder::new(orderbook_cn(), action.clone(), nonce);
+    // Deposits are backed by a transfer to the orderbook
+    if let OrderbookAction::Deposit { token, amount } = action {
+        let sender = TestSession(user).identity();
+        builder = builder.with_transfer(deposit_blob(
+            token.as_str().into(),
+            sender,
+            &orderbook_cn(),
+            amount,
+        ));
+    }
+    builder.build(&TestSession(user)).tx
+}
+
+/// Same calldata as the one built by `RollupExecutor::execute_blob_tx` for the orderbook blob
+fn orderbook_calldata(blob_tx: &BlobTransaction, tx_ctx: &TxContext) -> Calldata {
+    let index = blob_tx
+        .blobs
+        .iter()
+        .position(|blob| blob.contract_name == orderbook_cn())
+        .expect("orderbook blob");
+    Calldata {
+        identity: blob_tx.identity.clone(),
+        tx_hash: blob_tx.hashed(),
+        private_input: vec![],
+        blobs: blob_tx.blobs.clone().into(),
+        index: index.into(),
+        tx_ctx: Some(tx_ctx.clone()),
+        tx_blob_count: blob_tx.blobs.len(),
+    }
+}
+
+fn orderbook_state(contracts: &BTreeMap<ContractName, ContractBox>) -> &Orderbook {
+    contracts[&orderbook_cn()]
+        .downcast::<Orderbook>()
+        .expect("orderbook state")
+}
+
+/// Runs a batch through both executions, asserting they agree, and returns the resulting state.
+///
+/// As the prover does, the witness of each transaction is built on the state the previous
+/// ones led to, and merged into the witness of the batch.
+fn run_batch(state: Orderbook, batch: &[(BlobTransaction, TxContext)]) -> Orderbook {
+    let mut contracts = BTreeMap::from([(orderbook_cn(), ContractBox::new(state))]);
+    let mut commitment_metadata: Option<Vec<u8>> = None;
+    let mut optimistic_outputs = vec![];
+    for (blob_tx, tx_ctx) in batch {
+        let state = orderbook_state(&contracts);
+        let orderbook_blob = blob_tx.blobs.last().expect("orderbook blob");
+        let tx_metadata = state
+            .build_commitment_metadata(orderbook_blob)
+            .expect("commitment metadata");
+        commitment_metadata = Some(match commitment_metadata {
+            Some(metadata) => state
+                .merge_commitment_metadata(metadata, tx_metadata)
+                .expect("merged commitment metadata"),
+            None => tx_metadata,
+        });
+
+        optimistic_outputs.push(
+            RollupExecutor::execute_blob_tx(&mut contracts, blob_tx, Some(tx_ctx.clone())).ok(),
+        );
+    }
+    let commitment_metadata = commitment_metadata.expect("non empty batch");
+
+    let calldata: Vec<Calldata> = batch
+        .iter()
+        .map(|(blob_tx, tx_ctx)| orderbook_calldata(blob_tx, tx_ctx))
+        .collect();
+    let zk_outputs = execute::<ZkOrderbook>(&commitment_metadata, &calldata);
+    assert_eq!(zk_outputs.len(), batch.len());
+
+    for (i, (optimistic, zk)) in optimistic_outputs.iter().zip(&zk_outputs).enumerate() {
+        match optimistic {
+            Some(outputs) => {
+                let [(output, contract_name)] = outputs.as_slice() else {
+                    panic!("tx {i}: expected a single orderbook output, got {outputs:?}");
+                };
+                assert_eq!(*contract_name, orderbook_cn());
+                assert_eq!(output, zk, "tx {i}: outputs differ");
+            }
+            None => {
+                assert!(!zk.success, "tx {i}: only succeeded in the zkVM");
+                assert_eq!(
+                    zk.initial_state, zk.next_state,
+                    "tx {i}: failed tx changed the proven state"
+                );
+            }
+        }
+    }
+
+    let optimistic_state = orderbook_state(&contracts).clone();
+    assert_eq!(
+        optimistic_state.get_state_commitment(),
+        zk_outputs.last().expect("non empty batch").next_state,
+        "final states differ"
+    );
+    optimistic_state
+}
+
+fn deposits() -> Vec<(BlobTransaction, TxContext)> {
+    USERS
+        .iter()
+        .flat_map(|&user| {
+            [BASE, QUOTE].map(|token| {
+                let action = OrderbookAction::Deposit {
+                    token: token.to_string(),
+                    amount: 1_000_000,
+                };
+                (orderbook_tx(user, action), tx_ctx(1))
+            })
+        })
+        .collect()
+}
+
+/// Random orders, modifications, cancellations and withdrawals. Some of them are expected to fail
+/// (unknown order ids, insufficient balances...), which must fail identically on both sides.
+fn random_batch(
+    rng: &mut StdRng,
+    size: usize,
+    block_height: &mut u64,
+    order_ids: &mut Vec<String>,
+) -> Vec<(BlobTransaction, TxContext)> {
+    (0..size)
+        .map(|_| {
+            *block_height += 1;
+            let user = *USERS.choose(rng).expect("users");
+            let action = match rng.random_range(0..10) {
+                0..=6 => {
+                    // Ids are assigned in creation order, some of them to orders that fail
+                    let pair = (BASE.to_string(), QUOTE.to_string());
+                    order_ids.push(Orderbook::order_id(&pair, order_ids.len() as u64));
+                    OrderbookAction::CreateOrder {
+                        order_type: if rng.random_bool(0.5) {
+                            OrderType::Buy
+                        } else {
+                            OrderType::Sell
+                        },
+                        price: rng.random_bool(0.8).then(|| rng.random_range(90..=110)),
+                        pair,
+                        quantity: rng.random_range(1..=20),
+                        time_in_force: *[
+                            TimeInForce::GoodTilCancelled,
+                            TimeInForce::ImmediateOrCancel,
+                            TimeInForce::FillOrKill,
+                        ]
+                        .choose(rng)
+                        .expect("time in force"),
+                        trigger_price: rng.random_bool(0.1).then(|| rng.random_range(90..=110)),
+                        self_trade_prevention: *[
+                            None,
+                            Some(SelfTradePrevention::CancelNewest),
+                            Some(SelfTradePrevention::CancelOldest),
+                            Some(SelfTradePrevention::DecrementAndCancel),
+                        ]
+                        .choose(rng)
+                        .expect("self-trade prevention"),
+                        expires_at: rng.random_bool(0.2).then(|| {
+                            TimestampMs((*block_height + rng.random_range(0..5)) as u128 * 1000)
+                        }),
+                        display_quantity: rng.random_bool(0.2).then(|| rng.random_range(1..=5)),
+                    }
+                }
+                7 | 8 => {
+                    // Not necessarily owned by the user, nor still open
+                    let order_id = order_ids
+                        .choose(rng)
+                        .cloned()
+                        .unwrap_or_else(|| "unknown".to_string());
+                    if rng.random_bool(0.5) {
+                        OrderbookAction::Cancel { order_id }
+                    } else {
+                        OrderbookAction::ModifyOrder {
+                            order_id,
+                            new_price: rng.random_range(90..=110),
+                            new_quantity: rng.random_range(1..=20),
+                        }
+                    }
+                }
+                _ => OrderbookAction::Withdraw {
+                    token: [BASE, QUOTE].choose(rng).expect("tokens").to_string(),
+                    amount: rng.random_range(1..=100),
+                    recipient: None,
+                },
+            };
+            (orderbook_tx(user, action), tx_ctx(*block_height))
+        })
+        .collect()
+}
+
+#[test]
+fn optimistic_and_zkvm_executions_agree() {
+    for seed in 0..20 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut state = run_batch(
+            Orderbook::init(LaneId::default(), ADMIN.to_string()),
+            &deposits(),
+        );
+
+        // Orders can only be placed a few blocks after a deposit
+        let mut block_height = 10;
+        let mut order_ids = vec![];
+        for _ in 0..5 {
+            let size = rng.random_range(1..=30);
+            let batch = random_batch(&mut rng, size, &mut block_height, &mut order_ids);
+            state = run_batch(state, &batch);
+        }
+    }
+}
+
+#[test]
+fn failed_transactions_agree() {
+    let state = run_batch(
+        Orderbook::init(LaneId::default(), ADMIN.to_string()),
+        &deposits(),
+    );
+
+    let batch = vec![
+        (
+            orderbook_tx(
+                USERS[0],
+                OrderbookAction::Cancel {
+                    order_id: "unknown".to_string(),
+                },
+            ),
+            tx_ctx(10),
+        ),
+        (
+            orderbook_tx(
+                USERS[0],
+                OrderbookAction::Withdraw {
+                    token: BASE.to_string(),
+                    amount: u128::MAX,
+                    recipient: None,
+                },
+            ),
+            tx_ctx(10),
+        ),
+        (
+            orderbook_tx(
+                USERS[0],
+                OrderbookAction::CreateOrder {
+                    order_type: OrderType::Sell,
+                    price: Some(100),
+                    pair: (BASE.to_string(), QUOTE.to_string()),
+                    quantity: 10_000_000,
+                    time_in_force: TimeInForce::GoodTilCancelled,
+                    trigger_price: None,
+                    self_trade_prevention: None,
+                    expires_at: None,
+                    display_quantity: None,
+                },
+            ),
+            tx_ctx(10),
+        ),
+        // Orders on a token are locked for a few blocks after depositing it
+        (
+            orderbook_tx(
+                USERS[1],
+                OrderbookAction::Deposit {
+                    token: BASE.to_string(),
+                    amount: 1,
+                },
+            ),
+            tx_ctx(10),
+        ),
+        (
+            orderbook_tx(
+                USERS[1],
+                OrderbookAction::CreateOrder {
+                    order_type: OrderType::Sell,
+                    price: Some(100),
+                    pair: (BASE.to_string(), QUOTE.to_string()),
+                    quantity: 1,
+                    time_in_force: TimeInForce::GoodTilCancelled,
+                    trigger_price: None,
+                    self_trade_prevention: None,
+                    expires_at: None,
+                    display_quantity: None,
+                },
+            ),
+            tx_ctx(10),
+        ),
+    ];
+    run_batch(state, &batch);
+}